@@ -0,0 +1,1045 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::{Args, Parser, Subcommand};
+use reqwest::Url;
+
+use cloud_images_downloader::cloud::Arch;
+use cloud_images_downloader::helpers::{
+    ansible::AnsibleFormat,
+    customize::Customization,
+    image_resolver::{ExistingFile, OnMismatch, external::Downloader},
+    library::CloneMode,
+    oci::Reference,
+    packer,
+    progress::ProgressMode,
+    qemu_img::DiskFormat,
+    retention::{Retention, parse_size},
+    s3,
+    seed::{Seed, SeedFormat},
+    terraform,
+    trace::ExplainFormat,
+};
+use cloud_images_downloader::repositories::ImageQuery;
+
+use crate::lockfile::DEFAULT_LOCKFILE;
+
+/// Command line options accepted by the downloader. Every flag is optional so
+/// running the binary without arguments keeps the fully interactive wizard.
+#[derive(Debug, Parser)]
+#[command(name = "cloud-images-downloader", version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Distribution to download (ubuntu, debian, almalinux).
+    #[arg(long)]
+    pub distro: Option<String>,
+
+    /// Release to pick: Ubuntu version (24.04), Debian codename (bookworm)
+    /// or AlmaLinux major (9).
+    #[arg(long)]
+    pub release: Option<String>,
+
+    /// Architecture, in any distro's spelling (amd64 and x86_64 are the
+    /// same).
+    #[arg(long, value_parser = parse_arch)]
+    pub arch: Option<Arch>,
+
+    /// Image build, e.g. `latest` or `20241013-1744`.
+    #[arg(long)]
+    pub build: Option<String>,
+
+    /// Image variant (genericcloud, nocloud, disk1, ...).
+    #[arg(long)]
+    pub variant: Option<String>,
+
+    /// Disk image format (qcow2, raw, img, tar.xz, ...).
+    #[arg(long)]
+    pub format: Option<String>,
+
+    /// Download every image listed in a TOML manifest (`[[image]]` tables
+    /// with the keys distro, release, arch, build, variant and format).
+    #[arg(long, value_name = "FILE")]
+    pub manifest: Option<PathBuf>,
+
+    /// Extra `indexes.json` whose repositories replace or extend the built-in
+    /// ones by name (applied after the one in the configuration directory).
+    #[arg(long, value_name = "FILE")]
+    pub indexes: Option<PathBuf>,
+
+    /// Number of images downloaded at the same time in manifest mode.
+    #[arg(long, value_name = "N")]
+    pub jobs: Option<usize>,
+
+    /// Only offer the N most recent builds (plus `latest`) in the image
+    /// version menu. A "show all" entry is kept as an escape hatch.
+    #[arg(long, value_name = "N")]
+    pub max_builds: Option<usize>,
+
+    /// Print why every candidate image was kept or dropped at each filter
+    /// step, as a tree (default) or JSON on stderr.
+    #[arg(long, value_name = "FORMAT", num_args = 0..=1, default_missing_value = "tree")]
+    pub explain: Option<ExplainFormat>,
+
+    /// Print each selected image as a JSON object instead of the summary.
+    #[arg(long)]
+    pub json: bool,
+
+    /// Print `list` as JSON for Ansible: a module result (default) whose
+    /// images carry `url`, `checksum` (`sha256:<hex>`) and `dest` for
+    /// `get_url`, or a dynamic inventory with `--ansible=inventory`.
+    /// Failures are reported as `{"failed": true, "msg": ...}` on stdout.
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "result"
+    )]
+    pub ansible: Option<AnsibleFormat>,
+
+    /// Directory that receives downloaded images (defaults to
+    /// `~/Downloads/cloud-images` or `$XDG_DATA_HOME/cloud-images`).
+    #[arg(long, value_name = "DIR")]
+    pub output_dir: Option<PathBuf>,
+
+    /// Keep caches (HTTP metadata, the index) and temporary files in DIR
+    /// instead of the platform cache directory.
+    #[arg(long, value_name = "DIR")]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Save directly into the output directory instead of
+    /// `<distro>/<version>/<arch>` subfolders.
+    #[arg(long)]
+    pub flat: bool,
+
+    /// Download a single file over N concurrent range requests.
+    #[arg(long, value_name = "N")]
+    pub connections: Option<usize>,
+
+    /// Number of tries for every HTTP request before giving up.
+    #[arg(long, value_name = "N")]
+    pub retries: Option<u32>,
+
+    /// Save every metadata response (indexes, listings, checksum files) into
+    /// DIR, for replaying the resolution later with `--replay`.
+    #[arg(long, value_name = "DIR", conflicts_with = "replay")]
+    pub record: Option<PathBuf>,
+
+    /// Answer every request from responses saved with `--record` instead of
+    /// the network; requests nobody recorded fail with 404.
+    #[arg(long, value_name = "DIR")]
+    pub replay: Option<PathBuf>,
+
+    /// Never touch the network: images are resolved from the metadata index
+    /// and only downloaded from the library.
+    #[arg(long)]
+    pub offline: bool,
+
+    /// Replace an existing file that does not match the expected checksum.
+    #[arg(long, conflicts_with = "skip_existing")]
+    pub overwrite: bool,
+
+    /// Keep an existing file even when it cannot be verified.
+    #[arg(long)]
+    pub skip_existing: bool,
+
+    /// Cap the download bandwidth, e.g. `500K`, `10M` (bytes per second).
+    #[arg(long, value_name = "RATE")]
+    pub limit_rate: Option<String>,
+
+    /// Proxy for all requests, e.g. `http://proxy:3128` or
+    /// `socks5h://127.0.0.1:1080`. Defaults to HTTP(S)_PROXY / ALL_PROXY.
+    #[arg(long, value_name = "URL")]
+    pub proxy: Option<String>,
+
+    /// Seconds to wait for a TCP/TLS connection to be established.
+    #[arg(long, value_name = "SECS")]
+    pub connect_timeout: Option<u64>,
+
+    /// Seconds a transfer may stall before it is aborted.
+    #[arg(long, value_name = "SECS")]
+    pub read_timeout: Option<u64>,
+
+    /// Overall deadline in seconds for each request, body included (off by
+    /// default so large downloads are not cut short).
+    #[arg(long, value_name = "SECS")]
+    pub timeout: Option<u64>,
+
+    /// Extra header sent with every request, e.g. `'X-Token: abc'`
+    /// (repeatable).
+    #[arg(long = "header", value_name = "'NAME: VALUE'")]
+    pub headers: Vec<String>,
+
+    /// Override the User-Agent sent with every request.
+    #[arg(long, value_name = "UA")]
+    pub user_agent: Option<String>,
+
+    /// Maximum number of redirects followed per request (0 disables them).
+    #[arg(long, value_name = "N")]
+    pub max_redirects: Option<usize>,
+
+    /// Decompress `.xz`, `.bz2`, `.zst` and `.gz` images after verifying the
+    /// checksum of the compressed file.
+    #[arg(long)]
+    pub decompress: bool,
+
+    /// Convert the verified image with `qemu-img convert` (must be on PATH).
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    pub convert_to: Option<DiskFormat>,
+
+    /// Grow the final image with `qemu-img resize`, e.g. `40G` or `+10G`.
+    #[arg(long, value_name = "SIZE")]
+    pub resize: Option<String>,
+
+    /// Install these packages into a customized copy of the image
+    /// (`<image>-custom.<ext>`, built with `virt-customize`; repeatable or
+    /// comma-separated).
+    #[arg(long, value_name = "PACKAGE", value_delimiter = ',')]
+    pub install: Vec<String>,
+
+    /// Authorise the public key in FILE for USER in the customized copy
+    /// (repeatable).
+    #[arg(long, value_name = "USER:FILE")]
+    pub ssh_inject: Vec<String>,
+
+    /// Set the root password of the customized copy: `password:SECRET`,
+    /// `file:PATH`, `random` or `disabled`.
+    #[arg(long, value_name = "SELECTOR")]
+    pub root_password: Option<String>,
+
+    /// Run this script inside the customized copy (repeatable).
+    #[arg(long = "run", value_name = "SCRIPT")]
+    pub run_scripts: Vec<PathBuf>,
+
+    /// Run this shell command inside the customized copy (repeatable).
+    #[arg(long, value_name = "COMMAND")]
+    pub run_command: Vec<String>,
+
+    /// Reset machine ids, SSH host keys and logs of the customized copy with
+    /// `virt-sysprep`.
+    #[arg(long)]
+    pub sysprep: bool,
+
+    /// Build a cloud-init NoCloud seed (`<image>-seed.iso`) from this
+    /// user-data next to the final image.
+    #[arg(long, value_name = "USER_DATA")]
+    pub with_seed: Option<PathBuf>,
+
+    /// Butane (`.bu`, `.yaml`) or Ignition (`.ign`) config that `run` and
+    /// `libvirt create` pass to Fedora CoreOS and Flatcar images, which do
+    /// not use cloud-init.
+    #[arg(long, value_name = "FILE", conflicts_with = "with_seed")]
+    pub ignition: Option<PathBuf>,
+
+    /// `meta-data` of the seed; by default it names the instance after the
+    /// `--seed-hostname`.
+    #[arg(long, value_name = "FILE")]
+    pub seed_meta_data: Option<PathBuf>,
+
+    /// `network-config` of the seed.
+    #[arg(long, value_name = "FILE")]
+    pub seed_network_config: Option<PathBuf>,
+
+    /// Hostname written into the generated `meta-data` (the distro by
+    /// default).
+    #[arg(long, value_name = "NAME")]
+    pub seed_hostname: Option<String>,
+
+    /// Build the seed as an ISO (xorriso, genisoimage or mkisofs) or a FAT
+    /// image (mkfs.vfat and mcopy).
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    pub seed_format: Option<SeedFormat>,
+
+    /// Hand the transfer to `aria2c` or `curl` (must be on PATH) instead of
+    /// the built-in client.
+    #[arg(long, value_enum, value_name = "PROGRAM")]
+    pub downloader: Option<Downloader>,
+
+    /// Use the mirrors and hashes of a Metalink (`.meta4`) published next to
+    /// the image, starting with the fastest mirror.
+    #[arg(long)]
+    pub metalink: bool,
+
+    /// Download through the `.torrent` published next to the image when
+    /// there is one (needs `--downloader aria2c`).
+    #[arg(long)]
+    pub torrent: bool,
+
+    /// Extra keyring trusted when verifying the signature of upstream
+    /// checksum files (repeatable).
+    #[arg(long = "keyring", value_name = "FILE")]
+    pub keyrings: Vec<PathBuf>,
+
+    /// Refuse checksum files whose signature cannot be verified.
+    #[arg(long)]
+    pub require_signature: bool,
+
+    /// Pin the signing keys and hosts of repository metadata on first use
+    /// and warn when they change later.
+    #[arg(long)]
+    pub trust_store: bool,
+
+    /// Download into this staging directory and move images to the output
+    /// directory only once their checksum has been verified.
+    #[arg(long, value_name = "DIR")]
+    pub quarantine_dir: Option<PathBuf>,
+
+    /// Tag downloaded images with extended attributes (origin URL, checksum,
+    /// download time).
+    #[arg(long)]
+    pub xattrs: bool,
+
+    /// Write an in-toto provenance statement next to every download
+    /// (`<image>.intoto.json`): source repository, URL, mirror used,
+    /// retrieval time, checksum and downloader version.
+    #[arg(long)]
+    pub provenance: bool,
+
+    /// Upload every verified image below this S3 (or MinIO) location, tagged
+    /// with its distro, build and checksum; credentials and endpoint come
+    /// from the usual `AWS_*` variables and `~/.aws` files.
+    #[arg(long, value_name = "s3://BUCKET/PREFIX/", value_parser = s3::upload::parse_prefix)]
+    pub upload: Option<Url>,
+
+    /// Keep verified downloads in the managed image library and copy images
+    /// it already holds instead of downloading them again.
+    #[arg(long)]
+    pub library: bool,
+
+    /// Show a desktop notification when a long download finishes or fails.
+    #[arg(long)]
+    pub notify: bool,
+
+    /// Seconds a download has to take to be notified about (60 by default).
+    #[arg(long, value_name = "SECS")]
+    pub notify_after: Option<u64>,
+
+    /// What to do with a download that fails checksum verification
+    /// (prompts by default).
+    #[arg(long, value_enum, value_name = "ACTION")]
+    pub on_mismatch: Option<OnMismatch>,
+
+    /// Always download outdated images in full instead of reusing their
+    /// unchanged blocks via zsync.
+    #[arg(long)]
+    pub no_zsync: bool,
+
+    /// How download progress is reported: progress bars, plain log lines,
+    /// JSON events on stderr or nothing.
+    #[arg(long, value_enum, value_name = "MODE")]
+    pub progress: Option<ProgressMode>,
+}
+
+/// Maintenance commands run instead of the download wizard.
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Inspect the mirrors configured in `indexes.json`.
+    Mirrors {
+        #[command(subcommand)]
+        action: MirrorsCommand,
+    },
+    /// Finish the downloads an earlier run left incomplete, continuing from
+    /// the partially fetched files.
+    Resume,
+    /// Re-hash downloaded images and compare them with the `<image>.json`
+    /// report written when they were fetched.
+    Verify {
+        #[arg(required = true, value_name = "IMAGE")]
+        images: Vec<PathBuf>,
+    },
+    /// Resolve the `--manifest` entries (or the image selected by the flags)
+    /// to exact URLs and checksums and pin them in a lockfile.
+    Lock {
+        #[arg(long, value_name = "FILE", default_value = DEFAULT_LOCKFILE)]
+        lockfile: PathBuf,
+    },
+    /// Download the images of a lockfile. Without `--locked` the lockfile is
+    /// written first, as `lock` would.
+    Install {
+        /// Download exactly the pinned artifacts and fail if their upstream
+        /// checksums changed.
+        #[arg(long)]
+        locked: bool,
+
+        #[arg(long, value_name = "FILE", default_value = DEFAULT_LOCKFILE)]
+        lockfile: PathBuf,
+    },
+    /// Manage the image library kept by `--library`.
+    Library {
+        #[command(subcommand)]
+        action: LibraryCommand,
+    },
+    /// List the images matching the filter flags, from the local metadata
+    /// index once it has been synced.
+    List {
+        /// Ask the repositories instead of the index (needs --distro,
+        /// --release and --arch).
+        #[arg(long)]
+        remote: bool,
+    },
+    /// Find images across every distro by fuzzy matching the terms against
+    /// distro, release, version, arch and variant (`search jammy minimal
+    /// arm64`). The filter flags narrow the candidates.
+    Search {
+        #[arg(required = true, value_name = "TERM")]
+        terms: Vec<String>,
+
+        /// Crawl the repositories instead of reading the metadata index.
+        #[arg(long)]
+        remote: bool,
+    },
+    /// Manage the local metadata index.
+    Index {
+        #[command(subcommand)]
+        action: IndexCommand,
+    },
+    /// Manage the cache of metadata responses.
+    Cache {
+        #[command(subcommand)]
+        action: CacheCommand,
+    },
+    /// Download the selected image and boot it under `qemu-system` from a
+    /// copy-on-write overlay, with a cloud-init seed authorising an SSH key.
+    /// `--resize` grows the overlay; `--with-seed` replaces the generated
+    /// user-data.
+    Run {
+        /// Public key for the image's default user (the first of
+        /// `~/.ssh/id_{ed25519,ecdsa,rsa}.pub` by default).
+        #[arg(long, value_name = "FILE")]
+        ssh_key: Option<PathBuf>,
+
+        /// Guest memory, e.g. `4G`.
+        #[arg(long, value_name = "SIZE", default_value = "2G")]
+        memory: String,
+
+        #[arg(long, default_value_t = 2)]
+        cpus: u32,
+
+        /// Host port forwarded to the guest's SSH port.
+        #[arg(long, value_name = "PORT", default_value_t = 2222)]
+        ssh_port: u16,
+
+        /// Keep the overlay and seed after the VM powered off.
+        #[arg(long)]
+        keep: bool,
+    },
+    /// Hand the selected image to libvirt.
+    Libvirt {
+        #[command(subcommand)]
+        action: LibvirtCommand,
+    },
+    /// Hand the selected image to a Proxmox VE node (configured in the
+    /// `[proxmox]` table of the config file).
+    Proxmox {
+        #[command(subcommand)]
+        action: ProxmoxCommand,
+    },
+    /// Hand the selected image to Incus (or LXD).
+    Incus {
+        #[command(subcommand)]
+        action: IncusCommand,
+    },
+    /// Hand the selected image to an OpenStack cloud.
+    Openstack {
+        #[command(subcommand)]
+        action: OpenstackCommand,
+    },
+    /// Download the selected image and push it, with its provenance
+    /// statement, to an OCI registry as an artifact `oras pull` can fetch.
+    Push {
+        /// `oci://registry/repository[:tag]`, e.g.
+        /// `oci://ghcr.io/acme/images/ubuntu:noble`.
+        #[arg(value_name = "REFERENCE", value_parser = Reference::parse)]
+        reference: Reference,
+
+        /// Talk to the registry over plain HTTP, e.g. a local `registry:2`.
+        #[arg(long)]
+        plain_http: bool,
+    },
+    /// Describe the selected image for infrastructure-as-code tools instead
+    /// of downloading it.
+    Export {
+        #[command(subcommand)]
+        target: ExportCommand,
+    },
+    /// Download the selected image and push it as a KubeVirt containerdisk:
+    /// a container image holding the qcow2 disk below `/disk/`.
+    Containerdisk {
+        /// `oci://registry/repository[:tag]`, e.g.
+        /// `oci://quay.io/acme/ubuntu-containerdisk:noble`.
+        #[arg(value_name = "REFERENCE", value_parser = Reference::parse)]
+        reference: Reference,
+
+        /// Talk to the registry over plain HTTP, e.g. a local `registry:2`.
+        #[arg(long)]
+        plain_http: bool,
+    },
+    /// Build a cloud-init NoCloud seed from `USER_DATA` without downloading
+    /// anything; the `--seed-*` flags add the other files.
+    Seed {
+        #[arg(value_name = "USER_DATA")]
+        user_data: PathBuf,
+
+        /// The seed to write; `.img` builds a FAT image unless
+        /// `--seed-format` says otherwise.
+        #[arg(long, short, value_name = "FILE", default_value = "seed.iso")]
+        output: PathBuf,
+    },
+    /// Refresh images on a schedule with a systemd user timer or a crontab
+    /// line running this program non-interactively.
+    Schedule {
+        #[command(subcommand)]
+        action: ScheduleCommand,
+    },
+    /// Serve a JSON API over HTTP for resolving images, starting downloads
+    /// into the output directory and following their progress.
+    Serve {
+        /// Address to listen on.
+        #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:8080")]
+        listen: SocketAddr,
+
+        /// Seconds between checks of the images downloaded through the API
+        /// for newer builds, as reported by `/metrics`; 0 never checks.
+        #[arg(long, value_name = "SECS", default_value_t = 21600)]
+        recheck: u64,
+    },
+    /// Replicate the `--distro` images the filters select into `DIR`, laid
+    /// out as upstream and with the checksum and signature files published
+    /// next to them. Re-runs only fetch what changed. `--release`, `--arch`,
+    /// `--variant` and `--format` add to the lists below.
+    Mirror(MirrorArgs),
+    /// Serve a directory (a `mirror` tree, say) or, by default, the image
+    /// library read-only over HTTP, with index pages and a generated
+    /// `SHA256SUMS` per directory.
+    ServeFiles {
+        #[arg(value_name = "DIR")]
+        dir: Option<PathBuf>,
+
+        /// Address to listen on; use `0.0.0.0:8081` to serve the LAN.
+        #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:8081")]
+        listen: SocketAddr,
+    },
+    /// Transpile a Butane config to Ignition for Fedora CoreOS and Flatcar,
+    /// with the `butane` binary when installed and the built-in subset
+    /// otherwise.
+    Ignition {
+        #[arg(value_name = "BUTANE")]
+        butane: PathBuf,
+
+        #[arg(long, short, value_name = "FILE", default_value = "config.ign")]
+        output: PathBuf,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum LibvirtCommand {
+    /// Download the selected image, put it into a storage pool together with
+    /// a NoCloud seed and define a domain booting it.
+    Create {
+        /// Domain name (`<distro>-<release>-<arch>-<build>` by default).
+        #[arg(long)]
+        name: Option<String>,
+
+        /// libvirt connection URI, e.g. `qemu:///system`.
+        #[arg(long, value_name = "URI")]
+        connect: Option<String>,
+
+        /// Storage pool receiving the disk and the seed.
+        #[arg(long, default_value = "default")]
+        pool: String,
+
+        /// Create the disk as a qcow2 overlay backed by the download instead
+        /// of uploading a copy; the download has to stay in place.
+        #[arg(long)]
+        backing: bool,
+
+        /// Guest memory, e.g. `4G`.
+        #[arg(long, value_name = "SIZE", default_value = "2G", value_parser = parse_size)]
+        memory: u64,
+
+        #[arg(long, default_value_t = 2)]
+        cpus: u32,
+
+        /// libvirt network the NIC joins.
+        #[arg(long, default_value = "default")]
+        network: String,
+
+        /// Emulate the CPU (`qemu` domain) instead of using KVM.
+        #[arg(long)]
+        emulate: bool,
+
+        /// Public key for the image's default user (the first of
+        /// `~/.ssh/id_{ed25519,ecdsa,rsa}.pub` by default).
+        #[arg(long, value_name = "FILE")]
+        ssh_key: Option<PathBuf>,
+
+        /// Start the domain once defined.
+        #[arg(long)]
+        start: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ProxmoxCommand {
+    /// Download the selected image, upload it to the node's storage, import
+    /// it as the disk of a new VM with a cloud-init drive and optionally turn
+    /// the VM into a template.
+    Create {
+        /// Node to create the VM on (`node` of the config by default).
+        #[arg(long)]
+        node: Option<String>,
+
+        /// Storage the image is uploaded to (`local` by default); it needs
+        /// the `import` content type.
+        #[arg(long)]
+        storage: Option<String>,
+
+        /// Storage of the VM disk and the cloud-init drive (`local-lvm` by
+        /// default).
+        #[arg(long)]
+        disk_storage: Option<String>,
+
+        /// Bridge of the network interface (`vmbr0` by default).
+        #[arg(long)]
+        bridge: Option<String>,
+
+        /// VM id (the next free one by default).
+        #[arg(long)]
+        vmid: Option<u32>,
+
+        /// VM name (`<distro>-<release>-<arch>-<build>` by default).
+        #[arg(long)]
+        name: Option<String>,
+
+        /// VM memory, e.g. `4G`.
+        #[arg(long, value_name = "SIZE", default_value = "2G", value_parser = parse_size)]
+        memory: u64,
+
+        #[arg(long, default_value_t = 2)]
+        cpus: u32,
+
+        /// Default user of the cloud-init drive (the image's own otherwise).
+        #[arg(long)]
+        ci_user: Option<String>,
+
+        /// Public key for that user (the first of
+        /// `~/.ssh/id_{ed25519,ecdsa,rsa}.pub` by default).
+        #[arg(long, value_name = "FILE")]
+        ssh_key: Option<PathBuf>,
+
+        /// Convert the VM to a template.
+        #[arg(long)]
+        template: bool,
+
+        /// Leave the uploaded image on the storage after the import.
+        #[arg(long)]
+        keep_upload: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum IncusCommand {
+    /// Download the selected image and import it as a VM image, together
+    /// with a generated metadata tarball, so `incus launch --vm` can use it.
+    Import {
+        /// Alias of the image (`<distro>/<release>/<arch>` by default); may
+        /// be repeated.
+        #[arg(long = "alias", value_name = "ALIAS")]
+        aliases: Vec<String>,
+
+        /// Remote to import into (the default remote otherwise).
+        #[arg(long)]
+        remote: Option<String>,
+
+        /// Client to run, `incus` or `lxc` (the first found by default).
+        #[arg(long, value_name = "PROGRAM")]
+        client: Option<String>,
+
+        /// Keep Secure Boot enabled for instances of the image; most cloud
+        /// images only boot with it disabled.
+        #[arg(long)]
+        secureboot: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum OpenstackCommand {
+    /// Download the selected image and upload it to Glance, with its
+    /// formats, distro and upstream checksum as image properties.
+    Upload {
+        /// Cloud of `clouds.yaml` to use (`$OS_CLOUD`, else the `OS_*`
+        /// variables of an openrc file).
+        #[arg(long)]
+        cloud: Option<String>,
+
+        /// Image name (`<distro>-<release>-<arch>-<build>` by default).
+        #[arg(long)]
+        name: Option<String>,
+
+        /// `private`, `shared`, `community` or `public`.
+        #[arg(long)]
+        visibility: Option<String>,
+
+        /// Extra image property; may be repeated.
+        #[arg(long = "property", value_name = "KEY=VALUE", value_parser = parse_property)]
+        properties: Vec<(String, String)>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ScheduleCommand {
+    /// Resolve the selection flags (prompting for missing ones, or take
+    /// `--manifest`) and install a job downloading it again at every run.
+    Install {
+        /// Follow the newest build (the default unless `--build` pins one).
+        #[arg(long)]
+        latest: bool,
+
+        /// Run every day.
+        #[arg(long, group = "frequency")]
+        daily: bool,
+
+        /// Run every week (the default).
+        #[arg(long, group = "frequency")]
+        weekly: bool,
+
+        /// Run every month.
+        #[arg(long, group = "frequency")]
+        monthly: bool,
+
+        /// systemd calendar expression, e.g. `Sat *-*-* 03:00`.
+        #[arg(
+            long,
+            value_name = "SPEC",
+            group = "frequency",
+            conflicts_with = "cron"
+        )]
+        on_calendar: Option<String>,
+
+        /// Add a line to the user's crontab instead of systemd units.
+        #[arg(long)]
+        cron: bool,
+
+        /// Job name (`cloud-images-<distro>-<release>-<arch>` by default).
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Remove the timer (or crontab line) of a job.
+    Remove {
+        name: String,
+
+        /// Remove the crontab line instead of systemd units.
+        #[arg(long)]
+        cron: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ExportCommand {
+    /// Write an HCL2 Packer template whose source block builds from the
+    /// resolved image URL and checksum.
+    Packer {
+        /// Builder of the source block.
+        #[arg(long, value_enum, default_value_t)]
+        builder: packer::Builder,
+
+        /// Proxmox node the `proxmox-clone` source builds on.
+        #[arg(long)]
+        node: Option<String>,
+
+        /// File to write (`.pkr.hcl`); stdout by default.
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// Write the resolved image URL, checksum and version as a
+    /// `.tfvars.json` document, or as a module snippet for a provider.
+    Terraform {
+        /// Write variables and the resource declaring the image for this
+        /// provider instead of `.tfvars.json`.
+        #[arg(long, value_enum)]
+        module: Option<terraform::Module>,
+
+        /// File to write; stdout by default.
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// Write the resolved image as JSON for Ansible: `url`, `checksum`
+    /// (`sha256:<hex>`) and `dest` as `get_url` takes them, plus the build,
+    /// arch and variant.
+    Ansible {
+        /// Module result or dynamic inventory.
+        #[arg(long, value_enum, default_value_t)]
+        format: AnsibleFormat,
+
+        /// File to write; stdout by default.
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum IndexCommand {
+    /// Crawl every repository (or only `--distro`) and store all published
+    /// images in the index.
+    Sync,
+    /// Show when each distro was last synced.
+    Status,
+    /// Report the images published, removed or republished with another
+    /// checksum by the last `index sync`.
+    Diff,
+    /// Write the index, the cached metadata responses and optionally library
+    /// images into a bundle for hosts without network access.
+    Export {
+        /// Bundle to write, a zstd compressed tarball.
+        path: PathBuf,
+        /// Also carry this library image (a prefix of its id); repeatable.
+        #[arg(long = "image", value_name = "ID")]
+        images: Vec<String>,
+    },
+    /// Load a bundle written by `index export`.
+    Import { path: PathBuf },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CacheCommand {
+    /// Delete the cached files the retention limits evict, least recently
+    /// used first.
+    Gc {
+        #[command(flatten)]
+        retention: RetentionArgs,
+    },
+}
+
+/// What `mirror` replicates and where to.
+#[derive(Debug, Args)]
+pub struct MirrorArgs {
+    #[arg(value_name = "DIR")]
+    pub dir: PathBuf,
+
+    /// Releases to mirror, comma separated (all by default).
+    #[arg(long, value_name = "RELEASE", value_delimiter = ',')]
+    pub releases: Vec<String>,
+
+    /// Architectures to mirror (all the distro publishes by default).
+    #[arg(long, value_name = "ARCH", value_delimiter = ',', value_parser = parse_arch)]
+    pub arches: Vec<Arch>,
+
+    /// Variants to mirror (all by default).
+    #[arg(long, value_name = "VARIANT", value_delimiter = ',')]
+    pub variants: Vec<String>,
+
+    /// Disk formats to mirror (all by default).
+    #[arg(long, value_name = "FORMAT", value_delimiter = ',')]
+    pub formats: Vec<String>,
+
+    /// Newest builds kept per release and architecture; 0 mirrors every
+    /// build.
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    pub builds: usize,
+
+    /// Delete files mirrored earlier that the filters no longer select.
+    #[arg(long)]
+    pub prune: bool,
+
+    /// Also write Simplestreams metadata (`streams/v1/index.json`)
+    /// describing the mirrored images, so LXD, MAAS and the `ubuntu`
+    /// repository settings of this tool can consume the mirror.
+    #[arg(long)]
+    pub simplestreams: bool,
+}
+
+/// Retention limits given on the command line; each overrides the one in
+/// the config file.
+#[derive(Debug, Args)]
+pub struct RetentionArgs {
+    /// Shrink to this total size, e.g. `50G`, dropping the oldest first.
+    #[arg(long, value_name = "SIZE")]
+    pub max_size: Option<String>,
+
+    /// Drop entries older than this many days.
+    #[arg(long, value_name = "DAYS")]
+    pub max_age: Option<u64>,
+
+    /// Keep only the N newest entries per release.
+    #[arg(long, value_name = "N")]
+    pub keep_last: Option<usize>,
+}
+
+impl RetentionArgs {
+    /// These limits, falling back to `configured`.
+    pub fn or(&self, configured: Retention) -> Result<Retention> {
+        let given = Retention {
+            max_size: self
+                .max_size
+                .as_deref()
+                .map(parse_size)
+                .transpose()
+                .map_err(anyhow::Error::msg)?,
+            max_age_days: self.max_age,
+            keep_last: self.keep_last,
+        };
+        Ok(given.or(configured))
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum LibraryCommand {
+    /// List the images in the library.
+    List,
+    /// Remove the images whose id starts with ID; their files are deleted by
+    /// the next `gc`.
+    Rm {
+        #[arg(required = true, value_name = "ID")]
+        ids: Vec<String>,
+    },
+    /// Drop the images the retention limits evict (`keep_last` counts per
+    /// release) and delete stored files no image refers to any more.
+    Gc {
+        #[command(flatten)]
+        retention: RetentionArgs,
+    },
+    /// Print the path of the stored file of an image.
+    Path {
+        #[arg(value_name = "ID")]
+        id: String,
+    },
+    /// Place an image into a project directory, sharing the stored file
+    /// instead of copying it where the filesystem allows.
+    Export {
+        #[arg(value_name = "ID")]
+        id: String,
+
+        /// Target file, or directory to put the image in under its own name.
+        #[arg(long, value_name = "PATH")]
+        to: PathBuf,
+
+        /// How the image is placed there.
+        #[arg(long, value_enum, value_name = "MODE", default_value_t)]
+        mode: CloneMode,
+    },
+    /// Write Simplestreams metadata (`streams/v1/index.json`) describing the
+    /// images in the library, for serving it with `serve-files`.
+    Simplestreams,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum MirrorsCommand {
+    /// Download a small probe from every mirror and rank them by throughput
+    /// and latency.
+    Bench {
+        /// Only benchmark this repository (ubuntu, debian, almalinux).
+        #[arg(long)]
+        repo: Option<String>,
+
+        /// Remember the ranking in the user config so failover tries the
+        /// fastest mirror first.
+        #[arg(long)]
+        save: bool,
+    },
+}
+
+impl Cli {
+    /// Policy for destination files that already exist.
+    pub fn existing_file(&self) -> ExistingFile {
+        if self.overwrite {
+            ExistingFile::Overwrite
+        } else if self.skip_existing {
+            ExistingFile::Skip
+        } else {
+            ExistingFile::Ask
+        }
+    }
+
+    /// Whether the output is read by Ansible, which then also expects
+    /// failures as a JSON module result.
+    pub fn ansible_output(&self) -> bool {
+        self.ansible.is_some()
+            || matches!(
+                self.command,
+                Some(Command::Export {
+                    target: ExportCommand::Ansible { .. }
+                })
+            )
+    }
+
+    /// Whether the command hands the downloaded image to a hypervisor, which
+    /// needs it uncompressed and takes `--resize` and `--with-seed` itself.
+    pub fn deploys(&self) -> bool {
+        matches!(
+            self.command,
+            Some(
+                Command::Run { .. }
+                    | Command::Libvirt { .. }
+                    | Command::Proxmox { .. }
+                    | Command::Incus { .. }
+                    | Command::Openstack { .. }
+                    | Command::Containerdisk { .. }
+            )
+        )
+    }
+
+    /// The NoCloud seed described by the `--seed-*` flags around `user_data`.
+    pub fn seed(&self, user_data: PathBuf, format: SeedFormat) -> Seed {
+        Seed {
+            user_data,
+            meta_data: self.seed_meta_data.clone(),
+            network_config: self.seed_network_config.clone(),
+            hostname: self.seed_hostname.clone(),
+            format: self.seed_format.unwrap_or(format),
+        }
+    }
+
+    /// The changes the customization flags ask for.
+    pub fn customization(&self) -> Customization {
+        Customization {
+            install: self.install.clone(),
+            ssh_inject: self.ssh_inject.clone(),
+            root_password: self.root_password.clone(),
+            run: self.run_scripts.clone(),
+            run_command: self.run_command.clone(),
+            sysprep: self.sysprep,
+        }
+    }
+
+    /// Collect the selection flags into a (possibly partial) `ImageQuery`
+    /// used to preseed the wizard.
+    pub fn image_query(&self) -> ImageQuery {
+        let mut query = ImageQuery::new();
+        if let Some(distro) = &self.distro {
+            query = query.distro(distro);
+        }
+        if let Some(release) = &self.release {
+            query = query.release(release);
+        }
+        if let Some(arch) = self.arch {
+            query = query.arch(arch);
+        }
+        if let Some(build) = &self.build {
+            query = query.build(build);
+        }
+        if let Some(variant) = &self.variant {
+            query = query.variant(variant);
+        }
+        if let Some(format) = &self.format {
+            query = query.format(format);
+        }
+        query
+    }
+}
+
+/// `--property key=value`.
+fn parse_property(property: &str) -> Result<(String, String), String> {
+    property
+        .split_once('=')
+        .filter(|(key, _)| !key.is_empty())
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected KEY=VALUE, got '{property}'"))
+}
+
+/// `--arch` accepts every spelling [`Arch::from_name`] knows.
+fn parse_arch(name: &str) -> Result<Arch, String> {
+    Arch::from_name(name).ok_or_else(|| format!("unknown architecture '{name}'"))
+}
@@ -0,0 +1,104 @@
+use std::fmt;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+
+/// How a distro spells architecture names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchNaming {
+    /// Debian and Ubuntu: `amd64`, `arm64`, `ppc64el`.
+    Debian,
+    /// RPM based distros: `x86_64`, `aarch64`, `ppc64le`.
+    Rpm,
+}
+
+impl ArchNaming {
+    /// Naming used by the images of `os` (e.g. `almalinux`).
+    pub fn for_os(os: &str) -> Self {
+        match os.to_ascii_lowercase().as_str() {
+            "almalinux" | "rocky" | "centos" | "fedora" | "rhel" | "opensuse" => ArchNaming::Rpm,
+            _ => ArchNaming::Debian,
+        }
+    }
+}
+
+/// CPU architecture of an image, independent of how a distro names it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Arch {
+    Amd64,
+    Arm64,
+    Armhf,
+    Ppc64le,
+    S390x,
+    Riscv64,
+}
+
+impl Arch {
+    /// Parse any of the names distros use (`amd64` and `x86_64` alike),
+    /// ignoring case.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "amd64" | "x86_64" | "x86-64" | "x64" => Some(Arch::Amd64),
+            "arm64" | "aarch64" => Some(Arch::Arm64),
+            "armhf" | "armv7hl" | "armv7l" => Some(Arch::Armhf),
+            "ppc64el" | "ppc64le" => Some(Arch::Ppc64le),
+            "s390x" => Some(Arch::S390x),
+            "riscv64" => Some(Arch::Riscv64),
+            _ => None,
+        }
+    }
+
+    /// The name `naming` uses for this architecture.
+    pub fn name(self, naming: ArchNaming) -> &'static str {
+        match (self, naming) {
+            (Arch::Amd64, ArchNaming::Debian) => "amd64",
+            (Arch::Amd64, ArchNaming::Rpm) => "x86_64",
+            (Arch::Arm64, ArchNaming::Debian) => "arm64",
+            (Arch::Arm64, ArchNaming::Rpm) => "aarch64",
+            (Arch::Armhf, ArchNaming::Debian) => "armhf",
+            (Arch::Armhf, ArchNaming::Rpm) => "armv7hl",
+            (Arch::Ppc64le, ArchNaming::Debian) => "ppc64el",
+            (Arch::Ppc64le, ArchNaming::Rpm) => "ppc64le",
+            (Arch::S390x, _) => "s390x",
+            (Arch::Riscv64, _) => "riscv64",
+        }
+    }
+}
+
+impl fmt::Display for Arch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name(ArchNaming::Debian))
+    }
+}
+
+impl Serialize for Arch {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name(ArchNaming::Debian))
+    }
+}
+
+/// Accepts every spelling [`Arch::from_name`] does.
+impl<'de> Deserialize<'de> for Arch {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Arch::from_name(&name)
+            .ok_or_else(|| de::Error::custom(format!("unknown architecture '{name}'")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Arch, ArchNaming};
+
+    #[test]
+    fn normalises_names_across_distros() {
+        assert_eq!(Arch::from_name("x86_64"), Some(Arch::Amd64));
+        assert_eq!(Arch::from_name("AMD64"), Some(Arch::Amd64));
+        assert_eq!(Arch::from_name("aarch64"), Arch::from_name("arm64"));
+        assert_eq!(Arch::from_name("ppc64le"), Some(Arch::Ppc64le));
+        assert_eq!(Arch::from_name("mips"), None);
+
+        assert_eq!(Arch::Arm64.name(ArchNaming::for_os("almalinux")), "aarch64");
+        assert_eq!(Arch::Arm64.name(ArchNaming::for_os("ubuntu")), "arm64");
+        assert_eq!(Arch::Ppc64le.to_string(), "ppc64el");
+    }
+}
@@ -0,0 +1,162 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime, Utc};
+
+use crate::cloud::VersionKey;
+
+/// Identifier of an image build as published by the distros: a dated
+/// directory (`20241013-1744`), a point release with its date
+/// (`9.4-20240513`), an Ubuntu serial (`20240423.1`) or the `latest` alias.
+///
+/// Builds carrying a date order by it, then by [`VersionKey`] (so the serial
+/// `20240423.1` follows `20240423`); `latest` sorts above every build and
+/// anything without a date falls back to [`VersionKey`] alone.
+#[derive(Debug, Clone)]
+pub struct BuildId {
+    raw: String,
+    built: Option<NaiveDateTime>,
+    key: VersionKey,
+}
+
+impl BuildId {
+    pub fn new(raw: &str) -> Self {
+        Self {
+            raw: raw.to_string(),
+            built: parse_build_date(raw),
+            key: VersionKey::new(raw),
+        }
+    }
+
+    /// The identifier as published.
+    pub fn as_str(&self) -> &str {
+        &self.raw
+    }
+
+    /// When the build was made, if its identifier says.
+    pub fn built(&self) -> Option<NaiveDateTime> {
+        self.built
+    }
+
+    /// Age of the build at `now`, e.g. `3 days old`; `None` without a date.
+    pub fn age_at(&self, now: NaiveDateTime) -> Option<String> {
+        let days = (now - self.built?).num_days().max(0);
+        Some(match days {
+            0 => "today".to_string(),
+            1 => "1 day old".to_string(),
+            days => format!("{days} days old"),
+        })
+    }
+
+    /// Age of the build now, see [`age_at`](Self::age_at).
+    pub fn age(&self) -> Option<String> {
+        self.age_at(Utc::now().naive_utc())
+    }
+
+    /// The identifier followed by its age, e.g. `20241013-1744 (3 days old)`,
+    /// as shown in menus and summaries.
+    pub fn label(&self) -> String {
+        match self.age() {
+            Some(age) => format!("{} ({age})", self.raw),
+            None => self.raw.clone(),
+        }
+    }
+}
+
+/// The build date of `raw`: its first run of exactly eight digits read as
+/// `YYYYMMDD`, plus the time when the next run is four digits (`-HHMM`).
+fn parse_build_date(raw: &str) -> Option<NaiveDateTime> {
+    let runs: Vec<&str> = raw
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|run| !run.is_empty())
+        .collect();
+    let position = runs.iter().position(|run| run.len() == 8)?;
+    let date = NaiveDate::parse_from_str(runs[position], "%Y%m%d").ok()?;
+    let time = runs
+        .get(position + 1)
+        .filter(|run| run.len() == 4)
+        .and_then(|run| NaiveTime::parse_from_str(run, "%H%M").ok())
+        .unwrap_or(NaiveTime::MIN);
+    Some(date.and_time(time))
+}
+
+impl fmt::Display for BuildId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.raw)
+    }
+}
+
+impl PartialEq for BuildId {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for BuildId {}
+
+impl Ord for BuildId {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let latest = |b: &BuildId| b.raw.trim().eq_ignore_ascii_case("latest");
+        latest(self)
+            .cmp(&latest(other))
+            .then_with(|| match (self.built, other.built) {
+                (Some(a), Some(b)) => a.cmp(&b).then_with(|| self.key.cmp(&other.key)),
+                _ => self.key.cmp(&other.key),
+            })
+    }
+}
+
+impl PartialOrd for BuildId {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Sort `builds` newest first by [`BuildId`] and drop duplicates.
+pub fn sort_builds_newest_first<S: AsRef<str>>(builds: &mut Vec<S>) {
+    builds.sort_by_cached_key(|b| std::cmp::Reverse(BuildId::new(b.as_ref())));
+    builds.dedup_by(|a, b| a.as_ref() == b.as_ref());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BuildId, sort_builds_newest_first};
+    use chrono::NaiveDate;
+
+    #[test]
+    fn orders_builds_by_date_and_reports_their_age() {
+        let mut builds = vec![
+            "9.4-20240513",
+            "20240423.1",
+            "latest",
+            "20241013-1744",
+            "20240423",
+            "9.5-20241120",
+        ];
+        sort_builds_newest_first(&mut builds);
+        assert_eq!(
+            builds,
+            [
+                "latest",
+                "9.5-20241120",
+                "20241013-1744",
+                "9.4-20240513",
+                "20240423.1",
+                "20240423"
+            ]
+        );
+
+        let now = NaiveDate::from_ymd_opt(2024, 10, 16)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+        let build = BuildId::new("20241013-1744");
+        assert_eq!(build.built().unwrap().to_string(), "2024-10-13 17:44:00");
+        assert_eq!(build.age_at(now).as_deref(), Some("2 days old"));
+        assert_eq!(
+            BuildId::new("20241016").age_at(now).as_deref(),
+            Some("today")
+        );
+        assert!(BuildId::new("latest").age_at(now).is_none());
+    }
+}
@@ -0,0 +1,118 @@
+use std::fmt;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Disk image format, taken from the artifact's file name.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ImageFormat {
+    Qcow2,
+    Raw,
+    /// Ubuntu's `.img` files (QCOW2 despite the extension).
+    Img,
+    Vhd,
+    Vmdk,
+    Ova,
+    Iso,
+    TarGz,
+    TarXz,
+    Squashfs,
+    /// Any other extension, lowercased; empty when there is none.
+    Other(String),
+}
+
+/// Known formats by extension; compound extensions come first.
+const EXTENSIONS: &[(&str, ImageFormat)] = &[
+    ("tar.gz", ImageFormat::TarGz),
+    ("tar.xz", ImageFormat::TarXz),
+    ("qcow2", ImageFormat::Qcow2),
+    ("raw", ImageFormat::Raw),
+    ("img", ImageFormat::Img),
+    ("vhd", ImageFormat::Vhd),
+    ("vmdk", ImageFormat::Vmdk),
+    ("ova", ImageFormat::Ova),
+    ("iso", ImageFormat::Iso),
+    ("squashfs", ImageFormat::Squashfs),
+];
+
+impl ImageFormat {
+    /// Format named `name` (`qcow2`, `tar.xz`, ...), ignoring case.
+    pub fn from_name(name: &str) -> Self {
+        let name = name.trim().trim_start_matches('.').to_ascii_lowercase();
+        EXTENSIONS
+            .iter()
+            .find(|(ext, _)| *ext == name)
+            .map(|(_, format)| format.clone())
+            .unwrap_or(ImageFormat::Other(name))
+    }
+
+    /// Format of the file `name` (a file name, path or URL) by its extension.
+    pub fn from_file_name(name: &str) -> Self {
+        let file = name.rsplit('/').next().unwrap_or(name).to_ascii_lowercase();
+        if let Some((_, format)) = EXTENSIONS
+            .iter()
+            .find(|(ext, _)| file.ends_with(&format!(".{ext}")) || file == *ext)
+        {
+            return format.clone();
+        }
+        match file.rsplit_once('.') {
+            Some((_, ext)) => ImageFormat::Other(ext.to_string()),
+            None => ImageFormat::Other(String::new()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            ImageFormat::Other(ext) => ext,
+            known => EXTENSIONS
+                .iter()
+                .find(|(_, format)| format == known)
+                .map(|(ext, _)| *ext)
+                .expect("every known format has an extension"),
+        }
+    }
+}
+
+impl fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for ImageFormat {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for ImageFormat {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(ImageFormat::from_name(&String::deserialize(deserializer)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ImageFormat;
+
+    #[test]
+    fn detects_formats_from_file_names() {
+        assert_eq!(
+            ImageFormat::from_file_name("https://x.invalid/debian-12-nocloud-amd64.qcow2"),
+            ImageFormat::Qcow2
+        );
+        assert_eq!(
+            ImageFormat::from_file_name("noble-server-cloudimg-amd64-lxd.tar.xz"),
+            ImageFormat::TarXz
+        );
+        assert_eq!(
+            ImageFormat::from_file_name("noble-server-cloudimg-amd64.squashfs"),
+            ImageFormat::Squashfs
+        );
+        assert_eq!(
+            ImageFormat::from_file_name("image.zst"),
+            ImageFormat::Other("zst".to_string())
+        );
+        assert_eq!(ImageFormat::from_name("QCOW2"), ImageFormat::Qcow2);
+        assert_eq!(ImageFormat::TarGz.to_string(), "tar.gz");
+    }
+}
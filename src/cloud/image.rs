@@ -1,18 +1,75 @@
 use reqwest::Url;
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+use sha2::{Digest, Sha256};
 use std::fmt;
 
+use crate::cloud::{Arch, ArchNaming, ImageFormat, Variant};
+
 /// Supported checksum algorithms.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ChecksumKind {
+    Md5,
+    Sha1,
     Sha256,
     Sha512,
+    /// BLAKE2b with a 512-bit digest, as written by `b2sum`.
+    Blake2b,
 }
 
 impl ChecksumKind {
     pub fn as_str(&self) -> &'static str {
         match self {
+            ChecksumKind::Md5 => "md5",
+            ChecksumKind::Sha1 => "sha1",
             ChecksumKind::Sha256 => "sha256",
             ChecksumKind::Sha512 => "sha512",
+            ChecksumKind::Blake2b => "blake2b",
+        }
+    }
+
+    /// Inverse of [`ChecksumKind::as_str`], also accepting the spellings
+    /// used in BSD-style `ALGO (file) = hash` lines (`SHA256`, `SHA-256`,
+    /// `BLAKE2b-512`, ...).
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().replace('-', "").as_str() {
+            "md5" => Some(ChecksumKind::Md5),
+            "sha1" => Some(ChecksumKind::Sha1),
+            "sha256" => Some(ChecksumKind::Sha256),
+            "sha512" => Some(ChecksumKind::Sha512),
+            "blake2" | "blake2b" | "blake2b512" => Some(ChecksumKind::Blake2b),
+            _ => None,
+        }
+    }
+
+    /// Length of the hex encoded digest.
+    pub fn hex_len(&self) -> usize {
+        match self {
+            ChecksumKind::Md5 => 32,
+            ChecksumKind::Sha1 => 40,
+            ChecksumKind::Sha256 => 64,
+            ChecksumKind::Sha512 | ChecksumKind::Blake2b => 128,
+        }
+    }
+
+    /// Rank of the algorithm; a higher one is preferred for verification.
+    pub fn strength(&self) -> u8 {
+        match self {
+            ChecksumKind::Md5 => 0,
+            ChecksumKind::Sha1 => 1,
+            ChecksumKind::Sha256 => 2,
+            ChecksumKind::Sha512 | ChecksumKind::Blake2b => 3,
+        }
+    }
+
+    /// Guess the algorithm of an untagged digest from its length. 128 hex
+    /// digits are taken as SHA-512, by far the most common.
+    pub fn from_hex_len(len: usize) -> Option<Self> {
+        match len {
+            32 => Some(ChecksumKind::Md5),
+            40 => Some(ChecksumKind::Sha1),
+            64 => Some(ChecksumKind::Sha256),
+            128 => Some(ChecksumKind::Sha512),
+            _ => None,
         }
     }
 }
@@ -23,8 +80,23 @@ impl fmt::Display for ChecksumKind {
     }
 }
 
+impl Serialize for ChecksumKind {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+/// Accepts every spelling [`ChecksumKind::from_name`] does.
+impl<'de> Deserialize<'de> for ChecksumKind {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        ChecksumKind::from_name(&name)
+            .ok_or_else(|| de::Error::custom(format!("unknown checksum algorithm '{name}'")))
+    }
+}
+
 /// Convenience wrapper that couples the checksum value with its algorithm.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ImageChecksum {
     kind: ChecksumKind,
     value: String,
@@ -49,16 +121,105 @@ impl ImageChecksum {
 
 /// Normalised representation of a cloud image, regardless of the upstream
 /// repository format.
-#[derive(Debug, Clone)]
+///
+/// Serialises to a flat object with the fields below; `arch`, `variant`,
+/// `format` and the checksum `kind` are written as their lowercase names:
+///
+/// ```json
+/// {"os": "debian", "name": "bookworm", "distro_version": "12",
+///  "version": "20250210-2019", "arch": "amd64", "url": "https://...",
+///  "checksum": {"kind": "sha512", "value": "..."},
+///  "variant": "genericcloud", "format": "qcow2"}
+/// ```
+///
+/// `id` is [`Image::id`]; it is written but never read back, since it
+/// follows from the other fields. `size` is the size of the artifact in
+/// bytes, left out when unknown.
+/// `checksum` is the strongest digest known; when several are published,
+/// `checksums` lists all of them, strongest first.
+///
+/// `format` may be left out when deserialising; it is then taken from the
+/// URL like for images built by the providers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "ImageFields", into = "ImageFields")]
 pub struct Image {
     os: String,
     name: String,
     distro_version: String,
     version: String,
-    arch: String,
+    arch: Arch,
     url: String,
+    /// Strongest first.
+    checksums: Vec<ImageChecksum>,
+    variant: Variant,
+    format: ImageFormat,
+    size: Option<u64>,
+}
+
+/// Serialisation shape of [`Image`], with `format` optional.
+#[derive(Serialize, Deserialize)]
+struct ImageFields {
+    #[serde(default, skip_deserializing)]
+    id: String,
+    os: String,
+    name: String,
+    distro_version: String,
+    version: String,
+    arch: Arch,
+    url: String,
+    #[serde(default)]
     checksum: Option<ImageChecksum>,
-    image_type: String,
+    #[serde(default, skip_serializing_if = "has_one_at_most")]
+    checksums: Vec<ImageChecksum>,
+    variant: Variant,
+    #[serde(default)]
+    format: Option<ImageFormat>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+}
+
+fn has_one_at_most(checksums: &[ImageChecksum]) -> bool {
+    checksums.len() <= 1
+}
+
+impl From<ImageFields> for Image {
+    fn from(fields: ImageFields) -> Self {
+        let image = Image::new(
+            fields.os,
+            fields.name,
+            fields.distro_version,
+            fields.version,
+            fields.arch,
+            fields.url,
+            fields.checksum,
+            fields.variant,
+        )
+        .with_checksums(fields.checksums)
+        .with_size(fields.size);
+        match fields.format {
+            Some(format) => Self { format, ..image },
+            None => image,
+        }
+    }
+}
+
+impl From<Image> for ImageFields {
+    fn from(image: Image) -> Self {
+        Self {
+            id: image.id(),
+            checksum: image.checksums.first().cloned(),
+            checksums: image.checksums,
+            os: image.os,
+            name: image.name,
+            distro_version: image.distro_version,
+            version: image.version,
+            arch: image.arch,
+            url: image.url,
+            variant: image.variant,
+            format: Some(image.format),
+            size: image.size,
+        }
+    }
 }
 
 #[allow(unused)]
@@ -69,10 +230,10 @@ impl Image {
         name: String,
         distro_version: String,
         version: String,
-        arch: String,
+        arch: Arch,
         url: String,
         checksum: Option<ImageChecksum>,
-        image_type: String,
+        variant: Variant,
     ) -> Self {
         Self {
             os,
@@ -80,12 +241,35 @@ impl Image {
             distro_version,
             version,
             arch,
+            format: ImageFormat::from_file_name(&url),
             url,
-            checksum,
-            image_type,
+            checksums: checksum.into_iter().collect(),
+            variant,
+            size: None,
         }
     }
 
+    /// Stable identifier of the image: 32 hex digits hashed from its OS,
+    /// distro version, build, architecture, variant and URL. The same image
+    /// gets the same ID on every run and machine, so lockfiles and caches can
+    /// refer to it.
+    pub fn id(&self) -> String {
+        let mut hasher = Sha256::new();
+        for part in [
+            self.os.as_str(),
+            &self.distro_version,
+            &self.version,
+            self.arch.name(ArchNaming::Debian),
+            self.variant.as_str(),
+            &self.url,
+        ] {
+            hasher.update(part.as_bytes());
+            // Keeps `("ab", "c")` and `("a", "bc")` apart.
+            hasher.update([0]);
+        }
+        hex::encode(&hasher.finalize()[..16])
+    }
+
     /// OS
     /// eg. ubuntu
     pub fn os(&self) -> &str {
@@ -109,10 +293,14 @@ impl Image {
         &self.version
     }
 
-    // Architecture
-    // eg. amd64
-    pub fn arch(&self) -> &str {
-        &self.arch
+    /// Architecture
+    pub fn arch(&self) -> Arch {
+        self.arch
+    }
+
+    /// Architecture as the distro names it, e.g. `amd64` or `x86_64`.
+    pub fn arch_name(&self) -> &'static str {
+        self.arch.name(ArchNaming::for_os(&self.os))
     }
 
     // The url of the image
@@ -120,30 +308,75 @@ impl Image {
         &self.url
     }
 
+    /// The strongest checksum known, used to verify downloads.
     pub fn checksum(&self) -> Option<&ImageChecksum> {
-        self.checksum.as_ref()
+        self.checksums.first()
+    }
+
+    /// Every checksum known, strongest first.
+    pub fn checksums(&self) -> &[ImageChecksum] {
+        &self.checksums
     }
 
     pub fn checksum_value(&self) -> Option<&str> {
-        self.checksum.as_ref().map(|c| c.value())
+        self.checksum().map(|c| c.value())
     }
 
     pub fn checksum_kind(&self) -> Option<ChecksumKind> {
-        self.checksum.as_ref().map(|c| c.kind())
+        self.checksum().map(|c| c.kind())
     }
 
-    /// Convenience for existing callers expecting SHA256 (returns `None` if the
-    /// checksum is another algorithm).
+    /// The SHA256 checksum, if one is known.
     pub fn sha256(&self) -> Option<&str> {
-        match self.checksum_kind() {
-            Some(ChecksumKind::Sha256) => self.checksum_value(),
-            _ => None,
+        self.checksums
+            .iter()
+            .find(|c| c.kind() == ChecksumKind::Sha256)
+            .map(|c| c.value())
+    }
+
+    /// Add `checksums` to the known ones, keeping one per algorithm and the
+    /// strongest first.
+    pub fn with_checksums(mut self, checksums: impl IntoIterator<Item = ImageChecksum>) -> Self {
+        for checksum in checksums {
+            if !self.checksums.iter().any(|c| c.kind() == checksum.kind()) {
+                self.checksums.push(checksum);
+            }
         }
+        self.checksums
+            .sort_by_key(|c| std::cmp::Reverse(c.kind().strength()));
+        self
     }
 
-    // Sha256 hash for image validation
-    pub fn image_type(&self) -> &str {
-        &self.image_type
+    /// Size of the artifact in bytes, when the metadata tells.
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    /// The same image with `size` as its size, unless `size` is `None`.
+    pub fn with_size(self, size: Option<u64>) -> Self {
+        Self {
+            size: size.or(self.size),
+            ..self
+        }
+    }
+
+    /// Flavour of the image, e.g. `genericcloud`.
+    pub fn variant(&self) -> &Variant {
+        &self.variant
+    }
+
+    /// Disk format, from the file name of the artifact.
+    pub fn format(&self) -> &ImageFormat {
+        &self.format
+    }
+
+    /// The same image served from `url` and verified against `checksum`.
+    pub fn with_source(&self, url: String, checksum: Option<ImageChecksum>) -> Self {
+        Self {
+            url,
+            checksums: checksum.into_iter().collect(),
+            ..self.clone()
+        }
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -152,34 +385,38 @@ impl Image {
         release_name: &str,
         distro_version: &str,
         version: &str,
-        architecture: &str,
+        architecture: Arch,
         base_url: &str,
         relative_path: &str,
-        sha256: Option<String>,
-        image_type: String,
+        checksums: Vec<ImageChecksum>,
+        ftype: &str,
     ) -> Self {
-        // Simplestreams metadata may expose multiple checksum types, but the
-        // JSON files we consume currently only provide SHA256 values. Wrap the
-        // optional string into the strongly typed helper so callers can
-        // distinguish the hashing algorithm when more become available.
-        let checksum = sha256.map(|value| ImageChecksum::new(ChecksumKind::Sha256, value));
-
         // Try to build an absolute URL, fallback to string concatenation
         let absolute_url = Url::parse(base_url)
             .and_then(|base| base.join(relative_path))
             .map(|u| u.into())
             .unwrap_or_else(|_| format!("{}{}", base_url, relative_path));
 
+        // Simplestreams `ftype`s combine a variant and a format (`disk1.img`,
+        // `lxd.tar.xz`); the part before the format is the variant.
+        let format = ImageFormat::from_file_name(relative_path);
+        let variant = ftype
+            .strip_suffix(format.as_str())
+            .map(|v| v.trim_end_matches('.'))
+            .filter(|v| !v.is_empty())
+            .unwrap_or(ftype);
+
         Image::new(
             os_name,
             release_name.to_string(),
             distro_version.to_string(),
             version.to_string(),
-            architecture.to_string(),
+            architecture,
             absolute_url,
-            checksum,
-            image_type,
+            None,
+            Variant::from_name(variant),
         )
+        .with_checksums(checksums)
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -188,10 +425,10 @@ impl Image {
         name: String,
         distro_version: String,
         version: String,
-        arch: String,
+        arch: Arch,
         url: String,
         checksum: Option<ImageChecksum>,
-        image_type: String,
+        variant: Variant,
     ) -> Self {
         Image::new(
             os,
@@ -201,7 +438,129 @@ impl Image {
             arch,
             url,
             checksum,
-            image_type,
+            variant,
         )
     }
 }
+
+/// The Debian bookworm `genericcloud` build the tests describe; the test-only
+/// `with_*` methods below adjust it where a test needs something else.
+#[cfg(any(test, feature = "test-util"))]
+pub fn sample_image() -> Image {
+    Image::new(
+        "debian".to_string(),
+        "bookworm".to_string(),
+        "12".to_string(),
+        "20250210-2019".to_string(),
+        Arch::Amd64,
+        "https://cloud.debian.org/images/cloud/bookworm/20250210-2019/debian-12-genericcloud-amd64-20250210-2019.qcow2".to_string(),
+        None,
+        Variant::GenericCloud,
+    )
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Image {
+    /// The same image served from `url`, its format following the file name.
+    pub fn with_url(self, url: impl Into<String>) -> Self {
+        let url = url.into();
+        Self {
+            format: ImageFormat::from_file_name(&url),
+            url,
+            ..self
+        }
+    }
+
+    /// The same image as build `version`.
+    pub fn with_version(self, version: &str) -> Self {
+        Self {
+            version: version.to_string(),
+            ..self
+        }
+    }
+
+    /// The same image as part of release `name` (`distro_version`) of `os`.
+    pub fn with_release(self, os: &str, name: &str, distro_version: &str) -> Self {
+        Self {
+            os: os.to_string(),
+            name: name.to_string(),
+            distro_version: distro_version.to_string(),
+            ..self
+        }
+    }
+
+    /// The same image built for `arch`.
+    pub fn with_arch(self, arch: Arch) -> Self {
+        Self { arch, ..self }
+    }
+
+    /// The same image as flavour `variant`.
+    pub fn with_variant(self, variant: Variant) -> Self {
+        Self { variant, ..self }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChecksumKind, Image, ImageChecksum};
+    use crate::cloud::{Arch, ImageFormat, Variant};
+
+    #[test]
+    fn round_trips_through_json() {
+        let image = Image::new(
+            "almalinux".to_string(),
+            "AlmaLinux 9".to_string(),
+            "9.4".to_string(),
+            "20240507".to_string(),
+            Arch::Arm64,
+            "https://example.invalid/AlmaLinux-9-GenericCloud-9.4-20240507.aarch64.qcow2"
+                .to_string(),
+            Some(ImageChecksum::new(ChecksumKind::Sha256, "ab12")),
+            Variant::GenericCloud,
+        );
+        let json = serde_json::to_value(&image).unwrap();
+        assert_eq!(json["arch"], "arm64");
+        assert_eq!(json["variant"], "genericcloud");
+        assert_eq!(json["format"], "qcow2");
+        assert_eq!(json["checksum"]["kind"], "sha256");
+        assert_eq!(json["id"], image.id());
+        assert_eq!(image.id().len(), 32);
+        assert_eq!(image.clone().with_size(Some(1)).id(), image.id());
+        assert_ne!(
+            image
+                .with_source("https://mirror.invalid/x.qcow2".to_string(), None)
+                .id(),
+            image.id()
+        );
+        assert_eq!(serde_json::from_value::<Image>(json).unwrap(), image);
+
+        // `format` is optional and `arch` accepts the RPM spelling.
+        let minimal: Image = serde_json::from_str(
+            r#"{"os": "debian", "name": "bookworm", "distro_version": "12",
+                "version": "latest", "arch": "x86_64", "variant": "nocloud",
+                "url": "https://example.invalid/debian-12-nocloud-amd64.raw"}"#,
+        )
+        .unwrap();
+        assert_eq!(minimal.format(), &ImageFormat::Raw);
+        assert_eq!(minimal.arch(), Arch::Amd64);
+        assert!(minimal.checksum().is_none());
+
+        // The strongest of several checksums is the one verified.
+        let image = image.with_checksums([
+            ImageChecksum::new(ChecksumKind::Md5, "cd34"),
+            ImageChecksum::new(ChecksumKind::Sha512, "ef56"),
+        ]);
+        assert_eq!(image.checksum_kind(), Some(ChecksumKind::Sha512));
+        assert_eq!(image.sha256(), Some("ab12"));
+        let json = serde_json::to_value(&image).unwrap();
+        assert_eq!(json["checksum"]["kind"], "sha512");
+        assert_eq!(json["checksums"].as_array().unwrap().len(), 3);
+        assert!(json.get("size").is_none());
+        assert_eq!(serde_json::from_value::<Image>(json).unwrap(), image);
+
+        let image = image.with_size(Some(1024));
+        let json = serde_json::to_value(&image).unwrap();
+        assert_eq!(json["size"], 1024);
+        assert_eq!(serde_json::from_value::<Image>(json).unwrap(), image);
+    }
+}
@@ -1,12 +1,21 @@
 use serde::Deserialize;
 
+use crate::cloud::{ChecksumKind, ImageChecksum};
+
 /// Lowest-level Simplestreams entry that represents a single artifact on disk.
 #[derive(Debug, Deserialize)]
 pub struct Item {
     #[serde(default)]
     pub path: Option<String>,
     #[serde(default)]
+    md5: Option<String>,
+    #[serde(default)]
     sha256: Option<String>,
+    #[serde(default)]
+    sha512: Option<String>,
+    /// Size of the artifact in bytes.
+    #[serde(default)]
+    size: Option<u64>,
     // ftype exists but we won’t rely on it; keep optional for completeness
     #[serde(default)]
     ftype: Option<String>,
@@ -22,8 +31,42 @@ impl Item {
         &self.sha256
     }
 
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    /// Every digest published for the artifact, strongest first.
+    pub fn checksums(&self) -> Vec<ImageChecksum> {
+        [
+            (ChecksumKind::Sha512, &self.sha512),
+            (ChecksumKind::Sha256, &self.sha256),
+            (ChecksumKind::Md5, &self.md5),
+        ]
+        .into_iter()
+        .filter_map(|(kind, value)| value.as_ref().map(|v| ImageChecksum::new(kind, v.clone())))
+        .collect()
+    }
+
     #[allow(dead_code)]
     pub fn ftype(&self) -> &Option<String> {
         &self.ftype
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Item;
+    use crate::cloud::ChecksumKind;
+
+    #[test]
+    fn lists_every_checksum_strongest_first() {
+        let item: Item = serde_json::from_str(
+            r#"{"ftype": "disk1.img", "path": "server/noble/x.img", "size": 1024,
+                "md5": "d41d8cd98f00b204e9800998ecf8427e", "sha256": "ab12"}"#,
+        )
+        .unwrap();
+        let kinds: Vec<ChecksumKind> = item.checksums().iter().map(|c| c.kind()).collect();
+        assert_eq!(kinds, [ChecksumKind::Sha256, ChecksumKind::Md5]);
+        assert_eq!(item.size(), Some(1024));
+    }
+}
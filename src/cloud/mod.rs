@@ -1,11 +1,25 @@
+mod arch;
+mod build_id;
 mod catalog;
+mod format;
 mod image;
 mod item;
 mod product;
+mod stream_index;
+mod variant;
 mod version;
+mod version_key;
 
+pub use arch::{Arch, ArchNaming};
+pub use build_id::{BuildId, sort_builds_newest_first};
 pub use catalog::Catalog;
+pub use format::ImageFormat;
+#[cfg(any(test, feature = "test-util"))]
+pub use image::sample_image;
 pub use image::{ChecksumKind, Image, ImageChecksum};
 pub use item::Item;
 pub use product::Product;
+pub use stream_index::{IMAGE_DOWNLOADS, StreamEntry, StreamIndex};
+pub use variant::Variant;
 pub use version::Version;
+pub use version_key::{VersionKey, sort_newest_first};
@@ -0,0 +1,103 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Datatype of the Simplestreams products listing downloadable images.
+pub const IMAGE_DOWNLOADS: &str = "image-downloads";
+
+/// The `streams/v1/index.json` of a Simplestreams mirror: which products
+/// files it publishes, keyed by their content id.
+#[derive(Debug, Deserialize)]
+pub struct StreamIndex {
+    #[serde(default)]
+    index: HashMap<String, StreamEntry>,
+}
+
+/// One products file announced by a [`StreamIndex`].
+#[derive(Debug, Deserialize)]
+pub struct StreamEntry {
+    datatype: String,
+    /// Location of the products file, relative to the mirror root.
+    path: String,
+    #[serde(default)]
+    format: Option<String>,
+}
+
+impl StreamEntry {
+    pub fn datatype(&self) -> &str {
+        &self.datatype
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl StreamIndex {
+    /// The products file called `content_id`, or without one the
+    /// `image-downloads` products file of the stream (e.g.
+    /// `com.ubuntu.cloud:released:download`). Returns the content id along
+    /// with the entry.
+    pub fn products(&self, content_id: Option<&str>) -> Option<(&str, &StreamEntry)> {
+        if let Some(id) = content_id {
+            return self.index.get_key_value(id).map(|(k, e)| (k.as_str(), e));
+        }
+        let mut candidates: Vec<(&String, &StreamEntry)> = self
+            .index
+            .iter()
+            .filter(|(_, e)| e.datatype == IMAGE_DOWNLOADS)
+            .filter(|(_, e)| {
+                e.format
+                    .as_deref()
+                    .is_none_or(|f| f.starts_with("products:"))
+            })
+            .collect();
+        // Prefer the plain `:download` stream over any sibling download
+        // streams and pick deterministically between the rest.
+        candidates.sort_by_key(|(id, _)| (!id.ends_with(":download"), id.as_str()));
+        candidates
+            .into_iter()
+            .next()
+            .map(|(id, entry)| (id.as_str(), entry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StreamIndex;
+
+    #[test]
+    fn finds_the_image_downloads_products() {
+        let index: StreamIndex = serde_json::from_str(
+            r#"{
+                "format": "index:1.0",
+                "index": {
+                    "com.ubuntu.cloud:released:aws": {
+                        "datatype": "image-ids",
+                        "format": "products:1.0",
+                        "path": "streams/v1/com.ubuntu.cloud:released:aws.json"
+                    },
+                    "com.ubuntu.cloud:released:download": {
+                        "datatype": "image-downloads",
+                        "format": "products:1.0",
+                        "path": "streams/v1/com.ubuntu.cloud:released:download.json",
+                        "products": ["com.ubuntu.cloud:server:24.04:amd64"]
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let (id, entry) = index.products(None).unwrap();
+        assert_eq!(id, "com.ubuntu.cloud:released:download");
+        assert_eq!(
+            entry.path(),
+            "streams/v1/com.ubuntu.cloud:released:download.json"
+        );
+
+        let (_, aws) = index
+            .products(Some("com.ubuntu.cloud:released:aws"))
+            .unwrap();
+        assert_eq!(aws.datatype(), "image-ids");
+        assert!(index.products(Some("com.example:missing")).is_none());
+    }
+}
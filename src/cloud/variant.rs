@@ -0,0 +1,71 @@
+use std::fmt;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Flavour of a cloud image: which cloud or boot environment it targets.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Variant {
+    /// Debian `genericcloud`, AlmaLinux `GenericCloud`.
+    GenericCloud,
+    /// Debian `nocloud`: no cloud-init datasource, root login allowed.
+    NoCloud,
+    /// Debian `generic`: bare metal and VMs with all drivers.
+    Generic,
+    Minimal,
+    /// Anything else, as published (Ubuntu `disk1`, AlmaLinux `OCI`, ...).
+    Other(String),
+}
+
+impl Variant {
+    /// Variant named `name`, ignoring case for the known ones.
+    pub fn from_name(name: &str) -> Self {
+        match name.to_ascii_lowercase().as_str() {
+            "genericcloud" => Variant::GenericCloud,
+            "nocloud" => Variant::NoCloud,
+            "generic" => Variant::Generic,
+            "minimal" => Variant::Minimal,
+            _ => Variant::Other(name.to_string()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Variant::GenericCloud => "genericcloud",
+            Variant::NoCloud => "nocloud",
+            Variant::Generic => "generic",
+            Variant::Minimal => "minimal",
+            Variant::Other(name) => name,
+        }
+    }
+}
+
+impl fmt::Display for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Variant {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Variant {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Variant::from_name(&String::deserialize(deserializer)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Variant;
+
+    #[test]
+    fn known_variants_are_case_insensitive() {
+        assert_eq!(Variant::from_name("GenericCloud"), Variant::GenericCloud);
+        assert_eq!(Variant::from_name("genericcloud"), Variant::GenericCloud);
+        assert_eq!(Variant::from_name("OCI"), Variant::Other("OCI".to_string()));
+        assert_eq!(Variant::NoCloud.to_string(), "nocloud");
+    }
+}
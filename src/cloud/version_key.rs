@@ -0,0 +1,125 @@
+use std::cmp::Ordering;
+
+/// One run of a version string: digits compare by value, anything else as
+/// text. The variant order makes text sort below numbers at the same
+/// position, so `9.4-rc` < `9.4.1`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Part {
+    Text(String),
+    /// Digits without leading zeros, compared by length first so arbitrarily
+    /// long runs (dates, serials) never overflow.
+    Number(String),
+}
+
+/// Sort key for release versions and build identifiers.
+///
+/// Numeric runs compare by value, so `9.10` sorts after `9.9` and
+/// `20250101.1` after `20250101`. Dated builds (`20241013-1744`) and Ubuntu
+/// serials order chronologically, and the `latest` alias sorts above every
+/// concrete build.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionKey {
+    latest: bool,
+    parts: Vec<Part>,
+}
+
+impl VersionKey {
+    pub fn new(version: &str) -> Self {
+        let version = version.trim();
+        let mut parts = Vec::new();
+        let mut rest = version;
+        while let Some(first) = rest.chars().next() {
+            let is_digit = first.is_ascii_digit();
+            let end = rest
+                .find(|c: char| c.is_ascii_digit() != is_digit)
+                .unwrap_or(rest.len());
+            let (run, tail) = rest.split_at(end);
+            if is_digit {
+                let digits = run.trim_start_matches('0');
+                parts.push(Part::Number(digits.to_string()));
+            } else {
+                // Separators only delimit the numbers around them.
+                let text = run.trim_matches(|c: char| matches!(c, '.' | '-' | '_' | '+' | '~'));
+                if !text.is_empty() {
+                    parts.push(Part::Text(text.to_ascii_lowercase()));
+                }
+            }
+            rest = tail;
+        }
+        Self {
+            latest: version.eq_ignore_ascii_case("latest"),
+            parts,
+        }
+    }
+}
+
+impl Ord for VersionKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.latest.cmp(&other.latest).then_with(|| {
+            for (a, b) in self.parts.iter().zip(&other.parts) {
+                let ordering = match (a, b) {
+                    (Part::Number(a), Part::Number(b)) => a.len().cmp(&b.len()).then(a.cmp(b)),
+                    _ => a.cmp(b),
+                };
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            self.parts.len().cmp(&other.parts.len())
+        })
+    }
+}
+
+impl PartialOrd for VersionKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Sort `versions` newest first by [`VersionKey`] and drop duplicates.
+pub fn sort_newest_first<S: AsRef<str>>(versions: &mut Vec<S>) {
+    versions.sort_by_cached_key(|v| std::cmp::Reverse(VersionKey::new(v.as_ref())));
+    versions.dedup_by(|a, b| a.as_ref() == b.as_ref());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{VersionKey, sort_newest_first};
+
+    #[test]
+    fn numbers_compare_by_value() {
+        assert!(VersionKey::new("9.10") > VersionKey::new("9.9"));
+        assert!(VersionKey::new("10") > VersionKey::new("9"));
+        assert!(VersionKey::new("24.04") > VersionKey::new("22.10"));
+        assert!(VersionKey::new("9.4.1") > VersionKey::new("9.4"));
+        assert!(VersionKey::new("9.4.1") > VersionKey::new("9.4-rc"));
+        assert_eq!(
+            VersionKey::new("009"),
+            VersionKey::new("9"),
+            "leading zeros do not matter"
+        );
+    }
+
+    #[test]
+    fn builds_order_chronologically_below_latest() {
+        let mut builds = vec![
+            "20241013-1744",
+            "latest",
+            "20250101.1",
+            "20250101",
+            "20241013-0900",
+            "20250101",
+        ];
+        sort_newest_first(&mut builds);
+        assert_eq!(
+            builds,
+            [
+                "latest",
+                "20250101.1",
+                "20250101",
+                "20241013-1744",
+                "20241013-0900"
+            ]
+        );
+    }
+}
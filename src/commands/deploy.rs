@@ -0,0 +1,202 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+
+use cloud_images_downloader::{
+    Image,
+    helpers::{
+        customize, decompress, ignition,
+        image_resolver::{self as resolver, DownloadOptions},
+        incus, libvirt,
+        openstack::Cloud,
+        qemu_img::{self, DiskFormat},
+        seed::{self, Seed, SeedFormat},
+        vm::{self, VmOptions},
+    },
+};
+
+use super::{
+    incus as incus_command, libvirt as libvirt_command, openstack, proxmox, registry, run,
+};
+use crate::cli::{Cli, Command, IncusCommand, OpenstackCommand};
+use crate::config::Config;
+
+/// Fail before resolving anything when the hypervisor the command deploys to
+/// cannot take the image.
+pub fn check(cli: &Cli, config: &Config) -> Result<()> {
+    if cli.manifest.is_some() {
+        bail!("deploying handles a single image; drop --manifest");
+    }
+    qemu_img::locate().map_err(anyhow::Error::msg)?;
+    match &cli.command {
+        Some(Command::Libvirt { .. }) => {
+            libvirt::locate().map_err(anyhow::Error::msg)?;
+        }
+        Some(Command::Proxmox { .. }) => {
+            if cli.with_seed.is_some() {
+                bail!("Proxmox builds its own cloud-init drive; drop --with-seed");
+            }
+            config.proxmox().settings()?;
+        }
+        Some(Command::Incus {
+            action: IncusCommand::Import { client, .. },
+        }) => {
+            if cli.with_seed.is_some() {
+                bail!("Incus takes user-data from the instance config; drop --with-seed");
+            }
+            incus::locate(client.as_deref()).map_err(anyhow::Error::msg)?;
+        }
+        Some(Command::Containerdisk { .. }) if cli.with_seed.is_some() => {
+            bail!(
+                "KubeVirt takes user-data from the VM's cloudInitNoCloud volume; drop --with-seed"
+            );
+        }
+        Some(Command::Openstack {
+            action: OpenstackCommand::Upload { cloud, .. },
+        }) => {
+            if cli.with_seed.is_some() {
+                bail!("OpenStack passes user-data at boot; drop --with-seed");
+            }
+            Cloud::load(cloud.as_deref()).map_err(anyhow::Error::msg)?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Fail before downloading `image` when it cannot be deployed as asked:
+/// `run` needs a QEMU for its architecture, and CoreOS-like images take an
+/// Ignition config instead of a cloud-init seed.
+pub fn check_image(cli: &Cli, image: &Image) -> Result<()> {
+    if let Some(Command::Run { .. }) = &cli.command {
+        vm::locate(image.arch()).map_err(anyhow::Error::msg)?;
+    }
+    ignition::check_provisioning(image.os(), cli.with_seed.is_some(), cli.ignition.is_some())
+        .map_err(anyhow::Error::msg)
+}
+
+/// Hand `disk`, the download of `image`, to the hypervisor the command
+/// names, growing it to `size` where the hypervisor allows.
+pub async fn deploy(
+    cli: &Cli,
+    config: &Config,
+    image: &Image,
+    disk: &Path,
+    size: Option<&str>,
+    options: &DownloadOptions,
+) -> Result<()> {
+    let seed = cli
+        .with_seed
+        .clone()
+        .map(|user_data| cli.seed(user_data, SeedFormat::default()));
+    match &cli.command {
+        Some(Command::Run {
+            ssh_key,
+            memory,
+            cpus,
+            ssh_port,
+            keep,
+        }) => {
+            let vm = VmOptions {
+                memory: memory.clone(),
+                cpus: *cpus,
+                ssh_port: *ssh_port,
+            };
+            run::run(
+                image,
+                disk,
+                size,
+                seed,
+                cli.ignition.as_deref(),
+                ssh_key.as_deref(),
+                &vm,
+                *keep,
+            )
+        }
+        Some(Command::Libvirt { action }) => {
+            libvirt_command::create(image, disk, size, seed, cli.ignition.as_deref(), action)
+        }
+        Some(Command::Proxmox { action }) => {
+            proxmox::create(image, disk, size, action, config).await
+        }
+        Some(Command::Incus { action }) => incus_command::import(image, disk, size, action),
+        Some(Command::Containerdisk {
+            reference,
+            plain_http,
+        }) => registry::containerdisk(image, disk, size, reference, *plain_http).await,
+        Some(Command::Openstack { action }) => {
+            openstack::upload(image, disk, size, options, action).await
+        }
+        _ => Ok(()),
+    }
+}
+
+/// `seed`: build a NoCloud seed from `user_data` and the `--seed-*` flags.
+pub fn seed(cli: &Cli, user_data: &Path, output: &Path) -> Result<()> {
+    let seed = cli.seed(user_data.to_path_buf(), SeedFormat::for_path(output));
+    seed::locate(seed.format).map_err(anyhow::Error::msg)?;
+    seed::build(output, &seed, "cloud").map_err(anyhow::Error::msg)?;
+    println!("Wrote the NoCloud seed {}", output.display());
+    Ok(())
+}
+
+/// `ignition`: transpile the Butane config `butane` to `output`.
+pub fn ignition(butane: &Path, output: &Path) -> Result<()> {
+    ignition::build(butane, output).map_err(anyhow::Error::msg)?;
+    println!("Wrote the Ignition config {}", output.display());
+    Ok(())
+}
+
+/// The bootable file `download` left for `image` in `dest_dir`: the
+/// decompressed and, with `--convert-to`, converted image.
+pub fn downloaded_disk(
+    image: &Image,
+    dest_dir: &Path,
+    options: &DownloadOptions,
+) -> Result<PathBuf> {
+    let disk = resolver::output_path(image, dest_dir)?;
+    let disk = decompress::decompress_file(&disk)
+        .map_err(anyhow::Error::msg)?
+        .unwrap_or(disk);
+    let disk = match options.convert_to {
+        Some(format) => qemu_img::converted_path(&disk, format),
+        None => disk,
+    };
+    Ok(match options.customize {
+        Some(_) => customize::path_for(&disk),
+        None => disk,
+    })
+}
+
+/// `disk` if it is qcow2 already, else a qcow2 conversion next to it.
+pub fn qcow2_disk(disk: &Path) -> Result<PathBuf> {
+    if qemu_img::info(disk).map_err(anyhow::Error::msg)?.format == "qcow2" {
+        Ok(disk.to_path_buf())
+    } else {
+        qemu_img::convert(disk, DiskFormat::Qcow2).map_err(anyhow::Error::msg)
+    }
+}
+
+/// Write `user-data` authorising `ssh_key` (or the user's default key) to
+/// `user_data` and describe a seed around it, as an ISO when a tool to
+/// build one is installed and a FAT image otherwise.
+pub fn default_seed(user_data: PathBuf, ssh_key: Option<&Path>) -> Result<Seed> {
+    let key = vm::ssh_key(ssh_key).map_err(anyhow::Error::msg)?;
+    if key.is_none() {
+        eprintln!("Warning: no SSH public key found; only the console will be usable");
+    }
+    std::fs::write(&user_data, vm::user_data(key.as_deref()))
+        .with_context(|| format!("write {}", user_data.display()))?;
+    let format = if seed::locate(SeedFormat::Iso).is_ok() {
+        SeedFormat::Iso
+    } else {
+        SeedFormat::Vfat
+    };
+    Ok(Seed {
+        user_data,
+        meta_data: None,
+        network_config: None,
+        hostname: None,
+        format,
+    })
+}
@@ -0,0 +1,505 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, bail};
+
+use cloud_images_downloader::{
+    DEFAULT_TRACK, Image, Selection, download, find,
+    helpers::{
+        customize,
+        image_resolver::{
+            self as resolver, BatchItem, DownloadOptions, OnMismatch, destination_dir,
+            download_batch,
+            external::{self, Downloader},
+        },
+        library::Library,
+        qemu_img, report,
+        seed::{self, SeedFormat},
+        throttle::{RateLimiter, parse_rate},
+    },
+    repositories::{self as repos, ImageQuery},
+};
+
+use super::{deploy, download_root, explain, export, library::auto_gc, registry, report_selection};
+use crate::cli::{Cli, Command};
+use crate::config::Config;
+use crate::lockfile::{self, LockedImage, Lockfile};
+use crate::manifest::{self, Batch};
+use crate::queue::{Entry, Queue};
+
+/// What the downloading commands share, assembled from the flags and the
+/// config file.
+pub struct Downloads {
+    pub options: DownloadOptions,
+    /// The size deploying commands grow their disk to; they take `--resize`
+    /// themselves instead of resizing a copy of the image.
+    pub disk_size: Option<String>,
+    pub root: PathBuf,
+    pub flat: bool,
+    pub jobs: usize,
+    pub max_builds: Option<usize>,
+    /// The `--manifest` entries, when one was given.
+    pub batch: Option<Batch>,
+    pub queue: Queue,
+}
+
+impl Downloads {
+    /// Assemble the download settings, failing before anything is downloaded
+    /// when the transfer or the post-processing cannot run.
+    pub fn new(cli: &Cli, config: &Config) -> Result<Self> {
+        let (root, flat) = download_root(cli, config);
+        let rate_limit = cli
+            .limit_rate
+            .as_deref()
+            .or(config.limit_rate())
+            .map(parse_rate)
+            .transpose()?
+            .map(RateLimiter::new);
+        let batch = cli.manifest.as_deref().map(manifest::load).transpose()?;
+        let customization = batch
+            .as_ref()
+            .map(|batch| batch.hooks.clone())
+            .unwrap_or_default()
+            .merge(cli.customization());
+        let options = DownloadOptions {
+            connections: cli.connections.or(config.connections()).unwrap_or(1),
+            existing: cli.existing_file(),
+            rate_limit,
+            zsync: !cli.no_zsync && config.zsync(),
+            decompress: cli.decompress || config.decompress(),
+            convert_to: cli.convert_to.or(config.convert_to()),
+            customize: Some(customization).filter(|c| !c.is_empty()),
+            resize: cli.resize.clone().or(config.resize().map(str::to_string)),
+            // Deploying commands attach their own seed.
+            seed: cli
+                .with_seed
+                .clone()
+                .filter(|_| !cli.deploys())
+                .map(|user_data| cli.seed(user_data, SeedFormat::default())),
+            downloader: cli.downloader.or(config.downloader()).unwrap_or_default(),
+            metalink: cli.metalink || config.metalink(),
+            torrent: cli.torrent || config.torrent(),
+            quarantine_dir: cli.quarantine_dir.clone().or(config.quarantine_dir()),
+            xattrs: cli.xattrs || config.xattrs(),
+            // Pushed images carry their provenance statement.
+            provenance: cli.provenance
+                || config.provenance()
+                || matches!(cli.command, Some(Command::Push { .. })),
+            upload: match &cli.upload {
+                Some(prefix) => Some(prefix.clone()),
+                None => config.upload()?,
+            },
+            library: if cli.library || cli.offline || config.library() {
+                Some(Library::open_default().map_err(anyhow::Error::msg)?)
+            } else {
+                None
+            },
+            on_mismatch: cli
+                .on_mismatch
+                .or(config.on_mismatch())
+                .unwrap_or(OnMismatch::Ask),
+            notifier: config.notifier(cli.notify, cli.notify_after),
+            progress: cli
+                .progress
+                .or(config.progress())
+                .unwrap_or_default()
+                .sink(),
+            ..DownloadOptions::default()
+        };
+
+        external::locate(options.downloader).map_err(anyhow::Error::msg)?;
+        if options.torrent && options.downloader != Downloader::Aria2c {
+            bail!("--torrent needs `--downloader aria2c`");
+        }
+        if let Some(size) = &options.resize {
+            qemu_img::validate_size(size).map_err(anyhow::Error::msg)?;
+        }
+        if options.convert_to.is_some() || options.resize.is_some() {
+            qemu_img::locate().map_err(anyhow::Error::msg)?;
+        }
+        if let Some(customization) = &options.customize {
+            customization.validate().map_err(anyhow::Error::msg)?;
+            customize::locate(customization).map_err(anyhow::Error::msg)?;
+        }
+        if let Some(seed) = &options.seed {
+            seed::locate(seed.format).map_err(anyhow::Error::msg)?;
+        }
+        if matches!(cli.command, Some(Command::Push { .. })) && cli.manifest.is_some() {
+            bail!("push handles a single image; drop --manifest");
+        }
+        if cli.ignition.is_some()
+            && !matches!(
+                cli.command,
+                Some(Command::Run { .. } | Command::Libvirt { .. })
+            )
+        {
+            bail!("--ignition only applies to run and libvirt create");
+        }
+        if matches!(cli.command, Some(Command::Export { .. })) && cli.manifest.is_some() {
+            bail!("export describes a single image; drop --manifest");
+        }
+        // Deploying commands grow their own disk instead of resizing a copy
+        // of the image, and hypervisors cannot boot compressed files.
+        let (options, disk_size) = if cli.deploys() {
+            deploy::check(cli, config)?;
+            let options = DownloadOptions {
+                decompress: true,
+                resize: None,
+                ..options
+            };
+            (
+                options,
+                cli.resize.clone().or(config.resize().map(str::to_string)),
+            )
+        } else {
+            (options, None)
+        };
+
+        Ok(Self {
+            options,
+            disk_size,
+            root,
+            flat,
+            jobs: cli.jobs.or(config.jobs()).unwrap_or(3),
+            max_builds: cli.max_builds.or(config.max_builds()),
+            batch,
+            queue: Queue::load()?,
+        })
+    }
+
+    /// Cancel the downloads on Ctrl-C or `SIGTERM`, which removes their
+    /// partial files; a second signal exits at once. Not for `serve`, which
+    /// stops on the first one.
+    pub fn cancel_on_signal(&self) {
+        let cancel = self.options.cancel.clone();
+        tokio::spawn(async move {
+            shutdown_signal().await;
+            eprintln!("Cancelling the downloads; press Ctrl-C again to exit at once");
+            cancel.cancel();
+            shutdown_signal().await;
+            std::process::exit(130);
+        });
+    }
+
+    /// Resolve `query`, printing the trace (with `--explain`) and the
+    /// selection.
+    async fn select(&self, cli: &Cli, query: &ImageQuery) -> Result<Selection> {
+        let selection = super::select_image(
+            DEFAULT_TRACK,
+            query,
+            self.max_builds,
+            &self.options.cancel,
+            cli.offline,
+        )
+        .await;
+        explain(cli.explain);
+        let selection = selection?;
+        report_selection(cli.json, &selection)?;
+        Ok(selection)
+    }
+}
+
+/// Resolve on Ctrl-C, or on `SIGTERM` where there is one.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+            return;
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// `verify`: re-hash every image against the report written next to it.
+pub async fn verify(images: &[PathBuf]) -> Result<()> {
+    let mut failed = 0;
+    for image in images {
+        let path = image.clone();
+        match tokio::task::spawn_blocking(move || report::verify(&path)).await? {
+            Ok(report) => println!(
+                "{}: OK ({} {}{})",
+                image.display(),
+                report.algorithm,
+                report.digest,
+                if report.verified {
+                    ""
+                } else {
+                    ", not published upstream"
+                }
+            ),
+            Err(err) => {
+                eprintln!("{}: FAILED ({err})", image.display());
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("{failed} of {} image(s) failed verification", images.len());
+    }
+    Ok(())
+}
+
+/// Download the queued `entries` as one batch and drop the finished ones from
+/// the queue. Returns the number of failed downloads.
+async fn download_queued(
+    queue: &mut Queue,
+    entries: &[Entry],
+    options: &DownloadOptions,
+    jobs: usize,
+) -> Result<usize> {
+    let items: Vec<BatchItem> = entries.iter().map(Entry::item).collect();
+    let results = download_batch(&items, options, jobs).await;
+    let mut failed = 0;
+    for (entry, result) in entries.iter().zip(results) {
+        match result {
+            Ok(msg) => {
+                println!("{msg}");
+                queue.remove(entry)?;
+            }
+            Err(err) => {
+                eprintln!("{err}");
+                failed += 1;
+            }
+        }
+    }
+    Ok(failed)
+}
+
+/// Queue `images` and download them as one batch, failing if any download
+/// did not complete.
+async fn download_images(downloads: &mut Downloads, images: Vec<Image>) -> Result<()> {
+    let entries: Vec<Entry> = images
+        .into_iter()
+        .map(|image| {
+            let item = BatchItem {
+                dest_dir: destination_dir(&downloads.root, &image, downloads.flat),
+                mirrors: repos::mirror_urls(image.url()),
+                image,
+            };
+            Entry::new(&item, downloads.options.connections)
+        })
+        .collect();
+    for entry in &entries {
+        downloads.queue.add(entry.clone())?;
+    }
+    let failed = download_queued(
+        &mut downloads.queue,
+        &entries,
+        &downloads.options,
+        downloads.jobs,
+    )
+    .await?;
+    if failed > 0 {
+        bail!(
+            "{failed} of {} downloads failed; run `resume` to retry",
+            entries.len()
+        );
+    }
+    Ok(())
+}
+
+/// Pin `selections` in the lockfile at `path`.
+fn write_lockfile(path: &Path, selections: &[Selection]) -> Result<()> {
+    for selection in selections {
+        if selection.image.checksum().is_none() {
+            eprintln!(
+                "Warning: {} has no published checksum; `install --locked` cannot detect changes to it",
+                selection.image.url()
+            );
+        }
+    }
+    Lockfile::new(selections.iter().map(LockedImage::new).collect()).save(path)?;
+    println!("Locked {} image(s) in {}", selections.len(), path.display());
+    Ok(())
+}
+
+/// `lock`, and `install` without `--locked`: pin the manifest entries (or
+/// the image selected by the flags) in `path`, then download them when
+/// `install` is set.
+pub async fn lock(cli: &Cli, config: &Config, path: &Path, install: bool) -> Result<()> {
+    let mut downloads = Downloads::new(cli, config)?;
+    downloads.cancel_on_signal();
+    let queries = match downloads.batch.take() {
+        Some(batch) => batch.queries,
+        None => vec![cli.image_query()],
+    };
+    let mut selections = Vec::new();
+    for query in queries {
+        selections.push(downloads.select(cli, &query).await?);
+    }
+    write_lockfile(path, &selections)?;
+    if !install {
+        return Ok(());
+    }
+    let images = selections.into_iter().map(|s| s.image).collect();
+    download_images(&mut downloads, images).await?;
+    auto_gc(config)
+}
+
+/// `install --locked`: check every pinned image against what upstream
+/// publishes now and return the pinned images. Any artifact that vanished
+/// or whose checksum changed fails the whole install. `offline` skips the
+/// check and trusts the pins.
+async fn locked_images(path: &Path, offline: bool) -> Result<Vec<Image>> {
+    let lockfile = Lockfile::load(path)?;
+    let mut drifted = Vec::new();
+    for locked in lockfile.images().iter().filter(|_| !offline) {
+        let current = find(&locked.query()).await?;
+        if let Some(reason) = lockfile::drift(locked, &current) {
+            drifted.push(reason);
+        }
+    }
+    if !drifted.is_empty() {
+        bail!(
+            "{} no longer matches upstream:\n  {}",
+            path.display(),
+            drifted.join("\n  ")
+        );
+    }
+    Ok(lockfile
+        .images()
+        .iter()
+        .map(|locked| locked.image.clone())
+        .collect())
+}
+
+/// `install --locked`: download exactly the images pinned in `path`.
+pub async fn install_locked(cli: &Cli, config: &Config, path: &Path) -> Result<()> {
+    let mut downloads = Downloads::new(cli, config)?;
+    downloads.cancel_on_signal();
+    let images = locked_images(path, cli.offline).await?;
+    download_images(&mut downloads, images).await?;
+    auto_gc(config)
+}
+
+/// `resume`: finish every queued download, reusing partial files. Entries are
+/// grouped by the connection count they were started with so their segments
+/// line up again.
+pub async fn resume(cli: &Cli, config: &Config) -> Result<()> {
+    let downloads = Downloads::new(cli, config)?;
+    downloads.cancel_on_signal();
+    let Downloads {
+        mut queue,
+        options,
+        jobs,
+        ..
+    } = downloads;
+    let total = queue.entries().len();
+    if total == 0 {
+        println!("No interrupted downloads to resume");
+        return Ok(());
+    }
+
+    let mut connections: Vec<usize> = queue.entries().iter().map(|e| e.connections).collect();
+    connections.sort_unstable();
+    connections.dedup();
+
+    let mut failed = 0;
+    for connections in connections {
+        let entries: Vec<Entry> = queue
+            .entries()
+            .iter()
+            .filter(|e| e.connections == connections)
+            .cloned()
+            .collect();
+        let options = DownloadOptions {
+            connections,
+            resume_partial: true,
+            ..options.clone()
+        };
+        failed += download_queued(&mut queue, &entries, &options, jobs).await?;
+    }
+    if failed > 0 {
+        bail!("{failed} of {total} downloads failed; run `resume` again to retry");
+    }
+    Ok(())
+}
+
+/// Download the `--manifest` entries, or the image selected by the flags and
+/// hand it to `export`, `push` or a deploying command.
+pub async fn fetch(cli: &Cli, config: &Config) -> Result<()> {
+    let mut downloads = Downloads::new(cli, config)?;
+    downloads.cancel_on_signal();
+    if let Some(batch) = downloads.batch.take() {
+        // Resolve every entry first so prompts for incomplete entries do not
+        // interleave with the progress bars.
+        let mut images = Vec::new();
+        for query in batch.queries {
+            images.push(downloads.select(cli, &query).await?.image);
+        }
+        download_images(&mut downloads, images).await?;
+        return auto_gc(config);
+    }
+
+    let selection = super::select_image(
+        DEFAULT_TRACK,
+        &cli.image_query(),
+        downloads.max_builds,
+        &downloads.options.cancel,
+        cli.offline,
+    )
+    .await;
+    explain(cli.explain);
+    let selection = selection?;
+    if let Some(Command::Export { target }) = &cli.command {
+        let dest = resolver::output_path(
+            &selection.image,
+            &destination_dir(&downloads.root, &selection.image, downloads.flat),
+        )?;
+        return export::export(&selection.image, target, &dest);
+    }
+    report_selection(cli.json, &selection)?;
+    let image = selection.image;
+    if cli.deploys() {
+        deploy::check_image(cli, &image)?;
+    }
+
+    let dest_dir = destination_dir(&downloads.root, &image, downloads.flat);
+    let options = DownloadOptions {
+        mirrors: repos::mirror_urls(image.url()),
+        ..downloads.options
+    };
+    let entry = Entry::new(
+        &BatchItem {
+            image: image.clone(),
+            dest_dir: dest_dir.clone(),
+            mirrors: options.mirrors.clone(),
+        },
+        options.connections,
+    );
+    downloads.queue.add(entry.clone())?;
+    let output = download(&image, &dest_dir, &options).await;
+
+    // A failed download stays queued for `resume`.
+    let msg = output?;
+    println!("{msg}");
+    downloads.queue.remove(&entry)?;
+    auto_gc(config)?;
+
+    if let Some(Command::Push {
+        reference,
+        plain_http,
+    }) = &cli.command
+    {
+        let file = resolver::output_path(&image, &dest_dir)?;
+        return registry::push(&image, &file, reference, *plain_http).await;
+    }
+    if !cli.deploys() {
+        return Ok(());
+    }
+    let disk = deploy::downloaded_disk(&image, &dest_dir, &options)?;
+    deploy::deploy(
+        cli,
+        config,
+        &image,
+        &disk,
+        downloads.disk_size.as_deref(),
+        &options,
+    )
+    .await
+}
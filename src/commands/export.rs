@@ -0,0 +1,41 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use cloud_images_downloader::{
+    Image,
+    helpers::{ansible, packer, terraform},
+};
+
+use crate::cli::ExportCommand;
+
+/// `export`: describe `image` for the tool `target` names, on stdout or in
+/// the requested file.
+pub fn export(image: &Image, target: &ExportCommand, dest: &Path) -> Result<()> {
+    let (text, output) = match target {
+        ExportCommand::Packer {
+            builder,
+            node,
+            output,
+        } => (packer::template(image, *builder, node.as_deref()), output),
+        ExportCommand::Terraform { module, output } => (
+            match module {
+                Some(module) => terraform::module(image, *module),
+                None => terraform::tfvars_json(image),
+            },
+            output,
+        ),
+        ExportCommand::Ansible { format, output } => (
+            ansible::render(vec![ansible::facts(image, dest)], *format),
+            output,
+        ),
+    };
+    match output {
+        Some(path) => {
+            std::fs::write(path, text).with_context(|| format!("write {}", path.display()))?;
+            eprintln!("Wrote {}", path.display());
+        }
+        None => print!("{text}"),
+    }
+    Ok(())
+}
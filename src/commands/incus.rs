@@ -0,0 +1,50 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use cloud_images_downloader::{
+    Image,
+    helpers::{incus, paths},
+};
+
+use super::deploy::qcow2_disk;
+use crate::cli::IncusCommand;
+
+/// `incus import`: import `disk` as a VM image, converted to qcow2 when it
+/// is not already, next to a metadata tarball describing `image`.
+pub fn import(image: &Image, disk: &Path, size: Option<&str>, action: &IncusCommand) -> Result<()> {
+    let IncusCommand::Import {
+        aliases,
+        remote,
+        client,
+        secureboot,
+    } = action;
+    let client = incus::locate(client.as_deref()).map_err(anyhow::Error::msg)?;
+    if let Some(size) = size {
+        eprintln!(
+            "Warning: Incus sizes the root disk at launch (`-d root,size=...`); ignoring {size}"
+        );
+    }
+    let disk = qcow2_disk(disk)?;
+    let aliases = if aliases.is_empty() {
+        vec![incus::alias(image)]
+    } else {
+        aliases.clone()
+    };
+
+    let scratch = paths::temp_dir();
+    std::fs::create_dir_all(&scratch).with_context(|| format!("create {}", scratch.display()))?;
+    let metadata = scratch.join(format!("incus-metadata-{}.tar.gz", std::process::id()));
+    let imported = incus::write_metadata(&metadata, image, *secureboot)
+        .and_then(|_| incus::import(&client, &metadata, &disk, &aliases, remote.as_deref()));
+    let _ = std::fs::remove_file(&metadata);
+    imported.map_err(anyhow::Error::msg)?;
+    println!("Imported {} as {}", disk.display(), aliases.join(", "));
+    let remote = remote.as_ref().map(|r| format!("{r}:")).unwrap_or_default();
+    println!(
+        "Launch it with `{} launch {remote}{} --vm`",
+        client.file_name().unwrap_or_default().to_string_lossy(),
+        aliases[0]
+    );
+    Ok(())
+}
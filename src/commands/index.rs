@@ -0,0 +1,322 @@
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+
+use cloud_images_downloader::{
+    find,
+    helpers::{ansible, http, http_cache, image_resolver as resolver, library::Library},
+    repositories::{
+        self as repos, ImageQuery,
+        bundle::{self, BundleSummary},
+        diff::{Change, diff},
+        index::{self, IndexedImage, MetadataIndex},
+        search,
+    },
+};
+
+use super::download_root;
+use crate::cli::{Cli, IndexCommand};
+use crate::config::Config;
+
+/// The metadata index, if `index sync` ever created it.
+fn existing_index() -> Result<Option<MetadataIndex>> {
+    match MetadataIndex::default_path() {
+        Some(path) if path.exists() => Ok(Some(MetadataIndex::open(&path)?)),
+        _ => Ok(None),
+    }
+}
+
+/// The metadata index when it has synced the distro `query` names (any
+/// distro when it names none).
+pub fn covering_index(query: &ImageQuery) -> Result<Option<MetadataIndex>> {
+    let Some(store) = existing_index()? else {
+        return Ok(None);
+    };
+    let covered = match query.distro_name() {
+        Some(distro) => store.has_synced(distro)?,
+        None => !store.syncs()?.is_empty(),
+    };
+    Ok(covered.then_some(store))
+}
+
+/// Print `changes` grouped by distro: `+` for new images, `-` for removed
+/// ones and `~` for changed checksums.
+fn print_changes(changes: &[Change]) {
+    if changes.is_empty() {
+        println!("Nothing changed since the previous sync");
+        return;
+    }
+    let mut distro = "";
+    for change in changes {
+        let entry = change.entry();
+        if entry.distro != distro {
+            distro = &entry.distro;
+            println!("{distro}:");
+        }
+        let describe = |entry: &IndexedImage| {
+            format!(
+                "{} {} {} {} {}",
+                entry.release,
+                entry.image.version(),
+                entry.image.arch_name(),
+                entry.image.variant(),
+                entry.image.url()
+            )
+        };
+        match change {
+            Change::Added(entry) => println!("  + {}", describe(entry)),
+            Change::Removed(entry) => println!("  - {}", describe(entry)),
+            Change::ChecksumChanged { old, new } => {
+                let checksum = |entry: &IndexedImage| {
+                    entry.image.checksum().map_or("none".to_string(), |c| {
+                        format!("{} {}", c.kind(), c.value())
+                    })
+                };
+                println!(
+                    "  ~ {}: {} -> {}",
+                    describe(new),
+                    checksum(old),
+                    checksum(new)
+                );
+            }
+        }
+    }
+}
+
+/// Report what went into or came out of the bundle at `path`.
+fn print_bundle(action: &str, path: &Path, summary: &BundleSummary) {
+    for sync in &summary.syncs {
+        println!(
+            "{}: {} images, synced {}",
+            sync.distro, sync.images, sync.synced_at
+        );
+    }
+    println!(
+        "{action} {}: {} distro(s), {} cached response(s), {} image(s)",
+        path.display(),
+        summary.syncs.len(),
+        summary.cached_responses,
+        summary.images
+    );
+}
+
+/// `index sync|status|diff|export|import`.
+pub async fn manage(action: &IndexCommand, only: Option<&str>, track: &str) -> Result<()> {
+    let path =
+        MetadataIndex::default_path().context("no cache directory for the metadata index")?;
+    match action {
+        IndexCommand::Sync => {
+            if http::offline() {
+                bail!("index sync needs the network; drop --offline");
+            }
+            let providers: Vec<_> = repos::providers()
+                .into_iter()
+                .filter(|p| only.is_none_or(|d| p.display_name().eq_ignore_ascii_case(d)))
+                .collect();
+            if providers.is_empty() {
+                bail!("Unsupported distro '{}'", only.unwrap_or_default());
+            }
+            let mut store = MetadataIndex::open(&path)?;
+            // Keep what the index knew so far for `index diff`.
+            if let Some(previous) = MetadataIndex::previous_path() {
+                let _ = std::fs::remove_file(&previous);
+                store.snapshot(&previous)?;
+            }
+            for provider in providers {
+                let name = provider.display_name();
+                eprintln!("Syncing {name}...");
+                let images = index::crawl(provider, http::client(), track).await?;
+                store.replace(name, &images)?;
+                println!("{name}: {} images", images.len());
+            }
+        }
+        IndexCommand::Export { path: to, images } => {
+            let store =
+                existing_index()?.context("the index has not been synced; run `index sync`")?;
+            let library = Library::open_default().map_err(anyhow::Error::msg)?;
+            let images = images
+                .iter()
+                .map(|id| library.entry(id))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(anyhow::Error::msg)?;
+            let summary = bundle::export(
+                to,
+                &store,
+                http_cache::cache_dir().as_deref(),
+                &library,
+                &images,
+            )?;
+            print_bundle("Exported", to, &summary);
+        }
+        IndexCommand::Import { path: from } => {
+            let mut store = MetadataIndex::open(&path)?;
+            let library = Library::open_default().map_err(anyhow::Error::msg)?;
+            let summary = bundle::import(
+                from,
+                &mut store,
+                http_cache::cache_dir().as_deref(),
+                &library,
+            )?;
+            print_bundle("Imported", from, &summary);
+        }
+        IndexCommand::Diff => {
+            let previous = MetadataIndex::previous_path()
+                .filter(|previous| previous.exists())
+                .context("nothing to compare with; run `index sync` twice")?;
+            let current = existing_index()?.context("the index has not been synced")?;
+            let query = match only {
+                Some(distro) => ImageQuery::new().distro(distro),
+                None => ImageQuery::new(),
+            };
+            let changes = diff(
+                MetadataIndex::open(&previous)?.query(&query)?,
+                current.query(&query)?,
+            );
+            print_changes(&changes);
+        }
+        IndexCommand::Status => {
+            let syncs = match existing_index()? {
+                Some(store) => store.syncs()?,
+                None => Vec::new(),
+            };
+            if syncs.is_empty() {
+                println!("The index has not been synced; run `index sync`");
+            }
+            for sync in syncs {
+                println!(
+                    "{}: {} images, synced {}",
+                    sync.distro, sync.images, sync.synced_at
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `list`: images matching the filter flags, answered by the index when it
+/// covers the query and by the repositories otherwise (or with `--remote`).
+pub async fn list(cli: &Cli, remote: bool, config: &Config) -> Result<()> {
+    let query = cli.image_query();
+    let store = if remote {
+        None
+    } else {
+        covering_index(&query)?
+    };
+    let images = match store {
+        Some(store) => store.query(&query)?,
+        None => {
+            if query.distro_name().is_none()
+                || query.release_id().is_none()
+                || query.arch_value().is_none()
+            {
+                bail!(
+                    "listing live needs --distro, --release and --arch; run `index sync` to list from the local index"
+                );
+            }
+            if http::offline() {
+                bail!(
+                    "offline: the metadata index does not cover the query; run `index sync` while online"
+                );
+            }
+            let release = query.release_id().unwrap_or_default().to_string();
+            let distro = query.distro_name().unwrap_or_default().to_string();
+            find(&query)
+                .await?
+                .into_iter()
+                .map(|image| IndexedImage {
+                    distro: distro.clone(),
+                    release: release.clone(),
+                    image,
+                })
+                .collect()
+        }
+    };
+    if let Some(format) = cli.ansible {
+        let (root, flat) = download_root(cli, config);
+        let facts = images
+            .iter()
+            .map(|entry| {
+                let dir = resolver::destination_dir(&root, &entry.image, flat);
+                Ok(ansible::facts(
+                    &entry.image,
+                    &resolver::output_path(&entry.image, &dir)?,
+                ))
+            })
+            .collect::<Result<_>>()?;
+        print!("{}", ansible::render(facts, format));
+        return Ok(());
+    }
+    for entry in images {
+        if cli.json {
+            println!("{}", serde_json::to_string(&entry.image)?);
+        } else {
+            println!(
+                "{} {} {} {} {} {}",
+                entry.distro,
+                entry.release,
+                entry.image.version(),
+                entry.image.arch_name(),
+                entry.image.variant(),
+                entry.image.url()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// `search`: candidates from the index when it covers the query, from a
+/// crawl of the repositories otherwise (or with `--remote`).
+pub async fn search(cli: &Cli, terms: &[String], remote: bool, track: &str) -> Result<()> {
+    let query = cli.image_query();
+    let mut store = if remote {
+        None
+    } else {
+        covering_index(&query)?
+    };
+    if store.is_none() {
+        if http::offline() {
+            bail!(
+                "offline: the metadata index does not cover the query; run `index sync` while online"
+            );
+        }
+        let mut crawled = MetadataIndex::in_memory()?;
+        for provider in repos::providers() {
+            let name = provider.display_name();
+            if query
+                .distro_name()
+                .is_some_and(|d| !d.eq_ignore_ascii_case(name))
+            {
+                continue;
+            }
+            eprintln!("Listing {name}...");
+            let images = index::crawl(provider, http::client(), track).await?;
+            crawled.replace(name, &images)?;
+        }
+        store = Some(crawled);
+    }
+    let candidates = store
+        .map(|s| s.query(&query))
+        .transpose()?
+        .unwrap_or_default();
+    let found = search::search(candidates, &terms.join(" "));
+    if found.is_empty() {
+        bail!("No image matches '{}'", terms.join(" "));
+    }
+    for entry in found {
+        if cli.json {
+            println!("{}", serde_json::to_string(&entry.image)?);
+        } else {
+            println!(
+                "{}  {} {} {} {} {}  {}",
+                &entry.image.id()[..12],
+                entry.distro,
+                entry.release,
+                entry.image.version(),
+                entry.image.arch_name(),
+                entry.image.variant(),
+                entry.image.url()
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,132 @@
+use anyhow::Result;
+
+use cloud_images_downloader::helpers::{
+    http_cache, human_size,
+    library::Library,
+    retention::GcSummary,
+    simplestreams::{self, LocalImage},
+};
+
+use crate::cli::{CacheCommand, LibraryCommand};
+use crate::config::Config;
+
+/// Report what a garbage collection of `what` reclaimed.
+fn print_gc(what: &str, summary: &GcSummary) {
+    println!(
+        "{what}: dropped {} entries, deleted {} file(s), {} freed",
+        summary.entries,
+        summary.files,
+        human_size(Some(summary.bytes))
+    );
+}
+
+/// `cache gc`.
+pub fn manage_cache(action: &CacheCommand, config: &Config) -> Result<()> {
+    match action {
+        CacheCommand::Gc { retention } => {
+            let policy = retention.or(config.cache_retention()?)?;
+            let summary = http_cache::gc(&policy).map_err(anyhow::Error::msg)?;
+            print_gc("cache", &summary);
+        }
+    }
+    Ok(())
+}
+
+/// Apply the configured retention limits to the cache and the library after
+/// downloads, when `auto_gc` is set. Quiet unless something was reclaimed.
+pub fn auto_gc(config: &Config) -> Result<()> {
+    if !config.auto_gc() {
+        return Ok(());
+    }
+    let cache = http_cache::gc(&config.cache_retention()?).map_err(anyhow::Error::msg)?;
+    let library_policy = config.library_retention()?;
+    let library = Library::open_default()
+        .and_then(|library| library.gc(&library_policy))
+        .map_err(anyhow::Error::msg)?;
+    for (what, summary) in [("cache", cache), ("library", library)] {
+        if summary.files > 0 || summary.entries > 0 {
+            print_gc(what, &summary);
+        }
+    }
+    Ok(())
+}
+
+/// `library list|rm|gc|path|export`.
+pub fn manage(action: &LibraryCommand, config: &Config) -> Result<()> {
+    let library = Library::open_default().map_err(anyhow::Error::msg)?;
+    match action {
+        LibraryCommand::List => {
+            let entries = library.entries().map_err(anyhow::Error::msg)?;
+            if entries.is_empty() {
+                println!("The library at {} is empty", library.root().display());
+            }
+            for entry in entries {
+                let size = human_size(
+                    std::fs::metadata(library.blob(&entry.digest))
+                        .ok()
+                        .map(|m| m.len()),
+                );
+                println!(
+                    "{}  {} {} {} {}  {size}  {}",
+                    &entry.id()[..12],
+                    entry.image.os(),
+                    entry.image.distro_version(),
+                    entry.image.version(),
+                    entry.image.arch_name(),
+                    entry.link.display()
+                );
+            }
+        }
+        LibraryCommand::Rm { ids } => {
+            for id in ids {
+                for entry in library.remove(id).map_err(anyhow::Error::msg)? {
+                    println!("Removed {} ({})", &entry.id()[..12], entry.link.display());
+                }
+            }
+        }
+        LibraryCommand::Gc { retention } => {
+            let policy = retention.or(config.library_retention()?)?;
+            let summary = library.gc(&policy).map_err(anyhow::Error::msg)?;
+            print_gc("library", &summary);
+        }
+        LibraryCommand::Path { id } => {
+            println!(
+                "{}",
+                library.path(id).map_err(anyhow::Error::msg)?.display()
+            );
+        }
+        LibraryCommand::Export { id, to, mode } => {
+            let (path, used) = library.export(id, to, *mode).map_err(anyhow::Error::msg)?;
+            println!("Exported {} ({used})", path.display());
+        }
+        LibraryCommand::Simplestreams => {
+            let root = library.images_dir();
+            let images: Vec<LocalImage> = library
+                .entries()
+                .map_err(anyhow::Error::msg)?
+                .into_iter()
+                .filter_map(|entry| {
+                    let path = entry.link.strip_prefix(&root).ok()?;
+                    let path = path
+                        .components()
+                        .map(|c| c.as_os_str().to_str())
+                        .collect::<Option<Vec<_>>>()?
+                        .join("/");
+                    Some(LocalImage {
+                        image: entry.image,
+                        path,
+                        sha256: Some(entry.digest),
+                    })
+                })
+                .collect();
+            let described =
+                simplestreams::generate(&root, &images, simplestreams::LIBRARY_CONTENT_ID)
+                    .map_err(anyhow::Error::msg)?;
+            println!(
+                "Described {described} images in {}",
+                simplestreams::index_path(&root).display()
+            );
+        }
+    }
+    Ok(())
+}
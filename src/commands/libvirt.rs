@@ -0,0 +1,164 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use cloud_images_downloader::{
+    Image,
+    helpers::{
+        ignition,
+        libvirt::{self, Domain, Virsh},
+        paths, qemu_img,
+        seed::{self, Seed, SeedFormat},
+    },
+};
+
+use super::deploy::default_seed;
+use crate::cli::LibvirtCommand;
+
+/// `libvirt create`: import `disk` into the pool, next to a NoCloud seed
+/// (`seed`, else one authorising the SSH key), and define a domain booting
+/// it. The volume grows to `size` when given.
+pub fn create(
+    image: &Image,
+    disk: &Path,
+    size: Option<&str>,
+    seed: Option<Seed>,
+    ignition: Option<&Path>,
+    action: &LibvirtCommand,
+) -> Result<()> {
+    let LibvirtCommand::Create {
+        name,
+        connect,
+        pool,
+        backing,
+        memory,
+        cpus,
+        network,
+        emulate,
+        ssh_key,
+        start,
+    } = action;
+    let virsh = Virsh::new(connect.as_deref()).map_err(anyhow::Error::msg)?;
+    let name = name.clone().unwrap_or_else(|| libvirt::domain_name(image));
+
+    let info = qemu_img::info(disk).map_err(anyhow::Error::msg)?;
+    let disk_format = if *backing {
+        "qcow2".to_string()
+    } else {
+        info.format.clone()
+    };
+    let volume = format!("{name}.{disk_format}");
+    let disk = virsh
+        .import_volume(pool, &volume, disk, &info, *backing)
+        .map_err(anyhow::Error::msg)?;
+    if let Some(size) = size {
+        virsh
+            .resize_volume(pool, &volume, size)
+            .map_err(anyhow::Error::msg)?;
+    }
+    println!("Imported {}", disk.display());
+
+    let scratch = paths::temp_dir().join(format!("libvirt-{}", std::process::id()));
+    std::fs::create_dir_all(&scratch).with_context(|| format!("create {}", scratch.display()))?;
+    let provisioning = match ignition::fw_cfg_name(image.os()) {
+        Some(fw_cfg) => upload_ignition(
+            &virsh,
+            pool,
+            &name,
+            &scratch,
+            ignition,
+            ssh_key.as_deref(),
+            image,
+        )
+        .map(|path| (None, Some((fw_cfg.to_string(), path)))),
+        None => upload_seed(
+            &virsh,
+            pool,
+            &name,
+            &scratch,
+            seed,
+            ssh_key.as_deref(),
+            image,
+        )
+        .map(|seed| (Some(seed), None)),
+    };
+    let _ = std::fs::remove_dir_all(&scratch);
+    let (seed, ignition) = provisioning?;
+
+    let domain = Domain {
+        name: name.clone(),
+        description: format!(
+            "{} {} ({}) {} {} {}, from {}",
+            image.os(),
+            image.distro_version(),
+            image.name(),
+            image.version(),
+            image.arch_name(),
+            image.variant(),
+            image.url()
+        ),
+        arch: image.arch(),
+        domain_type: if *emulate { "qemu" } else { "kvm" }.to_string(),
+        memory_mib: memory >> 20,
+        cpus: *cpus,
+        disk,
+        disk_format,
+        seed,
+        ignition,
+        network: network.clone(),
+    };
+    virsh.define(&domain).map_err(anyhow::Error::msg)?;
+    println!("Defined domain {name}");
+    if *start {
+        virsh.start(&name).map_err(anyhow::Error::msg)?;
+        println!("Started {name}; attach with `virsh console {name}`");
+    }
+    Ok(())
+}
+
+/// Build the seed of the domain `name` in `scratch` and upload it into
+/// `pool`, returning the volume's path.
+fn upload_seed(
+    virsh: &Virsh,
+    pool: &str,
+    name: &str,
+    scratch: &Path,
+    seed: Option<Seed>,
+    ssh_key: Option<&Path>,
+    image: &Image,
+) -> Result<(PathBuf, SeedFormat)> {
+    let seed = match seed {
+        Some(seed) => seed,
+        None => default_seed(scratch.join("user-data"), ssh_key)?,
+    };
+    let volume = format!("{name}-seed.{}", seed.format);
+    let file = scratch.join(&volume);
+    seed::build(&file, &seed, image.os()).map_err(anyhow::Error::msg)?;
+    virsh
+        .upload(pool, &volume, &file, "raw")
+        .map_err(anyhow::Error::msg)?;
+    let path = virsh
+        .volume_path(pool, &volume)
+        .map_err(anyhow::Error::msg)?;
+    Ok((path, seed.format))
+}
+
+/// Write the Ignition config of `image` (see [`ignition::write_config`]) and
+/// upload it into `pool` as `<name>.ign`; returns its path there.
+fn upload_ignition(
+    virsh: &Virsh,
+    pool: &str,
+    name: &str,
+    scratch: &Path,
+    source: Option<&Path>,
+    ssh_key: Option<&Path>,
+    image: &Image,
+) -> Result<PathBuf> {
+    let volume = format!("{name}.ign");
+    let file = scratch.join(&volume);
+    ignition::write_config(&file, source, ssh_key, image.os()).map_err(anyhow::Error::msg)?;
+    virsh
+        .upload(pool, &volume, &file, "raw")
+        .map_err(anyhow::Error::msg)?;
+    virsh.volume_path(pool, &volume).map_err(anyhow::Error::msg)
+}
@@ -0,0 +1,133 @@
+use anyhow::{Context, Result, bail};
+
+use cloud_images_downloader::{
+    DEFAULT_TRACK, Image,
+    cloud::{ImageFormat, Variant},
+    helpers::{
+        http, human_size,
+        mirror::{self, MirrorFilter, MirrorState},
+        simplestreams::{self, LocalImage},
+    },
+    repositories::{self as repos, bench},
+};
+
+use super::download::Downloads;
+use crate::cli::{Cli, MirrorArgs};
+use crate::config::Config;
+
+/// `mirrors bench`: rank the roots of every (or the selected) repository and
+/// optionally store the order in the user config.
+pub async fn bench(only: Option<&str>, save: bool) -> Result<()> {
+    for repo in repos::all()? {
+        if only.is_some_and(|name| !name.eq_ignore_ascii_case(repo.name())) {
+            continue;
+        }
+
+        println!("\n=== {} ===", repo.name());
+        let scores = bench::bench(http::client(), &repo).await;
+        for (rank, score) in scores.iter().enumerate() {
+            match (score.latency, score.throughput) {
+                (Some(latency), Some(throughput)) => println!(
+                    "{:>2}. {:<60} {:>6} ms  {}/s",
+                    rank + 1,
+                    score.root,
+                    latency.as_millis(),
+                    human_size(Some(throughput as u64))
+                ),
+                _ => println!(
+                    "{:>2}. {:<60} unreachable: {}",
+                    rank + 1,
+                    score.root,
+                    score.error.as_deref().unwrap_or("unknown error")
+                ),
+            }
+        }
+
+        if save && scores.len() > 1 {
+            let order: Vec<String> = scores.into_iter().map(|s| s.root).collect();
+            let path = Config::save_mirror_ranking(repo.name(), &order)?;
+            println!("Saved mirror order to {}", path.display());
+        }
+    }
+    Ok(())
+}
+
+/// `mirror`: sync the `--distro` images `args` (and the selection flags)
+/// select into `args.dir`.
+pub async fn sync(cli: &Cli, args: &MirrorArgs, downloads: &Downloads) -> Result<()> {
+    let Some(distro) = &cli.distro else {
+        bail!("mirror needs --distro");
+    };
+    downloads.cancel_on_signal();
+    let filter = MirrorFilter {
+        releases: args.releases.iter().chain(&cli.release).cloned().collect(),
+        arches: args.arches.iter().copied().chain(cli.arch).collect(),
+        variants: args
+            .variants
+            .iter()
+            .chain(&cli.variant)
+            .map(|v| Variant::from_name(v))
+            .collect(),
+        formats: args
+            .formats
+            .iter()
+            .chain(&cli.format)
+            .map(|f| ImageFormat::from_name(f))
+            .collect(),
+        builds: args.builds,
+    };
+    let dir = &args.dir;
+    let provider = repos::provider(distro).with_context(|| format!("unknown distro '{distro}'"))?;
+    eprintln!("Listing {}...", provider.display_name());
+    let images = mirror::plan(provider, http::client(), DEFAULT_TRACK, &filter)
+        .await
+        .map_err(anyhow::Error::msg)?;
+    if images.is_empty() {
+        bail!("No {} image matches the filters", provider.display_name());
+    }
+    let size = images
+        .iter()
+        .map(Image::size)
+        .sum::<Option<u64>>()
+        .map(|size| format!(" ({})", human_size(Some(size))))
+        .unwrap_or_default();
+    eprintln!(
+        "Mirroring {} images{size} into {}",
+        images.len(),
+        dir.display()
+    );
+    let summary = mirror::sync(dir, &images, &downloads.options, downloads.jobs, args.prune)
+        .await
+        .map_err(anyhow::Error::msg)?;
+    println!(
+        "{} downloaded, {} up to date, {} checksum files updated, {} pruned",
+        summary.downloaded, summary.current, summary.updated, summary.pruned
+    );
+    for (url, err) in &summary.failed {
+        eprintln!("Failed: {url}: {err}");
+    }
+    if args.simplestreams {
+        let images: Vec<LocalImage> = MirrorState::load(dir)
+            .map_err(anyhow::Error::msg)?
+            .entries
+            .into_iter()
+            .filter_map(|entry| {
+                Some(LocalImage {
+                    image: entry.image?,
+                    path: entry.path,
+                    sha256: None,
+                })
+            })
+            .collect();
+        let described = simplestreams::generate(dir, &images, simplestreams::MIRROR_CONTENT_ID)
+            .map_err(anyhow::Error::msg)?;
+        println!(
+            "Described {described} images in {}",
+            simplestreams::index_path(dir).display()
+        );
+    }
+    if !summary.failed.is_empty() {
+        bail!("{} files could not be mirrored", summary.failed.len());
+    }
+    Ok(())
+}
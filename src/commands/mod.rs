@@ -0,0 +1,158 @@
+//! The bodies of the subcommands. `main` parses the flags, sets up the
+//! shared HTTP, signature and repository state and dispatches here.
+
+pub mod deploy;
+pub mod download;
+pub mod export;
+pub mod incus;
+pub mod index;
+pub mod library;
+pub mod libvirt;
+pub mod mirror;
+pub mod openstack;
+pub mod proxmox;
+pub mod registry;
+pub mod run;
+pub mod schedule;
+pub mod serve;
+
+use std::path::PathBuf;
+
+use anyhow::{Context, Result, bail};
+
+use cloud_images_downloader::{
+    CancellationToken, CloudImagesError, Image, Selection,
+    cloud::BuildId,
+    helpers::{
+        choose_one, human_size,
+        trace::{self, ExplainFormat},
+    },
+    repositories::{ImageQuery, index::IndexedImage},
+    select,
+};
+
+use crate::cli::Cli;
+use crate::config::Config;
+
+/// Print the selected image as one line of JSON (`--json`) or as the
+/// human readable summary.
+pub fn report_selection(json: bool, selection: &Selection) -> Result<()> {
+    if json {
+        println!("{}", serde_json::to_string(&selection.image)?);
+    } else {
+        print_selection(
+            &selection.distro,
+            &selection.arch,
+            &selection.version,
+            &selection.image,
+        );
+    }
+    Ok(())
+}
+
+/// Print the resolution trace collected for the last selection, if asked.
+pub fn explain(format: Option<ExplainFormat>) {
+    if let Some(format) = format
+        && let Some(trace) = trace::take()
+    {
+        eprint!("{}", trace.render(format));
+    }
+}
+
+/// A tiny wrapper to render the final selection cleanly
+fn print_selection(distro: &str, arch: &str, version: &str, image: &Image) {
+    // If your Image implements getters, use them here
+    println!("\n=== Selection ===");
+    println!("Distro:   {distro}");
+    println!("Arch:     {arch}");
+    println!("Version:  {version}");
+    println!("Image:");
+    println!("  id:          {}", image.id());
+    println!("  name:        {}", image.name());
+    println!("  distro ver:  {}", image.distro_version());
+    println!("  version:     {}", BuildId::new(image.version()).label());
+    println!("  variant:     {}", image.variant());
+    println!("  format:      {}", image.format());
+    println!("  arch:        {}", image.arch_name());
+    println!("  url:         {}", image.url());
+    if let Some(size) = image.size() {
+        println!("  size:        {}", human_size(Some(size)));
+    }
+    if let Some(checksum) = image.checksum() {
+        println!("  checksum:    {} ({})", checksum.value(), checksum.kind());
+    } else {
+        println!("  checksum:    <none>");
+    }
+}
+
+/// [`select`], or with `--offline` [`select_offline`].
+pub async fn select_image(
+    track: &str,
+    query: &ImageQuery,
+    max_builds: Option<usize>,
+    cancel: &CancellationToken,
+    offline: bool,
+) -> Result<Selection> {
+    if offline {
+        return select_offline(query);
+    }
+    Ok(select(track, query, max_builds, cancel).await?)
+}
+
+/// Resolve `query` from the metadata index alone. Without a build the newest
+/// one is taken, and the picker only shows when several images are left.
+fn select_offline(query: &ImageQuery) -> Result<Selection> {
+    let store = index::covering_index(query)?.with_context(|| {
+        format!(
+            "offline: the metadata index has not synced {}; run `index sync` while online",
+            query.distro_name().unwrap_or("any distro")
+        )
+    })?;
+    let query = match query.build_id() {
+        Some(_) => query.clone(),
+        None => query.clone().latest(),
+    };
+    let mut found = store.query(&query)?;
+    let label = |entry: &IndexedImage| {
+        format!(
+            "{} {} {} {} {} {}",
+            entry.distro,
+            entry.release,
+            entry.image.version(),
+            entry.image.arch_name(),
+            entry.image.variant(),
+            entry.image.url()
+        )
+    };
+    let entry = match found.len() {
+        0 => bail!(CloudImagesError::Resolution(
+            "offline: no indexed image matches the query".to_string()
+        )),
+        1 => found.remove(0),
+        _ => {
+            let chosen = choose_one("Select Image", found.iter().map(label).collect())?;
+            let at = found
+                .iter()
+                .position(|entry| label(entry) == chosen)
+                .context("the picker returned an unknown image")?;
+            found.remove(at)
+        }
+    };
+    Ok(Selection {
+        arch: entry.image.arch_name().to_string(),
+        version: format!("{} ({})", entry.release, entry.image.version()),
+        distro: entry.distro,
+        release: entry.release,
+        image: entry.image,
+    })
+}
+
+/// Directory downloads go to, and whether they skip the
+/// `<distro>/<version>/<arch>` subfolders.
+pub fn download_root(cli: &Cli, config: &Config) -> (PathBuf, bool) {
+    let root = cli
+        .output_dir
+        .clone()
+        .unwrap_or_else(|| config.download_dir());
+    (root, cli.flat || config.flat())
+}
@@ -0,0 +1,88 @@
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::{Context, Result, bail};
+
+use cloud_images_downloader::{
+    Image,
+    cloud::ImageFormat,
+    helpers::{
+        image_resolver::DownloadOptions,
+        libvirt,
+        openstack::{self, Cloud, Glance, ImageSpec},
+        qemu_img,
+    },
+};
+
+use crate::cli::OpenstackCommand;
+
+/// `openstack upload`: register `disk` with Glance, described by its
+/// formats, the distro and the upstream checksum, and upload it.
+pub async fn upload(
+    image: &Image,
+    disk: &Path,
+    size: Option<&str>,
+    options: &DownloadOptions,
+    action: &OpenstackCommand,
+) -> Result<()> {
+    let OpenstackCommand::Upload {
+        cloud,
+        name,
+        visibility,
+        properties,
+    } = action;
+    if let Some(size) = size {
+        eprintln!("Warning: OpenStack sizes the root disk by flavor or volume; ignoring {size}");
+    }
+    let format = match options.convert_to {
+        Some(format) => ImageFormat::from_name(format.as_str()),
+        None => image.format().clone(),
+    };
+    let (disk_format, container_format) = match openstack::formats(&format) {
+        Some(formats) => formats,
+        None => {
+            let probed = qemu_img::info(disk).map_err(anyhow::Error::msg)?.format;
+            openstack::formats(&ImageFormat::from_name(&probed)).with_context(|| {
+                format!("Glance does not take {probed} images; use --convert-to qcow2")
+            })?
+        }
+    };
+
+    let mut extra = BTreeMap::from([
+        ("os_distro".to_string(), image.os().to_string()),
+        ("os_version".to_string(), image.distro_version().to_string()),
+        (
+            "architecture".to_string(),
+            openstack::architecture(image.arch()).to_string(),
+        ),
+        ("source_url".to_string(), image.url().to_string()),
+        ("source_build".to_string(), image.version().to_string()),
+    ]);
+    if let (Some(kind), Some(value)) = (image.checksum_kind(), image.checksum_value()) {
+        extra.insert(
+            "source_checksum_algo".to_string(),
+            kind.as_str().to_string(),
+        );
+        extra.insert("source_checksum".to_string(), value.to_string());
+    }
+    extra.extend(properties.iter().cloned());
+    let spec = ImageSpec {
+        name: name.clone().unwrap_or_else(|| libvirt::domain_name(image)),
+        disk_format: disk_format.to_string(),
+        container_format: container_format.to_string(),
+        visibility: visibility.clone(),
+        properties: extra,
+    };
+
+    let cloud = Cloud::load(cloud.as_deref()).map_err(anyhow::Error::msg)?;
+    let glance = Glance::connect(&cloud).await.map_err(anyhow::Error::msg)?;
+    let id = glance.create(&spec).await.map_err(anyhow::Error::msg)?;
+    println!("Uploading {} to Glance image {id}...", disk.display());
+    if let Err(err) = glance.upload(&id, disk).await {
+        if let Err(cleanup) = glance.delete(&id).await {
+            eprintln!("Warning: failed to remove image {id}: {cleanup}");
+        }
+        bail!(err);
+    }
+    println!("Uploaded {} as {id} ({disk_format})", spec.name);
+    Ok(())
+}
@@ -0,0 +1,110 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use cloud_images_downloader::{
+    Image,
+    helpers::{
+        libvirt,
+        proxmox::{Proxmox, VmSpec as ProxmoxVm},
+        qemu_img, vm,
+    },
+};
+
+use crate::cli::ProxmoxCommand;
+use crate::config::Config;
+
+/// `proxmox create`: upload `disk` to the node, import it into a new VM
+/// with a cloud-init drive, grow it to `size` and optionally make the VM a
+/// template.
+pub async fn create(
+    image: &Image,
+    disk: &Path,
+    size: Option<&str>,
+    action: &ProxmoxCommand,
+    config: &Config,
+) -> Result<()> {
+    let ProxmoxCommand::Create {
+        node,
+        storage,
+        disk_storage,
+        bridge,
+        vmid,
+        name,
+        memory,
+        cpus,
+        ci_user,
+        ssh_key,
+        template,
+        keep_upload,
+    } = action;
+    let defaults = config.proxmox();
+    let node = node
+        .clone()
+        .or(defaults.node.clone())
+        .context("name the node with --node or `node` in the [proxmox] table")?;
+    let storage = storage
+        .clone()
+        .or(defaults.storage.clone())
+        .unwrap_or_else(|| "local".to_string());
+    let proxmox = Proxmox::new(&defaults.settings()?, &node).map_err(anyhow::Error::msg)?;
+    let name = name.clone().unwrap_or_else(|| libvirt::domain_name(image));
+    let ssh_keys = vm::ssh_key(ssh_key.as_deref()).map_err(anyhow::Error::msg)?;
+    if ssh_keys.is_none() {
+        eprintln!("Warning: no SSH public key found; set one in the cloud-init tab");
+    }
+
+    let format = qemu_img::info(disk).map_err(anyhow::Error::msg)?.format;
+    println!("Uploading {} to {node}/{storage}...", disk.display());
+    let volid = proxmox
+        .upload(&storage, disk, &name, &format)
+        .await
+        .map_err(anyhow::Error::msg)?;
+    let spec = ProxmoxVm {
+        vmid: *vmid,
+        name: name.clone(),
+        description: format!(
+            "{} {} ({}) {} {} {}, from {}",
+            image.os(),
+            image.distro_version(),
+            image.name(),
+            image.version(),
+            image.arch_name(),
+            image.variant(),
+            image.url()
+        ),
+        memory_mib: memory >> 20,
+        cores: *cpus,
+        disk_storage: disk_storage
+            .clone()
+            .or(defaults.disk_storage.clone())
+            .unwrap_or_else(|| "local-lvm".to_string()),
+        bridge: bridge
+            .clone()
+            .or(defaults.bridge.clone())
+            .unwrap_or_else(|| "vmbr0".to_string()),
+        ci_user: ci_user.clone(),
+        ssh_keys,
+    };
+    let created = proxmox.create_vm(&spec, &volid).await;
+    if !keep_upload && let Err(err) = proxmox.delete_volume(&storage, &volid).await {
+        eprintln!("Warning: failed to remove {volid}: {err}");
+    }
+    let vmid = created.map_err(anyhow::Error::msg)?;
+    println!("Created VM {vmid} ({name}) on {node}");
+    if let Some(size) = size {
+        proxmox
+            .resize_disk(vmid, size)
+            .await
+            .map_err(anyhow::Error::msg)?;
+        println!("Resized its disk to {size}");
+    }
+    if *template {
+        proxmox
+            .make_template(vmid)
+            .await
+            .map_err(anyhow::Error::msg)?;
+        println!("Converted VM {vmid} to a template");
+    }
+    Ok(())
+}
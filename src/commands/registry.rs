@@ -0,0 +1,130 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+
+use cloud_images_downloader::{
+    Image,
+    helpers::{
+        oci::{self, Blob, Reference, Registry, containerdisk},
+        paths, provenance,
+    },
+};
+
+use super::deploy::qcow2_disk;
+
+/// `push`: store `file`, the verified download of `image`, and its
+/// provenance statement (when one was written) as an OCI artifact tagged
+/// `reference`.
+pub async fn push(
+    image: &Image,
+    file: &Path,
+    reference: &Reference,
+    plain_http: bool,
+) -> Result<()> {
+    let registry = Registry::new(reference, plain_http).map_err(anyhow::Error::msg)?;
+    let mut files = vec![(
+        file.to_path_buf(),
+        oci::disk_media_type(image.format().as_str()),
+    )];
+    let statement = provenance::sidecar_path(file);
+    if statement.is_file() {
+        files.push((statement, oci::PROVENANCE_MEDIA_TYPE.to_string()));
+    }
+    let layers = tokio::task::spawn_blocking(move || {
+        files
+            .iter()
+            .map(|(path, media_type)| Blob::from_file(path, media_type))
+            .collect::<Result<Vec<_>, _>>()
+    })
+    .await?
+    .map_err(anyhow::Error::msg)?;
+
+    for layer in &layers {
+        println!("Pushing {} ({})...", layer.path.display(), layer.digest);
+        if !registry
+            .push_blob(layer)
+            .await
+            .map_err(anyhow::Error::msg)?
+        {
+            println!("  already in {}", reference.repository);
+        }
+    }
+    let annotations = [
+        ("org.opencontainers.image.source", image.url().to_string()),
+        (
+            "org.opencontainers.image.version",
+            image.version().to_string(),
+        ),
+        ("org.opencontainers.image.created", Utc::now().to_rfc3339()),
+        (
+            "org.opencontainers.image.description",
+            format!(
+                "{} {} ({}) {} {}",
+                image.os(),
+                image.distro_version(),
+                image.name(),
+                image.arch_name(),
+                image.variant()
+            ),
+        ),
+    ];
+    let config = oci::Config::empty();
+    let manifest = oci::manifest(&config, &layers, Some(oci::ARTIFACT_TYPE), &annotations);
+    let digest = registry
+        .push_manifest(&config, &manifest)
+        .await
+        .map_err(anyhow::Error::msg)?;
+    println!(
+        "Pushed {}/{}:{} ({digest})",
+        reference.registry, reference.repository, reference.tag
+    );
+    Ok(())
+}
+
+/// `containerdisk`: wrap `disk`, as qcow2, into a KubeVirt containerdisk
+/// and push it as `reference`.
+pub async fn containerdisk(
+    image: &Image,
+    disk: &Path,
+    size: Option<&str>,
+    reference: &Reference,
+    plain_http: bool,
+) -> Result<()> {
+    if let Some(size) = size {
+        eprintln!("Warning: containerdisks keep the image's virtual size; ignoring {size}");
+    }
+    let registry = Registry::new(reference, plain_http).map_err(anyhow::Error::msg)?;
+    let disk = qcow2_disk(disk)?;
+    let scratch = paths::temp_dir();
+    std::fs::create_dir_all(&scratch).with_context(|| format!("create {}", scratch.display()))?;
+    let output = scratch.join(format!("containerdisk-{}.tar", std::process::id()));
+
+    println!("Packing {}...", disk.display());
+    let layer_path = output.clone();
+    let layer =
+        tokio::task::spawn_blocking(move || containerdisk::write_layer(&disk, &layer_path)).await?;
+    let pushed = async {
+        let layer = layer?;
+        println!("Pushing the layer ({})...", layer.digest);
+        registry.push_blob(&layer).await?;
+        let config = containerdisk::config(image.arch(), &layer.digest);
+        let annotations = [
+            ("org.opencontainers.image.source", image.url().to_string()),
+            (
+                "org.opencontainers.image.version",
+                image.version().to_string(),
+            ),
+        ];
+        let manifest = oci::manifest(&config, &[layer], None, &annotations);
+        registry.push_manifest(&config, &manifest).await
+    }
+    .await;
+    let _ = std::fs::remove_file(&output);
+    let digest = pushed.map_err(anyhow::Error::msg)?;
+    println!(
+        "Pushed {}/{}:{} ({digest}); use it as a containerDisk volume image",
+        reference.registry, reference.repository, reference.tag
+    );
+    Ok(())
+}
@@ -0,0 +1,94 @@
+use std::path::Path;
+
+use anyhow::Result;
+
+use cloud_images_downloader::{
+    Image,
+    helpers::{
+        ignition, qemu_img,
+        seed::{self, Seed},
+        vm::{self, Provisioning, VmOptions},
+    },
+};
+
+use super::deploy::default_seed;
+
+/// Boot `disk`, the download of `image`, from a qcow2 overlay next to it
+/// with a NoCloud seed: `seed` when given, else one authorising `ssh_key`.
+/// Images provisioned by Ignition get the `ignition` config (or one
+/// authorising `ssh_key`) instead. The overlay and the seed or config are
+/// removed once the VM powered off unless `keep` is set.
+#[allow(clippy::too_many_arguments)]
+pub fn run(
+    image: &Image,
+    disk: &Path,
+    size: Option<&str>,
+    seed: Option<Seed>,
+    ignition: Option<&Path>,
+    ssh_key: Option<&Path>,
+    vm: &VmOptions,
+    keep: bool,
+) -> Result<()> {
+    let stem = disk
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "image".to_string());
+    let overlay = disk.with_file_name(format!("{stem}-run.qcow2"));
+    let mut scratch = vec![overlay.clone()];
+
+    let result = match ignition::fw_cfg_name(image.os()) {
+        Some(name) => {
+            let config = disk.with_file_name(format!("{stem}-run.ign"));
+            scratch.push(config.clone());
+            qemu_img::create_overlay(disk, &overlay, size)
+                .and_then(|_| ignition::write_config(&config, ignition, ssh_key, image.os()))
+                .and_then(|_| {
+                    println!(
+                        "Booting {} (Ctrl-a x quits); once Ignition is done: ssh -p {} core@127.0.0.1",
+                        overlay.display(),
+                        vm.ssh_port
+                    );
+                    let provisioning = Provisioning::Ignition {
+                        name,
+                        config: &config,
+                    };
+                    vm::boot(image.arch(), &overlay, provisioning, vm)
+                })
+        }
+        None => {
+            let user_data = disk.with_file_name(format!("{stem}-run.user-data"));
+            let seed = match seed {
+                Some(seed) => seed,
+                None => {
+                    scratch.push(user_data.clone());
+                    default_seed(user_data, ssh_key)?
+                }
+            };
+            let seed_path = seed::path_for(&overlay, seed.format);
+            scratch.push(seed_path.clone());
+            qemu_img::create_overlay(disk, &overlay, size)
+                .and_then(|_| seed::build(&seed_path, &seed, image.os()))
+                .and_then(|_| {
+                    println!(
+                        "Booting {} (Ctrl-a x quits); once cloud-init is done: ssh -p {} <default user>@127.0.0.1",
+                        overlay.display(),
+                        vm.ssh_port
+                    );
+                    vm::boot(image.arch(), &overlay, Provisioning::Seed(&seed_path), vm)
+                })
+        }
+    };
+    if keep {
+        let kept: Vec<String> = scratch
+            .iter()
+            .filter(|path| path.is_file())
+            .map(|path| path.display().to_string())
+            .collect();
+        println!("Kept {}", kept.join(" and "));
+    } else {
+        for path in scratch {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+    result.map_err(anyhow::Error::msg)
+}
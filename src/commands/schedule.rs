@@ -0,0 +1,78 @@
+use anyhow::{Result, bail};
+
+use cloud_images_downloader::{
+    CancellationToken, DEFAULT_TRACK,
+    helpers::schedule::{self, Frequency, Output},
+};
+
+use super::{download_root, select_image};
+use crate::cli::{Cli, ScheduleCommand};
+use crate::config::Config;
+
+/// `schedule`: install or remove a job refreshing the selection (or the
+/// manifest) into the output directory.
+pub async fn run(cli: &Cli, config: &Config, action: &ScheduleCommand) -> Result<()> {
+    let (latest, frequency, cron, name) = match action {
+        ScheduleCommand::Remove { name, cron } => {
+            if schedule::remove(name, *cron).map_err(anyhow::Error::msg)? {
+                println!("Removed {name}");
+            } else {
+                println!("No job named {name}");
+            }
+            return Ok(());
+        }
+        ScheduleCommand::Install {
+            latest,
+            daily,
+            weekly: _,
+            monthly,
+            on_calendar,
+            cron,
+            name,
+        } => {
+            let frequency = match on_calendar {
+                Some(spec) => Frequency::Calendar(spec.clone()),
+                None if *daily => Frequency::Daily,
+                None if *monthly => Frequency::Monthly,
+                None => Frequency::Weekly,
+            };
+            (*latest, frequency, *cron, name.as_deref())
+        }
+    };
+    if latest && cli.build.is_some() {
+        bail!("--latest follows the newest build; drop --build");
+    }
+    if let Some(name) = name {
+        schedule::validate_name(name).map_err(anyhow::Error::msg)?;
+    }
+
+    let (dir, flat) = download_root(cli, config);
+    let output = Output {
+        dir,
+        flat,
+        indexes: cli.indexes.clone(),
+    };
+    let job = match &cli.manifest {
+        Some(manifest) => schedule::manifest_job(manifest, &output, name),
+        None => {
+            let mut query = cli.image_query();
+            if query.build_id().is_none() {
+                query = query.latest();
+            }
+            let selection = select_image(
+                DEFAULT_TRACK,
+                &query,
+                cli.max_builds.or(config.max_builds()),
+                &CancellationToken::new(),
+                cli.offline,
+            )
+            .await?;
+            let build = cli.build.as_deref().unwrap_or("latest");
+            schedule::selection_job(&selection, build, &output, name)
+        }
+    }
+    .map_err(anyhow::Error::msg)?;
+    let installed = schedule::install(&job, &frequency, cron).map_err(anyhow::Error::msg)?;
+    println!("{installed}");
+    Ok(())
+}
@@ -0,0 +1,62 @@
+use std::{net::SocketAddr, path::Path, time::Duration};
+
+use anyhow::{Context, Result, bail};
+use tokio::net::TcpListener;
+
+use cloud_images_downloader::helpers::{
+    file_server::FileServer,
+    library::Library,
+    server::{ServeOptions, Server},
+};
+
+use super::download::Downloads;
+use crate::cli::Cli;
+use crate::config::Config;
+
+/// `serve`: answer the JSON API on `listen`, downloading into the output
+/// directory and checking for newer builds every `recheck` seconds.
+pub async fn api(cli: &Cli, config: &Config, listen: &SocketAddr, recheck: u64) -> Result<()> {
+    let downloads = Downloads::new(cli, config)?;
+    let server = Server::new(ServeOptions {
+        root: downloads.root,
+        flat: downloads.flat,
+        jobs: downloads.jobs,
+        library: downloads
+            .options
+            .library
+            .clone()
+            .or_else(|| Library::open_default().ok()),
+        recheck: Some(Duration::from_secs(recheck)).filter(|d| !d.is_zero()),
+        download: downloads.options,
+    });
+    let listener = TcpListener::bind(listen)
+        .await
+        .with_context(|| format!("listen on {listen}"))?;
+    eprintln!("Serving the API on http://{}", listener.local_addr()?);
+    server.serve(listener).await.map_err(anyhow::Error::msg)
+}
+
+/// `serve-files`: serve `dir`, or the image library, read-only on `listen`.
+pub async fn files(dir: Option<&Path>, listen: &SocketAddr) -> Result<()> {
+    let root = match dir {
+        Some(dir) => dir.to_path_buf(),
+        None => Library::open_default()
+            .map_err(anyhow::Error::msg)?
+            .images_dir(),
+    };
+    if !root.is_dir() {
+        bail!("{} is not a directory", root.display());
+    }
+    let listener = TcpListener::bind(listen)
+        .await
+        .with_context(|| format!("listen on {listen}"))?;
+    eprintln!(
+        "Serving {} on http://{}/",
+        root.display(),
+        listener.local_addr()?
+    );
+    FileServer::new(root)
+        .serve(listener)
+        .await
+        .map_err(anyhow::Error::msg)
+}
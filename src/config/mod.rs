@@ -0,0 +1,423 @@
+use std::{collections::HashMap, fs, path::PathBuf, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result};
+use reqwest::Url;
+use serde::Deserialize;
+
+use cloud_images_downloader::helpers::{
+    image_resolver::{OnMismatch, external::Downloader},
+    notify::{Notifier, Webhook},
+    paths,
+    progress::ProgressMode,
+    proxmox,
+    qemu_img::DiskFormat,
+    retention::{Retention, parse_size},
+    s3,
+};
+use cloud_images_downloader::repositories::Refresh;
+
+const CONFIG_FILE: &str = "config.toml";
+const INDEXES_FILE: &str = "indexes.json";
+const DOWNLOADS_SUBDIR: &str = "cloud-images";
+/// Downloads shorter than this are not announced by default.
+const DEFAULT_NOTIFY_AFTER: u64 = 60;
+
+/// User preferences read from `$XDG_CONFIG_HOME/cloud-images-downloader/config.toml`
+/// (or the platform equivalent). Command line flags take precedence over the
+/// values stored here.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Limit the image version menu to the most recent N builds.
+    max_builds: Option<usize>,
+    /// Root directory for downloaded images.
+    download_dir: Option<PathBuf>,
+    /// Directory for caches and temporary files.
+    cache_dir: Option<PathBuf>,
+    /// Store downloads directly in `download_dir` instead of
+    /// `<distro>/<version>/<arch>` subfolders.
+    flat: bool,
+    /// Concurrent range requests per download.
+    connections: Option<usize>,
+    /// Concurrent image downloads in manifest mode.
+    jobs: Option<usize>,
+    /// Number of tries for every HTTP request.
+    retries: Option<u32>,
+    /// Bandwidth cap such as `10M`.
+    limit_rate: Option<String>,
+    /// Proxy URL applied to every request.
+    proxy: Option<String>,
+    /// Connection timeout in seconds.
+    connect_timeout: Option<u64>,
+    /// Stall timeout in seconds.
+    read_timeout: Option<u64>,
+    /// Overall per-request deadline in seconds.
+    timeout: Option<u64>,
+    /// Extra `Name: value` headers sent with every request.
+    headers: Vec<String>,
+    /// User-Agent override.
+    user_agent: Option<String>,
+    /// Redirect hops followed per request.
+    max_redirects: Option<usize>,
+    /// Decompress compressed images after download.
+    decompress: bool,
+    /// Target format for `qemu-img convert`.
+    convert_to: Option<DiskFormat>,
+    /// Size passed to `qemu-img resize`.
+    resize: Option<String>,
+    /// Rebuild outdated images from their `.zsync` control file (default on).
+    zsync: Option<bool>,
+    /// Program performing the transfers.
+    downloader: Option<Downloader>,
+    /// Use Metalink mirrors and hashes when published.
+    metalink: bool,
+    /// Use published torrents (aria2c only).
+    torrent: bool,
+    /// Keyrings trusted for checksum file signatures.
+    keyrings: Vec<PathBuf>,
+    /// Refuse checksum files whose signature cannot be verified.
+    require_signature: bool,
+    /// Pin repository metadata on first use and warn about changes.
+    trust_store: bool,
+    /// Staging directory for downloads awaiting verification.
+    quarantine_dir: Option<PathBuf>,
+    /// Tag downloads with extended attributes.
+    xattrs: bool,
+    /// Write provenance statements next to downloads.
+    provenance: bool,
+    /// `s3://bucket/prefix/` verified downloads are uploaded to.
+    upload: Option<String>,
+    /// Keep downloads in the managed image library.
+    library: bool,
+    /// Apply the retention limits after every download.
+    auto_gc: bool,
+    /// Retention limits of the checksum cache.
+    cache_retention: RetentionConfig,
+    /// Retention limits of the image library.
+    library_retention: RetentionConfig,
+    /// Handling of downloads failing verification.
+    on_mismatch: Option<OnMismatch>,
+    /// How download progress is reported.
+    progress: Option<ProgressMode>,
+    /// Preferred mirror roots per repository name, best first.
+    mirrors: HashMap<String, Vec<String>>,
+    /// Metadata refresh intervals per repository name, replacing the ones
+    /// of `indexes.json`.
+    refresh: HashMap<String, Refresh>,
+    /// Proxmox VE API access for `proxmox create`.
+    proxmox: ProxmoxConfig,
+    /// Where new releases and long downloads are announced.
+    notifications: NotificationsConfig,
+}
+
+/// `[notifications]` table.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct NotificationsConfig {
+    /// Seconds a download has to take to be announced.
+    min_duration: Option<u64>,
+    /// Show long downloads as desktop notifications.
+    desktop: bool,
+    webhooks: Vec<Webhook>,
+}
+
+/// `[proxmox]` table.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxmoxConfig {
+    /// e.g. `https://pve.lan:8006`.
+    url: Option<String>,
+    /// `user@realm!token-name`.
+    token_id: Option<String>,
+    /// Falls back to `$PROXMOX_TOKEN_SECRET`.
+    token_secret: Option<String>,
+    /// Accept a self-signed certificate.
+    insecure: bool,
+    pub node: Option<String>,
+    /// Storage receiving the uploaded image (needs the `import` content
+    /// type).
+    pub storage: Option<String>,
+    /// Storage of the VM disks and the cloud-init drive.
+    pub disk_storage: Option<String>,
+    pub bridge: Option<String>,
+}
+
+impl ProxmoxConfig {
+    /// Connection settings; the URL and the token are required.
+    pub fn settings(&self) -> Result<proxmox::Settings> {
+        let missing = |key: &str| format!("set `{key}` in the [proxmox] table of the config file");
+        Ok(proxmox::Settings {
+            url: self.url.clone().with_context(|| missing("url"))?,
+            token_id: self.token_id.clone().with_context(|| missing("token_id"))?,
+            token_secret: self
+                .token_secret
+                .clone()
+                .or_else(|| std::env::var("PROXMOX_TOKEN_SECRET").ok())
+                .with_context(|| missing("token_secret"))?,
+            insecure: self.insecure,
+        })
+    }
+}
+
+/// `[cache_retention]` / `[library_retention]` tables.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RetentionConfig {
+    /// Total size such as `50G`.
+    max_size: Option<String>,
+    max_age_days: Option<u64>,
+    keep_last: Option<usize>,
+}
+
+impl RetentionConfig {
+    fn parse(&self) -> Result<Retention> {
+        Ok(Retention {
+            max_size: self
+                .max_size
+                .as_deref()
+                .map(parse_size)
+                .transpose()
+                .map_err(anyhow::Error::msg)?,
+            max_age_days: self.max_age_days,
+            keep_last: self.keep_last,
+        })
+    }
+}
+
+impl Config {
+    /// Location of the user configuration file, if the platform exposes a
+    /// configuration directory.
+    pub fn path() -> Option<PathBuf> {
+        paths::config_dir().map(|dir| dir.join(CONFIG_FILE))
+    }
+
+    /// Location of the user's `indexes.json`, whose repositories replace or
+    /// extend the embedded ones by name.
+    pub fn indexes_path() -> Option<PathBuf> {
+        paths::config_dir().map(|dir| dir.join(INDEXES_FILE))
+    }
+
+    /// Load the user configuration. A missing file yields the defaults while a
+    /// malformed one is reported to the caller.
+    pub fn load() -> Result<Self> {
+        let Some(path) = Self::path() else {
+            return Ok(Self::default());
+        };
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let data =
+            fs::read_to_string(&path).with_context(|| format!("read config {}", path.display()))?;
+        toml::from_str(&data).with_context(|| format!("parse config {}", path.display()))
+    }
+
+    pub fn max_builds(&self) -> Option<usize> {
+        self.max_builds
+    }
+
+    /// Configured download root, falling back to `~/Downloads/cloud-images`,
+    /// then `$XDG_DATA_HOME/cloud-images` and finally the current directory.
+    pub fn download_dir(&self) -> PathBuf {
+        self.download_dir
+            .clone()
+            .or_else(|| paths::downloads_dir().map(|dir| dir.join(DOWNLOADS_SUBDIR)))
+            .or_else(|| paths::shared_data_dir().map(|dir| dir.join(DOWNLOADS_SUBDIR)))
+            .unwrap_or_else(|| PathBuf::from("."))
+    }
+
+    pub fn cache_dir(&self) -> Option<PathBuf> {
+        self.cache_dir.clone()
+    }
+
+    pub fn flat(&self) -> bool {
+        self.flat
+    }
+
+    pub fn connections(&self) -> Option<usize> {
+        self.connections
+    }
+
+    pub fn jobs(&self) -> Option<usize> {
+        self.jobs
+    }
+
+    pub fn retries(&self) -> Option<u32> {
+        self.retries
+    }
+
+    pub fn limit_rate(&self) -> Option<&str> {
+        self.limit_rate.as_deref()
+    }
+
+    pub fn proxy(&self) -> Option<&str> {
+        self.proxy.as_deref()
+    }
+
+    pub fn connect_timeout(&self) -> Option<u64> {
+        self.connect_timeout
+    }
+
+    pub fn read_timeout(&self) -> Option<u64> {
+        self.read_timeout
+    }
+
+    pub fn timeout(&self) -> Option<u64> {
+        self.timeout
+    }
+
+    pub fn headers(&self) -> &[String] {
+        &self.headers
+    }
+
+    pub fn user_agent(&self) -> Option<&str> {
+        self.user_agent.as_deref()
+    }
+
+    pub fn max_redirects(&self) -> Option<usize> {
+        self.max_redirects
+    }
+
+    pub fn decompress(&self) -> bool {
+        self.decompress
+    }
+
+    pub fn convert_to(&self) -> Option<DiskFormat> {
+        self.convert_to
+    }
+
+    pub fn resize(&self) -> Option<&str> {
+        self.resize.as_deref()
+    }
+
+    pub fn zsync(&self) -> bool {
+        self.zsync.unwrap_or(true)
+    }
+
+    pub fn downloader(&self) -> Option<Downloader> {
+        self.downloader
+    }
+
+    pub fn metalink(&self) -> bool {
+        self.metalink
+    }
+
+    pub fn torrent(&self) -> bool {
+        self.torrent
+    }
+
+    pub fn keyrings(&self) -> &[PathBuf] {
+        &self.keyrings
+    }
+
+    pub fn require_signature(&self) -> bool {
+        self.require_signature
+    }
+
+    pub fn trust_store(&self) -> bool {
+        self.trust_store
+    }
+
+    pub fn quarantine_dir(&self) -> Option<PathBuf> {
+        self.quarantine_dir.clone()
+    }
+
+    pub fn proxmox(&self) -> &ProxmoxConfig {
+        &self.proxmox
+    }
+
+    pub fn xattrs(&self) -> bool {
+        self.xattrs
+    }
+
+    pub fn provenance(&self) -> bool {
+        self.provenance
+    }
+
+    pub fn upload(&self) -> Result<Option<Url>> {
+        self.upload
+            .as_deref()
+            .map(s3::upload::parse_prefix)
+            .transpose()
+            .map_err(anyhow::Error::msg)
+    }
+
+    pub fn library(&self) -> bool {
+        self.library
+    }
+
+    pub fn auto_gc(&self) -> bool {
+        self.auto_gc
+    }
+
+    pub fn cache_retention(&self) -> Result<Retention> {
+        self.cache_retention.parse().context("cache_retention")
+    }
+
+    pub fn library_retention(&self) -> Result<Retention> {
+        self.library_retention.parse().context("library_retention")
+    }
+
+    pub fn on_mismatch(&self) -> Option<OnMismatch> {
+        self.on_mismatch
+    }
+
+    pub fn progress(&self) -> Option<ProgressMode> {
+        self.progress
+    }
+
+    pub fn mirrors(&self) -> &HashMap<String, Vec<String>> {
+        &self.mirrors
+    }
+
+    /// The notifier of the `[notifications]` table, if it posts anywhere.
+    /// `desktop` turns desktop notifications on and `min_duration` overrides
+    /// the table's threshold, both from the command line.
+    pub fn notifier(&self, desktop: bool, min_duration: Option<u64>) -> Option<Arc<Notifier>> {
+        let notifier = Notifier {
+            webhooks: self.notifications.webhooks.clone(),
+            desktop: desktop || self.notifications.desktop,
+            min_duration: Duration::from_secs(
+                min_duration
+                    .or(self.notifications.min_duration)
+                    .unwrap_or(DEFAULT_NOTIFY_AFTER),
+            ),
+        };
+        (!notifier.is_empty()).then(|| Arc::new(notifier))
+    }
+
+    pub fn refresh(&self) -> &HashMap<String, Refresh> {
+        &self.refresh
+    }
+
+    /// Store the preferred mirror order for `repo` in the configuration file,
+    /// keeping every other setting already present there.
+    pub fn save_mirror_ranking(repo: &str, roots: &[String]) -> Result<PathBuf> {
+        let path = Self::path().context("no configuration directory on this platform")?;
+
+        let mut table: toml::Table = if path.exists() {
+            let data = fs::read_to_string(&path)
+                .with_context(|| format!("read config {}", path.display()))?;
+            toml::from_str(&data).with_context(|| format!("parse config {}", path.display()))?
+        } else {
+            toml::Table::new()
+        };
+
+        let mirrors = table
+            .entry("mirrors")
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+            .as_table_mut()
+            .context("`mirrors` in the config file must be a table")?;
+        mirrors.insert(
+            repo.to_string(),
+            toml::Value::Array(roots.iter().cloned().map(toml::Value::String).collect()),
+        );
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+        }
+        fs::write(&path, toml::to_string(&table)?)
+            .with_context(|| format!("write config {}", path.display()))?;
+        Ok(path)
+    }
+}
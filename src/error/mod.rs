@@ -0,0 +1,104 @@
+use crate::repositories::ReposError;
+
+/// Result type of the library entry points.
+pub type Result<T, E = CloudImagesError> = std::result::Result<T, E>;
+
+/// Why resolving or downloading an image failed. Every variant carries the
+/// human readable message; the variant tells callers what kind of failure it
+/// was and [`exit_code`](Self::exit_code) maps it to a process exit status.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum CloudImagesError {
+    /// No image matches the request, or a listing could not be understood.
+    #[error("{0}")]
+    Resolution(String),
+    /// A server could not be reached or answered with an error.
+    #[error("{0}")]
+    Network(String),
+    /// The downloaded file does not match its published checksum.
+    #[error("{0}")]
+    Checksum(String),
+    /// Reading or writing a local file failed.
+    #[error("{0}")]
+    Io(String),
+    /// The repositories or settings are missing or invalid.
+    #[error("{0}")]
+    Config(String),
+    /// The user backed out of a prompt.
+    #[error("{0}")]
+    Cancelled(String),
+}
+
+impl CloudImagesError {
+    /// Process exit status for the error: `2` is left to argument parsing and
+    /// `130` follows the shell convention for an interrupted command.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            CloudImagesError::Resolution(_) => 3,
+            CloudImagesError::Network(_) => 4,
+            CloudImagesError::Checksum(_) => 5,
+            CloudImagesError::Io(_) => 6,
+            CloudImagesError::Config(_) => 7,
+            CloudImagesError::Cancelled(_) => 130,
+        }
+    }
+}
+
+impl From<ReposError> for CloudImagesError {
+    fn from(err: ReposError) -> Self {
+        CloudImagesError::Config(err.to_string())
+    }
+}
+
+/// Classify the `anyhow` errors of the providers and the wizard by the first
+/// typed cause in their chain; anything unrecognised failed to resolve.
+impl From<anyhow::Error> for CloudImagesError {
+    fn from(err: anyhow::Error) -> Self {
+        let err = match err.downcast::<CloudImagesError>() {
+            Ok(typed) => return typed,
+            Err(err) => err,
+        };
+        let message = format!("{err:#}");
+        for cause in err.chain() {
+            if let Some(typed) = cause.downcast_ref::<CloudImagesError>() {
+                return typed.clone();
+            }
+            if cause.is::<reqwest::Error>() {
+                return CloudImagesError::Network(message);
+            }
+            if cause.is::<ReposError>() {
+                return CloudImagesError::Config(message);
+            }
+            if cause.is::<std::io::Error>() {
+                return CloudImagesError::Io(message);
+            }
+        }
+        CloudImagesError::Resolution(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::CloudImagesError;
+    use crate::repositories::ReposError;
+
+    #[test]
+    fn classifies_anyhow_chains_by_their_cause() {
+        let err = Err::<(), _>(ReposError::NotInitialized)
+            .context("load repositories")
+            .unwrap_err();
+        let err = CloudImagesError::from(err);
+        assert_eq!(
+            err,
+            CloudImagesError::Config("load repositories: repositories are not initialized".into())
+        );
+        assert_eq!(err.exit_code(), 7);
+
+        let cancelled = anyhow::Error::new(CloudImagesError::Cancelled("No selection made".into()));
+        assert_eq!(CloudImagesError::from(cancelled).exit_code(), 130);
+
+        let other = CloudImagesError::from(anyhow::anyhow!("No Debian images found"));
+        assert!(matches!(other, CloudImagesError::Resolution(_)));
+    }
+}
@@ -0,0 +1,114 @@
+use std::path::Path;
+
+use clap::ValueEnum;
+use serde_json::{Value, json};
+
+use crate::cloud::{ChecksumKind, Image};
+
+/// How `--ansible` and `export ansible` print images.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum AnsibleFormat {
+    /// A module result (`{"changed": false, "images": [...]}`), for
+    /// `register:` on a `command` task.
+    #[default]
+    Result,
+    /// A dynamic inventory (`all.vars.cloud_images`), for an inventory
+    /// script wrapping the call.
+    Inventory,
+}
+
+/// Checksum algorithms `ansible.builtin.get_url` verifies, most widely
+/// expected first: roles usually take a `sha256:` checksum.
+const ALGORITHMS: &[ChecksumKind] = &[
+    ChecksumKind::Sha256,
+    ChecksumKind::Sha512,
+    ChecksumKind::Sha1,
+    ChecksumKind::Md5,
+];
+
+/// `image` in the shape `get_url` takes: `url`, `checksum` as
+/// `<algo>:<hex>` (empty, which skips verification, when no usable checksum
+/// is published) and `dest`, the path this tool would download it to, plus
+/// what identifies the image.
+pub fn facts(image: &Image, dest: &Path) -> Value {
+    let checksum = image
+        .checksums()
+        .iter()
+        .filter(|checksum| ALGORITHMS.contains(&checksum.kind()))
+        .min_by_key(|checksum| ALGORITHMS.iter().position(|kind| *kind == checksum.kind()))
+        .map(|checksum| format!("{}:{}", checksum.kind().as_str(), checksum.value()))
+        .unwrap_or_default();
+    json!({
+        "url": image.url(),
+        "checksum": checksum,
+        "dest": dest.display().to_string(),
+        "id": image.id(),
+        "distro": image.os(),
+        "release": image.name(),
+        "distro_version": image.distro_version(),
+        "version": image.version(),
+        "arch": image.arch_name(),
+        "variant": image.variant().as_str(),
+        "format": image.format().as_str(),
+        "size": image.size(),
+    })
+}
+
+/// `images` (as [`facts`]) rendered as `format`.
+pub fn render(images: Vec<Value>, format: AnsibleFormat) -> String {
+    let value = match format {
+        AnsibleFormat::Result => json!({ "changed": false, "images": images }),
+        AnsibleFormat::Inventory => json!({
+            "all": { "hosts": [], "vars": { "cloud_images": images } },
+            "_meta": { "hostvars": {} },
+        }),
+    };
+    let mut text = serde_json::to_string_pretty(&value).expect("JSON values serialise");
+    text.push('\n');
+    text
+}
+
+/// The module result reporting a failed run, printed on stdout so a
+/// `command` task with `failed_when: (out.stdout | from_json).failed` (or
+/// the exit status `rc`) sees why.
+pub fn failure(msg: &str, rc: u8) -> String {
+    json!({ "changed": false, "failed": true, "msg": msg, "rc": rc }).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{AnsibleFormat, facts, failure, render};
+    use crate::cloud::{ChecksumKind, ImageChecksum, sample_image};
+
+    #[test]
+    fn describes_images_for_get_url() {
+        let image = sample_image().with_checksums([
+            ImageChecksum::new(ChecksumKind::Sha512, "cd"),
+            ImageChecksum::new(ChecksumKind::Sha256, "ab"),
+        ]);
+        let dest = Path::new("/srv/images/debian-12-genericcloud-amd64.qcow2");
+        let fact = facts(&image, dest);
+        assert_eq!(fact["checksum"], "sha256:ab");
+        assert_eq!(
+            fact["dest"],
+            "/srv/images/debian-12-genericcloud-amd64.qcow2"
+        );
+        assert_eq!(fact["arch"], "amd64");
+
+        let result: serde_json::Value =
+            serde_json::from_str(&render(vec![fact.clone()], AnsibleFormat::Result)).unwrap();
+        assert_eq!(result["changed"], false);
+        assert_eq!(result["images"][0], fact);
+        let inventory: serde_json::Value =
+            serde_json::from_str(&render(vec![fact.clone()], AnsibleFormat::Inventory)).unwrap();
+        assert_eq!(inventory["all"]["vars"]["cloud_images"][0], fact);
+        assert!(inventory["_meta"]["hostvars"].is_object());
+
+        let failed: serde_json::Value =
+            serde_json::from_str(&failure("No image matches", 3)).unwrap();
+        assert_eq!(failed["failed"], true);
+        assert_eq!(failed["rc"], 3);
+    }
+}
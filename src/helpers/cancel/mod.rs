@@ -0,0 +1,97 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tokio::sync::Notify;
+
+use crate::error::CloudImagesError;
+
+/// Shared flag that aborts a resolution or download in progress. Clones
+/// observe the same flag, so the caller keeps one and hands the others to
+/// [`select`](crate::select) or
+/// [`DownloadOptions::cancel`](crate::DownloadOptions::cancel).
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Abort every operation holding a clone of this token.
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        self.inner.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Resolve once the token is cancelled.
+    pub async fn cancelled(&self) {
+        loop {
+            // Register before checking so a concurrent `cancel` is not missed.
+            let notified = self.inner.notify.notified();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Drive `future` to completion unless the token is cancelled first, in
+    /// which case the future is dropped (aborting its requests).
+    pub async fn run<T>(
+        &self,
+        what: &str,
+        future: impl Future<Output = T>,
+    ) -> Result<T, CloudImagesError> {
+        tokio::select! {
+            biased;
+            () = self.cancelled() => Err(cancelled(what)),
+            value = future => Ok(value),
+        }
+    }
+}
+
+/// The error reported for an operation stopped by a [`CancellationToken`].
+pub fn cancelled(what: &str) -> CloudImagesError {
+    CloudImagesError::Cancelled(format!("Cancelled {what}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::CancellationToken;
+    use crate::error::CloudImagesError;
+
+    #[tokio::test]
+    async fn cancel_aborts_pending_futures() {
+        let token = CancellationToken::new();
+        assert_eq!(token.run("nothing", async { 1 }).await, Ok(1));
+
+        let clone = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            clone.cancel();
+        });
+        let pending = token
+            .run("listing", tokio::time::sleep(Duration::from_secs(60)))
+            .await;
+        assert_eq!(
+            pending,
+            Err(CloudImagesError::Cancelled("Cancelled listing".to_string()))
+        );
+        assert!(token.is_cancelled());
+    }
+}
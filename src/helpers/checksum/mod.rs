@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use std::sync::OnceLock;
+
+use blake2::Blake2b512;
+use md5::Md5;
+use regex::Regex;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::cloud::{ChecksumKind, ImageChecksum};
+
+mod source;
+
+pub use self::source::ChecksumSource;
+
+/// Incremental hasher matching the algorithm advertised by an `ImageChecksum`.
+pub enum StreamHasher {
+    Md5(Md5),
+    Sha1(Sha1),
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Blake2b(Blake2b512),
+}
+
+impl StreamHasher {
+    pub fn new(kind: ChecksumKind) -> Self {
+        match kind {
+            ChecksumKind::Md5 => StreamHasher::Md5(Md5::new()),
+            ChecksumKind::Sha1 => StreamHasher::Sha1(Sha1::new()),
+            ChecksumKind::Sha256 => StreamHasher::Sha256(Sha256::new()),
+            ChecksumKind::Sha512 => StreamHasher::Sha512(Sha512::new()),
+            ChecksumKind::Blake2b => StreamHasher::Blake2b(Blake2b512::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            StreamHasher::Md5(h) => h.update(data),
+            StreamHasher::Sha1(h) => h.update(data),
+            StreamHasher::Sha256(h) => h.update(data),
+            StreamHasher::Sha512(h) => h.update(data),
+            StreamHasher::Blake2b(h) => h.update(data),
+        }
+    }
+
+    pub fn finalize_hex(self) -> String {
+        match self {
+            StreamHasher::Md5(h) => hex::encode(h.finalize()),
+            StreamHasher::Sha1(h) => hex::encode(h.finalize()),
+            StreamHasher::Sha256(h) => hex::encode(h.finalize()),
+            StreamHasher::Sha512(h) => hex::encode(h.finalize()),
+            StreamHasher::Blake2b(h) => hex::encode(h.finalize()),
+        }
+    }
+}
+
+/// Feed the contents of `path` into `hasher`.
+pub fn hash_file(path: &Path, hasher: &mut StreamHasher) -> Result<(), String> {
+    let mut file =
+        File::open(path).map_err(|e| format!("Failed to open '{}': {e}", path.display()))?;
+    let mut buf = vec![0u8; 1024 * 1024];
+    loop {
+        let n = file
+            .read(&mut buf)
+            .map_err(|e| format!("Error while reading '{}': {e}", path.display()))?;
+        if n == 0 {
+            return Ok(());
+        }
+        hasher.update(&buf[..n]);
+    }
+}
+
+/// Compare the digest computed while streaming with the expected checksum.
+pub fn verify_digest(expected: &ImageChecksum, actual: &str) -> Result<(), String> {
+    if expected.value().eq_ignore_ascii_case(actual) {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} mismatch: expected {}, got {actual}",
+            expected.kind(),
+            expected.value()
+        ))
+    }
+}
+
+/// Hash the file at `path` and compare it with `expected`.
+pub fn verify_file(path: &Path, expected: &ImageChecksum) -> Result<(), String> {
+    let mut hasher = StreamHasher::new(expected.kind());
+    hash_file(path, &mut hasher)?;
+    verify_digest(expected, &hasher.finalize_hex())
+}
+
+/// One line of a checksum listing such as `SHA256SUMS` or `CHECKSUM`.
+#[derive(Debug, Clone)]
+pub struct ChecksumEntry {
+    pub file: String,
+    pub checksum: ImageChecksum,
+    /// Size of the file, when the listing annotates it.
+    pub size: Option<u64>,
+}
+
+fn tagged_line_regex() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| {
+        Regex::new(r"^(?P<algo>[A-Za-z0-9-]+) ?\((?P<file>.+)\) ?= ?(?P<hash>[A-Fa-f0-9]+)$")
+            .expect("invalid tagged checksum regex")
+    })
+}
+
+fn plain_line_regex() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| {
+        Regex::new(r"^(?P<hash>[A-Fa-f0-9]+) [ *]?(?P<file>\S.*)$")
+            .expect("invalid plain checksum regex")
+    })
+}
+
+/// Parse one checksum line in either BSD (`SHA256 (file) = hash`) or GNU
+/// coreutils (`hash  file`, `hash *file`) format. The algorithm of GNU lines
+/// is taken from the digest length. Anything else yields `None`.
+pub fn parse_line(line: &str) -> Option<ChecksumEntry> {
+    let line = line.trim();
+    let (kind, file, hash) = if let Some(caps) = tagged_line_regex().captures(line) {
+        let kind = ChecksumKind::from_name(&caps["algo"])?;
+        (kind, caps.name("file")?, caps.name("hash")?)
+    } else {
+        let caps = plain_line_regex().captures(line)?;
+        let hash = caps.name("hash")?;
+        (
+            ChecksumKind::from_hex_len(hash.len())?,
+            caps.name("file")?,
+            hash,
+        )
+    };
+    if hash.len() != kind.hex_len() {
+        return None;
+    }
+    Some(ChecksumEntry {
+        file: file.as_str().to_string(),
+        checksum: ImageChecksum::new(kind, hash.as_str().to_lowercase()),
+        size: None,
+    })
+}
+
+/// Parse a `# file: 123456 bytes` annotation, as written into the `CHECKSUM`
+/// files of AlmaLinux and Fedora ahead of the digest lines.
+pub fn parse_size_annotation(line: &str) -> Option<(&str, u64)> {
+    let rest = line.trim().strip_prefix('#')?;
+    let (file, size) = rest.trim().rsplit_once(':')?;
+    let size = size.trim().strip_suffix("bytes")?.trim().parse().ok()?;
+    Some((file.trim(), size))
+}
+
+/// Every recognised entry of a checksum listing, skipping blank lines,
+/// comments and PGP armour. Sizes annotated in comments are attached to the
+/// entries of the same file.
+pub fn parse_listing(text: &str) -> Vec<ChecksumEntry> {
+    let sizes: HashMap<&str, u64> = text.lines().filter_map(parse_size_annotation).collect();
+    text.lines()
+        .filter_map(parse_line)
+        .map(|entry| ChecksumEntry {
+            size: sizes.get(entry.file.as_str()).copied(),
+            ..entry
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StreamHasher, parse_line, parse_listing, verify_digest, verify_file};
+    use crate::cloud::{ChecksumKind, ImageChecksum};
+
+    #[test]
+    fn streaming_hash_matches_known_digest() {
+        let mut hasher = StreamHasher::new(ChecksumKind::Sha256);
+        hasher.update(b"hello ");
+        hasher.update(b"world");
+        let digest = hasher.finalize_hex();
+        assert_eq!(
+            digest,
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        );
+    }
+
+    #[test]
+    fn mismatch_is_reported() {
+        let expected = ImageChecksum::new(ChecksumKind::Sha512, "00");
+        let err = verify_digest(&expected, "ff").unwrap_err();
+        assert!(err.contains("sha512 mismatch"));
+        assert!(verify_digest(&expected, "00").is_ok());
+    }
+
+    #[test]
+    fn verifies_files_on_disk() {
+        let path = std::env::temp_dir().join(format!("cid-checksum-{}", std::process::id()));
+        std::fs::write(&path, b"hello world").unwrap();
+        let good = ImageChecksum::new(
+            ChecksumKind::Sha256,
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9",
+        );
+        assert!(verify_file(&path, &good).is_ok());
+        let bad = ImageChecksum::new(ChecksumKind::Sha256, "00");
+        assert!(verify_file(&path, &bad).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn parses_gnu_and_bsd_lines() {
+        let sha256 = "ab".repeat(32);
+        let entry = parse_line(&format!("{sha256}  image.qcow2")).unwrap();
+        assert_eq!(entry.file, "image.qcow2");
+        assert_eq!(entry.checksum.kind(), ChecksumKind::Sha256);
+
+        let entry = parse_line(&format!("{} *image.raw", "0".repeat(32))).unwrap();
+        assert_eq!(entry.file, "image.raw");
+        assert_eq!(entry.checksum.kind(), ChecksumKind::Md5);
+
+        let entry = parse_line(&format!("SHA1 (cirros.img) = {}", "F".repeat(40))).unwrap();
+        assert_eq!(entry.file, "cirros.img");
+        assert_eq!(entry.checksum.kind(), ChecksumKind::Sha1);
+        assert_eq!(entry.checksum.value(), "f".repeat(40));
+
+        let entry = parse_line(&format!("BLAKE2b (base.tgz) = {}", "1".repeat(128))).unwrap();
+        assert_eq!(entry.checksum.kind(), ChecksumKind::Blake2b);
+    }
+
+    #[test]
+    fn rejects_noise_and_mislabelled_digests() {
+        assert!(parse_line("-----BEGIN PGP SIGNED MESSAGE-----").is_none());
+        assert!(parse_line("Hash: SHA256").is_none());
+        assert!(parse_line(&format!("SHA256 (x) = {}", "a".repeat(128))).is_none());
+        assert!(parse_line(&format!("{}  x", "a".repeat(50))).is_none());
+
+        let listing = format!(
+            "# comment\n\n{}  a.img\n{}  b.img\n",
+            "1".repeat(64),
+            "2".repeat(128)
+        );
+        let files: Vec<_> = parse_listing(&listing)
+            .into_iter()
+            .map(|e| e.file)
+            .collect();
+        assert_eq!(files, ["a.img", "b.img"]);
+    }
+
+    #[test]
+    fn hashes_every_kind() {
+        for (kind, digest) in [
+            (ChecksumKind::Md5, "5eb63bbbe01eeed093cb22bb8f5acdc3"),
+            (
+                ChecksumKind::Sha1,
+                "2aae6c35c94fcfb415dbe95f408b9ce91ee846ed",
+            ),
+        ] {
+            let mut hasher = StreamHasher::new(kind);
+            hasher.update(b"hello world");
+            assert_eq!(hasher.finalize_hex(), digest);
+        }
+        let mut hasher = StreamHasher::new(ChecksumKind::Blake2b);
+        hasher.update(b"hello world");
+        assert_eq!(hasher.finalize_hex().len(), ChecksumKind::Blake2b.hex_len());
+    }
+
+    #[test]
+    fn attaches_annotated_sizes() {
+        let sha256 = "ab".repeat(32);
+        let listing = format!(
+            "# AlmaLinux-9-GenericCloud-latest.x86_64.qcow2: 567803904 bytes\n\
+             SHA256 (AlmaLinux-9-GenericCloud-latest.x86_64.qcow2) = {sha256}\n\
+             SHA256 (other.qcow2) = {sha256}\n"
+        );
+        let entries = parse_listing(&listing);
+        assert_eq!(entries[0].size, Some(567803904));
+        assert_eq!(entries[1].size, None);
+    }
+}
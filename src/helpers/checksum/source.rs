@@ -0,0 +1,143 @@
+use reqwest::Client;
+
+use super::{ChecksumEntry, parse_listing};
+use crate::cloud::{ChecksumKind, ImageChecksum};
+use crate::helpers::{
+    gpg::{self, Signing, Verified},
+    http_cache, trust,
+};
+
+/// Where the checksum of an artifact is published.
+#[derive(Debug, Clone)]
+pub enum ChecksumSource {
+    /// One listing covering a whole directory (`SHA512SUMS`, `CHECKSUM`),
+    /// signed the way `signing` says when set.
+    Combined {
+        url: String,
+        repo: &'static str,
+        signing: Option<Signing>,
+    },
+    /// One file per artifact at `<artifact URL><suffix>`, e.g. `.sha256`.
+    Sidecar(&'static str),
+    /// Part of the index the provider already parsed (Simplestreams).
+    Embedded(Option<ImageChecksum>),
+}
+
+impl ChecksumSource {
+    /// Every checksum the source publishes; `artifact_url` names the file a
+    /// per-artifact source belongs to. `Ok(None)` means nothing could be
+    /// fetched, while a listing failing signature verification is an error.
+    pub async fn entries(
+        &self,
+        client: &Client,
+        artifact_url: &str,
+    ) -> Result<Option<Vec<ChecksumEntry>>, String> {
+        match self {
+            ChecksumSource::Combined { url, repo, signing } => {
+                listing(client, url, repo, *signing).await
+            }
+            ChecksumSource::Sidecar(suffix) => Ok(sidecar(
+                client,
+                &format!("{artifact_url}{suffix}"),
+                file_name(artifact_url),
+            )
+            .await),
+            ChecksumSource::Embedded(checksum) => Ok(checksum.clone().map(|checksum| {
+                vec![ChecksumEntry {
+                    file: file_name(artifact_url).to_string(),
+                    checksum,
+                    size: None,
+                }]
+            })),
+        }
+    }
+
+    /// Checksum of the artifact at `artifact_url`; `None` when the source
+    /// does not list it or is not published.
+    pub async fn resolve(
+        &self,
+        client: &Client,
+        artifact_url: &str,
+    ) -> Result<Option<ImageChecksum>, String> {
+        let name = file_name(artifact_url);
+        Ok(self
+            .entries(client, artifact_url)
+            .await?
+            .and_then(|entries| {
+                entries
+                    .into_iter()
+                    .find(|e| e.file.rsplit('/').next() == Some(name))
+                    .map(|e| e.checksum)
+            }))
+    }
+}
+
+fn file_name(url: &str) -> &str {
+    url.rsplit('/').find(|s| !s.is_empty()).unwrap_or(url)
+}
+
+/// Fetch the checksum listing at `url`, check its signature for `repo` when
+/// it is signed, compare it with the trust store and parse it.
+async fn listing(
+    client: &Client,
+    url: &str,
+    repo: &str,
+    signing: Option<Signing>,
+) -> Result<Option<Vec<ChecksumEntry>>, String> {
+    let Ok(body) = http_cache::text(client, url).await else {
+        return Ok(None);
+    };
+    let verified = match signing {
+        Some(signing) => gpg::verify_checksums(client, url, body, repo, signing).await?,
+        None => Verified {
+            text: body,
+            signer: None,
+        },
+    };
+    trust::observe(repo, url, &verified.text, verified.signer.as_deref()).await;
+    Ok(Some(parse_listing(&verified.text)))
+}
+
+/// Fetch a per-artifact checksum file. Besides regular listings these often
+/// hold nothing but the digest, which is then attributed to `name`.
+async fn sidecar(client: &Client, url: &str, name: &str) -> Option<Vec<ChecksumEntry>> {
+    let body = http_cache::text(client, url).await.ok()?;
+    let entries = parse_listing(&body);
+    if !entries.is_empty() {
+        return Some(entries);
+    }
+    bare_digest(&body, name).map(|entry| vec![entry])
+}
+
+/// A file consisting of a single hex digest.
+fn bare_digest(body: &str, name: &str) -> Option<ChecksumEntry> {
+    let digest = body.split_whitespace().next()?;
+    if body.split_whitespace().count() != 1 || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(ChecksumEntry {
+        file: name.to_string(),
+        checksum: ImageChecksum::new(
+            ChecksumKind::from_hex_len(digest.len())?,
+            digest.to_lowercase(),
+        ),
+        size: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::bare_digest;
+    use crate::cloud::ChecksumKind;
+
+    #[test]
+    fn bare_digests_are_attributed_to_the_artifact() {
+        let entry = bare_digest(&format!("{}\n", "A".repeat(64)), "image.qcow2").unwrap();
+        assert_eq!(entry.file, "image.qcow2");
+        assert_eq!(entry.checksum.kind(), ChecksumKind::Sha256);
+        assert_eq!(entry.checksum.value(), "a".repeat(64));
+
+        assert!(bare_digest("not a digest", "x").is_none());
+        assert!(bare_digest(&"a".repeat(50), "x").is_none());
+    }
+}
@@ -0,0 +1,298 @@
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use serde::Deserialize;
+
+use crate::cloud::ChecksumKind;
+use crate::helpers::checksum::{StreamHasher, hash_file};
+use crate::helpers::find_program;
+
+/// Changes `virt-customize` makes to a copy of the verified image. The keys
+/// of a manifest's `[hooks]` table mirror the command-line flags:
+///
+/// ```toml
+/// [hooks]
+/// install = ["qemu-guest-agent", "htop"]
+/// ssh_inject = ["root:~/.ssh/id_ed25519.pub"]
+/// root_password = "random"
+/// run = ["provision.sh"]
+/// run_command = ["systemctl enable qemu-guest-agent"]
+/// sysprep = true
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Customization {
+    /// Packages installed with the guest's package manager.
+    pub install: Vec<String>,
+    /// `USER:FILE` pairs; the public key in `FILE` is authorised for `USER`.
+    pub ssh_inject: Vec<String>,
+    /// `virt-customize` password selector: `password:SECRET`, `file:PATH`,
+    /// `random` or `disabled`.
+    pub root_password: Option<String>,
+    /// Scripts copied into the guest and run there, in order.
+    pub run: Vec<PathBuf>,
+    /// Shell commands run in the guest after the scripts.
+    pub run_command: Vec<String>,
+    /// Reset machine ids, SSH host keys and logs with `virt-sysprep` at the
+    /// end, so every VM booted from the image gets its own.
+    pub sysprep: bool,
+}
+
+impl Customization {
+    /// Whether there is nothing to change.
+    pub fn is_empty(&self) -> bool {
+        *self == Customization::default()
+    }
+
+    /// Add the changes of `other` after those of `self`; a root password in
+    /// `other` wins.
+    pub fn merge(mut self, other: Customization) -> Self {
+        self.install.extend(other.install);
+        self.ssh_inject.extend(other.ssh_inject);
+        self.root_password = other.root_password.or(self.root_password);
+        self.run.extend(other.run);
+        self.run_command.extend(other.run_command);
+        self.sysprep |= other.sysprep;
+        self
+    }
+
+    /// Check what can be checked before downloading: key and script files
+    /// exist and `ssh_inject` entries name a user.
+    pub fn validate(&self) -> Result<(), String> {
+        for entry in &self.ssh_inject {
+            match entry.split_once(':') {
+                Some((user, file)) if !user.is_empty() && !file.is_empty() => {
+                    require_file(Path::new(file))?
+                }
+                _ => return Err(format!("invalid SSH key '{entry}', expected USER:FILE")),
+            }
+        }
+        for script in &self.run {
+            require_file(script)?;
+        }
+        Ok(())
+    }
+
+    /// Arguments to `virt-customize` after `-a <image>`.
+    pub fn args(&self) -> Vec<OsString> {
+        let mut args: Vec<OsString> = Vec::new();
+        if !self.install.is_empty() {
+            args.push("--install".into());
+            args.push(self.install.join(",").into());
+        }
+        for entry in &self.ssh_inject {
+            if let Some((user, file)) = entry.split_once(':') {
+                args.push("--ssh-inject".into());
+                args.push(format!("{user}:file:{file}").into());
+            }
+        }
+        if let Some(selector) = &self.root_password {
+            args.push("--root-password".into());
+            args.push(selector.into());
+        }
+        for script in &self.run {
+            args.push("--run".into());
+            args.push(script.into());
+        }
+        for command in &self.run_command {
+            args.push("--run-command".into());
+            args.push(command.into());
+        }
+        args
+    }
+}
+
+fn require_file(path: &Path) -> Result<(), String> {
+    if path.is_file() {
+        Ok(())
+    } else {
+        Err(format!("'{}' is not a file", path.display()))
+    }
+}
+
+fn missing(program: &str) -> String {
+    format!(
+        "{program} was not found on PATH; install libguestfs (e.g. `apt install \
+         libguestfs-tools` or `dnf install guestfs-tools`) to customize images"
+    )
+}
+
+/// Locate `virt-customize`, and `virt-sysprep` when `customization` asks
+/// for it.
+pub fn locate(customization: &Customization) -> Result<(), String> {
+    find_program("virt-customize").ok_or_else(|| missing("virt-customize"))?;
+    if customization.sysprep {
+        find_program("virt-sysprep").ok_or_else(|| missing("virt-sysprep"))?;
+    }
+    Ok(())
+}
+
+/// Where the customized derivative of the image at `image` is written:
+/// `<stem>-custom.<ext>` next to it.
+pub fn path_for(image: &Path) -> PathBuf {
+    let stem = image
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "image".to_string());
+    let mut name = format!("{stem}-custom");
+    if let Some(ext) = image.extension() {
+        name.push('.');
+        name.push_str(&ext.to_string_lossy());
+    }
+    image.with_file_name(name)
+}
+
+/// Path of the `sha256sum`-style checksum file written for `image`.
+pub fn checksum_path(image: &Path) -> PathBuf {
+    let mut path = image.as_os_str().to_owned();
+    path.push(".sha256");
+    PathBuf::from(path)
+}
+
+fn run(program: &str, image: &Path, args: &[OsString]) -> Result<(), String> {
+    let path = find_program(program).ok_or_else(|| missing(program))?;
+    let output = Command::new(&path)
+        .arg("-a")
+        .arg(image)
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run '{}': {e}", path.display()))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "{program} failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+/// Apply `customization` to a copy of `image` (see [`path_for`]), leaving
+/// the original byte-identical to its published checksum, and record the
+/// copy's SHA-256 next to it. Returns the copy and its digest. Like the
+/// `qemu-img` steps, the copy only appears under its final name once every
+/// tool succeeded.
+pub fn apply(image: &Path, customization: &Customization) -> Result<(PathBuf, String), String> {
+    let target = path_for(image);
+    let mut part = target.clone().into_os_string();
+    part.push(".part");
+    let part = PathBuf::from(part);
+
+    let result = fs::copy(image, &part)
+        .map_err(|e| format!("Failed to copy '{}': {e}", image.display()))
+        .and_then(|_| run("virt-customize", &part, &customization.args()))
+        .and_then(|_| {
+            if customization.sysprep {
+                run("virt-sysprep", &part, &[])
+            } else {
+                Ok(())
+            }
+        })
+        .and_then(|_| {
+            fs::rename(&part, &target)
+                .map_err(|e| format!("Failed to move '{}': {e}", part.display()))
+        });
+    if result.is_err() {
+        let _ = fs::remove_file(&part);
+    }
+    result?;
+
+    let digest = write_checksum(&target)?;
+    Ok((target, digest))
+}
+
+/// Hash `image` and write `<hex>  <name>` to its `.sha256` file.
+pub fn write_checksum(image: &Path) -> Result<String, String> {
+    let mut hasher = StreamHasher::new(ChecksumKind::Sha256);
+    hash_file(image, &mut hasher)?;
+    let digest = hasher.finalize_hex();
+    let name = image
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let path = checksum_path(image);
+    File::create(&path)
+        .and_then(|mut file| writeln!(file, "{digest}  {name}"))
+        .map_err(|e| format!("Failed to write '{}': {e}", path.display()))?;
+    Ok(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Customization, checksum_path, path_for, write_checksum};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn builds_virt_customize_arguments() {
+        let hooks: Customization = toml::from_str(
+            r#"
+            install = ["htop", "vim"]
+            ssh_inject = ["root:/keys/id.pub"]
+            run = ["setup.sh"]
+            "#,
+        )
+        .unwrap();
+        let cli = Customization {
+            root_password: Some("random".to_string()),
+            run_command: vec!["touch /etc/ready".to_string()],
+            sysprep: true,
+            ..Customization::default()
+        };
+        let merged = hooks.merge(cli);
+        let args: Vec<_> = merged
+            .args()
+            .into_iter()
+            .map(|arg| arg.into_string().unwrap())
+            .collect();
+        assert_eq!(
+            args,
+            [
+                "--install",
+                "htop,vim",
+                "--ssh-inject",
+                "root:file:/keys/id.pub",
+                "--root-password",
+                "random",
+                "--run",
+                "setup.sh",
+                "--run-command",
+                "touch /etc/ready"
+            ]
+        );
+        assert!(merged.sysprep);
+        assert!(Customization::default().is_empty());
+        assert!(toml::from_str::<Customization>("packages = [\"vim\"]").is_err());
+
+        let bad = Customization {
+            ssh_inject: vec!["/keys/id.pub".to_string()],
+            ..Customization::default()
+        };
+        assert!(bad.validate().is_err());
+    }
+
+    #[test]
+    fn records_the_derivative_checksum() {
+        assert_eq!(
+            path_for(Path::new("/images/noble.qcow2")),
+            PathBuf::from("/images/noble-custom.qcow2")
+        );
+        let dir = std::env::temp_dir().join(format!("cid-customize-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let image = dir.join("noble-custom.img");
+        std::fs::write(&image, b"abc").unwrap();
+        let digest = write_checksum(&image).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(checksum_path(&image)).unwrap(),
+            format!("{digest}  noble-custom.img\n")
+        );
+        assert_eq!(
+            digest,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
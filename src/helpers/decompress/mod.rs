@@ -0,0 +1,117 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use bzip2::read::BzDecoder;
+use flate2::read::GzDecoder;
+use xz2::read::XzDecoder;
+
+/// Compression formats images are commonly shipped in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Xz,
+    Bzip2,
+    Zstd,
+    Gzip,
+}
+
+impl Compression {
+    /// Detect the format from the file extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "xz" => Some(Compression::Xz),
+            "bz2" => Some(Compression::Bzip2),
+            "zst" | "zstd" => Some(Compression::Zstd),
+            "gz" => Some(Compression::Gzip),
+            _ => None,
+        }
+    }
+
+    fn decoder<'a>(&self, input: impl Read + 'a) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Compression::Xz => Box::new(XzDecoder::new_multi_decoder(input)),
+            Compression::Bzip2 => Box::new(BzDecoder::new(input)),
+            Compression::Zstd => Box::new(zstd::Decoder::new(input)?),
+            Compression::Gzip => Box::new(GzDecoder::new(input)),
+        })
+    }
+}
+
+/// Decompress `path` next to itself (dropping the compression extension) and
+/// return the path of the uncompressed image, or `None` when the file is not
+/// compressed. The output is written to `<image>.part` and renamed when
+/// complete; an output newer than `path` is reused as is.
+pub fn decompress_file(path: &Path) -> Result<Option<PathBuf>, String> {
+    let Some(compression) = Compression::from_path(path) else {
+        return Ok(None);
+    };
+    let target = path.with_extension("");
+
+    let modified = |p: &Path| fs::metadata(p).and_then(|m| m.modified()).ok();
+    if let (Some(source), Some(existing)) = (modified(path), modified(&target))
+        && existing >= source
+    {
+        return Ok(Some(target));
+    }
+
+    let mut part = target.clone().into_os_string();
+    part.push(".part");
+    let part = PathBuf::from(part);
+
+    let input =
+        File::open(path).map_err(|e| format!("Failed to open '{}': {e}", path.display()))?;
+    let result = compression
+        .decoder(BufReader::new(input))
+        .and_then(|mut decoder| {
+            let mut output = File::create(&part)?;
+            io::copy(&mut decoder, &mut output)?;
+            output.sync_all()
+        })
+        .map_err(|e| format!("Failed to decompress '{}': {e}", path.display()))
+        .and_then(|_| {
+            fs::rename(&part, &target)
+                .map_err(|e| format!("Failed to move '{}': {e}", part.display()))
+        });
+
+    if result.is_err() {
+        let _ = fs::remove_file(&part);
+    }
+    result.map(|_| Some(target))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Compression, decompress_file};
+    use flate2::{Compression as Level, write::GzEncoder};
+    use std::io::Write;
+    use std::path::Path;
+
+    #[test]
+    fn detects_format_from_extension() {
+        assert_eq!(
+            Compression::from_path(Path::new("Fedora.raw.xz")),
+            Some(Compression::Xz)
+        );
+        assert_eq!(
+            Compression::from_path(Path::new("arch.qcow2.ZST")),
+            Some(Compression::Zstd)
+        );
+        assert_eq!(Compression::from_path(Path::new("noble.img")), None);
+    }
+
+    #[test]
+    fn decompresses_next_to_the_source() {
+        let dir = std::env::temp_dir().join(format!("decompress-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("disk.raw.gz");
+
+        let mut encoder = GzEncoder::new(Vec::new(), Level::default());
+        encoder.write_all(b"raw disk bytes").unwrap();
+        std::fs::write(&source, encoder.finish().unwrap()).unwrap();
+
+        let target = decompress_file(&source).unwrap().unwrap();
+        assert_eq!(target, dir.join("disk.raw"));
+        assert_eq!(std::fs::read(&target).unwrap(), b"raw disk bytes");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
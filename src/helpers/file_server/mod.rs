@@ -0,0 +1,541 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fs;
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::stream;
+use http::header::{
+    ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, LAST_MODIFIED, LOCATION, RANGE,
+};
+use http::{HeaderMap, Method, Request, Response, StatusCode};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full, StreamBody};
+use hyper::body::{Frame, Incoming};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, percent_decode_str, utf8_percent_encode};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::net::TcpListener;
+
+use crate::cloud::ChecksumKind;
+use crate::helpers::checksum::{self, StreamHasher};
+use crate::helpers::human_size;
+use crate::helpers::mirror::{LISTINGS, SIDECARS, SIGNATURES};
+use crate::helpers::server::serve_http;
+
+/// Checksum listing generated for directories that do not publish one.
+const SUMS_FILE: &str = "SHA256SUMS";
+
+/// Characters escaped in the links of index pages: all but RFC 3986's
+/// unreserved ones.
+const HREF_ENCODE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Bytes read from disk per body frame.
+const CHUNK_SIZE: usize = 256 * 1024;
+
+type Body = BoxBody<Bytes, io::Error>;
+
+/// Size and modification time of a file, and its SHA-256 at that point.
+type Hashed = (u64, Option<SystemTime>, String);
+
+/// A requested byte range, both ends inclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ByteRange {
+    start: u64,
+    end: u64,
+}
+
+/// The single range of a `Range: bytes=...` header for a file of `len`
+/// bytes. `Ok(None)` serves the whole file (no header, or one this server
+/// does not understand such as several ranges); `Err` is unsatisfiable.
+fn parse_range(header: Option<&str>, len: u64) -> Result<Option<ByteRange>, ()> {
+    let Some(spec) = header.and_then(|h| h.trim().strip_prefix("bytes=")) else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let Some((first, last)) = spec.split_once('-') else {
+        return Ok(None);
+    };
+    let (first, last) = (first.trim(), last.trim());
+    let range = if first.is_empty() {
+        // `bytes=-N`: the last N bytes.
+        let suffix: u64 = last.parse().map_err(|_| ())?;
+        if suffix == 0 || len == 0 {
+            return Err(());
+        }
+        ByteRange {
+            start: len.saturating_sub(suffix),
+            end: len - 1,
+        }
+    } else {
+        let start: u64 = first.parse().map_err(|_| ())?;
+        let end = match last {
+            "" => len.saturating_sub(1),
+            last => last
+                .parse::<u64>()
+                .map_err(|_| ())?
+                .min(len.saturating_sub(1)),
+        };
+        if start >= len || end < start {
+            return Err(());
+        }
+        ByteRange { start, end }
+    };
+    Ok(Some(range))
+}
+
+/// The path segments of the request path `path`, percent-decoded. `None`
+/// for anything that could leave the served tree or names a hidden file
+/// (the mirror's bookkeeping, partial downloads).
+fn segments(path: &str) -> Option<Vec<String>> {
+    let decoded = percent_decode_str(path).decode_utf8().ok()?;
+    decoded
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| {
+            (!hidden(segment) && !segment.contains(['\\', '\0'])).then(|| segment.to_string())
+        })
+        .collect()
+}
+
+/// Names left out of index pages and refused: dotfiles (including `.` and
+/// `..`) and files still being written.
+fn hidden(name: &str) -> bool {
+    name.starts_with('.') || is_temporary(name)
+}
+
+/// Whether `name` is a file renamed into place once complete: a download's
+/// `.part`, `.segN` and `.part.segments` files, the `.json.tmp` and
+/// `.<n>.tmp` files of metadata writes and the library's store, and older
+/// `<sha256>.tmp` copies.
+fn is_temporary(name: &str) -> bool {
+    let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    if name.ends_with(".part")
+        || name.ends_with(".part.segments")
+        || name
+            .rsplit_once(".seg")
+            .is_some_and(|(stem, n)| !stem.is_empty() && digits(n))
+    {
+        return true;
+    }
+    let Some(stem) = name.strip_suffix(".tmp") else {
+        return false;
+    };
+    match stem.rsplit_once('.') {
+        Some((_, last)) => last == "json" || digits(last),
+        None => stem.len() == 64 && stem.bytes().all(|b| b.is_ascii_hexdigit()),
+    }
+}
+
+/// Whether `name` is itself a checksum listing or file, or the signature of
+/// one, left out of the generated listing.
+fn is_checksum_file(name: &str) -> bool {
+    let name = SIGNATURES
+        .iter()
+        .find_map(|suffix| name.strip_suffix(suffix))
+        .unwrap_or(name);
+    LISTINGS.contains(&name) || SIDECARS.iter().any(|suffix| name.ends_with(suffix))
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// One row of an index page.
+struct Listed {
+    name: String,
+    dir: bool,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+/// [`list_dir`] on the blocking pool.
+async fn list_dir_blocking(dir: &Path) -> io::Result<Vec<Listed>> {
+    let dir = dir.to_path_buf();
+    tokio::task::spawn_blocking(move || list_dir(&dir))
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// The readable entries of `dir`, directories first, each group by name.
+/// Symlinks (the library's image tree is made of them) are followed.
+fn list_dir(dir: &Path) -> io::Result<Vec<Listed>> {
+    let mut listed: Vec<Listed> = fs::read_dir(dir)?
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            if hidden(&name) {
+                return None;
+            }
+            let meta = fs::metadata(entry.path()).ok()?;
+            Some(Listed {
+                name,
+                dir: meta.is_dir(),
+                len: meta.len(),
+                modified: meta.modified().ok(),
+            })
+        })
+        .collect();
+    listed.sort_by(|a, b| b.dir.cmp(&a.dir).then_with(|| a.name.cmp(&b.name)));
+    Ok(listed)
+}
+
+/// An Apache-style index page of `entries` for the request path `path`,
+/// which [`listing`](crate::helpers::listing) reads back.
+fn index_page(path: &str, entries: &[Listed], with_sums: bool) -> String {
+    let title = escape_html(&format!("Index of {path}"));
+    let mut rows = String::new();
+    if path != "/" {
+        rows.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    let sums = with_sums.then(|| Listed {
+        name: SUMS_FILE.to_string(),
+        dir: false,
+        len: 0,
+        modified: None,
+    });
+    for entry in entries.iter().chain(sums.as_ref()) {
+        let slash = if entry.dir { "/" } else { "" };
+        let modified = entry
+            .modified
+            .map(|m| {
+                DateTime::<Utc>::from(m)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string()
+            })
+            .unwrap_or_default();
+        let size = if entry.dir || entry.modified.is_none() {
+            "-".to_string()
+        } else {
+            human_size(Some(entry.len))
+        };
+        rows.push_str(&format!(
+            "<tr><td><a href=\"{}{slash}\">{}{slash}</a></td><td>{modified}</td><td>{size}</td></tr>\n",
+            utf8_percent_encode(&entry.name, HREF_ENCODE),
+            escape_html(&entry.name),
+        ));
+    }
+    format!(
+        "<!DOCTYPE html>\n<html><head><title>{title}</title></head><body>\n<h1>{title}</h1>\n\
+         <table>\n<tr><th>Name</th><th>Last modified</th><th>Size</th></tr>\n{rows}</table>\n\
+         </body></html>\n"
+    )
+}
+
+fn full(status: StatusCode, content_type: &str, body: impl Into<Bytes>) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, content_type)
+        .body(
+            Full::new(body.into())
+                .map_err(|never: Infallible| match never {})
+                .boxed(),
+        )
+        .expect("responses are valid")
+}
+
+fn text(status: StatusCode, message: &str) -> Response<Body> {
+    full(status, "text/plain; charset=utf-8", format!("{message}\n"))
+}
+
+/// Content type of a served file, by extension.
+fn content_type(name: &str) -> &'static str {
+    match name.rsplit('.').next().unwrap_or_default() {
+        "json" => "application/json",
+        "html" => "text/html; charset=utf-8",
+        "txt" | "asc" | "sign" => "text/plain; charset=utf-8",
+        _ if is_checksum_file(name) => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Serves a directory tree read-only over HTTP: files (with single `Range`
+/// requests, so downloads can be resumed and segmented), an index page per
+/// directory, and a `SHA256SUMS` listing of every directory that does not
+/// publish one. Hashes are remembered until the file changes.
+#[derive(Debug)]
+pub struct FileServer {
+    root: PathBuf,
+    hashes: Mutex<HashMap<PathBuf, Hashed>>,
+}
+
+impl FileServer {
+    pub fn new(root: PathBuf) -> Arc<Self> {
+        Arc::new(Self {
+            root,
+            hashes: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Accept connections on `listener` until it fails.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> Result<(), String> {
+        serve_http(listener, move |request| Arc::clone(&self).handle(request)).await
+    }
+
+    async fn handle(self: Arc<Self>, request: Request<Incoming>) -> Response<Body> {
+        let method = request.method().clone();
+        if method != Method::GET && method != Method::HEAD {
+            return text(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "Only GET and HEAD are served",
+            );
+        }
+        let path = request.uri().path().to_string();
+        let mut response = self.respond(&path, request.headers()).await;
+        if method == Method::HEAD {
+            *response.body_mut() = Full::new(Bytes::new())
+                .map_err(|never: Infallible| match never {})
+                .boxed();
+        }
+        response
+    }
+
+    async fn respond(&self, path: &str, headers: &HeaderMap) -> Response<Body> {
+        let Some(segments) = segments(path) else {
+            return text(StatusCode::NOT_FOUND, "Not found");
+        };
+        let target = segments
+            .iter()
+            .fold(self.root.clone(), |path, segment| path.join(segment));
+        match tokio::fs::metadata(&target).await {
+            Ok(meta) if meta.is_dir() => {
+                if !path.ends_with('/') {
+                    return Response::builder()
+                        .status(StatusCode::MOVED_PERMANENTLY)
+                        .header(LOCATION, format!("{path}/"))
+                        .body(
+                            Full::new(Bytes::new())
+                                .map_err(|never: Infallible| match never {})
+                                .boxed(),
+                        )
+                        .expect("responses are valid");
+                }
+                match list_dir_blocking(&target).await {
+                    Ok(entries) => {
+                        let with_sums = !entries.iter().any(|e| e.name == SUMS_FILE)
+                            && entries.iter().any(|e| !e.dir && !is_checksum_file(&e.name));
+                        let page = index_page(path, &entries, with_sums);
+                        full(StatusCode::OK, "text/html; charset=utf-8", page)
+                    }
+                    Err(err) => text(StatusCode::FORBIDDEN, &err.to_string()),
+                }
+            }
+            Ok(meta) => self.file(&target, meta, headers).await,
+            Err(_) if segments.last().is_some_and(|name| name == SUMS_FILE) => {
+                let dir = target.parent().expect("sums have a directory");
+                match self.sums(dir).await {
+                    Ok(Some(sums)) => full(StatusCode::OK, "text/plain; charset=utf-8", sums),
+                    Ok(None) => text(StatusCode::NOT_FOUND, "Not found"),
+                    Err(err) => text(StatusCode::INTERNAL_SERVER_ERROR, &err),
+                }
+            }
+            Err(_) => text(StatusCode::NOT_FOUND, "Not found"),
+        }
+    }
+
+    /// Stream the file at `path`, or the range of it `headers` ask for.
+    async fn file(&self, path: &Path, meta: fs::Metadata, headers: &HeaderMap) -> Response<Body> {
+        let len = meta.len();
+        let range = headers.get(RANGE).and_then(|h| h.to_str().ok());
+        let range = match parse_range(range, len) {
+            Ok(range) => range,
+            Err(()) => {
+                let mut response = text(StatusCode::RANGE_NOT_SATISFIABLE, "Range not satisfiable");
+                response.headers_mut().insert(
+                    CONTENT_RANGE,
+                    format!("bytes */{len}").parse().expect("valid header"),
+                );
+                return response;
+            }
+        };
+        let mut file = match tokio::fs::File::open(path).await {
+            Ok(file) => file,
+            Err(err) => return text(StatusCode::FORBIDDEN, &err.to_string()),
+        };
+        let (status, start, count) = match range {
+            Some(ByteRange { start, end }) => (StatusCode::PARTIAL_CONTENT, start, end - start + 1),
+            None => (StatusCode::OK, 0, len),
+        };
+        if start > 0
+            && let Err(err) = file.seek(SeekFrom::Start(start)).await
+        {
+            return text(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string());
+        }
+
+        let frames = stream::unfold((file, count), |(mut file, left)| async move {
+            if left == 0 {
+                return None;
+            }
+            let mut buf = vec![0u8; CHUNK_SIZE.min(left as usize)];
+            match file.read(&mut buf).await {
+                Ok(0) => None,
+                Ok(n) => {
+                    buf.truncate(n);
+                    Some((Ok(Frame::data(Bytes::from(buf))), (file, left - n as u64)))
+                }
+                Err(err) => Some((Err(err), (file, 0))),
+            }
+        });
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut builder = Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, content_type(&name))
+            .header(CONTENT_LENGTH, count)
+            .header(ACCEPT_RANGES, "bytes");
+        if let Ok(modified) = meta.modified() {
+            builder = builder.header(
+                LAST_MODIFIED,
+                DateTime::<Utc>::from(modified)
+                    .format("%a, %d %b %Y %H:%M:%S GMT")
+                    .to_string(),
+            );
+        }
+        if let Some(ByteRange { start, end }) = range {
+            builder = builder.header(CONTENT_RANGE, format!("bytes {start}-{end}/{len}"));
+        }
+        builder
+            .body(StreamBody::new(frames).boxed())
+            .expect("responses are valid")
+    }
+
+    /// `SHA256SUMS` of the files in `dir`; `None` when it holds none.
+    async fn sums(&self, dir: &Path) -> Result<Option<String>, String> {
+        let entries = list_dir_blocking(dir)
+            .await
+            .map_err(|e| format!("{}: {e}", dir.display()))?;
+        let mut sums = String::new();
+        for entry in entries
+            .iter()
+            .filter(|e| !e.dir && !is_checksum_file(&e.name))
+        {
+            let digest = self.sha256(&dir.join(&entry.name)).await?;
+            sums.push_str(&format!("{digest}  {}\n", entry.name));
+        }
+        Ok((!sums.is_empty()).then_some(sums))
+    }
+
+    /// SHA-256 of `path`, hashed on the blocking pool the first time and
+    /// whenever its size or modification time changed.
+    async fn sha256(&self, path: &Path) -> Result<String, String> {
+        let meta = tokio::fs::metadata(path)
+            .await
+            .map_err(|e| format!("{}: {e}", path.display()))?;
+        let stamp = (meta.len(), meta.modified().ok());
+        if let Some((len, modified, digest)) =
+            self.hashes.lock().expect("hash cache lock").get(path)
+            && (*len, *modified) == stamp
+        {
+            return Ok(digest.clone());
+        }
+        let file = path.to_path_buf();
+        let digest = tokio::task::spawn_blocking(move || {
+            let mut hasher = StreamHasher::new(ChecksumKind::Sha256);
+            checksum::hash_file(&file, &mut hasher).map(|()| hasher.finalize_hex())
+        })
+        .await
+        .map_err(|e| e.to_string())??;
+        self.hashes
+            .lock()
+            .expect("hash cache lock")
+            .insert(path.to_path_buf(), (stamp.0, stamp.1, digest.clone()));
+        Ok(digest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ByteRange, FileServer, hidden, index_page, is_checksum_file, list_dir, parse_range,
+        segments,
+    };
+    use crate::helpers::listing;
+    use std::fs;
+
+    #[test]
+    fn parses_ranges_paths_and_lists_directories() {
+        let range = |start, end| Ok(Some(ByteRange { start, end }));
+        assert_eq!(parse_range(None, 100), Ok(None));
+        assert_eq!(parse_range(Some("bytes=10-"), 100), range(10, 99));
+        assert_eq!(parse_range(Some("bytes=10-19"), 100), range(10, 19));
+        assert_eq!(parse_range(Some("bytes=90-500"), 100), range(90, 99));
+        assert_eq!(parse_range(Some("bytes=-30"), 100), range(70, 99));
+        assert_eq!(parse_range(Some("bytes=0-1,5-6"), 100), Ok(None));
+        assert_eq!(parse_range(Some("bytes=100-"), 100), Err(()));
+
+        assert_eq!(
+            segments("/debian/My%20Images/disk.qcow2"),
+            Some(vec![
+                "debian".to_string(),
+                "My Images".to_string(),
+                "disk.qcow2".to_string()
+            ])
+        );
+        assert_eq!(segments("/debian/%2e%2e/etc"), None);
+        assert_eq!(segments("/.mirror.json"), None);
+
+        for name in [
+            "noble.img.part",
+            "noble.img.part.seg3",
+            "noble.img.part.segments",
+            "library.json.tmp",
+            "queue.json.4242.tmp",
+            "d01ae3d1b4c2c9e3f0b86e1e8d8e1f3c6a0e7f3bb6e0c9ad16d7f2f4cc8b1a5e.tmp",
+        ] {
+            assert!(hidden(name), "{name}");
+        }
+        for name in ["image-tmp", "foo.htmp", "notes.tmp", "segment.img", "x.seg"] {
+            assert!(!hidden(name), "{name}");
+        }
+        for name in [
+            "SHA256SUMS",
+            "SHA512SUMS.sign",
+            "CHECKSUM",
+            "noble.img.sha256",
+        ] {
+            assert!(is_checksum_file(name), "{name}");
+        }
+        for name in [
+            "CHECKSUMMED-disk.qcow2",
+            "SUMS-of-all.img",
+            "disk.SHA256.qcow2",
+        ] {
+            assert!(!is_checksum_file(name), "{name}");
+        }
+
+        let root = std::env::temp_dir().join(format!("cid-file-server-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("bookworm")).unwrap();
+        fs::write(root.join("disk one.qcow2"), b"disk").unwrap();
+        fs::write(root.join("disk one.qcow2.part"), b"di").unwrap();
+        let entries = list_dir(&root).unwrap();
+        let page = index_page("/", &entries, true);
+        let names: Vec<String> = listing::parse(&page).into_iter().map(|e| e.name).collect();
+        assert_eq!(names, ["bookworm", "disk one.qcow2", "SHA256SUMS"]);
+
+        let server = FileServer::new(root.clone());
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let sums = runtime.block_on(server.sums(&root)).unwrap().unwrap();
+        assert_eq!(
+            sums,
+            "1044dec7206e8d7c9fbb4ae8f766668406d2567fc7fc1a160a9d4700fcf8f8e9  disk one.qcow2\n"
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use reqwest::header::HeaderMap;
+use reqwest::{Method, Response, ResponseBuilderExt, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Process-wide fixture mode (set at most once during start-up).
+static MODE: OnceLock<Mode> = OnceLock::new();
+
+/// Headers worth keeping with a recorded response; the others only describe
+/// the original connection.
+const KEPT_HEADERS: &[&str] = &[
+    "accept-ranges",
+    "content-length",
+    "content-type",
+    "etag",
+    "last-modified",
+];
+
+/// Whether metadata responses are written to or served from a fixtures
+/// directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mode {
+    /// Save every metadata response below the directory.
+    Record(PathBuf),
+    /// Answer every request from the directory without touching the network.
+    Replay(PathBuf),
+}
+
+/// Status, headers and URL of a recorded response; the body sits next to it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Meta {
+    method: String,
+    url: String,
+    status: u16,
+    headers: BTreeMap<String, String>,
+}
+
+/// Install the fixture mode. Later calls are ignored.
+pub fn configure(mode: Mode) {
+    let _ = MODE.set(mode);
+}
+
+/// Whether responses are being recorded or replayed, in which case local
+/// caches must be bypassed.
+pub fn active() -> bool {
+    MODE.get().is_some()
+}
+
+fn recording_dir() -> Option<&'static Path> {
+    match MODE.get()? {
+        Mode::Record(dir) => Some(dir),
+        Mode::Replay(_) => None,
+    }
+}
+
+/// Whether responses are being recorded.
+pub fn recording() -> bool {
+    recording_dir().is_some()
+}
+
+/// Fixture files of `method url` below `dir`, without extension.
+fn stem(dir: &Path, method: &Method, url: &str) -> PathBuf {
+    let key = Sha256::digest(format!("{method} {url}").as_bytes());
+    dir.join(hex::encode(key))
+}
+
+/// Save the response to `method url` when recording. Failures are reported
+/// but never fail the request itself.
+pub fn record(method: &Method, url: &str, status: StatusCode, headers: &HeaderMap, body: &[u8]) {
+    let Some(dir) = recording_dir() else {
+        return;
+    };
+    let meta = Meta {
+        method: method.to_string(),
+        url: url.to_string(),
+        status: status.as_u16(),
+        headers: KEPT_HEADERS
+            .iter()
+            .filter_map(|name| {
+                let value = headers.get(*name)?.to_str().ok()?;
+                Some((name.to_string(), value.to_string()))
+            })
+            .collect(),
+    };
+    if let Err(err) = write(dir, &meta, body) {
+        eprintln!("Warning: failed to record {method} {url}: {err}");
+    }
+}
+
+fn write(dir: &Path, meta: &Meta, body: &[u8]) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let method = Method::from_bytes(meta.method.as_bytes()).unwrap_or_default();
+    let stem = stem(dir, &method, &meta.url);
+    let json = serde_json::to_vec_pretty(meta).map_err(std::io::Error::other)?;
+    fs::write(stem.with_extension("body"), body)?;
+    fs::write(stem.with_extension("json"), json)
+}
+
+fn load(dir: &Path, method: &Method, url: &str) -> Option<(Meta, Vec<u8>)> {
+    let stem = stem(dir, method, url);
+    let meta: Meta = serde_json::from_slice(&fs::read(stem.with_extension("json")).ok()?).ok()?;
+    let body = fs::read(stem.with_extension("body")).ok()?;
+    (meta.url == url).then_some((meta, body))
+}
+
+/// The recorded response to `method url` when replaying. Requests nobody
+/// recorded are answered with `404 Not Found`, so resolution never falls
+/// back to the network.
+pub fn replay(method: &Method, url: &Url) -> Option<Response> {
+    let Mode::Replay(dir) = MODE.get()? else {
+        return None;
+    };
+    let (status, headers, body) = match load(dir, method, url.as_str()) {
+        Some((meta, body)) => (meta.status, meta.headers, body),
+        None => {
+            eprintln!("Warning: no recorded response for {method} {url}");
+            (StatusCode::NOT_FOUND.as_u16(), BTreeMap::new(), Vec::new())
+        }
+    };
+    let mut builder = ::http::Response::builder().status(status).url(url.clone());
+    for (name, value) in &headers {
+        builder = builder.header(name, value);
+    }
+    builder.body(body).ok().map(Response::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Meta, load, write};
+    use reqwest::Method;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn responses_round_trip_through_the_directory() {
+        let dir = std::env::temp_dir().join(format!("cid-fixtures-{}", std::process::id()));
+        let url = "https://example.invalid/streams/v1/index.json";
+        let meta = Meta {
+            method: "GET".to_string(),
+            url: url.to_string(),
+            status: 200,
+            headers: BTreeMap::from([("etag".to_string(), "\"v1\"".to_string())]),
+        };
+        write(&dir, &meta, b"{}").unwrap();
+
+        let (loaded, body) = load(&dir, &Method::GET, url).unwrap();
+        assert_eq!(loaded, meta);
+        assert_eq!(body, b"{}");
+        assert!(load(&dir, &Method::HEAD, url).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
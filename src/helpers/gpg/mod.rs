@@ -0,0 +1,624 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::OnceLock;
+
+use base64::Engine;
+use reqwest::Client;
+
+use crate::helpers::{find_program, paths, retry};
+
+const KEYRINGS_SUBDIR: &str = "keyrings";
+const CLEARSIGN_HEADER: &str = "-----BEGIN PGP SIGNED MESSAGE-----";
+
+/// Keyrings shipped by the distributions themselves, used when installed.
+const SYSTEM_KEYRINGS: &[(&str, &str)] = &[(
+    "ubuntu",
+    "/usr/share/keyrings/ubuntu-cloudimage-keyring.gpg",
+)];
+
+/// Where the signing keys of distributions without a keyring package are
+/// published. They are fetched into the managed `<repo>.gpg` on first use
+/// and pinned there: later runs never replace them, so a checksum file
+/// signed by another key fails verification instead of being trusted.
+const PUBLISHED_KEYS: &[(&str, KeySource)] = &[
+    (
+        "almalinux",
+        KeySource::Urls(&[
+            "https://repo.almalinux.org/almalinux/RPM-GPG-KEY-AlmaLinux-8",
+            "https://repo.almalinux.org/almalinux/RPM-GPG-KEY-AlmaLinux-9",
+            "https://repo.almalinux.org/almalinux/RPM-GPG-KEY-AlmaLinux-10",
+        ]),
+    ),
+    (
+        "debian",
+        KeySource::Keyserver {
+            server: "https://keyring.debian.org",
+            fingerprints: DEBIAN_SIGNERS,
+        },
+    ),
+];
+
+/// Fingerprints of the keys Debian signs the `SHA512SUMS` of its cloud
+/// images with: the Debian CD signing key.
+const DEBIAN_SIGNERS: &[&str] = &["DF9B9C49EAA9298432589D76DA87E80D6294BE9B"];
+
+/// How the signing keys of a repository are obtained.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KeySource {
+    /// Armored keys at these URLs.
+    Urls(&'static [&'static str]),
+    /// The keys with these fingerprints, fetched from this HKP server.
+    /// Signatures by any other key are refused even when the server or a
+    /// keyring holds it.
+    Keyserver {
+        server: &'static str,
+        fingerprints: &'static [&'static str],
+    },
+}
+
+impl KeySource {
+    /// Where the keys are fetched from.
+    fn urls(self) -> Vec<String> {
+        match self {
+            Self::Urls(urls) => urls.iter().map(|url| url.to_string()).collect(),
+            Self::Keyserver {
+                server,
+                fingerprints,
+            } => fingerprints
+                .iter()
+                .map(|fpr| format!("{server}/pks/lookup?op=get&options=mr&search=0x{fpr}"))
+                .collect(),
+        }
+    }
+}
+
+fn published_keys(repo: &str) -> Option<KeySource> {
+    PUBLISHED_KEYS
+        .iter()
+        .find(|(name, _)| *name == repo)
+        .map(|(_, source)| *source)
+}
+
+/// The only keys accepted for `repo` unless keyrings were given explicitly;
+/// empty when any key of its keyrings is.
+fn pinned_signers(repo: &str) -> &'static [&'static str] {
+    match published_keys(repo) {
+        Some(KeySource::Keyserver { fingerprints, .. }) if policy().keyrings.is_empty() => {
+            fingerprints
+        }
+        _ => &[],
+    }
+}
+
+/// Process-wide signature policy (set at most once during start-up).
+static POLICY: OnceLock<SignaturePolicy> = OnceLock::new();
+
+/// Which keys checksum files are verified against and whether an unsigned
+/// file is acceptable.
+#[derive(Debug, Clone, Default)]
+pub struct SignaturePolicy {
+    /// Extra keyrings trusted for every repository (`--keyring`).
+    pub keyrings: Vec<PathBuf>,
+    /// Refuse checksum files that cannot be verified.
+    pub require: bool,
+}
+
+/// How a repository signs its checksum files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signing {
+    /// A detached signature published at the checksum URL plus this suffix,
+    /// e.g. `SHA512SUMS.sign`.
+    Detached(&'static str),
+    /// The checksum file itself is clearsigned.
+    Clearsigned,
+}
+
+/// Install the signature policy. Later calls are ignored.
+pub fn configure(policy: SignaturePolicy) {
+    let _ = POLICY.set(policy);
+}
+
+fn policy() -> &'static SignaturePolicy {
+    POLICY.get_or_init(SignaturePolicy::default)
+}
+
+/// Directory users drop per-repository keys into, as `<repo>.gpg`
+/// (`$XDG_DATA_HOME/cloud-images-downloader/keyrings` on Linux).
+pub fn managed_dir() -> Option<PathBuf> {
+    paths::data_dir().map(|dir| dir.join(KEYRINGS_SUBDIR))
+}
+
+/// Locate `gpgv` on `PATH`.
+fn locate() -> Result<PathBuf, String> {
+    find_program("gpgv").ok_or_else(|| {
+        "gpgv was not found on PATH; install GnuPG (e.g. `apt install gpgv`) to verify \
+         checksum signatures"
+            .to_string()
+    })
+}
+
+/// Keyrings trusted for `repo`: the configured ones, the managed
+/// `<repo>.gpg` and the distribution keyring when installed. Paths are made
+/// absolute since gpgv resolves relative ones against `~/.gnupg`.
+fn keyrings_for(repo: &str) -> Vec<PathBuf> {
+    let repo = repo.to_lowercase();
+    let managed = managed_dir().map(|dir| dir.join(format!("{repo}.gpg")));
+    let system = SYSTEM_KEYRINGS
+        .iter()
+        .filter(|(name, _)| *name == repo)
+        .map(|(_, path)| PathBuf::from(path));
+
+    policy()
+        .keyrings
+        .iter()
+        .cloned()
+        .chain(managed)
+        .chain(system)
+        .filter_map(|path| fs::canonicalize(path).ok())
+        .collect()
+}
+
+/// Whether checksum files of `repo` get verified: when signatures are
+/// required, when keyrings were given explicitly, or when gpgv is installed
+/// and a managed or distribution keyring exists or the distribution
+/// publishes its keys.
+pub fn enabled_for(repo: &str) -> bool {
+    let policy = policy();
+    policy.require
+        || !policy.keyrings.is_empty()
+        || ((!keyrings_for(repo).is_empty() || published_keys(&repo.to_lowercase()).is_some())
+            && locate().is_ok())
+}
+
+/// Temporary file removed again when dropped. It is created exclusively
+/// under an unpredictable name, so a file or symlink planted in a shared
+/// temp directory is never written through.
+struct TempFile(PathBuf);
+
+impl TempFile {
+    fn new(contents: &[u8]) -> Result<Self, String> {
+        let dir = paths::temp_dir();
+        fs::create_dir_all(&dir)
+            .map_err(|e| format!("Failed to create '{}': {e}", dir.display()))?;
+        Self::new_in(&dir, "cid-gpg", contents)
+    }
+
+    /// Create the file in `dir`, named `<prefix>-<pid>-<random>`.
+    fn new_in(dir: &Path, prefix: &str, contents: &[u8]) -> Result<Self, String> {
+        loop {
+            let path = dir.join(format!(
+                "{prefix}-{}-{:016x}",
+                std::process::id(),
+                fastrand::u64(..)
+            ));
+            let mut file = match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => file,
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(format!("Failed to create '{}': {err}", path.display())),
+            };
+            // Removed on drop even when writing fails.
+            let temp = Self(path);
+            file.write_all(contents)
+                .map_err(|e| format!("Failed to write '{}': {e}", temp.0.display()))?;
+            return Ok(temp);
+        }
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Run `f` on the blocking pool: gpgv and the keyring files are never
+/// waited for on the async workers.
+async fn run_blocking<T: Send + 'static, E: From<String> + Send + 'static>(
+    f: impl FnOnce() -> Result<T, E> + Send + 'static,
+) -> Result<T, E> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| E::from(format!("Blocking task failed: {e}")))?
+}
+
+/// [`TempFile::new`] on the blocking pool.
+async fn temp_file(contents: Vec<u8>) -> Result<TempFile, String> {
+    run_blocking(move || TempFile::new(&contents)).await
+}
+
+/// A checksum file after its signature was checked.
+#[derive(Debug, Clone)]
+pub struct Verified {
+    /// The checksum text (the payload for clearsigned files).
+    pub text: String,
+    /// Fingerprint of the key that made the signature; `None` when the file
+    /// was used unverified.
+    pub signer: Option<String>,
+}
+
+impl Verified {
+    fn unsigned(text: String) -> Self {
+        Self { text, signer: None }
+    }
+}
+
+/// Fingerprint of the signing key from gpgv's `VALIDSIG` status line.
+fn valid_signer(status: &str) -> Option<String> {
+    status.lines().find_map(|line| {
+        line.strip_prefix("[GNUPG:] VALIDSIG ")
+            .and_then(|rest| rest.split_whitespace().next())
+            .map(str::to_string)
+    })
+}
+
+/// Whether the `VALIDSIG` status line names one of `fingerprints` as the
+/// signing key or its primary key.
+fn signed_by(status: &str, fingerprints: &[&str]) -> bool {
+    status
+        .lines()
+        .filter_map(|line| line.strip_prefix("[GNUPG:] VALIDSIG "))
+        .flat_map(|rest| {
+            let fields: Vec<&str> = rest.split_whitespace().collect();
+            [fields.first().copied(), fields.get(9).copied()]
+        })
+        .flatten()
+        .any(|fpr| fingerprints.iter().any(|f| f.eq_ignore_ascii_case(fpr)))
+}
+
+/// Run gpgv against `keyrings` with `files` and return what it wrote to
+/// stdout (the signed text with `--output -`) and the signing key, which
+/// must be one of `signers` unless that is empty.
+fn gpgv(
+    keyrings: &[PathBuf],
+    signers: &[&str],
+    extra: &[&str],
+    files: &[&Path],
+) -> Result<(Vec<u8>, Option<String>), String> {
+    let gpgv = locate()?;
+    let mut command = Command::new(&gpgv);
+    for keyring in keyrings {
+        command.arg("--keyring").arg(keyring);
+    }
+    let output = command
+        .args(["--status-fd", "2"])
+        .args(extra)
+        .args(files)
+        .output()
+        .map_err(|e| format!("Failed to run '{}': {e}", gpgv.display()))?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        let message: Vec<&str> = stderr
+            .lines()
+            .filter(|line| !line.starts_with("[GNUPG:]"))
+            .collect();
+        return Err(format!(
+            "gpgv rejected the signature: {}",
+            message.join("\n").trim()
+        ));
+    }
+    let signer = valid_signer(&stderr);
+    if !signers.is_empty() && !signed_by(&stderr, signers) {
+        return Err(format!(
+            "the signature was made by key {}, not by one of {}",
+            signer.as_deref().unwrap_or("?"),
+            signers.join(", ")
+        ));
+    }
+    Ok((output.stdout, signer))
+}
+
+/// Decode the ASCII-armored key blocks in `text` into one binary keyring.
+fn dearmor(text: &str) -> Result<Vec<u8>, String> {
+    let mut keyring = Vec::new();
+    let mut lines = text.lines().map(str::trim);
+    while lines
+        .by_ref()
+        .any(|line| line == "-----BEGIN PGP PUBLIC KEY BLOCK-----")
+    {
+        // Armor headers end with the first empty line.
+        for line in lines.by_ref() {
+            if line.is_empty() {
+                break;
+            }
+        }
+        let body: String = lines
+            .by_ref()
+            .take_while(|line| !line.starts_with('=') && !line.starts_with("-----END"))
+            .collect();
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(body)
+            .map_err(|e| format!("invalid armored key: {e}"))?;
+        keyring.extend(decoded);
+    }
+    if keyring.is_empty() {
+        return Err("no armored public key found".to_string());
+    }
+    Ok(keyring)
+}
+
+/// Fetch the armored keys at `urls` and return them as one keyring.
+async fn fetch_keys(client: &Client, urls: &[String]) -> Result<Vec<u8>, String> {
+    let mut keyring = Vec::new();
+    for url in urls {
+        let armored = retry::bytes(|| client.get(url))
+            .await
+            .map_err(|e| format!("Failed to fetch the signing key {url}: {e}"))?;
+        keyring.extend(
+            dearmor(&String::from_utf8_lossy(&armored)).map_err(|e| format!("{url}: {e}"))?,
+        );
+    }
+    Ok(keyring)
+}
+
+/// Store `keyring` as the managed keyring of `repo`, which pins it: it is
+/// only ever fetched while no managed keyring exists. Returns its path.
+fn pin_keyring(repo: &str, keyring: &[u8], origin: &str) -> Result<PathBuf, String> {
+    let dir = managed_dir().ok_or("no data directory to keep the signing keys in")?;
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create '{}': {e}", dir.display()))?;
+    let path = dir.join(format!("{repo}.gpg"));
+    // Written under a name of its own, so another process pinning the same
+    // keyring never truncates it while gpgv reads it.
+    let tmp = TempFile::new_in(&dir, &format!(".{repo}.gpg"), keyring)?;
+    fs::rename(&tmp.0, &path).map_err(|e| format!("Failed to write '{}': {e}", path.display()))?;
+    eprintln!(
+        "Pinned the {repo} signing key(s) from {origin} in {}; remove it to fetch them again",
+        path.display()
+    );
+    Ok(path)
+}
+
+/// [`pin_keyring`] on the blocking pool.
+async fn pin_keyring_blocking(
+    repo: &str,
+    keyring: Vec<u8>,
+    origin: String,
+) -> Result<PathBuf, String> {
+    let repo = repo.to_string();
+    run_blocking(move || pin_keyring(&repo, &keyring, &origin)).await
+}
+
+/// [`keyrings_for`] on the blocking pool.
+async fn keyrings_for_blocking(repo: &str) -> Result<Vec<PathBuf>, String> {
+    let repo = repo.to_string();
+    run_blocking(move || Ok(keyrings_for(&repo))).await
+}
+
+/// Held while published keys are fetched and pinned, so checksum files
+/// verified concurrently on a first run fetch each keyring once.
+static PINNING: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Fetch and pin the keys `repo` publishes when it has no keyring yet; returns the keyrings to verify against.
+async fn keyrings_with_published(client: &Client, repo: &str) -> Result<Vec<PathBuf>, String> {
+    let keyrings = keyrings_for_blocking(repo).await?;
+    if !keyrings.is_empty() {
+        return Ok(keyrings);
+    }
+    let Some(source) = published_keys(repo) else {
+        return Ok(keyrings);
+    };
+    let _pinning = PINNING.lock().await;
+    // Another fetch may have pinned the keys while this one waited.
+    let keyrings = keyrings_for_blocking(repo).await?;
+    if !keyrings.is_empty() {
+        return Ok(keyrings);
+    }
+    let urls = source.urls();
+    let keyring = fetch_keys(client, &urls).await?;
+    pin_keyring_blocking(repo, keyring, urls.join(", ")).await?;
+    keyrings_for_blocking(repo).await
+}
+
+/// [`gpgv`] on the blocking pool, accepting only the pinned signers of
+/// `repo`.
+async fn gpgv_blocking(
+    repo: &str,
+    keyrings: Vec<PathBuf>,
+    extra: &'static [&'static str],
+    files: Vec<PathBuf>,
+) -> Result<(Vec<u8>, Option<String>), String> {
+    let signers = pinned_signers(repo);
+    run_blocking(move || {
+        let files: Vec<&Path> = files.iter().map(PathBuf::as_path).collect();
+        gpgv(&keyrings, signers, extra, &files)
+    })
+    .await
+}
+
+/// Check `body`, fetched from `url`, against its upstream signature.
+///
+/// A bad signature is always an error. A missing signature or keyring only
+/// is when signatures are required; otherwise `body` is returned unchanged,
+/// as it is when verification is not enabled for `repo`.
+pub async fn verify_checksums(
+    client: &Client,
+    url: &str,
+    body: String,
+    repo: &str,
+    signing: Signing,
+) -> Result<Verified, String> {
+    if !enabled_for(repo) {
+        return Ok(Verified::unsigned(body));
+    }
+    let require = policy().require;
+    let repo = repo.to_lowercase();
+    let keyrings = match keyrings_with_published(client, &repo).await {
+        Ok(keyrings) => keyrings,
+        Err(err) if !require => {
+            eprintln!("Warning: {err}; using {url} unverified");
+            return Ok(Verified::unsigned(body));
+        }
+        Err(err) => return Err(err),
+    };
+    if keyrings.is_empty() {
+        let hint = managed_dir()
+            .map(|dir| {
+                format!(
+                    " or place it in {}",
+                    dir.join(format!("{repo}.gpg")).display()
+                )
+            })
+            .unwrap_or_default();
+        return Err(format!(
+            "No keyring to verify {url}; pass the {repo} signing key with --keyring{hint}"
+        ));
+    }
+
+    let unsigned = |reason: String| {
+        if require {
+            Err(format!(
+                "{reason}; refusing it because signatures are required"
+            ))
+        } else {
+            eprintln!("Warning: {reason}; using it unverified");
+            Ok(Verified::unsigned(body.clone()))
+        }
+    };
+
+    match signing {
+        Signing::Detached(suffix) => {
+            let signature_url = format!("{url}{suffix}");
+            let signature = match retry::bytes(|| client.get(&signature_url)).await {
+                Ok(signature) => signature,
+                Err(err) => return unsigned(format!("{url} has no signature ({err})")),
+            };
+            let data = temp_file(body.clone().into_bytes()).await?;
+            let signature = temp_file(signature.to_vec()).await?;
+            let (_, signer) = gpgv_blocking(
+                &repo,
+                keyrings,
+                &[],
+                vec![signature.0.clone(), data.0.clone()],
+            )
+            .await
+            .map_err(|e| format!("{url}: {e}"))?;
+            Ok(Verified { text: body, signer })
+        }
+        Signing::Clearsigned => {
+            if !body.trim_start().starts_with(CLEARSIGN_HEADER) {
+                return unsigned(format!("{url} is not signed"));
+            }
+            let file = temp_file(body.clone().into_bytes()).await?;
+            let (payload, signer) =
+                gpgv_blocking(&repo, keyrings, &["--output", "-"], vec![file.0.clone()])
+                    .await
+                    .map_err(|e| format!("{url}: {e}"))?;
+            Ok(Verified {
+                text: String::from_utf8_lossy(&payload).into_owned(),
+                signer,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KeySource, TempFile, dearmor, keyrings_for, signed_by, valid_signer};
+
+    #[test]
+    fn temp_files_are_removed_on_drop() {
+        let file = TempFile::new(b"data").unwrap();
+        let path = file.0.clone();
+        assert_eq!(std::fs::read(&path).unwrap(), b"data");
+        drop(file);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn temp_files_never_reuse_a_name() {
+        let (a, b) = (TempFile::new(b"a").unwrap(), TempFile::new(b"b").unwrap());
+        assert_ne!(a.0, b.0);
+        assert_eq!(std::fs::read(&a.0).unwrap(), b"a");
+    }
+
+    #[test]
+    fn temp_files_are_created_in_the_given_directory() {
+        let dir = std::env::temp_dir();
+        let file = TempFile::new_in(&dir, ".debian.gpg", b"key").unwrap();
+        assert_eq!(file.0.parent(), Some(dir.as_path()));
+        assert!(
+            file.0
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .starts_with(".debian.gpg-")
+        );
+    }
+
+    #[test]
+    fn dearmors_every_key_block() {
+        let armored = "-----BEGIN PGP PUBLIC KEY BLOCK-----\n\
+                       Version: test\n\
+                       \n\
+                       AQID\n\
+                       BAU=\n\
+                       =abcd\n\
+                       -----END PGP PUBLIC KEY BLOCK-----\n\
+                       -----BEGIN PGP PUBLIC KEY BLOCK-----\n\
+                       \n\
+                       Bg==\n\
+                       -----END PGP PUBLIC KEY BLOCK-----\n";
+        assert_eq!(dearmor(armored).unwrap(), vec![1, 2, 3, 4, 5, 6]);
+        assert!(dearmor("<html>not a key</html>").is_err());
+    }
+
+    #[test]
+    fn fetches_pinned_keys_by_fingerprint() {
+        let source = KeySource::Keyserver {
+            server: "https://keyring.debian.org",
+            fingerprints: &["DF9B9C49EAA9298432589D76DA87E80D6294BE9B"],
+        };
+        assert_eq!(
+            source.urls(),
+            ["https://keyring.debian.org/pks/lookup?op=get&options=mr\
+              &search=0xDF9B9C49EAA9298432589D76DA87E80D6294BE9B"]
+        );
+    }
+
+    #[test]
+    fn accepts_only_pinned_signers() {
+        let status = |signing: &str, primary: &str| {
+            format!("[GNUPG:] VALIDSIG {signing} 2024-10-13 1728800000 0 4 0 1 10 00 {primary}\n")
+        };
+        let pinned = ["DF9B9C49EAA9298432589D76DA87E80D6294BE9B"];
+        assert!(signed_by(
+            &status(
+                "df9b9c49eaa9298432589d76da87e80d6294be9b",
+                "DF9B9C49EAA9298432589D76DA87E80D6294BE9B"
+            ),
+            &pinned
+        ));
+        // A subkey of the pinned key.
+        assert!(signed_by(
+            &status(
+                "0123456789ABCDEF",
+                "DF9B9C49EAA9298432589D76DA87E80D6294BE9B"
+            ),
+            &pinned
+        ));
+        assert!(!signed_by(
+            &status("0123456789ABCDEF", "0123456789ABCDEF"),
+            &pinned
+        ));
+        assert!(!signed_by("[GNUPG:] BADSIG 1234 x", &pinned));
+    }
+
+    #[test]
+    fn only_existing_keyrings_are_used() {
+        assert!(
+            keyrings_for("no-such-repo")
+                .iter()
+                .all(|k| k.is_absolute() && k.exists())
+        );
+    }
+
+    #[test]
+    fn reads_the_signer_from_status_lines() {
+        let status = "[GNUPG:] NEWSIG\n\
+                      gpgv: Good signature from \"Debian Cloud\"\n\
+                      [GNUPG:] VALIDSIG ABCDEF0123 2024-10-13 1728800000 0 4 0 1 10 00 ABCDEF0123\n";
+        assert_eq!(valid_signer(status).as_deref(), Some("ABCDEF0123"));
+        assert_eq!(valid_signer("[GNUPG:] BADSIG 1234 x"), None);
+    }
+}
@@ -0,0 +1,278 @@
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{
+    Client, Method, NoProxy, Proxy, Request, RequestBuilder, Response, ResponseBuilderExt,
+    StatusCode, Url, redirect,
+};
+
+use crate::helpers::fixtures;
+#[cfg(feature = "s3")]
+use crate::helpers::s3;
+
+/// User agent sent with every request unless overridden.
+pub const DEFAULT_USER_AGENT: &str = "cloud-index-reader-rust/1.0";
+
+/// Crate-wide HTTP client so metadata lookups and downloads share one
+/// connection pool (set at most once).
+static CLIENT: OnceLock<Client> = OnceLock::new();
+
+/// Extra headers for every URL below a root, e.g. the roots of a
+/// repository (set at most once).
+static SCOPED_HEADERS: OnceLock<Vec<(Url, HeaderMap)>> = OnceLock::new();
+
+/// Set by `--offline`: no request leaves the machine.
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+/// Knobs applied when the shared client is built.
+#[derive(Debug, Clone)]
+pub struct HttpSettings {
+    pub user_agent: String,
+    pub connect_timeout: Duration,
+    /// Maximum silence between two reads; catches mirrors that stall
+    /// mid-transfer without bounding the length of large downloads.
+    pub read_timeout: Duration,
+    /// Optional deadline for a whole request, body included.
+    pub timeout: Option<Duration>,
+    pub tcp_keepalive: Duration,
+    pub pool_idle_timeout: Duration,
+    /// Explicit proxy (`http://`, `https://`, `socks5://` or `socks5h://`)
+    /// used for every request. Without it `HTTP_PROXY`, `HTTPS_PROXY`,
+    /// `ALL_PROXY` and `NO_PROXY` from the environment apply.
+    pub proxy: Option<String>,
+    /// Redirect hops followed before a request fails; `0` disables
+    /// redirects altogether.
+    pub max_redirects: usize,
+    /// Headers sent with every request.
+    pub headers: HeaderMap,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        Self {
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            connect_timeout: Duration::from_secs(30),
+            read_timeout: Duration::from_secs(60),
+            timeout: None,
+            tcp_keepalive: Duration::from_secs(60),
+            pool_idle_timeout: Duration::from_secs(90),
+            proxy: None,
+            max_redirects: 10,
+            headers: HeaderMap::new(),
+        }
+    }
+}
+
+/// Build a client from `settings`.
+fn build(settings: &HttpSettings) -> reqwest::Result<Client> {
+    let mut builder = Client::builder()
+        .user_agent(settings.user_agent.as_str())
+        .default_headers(settings.headers.clone())
+        .connect_timeout(settings.connect_timeout)
+        .read_timeout(settings.read_timeout)
+        .tcp_keepalive(settings.tcp_keepalive)
+        .pool_idle_timeout(settings.pool_idle_timeout)
+        .redirect(match settings.max_redirects {
+            0 => redirect::Policy::none(),
+            hops => redirect::Policy::limited(hops),
+        });
+
+    if let Some(timeout) = settings.timeout {
+        builder = builder.timeout(timeout);
+    }
+
+    if let Some(proxy) = &settings.proxy {
+        // An explicit proxy replaces the environment ones but still honours
+        // `NO_PROXY` exclusions.
+        builder = builder.proxy(Proxy::all(proxy.as_str())?.no_proxy(NoProxy::from_env()));
+    }
+
+    builder.build()
+}
+
+/// Build and install the shared client. Must run before the first call to
+/// [`client`]; later calls are ignored.
+pub fn configure(settings: &HttpSettings) -> reqwest::Result<()> {
+    let client = build(settings)?;
+    let _ = CLIENT.set(client);
+    Ok(())
+}
+
+/// The shared client, built with default settings on first use when
+/// [`configure`] was never called.
+pub fn client() -> &'static Client {
+    CLIENT.get_or_init(|| {
+        build(&HttpSettings::default()).expect("default HTTP client configuration is valid")
+    })
+}
+
+/// Refuse every request from now on; see [`send`].
+pub fn set_offline() {
+    OFFLINE.store(true, Ordering::Relaxed);
+}
+
+/// Whether [`set_offline`] was called.
+pub fn offline() -> bool {
+    OFFLINE.load(Ordering::Relaxed)
+}
+
+/// The answer to `request` in offline mode: a `404`, which no caller
+/// retries, after telling the user what was not fetched.
+fn refuse(request: &Request) -> Option<Response> {
+    eprintln!(
+        "Offline: not fetching {} {}",
+        request.method(),
+        request.url()
+    );
+    ::http::Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .url(request.url().clone())
+        .body(Vec::new())
+        .ok()
+        .map(Response::from)
+}
+
+/// Parse a curl-style `Name: value` header.
+pub fn parse_header(header: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = header
+        .split_once(':')
+        .ok_or_else(|| format!("invalid header '{header}', expected `Name: value`"))?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|e| format!("invalid header name in '{header}': {e}"))?;
+    let value = HeaderValue::from_str(value.trim())
+        .map_err(|e| format!("invalid header value in '{header}': {e}"))?;
+    Ok((name, value))
+}
+
+/// Install headers that are only sent to URLs below the given root URL.
+/// Later calls are ignored.
+pub fn configure_scoped_headers(scoped: Vec<(String, HeaderMap)>) -> Result<(), String> {
+    let scoped = scoped
+        .into_iter()
+        .map(|(root, headers)| {
+            Url::parse(&root)
+                .map(|root| (root, headers))
+                .map_err(|e| format!("invalid repository root '{root}': {e}"))
+        })
+        .collect::<Result<_, _>>()?;
+    let _ = SCOPED_HEADERS.set(scoped);
+    Ok(())
+}
+
+/// Whether `url` lies below `root`: the same scheme, host and port, and a
+/// path that is the root's or continues it after a `/`. A plain prefix
+/// match would also hand the headers to `https://mirror.example.evil.com`.
+fn in_scope(url: &Url, root: &Url) -> bool {
+    if url.scheme() != root.scheme()
+        || url.host_str() != root.host_str()
+        || url.port_or_known_default() != root.port_or_known_default()
+    {
+        return false;
+    }
+    let base = root.path().trim_end_matches('/');
+    url.path()
+        .strip_prefix(base)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Add the scoped headers matching the URL of `request`; headers already set
+/// on the request win.
+fn apply_scoped_headers(request: &mut Request) {
+    let Some(scoped) = SCOPED_HEADERS.get() else {
+        return;
+    };
+    let url = request.url().clone();
+    for (_, headers) in scoped.iter().filter(|(root, _)| in_scope(&url, root)) {
+        for (name, value) in headers {
+            if !request.headers().contains_key(name) {
+                request.headers_mut().insert(name.clone(), value.clone());
+            }
+        }
+    }
+}
+
+/// Send `request` with the headers scoped to its URL added; `s3://` URLs are
+/// mapped to their bucket endpoint and signed (with the `s3` feature). Every request of
+/// the application goes through here (usually via the retry helpers), so
+/// replayed fixtures answer it, `HEAD` responses are recorded here and
+/// offline mode refuses it.
+pub async fn send(request: RequestBuilder) -> reqwest::Result<Response> {
+    let (client, request) = request.build_split();
+    let mut request = request?;
+    if let Some(res) = fixtures::replay(request.method(), request.url()) {
+        return Ok(res);
+    }
+    if offline()
+        && let Some(res) = refuse(&request)
+    {
+        return Ok(res);
+    }
+    let (method, url) = (request.method().clone(), request.url().to_string());
+    apply_scoped_headers(&mut request);
+    #[cfg(feature = "s3")]
+    s3::prepare(&mut request);
+    let res = client.execute(request).await?;
+    if method == Method::HEAD {
+        fixtures::record(&method, &url, res.status(), res.headers(), &[]);
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Url;
+
+    use super::{HttpSettings, build, in_scope, parse_header};
+
+    #[test]
+    fn accepts_socks_and_http_proxies() {
+        for proxy in ["http://proxy.invalid:3128", "socks5h://127.0.0.1:1080"] {
+            let settings = HttpSettings {
+                proxy: Some(proxy.to_string()),
+                ..HttpSettings::default()
+            };
+            assert!(build(&settings).is_ok(), "{proxy} should be accepted");
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_proxy() {
+        let settings = HttpSettings {
+            proxy: Some("not a url".to_string()),
+            ..HttpSettings::default()
+        };
+        assert!(build(&settings).is_err());
+    }
+
+    #[test]
+    fn scopes_headers_to_the_root() {
+        let root = Url::parse("https://mirror.internal/debian").unwrap();
+        let scoped = |url: &str| in_scope(&Url::parse(url).unwrap(), &root);
+        assert!(scoped("https://mirror.internal/debian"));
+        assert!(scoped("https://mirror.internal/debian/"));
+        assert!(scoped("https://mirror.internal:443/debian/12/disk.qcow2"));
+        assert!(!scoped(
+            "https://mirror.internal.evil.com/debian/disk.qcow2"
+        ));
+        assert!(!scoped("https://mirror.internal/debian-evil/disk.qcow2"));
+        assert!(!scoped("http://mirror.internal/debian/disk.qcow2"));
+        assert!(!scoped("https://mirror.internal:8443/debian/disk.qcow2"));
+
+        let host = Url::parse("https://mirror.internal").unwrap();
+        assert!(in_scope(
+            &Url::parse("https://mirror.internal/any/path").unwrap(),
+            &host
+        ));
+    }
+
+    #[test]
+    fn parses_curl_style_headers() {
+        let (name, value) = parse_header("X-Mirror-Token:  abc123 ").unwrap();
+        assert_eq!(name.as_str(), "x-mirror-token");
+        assert_eq!(value, "abc123");
+        assert!(parse_header("no colon").is_err());
+        assert!(parse_header("bad name: x").is_err());
+    }
+}
@@ -0,0 +1,394 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Utc};
+
+use reqwest::header::{ETAG, HeaderMap, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::{Client, Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::helpers::retention::{self, Candidate, GcSummary, Retention};
+use crate::helpers::{fixtures, http, paths, retry};
+
+const CACHE_SUBDIR: &str = "http";
+
+/// A cached response body together with the validators needed to ask the
+/// server whether it changed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    /// When the server last confirmed the body, RFC 3339; entries written
+    /// before refresh intervals existed have none and count as stale.
+    #[serde(default)]
+    validated: Option<String>,
+    body: String,
+}
+
+impl Entry {
+    /// Whether the body was confirmed less than `interval` before `now`.
+    fn is_fresh(&self, interval: Duration, now: DateTime<Utc>) -> bool {
+        self.validated
+            .as_deref()
+            .and_then(|validated| DateTime::parse_from_rfc3339(validated).ok())
+            .and_then(|validated| (now - validated.to_utc()).to_std().ok())
+            .is_some_and(|age| age < interval)
+    }
+}
+
+/// Refresh intervals by URL prefix (set at most once).
+static REFRESH: OnceLock<Vec<(String, Duration)>> = OnceLock::new();
+
+/// Serve cached responses below each URL prefix without revalidating them
+/// for the paired interval; the longest matching prefix wins. Later calls
+/// are ignored.
+pub fn configure_refresh(rules: Vec<(String, Duration)>) {
+    let _ = REFRESH.set(rules);
+}
+
+fn refresh_interval(url: &str) -> Option<Duration> {
+    longest_match(REFRESH.get()?, url)
+}
+
+fn longest_match(rules: &[(String, Duration)], url: &str) -> Option<Duration> {
+    rules
+        .iter()
+        .filter(|(prefix, _)| url.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, interval)| *interval)
+}
+
+/// Parse a refresh interval such as `30m`, `6h`, `7d` or `2w`; a bare number
+/// is seconds.
+pub fn parse_interval(input: &str) -> Result<Duration, String> {
+    let trimmed = input.trim();
+    let units = [
+        ('s', 1u64),
+        ('m', 60),
+        ('h', 3600),
+        ('d', 86_400),
+        ('w', 604_800),
+    ];
+    let (number, multiplier) = trimmed
+        .chars()
+        .last()
+        .and_then(|last| units.iter().find(|(unit, _)| *unit == last))
+        .map(|(_, multiplier)| (&trimmed[..trimmed.len() - 1], *multiplier))
+        .unwrap_or((trimmed, 1));
+    let value: u64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid interval '{input}'"))?;
+    value
+        .checked_mul(multiplier)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("invalid interval '{input}'"))
+}
+
+/// Directory holding the cached responses, `http` below the cache directory
+/// (`$XDG_CACHE_HOME/cloud-images-downloader/http` on Linux by default).
+pub fn cache_dir() -> Option<PathBuf> {
+    paths::cache_dir().map(|dir| dir.join(CACHE_SUBDIR))
+}
+
+/// Cache file for `url` below `dir`.
+fn entry_path(dir: &Path, url: &str) -> PathBuf {
+    dir.join(format!(
+        "{}.json",
+        hex::encode(Sha256::digest(url.as_bytes()))
+    ))
+}
+
+fn load(path: &Path, url: &str) -> Option<Entry> {
+    let data = fs::read_to_string(path).ok()?;
+    let entry: Entry = serde_json::from_str(&data).ok()?;
+    (entry.url == url).then_some(entry)
+}
+
+/// Best effort: a cache that cannot be written only costs a full download
+/// next time.
+fn store(path: &Path, entry: &Entry) {
+    let Ok(data) = serde_json::to_string(entry) else {
+        return;
+    };
+    if let Some(dir) = path.parent()
+        && fs::create_dir_all(dir).is_ok()
+    {
+        let tmp = path.with_extension("json.tmp");
+        if fs::write(&tmp, data).is_ok() {
+            let _ = fs::rename(&tmp, path);
+        }
+    }
+}
+
+fn header(headers: &HeaderMap, name: impl reqwest::header::AsHeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+}
+
+/// Fetch a metadata document as text: a Simplestreams index, a directory
+/// listing or a checksum file (SHA256SUMS, SHA512SUMS, CHECKSUM, ...). Every
+/// provider reads its catalogs through here.
+///
+/// Bodies served with an `ETag` or `Last-Modified`, and every body of a URL
+/// with a refresh interval, are kept on disk. Within the interval the cached
+/// copy is served without asking the server; after it the copy is
+/// revalidated with `If-None-Match` / `If-Modified-Since`, so a `304 Not
+/// Modified` answer reuses it instead of downloading it again. Offline, the
+/// cached copy is used as is.
+pub async fn text(client: &Client, url: &str) -> reqwest::Result<String> {
+    let path = cache_dir().map(|dir| entry_path(&dir, url));
+    let cached = path.as_deref().and_then(|path| load(path, url));
+    let refresh = refresh_interval(url);
+    // Recorded and replayed runs always go through the fixtures.
+    if let Some(entry) = &cached
+        && (http::offline()
+            || (!fixtures::active()
+                && refresh.is_some_and(|interval| entry.is_fresh(interval, Utc::now()))))
+    {
+        if let Some(path) = &path {
+            touch(path);
+        }
+        return Ok(entry.body.clone());
+    }
+
+    let res = retry::send(|| {
+        let mut request = client.get(url);
+        if let Some(entry) = &cached {
+            if let Some(etag) = &entry.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &entry.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+        request
+    })
+    .await?;
+
+    if res.status() == StatusCode::NOT_MODIFIED
+        && let Some(entry) = cached
+    {
+        let entry = Entry {
+            validated: Some(Utc::now().to_rfc3339()),
+            ..entry
+        };
+        if let Some(path) = &path {
+            store(path, &entry);
+        }
+        fixtures::record(
+            &Method::GET,
+            url,
+            StatusCode::OK,
+            &HeaderMap::new(),
+            entry.body.as_bytes(),
+        );
+        return Ok(entry.body);
+    }
+
+    let res = res.error_for_status()?;
+    let etag = header(res.headers(), ETAG);
+    let last_modified = header(res.headers(), LAST_MODIFIED);
+    let headers = res.headers().clone();
+    let body = res.text().await?;
+    fixtures::record(&Method::GET, url, StatusCode::OK, &headers, body.as_bytes());
+
+    if let Some(path) = &path
+        && (etag.is_some() || last_modified.is_some() || refresh.is_some())
+    {
+        let entry = Entry {
+            url: url.to_string(),
+            etag,
+            last_modified,
+            validated: Some(Utc::now().to_rfc3339()),
+            body: body.clone(),
+        };
+        store(path, &entry);
+    }
+    Ok(body)
+}
+
+/// Make the next [`text`] of `url` ask the server even within its refresh
+/// interval. The validators are kept, so an unchanged body still comes back
+/// as `304 Not Modified`.
+pub fn expire(url: &str) {
+    if let Some(dir) = cache_dir() {
+        expire_in(&dir, url);
+    }
+}
+
+fn expire_in(dir: &Path, url: &str) {
+    let path = entry_path(dir, url);
+    if let Some(entry) = load(&path, url) {
+        let entry = Entry {
+            validated: None,
+            ..entry
+        };
+        store(&path, &entry);
+    }
+}
+
+/// Mark `path` as just used; the age `cache gc` goes by is the time of last
+/// use.
+fn touch(path: &Path) {
+    if let Ok(file) = fs::File::options().write(true).open(path) {
+        let _ = file.set_modified(SystemTime::now());
+    }
+}
+
+/// Delete the cached responses `policy` evicts, going by when each
+/// was last used, plus leftovers of interrupted writes.
+pub fn gc(policy: &Retention) -> Result<GcSummary, String> {
+    let Some(dir) = cache_dir() else {
+        return Ok(GcSummary::default());
+    };
+    gc_dir(&dir, policy, Utc::now())
+}
+
+fn gc_dir(dir: &Path, policy: &Retention, now: DateTime<Utc>) -> Result<GcSummary, String> {
+    let mut summary = GcSummary::default();
+    let Ok(files) = fs::read_dir(dir) else {
+        return Ok(summary);
+    };
+    let mut paths = Vec::new();
+    let mut candidates = Vec::new();
+    for file in files.flatten() {
+        let path = file.path();
+        let Ok(meta) = file.metadata() else {
+            continue;
+        };
+        if path.extension().is_some_and(|ext| ext == "tmp") {
+            if fs::remove_file(&path).is_ok() {
+                summary.files += 1;
+                summary.bytes += meta.len();
+            }
+            continue;
+        }
+        candidates.push(Candidate {
+            group: None,
+            stored: meta.modified().map(DateTime::from).unwrap_or_default(),
+            size: meta.len(),
+            file: path.display().to_string(),
+        });
+        paths.push(path);
+    }
+    for i in retention::plan(&candidates, policy, now) {
+        fs::remove_file(&paths[i])
+            .map_err(|e| format!("Failed to remove '{}': {e}", paths[i].display()))?;
+        summary.entries += 1;
+        summary.files += 1;
+        summary.bytes += candidates[i].size;
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Entry, entry_path, expire_in, load, longest_match, parse_interval, store};
+    use chrono::{TimeDelta, Utc};
+    use std::path::Path;
+    use std::time::Duration;
+
+    #[test]
+    fn entries_are_keyed_by_url() {
+        let dir = Path::new("/cache");
+        let a = entry_path(dir, "https://example.invalid/a/SHA256SUMS");
+        let b = entry_path(dir, "https://example.invalid/b/SHA256SUMS");
+        assert_ne!(a, b);
+        assert_eq!(a, entry_path(dir, "https://example.invalid/a/SHA256SUMS"));
+        assert!(a.starts_with(dir));
+    }
+
+    #[test]
+    fn stored_entries_load_back_for_their_url_only() {
+        let dir = std::env::temp_dir().join(format!("cid-http-cache-{}", std::process::id()));
+        let url = "https://example.invalid/CHECKSUM";
+        let path = entry_path(&dir, url);
+        let entry = Entry {
+            url: url.to_string(),
+            etag: Some("\"abc\"".to_string()),
+            last_modified: None,
+            validated: None,
+            body: "sha  file\n".to_string(),
+        };
+        store(&path, &entry);
+
+        assert_eq!(load(&path, url), Some(entry));
+        assert_eq!(load(&path, "https://example.invalid/other"), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn expired_entries_keep_their_validators() {
+        let dir = std::env::temp_dir().join(format!("cid-http-expire-{}", std::process::id()));
+        let url = "https://example.invalid/SHA512SUMS";
+        let path = entry_path(&dir, url);
+        let entry = Entry {
+            url: url.to_string(),
+            etag: Some("\"abc\"".to_string()),
+            last_modified: None,
+            validated: Some(Utc::now().to_rfc3339()),
+            body: "sha  file\n".to_string(),
+        };
+        store(&path, &entry);
+
+        expire_in(&dir, url);
+        let expired = load(&path, url).unwrap();
+        assert_eq!(expired.validated, None);
+        assert_eq!(expired.etag, entry.etag);
+        assert!(!expired.is_fresh(Duration::from_secs(86_400), Utc::now()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn serves_entries_within_the_refresh_interval_of_the_longest_prefix() {
+        let rules = [
+            (
+                "https://cloud-images.ubuntu.com/".to_string(),
+                Duration::from_secs(86_400),
+            ),
+            (
+                "https://cloud-images.ubuntu.com/daily/".to_string(),
+                parse_interval("6h").unwrap(),
+            ),
+        ];
+        let daily = "https://cloud-images.ubuntu.com/daily/streams/v1/index.json";
+        assert_eq!(
+            longest_match(&rules, daily),
+            Some(Duration::from_secs(6 * 3600))
+        );
+        assert_eq!(
+            longest_match(&rules, "https://cloud-images.ubuntu.com/releases/"),
+            Some(Duration::from_secs(86_400))
+        );
+        assert_eq!(longest_match(&rules, "https://cloud.debian.org/"), None);
+
+        let now = Utc::now();
+        let entry = Entry {
+            url: daily.to_string(),
+            etag: None,
+            last_modified: None,
+            validated: Some((now - TimeDelta::hours(2)).to_rfc3339()),
+            body: String::new(),
+        };
+        assert!(entry.is_fresh(Duration::from_secs(3 * 3600), now));
+        assert!(!entry.is_fresh(Duration::from_secs(3600), now));
+        assert!(
+            !Entry {
+                validated: None,
+                ..entry
+            }
+            .is_fresh(Duration::MAX, now)
+        );
+
+        assert_eq!(parse_interval("7d"), Ok(Duration::from_secs(7 * 86_400)));
+        assert_eq!(parse_interval("90"), Ok(Duration::from_secs(90)));
+        assert!(parse_interval("soon").is_err());
+    }
+}
@@ -0,0 +1,437 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+
+use crate::helpers::{find_program, vm};
+
+/// QEMU firmware config key the image's Ignition reads its config from, or
+/// `None` when `os` is provisioned by cloud-init. Fedora CoreOS and Flatcar
+/// come from providers registered by embedding crates; the built-in distros
+/// all use cloud-init.
+pub fn fw_cfg_name(os: &str) -> Option<&'static str> {
+    match os.to_ascii_lowercase().as_str() {
+        "fedora-coreos" | "fcos" | "coreos" | "rhcos" => Some("opt/com.coreos/config"),
+        "flatcar" | "flatcar-linux" => Some("opt/org.flatcar-linux/config"),
+        _ => None,
+    }
+}
+
+/// Check the provisioning flags against an `os` image before it is
+/// downloaded: Ignition images cannot take a cloud-init `seed`, and an
+/// `ignition` config given for a cloud-init image is ignored with a warning.
+pub fn check_provisioning(os: &str, seed: bool, ignition: bool) -> Result<(), String> {
+    match fw_cfg_name(os) {
+        Some(_) if seed => Err(format!(
+            "{os} is provisioned by Ignition, not cloud-init; pass a Butane or Ignition \
+             config with --ignition instead of --with-seed"
+        )),
+        None if ignition => {
+            eprintln!("Warning: {os} uses cloud-init; ignoring --ignition");
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Ignition spec a Butane `variant` / `version` pair produces.
+fn spec_version(variant: &str, version: &str) -> Result<&'static str, String> {
+    let minor = version
+        .strip_prefix("1.")
+        .and_then(|rest| rest.split('.').next())
+        .and_then(|minor| minor.parse::<u32>().ok());
+    match (variant, minor) {
+        ("fcos", Some(0)) => Ok("3.0.0"),
+        ("fcos", Some(1)) => Ok("3.1.0"),
+        ("fcos", Some(2 | 3)) => Ok("3.2.0"),
+        ("fcos", Some(4)) => Ok("3.3.0"),
+        ("fcos", Some(5)) => Ok("3.4.0"),
+        ("fcos", Some(6)) => Ok("3.5.0"),
+        ("flatcar", Some(0)) => Ok("3.3.0"),
+        ("flatcar", Some(1)) => Ok("3.4.0"),
+        _ => Err(format!(
+            "Butane {variant} {version} is not supported without the butane binary"
+        )),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Butane {
+    variant: String,
+    version: String,
+    #[serde(default)]
+    passwd: Passwd,
+    #[serde(default)]
+    storage: Storage,
+    #[serde(default)]
+    systemd: Systemd,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Passwd {
+    users: Vec<User>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct User {
+    name: String,
+    #[serde(default)]
+    ssh_authorized_keys: Vec<String>,
+    password_hash: Option<String>,
+    #[serde(default)]
+    groups: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Storage {
+    directories: Vec<Node>,
+    files: Vec<Node>,
+}
+
+/// A file or directory; directories have no contents.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Node {
+    path: String,
+    contents: Option<Contents>,
+    /// Octal, either as YAML's `0o644` or Butane's customary `0644`.
+    mode: Option<serde_yaml::Value>,
+    overwrite: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Contents {
+    inline: Option<String>,
+    source: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Systemd {
+    units: Vec<Unit>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Unit {
+    name: String,
+    enabled: Option<bool>,
+    mask: Option<bool>,
+    contents: Option<String>,
+    #[serde(default)]
+    dropins: Vec<Dropin>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Dropin {
+    name: String,
+    contents: Option<String>,
+}
+
+/// Numeric value of a Butane `mode`.
+fn mode(value: &serde_yaml::Value) -> Result<u64, String> {
+    match value {
+        serde_yaml::Value::Number(n) => n.as_u64(),
+        serde_yaml::Value::String(s) => {
+            u64::from_str_radix(s.strip_prefix("0o").unwrap_or(s), 8).ok()
+        }
+        _ => None,
+    }
+    .ok_or_else(|| format!("invalid mode {value:?}"))
+}
+
+/// Insert `value` under `key` unless it is an empty array or object.
+fn insert(object: &mut Map<String, Value>, key: &str, value: Value) {
+    let empty = match &value {
+        Value::Array(items) => items.is_empty(),
+        Value::Object(fields) => fields.is_empty(),
+        _ => false,
+    };
+    if !empty {
+        object.insert(key.to_string(), value);
+    }
+}
+
+fn node(node: &Node) -> Result<Value, String> {
+    let mut object = Map::new();
+    object.insert("path".to_string(), json!(node.path));
+    if let Some(value) = &node.mode {
+        object.insert("mode".to_string(), json!(mode(value)?));
+    }
+    if let Some(overwrite) = node.overwrite {
+        object.insert("overwrite".to_string(), json!(overwrite));
+    }
+    if let Some(contents) = &node.contents {
+        let source = match (&contents.inline, &contents.source) {
+            (Some(inline), None) => {
+                format!("data:,{}", utf8_percent_encode(inline, NON_ALPHANUMERIC))
+            }
+            (None, Some(source)) => source.clone(),
+            _ => {
+                return Err(format!(
+                    "{}: contents need exactly one of inline and source",
+                    node.path
+                ));
+            }
+        };
+        object.insert("contents".to_string(), json!({ "source": source }));
+    }
+    Ok(Value::Object(object))
+}
+
+/// Transpile the Butane config `yaml` to Ignition JSON. Only the common
+/// subset is understood (users and their keys, files, directories and
+/// systemd units); anything else is an error, for the `butane` binary.
+pub fn transpile(yaml: &str) -> Result<String, String> {
+    let butane: Butane =
+        serde_yaml::from_str(yaml).map_err(|e| format!("Invalid Butane config: {e}"))?;
+    let mut config = Map::new();
+    config.insert(
+        "ignition".to_string(),
+        json!({ "version": spec_version(&butane.variant, &butane.version)? }),
+    );
+
+    let users: Vec<Value> = butane
+        .passwd
+        .users
+        .iter()
+        .map(|user| {
+            let mut object = Map::new();
+            object.insert("name".to_string(), json!(user.name));
+            insert(
+                &mut object,
+                "sshAuthorizedKeys",
+                json!(user.ssh_authorized_keys),
+            );
+            if let Some(hash) = &user.password_hash {
+                object.insert("passwordHash".to_string(), json!(hash));
+            }
+            insert(&mut object, "groups", json!(user.groups));
+            Value::Object(object)
+        })
+        .collect();
+    if !users.is_empty() {
+        config.insert("passwd".to_string(), json!({ "users": users }));
+    }
+
+    let mut storage = Map::new();
+    let directories = butane
+        .storage
+        .directories
+        .iter()
+        .map(node)
+        .collect::<Result<Vec<_>, _>>()?;
+    insert(&mut storage, "directories", Value::Array(directories));
+    let files = butane
+        .storage
+        .files
+        .iter()
+        .map(node)
+        .collect::<Result<Vec<_>, _>>()?;
+    insert(&mut storage, "files", Value::Array(files));
+    insert(&mut config, "storage", Value::Object(storage));
+
+    let units: Vec<Value> = butane
+        .systemd
+        .units
+        .iter()
+        .map(|unit| {
+            let mut object = Map::new();
+            object.insert("name".to_string(), json!(unit.name));
+            if let Some(enabled) = unit.enabled {
+                object.insert("enabled".to_string(), json!(enabled));
+            }
+            if let Some(mask) = unit.mask {
+                object.insert("mask".to_string(), json!(mask));
+            }
+            if let Some(contents) = &unit.contents {
+                object.insert("contents".to_string(), json!(contents));
+            }
+            let dropins: Vec<Value> = unit
+                .dropins
+                .iter()
+                .map(|dropin| json!({ "name": dropin.name, "contents": dropin.contents }))
+                .collect();
+            insert(&mut object, "dropins", Value::Array(dropins));
+            Value::Object(object)
+        })
+        .collect();
+    if !units.is_empty() {
+        config.insert("systemd".to_string(), json!({ "units": units }));
+    }
+
+    let mut json = serde_json::to_string_pretty(&config).expect("configs serialise");
+    json.push('\n');
+    Ok(json)
+}
+
+/// A Butane config for `os` authorising `ssh_key` for the `core` user.
+pub fn default_butane(os: &str, ssh_key: Option<&str>) -> String {
+    let (variant, version) = match fw_cfg_name(os) {
+        Some("opt/org.flatcar-linux/config") => ("flatcar", "1.0.0"),
+        _ => ("fcos", "1.5.0"),
+    };
+    let mut yaml = format!("variant: {variant}\nversion: {version}\n");
+    if let Some(key) = ssh_key {
+        yaml.push_str(&format!(
+            "passwd:\n  users:\n    - name: core\n      ssh_authorized_keys:\n        - {}\n",
+            serde_json::to_string(key).expect("strings serialise")
+        ));
+    }
+    yaml
+}
+
+/// Write the Ignition config for `source` to `output`: `.ign` and `.json`
+/// files are taken as they are, Butane configs are transpiled with the
+/// `butane` binary when it is on `PATH` and the built-in subset otherwise.
+pub fn build(source: &Path, output: &Path) -> Result<(), String> {
+    let text = fs::read_to_string(source)
+        .map_err(|e| format!("Failed to read '{}': {e}", source.display()))?;
+    let config = match source.extension().and_then(|ext| ext.to_str()) {
+        Some("ign" | "json") => {
+            let value: Value = serde_json::from_str(&text)
+                .map_err(|e| format!("'{}' is not JSON: {e}", source.display()))?;
+            if value["ignition"]["version"].as_str().is_none() {
+                return Err(format!(
+                    "'{}' is not an Ignition config (no ignition.version)",
+                    source.display()
+                ));
+            }
+            text
+        }
+        _ => match find_program("butane") {
+            Some(butane) => {
+                let mut command = Command::new(&butane);
+                command.arg("--strict");
+                if let Some(dir) = source.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                    command.arg("--files-dir").arg(dir);
+                }
+                let result = command
+                    .arg(source)
+                    .output()
+                    .map_err(|e| format!("Failed to run '{}': {e}", butane.display()))?;
+                if !result.status.success() {
+                    return Err(format!(
+                        "butane failed ({}): {}",
+                        result.status,
+                        String::from_utf8_lossy(&result.stderr).trim()
+                    ));
+                }
+                String::from_utf8_lossy(&result.stdout).into_owned()
+            }
+            None => transpile(&text)?,
+        },
+    };
+    fs::write(output, config).map_err(|e| format!("Failed to write '{}': {e}", output.display()))
+}
+
+/// Write the Ignition config of an `os` image to `output`: `source` (Butane
+/// or Ignition) when given, else one authorising `ssh_key` (or the user's
+/// default key) for the `core` user.
+pub fn write_config(
+    output: &Path,
+    source: Option<&Path>,
+    ssh_key: Option<&Path>,
+    os: &str,
+) -> Result<(), String> {
+    if let Some(source) = source {
+        return build(source, output);
+    }
+    let key = vm::ssh_key(ssh_key)?;
+    if key.is_none() {
+        eprintln!("Warning: no SSH public key found; only the console will be usable");
+    }
+    let config = transpile(&default_butane(os, key.as_deref()))?;
+    fs::write(output, config).map_err(|e| format!("Failed to write '{}': {e}", output.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_provisioning, default_butane, fw_cfg_name, transpile, write_config};
+    use serde_json::Value;
+
+    #[test]
+    fn transpiles_the_common_subset() {
+        let config: Value = serde_json::from_str(
+            &transpile(
+                r#"
+variant: fcos
+version: 1.5.0
+passwd:
+  users:
+    - name: core
+      ssh_authorized_keys:
+        - ssh-ed25519 AAAA me@host
+storage:
+  files:
+    - path: /etc/hostname
+      mode: 0644
+      contents:
+        inline: node 1
+systemd:
+  units:
+    - name: hello.service
+      enabled: true
+      contents: "[Service]\nExecStart=/bin/true\n"
+"#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(config["ignition"]["version"], "3.4.0");
+        assert_eq!(
+            config["passwd"]["users"][0]["sshAuthorizedKeys"][0],
+            "ssh-ed25519 AAAA me@host"
+        );
+        let file = &config["storage"]["files"][0];
+        assert_eq!(file["mode"], 0o644);
+        assert_eq!(file["contents"]["source"], "data:,node%201");
+        assert_eq!(config["systemd"]["units"][0]["enabled"], true);
+
+        assert!(transpile("variant: fcos\nversion: 1.5.0\nkernel_arguments: {}\n").is_err());
+        let minimal: Value =
+            serde_json::from_str(&transpile(&default_butane("flatcar", None)).unwrap()).unwrap();
+        assert_eq!(
+            minimal,
+            serde_json::json!({"ignition": {"version": "3.3.0"}})
+        );
+        assert_eq!(fw_cfg_name("fedora-coreos"), Some("opt/com.coreos/config"));
+        assert_eq!(fw_cfg_name("ubuntu"), None);
+    }
+
+    #[test]
+    fn writes_a_default_config_authorising_the_key() {
+        let dir = std::env::temp_dir().join(format!("ignition-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let key = dir.join("id_ed25519.pub");
+        std::fs::write(&key, "ssh-ed25519 AAAA me@host\n").unwrap();
+        let output = dir.join("config.ign");
+
+        write_config(&output, None, Some(&key), "flatcar").unwrap();
+        let config: Value =
+            serde_json::from_str(&std::fs::read_to_string(&output).unwrap()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(config["ignition"]["version"], "3.3.0");
+        assert_eq!(config["passwd"]["users"][0]["name"], "core");
+        assert_eq!(
+            config["passwd"]["users"][0]["sshAuthorizedKeys"][0],
+            "ssh-ed25519 AAAA me@host"
+        );
+    }
+
+    #[test]
+    fn ignition_images_refuse_a_cloud_init_seed() {
+        assert!(check_provisioning("fedora-coreos", true, false).is_err());
+        assert!(check_provisioning("fedora-coreos", false, true).is_ok());
+        assert!(check_provisioning("debian", false, true).is_ok());
+    }
+}
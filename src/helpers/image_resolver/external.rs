@@ -0,0 +1,301 @@
+use std::ffi::OsString;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::time::Duration;
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use super::DownloadOptions;
+use crate::helpers::{cancel::CancellationToken, find_program, retry};
+
+/// How often a running download tool is checked for cancellation.
+const CANCEL_POLL: Duration = Duration::from_millis(200);
+
+/// Program that performs the transfer of an image. Everything around it
+/// (mirror selection, checksum verification, post-processing) stays the same.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Downloader {
+    /// The built-in HTTP client.
+    #[default]
+    Builtin,
+    /// `aria2c`, fetching from every mirror at once.
+    Aria2c,
+    /// `curl`, trying one mirror after the other.
+    Curl,
+}
+
+impl Downloader {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Downloader::Builtin => "builtin",
+            Downloader::Aria2c => "aria2c",
+            Downloader::Curl => "curl",
+        }
+    }
+}
+
+impl fmt::Display for Downloader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Locate the program behind `downloader`; `None` for the built-in client.
+pub fn locate(downloader: Downloader) -> Result<Option<PathBuf>, String> {
+    if downloader == Downloader::Builtin {
+        return Ok(None);
+    }
+    find_program(downloader.as_str()).map(Some).ok_or_else(|| {
+        format!(
+            "{downloader} was not found on PATH; install it or drop `--downloader {downloader}`"
+        )
+    })
+}
+
+/// Command line handing `urls` (the same file on every mirror) to
+/// `downloader`, writing to `part_path` and continuing a partial file.
+fn arguments(
+    downloader: Downloader,
+    urls: &[String],
+    part_path: &Path,
+    options: &DownloadOptions,
+    attempts: u32,
+) -> Vec<OsString> {
+    let mut args: Vec<OsString> = Vec::new();
+    let quiet = !options.progress.external_output();
+    let rate = options.rate_limit.as_ref().map(|l| l.bytes_per_sec());
+
+    match downloader {
+        Downloader::Builtin => {}
+        Downloader::Aria2c => {
+            let dir = part_path.parent().unwrap_or(Path::new("."));
+            let name = part_path.file_name().unwrap_or_default();
+            let connections = options.connections.max(1);
+            args.extend(
+                [
+                    "--continue=true".to_string(),
+                    "--allow-overwrite=true".to_string(),
+                    "--auto-file-renaming=false".to_string(),
+                    "--file-allocation=none".to_string(),
+                    format!("--max-tries={attempts}"),
+                    format!("--split={connections}"),
+                    format!("--max-connection-per-server={}", connections.min(16)),
+                ]
+                .map(OsString::from),
+            );
+            if let Some(rate) = rate {
+                args.push(format!("--max-overall-download-limit={rate}").into());
+            }
+            if quiet {
+                args.push("--quiet=true".into());
+            }
+            args.push("--dir".into());
+            args.push(dir.into());
+            args.push("--out".into());
+            args.push(name.into());
+            // Every URI is treated as a source of the same file.
+            args.extend(urls.iter().map(OsString::from));
+        }
+        Downloader::Curl => {
+            args.extend(
+                [
+                    "--fail",
+                    "--location",
+                    "--continue-at",
+                    "-",
+                    "--retry",
+                    &attempts.saturating_sub(1).to_string(),
+                ]
+                .map(OsString::from),
+            );
+            if let Some(rate) = rate {
+                args.push("--limit-rate".into());
+                args.push(rate.to_string().into());
+            }
+            if quiet {
+                args.push("--silent".into());
+                args.push("--show-error".into());
+            }
+            args.push("--output".into());
+            args.push(part_path.into());
+        }
+    }
+    args
+}
+
+/// Run `program` off the async runtime and wait for it to exit. Its output
+/// goes straight to the terminal. The program is killed when `cancel` fires.
+async fn run(
+    program: &Path,
+    args: Vec<OsString>,
+    cancel: &CancellationToken,
+) -> Result<ExitStatus, String> {
+    let program = program.to_path_buf();
+    let cancel = cancel.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut child = Command::new(&program)
+            .args(&args)
+            .stdin(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("Failed to run '{}': {e}", program.display()))?;
+        loop {
+            let exited = child
+                .try_wait()
+                .map_err(|e| format!("Failed to wait for '{}': {e}", program.display()))?;
+            if let Some(status) = exited {
+                return Ok(status);
+            }
+            if cancel.is_cancelled() {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("Cancelled '{}'", program.display()));
+            }
+            std::thread::sleep(CANCEL_POLL);
+        }
+    })
+    .await
+    .map_err(|e| format!("Download task failed: {e}"))?
+}
+
+/// Download `urls` into `part_path` with `downloader`. curl gets one mirror
+/// at a time and continues the partial file on the next one when it fails.
+pub(super) async fn download(
+    downloader: Downloader,
+    urls: &[String],
+    part_path: &Path,
+    options: &DownloadOptions,
+) -> Result<(), String> {
+    let program = locate(downloader)?.ok_or("the built-in client is not an external tool")?;
+    let attempts = retry::policy().attempts;
+
+    let invocations: Vec<Vec<OsString>> = match downloader {
+        Downloader::Curl => urls
+            .iter()
+            .map(|url| {
+                let mut args = arguments(downloader, urls, part_path, options, attempts);
+                args.push(url.into());
+                args
+            })
+            .collect(),
+        _ => vec![arguments(downloader, urls, part_path, options, attempts)],
+    };
+
+    let mut last_error = String::new();
+    for args in invocations {
+        let status = run(&program, args, &options.cancel).await?;
+        if status.success() {
+            return Ok(());
+        }
+        last_error = format!("{downloader} exited with {status}");
+    }
+    Err(last_error)
+}
+
+/// Fetch the single-file torrent at `torrent_url` with aria2c into
+/// `part_path`. aria2c names torrent downloads itself, so they land in a
+/// scratch directory first. Seeding stops once the download completes.
+pub(super) async fn download_torrent(
+    torrent_url: &str,
+    part_path: &Path,
+    options: &DownloadOptions,
+) -> Result<(), String> {
+    let program = locate(Downloader::Aria2c)?.ok_or("aria2c is required for torrents")?;
+    let mut scratch = part_path.as_os_str().to_owned();
+    scratch.push(".torrent.d");
+    let scratch = PathBuf::from(scratch);
+
+    let mut args: Vec<OsString> = [
+        "--seed-time=0",
+        "--follow-torrent=mem",
+        "--file-allocation=none",
+        "--continue=true",
+    ]
+    .map(OsString::from)
+    .to_vec();
+    if let Some(rate) = options.rate_limit.as_ref().map(|l| l.bytes_per_sec()) {
+        args.push(format!("--max-overall-download-limit={rate}").into());
+    }
+    if !options.progress.external_output() {
+        args.push("--quiet=true".into());
+    }
+    args.push("--dir".into());
+    args.push(scratch.clone().into());
+    args.push(torrent_url.into());
+
+    let status = run(&program, args, &options.cancel).await?;
+    if !status.success() {
+        return Err(format!("aria2c exited with {status} for {torrent_url}"));
+    }
+
+    let files: Vec<PathBuf> = std::fs::read_dir(&scratch)
+        .map_err(|e| format!("Failed to read '{}': {e}", scratch.display()))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_file())
+        .collect();
+    let [file] = files.as_slice() else {
+        return Err(format!(
+            "{torrent_url} does not describe a single image ({} files)",
+            files.len()
+        ));
+    };
+    std::fs::rename(file, part_path)
+        .map_err(|e| format!("Failed to move '{}': {e}", file.display()))?;
+    let _ = std::fs::remove_dir_all(&scratch);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Downloader, arguments};
+    use crate::helpers::image_resolver::DownloadOptions;
+    use crate::helpers::throttle::RateLimiter;
+    use std::ffi::OsString;
+    use std::path::Path;
+
+    fn has(args: &[OsString], arg: &str) -> bool {
+        args.iter().any(|a| a == arg)
+    }
+
+    #[test]
+    fn aria2c_gets_every_mirror_and_the_part_name() {
+        let urls = vec![
+            "https://a.invalid/x.img".to_string(),
+            "https://b.invalid/x.img".to_string(),
+        ];
+        let options = DownloadOptions {
+            connections: 4,
+            rate_limit: Some(RateLimiter::new(1024)),
+            ..DownloadOptions::default()
+        };
+        let args = arguments(
+            Downloader::Aria2c,
+            &urls,
+            Path::new("/tmp/dl/x.img.part"),
+            &options,
+            3,
+        );
+        assert!(has(&args, "--continue=true"));
+        assert!(has(&args, "--split=4"));
+        assert!(has(&args, "--max-overall-download-limit=1024"));
+        assert!(has(&args, "x.img.part"));
+        assert!(has(&args, "https://a.invalid/x.img") && has(&args, "https://b.invalid/x.img"));
+    }
+
+    #[test]
+    fn curl_continues_the_part_file() {
+        let args = arguments(
+            Downloader::Curl,
+            &[],
+            Path::new("/tmp/dl/x.img.part"),
+            &DownloadOptions::default(),
+            4,
+        );
+        assert!(has(&args, "--continue-at") && has(&args, "-"));
+        assert!(has(&args, "/tmp/dl/x.img.part"));
+        assert!(has(&args, "3"));
+        assert!(!has(&args, "--limit-rate"));
+    }
+}
@@ -1,71 +1,1947 @@
-// you need this in your cargo.toml
-// reqwest = { version = "0.11.3", features = ["stream"] }
-// futures-util = "0.3.14"
-// indicatif = "0.15.0"
 use std::cmp::min;
-use std::fs::File;
-use std::io::Write;
-use std::path::PathBuf;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use indicatif::{ProgressBar, ProgressStyle};
+use chrono::Utc;
+use clap::ValueEnum;
+use futures::future::join_all;
+use futures::stream::{self, StreamExt};
+use reqwest::header::{CONTENT_LENGTH, CONTENT_RANGE, ETAG, LAST_MODIFIED, RANGE};
+use reqwest::{StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::Digest;
 
-pub async fn download_file(url: &str) -> Result<String, String> {
-    // HTTP client
-    let client = reqwest::Client::new();
+use crate::cloud::{ChecksumKind, Image, ImageChecksum};
+use crate::error::CloudImagesError;
+#[cfg(feature = "s3")]
+use crate::helpers::s3;
+use crate::helpers::{
+    cancel::{self, CancellationToken},
+    checksum::{self, ChecksumSource, StreamHasher, verify_digest},
+    choose_one,
+    customize::{self, Customization},
+    decompress, http, http_cache, human_size,
+    library::{self, CloneMode, Library},
+    metalink,
+    notify::Notifier,
+    progress::{ProgressSink, TerminalProgress, Transfer},
+    provenance::{self, Statement},
+    qemu_img::{self, DiskFormat},
+    report::{self, Report},
+    retry, sanitize,
+    seed::{self, Seed},
+    throttle::RateLimiter,
+    xattr, zsync,
+};
+use crate::repositories;
+
+pub mod external;
+mod writer;
+
+use self::external::Downloader;
+use self::writer::ChunkWriter;
+
+/// Algorithm hashed while downloading `image`: the published one, or SHA-256
+/// for the verification report when upstream publishes none.
+fn digest_kind(image: &Image) -> ChecksumKind {
+    image
+        .checksum()
+        .map(|c| c.kind())
+        .unwrap_or(ChecksumKind::Sha256)
+}
+
+/// Decide what to do with a destination file that already exists: skip it
+/// when it matches the expected checksum, otherwise follow `policy`. Resuming
+/// is only offered when `partial`, the `.part` file of an interrupted run, is
+/// there to continue; the existing file itself failed verification and
+/// appending to it would keep its corrupt prefix.
+fn check_existing(
+    image: &Image,
+    out_path: &Path,
+    partial: Option<&Path>,
+    policy: ExistingFile,
+) -> Result<ExistingAction, CloudImagesError> {
+    if let Some(expected) = image.checksum()
+        && checksum::verify_file(out_path, expected).is_ok()
+    {
+        return Ok(ExistingAction::UpToDate);
+    }
+
+    match policy {
+        ExistingFile::Overwrite => Ok(ExistingAction::Overwrite),
+        ExistingFile::Skip => Ok(ExistingAction::Keep),
+        ExistingFile::Ask => {
+            let title = format!(
+                "'{}' already exists and does not match the expected checksum",
+                out_path.display()
+            );
+            let mut choices = vec!["Overwrite", "Abort"];
+            if partial.is_some() {
+                choices.insert(1, "Resume");
+            }
+            let choice = choose_one(&title, choices)?;
+            match choice.as_str() {
+                "Overwrite" => Ok(ExistingAction::Overwrite),
+                "Resume" => Ok(ExistingAction::Resume),
+                _ => Err(CloudImagesError::Cancelled(format!(
+                    "Aborted: '{}' left untouched",
+                    out_path.display()
+                ))),
+            }
+        }
+    }
+}
+
+/// Directory an image is stored in below `root`: `<os>/<distro_version>/<arch>`
+/// unless `flat` is requested.
+pub fn destination_dir(root: &Path, image: &Image, flat: bool) -> PathBuf {
+    if flat {
+        return root.to_path_buf();
+    }
+    root.join(image.os())
+        .join(image.distro_version())
+        .join(image.arch_name())
+}
+
+/// Issue one HEAD request per URL concurrently and return the advertised
+/// `Content-Length` for each of them, in the same order as `urls`. Failed
+/// requests or missing headers are reported as `None`.
+pub async fn content_lengths(urls: &[&str]) -> Vec<Option<u64>> {
+    let client = http::client();
+    let requests = urls.iter().map(|url| async move {
+        let res = retry::send(|| client.head(*url))
+            .await
+            .ok()?
+            .error_for_status()
+            .ok()?;
+        // `Response::content_length` reports the (empty) body of a HEAD
+        // response, so read the header instead.
+        res.headers()
+            .get(CONTENT_LENGTH)?
+            .to_str()
+            .ok()?
+            .parse()
+            .ok()
+    });
+    join_all(requests).await
+}
+
+/// Smallest segment worth opening an extra connection for.
+const MIN_SEGMENT_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Capacity of the writer in front of downloaded files; a few large
+/// sequential writes are much cheaper than one per network chunk.
+const WRITE_BUFFER_SIZE: usize = 4 * 1024 * 1024;
 
-    // Request
-    let mut res = client
-        .get(url)
-        .header("User-Agent", "cloud-index-reader-rust/1.0")
-        .send()
+/// Metalink mirrors probed for latency before a download.
+const METALINK_PROBES: usize = 8;
+
+/// Uncompressed to compressed size ratio assumed for disk images when
+/// reserving room for `--decompress`; sparse cloud images compress well.
+const DECOMPRESSION_HEADROOM: u64 = 4;
+
+/// Tunables for `download_file`. The defaults never prompt: an existing
+/// file that cannot be confirmed is overwritten and a download failing
+/// verification is deleted; interactive front-ends opt into
+/// [`ExistingFile::Ask`] and [`OnMismatch::Ask`].
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// Number of concurrent range requests used for a single file. `1`
+    /// disables segmented downloading.
+    pub connections: usize,
+    /// What to do when the destination file already exists and does not match
+    /// the expected checksum.
+    pub existing: ExistingFile,
+    /// Optional bandwidth cap shared by every connection of a download.
+    pub rate_limit: Option<RateLimiter>,
+    /// Other locations of the same file, tried in order when the image URL
+    /// fails; transfers switch over mid-file with a `Range` request.
+    pub mirrors: Vec<String>,
+    /// Rebuild an outdated existing file with zsync when the mirror publishes
+    /// a `.zsync` control file next to the image.
+    pub zsync: bool,
+    /// Decompress `.xz`, `.bz2`, `.zst` and `.gz` images after their checksum
+    /// has been verified.
+    pub decompress: bool,
+    /// Convert the verified image to this format with `qemu-img`.
+    pub convert_to: Option<DiskFormat>,
+    /// Apply these changes with `virt-customize` to a copy of the final
+    /// (resized) image.
+    pub customize: Option<Customization>,
+    /// Grow the final image with `qemu-img resize`, e.g. `40G`.
+    pub resize: Option<String>,
+    /// Build a cloud-init NoCloud seed next to the final image.
+    pub seed: Option<Seed>,
+    /// Where transfers report their progress; progress bars on the terminal
+    /// by default.
+    pub progress: Arc<dyn ProgressSink>,
+    /// Continue from the `.part` and `.segN` files an interrupted run left
+    /// behind instead of starting over. Segments are only reused when the
+    /// plan recorded next to them has the same byte ranges and the file on
+    /// the server is unchanged.
+    pub resume_partial: bool,
+    /// Program performing the transfer itself.
+    pub downloader: Downloader,
+    /// Look for a Metalink (`.meta4` / `.metalink`) next to the image and
+    /// use its mirrors and hashes.
+    pub metalink: bool,
+    /// Fetch the image through the `.torrent` published next to it when
+    /// there is one (requires the aria2c downloader).
+    pub torrent: bool,
+    /// Download into this staging directory and only move images into the
+    /// destination once their checksum has been verified.
+    pub quarantine_dir: Option<PathBuf>,
+    /// Tag finished images with extended attributes (origin URL, checksum,
+    /// download time).
+    pub xattrs: bool,
+    /// Write an in-toto provenance statement (`<image>.intoto.json`) next to
+    /// every download.
+    pub provenance: bool,
+    /// Upload every verified image below this `s3://bucket/prefix/`; an
+    /// error without the `s3` feature.
+    pub upload: Option<Url>,
+    /// What to do when the downloaded file does not match its checksum.
+    pub on_mismatch: OnMismatch,
+    /// Keep verified images in this library and copy the ones it already
+    /// holds instead of downloading them again.
+    pub library: Option<Library>,
+    /// Announces downloads that took at least its `min_duration`.
+    pub notifier: Option<Arc<Notifier>>,
+    /// Aborts the download when cancelled and removes its `.part` and
+    /// `.segN` files; a download that fails keeps them for resuming.
+    pub cancel: CancellationToken,
+}
+
+/// Policy for a destination file that already exists but cannot be confirmed
+/// as up to date.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExistingFile {
+    /// Prompt the user to overwrite, resume or abort.
+    Ask,
+    /// Download again from scratch.
+    Overwrite,
+    /// Keep the existing file and skip the download.
+    Skip,
+}
+
+/// What to do with a download whose checksum does not match.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnMismatch {
+    /// Prompt for one of the other choices.
+    Ask,
+    /// Download it again, starting with the next mirror (or from the same
+    /// source when there is no other), against the checksum as it is
+    /// published now.
+    Redownload,
+    /// Keep it as `<image>.corrupt` for inspection, in the quarantine
+    /// directory when there is one.
+    Keep,
+    /// Delete it.
+    #[default]
+    Delete,
+}
+
+/// Resolve `policy` for the mismatching download of `url`, prompting when
+/// it is [`OnMismatch::Ask`]; `mirrored` tells whether another source
+/// publishes the image.
+fn mismatch_action(
+    policy: OnMismatch,
+    url: &str,
+    err: &str,
+    mirrored: bool,
+) -> Result<OnMismatch, CloudImagesError> {
+    if policy != OnMismatch::Ask {
+        return Ok(policy);
+    }
+    let title = format!("{url} failed verification ({err})");
+    let again = if mirrored {
+        "Download again from another mirror"
+    } else {
+        "Download again from the same source"
+    };
+    let choice = choose_one(&title, vec![again, "Keep as .corrupt", "Delete"])?;
+    Ok(match choice.as_str() {
+        "Keep as .corrupt" => OnMismatch::Keep,
+        "Delete" => OnMismatch::Delete,
+        _ => OnMismatch::Redownload,
+    })
+}
+
+/// Outcome of inspecting an existing destination file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExistingAction {
+    UpToDate,
+    Keep,
+    Overwrite,
+    Resume,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            connections: 1,
+            existing: ExistingFile::Overwrite,
+            rate_limit: None,
+            mirrors: Vec::new(),
+            zsync: true,
+            decompress: false,
+            convert_to: None,
+            customize: None,
+            resize: None,
+            seed: None,
+            progress: Arc::new(TerminalProgress::default()),
+            resume_partial: false,
+            downloader: Downloader::Builtin,
+            metalink: false,
+            torrent: false,
+            quarantine_dir: None,
+            xattrs: false,
+            provenance: false,
+            upload: None,
+            on_mismatch: OnMismatch::Delete,
+            library: None,
+            notifier: None,
+            cancel: CancellationToken::new(),
+        }
+    }
+}
+
+/// Run `f` on the blocking pool. Unlike `block_in_place` this also works on
+/// a current-thread runtime, which embedding programs may well use.
+async fn run_blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, CloudImagesError> + Send + 'static,
+) -> Result<T, CloudImagesError> {
+    tokio::task::spawn_blocking(f)
         .await
-        .map_err(|e| format!("Failed to GET from '{url}': {e}"))?;
-
-    let total_size = res
-        .content_length()
-        .ok_or_else(|| format!("Failed to get content length from '{url}'"))?;
-
-    // Progress bar
-    let pb = ProgressBar::new(total_size);
-    let style = ProgressStyle::with_template(
-        "{msg}\n{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] \
-         {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
-    )
-    .map_err(|e| format!("Failed to build progress style: {e}"))?
-    .progress_chars("#>-");
-    pb.set_style(style);
-    pb.set_message(format!("Downloading {url}"));
-
-    // Output path: current directory + filename from the URL (fallback: "download")
-    let mut out_path: PathBuf =
-        std::env::current_dir().map_err(|e| format!("Failed to get current dir: {e}"))?;
-    let filename = url
-        .rsplit('/')
-        .find(|s| !s.is_empty())
-        .unwrap_or("download");
-    out_path.push(filename);
+        .map_err(|e| CloudImagesError::Io(format!("Blocking task failed: {e}")))?
+}
+
+/// Run `f` on the blocking pool with the hasher moved in, and put the hasher
+/// back afterwards.
+async fn with_hasher_blocking<T: Send + 'static>(
+    hasher: &mut Option<StreamHasher>,
+    f: impl FnOnce(&mut Option<StreamHasher>) -> Result<T, String> + Send + 'static,
+) -> Result<T, String> {
+    let mut owned = hasher.take();
+    let (result, owned) = tokio::task::spawn_blocking(move || {
+        let result = f(&mut owned);
+        (result, owned)
+    })
+    .await
+    .map_err(|e| format!("Blocking task failed: {e}"))?;
+    *hasher = owned;
+    result
+}
+
+/// [`hash_file_into`] off the async workers; a no-op without a hasher.
+async fn hash_file_blocking(path: &Path, hasher: &mut Option<StreamHasher>) -> Result<(), String> {
+    let path = path.to_path_buf();
+    with_hasher_blocking(hasher, move |hasher| match hasher {
+        Some(hasher) => checksum::hash_file(&path, hasher),
+        None => Ok(()),
+    })
+    .await
+}
+
+/// Open `path` for downloaded data behind a large write buffer, appending to
+/// its current content when `append` is set, and reserve disk space for the
+/// `total` bytes the file ends up with.
+fn open_output(path: &Path, append: bool, total: Option<u64>) -> Result<BufWriter<File>, String> {
+    let file = if append {
+        OpenOptions::new().append(true).open(path)
+    } else {
+        File::create(path)
+    }
+    .map_err(|e| format!("Failed to open file '{}': {e}", path.display()))?;
+    if let Some(total) = total {
+        preallocate(&file, total);
+    }
+    Ok(BufWriter::with_capacity(WRITE_BUFFER_SIZE, file))
+}
+
+/// Reserve blocks for `len` bytes so large images are not fragmented. The
+/// file size is left alone (`FALLOC_FL_KEEP_SIZE`) because resuming relies
+/// on it matching the bytes written, and filesystems without `fallocate`
+/// support are simply written to as before.
+#[cfg(target_os = "linux")]
+fn preallocate(file: &File, len: u64) {
+    use std::os::fd::AsRawFd;
+
+    let Ok(len) = libc::off_t::try_from(len) else {
+        return;
+    };
+    // SAFETY: the descriptor belongs to `file`, which outlives the call.
+    unsafe {
+        libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, len);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn preallocate(_file: &File, _len: u64) {}
+
+/// Move `from` to `to`, copying when they live on different filesystems
+/// (e.g. a quarantine directory on another disk).
+fn move_file(from: &Path, to: &Path) -> Result<(), String> {
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    std::fs::copy(from, to)
+        .and_then(|_| std::fs::remove_file(from))
+        .map_err(|e| {
+            format!(
+                "Failed to move '{}' to '{}': {e}",
+                from.display(),
+                to.display()
+            )
+        })
+}
+
+/// Remove `path` on the blocking pool, if it exists.
+async fn discard(path: &Path) {
+    let path = path.to_path_buf();
+    let _ = tokio::task::spawn_blocking(move || std::fs::remove_file(path)).await;
+}
+
+/// Remove the `.part` file and the segments of a cancelled download of at
+/// most `connections` ranges.
+async fn remove_partial(part_path: &Path, connections: usize) {
+    let mut paths = vec![part_path.to_path_buf(), plan_path(part_path)];
+    paths.extend((0..connections.max(1)).map(|i| with_suffix(part_path, &format!(".seg{i}"))));
+    let _ = tokio::task::spawn_blocking(move || {
+        for path in paths {
+            let _ = std::fs::remove_file(path);
+        }
+    })
+    .await;
+}
+
+/// Append `suffix` to the file name of `path` (e.g. `image.qcow2.part`).
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Ask the server for the first byte of `url`. Returns the total size, the
+/// URL that answered (after redirects) and the file's `ETag` or
+/// `Last-Modified` when the server honours `Range` requests, `None`
+/// otherwise.
+async fn probe_range_support(
+    client: &reqwest::Client,
+    url: &str,
+) -> Option<(u64, String, Option<String>)> {
+    let res = retry::send(|| client.get(url).header(RANGE, "bytes=0-0"))
+        .await
+        .ok()?;
+
+    if res.status() != StatusCode::PARTIAL_CONTENT {
+        return None;
+    }
+
+    // Content-Range: bytes 0-0/123456
+    let total = res
+        .headers()
+        .get(CONTENT_RANGE)?
+        .to_str()
+        .ok()?
+        .rsplit_once('/')?
+        .1
+        .parse()
+        .ok()?;
+    let validator = [ETAG, LAST_MODIFIED].into_iter().find_map(|name| {
+        let value = res.headers().get(&name)?.to_str().ok()?;
+        Some(format!("{name}: {value}"))
+    });
+    Some((total, res.url().to_string(), validator))
+}
+
+/// Point `requested` at the URL that actually served `res`. Redirectors may
+/// send every request to a different mirror, so range and resume requests
+/// have to go straight to the host the transfer started on.
+fn pin_redirect(requested: &mut String, res: &reqwest::Response) {
+    if res.url().as_str() != requested.as_str() {
+        eprintln!("Redirected {requested} -> {}", res.url());
+        *requested = res.url().to_string();
+    }
+}
+
+/// Re-request `url` from byte `offset` (up to the inclusive `end`, if given)
+/// after the connection dropped mid-transfer.
+async fn resume_request(
+    client: &reqwest::Client,
+    url: &str,
+    offset: u64,
+    end: Option<u64>,
+) -> Result<reqwest::Response, String> {
+    let range = match end {
+        Some(end) => format!("bytes={offset}-{end}"),
+        None => format!("bytes={offset}-"),
+    };
+    let res = retry::send(|| client.get(url).header(RANGE, &range))
+        .await
+        .map_err(|e| format!("Failed to resume '{url}' at byte {offset}: {e}"))?;
+
+    if res.status() != StatusCode::PARTIAL_CONTENT {
+        return Err(format!(
+            "Cannot resume '{url}' at byte {offset}: server answered HTTP {}",
+            res.status()
+        ));
+    }
+    Ok(res)
+}
+
+/// Open the first of `urls[mirror..]` that answers, starting at byte `offset`
+/// (up to the inclusive `end`, if given). Returns the index of the mirror in
+/// use together with its response; the entry is replaced by the final URL
+/// when the request was redirected.
+async fn open_from(
+    client: &reqwest::Client,
+    urls: &mut [String],
+    mut mirror: usize,
+    offset: u64,
+    end: Option<u64>,
+) -> Result<(usize, reqwest::Response), String> {
+    let mut last_err = format!("No download URL left to try after '{}'", urls[0]);
+
+    while let Some(url) = urls.get(mirror) {
+        let outcome = if offset == 0 && end.is_none() {
+            retry::send(|| client.get(url))
+                .await
+                .and_then(|res| res.error_for_status())
+                .map_err(|e| format!("Failed to GET from '{url}': {e}"))
+        } else {
+            resume_request(client, url, offset, end).await
+        };
+
+        match outcome {
+            Ok(res) => {
+                pin_redirect(&mut urls[mirror], &res);
+                return Ok((mirror, res));
+            }
+            Err(err) => {
+                if mirror + 1 < urls.len() {
+                    eprintln!("{err}; trying the next mirror");
+                }
+                last_err = err;
+                mirror += 1;
+            }
+        }
+    }
+
+    Err(last_err)
+}
+
+/// Split `total_size` bytes into at most `connections` inclusive byte ranges.
+fn segment_ranges(total_size: u64, connections: usize) -> Vec<(u64, u64)> {
+    if total_size == 0 {
+        return Vec::new();
+    }
+    let max_segments = total_size.div_ceil(MIN_SEGMENT_SIZE).max(1);
+    let segments = (connections.max(1) as u64).min(max_segments);
+    let segment_size = total_size.div_ceil(segments);
+
+    (0..segments)
+        .map(|i| i * segment_size)
+        .take_while(|start| *start < total_size)
+        .map(|start| (start, min(start + segment_size, total_size) - 1))
+        .collect()
+}
+
+/// The byte ranges the `.segN` files of a segmented download hold, stored
+/// next to them so a later run only continues segments of the same plan.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct SegmentPlan {
+    total: u64,
+    ranges: Vec<(u64, u64)>,
+    /// The file's `ETag` or `Last-Modified` header, when the server sent
+    /// one.
+    validator: Option<String>,
+}
+
+/// Where the [`SegmentPlan`] of the download into `part_path` is kept.
+fn plan_path(part_path: &Path) -> PathBuf {
+    with_suffix(part_path, ".segments")
+}
+
+/// Get the segments of `part_path` ready for `plan`: keep them when `resume`
+/// is set and they were fetched for the same plan, otherwise remove them
+/// (however many an earlier run left) and record `plan`.
+fn prepare_segments(part_path: &Path, plan: &SegmentPlan, resume: bool) -> Result<(), String> {
+    let path = plan_path(part_path);
+    let recorded = std::fs::read(&path)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice::<SegmentPlan>(&bytes).ok());
+    if resume && recorded.as_ref() == Some(plan) {
+        return Ok(());
+    }
+    for i in 0.. {
+        if std::fs::remove_file(with_suffix(part_path, &format!(".seg{i}"))).is_err() {
+            break;
+        }
+    }
+    let json = serde_json::to_vec(plan).map_err(|e| e.to_string())?;
+    std::fs::write(&path, json).map_err(|e| format!("Failed to write '{}': {e}", path.display()))
+}
+
+/// Stream a single response body into `out_path`, hashing it on the fly. A
+/// non-zero `offset` appends to the bytes already present in `out_path`.
+/// `urls` lists the same file on every mirror; a failing mirror hands over to
+/// the next one at the current offset. Returns the transfer together with the
+/// URL that served the end of the file.
+async fn download_single(
+    client: &reqwest::Client,
+    urls: &[String],
+    out_path: &Path,
+    offset: u64,
+    hasher: &mut Option<StreamHasher>,
+    options: &DownloadOptions,
+) -> Result<(Transfer, String), String> {
+    let url = urls[0].as_str();
+    let mut urls = urls.to_vec();
+    let limiter = options.rate_limit.as_ref();
+    let (mut mirror, mut res) = open_from(client, &mut urls, 0, offset, None).await?;
+
+    let total_size = offset
+        + res
+            .content_length()
+            .ok_or_else(|| format!("Failed to get content length from '{url}'"))?;
+
+    let pb = options.progress.transfer(url, total_size);
+    pb.set_position(offset);
 
     // Download chunks (use chunk() to avoid bytes_stream() feature issues)
-    let mut file = File::create(&out_path)
-        .map_err(|e| format!("Failed to create file '{}': {e}", out_path.display()))?;
-    let mut downloaded: u64 = 0;
+    let mut writer = ChunkWriter::spawn(
+        open_output(out_path, offset > 0, Some(total_size))?,
+        hasher.take(),
+    );
+    let mut downloaded: u64 = offset;
+    let policy = retry::policy();
+    let mut resumes = 0;
+
+    loop {
+        let next = options.cancel.run("download", res.chunk()).await;
+        let chunk = match next.map_err(|e| e.to_string())? {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(err) if retry::is_transient(&err) && resumes + 1 < policy.attempts => {
+                // Connection reset mid-body: continue where we stopped.
+                resumes += 1;
+                policy.backoff(resumes).await;
+                (mirror, res) = open_from(client, &mut urls, mirror, downloaded, None).await?;
+                continue;
+            }
+            Err(err) if mirror + 1 < urls.len() => {
+                pb.println(&format!(
+                    "Download from '{}' failed ({err}); switching to '{}'",
+                    urls[mirror],
+                    urls[mirror + 1]
+                ));
+                (mirror, res) = open_from(client, &mut urls, mirror + 1, downloaded, None).await?;
+                resumes = 0;
+                continue;
+            }
+            Err(err) => return Err(format!("Error while downloading file: {err}")),
+        };
+
+        if let Some(limiter) = limiter {
+            limiter.acquire(chunk.len()).await;
+        }
+        let len = chunk.len() as u64;
+        writer.write(chunk).await?;
+        downloaded += len;
+        pb.set_position(min(downloaded, total_size));
+    }
+    *hasher = writer.finish().await?;
+
+    if downloaded != total_size {
+        return Err(format!(
+            "Incomplete download from '{url}': received {downloaded} of {total_size} bytes"
+        ));
+    }
+
+    Ok((pb, urls.swap_remove(mirror)))
+}
+
+/// Fetch one inclusive byte range of the file at `urls` into `part_path`,
+/// failing over between mirrors like [`download_single`].
+async fn download_segment(
+    client: reqwest::Client,
+    mut urls: Vec<String>,
+    (start, end): (u64, u64),
+    part_path: PathBuf,
+    pb: Transfer,
+    options: DownloadOptions,
+) -> Result<(), String> {
+    // Keep what an interrupted run of the same plan already fetched for
+    // this range.
+    let existing = if options.resume_partial {
+        tokio::fs::metadata(&part_path).await.map_or(0, |m| m.len())
+    } else {
+        0
+    };
+    let length = end - start + 1;
+    let mut written = if existing > 0 && existing <= length {
+        existing
+    } else {
+        0
+    };
+    let mut writer = ChunkWriter::spawn(open_output(&part_path, written > 0, Some(length))?, None);
+    pb.inc(written);
+    if start + written > end {
+        return Ok(());
+    }
+
+    let (mut mirror, mut res) =
+        open_from(&client, &mut urls, 0, start + written, Some(end)).await?;
+    let policy = retry::policy();
+    let mut resumes = 0;
+
+    loop {
+        let next = options.cancel.run("download", res.chunk()).await;
+        let chunk = match next.map_err(|e| e.to_string())? {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(err) if retry::is_transient(&err) && resumes + 1 < policy.attempts => {
+                resumes += 1;
+                policy.backoff(resumes).await;
+                (mirror, res) =
+                    open_from(&client, &mut urls, mirror, start + written, Some(end)).await?;
+                continue;
+            }
+            Err(_) if mirror + 1 < urls.len() => {
+                (mirror, res) =
+                    open_from(&client, &mut urls, mirror + 1, start + written, Some(end)).await?;
+                resumes = 0;
+                continue;
+            }
+            Err(err) => return Err(format!("Error while downloading file: {err}")),
+        };
+
+        if let Some(limiter) = &options.rate_limit {
+            limiter.acquire(chunk.len()).await;
+        }
+        let len = chunk.len() as u64;
+        writer.write(chunk).await?;
+        written += len;
+        pb.inc(len);
+    }
+
+    writer.finish().await.map(|_| ())
+}
+
+/// Download the file at `urls` as concurrent byte ranges into `<out>.segN`
+/// files and reassemble them into `out_path`, hashing the result in order.
+async fn download_segmented(
+    client: &reqwest::Client,
+    urls: &[String],
+    out_path: &Path,
+    (total_size, validator): (u64, Option<String>),
+    hasher: &mut Option<StreamHasher>,
+    options: &DownloadOptions,
+) -> Result<Transfer, String> {
+    let url = urls[0].as_str();
+    let pb = options.progress.transfer(url, total_size);
+    let ranges = segment_ranges(total_size, options.connections);
+    let part_paths: Vec<PathBuf> = (0..ranges.len())
+        .map(|i| with_suffix(out_path, &format!(".seg{i}")))
+        .collect();
+    let plan = SegmentPlan {
+        total: total_size,
+        ranges: ranges.clone(),
+        validator,
+    };
+    let (out, resume) = (out_path.to_path_buf(), options.resume_partial);
+    tokio::task::spawn_blocking(move || prepare_segments(&out, &plan, resume))
+        .await
+        .map_err(|e| format!("Blocking task failed: {e}"))??;
+
+    let tasks: Vec<_> = ranges
+        .iter()
+        .zip(&part_paths)
+        .map(|(range, part_path)| {
+            tokio::spawn(download_segment(
+                client.clone(),
+                urls.to_vec(),
+                *range,
+                part_path.clone(),
+                pb.clone(),
+                options.clone(),
+            ))
+        })
+        .collect();
+
+    let mut result = Ok(());
+    for task in join_all(tasks).await {
+        let outcome = task
+            .map_err(|e| format!("Segment task failed: {e}"))
+            .and_then(|r| r);
+        if result.is_ok() {
+            result = outcome;
+        }
+    }
+
+    // A failed segment leaves every `.segN` in place for the next run to
+    // resume (cancelling removes them); they go once the image is whole.
+    result?;
+    let out = out_path.to_path_buf();
+    with_hasher_blocking(hasher, move |hasher| {
+        reassemble(&part_paths, &out, hasher)?;
+        for part_path in &part_paths {
+            let _ = std::fs::remove_file(part_path);
+        }
+        let _ = std::fs::remove_file(plan_path(&out));
+        Ok(())
+    })
+    .await?;
+
+    let written = tokio::fs::metadata(out_path)
+        .await
+        .map_err(|e| format!("Failed to stat '{}': {e}", out_path.display()))?
+        .len();
+    if written != total_size {
+        return Err(format!(
+            "Incomplete download from '{url}': received {written} of {total_size} bytes"
+        ));
+    }
+
+    Ok(pb)
+}
+
+/// Whether `url` answers a HEAD request successfully.
+async fn is_published(url: &str) -> bool {
+    let client = http::client();
+    retry::send(|| client.head(url))
+        .await
+        .is_ok_and(|res| res.status().is_success())
+}
+
+/// Let the configured external tool fetch `urls` into `part_path`, then hash
+/// the whole file since the tool may have continued it on its own.
+async fn download_external(
+    urls: &[String],
+    part_path: &Path,
+    hasher: &mut Option<StreamHasher>,
+    image: &Image,
+    options: &DownloadOptions,
+) -> Result<Transfer, String> {
+    let torrent_url = format!("{}.torrent", urls[0]);
+    if options.torrent && is_published(&torrent_url).await {
+        external::download_torrent(&torrent_url, part_path, options).await?;
+    } else {
+        external::download(options.downloader, urls, part_path, options).await?;
+    }
+
+    let size = tokio::fs::metadata(part_path)
+        .await
+        .map_err(|e| format!("Failed to stat '{}': {e}", part_path.display()))?
+        .len();
+    *hasher = Some(StreamHasher::new(digest_kind(image)));
+    hash_file_blocking(part_path, hasher).await?;
+
+    let pb = options.progress.transfer(&urls[0], size);
+    pb.inc(size);
+    Ok(pb)
+}
+
+/// Rebuild `url` into `part_path` from the blocks of `seed` that are still
+/// current and fetch only the ranges that changed. Returns `None` when no
+/// `.zsync` control file is published next to the image.
+async fn download_zsync(
+    client: &reqwest::Client,
+    url: &str,
+    seed: &Path,
+    part_path: &Path,
+    options: &DownloadOptions,
+) -> Result<Option<Transfer>, String> {
+    let limiter = options.rate_limit.as_ref();
+    let control_url = format!("{url}.zsync");
+    let res = retry::send(|| client.get(&control_url))
+        .await
+        .map_err(|e| format!("Failed to GET from '{control_url}': {e}"))?;
+    if !res.status().is_success() {
+        return Ok(None);
+    }
+    let body = res
+        .bytes()
+        .await
+        .map_err(|e| format!("Failed to read '{control_url}': {e}"))?;
+    let control = zsync::ControlFile::parse(&body)?;
+
+    // Scanning the seed is CPU bound; keep it off the async workers.
+    let (seed_path, out_path) = (seed.to_path_buf(), part_path.to_path_buf());
+    let (control, plan) = tokio::task::spawn_blocking(move || {
+        let mut out = File::create(&out_path)
+            .map_err(|e| format!("Failed to create file '{}': {e}", out_path.display()))?;
+        out.set_len(control.length())
+            .map_err(|e| format!("Failed to size '{}': {e}", out_path.display()))?;
+        let plan = control.reuse_seed(&seed_path, &mut out)?;
+        Ok::<_, String>((control, plan))
+    })
+    .await
+    .map_err(|e| format!("Seed scan failed: {e}"))??;
+
+    let to_fetch: u64 = plan
+        .missing
+        .iter()
+        .map(|(start, end)| end - start + 1)
+        .sum();
+    let pb = options.progress.transfer(url, to_fetch);
+    pb.println(&format!(
+        "Reusing {} from '{}', fetching {}",
+        human_size(Some(plan.reused)),
+        seed.display(),
+        human_size(Some(to_fetch))
+    ));
+
+    let out = OpenOptions::new()
+        .write(true)
+        .open(part_path)
+        .map_err(|e| format!("Failed to open file '{}': {e}", part_path.display()))?;
+    let mut writer = ChunkWriter::spawn(BufWriter::with_capacity(WRITE_BUFFER_SIZE, out), None);
+    let mut target = url.to_string();
+    for (start, end) in plan.missing {
+        let mut res = resume_request(client, &target, start, Some(end)).await?;
+        pin_redirect(&mut target, &res);
+        writer.seek(start).await?;
+        let mut received: u64 = 0;
+        while let Some(chunk) = options
+            .cancel
+            .run("download", res.chunk())
+            .await
+            .map_err(|e| e.to_string())?
+            .map_err(|e| format!("Error while downloading file: {e}"))?
+        {
+            if let Some(limiter) = limiter {
+                limiter.acquire(chunk.len()).await;
+            }
+            let len = chunk.len() as u64;
+            writer.write(chunk).await?;
+            received += len;
+            pb.inc(len);
+        }
+        if received != end - start + 1 {
+            return Err(format!(
+                "Incomplete range {start}-{end} from '{url}': received {received} bytes"
+            ));
+        }
+    }
+    writer.finish().await?;
 
-    while let Some(chunk) = res
-        .chunk()
+    if let Some(expected) = &control.sha1 {
+        let path = part_path.to_path_buf();
+        let actual = tokio::task::spawn_blocking(move || {
+            let mut sha1 = Sha1::new();
+            let mut file = File::open(&path)
+                .map_err(|e| format!("Failed to open file '{}': {e}", path.display()))?;
+            let mut buf = vec![0u8; 1024 * 1024];
+            loop {
+                let n = file
+                    .read(&mut buf)
+                    .map_err(|e| format!("Error while reading file: {e}"))?;
+                if n == 0 {
+                    break;
+                }
+                sha1.update(&buf[..n]);
+            }
+            Ok::<_, String>(hex::encode(sha1.finalize()))
+        })
         .await
-        .map_err(|e| format!("Error while downloading file: {e}"))?
+        .map_err(|e| format!("Hashing task failed: {e}"))??;
+        if &actual != expected {
+            return Err(format!(
+                "rebuilt file does not match the zsync SHA-1 (expected {expected}, got {actual})"
+            ));
+        }
+    }
+
+    Ok(Some(pb))
+}
+
+/// Concatenate the downloaded segments into `out_path`, feeding the hasher.
+fn reassemble(
+    part_paths: &[PathBuf],
+    out_path: &Path,
+    hasher: &mut Option<StreamHasher>,
+) -> Result<(), String> {
+    let total = part_paths
+        .iter()
+        .filter_map(|p| std::fs::metadata(p).ok())
+        .map(|m| m.len())
+        .sum();
+    let mut out = open_output(out_path, false, Some(total))?;
+    let mut buf = vec![0u8; 1024 * 1024];
+
+    for part_path in part_paths {
+        let mut part = File::open(part_path)
+            .map_err(|e| format!("Failed to open segment '{}': {e}", part_path.display()))?;
+        loop {
+            let n = part
+                .read(&mut buf)
+                .map_err(|e| format!("Error while reading segment: {e}"))?;
+            if n == 0 {
+                break;
+            }
+            out.write_all(&buf[..n])
+                .map_err(|e| format!("Error while writing to file: {e}"))?;
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(&buf[..n]);
+            }
+        }
+    }
+
+    out.flush()
+        .map_err(|e| format!("Error while writing to file: {e}"))
+}
+
+/// Download `image` into `dest_dir`, creating the directory when needed.
+///
+/// With more than one connection configured the file is fetched as concurrent
+/// byte ranges, falling back to a single stream when the server does not
+/// support `Range`. Data is written to `<name>.part` and only renamed to its
+/// final name once the size and, when the image carries one, the checksum
+/// have been validated; failed downloads never leave a plausible-looking
+/// image behind.
+///
+/// With [`DownloadOptions::metalink`] set, a Metalink published next to the
+/// image contributes its mirrors (the fastest responding one first) and its
+/// hash when the index carries none. Long downloads are announced through
+/// [`DownloadOptions::notifier`].
+pub async fn download_file(
+    image: &Image,
+    dest_dir: &Path,
+    options: &DownloadOptions,
+) -> Result<String, CloudImagesError> {
+    let started = Instant::now();
+    let result = fetch_from_sources(image, dest_dir, options).await;
+    if let Some(notifier) = &options.notifier {
+        notifier.download(image, started.elapsed(), &result).await;
+    }
+    result
+}
+
+/// The body of [`download_file`]: the image's published checksum, then the
+/// transfer from the Metalink's mirrors or the ones of `options`.
+async fn fetch_from_sources(
+    image: &Image,
+    dest_dir: &Path,
+    options: &DownloadOptions,
+) -> Result<String, CloudImagesError> {
+    let image = &image.with_source(image.url().to_string(), published_checksum(image).await);
+    if !options.metalink {
+        return fetch_file(image, dest_dir, options).await;
+    }
+    let Some(file) = fetch_metalink(http::client(), image.url()).await else {
+        return fetch_file(image, dest_dir, options).await;
+    };
+
+    let mut sources = rank_by_latency(http::client(), &file.urls).await;
+    let Some(fastest) = sources.first().cloned() else {
+        return fetch_file(image, dest_dir, options).await;
+    };
+    for url in std::iter::once(image.url()).chain(options.mirrors.iter().map(String::as_str)) {
+        if !sources.iter().any(|s| s == url) {
+            sources.push(url.to_string());
+        }
+    }
+
+    let checksum = image.checksum().cloned().or_else(|| file.checksum());
+    let image = image.with_source(fastest, checksum);
+    let options = DownloadOptions {
+        mirrors: sources,
+        ..options.clone()
+    };
+    fetch_file(&image, dest_dir, &options).await
+}
+
+/// Checksum sources tried for an image, in order: the one its index carries,
+/// then per-file `.sha256`/`.sha512` sidecars published next to it.
+async fn published_checksum(image: &Image) -> Option<ImageChecksum> {
+    let sources = [
+        ChecksumSource::Embedded(image.checksum().cloned()),
+        ChecksumSource::Sidecar(".sha256"),
+        ChecksumSource::Sidecar(".sha512"),
+    ];
+    for source in sources {
+        if let Ok(Some(checksum)) = source.resolve(http::client(), image.url()).await {
+            return Some(checksum);
+        }
+    }
+    None
+}
+
+/// The checksum `image` is published with now, read again from its
+/// provider's listing or the sidecars next to it rather than the cache: a
+/// mismatch may mean the artifact was rebuilt since it was resolved. Falls
+/// back to the checksum it was resolved with when none is found.
+async fn republished_checksum(image: &Image) -> Option<ImageChecksum> {
+    let listing = repositories::provider(image.os()).and_then(|p| p.checksum_source(image));
+    let sources = listing.into_iter().chain([
+        ChecksumSource::Sidecar(".sha256"),
+        ChecksumSource::Sidecar(".sha512"),
+    ]);
+    for source in sources {
+        match &source {
+            ChecksumSource::Combined { url, .. } => http_cache::expire(url),
+            ChecksumSource::Sidecar(suffix) => {
+                http_cache::expire(&format!("{}{suffix}", image.url()))
+            }
+            ChecksumSource::Embedded(_) => {}
+        }
+        if let Ok(Some(checksum)) = source.resolve(http::client(), image.url()).await {
+            if image
+                .checksum()
+                .is_some_and(|old| old.kind() == checksum.kind() && *old != checksum)
+            {
+                eprintln!(
+                    "The published checksum of {} changed; verifying against the new one",
+                    image.url()
+                );
+            }
+            return Some(checksum);
+        }
+    }
+    image.checksum().cloned()
+}
+
+/// Fetch and parse `<url>.meta4` (or `<url>.metalink`) and return the entry
+/// for the file behind `url`, if one is published.
+async fn fetch_metalink(client: &reqwest::Client, url: &str) -> Option<metalink::MetalinkFile> {
+    let filename = url.rsplit('/').find(|s| !s.is_empty())?;
+    for suffix in [".meta4", ".metalink"] {
+        let metalink_url = format!("{url}{suffix}");
+        let Ok(res) = retry::send(|| client.get(&metalink_url)).await else {
+            continue;
+        };
+        if !res.status().is_success() {
+            continue;
+        }
+        let Ok(body) = res.text().await else {
+            continue;
+        };
+        match metalink::parse(&body) {
+            Ok(files) => return metalink::find_file(files, filename),
+            Err(err) => eprintln!("Ignoring {metalink_url}: {err}"),
+        }
+    }
+    None
+}
+
+/// Order the first [`METALINK_PROBES`] of `urls` by how fast they answer a
+/// HEAD request; unreachable ones are dropped, the untested rest keeps its
+/// order at the end.
+async fn rank_by_latency(client: &reqwest::Client, urls: &[String]) -> Vec<String> {
+    let probes = urls.iter().take(METALINK_PROBES).map(|url| async move {
+        let started = Instant::now();
+        let res = http::send(client.head(url)).await.ok()?;
+        res.status()
+            .is_success()
+            .then(|| (started.elapsed(), url.clone()))
+    });
+    let mut reachable: Vec<(Duration, String)> =
+        join_all(probes).await.into_iter().flatten().collect();
+    reachable.sort_by_key(|(latency, _)| *latency);
+
+    reachable
+        .into_iter()
+        .map(|(_, url)| url)
+        .chain(urls.iter().skip(METALINK_PROBES).cloned())
+        .collect()
+}
+
+/// Download `image` into `dest_dir`; see [`download_file`].
+async fn fetch_file(
+    image: &Image,
+    dest_dir: &Path,
+    options: &DownloadOptions,
+) -> Result<String, CloudImagesError> {
+    let url = image.url();
+    if options.cancel.is_cancelled() {
+        return Err(cancel::cancelled(&format!("download of {url}")));
+    }
+
+    // HTTP client
+    let client = http::client();
+
+    // Output path: destination directory + filename from the URL (fallback: "download")
+    tokio::fs::create_dir_all(dest_dir).await.map_err(|e| {
+        CloudImagesError::Io(format!(
+            "Failed to create directory '{}': {e}",
+            dest_dir.display()
+        ))
+    })?;
+    let mut out_path = output_path(image, dest_dir)?;
+    let filename = out_path
+        .file_name()
+        .expect("output paths end in a file name")
+        .to_owned();
+    let filename = filename.as_os_str();
+    let staging_dir = options.quarantine_dir.as_deref().unwrap_or(dest_dir);
+    tokio::fs::create_dir_all(staging_dir).await.map_err(|e| {
+        CloudImagesError::Io(format!(
+            "Failed to create directory '{}': {e}",
+            staging_dir.display()
+        ))
+    })?;
+    let part_path = with_suffix(&staging_dir.join(filename), ".part");
+
+    let mut hasher = Some(StreamHasher::new(digest_kind(image)));
+    let mut offset = 0;
+    let mut seed = None;
+
+    // Reading the library index is file I/O; keep it off the async workers.
+    let out_exists = tokio::fs::try_exists(&out_path).await.unwrap_or(false);
+    let stored = match &options.library {
+        Some(library) if !out_exists => {
+            let (library, owned) = (library.clone(), image.clone());
+            run_blocking(move || Ok(library.lookup(&owned))).await?
+        }
+        _ => None,
+    };
+    if let Some(stored) = stored {
+        // A reflink costs no space; a hardlink would let the downloaded image
+        // be written through to the library.
+        let to = out_path.clone();
+        let used = run_blocking(move || {
+            library::clone_file(&stored, &to, CloneMode::Reflink)
+                .or_else(|_| library::clone_file(&stored, &to, CloneMode::Copy))
+                .map_err(CloudImagesError::Io)
+        })
+        .await?;
+        let message = format!("Took {} from the library ({used})", out_path.display());
+        return post_process(image, &out_path, options, message).await;
+    }
+
+    if out_exists {
+        // Hashing a large existing image is slow; keep it off the async
+        // workers.
+        let partial = tokio::fs::metadata(&part_path)
+            .await
+            .is_ok_and(|meta| meta.len() > 0)
+            .then(|| part_path.clone());
+        let (owned, path, existing) = (image.clone(), out_path.clone(), options.existing);
+        let action =
+            run_blocking(move || check_existing(&owned, &path, partial.as_deref(), existing))
+                .await?;
+        match action {
+            ExistingAction::UpToDate => {
+                add_to_library(image, &out_path, None, options).await;
+                let message = format!("{} is already up to date", out_path.display());
+                return post_process(image, &out_path, options, message).await;
+            }
+            ExistingAction::Keep => {
+                return Ok(format!(
+                    "Keeping existing {} (checksum not verified)",
+                    out_path.display()
+                ));
+            }
+            ExistingAction::Overwrite => {
+                // The outdated file usually shares most blocks with the new
+                // build; reuse them when a zsync control file is available.
+                if options.zsync {
+                    seed = Some(out_path.clone());
+                }
+            }
+            ExistingAction::Resume => {
+                // Continue the interrupted run's `.part`; the existing file is
+                // replaced once the download has been verified.
+                hash_file_blocking(&part_path, &mut hasher)
+                    .await
+                    .map_err(CloudImagesError::Io)?;
+                offset = tokio::fs::metadata(&part_path)
+                    .await
+                    .map_err(|e| {
+                        CloudImagesError::Io(format!(
+                            "Failed to stat '{}': {e}",
+                            part_path.display()
+                        ))
+                    })?
+                    .len();
+            }
+        }
+    }
+
+    if http::offline() {
+        return Err(CloudImagesError::Network(format!(
+            "offline: {url} is not in the library"
+        )));
+    }
+
+    // Pick up the partial file of an interrupted run, unless it was cut
+    // short while its segments were being reassembled.
+    if options.resume_partial
+        && offset == 0
+        && seed.is_none()
+        && !tokio::fs::try_exists(with_suffix(&part_path, ".seg0"))
+            .await
+            .unwrap_or(false)
+        && let Ok(meta) = tokio::fs::metadata(&part_path).await
+        && meta.len() > 0
+    {
+        hash_file_blocking(&part_path, &mut hasher)
+            .await
+            .map_err(CloudImagesError::Io)?;
+        offset = meta.len();
+    }
+
+    // Fail now rather than with ENOSPC halfway through the transfer.
+    let size = match image.size() {
+        Some(size) => Some(size),
+        None => content_lengths(&[url]).await.into_iter().next().flatten(),
+    };
+    if let Some(size) = size {
+        let required = required_space(size, offset, &out_path, options);
+        ensure_free_space(staging_dir, required)?;
+        if staging_dir != dest_dir {
+            ensure_free_space(dest_dir, required)?;
+        }
+    }
+
+    let mut rebuilt = None;
+    if let Some(seed) = &seed {
+        match download_zsync(client, url, seed, &part_path, options).await {
+            Ok(Some(pb)) => {
+                hash_file_blocking(&part_path, &mut hasher)
+                    .await
+                    .map_err(CloudImagesError::Io)?;
+                rebuilt = Some(pb);
+            }
+            Ok(None) => {}
+            Err(err) => {
+                eprintln!("Delta download failed ({err}); fetching the whole file");
+                discard(&part_path).await;
+            }
+        }
+    }
+
+    let external = options.downloader != Downloader::Builtin;
+    let segmented_size = if rebuilt.is_none() && !external && options.connections > 1 && offset == 0
+    {
+        probe_range_support(client, url)
+            .await
+            .filter(|(size, ..)| *size >= 2 * MIN_SEGMENT_SIZE)
+    } else {
+        None
+    };
+
+    let mut urls: Vec<String> = std::iter::once(url.to_string())
+        .chain(options.mirrors.iter().filter(|m| *m != url).cloned())
+        .collect();
+    // Every segment has to hit the host the probe was redirected to.
+    let segmented_size = segmented_size.map(|(size, resolved, validator)| {
+        if resolved != url {
+            eprintln!("Redirected {url} -> {resolved}");
+            urls[0] = resolved;
+        }
+        (size, validator)
+    });
+
+    let started = Utc::now();
+    let downloaded = match (rebuilt, segmented_size) {
+        (Some(pb), _) => Ok((pb, urls[0].clone())),
+        (None, _) if external => download_external(&urls, &part_path, &mut hasher, image, options)
+            .await
+            .map(|pb| (pb, urls[0].clone())),
+        (None, Some(plan)) => {
+            download_segmented(client, &urls, &part_path, plan, &mut hasher, options)
+                .await
+                .map(|pb| (pb, urls[0].clone()))
+        }
+        (None, None) => {
+            download_single(client, &urls, &part_path, offset, &mut hasher, options).await
+        }
+    };
+    let (pb, served_by) = match downloaded {
+        Ok(downloaded) => downloaded,
+        Err(_) if options.cancel.is_cancelled() => {
+            remove_partial(&part_path, options.connections).await;
+            return Err(cancel::cancelled(&format!("download of {url}")));
+        }
+        // The `.part` of a transfer that broke off stays for `resume` to
+        // continue; only a mismatching download is thrown away.
+        Err(err) => return Err(CloudImagesError::Network(err)),
+    };
+
+    let digest = hasher.map(StreamHasher::finalize_hex);
+    if let (Some(expected), Some(digest)) = (image.checksum(), &digest)
+        && let Err(err) = verify_digest(expected, digest)
     {
-        file.write_all(&chunk)
-            .map_err(|e| format!("Error while writing to file: {e}"))?;
+        pb.abandon(&format!("Checksum verification failed for {url}"));
+        let mut sources: Vec<String> = std::iter::once(url.to_string())
+            .chain(options.mirrors.iter().filter(|m| *m != url).cloned())
+            .collect();
+        match mismatch_action(options.on_mismatch, url, &err, sources.len() > 1)? {
+            OnMismatch::Ask | OnMismatch::Delete => {
+                discard(&part_path).await;
+                return Err(CloudImagesError::Checksum(format!(
+                    "Download of '{url}' failed verification ({err}); the partial file has been removed"
+                )));
+            }
+            OnMismatch::Keep => {
+                // Next to the `.part`: a quarantined image must not show up
+                // in the destination, corrupt or not.
+                let corrupt = with_suffix(&staging_dir.join(filename), ".corrupt");
+                let (from, to) = (part_path.clone(), corrupt.clone());
+                run_blocking(move || move_file(&from, &to).map_err(CloudImagesError::Io)).await?;
+                return Err(CloudImagesError::Checksum(format!(
+                    "Download of '{url}' failed verification ({err}); kept as '{}'",
+                    corrupt.display()
+                )));
+            }
+            OnMismatch::Redownload => {
+                discard(&part_path).await;
+                // Start over from the next mirror, once; a second mismatch
+                // is reported as usual.
+                if sources.len() > 1 {
+                    sources.rotate_left(1);
+                    eprintln!(
+                        "Checksum mismatch for {url}; downloading again from {}",
+                        sources[0]
+                    );
+                } else {
+                    eprintln!(
+                        "Checksum mismatch for {url}; no other mirror publishes it, \
+                         downloading it again from the same source"
+                    );
+                }
+                let checksum = republished_checksum(image).await;
+                let retry_image = image.with_source(sources[0].clone(), checksum);
+                let retry_options = DownloadOptions {
+                    mirrors: sources,
+                    on_mismatch: OnMismatch::Delete,
+                    resume_partial: false,
+                    ..options.clone()
+                };
+                return Box::pin(fetch_file(&retry_image, dest_dir, &retry_options)).await;
+            }
+        }
+    }
+
+    // Without a published checksum nothing vouches for a quarantined image;
+    // it stays in the staging directory for the user to inspect.
+    let held = options.quarantine_dir.is_some() && image.checksum().is_none();
+    if held {
+        out_path = staging_dir.join(filename);
+    }
+    let (from, to) = (part_path.clone(), out_path.clone());
+    run_blocking(move || move_file(&from, &to).map_err(CloudImagesError::Io)).await?;
+
+    if !held {
+        let sha256 = digest
+            .as_deref()
+            .filter(|_| digest_kind(image) == ChecksumKind::Sha256);
+        add_to_library(image, &out_path, sha256, options).await;
+    }
+
+    if let Some(digest) = digest {
+        let (image, out_path) = (image.clone(), out_path.clone());
+        let (xattrs, provenance) = (options.xattrs, options.provenance);
+        let _ = tokio::task::spawn_blocking(move || {
+            let report = Report::new(
+                &image,
+                digest_kind(&image),
+                digest,
+                image.checksum().is_some(),
+            );
+            if let Err(err) = report::write(&out_path, &report) {
+                eprintln!("Warning: {err}");
+            }
+            if xattrs && let Err(err) = xattr::tag(&out_path, &report) {
+                eprintln!("Warning: {err}");
+            }
+            if provenance {
+                let statement = Statement::new(
+                    &image,
+                    &out_path,
+                    &served_by,
+                    digest_kind(&image),
+                    &report.digest,
+                    started,
+                );
+                if let Err(err) = provenance::write(&out_path, &statement) {
+                    eprintln!("Warning: {err}");
+                }
+            }
+        })
+        .await;
+    }
 
-        let new = min(downloaded + chunk.len() as u64, total_size);
-        downloaded = new;
-        pb.set_position(new);
+    if held {
+        let message = format!(
+            "Downloaded {url} to {}; no checksum is published for it, so it stays in quarantine",
+            out_path.display()
+        );
+        pb.finish(&message);
+        return Ok(message);
     }
 
     let finish_download_message = format!("Downloaded {url} to {}", out_path.display());
 
-    pb.finish_with_message(finish_download_message.clone());
+    pb.finish(&finish_download_message);
 
-    Ok(finish_download_message.clone())
+    post_process(image, &out_path, options, finish_download_message).await
+}
+
+/// Add the finished `out_path` to the configured library. Only images
+/// verified against a published checksum are kept there; failures are
+/// reported without failing the download.
+async fn add_to_library(
+    image: &Image,
+    out_path: &Path,
+    sha256: Option<&str>,
+    options: &DownloadOptions,
+) {
+    let Some(library) = options.library.clone() else {
+        return;
+    };
+    if image.checksum().is_none() {
+        return;
+    }
+    // Hashing and copying a multi-gigabyte image; keep it off the async
+    // workers.
+    let (image, path, sha256) = (
+        image.clone(),
+        out_path.to_path_buf(),
+        sha256.map(str::to_string),
+    );
+    let imported = run_blocking(move || {
+        library
+            .import(&path, &image, sha256.as_deref())
+            .map_err(CloudImagesError::Io)
+    })
+    .await;
+    if let Err(err) = imported {
+        eprintln!("Warning: {err}");
+    }
+}
+
+/// Bytes the download of a `size` byte file into `out_path` will still
+/// write: the remaining transfer, the reassembled copy of a segmented
+/// download and the uncompressed image when decompression follows.
+fn required_space(size: u64, offset: u64, out_path: &Path, options: &DownloadOptions) -> u64 {
+    let mut needed = size.saturating_sub(offset);
+    if options.connections > 1 && offset == 0 && options.downloader == Downloader::Builtin {
+        // Segments are reassembled into a second copy before being removed.
+        needed = needed.saturating_mul(2);
+    }
+    let decompresses =
+        options.decompress || options.convert_to.is_some() || options.resize.is_some();
+    if decompresses && decompress::Compression::from_path(out_path).is_some() {
+        needed = needed.saturating_add(size.saturating_mul(DECOMPRESSION_HEADROOM));
+    }
+    needed
+}
+
+/// Abort with a clear message when the filesystem holding `dir` has less
+/// than `needed` bytes available. Filesystems that cannot be queried pass.
+fn ensure_free_space(dir: &Path, needed: u64) -> Result<(), CloudImagesError> {
+    let Ok(available) = fs4::available_space(dir) else {
+        return Ok(());
+    };
+    if available < needed {
+        return Err(CloudImagesError::Io(format!(
+            "Not enough free space in '{}': {} needed, {} available",
+            dir.display(),
+            human_size(Some(needed)),
+            human_size(Some(available))
+        )));
+    }
+    Ok(())
+}
+
+/// Where [`download`](crate::download) stores `image` below `dest_dir`: the
+/// last segment of its URL (`download` when there is none).
+pub fn output_path(image: &Image, dest_dir: &Path) -> Result<PathBuf, CloudImagesError> {
+    let filename = image
+        .url()
+        .rsplit('/')
+        .find(|s| !s.is_empty())
+        .unwrap_or("download");
+    let filename = sanitize::file_name(filename).map_err(CloudImagesError::Resolution)?;
+    Ok(dest_dir.join(filename))
+}
+
+/// Upload `out_path`, downloaded from `source`, below `prefix`.
+#[cfg(feature = "s3")]
+async fn upload(out_path: &Path, prefix: &Url, source: &Image) -> Result<String, CloudImagesError> {
+    s3::upload::upload(out_path, prefix, source)
+        .await
+        .map_err(CloudImagesError::Network)
+}
+
+#[cfg(not(feature = "s3"))]
+async fn upload(
+    _out_path: &Path,
+    prefix: &Url,
+    _source: &Image,
+) -> Result<String, CloudImagesError> {
+    Err(CloudImagesError::Config(format!(
+        "cannot upload to {prefix}: built without the s3 feature"
+    )))
+}
+
+/// Run the optional post-processing steps on a verified download:
+/// decompression, format conversion, resizing, the NoCloud seed and the
+/// upload of the download itself to S3. The
+/// `qemu-img` steps imply decompression since it cannot read compressed
+/// files. Resizing never touches the download itself so it can still be
+/// verified on later runs; when no other step produced a copy, a
+/// `<name>-<size>` copy is resized. The seed is written next to the final
+/// image.
+async fn post_process(
+    source: &Image,
+    out_path: &Path,
+    options: &DownloadOptions,
+    mut message: String,
+) -> Result<String, CloudImagesError> {
+    let wants_image =
+        options.convert_to.is_some() || options.customize.is_some() || options.resize.is_some();
+    if let Some(prefix) = &options.upload {
+        let uploaded = upload(out_path, prefix, source).await?;
+        message.push('\n');
+        message.push_str(&uploaded);
+    }
+    if !options.decompress && !wants_image && options.seed.is_none() {
+        return Ok(message);
+    }
+
+    let path = out_path.to_path_buf();
+    let convert_to = options.convert_to;
+    let customize = options.customize.clone();
+    let resize = options.resize.clone();
+    let seed = options.seed.clone();
+    let hostname = source.os().to_string();
+    let decompress = options.decompress || wants_image;
+    let steps = tokio::task::spawn_blocking(move || {
+        let mut image = path;
+        let mut steps = Vec::new();
+        if decompress && let Some(decompressed) = decompress::decompress_file(&image)? {
+            steps.push(format!("Decompressed to {}", decompressed.display()));
+            image = decompressed;
+        }
+        if let Some(format) = convert_to
+            && qemu_img::converted_path(&image, format) != image
+        {
+            eprintln!("Converting {} to {format}...", image.display());
+            image = qemu_img::convert(&image, format)?;
+            steps.push(format!("Converted to {}", image.display()));
+        }
+        if let Some(size) = resize {
+            if steps.is_empty() {
+                image = resized_copy(&image, &size)?;
+            }
+            qemu_img::resize(&image, &size)?;
+            steps.push(format!("Resized {} to {size}", image.display()));
+        }
+        if let Some(customization) = customize {
+            eprintln!("Customizing a copy of {}...", image.display());
+            let (custom, digest) = customize::apply(&image, &customization)?;
+            image = custom;
+            steps.push(format!("Customized {} (sha256 {digest})", image.display()));
+        }
+        if let Some(seed) = seed {
+            let output = seed::path_for(&image, seed.format);
+            seed::build(&output, &seed, &hostname)?;
+            steps.push(format!("Wrote the NoCloud seed {}", output.display()));
+        }
+        Ok::<_, String>(steps)
+    })
+    .await
+    .map_err(|e| format!("Post-processing task failed: {e}"))
+    .and_then(|steps| steps)
+    .map_err(CloudImagesError::Io)?;
+
+    for step in steps {
+        message.push('\n');
+        message.push_str(&step);
+    }
+    Ok(message)
+}
+
+/// Copy `image` to `<stem>-<size>.<ext>` next to it so the original stays
+/// byte-identical to the published checksum.
+fn resized_copy(image: &Path, size: &str) -> Result<PathBuf, String> {
+    let stem = image
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "image".to_string());
+    let mut name = format!("{stem}-{}", size.trim_start_matches('+'));
+    if let Some(ext) = image.extension() {
+        name.push('.');
+        name.push_str(&ext.to_string_lossy());
+    }
+    let copy = image.with_file_name(name);
+    std::fs::copy(image, &copy)
+        .map_err(|e| format!("Failed to copy '{}': {e}", image.display()))?;
+    Ok(copy)
+}
+
+/// One image of a batch download.
+#[derive(Debug, Clone)]
+pub struct BatchItem {
+    pub image: Image,
+    pub dest_dir: PathBuf,
+    /// Other locations of the image, see [`DownloadOptions::mirrors`].
+    pub mirrors: Vec<String>,
+}
+
+/// Download several images with at most `jobs` transfers in flight. Every
+/// file reports its own progress next to an aggregate counting finished
+/// images. Results are returned in the order of `items`.
+pub async fn download_batch(
+    items: &[BatchItem],
+    options: &DownloadOptions,
+    jobs: usize,
+) -> Vec<Result<String, CloudImagesError>> {
+    let overall = options.progress.batch(items.len() as u64);
+
+    let mut results: Vec<(usize, Result<String, CloudImagesError>)> =
+        stream::iter(items.iter().enumerate())
+            .map(|(index, item)| {
+                let options = DownloadOptions {
+                    mirrors: item.mirrors.clone(),
+                    ..options.clone()
+                };
+                let overall = overall.clone();
+                async move {
+                    let result = download_file(&item.image, &item.dest_dir, &options).await;
+                    overall.inc(1);
+                    (index, result)
+                }
+            })
+            .buffer_unordered(jobs.max(1))
+            .collect()
+            .await;
+
+    let failed = results.iter().filter(|(_, r)| r.is_err()).count();
+    overall.finish(&if failed == 0 {
+        String::new()
+    } else {
+        format!("({failed} failed)")
+    });
+
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        DECOMPRESSION_HEADROOM, DownloadOptions, ExistingAction, ExistingFile, MIN_SEGMENT_SIZE,
+        SegmentPlan, check_existing, destination_dir, ensure_free_space, open_output,
+        prepare_segments, remove_partial, required_space, segment_ranges, with_suffix,
+    };
+    use crate::cloud::{ChecksumKind, ImageChecksum, sample_image};
+    #[cfg(feature = "server")]
+    use crate::helpers::{
+        checksum::StreamHasher, file_server::FileServer, progress::SilentProgress,
+    };
+    use std::io::Write;
+    use std::path::Path;
+    #[cfg(feature = "server")]
+    use std::sync::Arc;
+
+    #[test]
+    fn nests_by_distro_version_and_arch() {
+        let dir = destination_dir(Path::new("/data"), &sample_image(), false);
+        assert_eq!(dir, Path::new("/data/debian/12/amd64"));
+    }
+
+    #[test]
+    fn flat_keeps_root() {
+        let dir = destination_dir(Path::new("/data"), &sample_image(), true);
+        assert_eq!(dir, Path::new("/data"));
+    }
+
+    #[test]
+    fn segments_cover_the_whole_file() {
+        let total = 10 * MIN_SEGMENT_SIZE + 3;
+        let ranges = segment_ranges(total, 4);
+        assert_eq!(ranges.len(), 4);
+        assert_eq!(ranges.first().unwrap().0, 0);
+        assert_eq!(ranges.last().unwrap().1, total - 1);
+        for pair in ranges.windows(2) {
+            assert_eq!(pair[0].1 + 1, pair[1].0);
+        }
+    }
+
+    #[test]
+    fn small_files_use_fewer_segments() {
+        assert_eq!(
+            segment_ranges(MIN_SEGMENT_SIZE, 8),
+            vec![(0, MIN_SEGMENT_SIZE - 1)]
+        );
+        assert!(segment_ranges(0, 4).is_empty());
+    }
+
+    #[test]
+    fn segments_of_another_plan_are_discarded() {
+        let dir = std::env::temp_dir().join(format!("cid-plan-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let part = dir.join("image.qcow2.part");
+        let plan = |connections, validator: &str| SegmentPlan {
+            total: 10 * MIN_SEGMENT_SIZE,
+            ranges: segment_ranges(10 * MIN_SEGMENT_SIZE, connections),
+            validator: Some(validator.to_string()),
+        };
+        let seg = |i: usize| with_suffix(&part, &format!(".seg{i}"));
+
+        prepare_segments(&part, &plan(4, "etag: \"a\""), true).unwrap();
+        for i in 0..4 {
+            std::fs::write(seg(i), b"partial").unwrap();
+        }
+        prepare_segments(&part, &plan(4, "etag: \"a\""), true).unwrap();
+        assert!((0..4).all(|i| seg(i).exists()));
+
+        // Other ranges, a changed file or a fresh run start over.
+        prepare_segments(&part, &plan(2, "etag: \"a\""), true).unwrap();
+        assert!((0..4).all(|i| !seg(i).exists()));
+        std::fs::write(seg(0), b"partial").unwrap();
+        prepare_segments(&part, &plan(2, "etag: \"b\""), true).unwrap();
+        assert!(!seg(0).exists());
+        std::fs::write(seg(0), b"partial").unwrap();
+        prepare_segments(&part, &plan(2, "etag: \"b\""), false).unwrap();
+        assert!(!seg(0).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn partial_files_keep_the_original_name() {
+        let part = with_suffix(Path::new("/data/debian-12.qcow2"), ".part");
+        assert_eq!(part, Path::new("/data/debian-12.qcow2.part"));
+    }
+
+    #[tokio::test]
+    async fn cancelling_removes_the_partial_files() {
+        let dir = std::env::temp_dir().join(format!("cid-cancel-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let part = dir.join("image.qcow2.part");
+        for path in [
+            &part,
+            &with_suffix(&part, ".seg0"),
+            &with_suffix(&part, ".seg1"),
+        ] {
+            std::fs::write(path, b"partial").unwrap();
+        }
+        remove_partial(&part, 2).await;
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn existing_file_is_checked_against_the_checksum() {
+        let path = std::env::temp_dir().join(format!("cid-existing-{}.img", std::process::id()));
+        std::fs::write(&path, b"hello world").unwrap();
+
+        let with_checksum = |value: &str| {
+            sample_image().with_checksums([ImageChecksum::new(ChecksumKind::Sha256, value)])
+        };
+
+        let matching =
+            with_checksum("b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9");
+        assert_eq!(
+            check_existing(&matching, &path, None, ExistingFile::Overwrite),
+            Ok(ExistingAction::UpToDate)
+        );
+
+        let stale = with_checksum("00");
+        assert_eq!(
+            check_existing(&stale, &path, None, ExistingFile::Overwrite),
+            Ok(ExistingAction::Overwrite)
+        );
+        assert_eq!(
+            check_existing(&stale, &path, None, ExistingFile::Skip),
+            Ok(ExistingAction::Keep)
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn required_space_accounts_for_segments_and_decompression() {
+        let options = DownloadOptions::default();
+        let img = Path::new("noble.img");
+        assert_eq!(required_space(100, 0, img, &options), 100);
+        assert_eq!(required_space(100, 40, img, &options), 60);
+
+        let segmented = DownloadOptions {
+            connections: 4,
+            ..DownloadOptions::default()
+        };
+        assert_eq!(required_space(100, 0, img, &segmented), 200);
+
+        let decompress = DownloadOptions {
+            decompress: true,
+            ..DownloadOptions::default()
+        };
+        assert_eq!(
+            required_space(100, 0, Path::new("Fedora.raw.xz"), &decompress),
+            100 + 100 * DECOMPRESSION_HEADROOM
+        );
+        assert_eq!(required_space(100, 0, img, &decompress), 100);
+    }
+
+    #[test]
+    fn free_space_check_rejects_impossible_sizes() {
+        let dir = std::env::temp_dir();
+        assert!(ensure_free_space(&dir, 1).is_ok());
+        assert!(ensure_free_space(&dir, u64::MAX).is_err());
+    }
+
+    #[test]
+    fn preallocated_output_keeps_the_written_length() {
+        let path = std::env::temp_dir().join(format!("cid-output-{}", std::process::id()));
+        let mut out = open_output(&path, false, Some(1 << 20)).unwrap();
+        out.write_all(b"abc").unwrap();
+        out.flush().unwrap();
+        drop(out);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 3);
+
+        let mut out = open_output(&path, true, Some(1 << 20)).unwrap();
+        out.write_all(b"def").unwrap();
+        out.flush().unwrap();
+        drop(out);
+        assert_eq!(std::fs::read(&path).unwrap(), b"abcdef");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // The default `#[tokio::test]` runtime is current-thread, like many
+    // embedders': nothing on the download path may use `block_in_place`.
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn downloads_on_a_current_thread_runtime() {
+        let root = std::env::temp_dir().join(format!("cid-download-{}", std::process::id()));
+        let (served, dest) = (root.join("served"), root.join("dest"));
+        std::fs::create_dir_all(&served).unwrap();
+        std::fs::write(served.join("image.qcow2"), b"cloud image").unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(FileServer::new(served).serve(listener));
+
+        let mut hasher = StreamHasher::new(ChecksumKind::Sha256);
+        hasher.update(b"cloud image");
+        let image = sample_image().with_source(
+            format!("http://{addr}/image.qcow2"),
+            Some(ImageChecksum::new(
+                ChecksumKind::Sha256,
+                hasher.finalize_hex(),
+            )),
+        );
+        let options = DownloadOptions {
+            progress: Arc::new(SilentProgress),
+            ..DownloadOptions::default()
+        };
+        crate::download(&image, &dest, &options).await.unwrap();
+
+        assert_eq!(
+            std::fs::read(dest.join("image.qcow2")).unwrap(),
+            b"cloud image"
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 }
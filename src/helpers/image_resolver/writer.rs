@@ -0,0 +1,119 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::thread::{self, JoinHandle};
+
+use bytes::Bytes;
+use tokio::sync::mpsc;
+
+use crate::helpers::checksum::StreamHasher;
+
+/// Chunks buffered between the network and the disk before the download
+/// waits for the writer.
+const QUEUED_CHUNKS: usize = 64;
+
+enum Op {
+    Data(Bytes),
+    Seek(u64),
+}
+
+/// Writes downloaded chunks on a dedicated thread, feeding the hasher there
+/// as well, so a slow disk never stalls the async workers. The channel is
+/// bounded: a disk that cannot keep up slows the download down instead of
+/// filling memory.
+pub(super) struct ChunkWriter {
+    tx: Option<mpsc::Sender<Op>>,
+    handle: Option<JoinHandle<Result<Option<StreamHasher>, String>>>,
+}
+
+impl ChunkWriter {
+    pub(super) fn spawn(mut file: BufWriter<File>, mut hasher: Option<StreamHasher>) -> Self {
+        let (tx, mut rx) = mpsc::channel(QUEUED_CHUNKS);
+        let handle = thread::spawn(move || {
+            while let Some(op) = rx.blocking_recv() {
+                match op {
+                    Op::Data(chunk) => {
+                        file.write_all(&chunk)
+                            .map_err(|e| format!("Error while writing to file: {e}"))?;
+                        if let Some(hasher) = hasher.as_mut() {
+                            hasher.update(&chunk);
+                        }
+                    }
+                    Op::Seek(position) => {
+                        file.seek(SeekFrom::Start(position))
+                            .map_err(|e| format!("Error while writing to file: {e}"))?;
+                    }
+                }
+            }
+            file.flush()
+                .map_err(|e| format!("Error while writing to file: {e}"))?;
+            Ok(hasher)
+        });
+        Self {
+            tx: Some(tx),
+            handle: Some(handle),
+        }
+    }
+
+    async fn send(&mut self, op: Op) -> Result<(), String> {
+        let Some(tx) = &self.tx else {
+            return Err("Writer already finished".to_string());
+        };
+        if tx.send(op).await.is_err() {
+            // The thread only hangs up after a failed write; report that.
+            self.finish().await?;
+            return Err("Writer stopped unexpectedly".to_string());
+        }
+        Ok(())
+    }
+
+    /// Queue `chunk` for writing at the current position.
+    pub(super) async fn write(&mut self, chunk: Bytes) -> Result<(), String> {
+        self.send(Op::Data(chunk)).await
+    }
+
+    /// Move the write position to `position` bytes from the start.
+    pub(super) async fn seek(&mut self, position: u64) -> Result<(), String> {
+        self.send(Op::Seek(position)).await
+    }
+
+    /// Flush everything queued and hand the hasher back.
+    pub(super) async fn finish(&mut self) -> Result<Option<StreamHasher>, String> {
+        self.tx.take();
+        let Some(handle) = self.handle.take() else {
+            return Err("Writer already finished".to_string());
+        };
+        tokio::task::spawn_blocking(move || handle.join())
+            .await
+            .map_err(|e| format!("Writer task failed: {e}"))?
+            .map_err(|_| "Writer thread panicked".to_string())?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ChunkWriter;
+    use crate::cloud::ChecksumKind;
+    use crate::helpers::checksum::StreamHasher;
+    use bytes::Bytes;
+    use std::fs::File;
+    use std::io::BufWriter;
+
+    #[tokio::test]
+    async fn writes_in_order_and_returns_the_hasher() {
+        let path = std::env::temp_dir().join(format!("cid-writer-{}", std::process::id()));
+        let file = BufWriter::new(File::create(&path).unwrap());
+        let mut writer = ChunkWriter::spawn(file, Some(StreamHasher::new(ChecksumKind::Sha256)));
+        writer.write(Bytes::from_static(b"hello ")).await.unwrap();
+        writer.write(Bytes::from_static(b"world")).await.unwrap();
+        writer.seek(0).await.unwrap();
+        writer.write(Bytes::from_static(b"J")).await.unwrap();
+        let hasher = writer.finish().await.unwrap().unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"Jello world");
+        // The hasher sees the stream as received, not the file after seeks.
+        let mut expected = StreamHasher::new(ChecksumKind::Sha256);
+        expected.update(b"hello worldJ");
+        assert_eq!(hasher.finalize_hex(), expected.finalize_hex());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,156 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use chrono::Utc;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+use crate::cloud::{Arch, Image};
+use crate::helpers::find_program;
+
+/// Client programs speaking to an Incus or LXD daemon, preferred first.
+const CLIENTS: &[&str] = &["incus", "lxc"];
+
+/// Locate `client`, or the first of `incus` and `lxc` on `PATH`.
+pub fn locate(client: Option<&str>) -> Result<PathBuf, String> {
+    let candidates = client.map_or(CLIENTS.to_vec(), |client| vec![client]);
+    candidates
+        .iter()
+        .find_map(|name| find_program(name))
+        .ok_or_else(|| {
+            format!(
+                "{} was not found on PATH; install Incus (or LXD) to import images",
+                candidates.join(" or ")
+            )
+        })
+}
+
+/// Default alias of `image`, e.g. `ubuntu/noble/amd64`.
+pub fn alias(image: &Image) -> String {
+    format!("{}/{}/{}", image.os(), image.name(), image.arch_name())
+}
+
+fn architecture(arch: Arch) -> &'static str {
+    match arch {
+        Arch::Amd64 => "x86_64",
+        Arch::Arm64 => "aarch64",
+        Arch::Armhf => "armv7l",
+        Arch::Ppc64le => "ppc64le",
+        Arch::S390x => "s390x",
+        Arch::Riscv64 => "riscv64",
+    }
+}
+
+/// Quote `value` as a YAML string.
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// `metadata.yaml` of a VM image built from `image`. Without `secureboot`
+/// the image asks for Secure Boot to be disabled, as most cloud images are
+/// not signed for the firmware Incus ships.
+pub fn metadata_yaml(image: &Image, secureboot: bool) -> String {
+    let description = format!(
+        "{} {} ({}) {} {} {}",
+        image.os(),
+        image.distro_version(),
+        image.name(),
+        image.arch_name(),
+        image.variant(),
+        image.version()
+    );
+    let properties = [
+        ("architecture", image.arch_name()),
+        ("description", description.as_str()),
+        ("os", image.os()),
+        ("release", image.name()),
+        ("serial", image.version()),
+        ("type", "disk-kvm.img"),
+        ("variant", image.variant().as_str()),
+        ("version", image.distro_version()),
+    ];
+    let mut yaml = format!(
+        "architecture: {}\ncreation_date: {}\nproperties:\n",
+        architecture(image.arch()),
+        Utc::now().timestamp()
+    );
+    for (key, value) in properties {
+        yaml.push_str(&format!("  {key}: {}\n", quote(value)));
+    }
+    if !secureboot {
+        yaml.push_str("requirements:\n  secureboot: \"false\"\n");
+    }
+    yaml
+}
+
+/// Write the metadata tarball (`metadata.yaml` only) for `image` to `path`.
+pub fn write_metadata(path: &Path, image: &Image, secureboot: bool) -> Result<(), String> {
+    let file =
+        File::create(path).map_err(|e| format!("Failed to create '{}': {e}", path.display()))?;
+    let yaml = metadata_yaml(image, secureboot);
+    let mut header = tar::Header::new_gnu();
+    header.set_size(yaml.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp().max(0) as u64);
+    let mut tar = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+    tar.append_data(&mut header, "metadata.yaml", yaml.as_bytes())
+        .and_then(|_| tar.into_inner()?.finish())
+        .map(|_| ())
+        .map_err(|e| format!("Failed to write '{}': {e}", path.display()))
+}
+
+/// Import the split image `metadata` + `disk` (qcow2) with `client`, under
+/// every alias in `aliases`, optionally into `remote`.
+pub fn import(
+    client: &Path,
+    metadata: &Path,
+    disk: &Path,
+    aliases: &[String],
+    remote: Option<&str>,
+) -> Result<(), String> {
+    let mut command = Command::new(client);
+    command.args([
+        "image".as_ref(),
+        "import".as_ref(),
+        metadata.as_os_str(),
+        disk.as_os_str(),
+    ]);
+    if let Some(remote) = remote {
+        command.arg(format!("{remote}:"));
+    }
+    for alias in aliases {
+        command.args(["--alias", alias]);
+    }
+    let output = command
+        .output()
+        .map_err(|e| format!("Failed to run '{}': {e}", client.display()))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} image import failed ({}): {}",
+            client.display(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{alias, metadata_yaml};
+    use crate::cloud::{Arch, sample_image};
+
+    #[test]
+    fn describes_a_vm_image() {
+        let image = sample_image().with_arch(Arch::Arm64);
+        assert_eq!(alias(&image), "debian/bookworm/arm64");
+        let yaml = metadata_yaml(&image, false);
+        assert!(yaml.starts_with("architecture: aarch64\ncreation_date: "));
+        assert!(yaml.contains("  os: \"debian\"\n"));
+        assert!(yaml.contains("  serial: \"20250210-2019\"\n"));
+        assert!(yaml.ends_with("requirements:\n  secureboot: \"false\"\n"));
+        assert!(!metadata_yaml(&image, true).contains("requirements"));
+    }
+}
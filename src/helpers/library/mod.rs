@@ -0,0 +1,599 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::cloud::{ChecksumKind, Image};
+use crate::helpers::checksum::{self, StreamHasher};
+use crate::helpers::retention::{self, Candidate, GcSummary, Retention};
+use crate::helpers::{paths, sanitize};
+
+const LIBRARY_SUBDIR: &str = "library";
+const STORE_SUBDIR: &str = "store";
+const IMAGES_SUBDIR: &str = "images";
+const INDEX_FILE: &str = "index.json";
+const LOCK_FILE: &str = "index.json.lock";
+
+/// Managed image store. Every image is kept once under `store/<sha256>` of
+/// its contents, however many builds or aliases resolve to it, and is
+/// reachable through a readable symlink
+/// `images/<os>/<distro version>/<build>/<file>`; `index.json` tells which
+/// image each blob came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Library {
+    root: PathBuf,
+}
+
+/// An image held by the library.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LibraryEntry {
+    /// SHA-256 of the contents, naming the blob in the store.
+    pub digest: String,
+    pub image: Image,
+    /// Human-friendly symlink to the blob.
+    pub link: PathBuf,
+    /// When the image was added, RFC 3339.
+    pub added: String,
+}
+
+impl LibraryEntry {
+    /// The image's stable id, used to refer to it on the command line.
+    pub fn id(&self) -> String {
+        self.image.id()
+    }
+}
+
+/// How an image leaves the library for a project directory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum CloneMode {
+    /// The first of reflink, hardlink and copy the filesystem allows.
+    #[default]
+    Auto,
+    /// Copy-on-write clone sharing the stored blocks (`FICLONE`, Linux on
+    /// Btrfs, XFS and similar).
+    Reflink,
+    /// Second name for the stored file. It is read-only, as writing to it
+    /// would alter the library.
+    Hardlink,
+    /// Full, independent copy.
+    Copy,
+}
+
+impl fmt::Display for CloneMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CloneMode::Auto => "auto",
+            CloneMode::Reflink => "reflink",
+            CloneMode::Hardlink => "hardlink",
+            CloneMode::Copy => "copy",
+        })
+    }
+}
+
+impl Library {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    /// `$XDG_DATA_HOME/cloud-images-downloader/library` on Linux.
+    pub fn default_root() -> Option<PathBuf> {
+        paths::data_dir().map(|dir| dir.join(LIBRARY_SUBDIR))
+    }
+
+    /// The library at [`default_root`](Self::default_root).
+    pub fn open_default() -> Result<Self, String> {
+        Self::default_root()
+            .map(Self::new)
+            .ok_or_else(|| "No data directory to keep the image library in".to_string())
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Tree of readable symlinks to the stored images,
+    /// `<os>/<distro version>/<build>/<file>`.
+    pub fn images_dir(&self) -> PathBuf {
+        self.root.join(IMAGES_SUBDIR)
+    }
+
+    /// Stored file with the SHA-256 `digest`.
+    pub fn blob(&self, digest: &str) -> PathBuf {
+        self.root.join(STORE_SUBDIR).join(digest)
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.root.join(INDEX_FILE)
+    }
+
+    /// Every image in the library, oldest first.
+    pub fn entries(&self) -> Result<Vec<LibraryEntry>, String> {
+        let path = self.index_path();
+        match fs::read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data)
+                .map_err(|e| format!("Failed to parse '{}': {e}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(format!("Failed to read '{}': {e}", path.display())),
+        }
+    }
+
+    /// Take the library's exclusive lock, held until the returned file is
+    /// dropped. Every change to the index, the links and the store happens
+    /// under it, so concurrent imports (a manifest's downloads, another
+    /// invocation) never drop each other's entries and `gc` never deletes a
+    /// blob an import is about to record.
+    fn lock(&self) -> Result<File, String> {
+        fs::create_dir_all(&self.root)
+            .map_err(|e| format!("Failed to create '{}': {e}", self.root.display()))?;
+        let path = self.root.join(LOCK_FILE);
+        let lock =
+            File::create(&path).map_err(|e| format!("Failed to open '{}': {e}", path.display()))?;
+        lock.lock()
+            .map_err(|e| format!("Failed to lock '{}': {e}", path.display()))?;
+        Ok(lock)
+    }
+
+    fn save(&self, entries: &[LibraryEntry]) -> Result<(), String> {
+        let path = self.index_path();
+        let json = serde_json::to_string_pretty(entries)
+            .map_err(|e| format!("Failed to serialise the library index: {e}"))?;
+        let tmp = temp_path(&path);
+        fs::write(&tmp, json).map_err(|e| format!("Failed to write '{}': {e}", tmp.display()))?;
+        fs::rename(&tmp, &path).map_err(|e| {
+            let _ = fs::remove_file(&tmp);
+            format!("Failed to write '{}': {e}", path.display())
+        })
+    }
+
+    /// A stored copy of `image`: a blob whose SHA-256 or whose original
+    /// image's published checksum equals the one `image` carries. Builds
+    /// that are byte for byte the same (a dated build and the `latest`
+    /// alias, say) share it; without a checksum nothing can be matched.
+    pub fn lookup(&self, image: &Image) -> Option<PathBuf> {
+        let expected = image.checksum()?;
+        let same = |kind: ChecksumKind, value: &str| {
+            kind == expected.kind() && value.eq_ignore_ascii_case(expected.value())
+        };
+        self.entries()
+            .ok()?
+            .into_iter()
+            .find(|entry| {
+                same(ChecksumKind::Sha256, &entry.digest)
+                    || entry
+                        .image
+                        .checksums()
+                        .iter()
+                        .any(|c| same(c.kind(), c.value()))
+            })
+            .map(|entry| self.blob(&entry.digest))
+            .filter(|blob| blob.is_file())
+    }
+
+    /// Add the verified `file` holding `image` to the library. `sha256` is
+    /// its digest when already known; identical contents share one blob.
+    pub fn import(
+        &self,
+        file: &Path,
+        image: &Image,
+        sha256: Option<&str>,
+    ) -> Result<LibraryEntry, String> {
+        let digest = match sha256 {
+            Some(digest) => digest.to_ascii_lowercase(),
+            None => {
+                let mut hasher = StreamHasher::new(ChecksumKind::Sha256);
+                checksum::hash_file(file, &mut hasher)?;
+                hasher.finalize_hex()
+            }
+        };
+
+        // Copy before taking the lock: it can take minutes.
+        let blob = self.blob(&digest);
+        let mut copied = if blob.is_file() {
+            None
+        } else {
+            Some(self.copy_into_store(file)?)
+        };
+        let _lock = self.lock()?;
+        // `gc` may have removed the blob meanwhile.
+        if copied.is_none() && !blob.is_file() {
+            copied = Some(self.copy_into_store(file)?);
+        }
+        if let Some(tmp) = copied {
+            fs::rename(&tmp, &blob).map_err(|e| {
+                let _ = fs::remove_file(&tmp);
+                format!("Failed to store '{}': {e}", blob.display())
+            })?;
+        }
+
+        let link = self.link_path(image)?;
+        link_blob(&blob, &link)?;
+
+        let entry = LibraryEntry {
+            digest,
+            image: image.clone(),
+            link,
+            added: Utc::now().to_rfc3339(),
+        };
+        let mut entries = self.entries()?;
+        entries.retain(|e| e.id() != entry.id());
+        entries.push(entry.clone());
+        self.save(&entries)?;
+        Ok(entry)
+    }
+
+    /// Copy `file` to a temporary file of its own in the store, read-only,
+    /// ready to be renamed to its blob. Copy rather than link: the
+    /// downloaded file may be booted and written to, which must not alter
+    /// the stored image.
+    fn copy_into_store(&self, file: &Path) -> Result<PathBuf, String> {
+        let store = self.root.join(STORE_SUBDIR);
+        fs::create_dir_all(&store)
+            .map_err(|e| format!("Failed to create '{}': {e}", store.display()))?;
+        let tmp = temp_path(&store.join("blob"));
+        fs::copy(file, &tmp).map_err(|e| {
+            let _ = fs::remove_file(&tmp);
+            format!("Failed to copy '{}' into the library: {e}", file.display())
+        })?;
+        // Hardlinked exports share the stored file; keep it read-only so
+        // they cannot be written through.
+        if let Ok(meta) = fs::metadata(&tmp) {
+            let mut permissions = meta.permissions();
+            permissions.set_readonly(true);
+            let _ = fs::set_permissions(&tmp, permissions);
+        }
+        Ok(tmp)
+    }
+
+    fn link_path(&self, image: &Image) -> Result<PathBuf, String> {
+        let file = image
+            .url()
+            .rsplit('/')
+            .find(|s| !s.is_empty())
+            .unwrap_or("image");
+        let mut link = self.root.join(IMAGES_SUBDIR);
+        for part in [image.os(), image.distro_version(), image.version(), file] {
+            link.push(sanitize::file_name(part)?);
+        }
+        Ok(link)
+    }
+
+    /// The entries whose id starts with `prefix`; more than one is only an
+    /// error for callers that need a single image.
+    pub fn matching(&self, prefix: &str) -> Result<Vec<LibraryEntry>, String> {
+        let prefix = prefix.to_ascii_lowercase();
+        let found: Vec<LibraryEntry> = self
+            .entries()?
+            .into_iter()
+            .filter(|e| !prefix.is_empty() && e.id().starts_with(&prefix))
+            .collect();
+        if found.is_empty() {
+            return Err(format!("No image in the library matches '{prefix}'"));
+        }
+        Ok(found)
+    }
+
+    /// The single entry whose id starts with `prefix`.
+    pub fn entry(&self, prefix: &str) -> Result<LibraryEntry, String> {
+        let mut found = self.matching(prefix)?;
+        if found.len() > 1 {
+            return Err(format!(
+                "'{prefix}' matches {} images; give more of the id",
+                found.len()
+            ));
+        }
+        Ok(found.remove(0))
+    }
+
+    /// Path of the single image whose id starts with `prefix`.
+    pub fn path(&self, prefix: &str) -> Result<PathBuf, String> {
+        Ok(self.blob(&self.entry(prefix)?.digest))
+    }
+
+    /// Place the image whose id starts with `prefix` at `to` (or under its
+    /// own file name when `to` is a directory) without copying it where the
+    /// filesystem allows. Returns the path written and how.
+    pub fn export(
+        &self,
+        prefix: &str,
+        to: &Path,
+        mode: CloneMode,
+    ) -> Result<(PathBuf, CloneMode), String> {
+        let entry = self.entry(prefix)?;
+        let target = match entry.link.file_name() {
+            Some(name) if to.is_dir() => to.join(name),
+            _ => to.to_path_buf(),
+        };
+        if let Some(parent) = target.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("Failed to create '{}': {e}", parent.display()))?;
+        }
+        let used = clone_file(&self.blob(&entry.digest), &target, mode)?;
+        Ok((target, used))
+    }
+
+    /// Drop the entries whose id starts with `prefix` together with their
+    /// links. Their blobs stay until [`gc`](Self::gc) finds them unused.
+    pub fn remove(&self, prefix: &str) -> Result<Vec<LibraryEntry>, String> {
+        let _lock = self.lock()?;
+        let removed = self.matching(prefix)?;
+        let mut entries = self.entries()?;
+        entries.retain(|e| !removed.contains(e));
+        self.save(&entries)?;
+        for entry in &removed {
+            if entries.iter().all(|e| e.link != entry.link) {
+                let _ = fs::remove_file(&entry.link);
+                prune_empty_dirs(&entry.link, &self.root.join(IMAGES_SUBDIR));
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Drop the entries `policy` evicts (`keep_last` counts per release and
+    /// architecture) and those whose blob went missing, then delete the
+    /// blobs no entry refers to.
+    pub fn gc(&self, policy: &Retention) -> Result<GcSummary, String> {
+        let _lock = self.lock()?;
+        let mut entries = self.entries()?;
+        let before = entries.len();
+        entries.retain(|e| self.blob(&e.digest).is_file());
+        let candidates: Vec<Candidate> = entries
+            .iter()
+            .map(|e| Candidate {
+                group: Some(format!(
+                    "{}/{}/{}/{}",
+                    e.image.os(),
+                    e.image.distro_version(),
+                    e.image.arch_name(),
+                    e.image.variant()
+                )),
+                stored: DateTime::parse_from_rfc3339(&e.added)
+                    .map(|added| added.with_timezone(&Utc))
+                    .unwrap_or_default(),
+                size: fs::metadata(self.blob(&e.digest))
+                    .map(|m| m.len())
+                    .unwrap_or(0),
+                file: e.digest.clone(),
+            })
+            .collect();
+        let evicted = retention::plan(&candidates, policy, Utc::now());
+        let mut summary = GcSummary {
+            entries: evicted.len(),
+            ..GcSummary::default()
+        };
+        let (dropped, kept): (Vec<_>, Vec<_>) = entries
+            .into_iter()
+            .enumerate()
+            .partition(|(i, _)| evicted.contains(i));
+        let entries: Vec<LibraryEntry> = kept.into_iter().map(|(_, e)| e).collect();
+        if entries.len() != before {
+            self.save(&entries)?;
+        }
+        for (_, entry) in &dropped {
+            if entries.iter().all(|e| e.link != entry.link) {
+                let _ = fs::remove_file(&entry.link);
+                prune_empty_dirs(&entry.link, &self.root.join(IMAGES_SUBDIR));
+            }
+        }
+
+        let store = self.root.join(STORE_SUBDIR);
+        let Ok(blobs) = fs::read_dir(&store) else {
+            return Ok(summary);
+        };
+        for blob in blobs.flatten() {
+            let name = blob.file_name().to_string_lossy().into_owned();
+            // Temporary files are the copies of imports still running.
+            if name.ends_with(".tmp") || entries.iter().any(|e| e.digest == name) {
+                continue;
+            }
+            let size = blob.metadata().map(|m| m.len()).unwrap_or(0);
+            fs::remove_file(blob.path())
+                .map_err(|e| format!("Failed to remove '{}': {e}", blob.path().display()))?;
+            summary.files += 1;
+            summary.bytes += size;
+        }
+        Ok(summary)
+    }
+}
+
+/// A name of its own next to `path` to write it under before renaming it
+/// into place, `<name>.<random>.tmp`.
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}.tmp", fastrand::u64(..)));
+    PathBuf::from(name)
+}
+
+/// Create `to` from `from` the way `mode` asks, or with the first way that
+/// works for [`CloneMode::Auto`]. `to` must not exist yet.
+pub fn clone_file(from: &Path, to: &Path, mode: CloneMode) -> Result<CloneMode, String> {
+    if to.exists() {
+        return Err(format!("'{}' already exists", to.display()));
+    }
+    let attempts: &[CloneMode] = match mode {
+        CloneMode::Auto => &[CloneMode::Reflink, CloneMode::Hardlink, CloneMode::Copy],
+        CloneMode::Reflink => &[CloneMode::Reflink],
+        CloneMode::Hardlink => &[CloneMode::Hardlink],
+        CloneMode::Copy => &[CloneMode::Copy],
+    };
+    let mut last = None;
+    for &attempt in attempts {
+        let result = match attempt {
+            CloneMode::Reflink => reflink(from, to),
+            CloneMode::Hardlink => fs::hard_link(from, to),
+            _ => copy_new(from, to),
+        };
+        match result {
+            Ok(()) => return Ok(attempt),
+            Err(err) => last = Some((attempt, err)),
+        }
+    }
+    let (attempt, err) = last.expect("at least one attempt");
+    Err(format!(
+        "Failed to {attempt} '{}' to '{}': {err}",
+        from.display(),
+        to.display()
+    ))
+}
+
+/// Clone `from` into the new file `to` sharing its blocks.
+#[cfg(target_os = "linux")]
+fn reflink(from: &Path, to: &Path) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let src = File::open(from)?;
+    let dst = File::create_new(to)?;
+    // SAFETY: both descriptors belong to files that stay open for the call.
+    let rc = unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) };
+    if rc == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    drop(dst);
+    let _ = fs::remove_file(to);
+    Err(err)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_from: &Path, _to: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Copy `from` into the new, writable file `to`.
+fn copy_new(from: &Path, to: &Path) -> io::Result<()> {
+    let mut src = File::open(from)?;
+    let mut dst = File::create_new(to)?;
+    if let Err(err) = io::copy(&mut src, &mut dst) {
+        drop(dst);
+        let _ = fs::remove_file(to);
+        return Err(err);
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn link_blob(blob: &Path, link: &Path) -> Result<(), String> {
+    if let Some(parent) = link.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create '{}': {e}", parent.display()))?;
+    }
+    let _ = fs::remove_file(link);
+    std::os::unix::fs::symlink(blob, link)
+        .map_err(|e| format!("Failed to link '{}': {e}", link.display()))
+}
+
+/// Without symlinks the index is the only way to the blobs.
+#[cfg(not(unix))]
+fn link_blob(_blob: &Path, _link: &Path) -> Result<(), String> {
+    Ok(())
+}
+
+/// Remove the directories left empty above `path`, up to `top`.
+fn prune_empty_dirs(path: &Path, top: &Path) {
+    let mut dir = path.parent();
+    while let Some(current) = dir {
+        if current == top || !current.starts_with(top) || fs::remove_dir(current).is_err() {
+            break;
+        }
+        dir = current.parent();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CloneMode, Library};
+    use crate::cloud::{ChecksumKind, Image, ImageChecksum, sample_image};
+    use crate::helpers::retention::Retention;
+
+    fn image(version: &str, sha512: &str) -> Image {
+        sample_image().with_version(version).with_source(
+            format!("https://example.invalid/{version}/debian-12-genericcloud-amd64.qcow2"),
+            Some(ImageChecksum::new(ChecksumKind::Sha512, sha512)),
+        )
+    }
+
+    #[test]
+    fn dedups_blobs_and_collects_garbage() {
+        let root = std::env::temp_dir().join(format!("cid-library-{}", std::process::id()));
+        let library = Library::new(root.join("library"));
+        let file = root.join("download.qcow2");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(&file, b"same bytes").unwrap();
+
+        let first = library
+            .import(&file, &image("20250210-2019", "aa"), None)
+            .unwrap();
+        let second = library.import(&file, &image("latest", "aa"), None).unwrap();
+        assert_eq!(first.digest, second.digest);
+        assert_eq!(library.entries().unwrap().len(), 2);
+        assert!(library.lookup(&image("20250210-2019", "AA")).is_some());
+        assert!(library.lookup(&image("latest", "bb")).is_none());
+        #[cfg(unix)]
+        assert_eq!(
+            std::fs::read(&first.link).unwrap(),
+            b"same bytes",
+            "the friendly link resolves to the blob"
+        );
+
+        let project = root.join("vm");
+        std::fs::create_dir_all(&project).unwrap();
+        let (disk, _) = library
+            .export(&first.id(), &project, CloneMode::Auto)
+            .unwrap();
+        assert_eq!(disk, project.join("debian-12-genericcloud-amd64.qcow2"));
+        assert_eq!(std::fs::read(&disk).unwrap(), b"same bytes");
+        let copy = project.join("disk.qcow2");
+        let (_, used) = library.export(&first.id(), &copy, CloneMode::Copy).unwrap();
+        assert_eq!(used, CloneMode::Copy);
+        assert!(!std::fs::metadata(&copy).unwrap().permissions().readonly());
+        assert!(library.export(&first.id(), &copy, CloneMode::Copy).is_err());
+
+        let keep_one = Retention {
+            keep_last: Some(1),
+            ..Retention::default()
+        };
+        let summary = library.gc(&keep_one).unwrap();
+        assert_eq!(
+            (summary.entries, summary.files),
+            (1, 0),
+            "the blob is still used"
+        );
+        assert_eq!(library.entries().unwrap()[0].id(), second.id());
+        library.remove(&second.id()[..8]).unwrap();
+        let summary = library.gc(&Retention::default()).unwrap();
+        assert_eq!((summary.files, summary.bytes), (1, 10));
+        assert!(library.path(&first.id()).is_err());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn concurrent_imports_keep_every_entry() {
+        let root = std::env::temp_dir().join(format!("cid-library-mt-{}", std::process::id()));
+        let library = Library::new(root.join("library"));
+        let file = root.join("download.qcow2");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(&file, b"same bytes").unwrap();
+
+        std::thread::scope(|scope| {
+            for build in 0..8 {
+                let (library, file) = (&library, &file);
+                scope.spawn(move || {
+                    library
+                        .import(file, &image(&format!("2025021{build}"), "aa"), None)
+                        .unwrap()
+                });
+            }
+        });
+        assert_eq!(library.entries().unwrap().len(), 8);
+        let stored: Vec<_> = std::fs::read_dir(library.root().join("store"))
+            .unwrap()
+            .collect();
+        assert_eq!(stored.len(), 1, "one blob and no leftover copies");
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
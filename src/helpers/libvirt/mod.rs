@@ -0,0 +1,326 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::cloud::{Arch, Image};
+use crate::helpers::seed::SeedFormat;
+use crate::helpers::{find_program, paths, qemu_img};
+
+/// Locate `virsh` on `PATH`.
+pub fn locate() -> Result<PathBuf, String> {
+    find_program("virsh").ok_or_else(|| {
+        "virsh was not found on PATH; install the libvirt client (e.g. \
+         `apt install libvirt-clients` or `dnf install libvirt-client`)"
+            .to_string()
+    })
+}
+
+/// Domain name derived from the image, e.g. `ubuntu-noble-amd64-20250115`.
+pub fn domain_name(image: &Image) -> String {
+    format!(
+        "{}-{}-{}-{}",
+        image.os(),
+        image.name(),
+        image.arch_name(),
+        image.version()
+    )
+    .chars()
+    .map(|c| {
+        if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') {
+            c
+        } else {
+            '-'
+        }
+    })
+    .collect()
+}
+
+/// A domain to define around an imported image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Domain {
+    pub name: String,
+    /// Shown by `virsh desc`.
+    pub description: String,
+    pub arch: Arch,
+    /// `kvm`, or `qemu` for emulation.
+    pub domain_type: String,
+    pub memory_mib: u64,
+    pub cpus: u32,
+    pub disk: PathBuf,
+    pub disk_format: String,
+    /// NoCloud seed attached next to the disk.
+    pub seed: Option<(PathBuf, SeedFormat)>,
+    /// Ignition config passed as the firmware config entry of that name,
+    /// for images not using cloud-init.
+    pub ignition: Option<(String, PathBuf)>,
+    /// Libvirt network the NIC joins.
+    pub network: String,
+}
+
+impl Domain {
+    /// Domain XML: virtio disk and NIC, serial console and EFI firmware on
+    /// the architectures that need it.
+    pub fn to_xml(&self) -> String {
+        let (arch, machine) = match self.arch {
+            Arch::Amd64 => ("x86_64", "q35"),
+            Arch::Arm64 => ("aarch64", "virt"),
+            Arch::Armhf => ("armv7l", "virt"),
+            Arch::Ppc64le => ("ppc64le", "pseries"),
+            Arch::S390x => ("s390x", "s390-ccw-virtio"),
+            Arch::Riscv64 => ("riscv64", "virt"),
+        };
+        let firmware = if self.arch == Arch::Arm64 {
+            " firmware='efi'"
+        } else {
+            ""
+        };
+        let cpu = if self.domain_type == "kvm" {
+            "host-passthrough"
+        } else {
+            "maximum"
+        };
+        let mut xml = format!(
+            "<domain type='{}'>\n  <name>{}</name>\n  <description>{}</description>\n  \
+             <memory unit='MiB'>{}</memory>\n  <vcpu>{}</vcpu>\n  \
+             <os{firmware}>\n    <type arch='{arch}' machine='{machine}'>hvm</type>\n  </os>\n  \
+             <features>\n    <acpi/>\n  </features>\n  <cpu mode='{cpu}'/>\n",
+            escape(&self.domain_type),
+            escape(&self.name),
+            escape(&self.description),
+            self.memory_mib,
+            self.cpus,
+        );
+        if let Some((name, config)) = &self.ignition {
+            xml.push_str(&format!(
+                "  <sysinfo type='fwcfg'>\n    <entry name='{}' file='{}'/>\n  </sysinfo>\n",
+                escape(name),
+                escape(&config.to_string_lossy())
+            ));
+        }
+        xml.push_str("  <devices>\n");
+        xml.push_str(&disk_xml(&self.disk, &self.disk_format, "disk", "vda"));
+        if let Some((seed, format)) = &self.seed {
+            xml.push_str(&match format {
+                SeedFormat::Iso => disk_xml(seed, "raw", "cdrom", "sda"),
+                SeedFormat::Vfat => disk_xml(seed, "raw", "disk", "vdb"),
+            });
+        }
+        xml.push_str(&format!(
+            "    <interface type='network'>\n      <source network='{}'/>\n      \
+             <model type='virtio'/>\n    </interface>\n    \
+             <serial type='pty'/>\n    <console type='pty'/>\n  </devices>\n</domain>\n",
+            escape(&self.network)
+        ));
+        xml
+    }
+}
+
+fn disk_xml(path: &Path, format: &str, device: &str, target: &str) -> String {
+    let bus = if target.starts_with("vd") {
+        "virtio"
+    } else {
+        "sata"
+    };
+    let readonly = if device == "cdrom" {
+        "\n      <readonly/>"
+    } else {
+        ""
+    };
+    format!(
+        "    <disk type='file' device='{device}'>\n      <driver name='qemu' type='{}'/>\n      \
+         <source file='{}'/>\n      <target dev='{target}' bus='{bus}'/>{readonly}\n    </disk>\n",
+        escape(format),
+        escape(&path.to_string_lossy()),
+    )
+}
+
+/// Escape `text` for an XML attribute or element.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('\'', "&apos;")
+        .replace('"', "&quot;")
+}
+
+/// `virsh`, connected to `uri` (the libvirt default otherwise).
+#[derive(Debug, Clone)]
+pub struct Virsh {
+    program: PathBuf,
+    uri: Option<String>,
+}
+
+impl Virsh {
+    pub fn new(uri: Option<&str>) -> Result<Self, String> {
+        Ok(Self {
+            program: locate()?,
+            uri: uri.map(str::to_string),
+        })
+    }
+
+    /// Run `virsh` with `args` and return its stdout, turning a non-zero
+    /// exit into an error that carries its stderr.
+    fn run(&self, args: &[&std::ffi::OsStr]) -> Result<String, String> {
+        let mut command = Command::new(&self.program);
+        if let Some(uri) = &self.uri {
+            command.args(["--connect", uri]);
+        }
+        let output = command
+            .args(args)
+            .output()
+            .map_err(|e| format!("Failed to run '{}': {e}", self.program.display()))?;
+        if output.status.success() {
+            Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        } else {
+            Err(format!(
+                "virsh {} failed ({}): {}",
+                args.first()
+                    .map(|a| a.to_string_lossy())
+                    .unwrap_or_default(),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))
+        }
+    }
+
+    /// Put `source` into `pool` as the volume `name` and return its path.
+    /// With `backing` the volume is a qcow2 overlay on top of `source`, which
+    /// then has to stay in place and be readable by the hypervisor; otherwise
+    /// the image is uploaded as is.
+    pub fn import_volume(
+        &self,
+        pool: &str,
+        name: &str,
+        source: &Path,
+        info: &qemu_img::Info,
+        backing: bool,
+    ) -> Result<PathBuf, String> {
+        if backing {
+            let source = source
+                .canonicalize()
+                .map_err(|e| format!("Failed to resolve '{}': {e}", source.display()))?;
+            self.run(&[
+                "vol-create-as".as_ref(),
+                pool.as_ref(),
+                name.as_ref(),
+                info.virtual_size.to_string().as_ref(),
+                "--format".as_ref(),
+                "qcow2".as_ref(),
+                "--backing-vol".as_ref(),
+                source.as_os_str(),
+                "--backing-vol-format".as_ref(),
+                info.format.as_ref(),
+            ])?;
+        } else {
+            self.upload(pool, name, source, &info.format)?;
+        }
+        self.volume_path(pool, name)
+    }
+
+    /// Create the volume `name` in `pool` sized for `source` and upload it.
+    pub fn upload(
+        &self,
+        pool: &str,
+        name: &str,
+        source: &Path,
+        format: &str,
+    ) -> Result<(), String> {
+        let size = fs::metadata(source)
+            .map_err(|e| format!("Failed to read '{}': {e}", source.display()))?
+            .len();
+        self.run(&[
+            "vol-create-as".as_ref(),
+            pool.as_ref(),
+            name.as_ref(),
+            size.to_string().as_ref(),
+            "--format".as_ref(),
+            format.as_ref(),
+        ])?;
+        self.run(&[
+            "vol-upload".as_ref(),
+            "--pool".as_ref(),
+            pool.as_ref(),
+            name.as_ref(),
+            source.as_os_str(),
+        ])
+        .map(|_| ())
+    }
+
+    /// Grow the volume to `size`, or by it when it starts with `+`.
+    pub fn resize_volume(&self, pool: &str, name: &str, size: &str) -> Result<(), String> {
+        let mut args: Vec<&std::ffi::OsStr> = vec![
+            "vol-resize".as_ref(),
+            "--pool".as_ref(),
+            pool.as_ref(),
+            name.as_ref(),
+            size.trim_start_matches('+').as_ref(),
+        ];
+        if size.starts_with('+') {
+            args.push("--delta".as_ref());
+        }
+        self.run(&args).map(|_| ())
+    }
+
+    pub fn volume_path(&self, pool: &str, name: &str) -> Result<PathBuf, String> {
+        self.run(&[
+            "vol-path".as_ref(),
+            "--pool".as_ref(),
+            pool.as_ref(),
+            name.as_ref(),
+        ])
+        .map(PathBuf::from)
+    }
+
+    /// Define `domain` persistently.
+    pub fn define(&self, domain: &Domain) -> Result<(), String> {
+        let dir = paths::temp_dir();
+        fs::create_dir_all(&dir)
+            .map_err(|e| format!("Failed to create '{}': {e}", dir.display()))?;
+        let xml = dir.join(format!("{}.xml", domain.name));
+        fs::write(&xml, domain.to_xml())
+            .map_err(|e| format!("Failed to write '{}': {e}", xml.display()))?;
+        let result = self.run(&["define".as_ref(), xml.as_os_str()]);
+        let _ = fs::remove_file(&xml);
+        result.map(|_| ())
+    }
+
+    pub fn start(&self, name: &str) -> Result<(), String> {
+        self.run(&["start".as_ref(), name.as_ref()]).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Domain, domain_name};
+    use crate::cloud::{Arch, sample_image};
+    use crate::helpers::seed::SeedFormat;
+    use std::path::PathBuf;
+
+    #[test]
+    fn describes_the_image_as_a_domain() {
+        let image = sample_image();
+        let name = domain_name(&image);
+        assert_eq!(name, "debian-bookworm-amd64-20250210-2019");
+
+        let xml = Domain {
+            name,
+            description: "ubuntu <noble>".to_string(),
+            arch: Arch::Amd64,
+            domain_type: "kvm".to_string(),
+            memory_mib: 2048,
+            cpus: 2,
+            disk: PathBuf::from("/pool/vm.qcow2"),
+            disk_format: "qcow2".to_string(),
+            seed: Some((PathBuf::from("/pool/vm-seed.iso"), SeedFormat::Iso)),
+            ignition: None,
+            network: "default".to_string(),
+        }
+        .to_xml();
+        assert!(xml.starts_with("<domain type='kvm'>"));
+        assert!(xml.contains("<description>ubuntu &lt;noble&gt;</description>"));
+        assert!(xml.contains("<source file='/pool/vm.qcow2'/>"));
+        assert!(xml.contains("<target dev='vda' bus='virtio'/>"));
+        assert!(xml.contains("device='cdrom'"));
+        assert!(xml.contains("<source network='default'/>"));
+    }
+}
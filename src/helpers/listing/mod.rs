@@ -0,0 +1,159 @@
+use anyhow::{Context, Result};
+use percent_encoding::percent_decode_str;
+use reqwest::Client;
+use scraper::{Html, Selector};
+use serde::Deserialize;
+
+use crate::helpers::http_cache;
+
+/// One entry of a directory index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Last path segment, percent-decoded and without the trailing slash.
+    pub name: String,
+    /// Whether the entry is a subdirectory.
+    pub dir: bool,
+}
+
+/// Turns the body of a directory index into its entries. Every index style a
+/// mirror may serve has its own parser; [`parse`] picks one per body.
+pub trait ListingParser: Send + Sync {
+    /// Whether `body` looks like an index this parser understands.
+    fn accepts(&self, body: &str) -> bool;
+
+    /// Entries of `body`, in document order.
+    fn parse(&self, body: &str) -> Vec<Entry>;
+}
+
+/// Every index style known, tried in order; the HTML parser accepts anything
+/// and comes last.
+static PARSERS: &[&dyn ListingParser] = &[&JsonIndex, &HtmlIndex];
+
+/// HTML indexes as written by Apache `mod_autoindex`, nginx `autoindex` or
+/// S3 style web frontends: every `<a href>` is an entry, whatever its
+/// quoting, and links to other sites, parents or sort orders are skipped.
+pub struct HtmlIndex;
+
+impl ListingParser for HtmlIndex {
+    fn accepts(&self, _body: &str) -> bool {
+        true
+    }
+
+    fn parse(&self, body: &str) -> Vec<Entry> {
+        let selector = Selector::parse("a[href]").expect("valid selector");
+        Html::parse_document(body)
+            .select(&selector)
+            .filter_map(|a| a.value().attr("href"))
+            .filter_map(entry_from_href)
+            .collect()
+    }
+}
+
+/// JSON indexes as written by nginx `autoindex_format json`.
+pub struct JsonIndex;
+
+#[derive(Deserialize)]
+struct JsonEntry {
+    name: String,
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+impl ListingParser for JsonIndex {
+    fn accepts(&self, body: &str) -> bool {
+        body.trim_start().starts_with('[')
+    }
+
+    fn parse(&self, body: &str) -> Vec<Entry> {
+        serde_json::from_str::<Vec<JsonEntry>>(body)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|e| Entry {
+                name: e.name.trim_end_matches('/').to_string(),
+                dir: e.kind == "directory",
+            })
+            .filter(|e| !e.name.is_empty())
+            .collect()
+    }
+}
+
+/// The entry an index link points at, if it names a child of the listed
+/// directory rather than a sort order, fragment, parent or another site.
+fn entry_from_href(href: &str) -> Option<Entry> {
+    let href = href.trim();
+    if href.is_empty() || href.starts_with(['?', '#']) || href.contains("://") {
+        return None;
+    }
+    let path = href.split(['?', '#']).next().unwrap_or_default();
+    let dir = path.ends_with('/');
+    let segment = path.trim_end_matches('/').rsplit('/').next()?;
+    if segment.is_empty() || segment == "." || segment == ".." {
+        return None;
+    }
+    let name = percent_decode_str(segment).decode_utf8_lossy().into_owned();
+    Some(Entry { name, dir })
+}
+
+/// Entries of the index `body`, read by the first parser accepting it.
+pub fn parse(body: &str) -> Vec<Entry> {
+    PARSERS
+        .iter()
+        .find(|p| p.accepts(body))
+        .map(|p| p.parse(body))
+        .unwrap_or_default()
+}
+
+/// Download the index at `url` and return its entries.
+pub async fn fetch(client: &Client, url: &str) -> Result<Vec<Entry>> {
+    let body = http_cache::text(client, url)
+        .await
+        .with_context(|| format!("fetch directory listing: {url}"))?;
+    Ok(parse(&body))
+}
+
+/// Names of the subdirectories among `entries`, without duplicates and in
+/// document order.
+pub fn subdirs(entries: &[Entry]) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for entry in entries.iter().filter(|e| e.dir) {
+        if !names.contains(&entry.name) {
+            names.push(entry.name.clone());
+        }
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, subdirs};
+
+    #[test]
+    fn reads_html_and_json_indexes() {
+        let apache = r#"<html><body><pre>
+            <a href="?C=N;O=D">Name</a> <a href="../">Parent Directory</a>
+            <a href='bookworm/'>bookworm/</a>
+            <a href=trixie/>trixie/</a>
+            <a href="/almalinux/9/">9/</a>
+            <a href="https://example.invalid/other/">elsewhere</a>
+            <a href="SHA512SUMS">SHA512SUMS</a>
+            <a href="bookworm/">bookworm/</a>
+            <a href="debian%2012/">debian 12/</a>
+        </pre></body></html>"#;
+        assert_eq!(
+            subdirs(&parse(apache)),
+            ["bookworm", "trixie", "9", "debian 12"]
+        );
+        assert!(
+            parse(apache)
+                .iter()
+                .any(|e| e.name == "SHA512SUMS" && !e.dir)
+        );
+
+        let nginx = r#"[
+            { "name": "20250210-2019", "type": "directory", "mtime": "Mon, 10 Feb 2025 20:19:00 GMT" },
+            { "name": "latest", "type": "directory" },
+            { "name": "SHA512SUMS", "type": "file", "size": 512 }
+        ]"#;
+        assert_eq!(subdirs(&parse(nginx)), ["20250210-2019", "latest"]);
+    }
+}
@@ -0,0 +1,151 @@
+use crate::cloud::{ChecksumKind, ImageChecksum};
+
+/// One `<file>` of a Metalink v4 (RFC 5854) document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetalinkFile {
+    pub name: String,
+    pub size: Option<u64>,
+    /// `(type, hex digest)` pairs such as `("sha-256", "ab12...")`.
+    pub hashes: Vec<(String, String)>,
+    /// Download URLs, most preferred first.
+    pub urls: Vec<String>,
+}
+
+impl MetalinkFile {
+    /// Strongest embedded hash this tool can verify.
+    pub fn checksum(&self) -> Option<ImageChecksum> {
+        [
+            ("sha-512", ChecksumKind::Sha512),
+            ("sha-256", ChecksumKind::Sha256),
+            ("sha-1", ChecksumKind::Sha1),
+            ("md5", ChecksumKind::Md5),
+        ]
+        .into_iter()
+        .find_map(|(name, kind)| {
+            self.hashes
+                .iter()
+                .find(|(t, _)| t.eq_ignore_ascii_case(name))
+                .map(|(_, value)| ImageChecksum::new(kind, value.to_lowercase()))
+        })
+    }
+}
+
+/// Parse a Metalink v4 document. URLs are ordered by their `priority`
+/// attribute (lower is better, missing ones last) keeping document order for
+/// ties; only HTTP(S) URLs are kept.
+pub fn parse(xml: &str) -> Result<Vec<MetalinkFile>, String> {
+    let doc = roxmltree::Document::parse(xml).map_err(|e| format!("Invalid metalink: {e}"))?;
+    let root = doc.root_element();
+    if root.tag_name().name() != "metalink" {
+        return Err(format!(
+            "Invalid metalink: unexpected root element <{}>",
+            root.tag_name().name()
+        ));
+    }
+
+    let files = root
+        .children()
+        .filter(|n| n.tag_name().name() == "file")
+        .filter_map(|file| {
+            let name = file.attribute("name")?.to_string();
+            let child_text = |tag: &str| {
+                file.children()
+                    .find(|n| n.tag_name().name() == tag)
+                    .and_then(|n| n.text())
+                    .map(str::trim)
+            };
+
+            let size = child_text("size").and_then(|s| s.parse().ok());
+            let hashes = file
+                .children()
+                .filter(|n| n.tag_name().name() == "hash")
+                .filter_map(|n| {
+                    Some((
+                        n.attribute("type")?.to_string(),
+                        n.text()?.trim().to_string(),
+                    ))
+                })
+                .collect();
+
+            let mut urls: Vec<(u32, String)> = file
+                .children()
+                .filter(|n| n.tag_name().name() == "url")
+                .filter_map(|n| {
+                    let url = n.text()?.trim();
+                    let priority = n
+                        .attribute("priority")
+                        .and_then(|p| p.parse().ok())
+                        .unwrap_or(u32::MAX);
+                    (url.starts_with("http://") || url.starts_with("https://"))
+                        .then(|| (priority, url.to_string()))
+                })
+                .collect();
+            urls.sort_by_key(|(priority, _)| *priority);
+
+            Some(MetalinkFile {
+                name,
+                size,
+                hashes,
+                urls: urls.into_iter().map(|(_, url)| url).collect(),
+            })
+        })
+        .collect();
+    Ok(files)
+}
+
+/// Pick the entry describing `filename`, or the only entry of the document.
+pub fn find_file(files: Vec<MetalinkFile>, filename: &str) -> Option<MetalinkFile> {
+    if files.len() == 1 {
+        return files.into_iter().next();
+    }
+    files.into_iter().find(|f| f.name == filename)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_file, parse};
+    use crate::cloud::ChecksumKind;
+
+    const SAMPLE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<metalink xmlns="urn:ietf:params:xml:ns:metalink">
+  <file name="Fedora-Cloud.qcow2">
+    <size>524288</size>
+    <hash type="sha-256">AB12</hash>
+    <hash type="sha-512">cd34</hash>
+    <url priority="2">https://slow.invalid/Fedora-Cloud.qcow2</url>
+    <url>https://unranked.invalid/Fedora-Cloud.qcow2</url>
+    <url priority="1">https://fast.invalid/Fedora-Cloud.qcow2</url>
+    <url priority="1">ftp://ftp.invalid/Fedora-Cloud.qcow2</url>
+  </file>
+  <file name="CHECKSUM">
+    <url>https://fast.invalid/CHECKSUM</url>
+  </file>
+</metalink>"#;
+
+    #[test]
+    fn parses_files_urls_and_hashes() {
+        let files = parse(SAMPLE).unwrap();
+        assert_eq!(files.len(), 2);
+
+        let file = find_file(files, "Fedora-Cloud.qcow2").unwrap();
+        assert_eq!(file.size, Some(524288));
+        assert_eq!(
+            file.urls,
+            [
+                "https://fast.invalid/Fedora-Cloud.qcow2",
+                "https://slow.invalid/Fedora-Cloud.qcow2",
+                "https://unranked.invalid/Fedora-Cloud.qcow2",
+            ]
+        );
+
+        let checksum = file.checksum().unwrap();
+        assert_eq!(checksum.kind(), ChecksumKind::Sha512);
+        assert_eq!(checksum.value(), "cd34");
+    }
+
+    #[test]
+    fn rejects_other_documents() {
+        assert!(parse("<html></html>").is_err());
+        assert!(parse("not xml").is_err());
+    }
+}
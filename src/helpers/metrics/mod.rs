@@ -0,0 +1,265 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::cloud::{BuildId, Image};
+use crate::error::CloudImagesError;
+
+/// Content type of [`Metrics::render`].
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Labels identifying a tracked image: every build of it shares them.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Tracked {
+    distro: String,
+    release: String,
+    arch: String,
+    variant: String,
+    format: String,
+}
+
+impl Tracked {
+    fn of(image: &Image) -> Self {
+        Self {
+            distro: image.os().to_string(),
+            release: image.name().to_string(),
+            arch: image.arch_name().to_string(),
+            variant: image.variant().to_string(),
+            format: image.format().as_str().to_string(),
+        }
+    }
+
+    fn labels(&self) -> String {
+        [
+            ("distro", &self.distro),
+            ("release", &self.release),
+            ("arch", &self.arch),
+            ("variant", &self.variant),
+            ("format", &self.format),
+        ]
+        .iter()
+        .map(|(name, value)| format!("{name}=\"{}\"", escape(value)))
+        .collect::<Vec<_>>()
+        .join(",")
+    }
+}
+
+/// Newest builds of a tracked image seen upstream and downloaded.
+#[derive(Debug, Default)]
+struct Freshness {
+    available: Option<BuildId>,
+    downloaded: Option<BuildId>,
+}
+
+/// Name, help and value per tracked image of a gauge.
+type Gauge = (&'static str, &'static str, fn(&Freshness) -> Option<i64>);
+
+/// Escape a label value of the Prometheus text format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Keep the newer of `current` and `build`.
+fn newest(current: &mut Option<BuildId>, build: BuildId) {
+    if current.as_ref().is_none_or(|current| build > *current) {
+        *current = Some(build);
+    }
+}
+
+/// Counters of the downloads a long-running process made and the freshness
+/// of the images it tracks, in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    started: AtomicU64,
+    completed: AtomicU64,
+    failed: AtomicU64,
+    cancelled: AtomicU64,
+    bytes: AtomicU64,
+    checksum_failures: AtomicU64,
+    images: Mutex<BTreeMap<Tracked, Freshness>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn download_started(&self) {
+        self.started.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count the outcome of a download; successful ones also record the
+    /// build of `image` as downloaded.
+    pub fn download_finished<T>(&self, image: &Image, result: &Result<T, CloudImagesError>) {
+        let counter = match result {
+            Ok(_) => {
+                self.downloaded(image);
+                &self.completed
+            }
+            Err(CloudImagesError::Cancelled(_)) => &self.cancelled,
+            Err(_) => &self.failed,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `bytes` more were transferred.
+    pub fn transferred(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// A download failed verification (and may be retried).
+    pub fn checksum_failed(&self) {
+        self.checksum_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record the builds of `images` as available upstream.
+    pub fn available(&self, images: &[Image]) {
+        let mut tracked = self.images.lock().expect("metrics lock");
+        for image in images {
+            let freshness = tracked.entry(Tracked::of(image)).or_default();
+            newest(&mut freshness.available, BuildId::new(image.version()));
+        }
+    }
+
+    /// Record the build of `image` as downloaded.
+    pub fn downloaded(&self, image: &Image) {
+        let mut tracked = self.images.lock().expect("metrics lock");
+        let freshness = tracked.entry(Tracked::of(image)).or_default();
+        newest(&mut freshness.downloaded, BuildId::new(image.version()));
+        // What was just downloaded was available, even if never resolved.
+        newest(&mut freshness.available, BuildId::new(image.version()));
+    }
+
+    /// Every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "cloud_images_downloads_started_total",
+                "Downloads started.",
+                &self.started,
+            ),
+            (
+                "cloud_images_downloads_completed_total",
+                "Downloads verified and finished.",
+                &self.completed,
+            ),
+            (
+                "cloud_images_downloads_failed_total",
+                "Downloads that failed.",
+                &self.failed,
+            ),
+            (
+                "cloud_images_downloads_cancelled_total",
+                "Downloads cancelled before they finished.",
+                &self.cancelled,
+            ),
+            (
+                "cloud_images_downloaded_bytes_total",
+                "Bytes transferred by downloads.",
+                &self.bytes,
+            ),
+            (
+                "cloud_images_checksum_failures_total",
+                "Transfers that failed checksum verification.",
+                &self.checksum_failures,
+            ),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} counter");
+            let _ = writeln!(out, "{name} {}", value.load(Ordering::Relaxed));
+        }
+
+        let tracked = self.images.lock().expect("metrics lock");
+        let gauges: [Gauge; 3] = [
+            (
+                "cloud_images_newest_available_build_timestamp_seconds",
+                "Build date of the newest build seen upstream.",
+                |f| timestamp(f.available.as_ref()),
+            ),
+            (
+                "cloud_images_newest_downloaded_build_timestamp_seconds",
+                "Build date of the newest build downloaded.",
+                |f| timestamp(f.downloaded.as_ref()),
+            ),
+            (
+                "cloud_images_up_to_date",
+                "Whether the newest build seen upstream has been downloaded.",
+                |f| {
+                    f.downloaded
+                        .as_ref()
+                        .map(|downloaded| i64::from(Some(downloaded) >= f.available.as_ref()))
+                },
+            ),
+        ];
+        for (name, help, value) in gauges {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} gauge");
+            for (image, freshness) in tracked.iter() {
+                if let Some(value) = value(freshness) {
+                    let _ = writeln!(out, "{name}{{{}}} {value}", image.labels());
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Seconds since the epoch of the date in `build`, if it has one.
+fn timestamp(build: Option<&BuildId>) -> Option<i64> {
+    build
+        .and_then(BuildId::built)
+        .map(|built| built.and_utc().timestamp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Metrics;
+    use crate::cloud::{Image, sample_image};
+    use crate::error::CloudImagesError;
+
+    fn image(version: &str) -> Image {
+        sample_image().with_version(version)
+    }
+
+    #[test]
+    fn renders_counters_and_freshness() {
+        let metrics = Metrics::new();
+        metrics.download_started();
+        metrics.transferred(1024);
+        metrics.checksum_failed();
+        metrics.download_finished(&image("20250101-0000"), &Ok(()));
+        metrics.download_finished::<()>(
+            &image("20250101-0000"),
+            &Err(CloudImagesError::Network("down".to_string())),
+        );
+        metrics.available(&[image("20241201-0000"), image("20250210-2019")]);
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE cloud_images_downloads_started_total counter\n"));
+        assert!(text.contains("cloud_images_downloads_completed_total 1\n"));
+        assert!(text.contains("cloud_images_downloads_failed_total 1\n"));
+        assert!(text.contains("cloud_images_downloaded_bytes_total 1024\n"));
+        assert!(text.contains("cloud_images_checksum_failures_total 1\n"));
+        let labels = r#"{distro="debian",release="bookworm",arch="amd64",variant="genericcloud",format="qcow2"}"#;
+        assert!(text.contains(&format!(
+            "cloud_images_newest_available_build_timestamp_seconds{labels} 1739218740\n"
+        )));
+        assert!(text.contains(&format!(
+            "cloud_images_newest_downloaded_build_timestamp_seconds{labels} 1735689600\n"
+        )));
+        assert!(text.contains(&format!("cloud_images_up_to_date{labels} 0\n")));
+
+        metrics.downloaded(&image("20250210-2019"));
+        assert!(
+            metrics
+                .render()
+                .contains(&format!("cloud_images_up_to_date{labels} 1\n"))
+        );
+    }
+}
@@ -0,0 +1,570 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use futures::{StreamExt, stream};
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+
+use crate::cloud::{Arch, Image, ImageFormat, Variant, sort_builds_newest_first};
+use crate::error::CloudImagesError;
+use crate::helpers::image_resolver::{
+    BatchItem, DownloadOptions, ExistingFile, OnMismatch, download_batch,
+};
+use crate::helpers::{http, listing, report, retry, sanitize};
+use crate::repositories::{self, ImageProvider, Release};
+
+/// Bookkeeping of a mirror, at its root.
+const STATE_FILE: &str = ".mirror.json";
+
+/// Releases listed at the same time while planning.
+const LIST_CONCURRENCY: usize = 4;
+
+/// Directory-wide checksum listings published next to images.
+pub(crate) const LISTINGS: &[&str] = &[
+    "SHA256SUMS",
+    "SHA512SUMS",
+    "MD5SUMS",
+    "CHECKSUM",
+    "CHECKSUMS",
+];
+
+/// Per-image checksum files, `<image><suffix>`.
+pub(crate) const SIDECARS: &[&str] = &[
+    ".sha256",
+    ".sha512",
+    ".sha256sum",
+    ".sha512sum",
+    ".CHECKSUM",
+];
+
+/// Detached signatures of either, `<file><suffix>`.
+pub(crate) const SIGNATURES: &[&str] = &[".gpg", ".sign", ".sig", ".asc"];
+
+/// Which images of a distro a mirror holds; empty lists keep everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MirrorFilter {
+    /// Release ids as accepted by `--release`.
+    pub releases: Vec<String>,
+    pub arches: Vec<Arch>,
+    pub variants: Vec<Variant>,
+    pub formats: Vec<ImageFormat>,
+    /// Newest builds kept per release and architecture; 0 keeps all.
+    pub builds: usize,
+}
+
+impl MirrorFilter {
+    fn wants_release(&self, release: &Release) -> bool {
+        self.releases.is_empty()
+            || self
+                .releases
+                .iter()
+                .any(|id| id.eq_ignore_ascii_case(&release.id))
+    }
+
+    fn wants(&self, image: &Image) -> bool {
+        (self.variants.is_empty() || self.variants.contains(image.variant()))
+            && (self.formats.is_empty() || self.formats.contains(image.format()))
+    }
+
+    /// Drop every build of `images` but the `builds` newest.
+    fn newest_builds(&self, images: &mut Vec<Image>) {
+        if self.builds == 0 {
+            return;
+        }
+        let mut builds: Vec<&str> = images.iter().map(Image::version).collect();
+        sort_builds_newest_first(&mut builds);
+        let keep: HashSet<String> = builds
+            .into_iter()
+            .take(self.builds)
+            .map(str::to_string)
+            .collect();
+        images.retain(|image| keep.contains(image.version()));
+    }
+}
+
+/// Every image of `provider` `filter` selects, listed from upstream.
+/// Releases that fail to list are skipped with a warning; naming a release
+/// no architecture offers is an error.
+pub async fn plan(
+    provider: &dyn ImageProvider,
+    client: &Client,
+    track: &str,
+    filter: &MirrorFilter,
+) -> Result<Vec<Image>, String> {
+    let name = provider.display_name();
+    let arches: Vec<Arch> = provider
+        .supported_arches()
+        .into_iter()
+        .filter(|arch| filter.arches.is_empty() || filter.arches.contains(arch))
+        .collect();
+    if arches.is_empty() {
+        return Err(format!(
+            "{name} publishes none of the requested architectures"
+        ));
+    }
+
+    let mut seen = BTreeSet::new();
+    let mut planned = Vec::new();
+    for arch in arches {
+        let arch_name = arch.name(provider.arch_naming());
+        let releases = match provider.releases(client, track, arch).await {
+            Ok(releases) => releases,
+            Err(err) => {
+                eprintln!("Warning: skipping {name} {arch_name}: {err:#}");
+                continue;
+            }
+        };
+        let releases: Vec<Release> = releases
+            .into_iter()
+            .filter(|release| filter.wants_release(release))
+            .collect();
+        seen.extend(releases.iter().map(|r| r.id.to_ascii_lowercase()));
+        let listings = stream::iter(releases)
+            .map(|release| async move {
+                let images = provider.list(client, track, &release, arch).await;
+                (release, images)
+            })
+            .buffer_unordered(LIST_CONCURRENCY)
+            .collect::<Vec<_>>()
+            .await;
+        for (release, images) in listings {
+            match images {
+                Ok(mut images) => {
+                    images.retain(|image| filter.wants(image));
+                    filter.newest_builds(&mut images);
+                    planned.extend(images);
+                }
+                Err(err) => eprintln!(
+                    "Warning: skipping {name} {} {arch_name}: {err:#}",
+                    release.id
+                ),
+            }
+        }
+    }
+
+    let missing: Vec<&str> = filter
+        .releases
+        .iter()
+        .filter(|id| !seen.contains(&id.to_ascii_lowercase()))
+        .map(String::as_str)
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!(
+            "{name} has no release called {}",
+            missing.join(", ")
+        ));
+    }
+    planned.sort_by(|a, b| a.url().cmp(b.url()));
+    planned.dedup_by(|a, b| a.url() == b.url());
+    Ok(planned)
+}
+
+/// Where `url` lives in a mirror: the repository's name followed by the
+/// path below its root (`debian/bookworm/20250210-2019/…`), or the host
+/// followed by the path for URLs outside the configured repositories.
+pub fn upstream_path(url: &str) -> Result<PathBuf, String> {
+    let below_root = repositories::all().ok().and_then(|repos| {
+        repos.iter().find_map(|repo| {
+            repo.roots()
+                .iter()
+                .find_map(|root| url.strip_prefix(root))
+                .map(|rest| (repo.name().to_string(), rest.to_string()))
+        })
+    });
+    let (top, rest) = match below_root {
+        Some(found) => found,
+        None => {
+            let parsed = Url::parse(url).map_err(|e| format!("Invalid URL {url}: {e}"))?;
+            let host = parsed
+                .host_str()
+                .ok_or_else(|| format!("{url} has no host"))?
+                .to_string();
+            (host, parsed.path().to_string())
+        }
+    };
+    let rest = rest.split(['?', '#']).next().unwrap_or_default();
+    let mut path = PathBuf::from(sanitize::file_name(&top)?);
+    for segment in rest.split('/').filter(|s| !s.is_empty()) {
+        path.push(sanitize::file_name(segment)?);
+    }
+    if path.components().count() < 2 {
+        return Err(format!("{url} names no file"));
+    }
+    Ok(path)
+}
+
+/// Whether `name`, listed next to the mirrored `images`, is a checksum
+/// listing, the checksum file of one of them, or a signature of either.
+fn is_verification_file(name: &str, images: &HashSet<&str>) -> bool {
+    if let Some(signed) = SIGNATURES
+        .iter()
+        .find_map(|suffix| name.strip_suffix(suffix))
+    {
+        return is_verification_file(signed, images);
+    }
+    LISTINGS.contains(&name)
+        || SIDECARS.iter().any(|suffix| {
+            name.strip_suffix(suffix)
+                .is_some_and(|image| images.contains(image))
+        })
+}
+
+/// A file the mirror holds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MirrorEntry {
+    /// Relative to the mirror's root, `/` separated.
+    pub path: String,
+    /// Where it was fetched from.
+    pub url: String,
+    /// The image it is, with its published checksum; `None` for checksum
+    /// and signature files.
+    pub image: Option<Image>,
+    pub size: Option<u64>,
+}
+
+/// The files a mirror holds, as recorded by the last sync.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct MirrorState {
+    pub entries: Vec<MirrorEntry>,
+}
+
+impl MirrorState {
+    /// The state of the mirror at `root`; empty before the first sync.
+    pub fn load(root: &Path) -> Result<Self, String> {
+        let path = root.join(STATE_FILE);
+        match fs::read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data)
+                .map_err(|e| format!("Failed to parse '{}': {e}", path.display())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(format!("Failed to read '{}': {e}", path.display())),
+        }
+    }
+
+    fn save(&self, root: &Path) -> Result<(), String> {
+        let path = root.join(STATE_FILE);
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| format!("Failed to serialise the mirror state: {e}"))?;
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, json).map_err(|e| format!("Failed to write '{}': {e}", tmp.display()))?;
+        fs::rename(&tmp, &path).map_err(|e| format!("Failed to write '{}': {e}", path.display()))
+    }
+
+    fn entry(&self, path: &str) -> Option<&MirrorEntry> {
+        self.entries.iter().find(|entry| entry.path == path)
+    }
+
+    /// Whether `image` is already mirrored at `path`: recorded from the same
+    /// URL with the same published checksum, and still of the recorded size.
+    /// Without a checksum to compare the download decides.
+    fn holds(&self, root: &Path, path: &str, image: &Image) -> bool {
+        let Some(entry) = self.entry(path) else {
+            return false;
+        };
+        let Some(recorded) = entry.image.as_ref().and_then(Image::checksum) else {
+            return false;
+        };
+        entry.url == image.url()
+            && image.checksum() == Some(recorded)
+            && fs::metadata(root.join(path))
+                .is_ok_and(|meta| entry.size.is_none_or(|size| meta.len() == size))
+    }
+}
+
+/// Outcome of [`sync`].
+#[derive(Debug, Default)]
+pub struct MirrorSummary {
+    /// Images downloaded (or repaired) by this run.
+    pub downloaded: usize,
+    /// Images the mirror already held.
+    pub current: usize,
+    /// Checksum and signature files that changed.
+    pub updated: usize,
+    /// Files removed because nothing selects them any more.
+    pub pruned: usize,
+    /// URLs that could not be mirrored, with the reason.
+    pub failed: Vec<(String, String)>,
+}
+
+fn path_string(path: &Path) -> String {
+    path.iter()
+        .map(|segment| segment.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Write `data` to `path` unless it already holds it; returns whether it
+/// changed.
+fn write_if_changed(path: &Path, data: &[u8]) -> Result<bool, String> {
+    if fs::read(path).is_ok_and(|current| current == data) {
+        return Ok(false);
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("Failed to create '{}': {e}", dir.display()))?;
+    }
+    let tmp = path.with_extension("mirror-tmp");
+    fs::write(&tmp, data).map_err(|e| format!("Failed to write '{}': {e}", tmp.display()))?;
+    fs::rename(&tmp, path).map_err(|e| format!("Failed to write '{}': {e}", path.display()))?;
+    Ok(true)
+}
+
+/// Delete `path` and the directories it leaves empty, up to `root`.
+fn remove(root: &Path, path: &Path) {
+    let _ = fs::remove_file(path);
+    let _ = fs::remove_file(report::sidecar_path(path));
+    let mut dir = path.parent();
+    while let Some(current) = dir.filter(|dir| *dir != root && dir.starts_with(root)) {
+        if fs::remove_dir(current).is_err() {
+            break;
+        }
+        dir = current.parent();
+    }
+}
+
+/// Bring the mirror at `root` in line with `images`: download the ones it
+/// does not hold yet (at most `jobs` at a time) and refresh the checksum
+/// listings, per-image checksum files and signatures published next to
+/// them, all at their upstream paths (see [`upstream_path`]). Images
+/// recorded with an unchanged checksum are not even re-hashed. With `prune`
+/// files mirrored earlier that `images` no longer covers are deleted;
+/// otherwise they are kept, and still known to a later pruning run.
+pub async fn sync(
+    root: &Path,
+    images: &[Image],
+    options: &DownloadOptions,
+    jobs: usize,
+    prune: bool,
+) -> Result<MirrorSummary, String> {
+    fs::create_dir_all(root).map_err(|e| format!("Failed to create '{}': {e}", root.display()))?;
+    let previous = MirrorState::load(root)?;
+    let mut summary = MirrorSummary::default();
+    let mut entries: BTreeMap<String, MirrorEntry> = BTreeMap::new();
+
+    // The mirror keeps the files as published: no post-processing, no
+    // prompts.
+    let options = DownloadOptions {
+        existing: ExistingFile::Overwrite,
+        on_mismatch: match options.on_mismatch {
+            OnMismatch::Ask => OnMismatch::Redownload,
+            other => other,
+        },
+        decompress: false,
+        convert_to: None,
+        customize: None,
+        resize: None,
+        seed: None,
+        upload: None,
+        ..options.clone()
+    };
+
+    let mut items = Vec::new();
+    let mut directories: BTreeMap<String, HashSet<&str>> = BTreeMap::new();
+    for image in images {
+        let relative = upstream_path(image.url())?;
+        let path = path_string(&relative);
+        let (dir_url, file) = image
+            .url()
+            .rsplit_once('/')
+            .expect("upstream paths have a file name");
+        directories
+            .entry(format!("{dir_url}/"))
+            .or_default()
+            .insert(file);
+        let entry = MirrorEntry {
+            path: path.clone(),
+            url: image.url().to_string(),
+            image: Some(image.clone()),
+            size: image.size(),
+        };
+        if previous.holds(root, &path, image) {
+            summary.current += 1;
+            entries.insert(path, entry);
+            continue;
+        }
+        items.push((
+            BatchItem {
+                image: image.clone(),
+                dest_dir: root.join(relative.parent().expect("upstream paths have a parent")),
+                mirrors: repositories::mirror_urls(image.url()),
+            },
+            entry,
+        ));
+    }
+
+    let batch: Vec<BatchItem> = items.iter().map(|(item, _)| item.clone()).collect();
+    let results = download_batch(&batch, &options, jobs).await;
+    for ((_, mut entry), result) in items.into_iter().zip(results) {
+        match result {
+            Ok(_) => {
+                summary.downloaded += 1;
+                entry.size = fs::metadata(root.join(&entry.path))
+                    .ok()
+                    .map(|meta| meta.len());
+                entries.insert(entry.path.clone(), entry);
+            }
+            Err(CloudImagesError::Cancelled(reason)) => {
+                return Err(format!("Mirroring cancelled: {reason}"));
+            }
+            Err(err) => {
+                // Keep what an earlier run mirrored rather than pruning it.
+                if let Some(old) = previous.entry(&entry.path) {
+                    entries.insert(old.path.clone(), old.clone());
+                }
+                summary.failed.push((entry.url, err.to_string()));
+            }
+        }
+    }
+
+    let client = http::client();
+    for (dir_url, files) in &directories {
+        let listed = match listing::fetch(client, dir_url).await {
+            Ok(listed) => listed,
+            Err(err) => {
+                eprintln!("Warning: no checksum files mirrored for {dir_url}: {err:#}");
+                continue;
+            }
+        };
+        for name in listed
+            .iter()
+            .filter(|entry| !entry.dir && is_verification_file(&entry.name, files))
+            .map(|entry| entry.name.as_str())
+        {
+            let url = match sanitize::artifact_url(dir_url, name) {
+                Ok(url) => url,
+                Err(err) => {
+                    summary.failed.push((format!("{dir_url}{name}"), err));
+                    continue;
+                }
+            };
+            let fetched = async {
+                let relative = upstream_path(&url)?;
+                let data = retry::bytes(|| client.get(&url))
+                    .await
+                    .map_err(|e| e.to_string())?;
+                let changed = write_if_changed(&root.join(&relative), &data)?;
+                Ok::<_, String>((path_string(&relative), data.len() as u64, changed))
+            };
+            match fetched.await {
+                Ok((path, size, changed)) => {
+                    summary.updated += usize::from(changed);
+                    entries.insert(
+                        path.clone(),
+                        MirrorEntry {
+                            path,
+                            url,
+                            image: None,
+                            size: Some(size),
+                        },
+                    );
+                }
+                Err(err) => summary.failed.push((url, err)),
+            }
+        }
+    }
+
+    for old in previous.entries {
+        if entries.contains_key(&old.path) {
+            continue;
+        }
+        if prune {
+            remove(root, &root.join(&old.path));
+            summary.pruned += 1;
+        } else {
+            entries.insert(old.path.clone(), old);
+        }
+    }
+
+    MirrorState {
+        entries: entries.into_values().collect(),
+    }
+    .save(root)?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::{
+        MirrorEntry, MirrorFilter, MirrorState, is_verification_file, upstream_path,
+        write_if_changed,
+    };
+    use crate::cloud::{ChecksumKind, Image, ImageChecksum, sample_image};
+
+    fn image(version: &str, sha: &str) -> Image {
+        sample_image().with_version(version).with_source(
+            format!("https://mirror.invalid/debian/{version}/debian-12-genericcloud-amd64.qcow2"),
+            Some(ImageChecksum::new(ChecksumKind::Sha512, sha)),
+        )
+    }
+
+    #[test]
+    fn plans_paths_and_incremental_syncs() {
+        assert_eq!(
+            upstream_path("https://mirror.invalid/debian/20250210-2019/disk.qcow2?x=1").unwrap(),
+            PathBuf::from("mirror.invalid/debian/20250210-2019/disk.qcow2")
+        );
+        assert!(upstream_path("https://mirror.invalid/").is_err());
+
+        let images: HashSet<&str> = [
+            "debian-12-genericcloud-amd64.qcow2",
+            "AlmaLinux-9-GenericCloud-9.4-20240513.x86_64.qcow2",
+        ]
+        .into();
+        for name in [
+            "SHA512SUMS",
+            "SHA512SUMS.sign",
+            "debian-12-genericcloud-amd64.qcow2.sha256",
+            "AlmaLinux-9-GenericCloud-9.4-20240513.x86_64.qcow2.CHECKSUM.asc",
+        ] {
+            assert!(is_verification_file(name, &images), "{name}");
+        }
+        assert!(!is_verification_file(
+            "debian-12-nocloud-amd64.qcow2.sha256",
+            &images
+        ));
+        assert!(!is_verification_file(
+            "debian-12-genericcloud-amd64.qcow2",
+            &images
+        ));
+
+        let mut builds = vec![
+            image("20250101-0000", "aa"),
+            image("20250210-2019", "bb"),
+            image("20241201-0000", "cc"),
+        ];
+        let filter = MirrorFilter {
+            builds: 2,
+            ..MirrorFilter::default()
+        };
+        filter.newest_builds(&mut builds);
+        let versions: Vec<&str> = builds.iter().map(Image::version).collect();
+        assert_eq!(versions, ["20250101-0000", "20250210-2019"]);
+
+        let root = std::env::temp_dir().join(format!("cid-mirror-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let path = "mirror.invalid/debian/20250210-2019/debian-12-genericcloud-amd64.qcow2";
+        assert!(write_if_changed(&root.join(path), b"disk").unwrap());
+        assert!(!write_if_changed(&root.join(path), b"disk").unwrap());
+        let state = MirrorState {
+            entries: vec![MirrorEntry {
+                path: path.to_string(),
+                url: builds[1].url().to_string(),
+                image: Some(builds[1].clone()),
+                size: Some(4),
+            }],
+        };
+        state.save(&root).unwrap();
+        let state = MirrorState::load(&root).unwrap();
+        assert!(state.holds(&root, path, &builds[1]));
+        assert!(!state.holds(&root, path, &image("20250210-2019", "dd")));
+        fs::write(root.join(path), b"truncated").unwrap();
+        assert!(!state.holds(&root, path, &builds[1]));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,9 +1,74 @@
+#[cfg(feature = "deploy")]
+pub mod ansible;
+pub mod cancel;
+pub mod checksum;
+pub mod customize;
+pub mod decompress;
+#[cfg(feature = "server")]
+pub mod file_server;
+pub mod fixtures;
 pub mod fzf_invoker;
+pub mod gpg;
+pub mod http;
+pub mod http_cache;
+#[cfg(feature = "deploy")]
+pub mod ignition;
 pub mod image_resolver;
+#[cfg(feature = "deploy")]
+pub mod incus;
+pub mod library;
+#[cfg(feature = "deploy")]
+pub mod libvirt;
+pub mod listing;
+pub mod metalink;
+#[cfg(feature = "server")]
+pub mod metrics;
+#[cfg(feature = "mirror")]
+pub mod mirror;
+pub mod notify;
+#[cfg(feature = "oci")]
+pub mod oci;
+#[cfg(feature = "deploy")]
+pub mod openstack;
+#[cfg(feature = "deploy")]
+pub mod packer;
+pub mod paths;
+pub mod progress;
+pub mod provenance;
+#[cfg(feature = "deploy")]
+pub mod proxmox;
+pub mod qemu_img;
+pub mod report;
+pub mod retention;
+pub mod retry;
+#[cfg(feature = "s3")]
+pub mod s3;
+pub mod sanitize;
+#[cfg(feature = "deploy")]
+pub mod schedule;
+pub mod seed;
+pub mod selection;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "mirror")]
+pub mod simplestreams;
+#[cfg(feature = "deploy")]
+pub mod terraform;
+pub mod throttle;
+pub mod trace;
+pub mod trust;
+#[cfg(feature = "deploy")]
+pub mod vm;
+pub mod xattr;
+pub mod zsync;
 
 use self::fzf_invoker::FzfInvoker;
+use crate::cloud::BuildId;
+use crate::error::CloudImagesError;
 use anyhow::Result;
 use anyhow::bail;
+use std::env;
+use std::path::PathBuf;
 
 /// Wrapper around the `termenu` picker that keeps the UX consistent across the
 /// project. The helper converts the supplied items into `String`s so callers do
@@ -14,20 +79,178 @@ pub fn choose_one<S: ToString>(title: &str, items: Vec<S>) -> Result<String> {
     if let Some(choice) = picker.invoke() {
         Ok(choice)
     } else {
-        bail!("No selection made");
+        bail!(CloudImagesError::Cancelled("No selection made".to_string()));
     }
 }
 
-/// Return reasonable arch options per distro
-///
-/// The lists are intentionally small to keep the menus manageable, and can be
-/// expanded without touching the call sites.
-pub fn arch_options_for(distro: &str) -> Vec<&'static str> {
-    match distro {
-        // You can widen these as your indexers evolve
-        "Ubuntu" => vec!["amd64", "arm64", "ppc64el", "s390x"],
-        "Debian" => vec!["amd64", "arm64"], // TODO(debian): confirm available arches from debian_list(...)
-        "AlmaLinux" => vec!["x86_64", "aarch64"],
-        _ => vec!["amd64"],
+/// Return the entry of `items` matching `preset` (case-insensitively) or fall
+/// back to the interactive picker when no preset was given. A preset that
+/// matches nothing is an error so typos are not silently turned into prompts.
+pub fn choose_or_preset<S: ToString>(
+    title: &str,
+    preset: Option<&str>,
+    items: Vec<S>,
+) -> Result<String> {
+    let Some(preset) = preset else {
+        return choose_one(title, items);
+    };
+
+    let items: Vec<String> = items.into_iter().map(|s| s.to_string()).collect();
+    match items.iter().find(|item| item.eq_ignore_ascii_case(preset)) {
+        Some(item) => Ok(item.clone()),
+        None => bail!(
+            "'{preset}' is not a valid choice for '{title}' (available: {})",
+            items.join(", ")
+        ),
+    }
+}
+
+/// Find the executable `name` on `PATH` (with `.exe` appended on Windows).
+pub fn find_program(name: &str) -> Option<PathBuf> {
+    let binary = if cfg!(windows) {
+        format!("{name}.exe")
+    } else {
+        name.to_string()
+    };
+    env::var_os("PATH")
+        .iter()
+        .flat_map(env::split_paths)
+        .map(|dir| dir.join(&binary))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Render a byte count with binary units (e.g. `312.4 MiB`) for picker
+/// labels. Unknown sizes are shown as `?`.
+pub fn human_size(bytes: Option<u64>) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let Some(bytes) = bytes else {
+        return "?".to_string();
+    };
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+/// Menu entry that expands the trimmed build list back to every build.
+const SHOW_ALL_BUILDS: &str = "Show all builds…";
+
+/// Split a newest-first list of builds into the entries shown by default and
+/// the number of builds hidden behind the "show all" escape hatch. `latest`
+/// is always kept and does not count towards `max_builds`.
+fn recent_builds(versions: &[String], max_builds: usize) -> (Vec<String>, usize) {
+    let mut shown = Vec::new();
+    let mut dated = 0;
+    for version in versions {
+        if version == "latest" {
+            shown.push(version.clone());
+        } else if dated < max_builds {
+            shown.push(version.clone());
+            dated += 1;
+        }
+    }
+    let hidden = versions.len() - shown.len();
+    (shown, hidden)
+}
+
+/// Prompt for an image build, optionally limited to the `max_builds` most
+/// recent entries. `versions` must already be sorted newest-first. A `preset`
+/// build is matched against the full list and skips the prompt.
+pub fn choose_build(
+    title: &str,
+    preset: Option<&str>,
+    versions: Vec<String>,
+    max_builds: Option<usize>,
+) -> Result<String> {
+    if preset.is_some() {
+        return choose_or_preset(title, preset, versions);
+    }
+
+    let Some(limit) = max_builds else {
+        return choose_labelled_build(title, versions, None);
+    };
+
+    let (shown, hidden) = recent_builds(&versions, limit);
+    if hidden == 0 {
+        return choose_labelled_build(title, shown, None);
+    }
+
+    let show_all = format!("{SHOW_ALL_BUILDS} ({hidden} more)");
+    let choice = choose_labelled_build(title, shown, Some(&show_all))?;
+    if choice == show_all {
+        choose_labelled_build(title, versions, None)
+    } else {
+        Ok(choice)
+    }
+}
+
+/// Prompt for one of `builds`, each shown with its age
+/// ([`BuildId::label`]), plus the `extra` entry when given. Returns the
+/// chosen build (or `extra`) without the decoration.
+fn choose_labelled_build(title: &str, builds: Vec<String>, extra: Option<&str>) -> Result<String> {
+    let mut labels: Vec<String> = builds.iter().map(|b| BuildId::new(b).label()).collect();
+    labels.extend(extra.map(str::to_string));
+    let choice = choose_one(title, labels.clone())?;
+    let index = labels
+        .iter()
+        .position(|l| *l == choice)
+        .expect("chosen label must be offered");
+    Ok(builds.get(index).cloned().unwrap_or(choice))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{choose_or_preset, human_size, recent_builds};
+
+    fn builds(items: &[&str]) -> Vec<String> {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn keeps_latest_and_most_recent_builds() {
+        let versions = builds(&["latest", "20250901-0000", "20250801-0000", "20250701-0000"]);
+        let (shown, hidden) = recent_builds(&versions, 2);
+        assert_eq!(shown, builds(&["latest", "20250901-0000", "20250801-0000"]));
+        assert_eq!(hidden, 1);
+    }
+
+    #[test]
+    fn nothing_hidden_when_under_limit() {
+        let versions = builds(&["20250901", "20250801"]);
+        let (shown, hidden) = recent_builds(&versions, 5);
+        assert_eq!(shown, versions);
+        assert_eq!(hidden, 0);
+    }
+
+    #[test]
+    fn human_size_uses_binary_units() {
+        assert_eq!(human_size(None), "?");
+        assert_eq!(human_size(Some(512)), "512 B");
+        assert_eq!(human_size(Some(1536)), "1.5 KiB");
+        assert_eq!(human_size(Some(2 * 1024 * 1024 * 1024)), "2.0 GiB");
+    }
+
+    #[test]
+    fn preset_matches_case_insensitively() {
+        let choice = choose_or_preset("Select Distro", Some("debian"), vec!["Ubuntu", "Debian"])
+            .expect("preset should match");
+        assert_eq!(choice, "Debian");
+    }
+
+    #[test]
+    fn unknown_preset_is_an_error() {
+        assert!(
+            choose_or_preset("Select Distro", Some("fedora"), vec!["Ubuntu", "Debian"]).is_err()
+        );
     }
 }
@@ -0,0 +1,436 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use reqwest::Url;
+use reqwest::header::CONTENT_TYPE;
+use serde::Deserialize;
+
+use crate::cloud::Image;
+use crate::error::CloudImagesError;
+use crate::helpers::{http, retry};
+use crate::repositories::Secret;
+
+/// What a notification is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// A newer build of a tracked image was published.
+    NewRelease,
+    /// A long download finished and verified.
+    DownloadFinished,
+    /// A long download failed.
+    DownloadFailed,
+}
+
+impl EventKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EventKind::NewRelease => "new_release",
+            EventKind::DownloadFinished => "download_finished",
+            EventKind::DownloadFailed => "download_failed",
+        }
+    }
+}
+
+/// An event worth telling people about.
+#[derive(Debug, Clone, Copy)]
+pub enum Event<'a> {
+    /// `image` is a newer build than `previous`.
+    NewRelease {
+        image: &'a Image,
+        previous: Option<&'a str>,
+    },
+    /// Downloading `image` took `elapsed` and ended with `result`.
+    Download {
+        image: &'a Image,
+        elapsed: Duration,
+        result: &'a Result<String, CloudImagesError>,
+    },
+}
+
+impl Event<'_> {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::NewRelease { .. } => EventKind::NewRelease,
+            Event::Download { result: Ok(_), .. } => EventKind::DownloadFinished,
+            Event::Download { result: Err(_), .. } => EventKind::DownloadFailed,
+        }
+    }
+
+    pub fn image(&self) -> &Image {
+        match self {
+            Event::NewRelease { image, .. } | Event::Download { image, .. } => image,
+        }
+    }
+
+    /// One line describing the event, e.g. `Downloaded debian bookworm
+    /// amd64 20250210-2019 in 12m 3s`.
+    pub fn message(&self) -> String {
+        let image = self.image();
+        let name = format!(
+            "{} {} {} {}",
+            image.os(),
+            image.name(),
+            image.arch_name(),
+            image.version()
+        );
+        match self {
+            Event::NewRelease {
+                previous: Some(previous),
+                ..
+            } => format!("New build {name} (was {previous})"),
+            Event::NewRelease { previous: None, .. } => format!("New build {name}"),
+            Event::Download {
+                elapsed,
+                result: Ok(_),
+                ..
+            } => format!("Downloaded {name} in {}", duration_label(*elapsed)),
+            Event::Download {
+                elapsed,
+                result: Err(err),
+                ..
+            } => format!(
+                "Downloading {name} failed after {}: {err}",
+                duration_label(*elapsed)
+            ),
+        }
+    }
+
+    /// Values of the template placeholders.
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        let image = self.image();
+        let mut fields = vec![
+            ("event", self.kind().as_str().to_string()),
+            ("message", self.message()),
+            ("distro", image.os().to_string()),
+            ("release", image.name().to_string()),
+            ("distro_version", image.distro_version().to_string()),
+            ("arch", image.arch_name().to_string()),
+            ("variant", image.variant().to_string()),
+            ("format", image.format().as_str().to_string()),
+            ("version", image.version().to_string()),
+            ("url", image.url().to_string()),
+        ];
+        match self {
+            Event::NewRelease { previous, .. } => {
+                fields.push(("previous", previous.unwrap_or_default().to_string()));
+            }
+            Event::Download {
+                elapsed, result, ..
+            } => {
+                fields.push(("duration", elapsed.as_secs().to_string()));
+                if let Err(err) = result {
+                    fields.push(("error", err.to_string()));
+                }
+            }
+        }
+        fields
+    }
+}
+
+/// `12s`, `3m 5s` or `1h 2m`.
+pub fn duration_label(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..60 => format!("{secs}s"),
+        60..3600 => format!("{}m {}s", secs / 60, secs % 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+/// Payload shape of a webhook without its own `template`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Preset {
+    /// Every field as a JSON object.
+    #[default]
+    Json,
+    /// Slack incoming webhook.
+    Slack,
+    /// Discord webhook.
+    Discord,
+    /// Matrix, through a hookshot generic webhook.
+    Matrix,
+}
+
+impl Preset {
+    fn template(self) -> &'static str {
+        match self {
+            Preset::Json => {
+                r#"{"event": "{event}", "message": "{message}", "distro": "{distro}", "release": "{release}", "arch": "{arch}", "variant": "{variant}", "format": "{format}", "version": "{version}", "url": "{url}"}"#
+            }
+            Preset::Slack => r#"{"text": "{message}"}"#,
+            Preset::Discord => r#"{"content": "{message}"}"#,
+            Preset::Matrix => r#"{"text": "{message}", "msgtype": "m.notice"}"#,
+        }
+    }
+}
+
+/// One `[[notifications.webhooks]]` entry of the config file:
+///
+/// ```toml
+/// [[notifications.webhooks]]
+/// url = "https://hooks.slack.com/services/T000/B000/XXXX"
+/// preset = "slack"
+/// events = ["new_release", "download_failed"]
+///
+/// [[notifications.webhooks]]
+/// url = "https://ci.example.com/hooks/images"
+/// template = '{"ref": "{distro}-{release}", "build": "{version}"}'
+/// headers = { Authorization = { env = "IMAGES_HOOK_AUTH" } }
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Webhook {
+    pub url: String,
+    #[serde(default)]
+    pub preset: Preset,
+    /// JSON body with `{placeholders}` (see [`render`]); overrides `preset`.
+    pub template: Option<String>,
+    /// Events posted; all of them when empty.
+    #[serde(default)]
+    pub events: Vec<EventKind>,
+    /// Extra headers, e.g. a token, each given inline or as
+    /// `{ env = "VAR" }` so the config file can be shared without it.
+    #[serde(default)]
+    pub headers: BTreeMap<String, Secret>,
+}
+
+impl Webhook {
+    fn wants(&self, kind: EventKind) -> bool {
+        self.events.is_empty() || self.events.contains(&kind)
+    }
+
+    /// The scheme and host of the URL, for messages: Slack and Discord
+    /// webhook URLs carry their token in the path.
+    fn redacted_url(&self) -> String {
+        match Url::parse(&self.url) {
+            Ok(url) => format!("{}://{}", url.scheme(), url.host_str().unwrap_or_default()),
+            Err(_) => "an invalid webhook URL".to_string(),
+        }
+    }
+}
+
+/// Escape `value` for the inside of a JSON string.
+fn json_escape(value: &str) -> String {
+    let quoted = serde_json::to_string(value).expect("strings serialise");
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+/// Fill the `{event}`, `{message}`, `{distro}`, `{release}`,
+/// `{distro_version}`, `{arch}`, `{variant}`, `{format}`, `{version}` and
+/// `{url}` placeholders of `template`, plus `{previous}` for new releases
+/// and `{duration}` (seconds) and `{error}` for downloads. Values are JSON
+/// escaped, for use inside strings; unknown placeholders stay as they are.
+pub fn render(template: &str, event: &Event) -> String {
+    let fields = event.fields();
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest.find('}').and_then(|end| {
+            let name = &rest[1..end];
+            fields
+                .iter()
+                .find(|(field, _)| *field == name)
+                .map(|(_, value)| (end, json_escape(value)))
+        });
+        match value {
+            Some((end, value)) => {
+                out.push_str(&value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Posts events to the configured webhooks and, with `desktop`, shows
+/// finished and failed downloads as desktop notifications. Downloads are
+/// only announced once they took at least `min_duration`; short ones finish
+/// while the user is still watching.
+#[derive(Debug, Clone, Default)]
+pub struct Notifier {
+    pub webhooks: Vec<Webhook>,
+    pub desktop: bool,
+    pub min_duration: Duration,
+}
+
+impl Notifier {
+    /// Whether any event would be posted anywhere.
+    pub fn is_empty(&self) -> bool {
+        self.webhooks.is_empty() && !self.desktop
+    }
+
+    /// Post `event` to every webhook that wants it. Failures are reported on
+    /// stderr and never fail the operation the event is about.
+    pub async fn send(&self, event: &Event<'_>) {
+        for hook in self.webhooks.iter().filter(|hook| hook.wants(event.kind())) {
+            if let Err(err) = post(hook, event).await {
+                eprintln!("Warning: notifying {} failed: {err}", hook.redacted_url());
+            }
+        }
+    }
+
+    /// Announce the outcome of downloading `image` when it took long enough.
+    /// Cancelled downloads are not announced: whoever cancelled them knows.
+    pub async fn download(
+        &self,
+        image: &Image,
+        elapsed: Duration,
+        result: &Result<String, CloudImagesError>,
+    ) {
+        if elapsed < self.min_duration || matches!(result, Err(CloudImagesError::Cancelled(_))) {
+            return;
+        }
+        let event = Event::Download {
+            image,
+            elapsed,
+            result,
+        };
+        if self.desktop
+            && let Err(err) = show(&event).await
+        {
+            eprintln!("Warning: showing a desktop notification failed: {err}");
+        }
+        self.send(&event).await;
+    }
+}
+
+/// Summary line of the desktop notification of `event`.
+#[cfg_attr(not(feature = "desktop-notifications"), allow(dead_code))]
+fn summary(event: &Event) -> &'static str {
+    match event.kind() {
+        EventKind::NewRelease => "New cloud image build",
+        EventKind::DownloadFinished => "Download finished",
+        EventKind::DownloadFailed => "Download failed",
+    }
+}
+
+/// Show `event` through the desktop's notification service (D-Bus on
+/// Linux and the BSDs, Notification Center on macOS).
+#[cfg(feature = "desktop-notifications")]
+async fn show(event: &Event<'_>) -> Result<(), String> {
+    let mut notification = notify_rust::Notification::new();
+    notification
+        .appname("cloud-images-downloader")
+        .summary(summary(event))
+        .body(&event.message());
+    // Talking to the notification service blocks.
+    tokio::task::spawn_blocking(move || notification.show().map(|_| ()))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(not(feature = "desktop-notifications"))]
+async fn show(_event: &Event<'_>) -> Result<(), String> {
+    Err("built without the desktop-notifications feature".to_string())
+}
+
+async fn post(hook: &Webhook, event: &Event<'_>) -> Result<(), String> {
+    let template = hook
+        .template
+        .as_deref()
+        .unwrap_or_else(|| hook.preset.template());
+    let body = render(template, event);
+    let mut headers = Vec::new();
+    for (name, secret) in &hook.headers {
+        let value = secret.resolve().map_err(|e| e.to_string())?;
+        let (name, mut value) = http::parse_header(&format!("{name}: {value}"))
+            .map_err(|_| format!("invalid value for the {name} header"))?;
+        value.set_sensitive(true);
+        headers.push((name, value));
+    }
+    retry::send(|| {
+        let mut request = http::client()
+            .post(&hook.url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.clone());
+        for (name, value) in &headers {
+            request = request.header(name, value.clone());
+        }
+        request
+    })
+    .await
+    .and_then(|res| res.error_for_status())
+    .map(|_| ())
+    .map_err(|e| e.without_url().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Event, EventKind, Preset, Secret, Webhook, duration_label, render, summary};
+    use crate::cloud::sample_image;
+    use crate::error::CloudImagesError;
+
+    #[test]
+    fn renders_templates() {
+        let image = sample_image();
+        let release = Event::NewRelease {
+            image: &image,
+            previous: Some("20250110-1950"),
+        };
+        assert_eq!(
+            render(Preset::Slack.template(), &release),
+            r#"{"text": "New build debian bookworm amd64 20250210-2019 (was 20250110-1950)"}"#
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&render(Preset::Json.template(), &release)).unwrap();
+        assert_eq!(json["event"], "new_release");
+        assert_eq!(json["variant"], "genericcloud");
+
+        let result = Err(CloudImagesError::Network("reset by \"peer\"".to_string()));
+        let failed = Event::Download {
+            image: &image,
+            elapsed: Duration::from_secs(725),
+            result: &result,
+        };
+        assert_eq!(failed.kind(), EventKind::DownloadFailed);
+        assert_eq!(summary(&failed), "Download failed");
+        assert_eq!(
+            render(
+                r#"{"e": "{error}", "took": {duration}, "x": "{nope}"}"#,
+                &failed
+            ),
+            r#"{"e": "reset by \"peer\"", "took": 725, "x": "{nope}"}"#
+        );
+        assert_eq!(duration_label(Duration::from_secs(725)), "12m 5s");
+
+        let hook: Webhook = toml::from_str(
+            r#"
+            url = "https://example.invalid/hook"
+            preset = "matrix"
+            events = ["download_failed"]
+            "#,
+        )
+        .unwrap();
+        assert!(hook.wants(EventKind::DownloadFailed));
+        assert!(!hook.wants(EventKind::NewRelease));
+    }
+
+    #[test]
+    fn keeps_webhook_secrets_out_of_messages() {
+        let hook: Webhook = toml::from_str(
+            r#"
+            url = "https://hooks.slack.com/services/T000/B000/XXXX"
+            headers = { Authorization = { env = "NOTIFY_TEST_TOKEN" }, X-Team = "images" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(hook.redacted_url(), "https://hooks.slack.com");
+        assert!(matches!(
+            hook.headers["Authorization"],
+            Secret::Env { ref env } if env == "NOTIFY_TEST_TOKEN"
+        ));
+        assert!(matches!(hook.headers["X-Team"], Secret::Literal(ref v) if v == "images"));
+    }
+}
@@ -0,0 +1,168 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use chrono::Utc;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use super::{Blob, Config};
+use crate::cloud::Arch;
+
+/// Uncompressed layer; qcow2 images barely compress and KubeVirt nodes pull
+/// them once per node.
+pub const LAYER_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar";
+const CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.image.config.v1+json";
+
+/// The `qemu` user KubeVirt's virt-launcher runs as.
+const QEMU_UID: u64 = 107;
+
+/// `GOARCH` spelling of `arch`, as image configs carry it.
+fn oci_arch(arch: Arch) -> &'static str {
+    match arch {
+        Arch::Amd64 => "amd64",
+        Arch::Arm64 => "arm64",
+        Arch::Armhf => "arm",
+        Arch::Ppc64le => "ppc64le",
+        Arch::S390x => "s390x",
+        Arch::Riscv64 => "riscv64",
+    }
+}
+
+/// Writer hashing everything it passes on.
+struct Hashing<W> {
+    inner: W,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Write the single layer of a containerdisk to `output`: `disk` as
+/// `/disk/<name>`, readable by the qemu user only, which is where KubeVirt
+/// looks for it.
+pub fn write_layer(disk: &Path, output: &Path) -> Result<Blob, String> {
+    let name = disk
+        .file_name()
+        .ok_or_else(|| format!("'{}' names no file", disk.display()))?;
+    let failed = |e: io::Error| format!("Failed to write '{}': {e}", output.display());
+    let file = File::create(output).map_err(failed)?;
+    let mut tar = tar::Builder::new(Hashing {
+        inner: BufWriter::new(file),
+        hasher: Sha256::new(),
+        size: 0,
+    });
+    let mtime = Utc::now().timestamp().max(0) as u64;
+
+    let mut dir = tar::Header::new_gnu();
+    dir.set_entry_type(tar::EntryType::Directory);
+    dir.set_mode(0o555);
+    dir.set_uid(QEMU_UID);
+    dir.set_gid(QEMU_UID);
+    dir.set_mtime(mtime);
+    dir.set_size(0);
+    tar.append_data(&mut dir, "disk/", io::empty())
+        .map_err(failed)?;
+
+    let mut source =
+        File::open(disk).map_err(|e| format!("Failed to open '{}': {e}", disk.display()))?;
+    let size = source
+        .metadata()
+        .map_err(|e| format!("Failed to read '{}': {e}", disk.display()))?
+        .len();
+    let mut header = tar::Header::new_gnu();
+    header.set_mode(0o440);
+    header.set_uid(QEMU_UID);
+    header.set_gid(QEMU_UID);
+    header.set_mtime(mtime);
+    header.set_size(size);
+    tar.append_data(&mut header, Path::new("disk").join(name), &mut source)
+        .map_err(failed)?;
+
+    let mut hashing = tar.into_inner().map_err(failed)?;
+    hashing.flush().map_err(failed)?;
+    Ok(Blob {
+        path: output.to_path_buf(),
+        media_type: LAYER_MEDIA_TYPE.to_string(),
+        digest: format!("sha256:{}", hex::encode(hashing.hasher.finalize())),
+        size: hashing.size,
+        title: None,
+    })
+}
+
+/// Image config of a containerdisk for `arch` whose only layer has the
+/// digest `layer` (uncompressed, so it is also the diff id).
+pub fn config(arch: Arch, layer: &str) -> Config {
+    let config = json!({
+        "created": Utc::now().to_rfc3339(),
+        "architecture": oci_arch(arch),
+        "os": "linux",
+        "config": {},
+        "rootfs": {"type": "layers", "diff_ids": [layer]},
+    });
+    Config {
+        media_type: CONFIG_MEDIA_TYPE.to_string(),
+        data: serde_json::to_vec(&config).expect("configs serialise"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{config, write_layer};
+    use crate::cloud::Arch;
+    use std::io::Read;
+
+    #[test]
+    fn puts_the_disk_where_kubevirt_looks() {
+        let dir = std::env::temp_dir().join(format!("cid-containerdisk-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let disk = dir.join("noble.qcow2");
+        std::fs::write(&disk, b"QFI\xfb").unwrap();
+        let layer = write_layer(&disk, &dir.join("layer.tar")).unwrap();
+
+        let mut data = Vec::new();
+        std::fs::File::open(&layer.path)
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(layer.size, data.len() as u64);
+        let mut archive = tar::Archive::new(data.as_slice());
+        let entries: Vec<_> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let header = entry.header();
+                (
+                    entry.path().unwrap().to_string_lossy().into_owned(),
+                    header.mode().unwrap(),
+                    header.uid().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                ("disk/".to_string(), 0o555, 107),
+                ("disk/noble.qcow2".to_string(), 0o440, 107)
+            ]
+        );
+
+        let config: serde_json::Value =
+            serde_json::from_slice(&config(Arch::Arm64, &layer.digest).data).unwrap();
+        assert_eq!(config["architecture"], "arm64");
+        assert_eq!(config["rootfs"]["diff_ids"][0], layer.digest.as_str());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,573 @@
+pub mod containerdisk;
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use reqwest::header::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, LOCATION, WWW_AUTHENTICATE};
+use reqwest::{Body, RequestBuilder, Response, StatusCode, Url};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+
+use crate::helpers::{http, paths};
+
+pub const MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+/// `artifactType` of pushed images, as `oras` shows it.
+pub const ARTIFACT_TYPE: &str = "application/vnd.cloud-images.image.v1";
+pub const PROVENANCE_MEDIA_TYPE: &str = "application/vnd.in-toto+json";
+const EMPTY_MEDIA_TYPE: &str = "application/vnd.oci.empty.v1+json";
+const EMPTY_CONFIG: &[u8] = b"{}";
+
+/// `registry/repository:tag` as given to `push oci://...`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    /// Host (and port) of the registry, e.g. `ghcr.io`.
+    pub registry: String,
+    pub repository: String,
+    pub tag: String,
+}
+
+impl Reference {
+    /// Parse `[oci://]registry/repository[:tag]`; the tag defaults to
+    /// `latest`.
+    pub fn parse(reference: &str) -> Result<Self, String> {
+        let invalid = || format!("'{reference}' is not an oci://registry/repository:tag reference");
+        let rest = reference.strip_prefix("oci://").unwrap_or(reference);
+        let (registry, path) = rest.split_once('/').ok_or_else(invalid)?;
+        let (repository, tag) = match path.rsplit_once(':') {
+            Some((repository, tag)) if !tag.contains('/') => (repository, tag),
+            _ => (path, "latest"),
+        };
+        let valid_repository = !repository.is_empty()
+            && repository.split('/').all(|component| {
+                !component.is_empty()
+                    && component
+                        .chars()
+                        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "._-".contains(c))
+            });
+        let valid_tag = !tag.is_empty()
+            && tag.len() <= 128
+            && tag
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c));
+        if registry.is_empty() || !valid_repository || !valid_tag {
+            return Err(invalid());
+        }
+        Ok(Self {
+            registry: registry.to_string(),
+            repository: repository.to_string(),
+            tag: tag.to_string(),
+        })
+    }
+
+    /// Host serving the registry API; Docker Hub answers elsewhere.
+    fn api_host(&self) -> &str {
+        match self.registry.as_str() {
+            "docker.io" | "index.docker.io" => "registry-1.docker.io",
+            host => host,
+        }
+    }
+}
+
+/// A file stored as a blob: its digest, size and media type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Blob {
+    pub path: PathBuf,
+    pub media_type: String,
+    pub digest: String,
+    pub size: u64,
+    /// File name `oras pull` restores the blob as.
+    pub title: Option<String>,
+}
+
+impl Blob {
+    /// Describe the file at `path`, hashing it, titled with its name.
+    pub fn from_file(path: &Path, media_type: &str) -> Result<Self, String> {
+        let mut file =
+            File::open(path).map_err(|e| format!("Failed to open '{}': {e}", path.display()))?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0u8; 1 << 20];
+        let mut size = 0;
+        loop {
+            let n = file
+                .read(&mut buf)
+                .map_err(|e| format!("Failed to read '{}': {e}", path.display()))?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            size += n as u64;
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            media_type: media_type.to_string(),
+            digest: format!("sha256:{}", hex::encode(hasher.finalize())),
+            size,
+            title: path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned()),
+        })
+    }
+
+    fn descriptor(&self) -> Value {
+        let mut descriptor = json!({
+            "mediaType": self.media_type,
+            "digest": self.digest,
+            "size": self.size,
+        });
+        if let Some(title) = &self.title {
+            descriptor["annotations"] = json!({ "org.opencontainers.image.title": title });
+        }
+        descriptor
+    }
+}
+
+/// The config blob of a manifest, small enough to keep in memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub media_type: String,
+    pub data: Vec<u8>,
+}
+
+impl Config {
+    /// The empty config of artifacts.
+    pub fn empty() -> Self {
+        Self {
+            media_type: EMPTY_MEDIA_TYPE.to_string(),
+            data: EMPTY_CONFIG.to_vec(),
+        }
+    }
+
+    fn digest(&self) -> String {
+        sha256_digest(&self.data)
+    }
+
+    fn descriptor(&self) -> Value {
+        json!({
+            "mediaType": self.media_type,
+            "digest": self.digest(),
+            "size": self.data.len(),
+        })
+    }
+}
+
+/// Media type of a disk image layer, e.g.
+/// `application/vnd.cloud-images.disk.qcow2`.
+pub fn disk_media_type(format: &str) -> String {
+    format!("application/vnd.cloud-images.disk.{format}")
+}
+
+fn sha256_digest(data: &[u8]) -> String {
+    format!("sha256:{}", hex::encode(Sha256::digest(data)))
+}
+
+/// Image manifest of `config` and `layers`, with `annotations` on the
+/// manifest itself; an OCI 1.1 artifact when `artifact_type` is given.
+pub fn manifest(
+    config: &Config,
+    layers: &[Blob],
+    artifact_type: Option<&str>,
+    annotations: &[(&str, String)],
+) -> Value {
+    let mut manifest = json!({
+        "schemaVersion": 2,
+        "mediaType": MANIFEST_MEDIA_TYPE,
+        "config": config.descriptor(),
+        "layers": layers.iter().map(Blob::descriptor).collect::<Vec<_>>(),
+        "annotations": annotations
+            .iter()
+            .map(|(key, value)| (key.to_string(), json!(value)))
+            .collect::<serde_json::Map<_, _>>(),
+    });
+    if let Some(artifact_type) = artifact_type {
+        manifest["artifactType"] = json!(artifact_type);
+    }
+    manifest
+}
+
+#[derive(Debug, Deserialize)]
+struct AuthFile {
+    #[serde(default)]
+    auths: std::collections::HashMap<String, AuthEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AuthEntry {
+    auth: Option<String>,
+}
+
+/// Docker and Podman credential files, in the order they are read.
+fn auth_files() -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    if let Some(file) = std::env::var_os("REGISTRY_AUTH_FILE") {
+        files.push(PathBuf::from(file));
+    }
+    if let Some(dir) = std::env::var_os("XDG_RUNTIME_DIR") {
+        files.push(Path::new(&dir).join("containers/auth.json"));
+    }
+    match std::env::var_os("DOCKER_CONFIG") {
+        Some(dir) => files.push(Path::new(&dir).join("config.json")),
+        None => files.extend(paths::home_dir().map(|home| home.join(".docker/config.json"))),
+    }
+    files
+}
+
+/// `user:password` stored for `registry` in the auth file `text`.
+fn stored_credentials(text: &str, registry: &str) -> Option<(String, String)> {
+    let file: AuthFile = serde_json::from_str(text).ok()?;
+    let keys = [
+        registry.to_string(),
+        format!("https://{registry}"),
+        format!("https://{registry}/v1/"),
+    ];
+    let docker_hub = matches!(registry, "docker.io" | "index.docker.io")
+        .then_some("https://index.docker.io/v1/".to_string());
+    let auth = keys
+        .iter()
+        .chain(docker_hub.iter())
+        .find_map(|key| file.auths.get(key)?.auth.clone())?;
+    let decoded = String::from_utf8(BASE64.decode(auth).ok()?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+/// Credentials for `registry`: `$REGISTRY_USERNAME` / `$REGISTRY_PASSWORD`,
+/// else what `docker login` or `podman login` stored.
+fn credentials(registry: &str) -> Option<(String, String)> {
+    if let (Ok(user), Ok(password)) = (
+        std::env::var("REGISTRY_USERNAME"),
+        std::env::var("REGISTRY_PASSWORD"),
+    ) {
+        return Some((user, password));
+    }
+    auth_files().iter().find_map(|file| {
+        let text = fs::read_to_string(file).ok()?;
+        stored_credentials(&text, registry)
+    })
+}
+
+/// Parameters of a `WWW-Authenticate: Bearer realm="...",service="..."`
+/// challenge.
+fn challenge_params(challenge: &str) -> Vec<(String, String)> {
+    let Some(params) = challenge.strip_prefix("Bearer ") else {
+        return Vec::new();
+    };
+    let mut parsed = Vec::new();
+    let mut rest = params.trim();
+    while let Some((key, value)) = rest.split_once('=') {
+        let key = key.trim().trim_start_matches(',').trim().to_string();
+        let (value, tail) = match value.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+            None => value.split_once(',').unwrap_or((value, "")),
+        };
+        parsed.push((key, value.to_string()));
+        rest = tail.trim_start_matches(',').trim();
+    }
+    parsed
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+}
+
+/// One repository of a registry, authenticated lazily on the first `401`.
+#[derive(Debug)]
+pub struct Registry {
+    reference: Reference,
+    base: Url,
+    credentials: Option<(String, String)>,
+    authorization: Mutex<Option<String>>,
+}
+
+impl Registry {
+    /// Talk to the registry of `reference` over HTTPS, or plain HTTP for
+    /// local registries with `plain_http`.
+    pub fn new(reference: &Reference, plain_http: bool) -> Result<Self, String> {
+        let scheme = if plain_http { "http" } else { "https" };
+        let base = Url::parse(&format!(
+            "{scheme}://{}/v2/{}/",
+            reference.api_host(),
+            reference.repository
+        ))
+        .map_err(|e| format!("Invalid registry '{}': {e}", reference.registry))?;
+        Ok(Self {
+            credentials: credentials(&reference.registry),
+            reference: reference.clone(),
+            base,
+            authorization: Mutex::new(None),
+        })
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match self.authorization.lock().expect("poisoned").clone() {
+            Some(authorization) => request.header(AUTHORIZATION, authorization),
+            None => request,
+        }
+    }
+
+    /// Answer the challenge of `res`: a bearer token from the realm it
+    /// names, or the stored credentials for basic authentication.
+    async fn authenticate(&self, res: &Response) -> Result<(), String> {
+        let challenge = res
+            .headers()
+            .get(WWW_AUTHENTICATE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let basic = self.credentials.as_ref().map(|(user, password)| {
+            format!("Basic {}", BASE64.encode(format!("{user}:{password}")))
+        });
+        let authorization = if challenge.starts_with("Bearer ") {
+            let params = challenge_params(&challenge);
+            let realm = params
+                .iter()
+                .find(|(key, _)| key == "realm")
+                .map(|(_, realm)| realm.clone())
+                .ok_or_else(|| format!("Unsupported registry challenge '{challenge}'"))?;
+            let mut url =
+                Url::parse(&realm).map_err(|e| format!("Invalid token realm '{realm}': {e}"))?;
+            {
+                let mut query = url.query_pairs_mut();
+                if let Some((_, service)) = params.iter().find(|(key, _)| key == "service") {
+                    query.append_pair("service", service);
+                }
+                query.append_pair(
+                    "scope",
+                    &format!("repository:{}:pull,push", self.reference.repository),
+                );
+            }
+            let mut request = http::client().get(url);
+            if let Some(basic) = &basic {
+                request = request.header(AUTHORIZATION, basic);
+            }
+            let res = http::send(request)
+                .await
+                .map_err(|e| format!("Registry token request failed: {e}"))?;
+            let status = res.status();
+            if !status.is_success() {
+                return Err(format!(
+                    "{} refused a token ({status}); log in with `docker login {}` or set \
+                     REGISTRY_USERNAME and REGISTRY_PASSWORD",
+                    self.reference.registry, self.reference.registry
+                ));
+            }
+            let token: TokenResponse = res
+                .json()
+                .await
+                .map_err(|e| format!("Unexpected token response: {e}"))?;
+            let token = token
+                .token
+                .or(token.access_token)
+                .ok_or("The registry returned no token")?;
+            format!("Bearer {token}")
+        } else {
+            basic.ok_or_else(|| {
+                format!(
+                    "{} needs credentials; log in with `docker login {}` or set \
+                     REGISTRY_USERNAME and REGISTRY_PASSWORD",
+                    self.reference.registry, self.reference.registry
+                )
+            })?
+        };
+        *self.authorization.lock().expect("poisoned") = Some(authorization);
+        Ok(())
+    }
+
+    /// Send the request `make_request` builds, authenticating and trying
+    /// once more when the registry answers `401`.
+    async fn send(&self, make_request: impl Fn() -> RequestBuilder) -> Result<Response, String> {
+        let failed = |e: reqwest::Error| format!("Registry request failed: {e}");
+        let res = http::send(self.authorize(make_request()))
+            .await
+            .map_err(failed)?;
+        if res.status() != StatusCode::UNAUTHORIZED {
+            return Ok(res);
+        }
+        self.authenticate(&res).await?;
+        http::send(self.authorize(make_request()))
+            .await
+            .map_err(failed)
+    }
+
+    fn url(&self, path: &str) -> Url {
+        self.base.join(path).expect("valid registry path")
+    }
+
+    /// Upload `blob` unless the repository already has it; returns whether
+    /// it was sent.
+    pub async fn push_blob(&self, blob: &Blob) -> Result<bool, String> {
+        let file = tokio::fs::File::open(&blob.path)
+            .await
+            .map_err(|e| format!("Failed to open '{}': {e}", blob.path.display()))?;
+        self.upload(&blob.digest, blob.size, Body::from(file)).await
+    }
+
+    /// Upload `body` as the blob `digest` of `size` bytes unless the
+    /// repository already has it.
+    async fn upload(&self, digest: &str, size: u64, body: Body) -> Result<bool, String> {
+        let res = self
+            .send(|| http::client().head(self.url(&format!("blobs/{digest}"))))
+            .await?;
+        if res.status().is_success() {
+            return Ok(false);
+        }
+
+        let res = self
+            .send(|| http::client().post(self.url("blobs/uploads/")))
+            .await?;
+        let status = res.status();
+        if status != StatusCode::ACCEPTED {
+            return Err(error(res, "start a blob upload").await);
+        }
+        let location = res
+            .headers()
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .ok_or("The registry returned no upload location")?;
+        let mut upload = self
+            .base
+            .join(location)
+            .map_err(|e| format!("Invalid upload location '{location}': {e}"))?;
+        upload.query_pairs_mut().append_pair("digest", digest);
+
+        // The body is streamed, so this request cannot be replayed after a
+        // challenge; the upload session above already authenticated.
+        let request = self.authorize(
+            http::client()
+                .put(upload)
+                .header(CONTENT_TYPE, "application/octet-stream")
+                .header(CONTENT_LENGTH, size)
+                .body(body),
+        );
+        let res = http::send(request)
+            .await
+            .map_err(|e| format!("Registry request failed: {e}"))?;
+        if res.status() != StatusCode::CREATED {
+            return Err(error(res, &format!("upload {digest}")).await);
+        }
+        Ok(true)
+    }
+
+    /// Push `config`, then tag `manifest` (built around it) and return its
+    /// digest.
+    pub async fn push_manifest(&self, config: &Config, manifest: &Value) -> Result<String, String> {
+        self.upload(
+            &config.digest(),
+            config.data.len() as u64,
+            Body::from(config.data.clone()),
+        )
+        .await?;
+        let body = serde_json::to_vec(manifest).expect("manifests serialise");
+        let digest = sha256_digest(&body);
+        let res = self
+            .send(|| {
+                http::client()
+                    .put(self.url(&format!("manifests/{}", self.reference.tag)))
+                    .header(CONTENT_TYPE, MANIFEST_MEDIA_TYPE)
+                    .body(body.clone())
+            })
+            .await?;
+        if res.status() != StatusCode::CREATED {
+            return Err(error(res, "push the manifest").await);
+        }
+        Ok(digest)
+    }
+}
+
+/// Describe the failed `action` from the registry's answer.
+async fn error(res: Response, action: &str) -> String {
+    let status = res.status();
+    let body = res.text().await.unwrap_or_default();
+    format!("The registry could not {action}: {status} {}", body.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ARTIFACT_TYPE, Blob, Config, Reference, challenge_params, manifest, stored_credentials,
+    };
+    use std::path::PathBuf;
+
+    #[test]
+    fn parses_references() {
+        let reference =
+            Reference::parse("oci://localhost:5000/images/ubuntu:noble-20250115").unwrap();
+        assert_eq!(reference.registry, "localhost:5000");
+        assert_eq!(reference.repository, "images/ubuntu");
+        assert_eq!(reference.tag, "noble-20250115");
+        assert_eq!(
+            Reference::parse("ghcr.io/acme/debian").unwrap().tag,
+            "latest"
+        );
+        assert!(Reference::parse("oci://ghcr.io").is_err());
+        assert!(Reference::parse("oci://ghcr.io/Acme/debian:12").is_err());
+        assert_eq!(
+            Reference::parse("docker.io/acme/debian:12")
+                .unwrap()
+                .api_host(),
+            "registry-1.docker.io"
+        );
+    }
+
+    #[test]
+    fn authenticates_with_stored_credentials() {
+        let config = r#"{"auths": {"ghcr.io": {"auth": "YWNtZTpzM2NyM3Q="}}}"#;
+        assert_eq!(
+            stored_credentials(config, "ghcr.io"),
+            Some(("acme".to_string(), "s3cr3t".to_string()))
+        );
+        assert_eq!(stored_credentials(config, "quay.io"), None);
+        assert_eq!(
+            challenge_params(
+                r#"Bearer realm="https://ghcr.io/token",service="ghcr.io",scope="repository:acme/debian:pull""#
+            ),
+            [
+                ("realm".to_string(), "https://ghcr.io/token".to_string()),
+                ("service".to_string(), "ghcr.io".to_string()),
+                (
+                    "scope".to_string(),
+                    "repository:acme/debian:pull".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn describes_the_image_as_an_artifact() {
+        let image = Blob {
+            path: PathBuf::from("/data/noble.img"),
+            media_type: "application/vnd.cloud-images.disk.qcow2".to_string(),
+            digest: "sha256:ab".to_string(),
+            size: 42,
+            title: Some("noble.img".to_string()),
+        };
+        let manifest = manifest(
+            &Config::empty(),
+            &[image],
+            Some(ARTIFACT_TYPE),
+            &[("org.opencontainers.image.version", "20250115".to_string())],
+        );
+        assert_eq!(
+            manifest["artifactType"],
+            "application/vnd.cloud-images.image.v1"
+        );
+        assert_eq!(
+            manifest["config"]["digest"],
+            "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+        );
+        assert_eq!(
+            manifest["layers"][0]["annotations"]["org.opencontainers.image.title"],
+            "noble.img"
+        );
+        assert_eq!(
+            manifest["annotations"]["org.opencontainers.image.version"],
+            "20250115"
+        );
+    }
+}
@@ -0,0 +1,474 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Certificate, Client, RequestBuilder, Url};
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::cloud::{Arch, ImageFormat};
+use crate::helpers::http::DEFAULT_USER_AGENT;
+use crate::helpers::paths;
+
+/// Where `clouds.yaml` is looked for, after the current directory.
+const CLOUDS_YAML_DIRS: &[&str] = &[".config/openstack", "/etc/openstack"];
+
+/// Keystone v3 credentials, as in the `auth` section of `clouds.yaml`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct Auth {
+    pub auth_url: Option<String>,
+    pub username: Option<String>,
+    pub user_id: Option<String>,
+    pub password: Option<String>,
+    pub user_domain_name: Option<String>,
+    pub user_domain_id: Option<String>,
+    pub project_name: Option<String>,
+    pub project_id: Option<String>,
+    pub project_domain_name: Option<String>,
+    pub project_domain_id: Option<String>,
+    pub application_credential_id: Option<String>,
+    pub application_credential_secret: Option<String>,
+}
+
+/// One cloud of `clouds.yaml`, or the `OS_*` environment.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct Cloud {
+    pub auth: Auth,
+    pub region_name: Option<String>,
+    /// Endpoint interface, `public` by default.
+    pub interface: Option<String>,
+    /// `false` accepts any certificate.
+    pub verify: Option<bool>,
+    /// PEM bundle the endpoints' certificates are checked against.
+    pub cacert: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CloudsYaml {
+    clouds: HashMap<String, Cloud>,
+}
+
+impl Cloud {
+    /// The cloud `name` (else `$OS_CLOUD`) of the first `clouds.yaml` found,
+    /// or the `OS_*` variables when no cloud is named.
+    pub fn load(name: Option<&str>) -> Result<Self, String> {
+        let name = name
+            .map(str::to_string)
+            .or_else(|| std::env::var("OS_CLOUD").ok());
+        let Some(name) = name else {
+            return Self::from_env(|key| std::env::var(key).ok());
+        };
+        let path = clouds_yaml()
+            .ok_or_else(|| format!("no clouds.yaml was found for the cloud '{name}'"))?;
+        let text = fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read '{}': {e}", path.display()))?;
+        Self::from_clouds_yaml(&text, &name).map_err(|e| format!("{}: {e}", path.display()))
+    }
+
+    /// The cloud `name` of the `clouds.yaml` document `text`.
+    pub fn from_clouds_yaml(text: &str, name: &str) -> Result<Self, String> {
+        let clouds: CloudsYaml = serde_yaml::from_str(text).map_err(|e| e.to_string())?;
+        clouds
+            .clouds
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("no cloud named '{name}'"))
+    }
+
+    /// The cloud described by the `OS_*` variables `var` returns.
+    pub fn from_env(var: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let auth = Auth {
+            auth_url: var("OS_AUTH_URL"),
+            username: var("OS_USERNAME"),
+            user_id: var("OS_USER_ID"),
+            password: var("OS_PASSWORD"),
+            user_domain_name: var("OS_USER_DOMAIN_NAME"),
+            user_domain_id: var("OS_USER_DOMAIN_ID"),
+            project_name: var("OS_PROJECT_NAME"),
+            project_id: var("OS_PROJECT_ID"),
+            project_domain_name: var("OS_PROJECT_DOMAIN_NAME"),
+            project_domain_id: var("OS_PROJECT_DOMAIN_ID"),
+            application_credential_id: var("OS_APPLICATION_CREDENTIAL_ID"),
+            application_credential_secret: var("OS_APPLICATION_CREDENTIAL_SECRET"),
+        };
+        if auth.auth_url.is_none() {
+            return Err(
+                "no OpenStack credentials: name a cloud of clouds.yaml (--cloud or $OS_CLOUD) \
+                 or source an openrc file"
+                    .to_string(),
+            );
+        }
+        Ok(Self {
+            auth,
+            region_name: var("OS_REGION_NAME"),
+            interface: var("OS_INTERFACE"),
+            verify: var("OS_INSECURE").map(|v| !matches!(v.as_str(), "1" | "true" | "True")),
+            cacert: var("OS_CACERT").map(PathBuf::from),
+        })
+    }
+}
+
+fn clouds_yaml() -> Option<PathBuf> {
+    let home = paths::home_dir();
+    std::iter::once(PathBuf::from("clouds.yaml"))
+        .chain(CLOUDS_YAML_DIRS.iter().filter_map(|dir| {
+            if dir.starts_with('/') {
+                Some(Path::new(dir).join("clouds.yaml"))
+            } else {
+                home.as_ref().map(|home| home.join(dir).join("clouds.yaml"))
+            }
+        }))
+        .find(|path| path.is_file())
+}
+
+/// Body of `POST /v3/auth/tokens`: an application credential when there is
+/// one, else a password scoped to the project.
+fn token_request(auth: &Auth) -> Result<Value, String> {
+    if let (Some(id), Some(secret)) = (
+        &auth.application_credential_id,
+        &auth.application_credential_secret,
+    ) {
+        return Ok(json!({"auth": {"identity": {
+            "methods": ["application_credential"],
+            "application_credential": {"id": id, "secret": secret},
+        }}}));
+    }
+    let password = auth
+        .password
+        .as_ref()
+        .ok_or("the OpenStack credentials have neither a password nor an application credential")?;
+    let domain = |id: &Option<String>, name: &Option<String>| match (id, name) {
+        (Some(id), _) => json!({"id": id}),
+        (None, Some(name)) => json!({"name": name}),
+        (None, None) => json!({"id": "default"}),
+    };
+    let user = match (&auth.user_id, &auth.username) {
+        (Some(id), _) => json!({"id": id, "password": password}),
+        (None, Some(name)) => json!({
+            "name": name,
+            "domain": domain(&auth.user_domain_id, &auth.user_domain_name),
+            "password": password,
+        }),
+        (None, None) => return Err("the OpenStack credentials name no user".to_string()),
+    };
+    let mut request = json!({"auth": {"identity": {
+        "methods": ["password"],
+        "password": {"user": user},
+    }}});
+    let project = match (&auth.project_id, &auth.project_name) {
+        (Some(id), _) => Some(json!({"id": id})),
+        (None, Some(name)) => Some(json!({
+            "name": name,
+            "domain": domain(&auth.project_domain_id, &auth.project_domain_name),
+        })),
+        (None, None) => None,
+    };
+    if let Some(project) = project {
+        request["auth"]["scope"] = json!({ "project": project });
+    }
+    Ok(request)
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    token: Token,
+}
+
+#[derive(Debug, Deserialize)]
+struct Token {
+    #[serde(default)]
+    catalog: Vec<Service>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Service {
+    #[serde(rename = "type")]
+    kind: String,
+    endpoints: Vec<Endpoint>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Endpoint {
+    interface: String,
+    #[serde(default)]
+    region_id: Option<String>,
+    url: String,
+}
+
+/// URL of the `kind` service on `interface`, in `region` when given.
+fn endpoint(
+    catalog: &[Service],
+    kind: &str,
+    interface: &str,
+    region: Option<&str>,
+) -> Option<String> {
+    catalog
+        .iter()
+        .filter(|service| service.kind == kind)
+        .flat_map(|service| &service.endpoints)
+        .find(|endpoint| {
+            endpoint.interface == interface
+                && region.is_none_or(|region| endpoint.region_id.as_deref() == Some(region))
+        })
+        .map(|endpoint| endpoint.url.clone())
+}
+
+/// Glance `disk_format` and `container_format` of an image in `format`;
+/// `None` for formats Glance does not know.
+pub fn formats(format: &ImageFormat) -> Option<(&'static str, &'static str)> {
+    Some(match format {
+        ImageFormat::Qcow2 | ImageFormat::Img => ("qcow2", "bare"),
+        ImageFormat::Raw => ("raw", "bare"),
+        ImageFormat::Vhd => ("vhd", "bare"),
+        ImageFormat::Vmdk => ("vmdk", "bare"),
+        ImageFormat::Iso => ("iso", "bare"),
+        ImageFormat::Ova => ("vmdk", "ova"),
+        ImageFormat::Other(ext) if ext == "vdi" => ("vdi", "bare"),
+        _ => return None,
+    })
+}
+
+/// The `architecture` property Nova schedules on.
+pub fn architecture(arch: Arch) -> &'static str {
+    match arch {
+        Arch::Amd64 => "x86_64",
+        Arch::Arm64 => "aarch64",
+        Arch::Armhf => "armv7l",
+        Arch::Ppc64le => "ppc64le",
+        Arch::S390x => "s390x",
+        Arch::Riscv64 => "riscv64",
+    }
+}
+
+/// The image `create` registers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageSpec {
+    pub name: String,
+    pub disk_format: String,
+    pub container_format: String,
+    /// `private`, `shared`, `community` or `public`; Glance's default when
+    /// `None`.
+    pub visibility: Option<String>,
+    /// Extra properties, e.g. `os_distro`.
+    pub properties: BTreeMap<String, String>,
+}
+
+impl ImageSpec {
+    fn to_json(&self) -> Value {
+        let mut body = json!({
+            "name": self.name,
+            "disk_format": self.disk_format,
+            "container_format": self.container_format,
+        });
+        if let Some(visibility) = &self.visibility {
+            body["visibility"] = json!(visibility);
+        }
+        for (key, value) in &self.properties {
+            body[key] = json!(value);
+        }
+        body
+    }
+}
+
+/// The image service of a cloud, authenticated with Keystone.
+#[derive(Debug, Clone)]
+pub struct Glance {
+    client: Client,
+    images: Url,
+    token: String,
+}
+
+impl Glance {
+    /// Authenticate against `cloud` and find its image endpoint.
+    pub async fn connect(cloud: &Cloud) -> Result<Self, String> {
+        let mut builder = Client::builder()
+            .user_agent(DEFAULT_USER_AGENT)
+            .danger_accept_invalid_certs(cloud.verify == Some(false));
+        if let Some(cacert) = &cloud.cacert {
+            let pem = fs::read(cacert)
+                .map_err(|e| format!("Failed to read '{}': {e}", cacert.display()))?;
+            let certificate = Certificate::from_pem(&pem)
+                .map_err(|e| format!("Invalid certificate '{}': {e}", cacert.display()))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+        let client = builder
+            .build()
+            .map_err(|e| format!("Failed to build the OpenStack client: {e}"))?;
+
+        let auth_url = cloud
+            .auth
+            .auth_url
+            .as_deref()
+            .ok_or("the OpenStack credentials have no auth_url")?
+            .trim_end_matches('/');
+        let auth_url = if auth_url.ends_with("/v3") {
+            auth_url.to_string()
+        } else {
+            format!("{auth_url}/v3")
+        };
+        let res = send(
+            client
+                .post(format!("{auth_url}/auth/tokens"))
+                .json(&token_request(&cloud.auth)?),
+        )
+        .await?;
+        let token = res
+            .headers()
+            .get("x-subject-token")
+            .and_then(|token| token.to_str().ok())
+            .ok_or("Keystone returned no token")?
+            .to_string();
+        let body: TokenResponse = res
+            .json()
+            .await
+            .map_err(|e| format!("Unexpected Keystone response: {e}"))?;
+
+        let interface = cloud.interface.as_deref().unwrap_or("public");
+        let base = endpoint(
+            &body.token.catalog,
+            "image",
+            interface,
+            cloud.region_name.as_deref(),
+        )
+        .ok_or_else(|| format!("the catalog has no {interface} image endpoint"))?;
+        let base = base.trim_end_matches('/').trim_end_matches("/v2");
+        let images = Url::parse(&format!("{base}/v2/images"))
+            .map_err(|e| format!("Invalid image endpoint '{base}': {e}"))?;
+        Ok(Self {
+            client,
+            images,
+            token,
+        })
+    }
+
+    fn request(&self, method: reqwest::Method, path: &[&str]) -> RequestBuilder {
+        let mut url = self.images.clone();
+        url.path_segments_mut()
+            .expect("the image URL is a base")
+            .extend(path);
+        self.client
+            .request(method, url)
+            .header("X-Auth-Token", &self.token)
+    }
+
+    /// Register `spec` and return the new image's id.
+    pub async fn create(&self, spec: &ImageSpec) -> Result<String, String> {
+        #[derive(Deserialize)]
+        struct Created {
+            id: String,
+        }
+        let created: Created = send(
+            self.request(reqwest::Method::POST, &[])
+                .json(&spec.to_json()),
+        )
+        .await?
+        .json()
+        .await
+        .map_err(|e| format!("Unexpected Glance response: {e}"))?;
+        Ok(created.id)
+    }
+
+    /// Stream the file at `path` as the data of the image `id`.
+    pub async fn upload(&self, id: &str, path: &Path) -> Result<(), String> {
+        let file = tokio::fs::File::open(path)
+            .await
+            .map_err(|e| format!("Failed to open '{}': {e}", path.display()))?;
+        send(
+            self.request(reqwest::Method::PUT, &[id, "file"])
+                .header(CONTENT_TYPE, "application/octet-stream")
+                .body(reqwest::Body::from(file)),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Remove the image `id`, e.g. after a failed upload.
+    pub async fn delete(&self, id: &str) -> Result<(), String> {
+        send(self.request(reqwest::Method::DELETE, &[id]))
+            .await
+            .map(|_| ())
+    }
+}
+
+/// Send `request`, turning an error status into an error carrying the body.
+async fn send(request: RequestBuilder) -> Result<reqwest::Response, String> {
+    let res = request
+        .send()
+        .await
+        .map_err(|e| format!("OpenStack request failed: {e}"))?;
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+    let url = res.url().clone();
+    let body = res.text().await.unwrap_or_default();
+    Err(format!("OpenStack {url}: {status} {}", body.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cloud, Service, endpoint, token_request};
+    use serde_json::json;
+
+    #[test]
+    fn authenticates_as_clouds_yaml_describes() {
+        let text = "clouds:\n  lab:\n    auth:\n      auth_url: https://keystone.lab:5000\n      \
+                    username: ops\n      password: secret\n      project_name: images\n      \
+                    user_domain_name: Default\n    region_name: RegionOne\n    verify: false\n";
+        let cloud = Cloud::from_clouds_yaml(text, "lab").unwrap();
+        assert_eq!(cloud.region_name.as_deref(), Some("RegionOne"));
+        assert_eq!(cloud.verify, Some(false));
+        assert!(Cloud::from_clouds_yaml(text, "prod").is_err());
+
+        let request = token_request(&cloud.auth).unwrap();
+        assert_eq!(
+            request["auth"]["identity"]["password"]["user"],
+            json!({"name": "ops", "domain": {"name": "Default"}, "password": "secret"})
+        );
+        assert_eq!(
+            request["auth"]["scope"]["project"],
+            json!({"name": "images", "domain": {"id": "default"}})
+        );
+
+        let env = Cloud::from_env(|key| match key {
+            "OS_AUTH_URL" => Some("https://keystone.lab:5000/v3".to_string()),
+            "OS_APPLICATION_CREDENTIAL_ID" => Some("abc".to_string()),
+            "OS_APPLICATION_CREDENTIAL_SECRET" => Some("xyz".to_string()),
+            _ => None,
+        })
+        .unwrap();
+        let request = token_request(&env.auth).unwrap();
+        assert_eq!(
+            request["auth"]["identity"]["methods"],
+            json!(["application_credential"])
+        );
+        assert!(request["auth"].get("scope").is_none());
+    }
+
+    #[test]
+    fn picks_the_image_endpoint_of_the_region() {
+        let catalog: Vec<Service> = serde_json::from_value(json!([
+            {"type": "compute", "endpoints": [
+                {"interface": "public", "region_id": "RegionOne", "url": "https://nova.lab"}
+            ]},
+            {"type": "image", "endpoints": [
+                {"interface": "internal", "region_id": "RegionOne", "url": "http://glance.int"},
+                {"interface": "public", "region_id": "RegionTwo", "url": "https://glance2.lab"},
+                {"interface": "public", "region_id": "RegionOne", "url": "https://glance.lab"}
+            ]}
+        ]))
+        .unwrap();
+        assert_eq!(
+            endpoint(&catalog, "image", "public", Some("RegionOne")).as_deref(),
+            Some("https://glance.lab")
+        );
+        assert_eq!(
+            endpoint(&catalog, "image", "public", None).as_deref(),
+            Some("https://glance2.lab")
+        );
+        assert_eq!(endpoint(&catalog, "volume", "public", None), None);
+    }
+}
@@ -0,0 +1,168 @@
+use std::fmt::Write;
+
+use clap::ValueEnum;
+
+use crate::cloud::{ChecksumKind, Image};
+use crate::helpers::{libvirt, vm};
+
+/// Packer builder the exported source block is written for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Builder {
+    /// `qemu`, booting the image itself (`disk_image = true`).
+    #[default]
+    Qemu,
+    /// `proxmox-clone`, cloning the template `proxmox create --template`
+    /// builds from the image.
+    Proxmox,
+}
+
+/// Quote `value` as an HCL string, escaping template sequences.
+pub fn quote(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace("${", "$${")
+        .replace("%{", "%%{");
+    format!("\"{escaped}\"")
+}
+
+/// `iso_checksum` of `image`: the strongest checksum Packer understands as
+/// `<algo>:<hex>`, or `none`.
+pub fn checksum(image: &Image) -> String {
+    image
+        .checksums()
+        .iter()
+        .find(|checksum| checksum.kind() != ChecksumKind::Blake2b)
+        .map_or("none".to_string(), |checksum| {
+            format!("{}:{}", checksum.kind().as_str(), checksum.value())
+        })
+}
+
+/// Label of the source block, e.g. `ubuntu-noble-amd64`.
+pub fn source_name(image: &Image) -> String {
+    format!("{}-{}-{}", image.os(), image.name(), image.arch_name())
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+/// Write `pairs` as aligned HCL attributes indented by two spaces; values
+/// are written as given.
+pub fn attributes(out: &mut String, pairs: &[(&str, String)]) {
+    let width = pairs.iter().map(|(key, _)| key.len()).max().unwrap_or(0);
+    for (key, value) in pairs {
+        let _ = writeln!(out, "  {key:<width$} = {value}");
+    }
+}
+
+/// An HCL2 template for `builder`: a `locals` block with the resolved URL,
+/// checksum, format and build of `image`, and a source block using them.
+/// `node` is the Proxmox node to build on.
+pub fn template(image: &Image, builder: Builder, node: Option<&str>) -> String {
+    let name = source_name(image);
+    let mut out = format!(
+        "# {} {} ({}) {} {} build {}, resolved by cloud-images-downloader.\n",
+        image.os(),
+        image.distro_version(),
+        image.name(),
+        image.arch_name(),
+        image.variant(),
+        image.version()
+    );
+    out.push_str("locals {\n");
+    attributes(
+        &mut out,
+        &[
+            ("image_url", quote(image.url())),
+            ("image_checksum", quote(&checksum(image))),
+            ("image_format", quote(image.format().as_str())),
+            ("image_version", quote(image.version())),
+        ],
+    );
+    out.push_str("}\n\n");
+
+    match builder {
+        Builder::Qemu => {
+            let _ = writeln!(out, "source \"qemu\" {} {{", quote(&name));
+            attributes(
+                &mut out,
+                &[
+                    ("iso_url", "local.image_url".to_string()),
+                    ("iso_checksum", "local.image_checksum".to_string()),
+                    ("disk_image", "true".to_string()),
+                    ("format", quote("qcow2")),
+                    (
+                        "qemu_binary",
+                        quote(&format!("qemu-system-{}", vm::system_arch(image.arch()))),
+                    ),
+                    ("vm_name", quote(&format!("{name}.qcow2"))),
+                    ("output_directory", quote(&format!("output-{name}"))),
+                    ("headless", "true".to_string()),
+                ],
+            );
+        }
+        Builder::Proxmox => {
+            let _ = writeln!(out, "source \"proxmox-clone\" {} {{", quote(&name));
+            out.push_str(
+                "  # Built from local.image_url by `cloud-images-downloader proxmox create --template`.\n",
+            );
+            let mut pairs = vec![
+                ("clone_vm", quote(&libvirt::domain_name(image))),
+                ("vm_name", quote(&format!("{name}-golden"))),
+                ("template_name", quote(&format!("{name}-golden"))),
+                (
+                    "template_description",
+                    quote(&format!("{} ({})", image.url(), checksum(image))),
+                ),
+                ("full_clone", "true".to_string()),
+                ("cloud_init", "true".to_string()),
+            ];
+            if let Some(node) = node {
+                pairs.insert(0, ("node", quote(node)));
+            }
+            attributes(&mut out, &pairs);
+        }
+    }
+    out.push_str("}\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Builder, template};
+    use crate::cloud::{Arch, ChecksumKind, Image, ImageChecksum, sample_image};
+
+    fn image() -> Image {
+        sample_image().with_arch(Arch::Arm64).with_source(
+            "https://cloud.debian.org/debian-12-genericcloud-${arch}.qcow2".to_string(),
+            Some(ImageChecksum::new(ChecksumKind::Sha256, "ab12")),
+        )
+    }
+
+    #[test]
+    fn fills_in_the_resolved_image() {
+        let qemu = template(&image(), Builder::Qemu, None);
+        assert!(qemu.contains(
+            "  image_url      = \"https://cloud.debian.org/debian-12-genericcloud-$${arch}.qcow2\"\n"
+        ));
+        assert!(qemu.contains("  image_checksum = \"sha256:ab12\"\n"));
+        assert!(qemu.contains("source \"qemu\" \"debian-bookworm-arm64\" {\n"));
+        assert!(qemu.contains("  iso_checksum     = local.image_checksum\n"));
+        assert!(qemu.contains("  qemu_binary      = \"qemu-system-aarch64\"\n"));
+        assert!(qemu.ends_with("}\n"));
+
+        let proxmox = template(&image(), Builder::Proxmox, Some("pve1"));
+        assert!(proxmox.contains("source \"proxmox-clone\" \"debian-bookworm-arm64\" {\n"));
+        assert!(proxmox.contains("  node                 = \"pve1\"\n"));
+        assert!(
+            proxmox.contains("  clone_vm             = \"debian-bookworm-arm64-20250210-2019\"\n")
+        );
+    }
+}
@@ -0,0 +1,85 @@
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use directories::{BaseDirs, ProjectDirs, UserDirs};
+
+const APP_DIR: &str = "cloud-images-downloader";
+
+/// Set by `--cache-dir` / `cache_dir` (at most once).
+static CACHE_OVERRIDE: OnceLock<PathBuf> = OnceLock::new();
+
+fn project() -> Option<ProjectDirs> {
+    ProjectDirs::from("", "", APP_DIR)
+}
+
+/// Keep caches and temporary files below `dir` instead of the platform
+/// cache directory. Must run before the first lookup; later calls are
+/// ignored.
+pub fn set_cache_dir(dir: PathBuf) {
+    let _ = CACHE_OVERRIDE.set(dir);
+}
+
+/// Caches: the configured directory, else `$XDG_CACHE_HOME/cloud-images-downloader`
+/// on Linux, `~/Library/Caches/cloud-images-downloader` on macOS and
+/// `%LOCALAPPDATA%\cloud-images-downloader\cache` on Windows.
+pub fn cache_dir() -> Option<PathBuf> {
+    CACHE_OVERRIDE
+        .get()
+        .cloned()
+        .or_else(|| project().map(|p| p.cache_dir().to_path_buf()))
+}
+
+/// Configuration: `$XDG_CONFIG_HOME/cloud-images-downloader` on Linux,
+/// `~/Library/Application Support/cloud-images-downloader` on macOS and
+/// `%APPDATA%\cloud-images-downloader\config` on Windows.
+pub fn config_dir() -> Option<PathBuf> {
+    project().map(|p| p.config_dir().to_path_buf())
+}
+
+/// Data such as the image library and keyrings: `$XDG_DATA_HOME/cloud-images-downloader`
+/// on Linux, `~/Library/Application Support/cloud-images-downloader` on
+/// macOS and `%APPDATA%\cloud-images-downloader\data` on Windows.
+pub fn data_dir() -> Option<PathBuf> {
+    project().map(|p| p.data_dir().to_path_buf())
+}
+
+/// State that outlives a run, like the download queue:
+/// `$XDG_STATE_HOME/cloud-images-downloader` on Linux, the local data
+/// directory elsewhere.
+pub fn state_dir() -> Option<PathBuf> {
+    let project = project()?;
+    Some(
+        project
+            .state_dir()
+            .unwrap_or_else(|| project.data_local_dir())
+            .to_path_buf(),
+    )
+}
+
+/// Scratch files: `tmp` below the configured cache directory, the system
+/// temp folder otherwise.
+pub fn temp_dir() -> PathBuf {
+    match CACHE_OVERRIDE.get() {
+        Some(dir) => dir.join("tmp"),
+        None => std::env::temp_dir(),
+    }
+}
+
+/// The user's downloads folder.
+pub fn downloads_dir() -> Option<PathBuf> {
+    UserDirs::new().and_then(|dirs| dirs.download_dir().map(PathBuf::from))
+}
+
+/// Shared data folder of all applications, e.g. `~/.local/share`.
+pub fn shared_data_dir() -> Option<PathBuf> {
+    BaseDirs::new().map(|dirs| dirs.data_dir().to_path_buf())
+}
+
+/// Units of the user's systemd instance, e.g. `~/.config/systemd/user`.
+pub fn systemd_user_dir() -> Option<PathBuf> {
+    BaseDirs::new().map(|dirs| dirs.config_dir().join("systemd").join("user"))
+}
+
+pub fn home_dir() -> Option<PathBuf> {
+    BaseDirs::new().map(|dirs| dirs.home_dir().to_path_buf())
+}
@@ -0,0 +1,393 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use clap::ValueEnum;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::helpers::human_size;
+
+/// Handle of one running transfer (or of the overall count of a batch).
+/// Cheap to clone into the tasks of a segmented download.
+pub type Transfer = Arc<dyn TransferProgress>;
+
+/// Receives the progress of downloads. The library reports through this
+/// trait only, so embedders can forward progress to their own UI.
+pub trait ProgressSink: Send + Sync + fmt::Debug {
+    /// A transfer of `total` bytes from `url` starts.
+    fn transfer(&self, url: &str, total: u64) -> Transfer;
+
+    /// A batch of `images` downloads starts; the handle counts finished
+    /// images.
+    fn batch(&self, images: u64) -> Transfer;
+
+    /// Whether external downloaders may draw their own progress on the
+    /// terminal.
+    fn external_output(&self) -> bool {
+        false
+    }
+}
+
+/// Updates of a single transfer.
+pub trait TransferProgress: Send + Sync {
+    /// Absolute number of bytes (or images) done.
+    fn set_position(&self, position: u64);
+    /// `delta` more bytes (or images) are done.
+    fn inc(&self, delta: u64);
+    /// Message about the transfer, e.g. a mirror switch.
+    fn println(&self, message: &str);
+    /// The transfer completed.
+    fn finish(&self, message: &str);
+    /// The transfer failed.
+    fn abandon(&self, message: &str);
+}
+
+/// How the command line reports progress.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProgressMode {
+    /// Progress bars on the terminal.
+    #[default]
+    Bar,
+    /// Plain lines every 10 %, for logs and CI.
+    Log,
+    /// One JSON event per line on stderr.
+    Json,
+    /// Nothing.
+    None,
+}
+
+impl ProgressMode {
+    pub fn sink(self) -> Arc<dyn ProgressSink> {
+        match self {
+            ProgressMode::Bar => Arc::new(TerminalProgress::default()),
+            ProgressMode::Log => Arc::new(LogProgress),
+            ProgressMode::Json => Arc::new(JsonProgress::new(io::stderr())),
+            ProgressMode::None => Arc::new(SilentProgress),
+        }
+    }
+}
+
+/// Every sink except the terminal one shares this position bookkeeping and
+/// reports at most once per `step` percent.
+struct Counter {
+    total: u64,
+    position: AtomicU64,
+    reported: AtomicU64,
+}
+
+impl Counter {
+    fn new(total: u64) -> Self {
+        Self {
+            total,
+            position: AtomicU64::new(0),
+            reported: AtomicU64::new(0),
+        }
+    }
+
+    /// Record the new position and return the percentage to report when it
+    /// crossed the next multiple of `step`.
+    fn advance(&self, position: Option<u64>, delta: u64, step: u64) -> Option<(u64, u64)> {
+        let position = match position {
+            Some(position) => {
+                self.position.store(position, Ordering::Relaxed);
+                position
+            }
+            None => self.position.fetch_add(delta, Ordering::Relaxed) + delta,
+        };
+        if self.total == 0 {
+            return None;
+        }
+        let percent = (position.min(self.total) * 100 / self.total) / step * step;
+        let previous = self.reported.fetch_max(percent, Ordering::Relaxed);
+        (percent > previous).then_some((position, percent))
+    }
+}
+
+/// indicatif progress bars, stacked when several downloads run at once.
+#[derive(Debug, Default)]
+pub struct TerminalProgress {
+    multi: MultiProgress,
+    batch: AtomicBool,
+}
+
+struct Bar(ProgressBar);
+
+impl TransferProgress for Bar {
+    fn set_position(&self, position: u64) {
+        self.0.set_position(position);
+    }
+
+    fn inc(&self, delta: u64) {
+        self.0.inc(delta);
+    }
+
+    fn println(&self, message: &str) {
+        self.0.println(message);
+    }
+
+    fn finish(&self, message: &str) {
+        self.0.finish_with_message(message.to_string());
+    }
+
+    fn abandon(&self, message: &str) {
+        self.0.abandon_with_message(message.to_string());
+    }
+}
+
+impl ProgressSink for TerminalProgress {
+    fn transfer(&self, url: &str, total: u64) -> Transfer {
+        let pb = self.multi.add(ProgressBar::new(total));
+        if let Ok(style) = ProgressStyle::with_template(
+            "{msg}\n{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] \
+             {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
+        ) {
+            pb.set_style(style.progress_chars("#>-"));
+        }
+        pb.set_message(format!("Downloading {url}"));
+        Arc::new(Bar(pb))
+    }
+
+    fn batch(&self, images: u64) -> Transfer {
+        self.batch.store(true, Ordering::Relaxed);
+        let overall = self.multi.add(ProgressBar::new(images));
+        if let Ok(style) =
+            ProgressStyle::with_template("[{elapsed_precise}] {pos}/{len} images done {msg}")
+        {
+            overall.set_style(style);
+        }
+        Arc::new(Bar(overall))
+    }
+
+    /// Only single downloads leave the terminal to the external tool; in a
+    /// batch its output would tear the bars apart.
+    fn external_output(&self) -> bool {
+        !self.batch.load(Ordering::Relaxed)
+    }
+}
+
+/// Plain lines on stderr: the start, every 10 % and the outcome.
+#[derive(Debug, Default)]
+pub struct LogProgress;
+
+struct LogLine {
+    label: String,
+    counter: Counter,
+}
+
+impl LogLine {
+    fn report(&self, position: Option<u64>, delta: u64) {
+        if let Some((position, percent)) = self.counter.advance(position, delta, 10) {
+            eprintln!(
+                "{}: {percent}% ({} of {})",
+                self.label,
+                human_size(Some(position)),
+                human_size(Some(self.counter.total))
+            );
+        }
+    }
+}
+
+impl TransferProgress for LogLine {
+    fn set_position(&self, position: u64) {
+        self.report(Some(position), 0);
+    }
+
+    fn inc(&self, delta: u64) {
+        self.report(None, delta);
+    }
+
+    fn println(&self, message: &str) {
+        eprintln!("{message}");
+    }
+
+    fn finish(&self, message: &str) {
+        eprintln!("{message}");
+    }
+
+    fn abandon(&self, message: &str) {
+        eprintln!("{message}");
+    }
+}
+
+impl ProgressSink for LogProgress {
+    fn transfer(&self, url: &str, total: u64) -> Transfer {
+        eprintln!("Downloading {url} ({})", human_size(Some(total)));
+        Arc::new(LogLine {
+            label: url.to_string(),
+            counter: Counter::new(total),
+        })
+    }
+
+    fn batch(&self, images: u64) -> Transfer {
+        eprintln!("Downloading {images} image(s)");
+        Arc::new(LogLine {
+            label: "images done".to_string(),
+            counter: Counter::new(images),
+        })
+    }
+}
+
+/// JSON Lines events (`start`, `progress` per percent, `message`, `finish`,
+/// `fail`) for frontends that drive the binary.
+pub struct JsonProgress {
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl JsonProgress {
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Self {
+            out: Arc::new(Mutex::new(Box::new(out))),
+        }
+    }
+
+    fn open(&self, kind: &'static str, label: &str, total: u64) -> Transfer {
+        let events = JsonEvents {
+            out: Arc::clone(&self.out),
+            kind,
+            label: label.to_string(),
+            counter: Counter::new(total),
+        };
+        events.emit("start", json!({ "total": total }));
+        Arc::new(events)
+    }
+}
+
+impl fmt::Debug for JsonProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JsonProgress").finish_non_exhaustive()
+    }
+}
+
+struct JsonEvents {
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+    /// `transfer` or `batch`.
+    kind: &'static str,
+    label: String,
+    counter: Counter,
+}
+
+impl JsonEvents {
+    fn emit(&self, event: &str, mut fields: serde_json::Value) {
+        fields["event"] = json!(event);
+        fields[self.kind] = json!(self.label);
+        if let Ok(mut out) = self.out.lock() {
+            let _ = writeln!(out, "{fields}");
+            let _ = out.flush();
+        }
+    }
+
+    fn report(&self, position: Option<u64>, delta: u64) {
+        if let Some((position, percent)) = self.counter.advance(position, delta, 1) {
+            self.emit(
+                "progress",
+                json!({ "position": position, "total": self.counter.total, "percent": percent }),
+            );
+        }
+    }
+}
+
+impl TransferProgress for JsonEvents {
+    fn set_position(&self, position: u64) {
+        self.report(Some(position), 0);
+    }
+
+    fn inc(&self, delta: u64) {
+        self.report(None, delta);
+    }
+
+    fn println(&self, message: &str) {
+        self.emit("message", json!({ "message": message }));
+    }
+
+    fn finish(&self, message: &str) {
+        self.emit("finish", json!({ "message": message }));
+    }
+
+    fn abandon(&self, message: &str) {
+        self.emit("fail", json!({ "message": message }));
+    }
+}
+
+impl ProgressSink for JsonProgress {
+    fn transfer(&self, url: &str, total: u64) -> Transfer {
+        self.open("transfer", url, total)
+    }
+
+    fn batch(&self, images: u64) -> Transfer {
+        self.open("batch", &images.to_string(), images)
+    }
+}
+
+/// Reports nothing.
+#[derive(Debug, Default)]
+pub struct SilentProgress;
+
+impl TransferProgress for SilentProgress {
+    fn set_position(&self, _position: u64) {}
+    fn inc(&self, _delta: u64) {}
+    fn println(&self, _message: &str) {}
+    fn finish(&self, _message: &str) {}
+    fn abandon(&self, _message: &str) {}
+}
+
+impl ProgressSink for SilentProgress {
+    fn transfer(&self, _url: &str, _total: u64) -> Transfer {
+        Arc::new(SilentProgress)
+    }
+
+    fn batch(&self, _images: u64) -> Transfer {
+        Arc::new(SilentProgress)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    use super::{JsonProgress, ProgressSink};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_events_report_each_percent_once() {
+        let buffer = Buffer::default();
+        let sink = JsonProgress::new(buffer.clone());
+        let transfer = sink.transfer("https://example.invalid/a.img", 200);
+        transfer.inc(1);
+        transfer.inc(1);
+        transfer.inc(1);
+        transfer.set_position(200);
+        transfer.finish("done");
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let events: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let kinds: Vec<&str> = events
+            .iter()
+            .map(|e| e["event"].as_str().unwrap())
+            .collect();
+        assert_eq!(kinds, ["start", "progress", "progress", "finish"]);
+        assert_eq!(events[1]["percent"], 1);
+        assert_eq!(events[2]["percent"], 100);
+        assert_eq!(events[3]["transfer"], "https://example.invalid/a.img");
+    }
+}
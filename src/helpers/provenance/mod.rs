@@ -0,0 +1,217 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::cloud::{ChecksumKind, Image};
+
+const STATEMENT_TYPE: &str = "https://in-toto.io/Statement/v1";
+const PREDICATE_TYPE: &str = "https://slsa.dev/provenance/v1";
+const BUILD_TYPE: &str = "https://github.com/AlexKintis/cloud-images-downloader/download/v1";
+const BUILDER_ID: &str = "https://github.com/AlexKintis/cloud-images-downloader";
+
+/// Where a downloaded image came from, as an unsigned in-toto statement with
+/// a SLSA provenance predicate. Stored as `<image>.intoto.json` so it can be
+/// signed as is, e.g. with `cosign attest-blob --statement`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Statement {
+    #[serde(rename = "_type")]
+    pub statement_type: String,
+    pub subject: Vec<ResourceDescriptor>,
+    #[serde(rename = "predicateType")]
+    pub predicate_type: String,
+    pub predicate: Provenance,
+}
+
+/// A file and its digests, keyed by algorithm (`sha256`, ...).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResourceDescriptor {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uri: Option<String>,
+    pub digest: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Provenance {
+    pub build_definition: BuildDefinition,
+    pub run_details: RunDetails,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildDefinition {
+    pub build_type: String,
+    /// The image as the repository lists it.
+    pub external_parameters: Source,
+    /// How the download was checked.
+    pub internal_parameters: Verification,
+    /// The mirror the bytes were actually served from.
+    pub resolved_dependencies: Vec<ResourceDescriptor>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Source {
+    /// Repository the image was chosen from, e.g. `debian`.
+    pub repository: String,
+    pub release: String,
+    pub version: String,
+    pub arch: String,
+    pub variant: String,
+    pub format: String,
+    /// Where the repository publishes the image.
+    pub url: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Verification {
+    /// Checksum published upstream, `<algorithm>:<hex>`.
+    pub published_checksum: Option<String>,
+    /// Whether the download matched `published_checksum`.
+    pub verified: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunDetails {
+    pub builder: Builder,
+    pub metadata: RunMetadata,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Builder {
+    pub id: String,
+    /// Version of the downloader that fetched and verified the image.
+    pub version: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunMetadata {
+    /// RFC 3339, when the transfer started.
+    pub started_on: String,
+    /// RFC 3339, when the image was in place.
+    pub finished_on: String,
+}
+
+impl Statement {
+    /// Provenance of `file`, the download of `image` served by `mirror`,
+    /// whose `kind` digest is `digest`.
+    pub fn new(
+        image: &Image,
+        file: &Path,
+        mirror: &str,
+        kind: ChecksumKind,
+        digest: &str,
+        started: DateTime<Utc>,
+    ) -> Self {
+        let digests = BTreeMap::from([(kind.as_str().to_string(), digest.to_lowercase())]);
+        let timestamp = |at: DateTime<Utc>| at.to_rfc3339_opts(SecondsFormat::Secs, true);
+        Self {
+            statement_type: STATEMENT_TYPE.to_string(),
+            subject: vec![ResourceDescriptor {
+                name: file.file_name().map(|n| n.to_string_lossy().into_owned()),
+                uri: None,
+                digest: digests.clone(),
+            }],
+            predicate_type: PREDICATE_TYPE.to_string(),
+            predicate: Provenance {
+                build_definition: BuildDefinition {
+                    build_type: BUILD_TYPE.to_string(),
+                    external_parameters: Source {
+                        repository: image.os().to_string(),
+                        release: image.name().to_string(),
+                        version: image.version().to_string(),
+                        arch: image.arch_name().to_string(),
+                        variant: image.variant().to_string(),
+                        format: image.format().to_string(),
+                        url: image.url().to_string(),
+                    },
+                    internal_parameters: Verification {
+                        published_checksum: image
+                            .checksum()
+                            .map(|c| format!("{}:{}", c.kind().as_str(), c.value())),
+                        verified: image.checksum().is_some(),
+                    },
+                    resolved_dependencies: vec![ResourceDescriptor {
+                        name: None,
+                        uri: Some(mirror.to_string()),
+                        digest: digests,
+                    }],
+                },
+                run_details: RunDetails {
+                    builder: Builder {
+                        id: BUILDER_ID.to_string(),
+                        version: BTreeMap::from([(
+                            env!("CARGO_PKG_NAME").to_string(),
+                            env!("CARGO_PKG_VERSION").to_string(),
+                        )]),
+                    },
+                    metadata: RunMetadata {
+                        started_on: timestamp(started),
+                        finished_on: timestamp(Utc::now()),
+                    },
+                },
+            },
+        }
+    }
+}
+
+/// Path of the provenance statement belonging to `image_path`.
+pub fn sidecar_path(image_path: &Path) -> PathBuf {
+    let mut path = image_path.as_os_str().to_owned();
+    path.push(".intoto.json");
+    PathBuf::from(path)
+}
+
+/// Write `statement` next to `image_path`.
+pub fn write(image_path: &Path, statement: &Statement) -> Result<(), String> {
+    let path = sidecar_path(image_path);
+    let json = serde_json::to_string_pretty(statement)
+        .map_err(|e| format!("Failed to serialise '{}': {e}", path.display()))?;
+    fs::write(&path, json).map_err(|e| format!("Failed to write '{}': {e}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Statement, sidecar_path};
+    use crate::cloud::{ChecksumKind, ImageChecksum, sample_image};
+    use chrono::Utc;
+    use std::path::Path;
+
+    #[test]
+    fn is_an_in_toto_statement_about_the_download() {
+        let image =
+            sample_image().with_checksums([ImageChecksum::new(ChecksumKind::Sha512, "ABCD")]);
+        let file = Path::new("/data/disk.qcow2");
+        let statement = Statement::new(
+            &image,
+            file,
+            "https://mirror.example/disk.qcow2",
+            ChecksumKind::Sha512,
+            "ABCD",
+            Utc::now(),
+        );
+        let json = serde_json::to_value(&statement).unwrap();
+        assert_eq!(json["_type"], "https://in-toto.io/Statement/v1");
+        assert_eq!(json["subject"][0]["name"], "disk.qcow2");
+        assert_eq!(json["subject"][0]["digest"]["sha512"], "abcd");
+        let definition = &json["predicate"]["buildDefinition"];
+        assert_eq!(definition["externalParameters"]["repository"], "debian");
+        assert_eq!(
+            definition["resolvedDependencies"][0]["uri"],
+            "https://mirror.example/disk.qcow2"
+        );
+        assert_eq!(definition["internalParameters"]["verified"], true);
+        assert_eq!(
+            sidecar_path(file),
+            Path::new("/data/disk.qcow2.intoto.json")
+        );
+    }
+}
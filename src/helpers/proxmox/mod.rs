@@ -0,0 +1,322 @@
+use std::path::Path;
+use std::time::Duration;
+
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use reqwest::header::{AUTHORIZATION, HeaderMap, HeaderValue};
+use reqwest::{Client, Method, RequestBuilder, Url, multipart};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+
+use crate::helpers::http::DEFAULT_USER_AGENT;
+
+/// How often a running task is polled.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Disk formats Proxmox accepts as `import` content.
+const IMPORT_FORMATS: &[&str] = &["qcow2", "raw", "vmdk"];
+
+/// Where and as whom the Proxmox VE API is called.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Settings {
+    /// Base URL, e.g. `https://pve.lan:8006`.
+    pub url: String,
+    /// `user@realm!token-name`.
+    pub token_id: String,
+    pub token_secret: String,
+    /// Accept self-signed certificates, as a fresh installation has.
+    pub insecure: bool,
+}
+
+/// The VM `create_vm` sets up around an uploaded image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmSpec {
+    pub vmid: Option<u32>,
+    pub name: String,
+    pub description: String,
+    pub memory_mib: u64,
+    pub cores: u32,
+    /// Storage receiving the imported disk and the cloud-init drive, e.g.
+    /// `local-lvm`.
+    pub disk_storage: String,
+    /// Bridge of the virtio NIC, e.g. `vmbr0`.
+    pub bridge: String,
+    /// Default user of the cloud-init drive (the image's own by default).
+    pub ci_user: Option<String>,
+    /// Public keys authorised for it.
+    pub ssh_keys: Option<String>,
+}
+
+/// Envelope of every API response.
+#[derive(Debug, Deserialize)]
+struct Data<T> {
+    data: T,
+}
+
+#[derive(Debug, Deserialize)]
+struct TaskStatus {
+    status: String,
+    exitstatus: Option<String>,
+}
+
+/// A Proxmox VE node reached through an API token.
+#[derive(Debug, Clone)]
+pub struct Proxmox {
+    client: Client,
+    base: Url,
+    node: String,
+}
+
+impl Proxmox {
+    pub fn new(settings: &Settings, node: &str) -> Result<Self, String> {
+        let mut base = Url::parse(&settings.url)
+            .map_err(|e| format!("Invalid Proxmox URL '{}': {e}", settings.url))?;
+        base.set_path("/api2/json/");
+        let mut token = HeaderValue::from_str(&format!(
+            "PVEAPIToken={}={}",
+            settings.token_id, settings.token_secret
+        ))
+        .map_err(|_| "The Proxmox API token contains invalid characters".to_string())?;
+        token.set_sensitive(true);
+        let client = Client::builder()
+            .user_agent(DEFAULT_USER_AGENT)
+            .default_headers(HeaderMap::from_iter([(AUTHORIZATION, token)]))
+            .danger_accept_invalid_certs(settings.insecure)
+            .build()
+            .map_err(|e| format!("Failed to build the Proxmox client: {e}"))?;
+        Ok(Self {
+            client,
+            base,
+            node: node.to_string(),
+        })
+    }
+
+    /// `path` below `/api2/json/nodes/<node>/`, each segment escaped.
+    fn node_url(&self, path: &[&str]) -> Url {
+        let mut url = self.base.clone();
+        url.path_segments_mut()
+            .expect("the API URL is a base")
+            .pop_if_empty()
+            .extend(["nodes", self.node.as_str()])
+            .extend(path);
+        url
+    }
+
+    async fn call<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T, String> {
+        let res = request
+            .send()
+            .await
+            .map_err(|e| format!("Proxmox request failed: {e}"))?;
+        let status = res.status();
+        let url = res.url().clone();
+        if !status.is_success() {
+            let body = res.text().await.unwrap_or_default();
+            return Err(format!("Proxmox {url}: {status} {}", body.trim()));
+        }
+        res.json::<Data<T>>()
+            .await
+            .map(|data| data.data)
+            .map_err(|e| format!("Unexpected Proxmox response from {url}: {e}"))
+    }
+
+    fn request(&self, method: Method, path: &[&str]) -> RequestBuilder {
+        self.client.request(method, self.node_url(path))
+    }
+
+    /// Wait for the task `upid` to stop and fail unless it ended `OK`.
+    async fn wait(&self, upid: Option<String>) -> Result<(), String> {
+        let Some(upid) = upid else {
+            return Ok(());
+        };
+        loop {
+            let status: TaskStatus = self
+                .call(self.request(Method::GET, &["tasks", &upid, "status"]))
+                .await?;
+            if status.status == "stopped" {
+                return match status.exitstatus.as_deref() {
+                    Some("OK") => Ok(()),
+                    other => Err(format!(
+                        "Proxmox task {upid} failed: {}",
+                        other.unwrap_or("unknown error")
+                    )),
+                };
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Upload `image` (of `format`) to `storage` as `import` content named
+    /// `<name>.<format>` and return its volume id, e.g.
+    /// `local:import/noble.qcow2`.
+    pub async fn upload(
+        &self,
+        storage: &str,
+        image: &Path,
+        name: &str,
+        format: &str,
+    ) -> Result<String, String> {
+        if !IMPORT_FORMATS.contains(&format) {
+            return Err(format!(
+                "Proxmox imports {} images, not {format}; use --convert-to qcow2",
+                IMPORT_FORMATS.join(", ")
+            ));
+        }
+        let file_name = format!("{name}.{format}");
+        let file = tokio::fs::File::open(image)
+            .await
+            .map_err(|e| format!("Failed to open '{}': {e}", image.display()))?;
+        let size = file
+            .metadata()
+            .await
+            .map_err(|e| format!("Failed to read '{}': {e}", image.display()))?
+            .len();
+        let part = multipart::Part::stream_with_length(file, size).file_name(file_name.clone());
+        let form = multipart::Form::new()
+            .text("content", "import")
+            .part("filename", part);
+        let upid = self
+            .call(
+                self.request(Method::POST, &["storage", storage, "upload"])
+                    .multipart(form),
+            )
+            .await?;
+        self.wait(upid).await?;
+        Ok(format!("{storage}:import/{file_name}"))
+    }
+
+    /// Remove the volume `volid` from `storage`.
+    pub async fn delete_volume(&self, storage: &str, volid: &str) -> Result<(), String> {
+        let upid = self
+            .call(self.request(Method::DELETE, &["storage", storage, "content", volid]))
+            .await?;
+        self.wait(upid).await
+    }
+
+    /// Create a VM from `spec` whose `scsi0` disk is imported from `volid`,
+    /// with a cloud-init drive and the serial console, and return its id.
+    pub async fn create_vm(&self, spec: &VmSpec, volid: &str) -> Result<u32, String> {
+        let vmid = match spec.vmid {
+            Some(vmid) => vmid,
+            None => {
+                let url = self.base.join("cluster/nextid").expect("valid API path");
+                // A string or a number, depending on the version.
+                let next: serde_json::Value = self.call(self.client.get(url)).await?;
+                next.as_u64()
+                    .or_else(|| next.as_str().and_then(|id| id.parse().ok()))
+                    .and_then(|id| u32::try_from(id).ok())
+                    .ok_or_else(|| format!("Unexpected next VM id {next}"))?
+            }
+        };
+        let upid = self
+            .call(
+                self.request(Method::POST, &["qemu"])
+                    .form(&vm_params(spec, vmid, volid)),
+            )
+            .await?;
+        self.wait(upid).await?;
+        Ok(vmid)
+    }
+
+    /// Grow the disk of `vmid` to `size`, or by it when it starts with `+`.
+    pub async fn resize_disk(&self, vmid: u32, size: &str) -> Result<(), String> {
+        let upid = self
+            .call(
+                self.request(Method::PUT, &["qemu", &vmid.to_string(), "resize"])
+                    .form(&[("disk", "scsi0"), ("size", size)]),
+            )
+            .await?;
+        self.wait(upid).await
+    }
+
+    /// Turn `vmid` into a template.
+    pub async fn make_template(&self, vmid: u32) -> Result<(), String> {
+        let upid = self
+            .call(self.request(Method::POST, &["qemu", &vmid.to_string(), "template"]))
+            .await?;
+        self.wait(upid).await
+    }
+}
+
+/// Form parameters of `POST /nodes/<node>/qemu`.
+fn vm_params(spec: &VmSpec, vmid: u32, volid: &str) -> Vec<(&'static str, String)> {
+    let mut params = vec![
+        ("vmid", vmid.to_string()),
+        ("name", spec.name.clone()),
+        ("description", spec.description.clone()),
+        ("memory", spec.memory_mib.to_string()),
+        ("cores", spec.cores.to_string()),
+        ("ostype", "l26".to_string()),
+        ("scsihw", "virtio-scsi-pci".to_string()),
+        (
+            "scsi0",
+            format!("{}:0,import-from={volid}", spec.disk_storage),
+        ),
+        ("ide2", format!("{}:cloudinit", spec.disk_storage)),
+        ("boot", "order=scsi0".to_string()),
+        ("serial0", "socket".to_string()),
+        ("vga", "serial0".to_string()),
+        ("net0", format!("virtio,bridge={}", spec.bridge)),
+        ("ipconfig0", "ip=dhcp".to_string()),
+        ("agent", "1".to_string()),
+    ];
+    if let Some(user) = &spec.ci_user {
+        params.push(("ciuser", user.clone()));
+    }
+    if let Some(keys) = &spec.ssh_keys {
+        // The API expects the keys URL-encoded once more inside the form.
+        params.push((
+            "sshkeys",
+            utf8_percent_encode(keys, NON_ALPHANUMERIC).to_string(),
+        ));
+    }
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Proxmox, Settings, VmSpec, vm_params};
+
+    #[test]
+    fn imports_the_disk_next_to_a_cloud_init_drive() {
+        let spec = VmSpec {
+            vmid: None,
+            name: "noble".to_string(),
+            description: String::new(),
+            memory_mib: 2048,
+            cores: 2,
+            disk_storage: "local-lvm".to_string(),
+            bridge: "vmbr0".to_string(),
+            ci_user: None,
+            ssh_keys: Some("ssh-ed25519 AAAA me@host".to_string()),
+        };
+        let params = vm_params(&spec, 9000, "local:import/noble.qcow2");
+        let get = |key: &str| {
+            params
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(
+            get("scsi0"),
+            Some("local-lvm:0,import-from=local:import/noble.qcow2")
+        );
+        assert_eq!(get("ide2"), Some("local-lvm:cloudinit"));
+        assert_eq!(get("sshkeys"), Some("ssh%2Ded25519%20AAAA%20me%40host"));
+        assert_eq!(get("ciuser"), None);
+
+        let node = Proxmox::new(
+            &Settings {
+                url: "https://pve.lan:8006".to_string(),
+                token_id: "root@pam!cid".to_string(),
+                token_secret: "secret".to_string(),
+                insecure: false,
+            },
+            "pve",
+        )
+        .unwrap();
+        assert_eq!(
+            node.node_url(&["tasks", "UPID:pve:0001:task", "status"])
+                .as_str(),
+            "https://pve.lan:8006/api2/json/nodes/pve/tasks/UPID:pve:0001:task/status"
+        );
+    }
+}
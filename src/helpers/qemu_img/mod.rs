@@ -0,0 +1,199 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::helpers::find_program;
+
+/// Disk formats `qemu-img convert` can produce for the common hypervisors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskFormat {
+    Qcow2,
+    Raw,
+    Vmdk,
+    Vdi,
+}
+
+impl DiskFormat {
+    /// Name understood by `qemu-img -O`, also used as file extension.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DiskFormat::Qcow2 => "qcow2",
+            DiskFormat::Raw => "raw",
+            DiskFormat::Vmdk => "vmdk",
+            DiskFormat::Vdi => "vdi",
+        }
+    }
+}
+
+impl fmt::Display for DiskFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Locate `qemu-img` on `PATH`.
+pub fn locate() -> Result<PathBuf, String> {
+    find_program("qemu-img").ok_or_else(|| {
+        "qemu-img was not found on PATH; install it (e.g. `apt install qemu-utils`, \
+             `dnf install qemu-img` or `brew install qemu`) to convert images"
+            .to_string()
+    })
+}
+
+/// Run `qemu-img` with `args` and return its stdout, turning a non-zero exit
+/// into an error that carries its stderr.
+fn run(args: &[&std::ffi::OsStr]) -> Result<String, String> {
+    let qemu_img = locate()?;
+    let output = Command::new(&qemu_img)
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run '{}': {e}", qemu_img.display()))?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        Err(format!(
+            "qemu-img {} failed ({}): {}",
+            args.first()
+                .map(|a| a.to_string_lossy())
+                .unwrap_or_default(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+/// Path the image at `source` gets once converted to `format`.
+pub fn converted_path(source: &Path, format: DiskFormat) -> PathBuf {
+    source.with_extension(format.as_str())
+}
+
+/// Convert `source` to `format` next to it and return the new path. The
+/// output is written under a temporary name and renamed when `qemu-img`
+/// succeeds, so an interrupted conversion never looks finished.
+pub fn convert(source: &Path, format: DiskFormat) -> Result<PathBuf, String> {
+    let target = converted_path(source, format);
+    if target == source {
+        return Ok(target);
+    }
+
+    let mut part = target.clone().into_os_string();
+    part.push(".part");
+    let part = PathBuf::from(part);
+
+    let result = run(&[
+        "convert".as_ref(),
+        "-O".as_ref(),
+        format.as_str().as_ref(),
+        source.as_os_str(),
+        part.as_os_str(),
+    ])
+    .and_then(|_| {
+        fs::rename(&part, &target).map_err(|e| format!("Failed to move '{}': {e}", part.display()))
+    });
+
+    if result.is_err() {
+        let _ = fs::remove_file(&part);
+    }
+    result.map(|_| target)
+}
+
+/// Check that `size` is something `qemu-img resize` accepts: an optional `+`
+/// followed by a number and an optional `K`, `M`, `G` or `T` suffix.
+pub fn validate_size(size: &str) -> Result<(), String> {
+    let digits = size.strip_prefix('+').unwrap_or(size);
+    let digits = digits
+        .strip_suffix(['K', 'M', 'G', 'T', 'k', 'm', 'g', 't'])
+        .unwrap_or(digits);
+    if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+        Ok(())
+    } else {
+        Err(format!(
+            "invalid size '{size}', expected e.g. `40G` or `+10G`"
+        ))
+    }
+}
+
+/// Grow the image at `path` in place to `size` (see [`validate_size`]).
+pub fn resize(path: &Path, size: &str) -> Result<(), String> {
+    validate_size(size)?;
+    run(&["resize".as_ref(), path.as_os_str(), size.as_ref()]).map(|_| ())
+}
+
+/// What `qemu-img info` reports about an image.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Info {
+    /// Detected format; Ubuntu's `.img` files are qcow2, for instance.
+    pub format: String,
+    /// Size of the disk as the guest sees it, in bytes.
+    #[serde(rename = "virtual-size")]
+    pub virtual_size: u64,
+}
+
+/// Inspect the image at `path`.
+pub fn info(path: &Path) -> Result<Info, String> {
+    let json = run(&["info".as_ref(), "--output=json".as_ref(), path.as_os_str()])?;
+    serde_json::from_str(&json).map_err(|e| {
+        format!(
+            "Unexpected `qemu-img info` output for '{}': {e}",
+            path.display()
+        )
+    })
+}
+
+/// Create a qcow2 `overlay` on top of `backing`, which stays untouched;
+/// `size` (see [`validate_size`]) grows the overlay beyond the backing image.
+pub fn create_overlay(backing: &Path, overlay: &Path, size: Option<&str>) -> Result<(), String> {
+    let backing_format = info(backing)?.format;
+    // Relative to the overlay, qemu resolves the backing file from its own
+    // directory.
+    let backing = backing
+        .canonicalize()
+        .map_err(|e| format!("Failed to resolve '{}': {e}", backing.display()))?;
+    run(&[
+        "create".as_ref(),
+        "-q".as_ref(),
+        "-f".as_ref(),
+        "qcow2".as_ref(),
+        "-F".as_ref(),
+        backing_format.as_ref(),
+        "-b".as_ref(),
+        backing.as_os_str(),
+        overlay.as_os_str(),
+    ])?;
+    match size {
+        Some(size) => resize(overlay, size),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DiskFormat, converted_path, validate_size};
+    use std::path::Path;
+
+    #[test]
+    fn converted_path_swaps_the_extension() {
+        assert_eq!(
+            converted_path(Path::new("/tmp/noble.img"), DiskFormat::Vmdk),
+            Path::new("/tmp/noble.vmdk")
+        );
+        assert_eq!(
+            converted_path(Path::new("debian-12.qcow2"), DiskFormat::Qcow2),
+            Path::new("debian-12.qcow2")
+        );
+    }
+
+    #[test]
+    fn validates_resize_sizes() {
+        assert!(validate_size("40G").is_ok());
+        assert!(validate_size("+10g").is_ok());
+        assert!(validate_size("1073741824").is_ok());
+        assert!(validate_size("G").is_err());
+        assert!(validate_size("forty").is_err());
+    }
+}
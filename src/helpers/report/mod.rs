@@ -0,0 +1,131 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cloud::{ChecksumKind, Image, ImageChecksum};
+use crate::helpers::checksum;
+
+/// What was downloaded and how it was checked, stored as `<image>.json`
+/// next to the image so it can be re-verified without asking upstream.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Report {
+    pub os: String,
+    pub name: String,
+    pub distro_version: String,
+    pub version: String,
+    pub arch: String,
+    pub variant: String,
+    /// Disk format; missing from reports written by earlier versions.
+    #[serde(default)]
+    pub format: String,
+    /// Where the image was fetched from.
+    pub url: String,
+    /// Algorithm name as understood by [`ChecksumKind::from_name`].
+    pub algorithm: String,
+    /// Digest of the file as downloaded.
+    pub digest: String,
+    /// Whether `digest` matched the checksum published upstream. Without a
+    /// published checksum it is only what was received.
+    pub verified: bool,
+    /// Seconds since the Unix epoch when the download finished.
+    pub downloaded_at: u64,
+}
+
+impl Report {
+    pub fn new(image: &Image, kind: ChecksumKind, digest: String, verified: bool) -> Self {
+        let downloaded_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        Self {
+            os: image.os().to_string(),
+            name: image.name().to_string(),
+            distro_version: image.distro_version().to_string(),
+            version: image.version().to_string(),
+            arch: image.arch_name().to_string(),
+            variant: image.variant().to_string(),
+            format: image.format().to_string(),
+            url: image.url().to_string(),
+            algorithm: kind.as_str().to_string(),
+            digest,
+            verified,
+            downloaded_at,
+        }
+    }
+
+    /// The recorded digest as a checksum to verify against.
+    pub fn checksum(&self) -> Result<ImageChecksum, String> {
+        let kind = ChecksumKind::from_name(&self.algorithm)
+            .ok_or_else(|| format!("Unknown checksum algorithm '{}'", self.algorithm))?;
+        Ok(ImageChecksum::new(kind, self.digest.clone()))
+    }
+}
+
+/// Path of the report belonging to `image_path`.
+pub fn sidecar_path(image_path: &Path) -> PathBuf {
+    let mut path = image_path.as_os_str().to_owned();
+    path.push(".json");
+    PathBuf::from(path)
+}
+
+/// Write `report` next to `image_path`.
+pub fn write(image_path: &Path, report: &Report) -> Result<(), String> {
+    let path = sidecar_path(image_path);
+    let json = serde_json::to_string_pretty(report)
+        .map_err(|e| format!("Failed to serialise '{}': {e}", path.display()))?;
+    fs::write(&path, json).map_err(|e| format!("Failed to write '{}': {e}", path.display()))
+}
+
+/// Read the report stored next to `image_path`.
+pub fn load(image_path: &Path) -> Result<Report, String> {
+    let path = sidecar_path(image_path);
+    let data = fs::read_to_string(&path)
+        .map_err(|e| format!("Failed to read '{}': {e}", path.display()))?;
+    serde_json::from_str(&data).map_err(|e| format!("Invalid report '{}': {e}", path.display()))
+}
+
+/// Re-hash `image_path` and compare it with its report.
+pub fn verify(image_path: &Path) -> Result<Report, String> {
+    let report = load(image_path)?;
+    checksum::verify_file(image_path, &report.checksum()?)?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Report, sidecar_path, verify, write};
+    use crate::cloud::{ChecksumKind, sample_image};
+    use std::path::Path;
+
+    #[test]
+    fn sidecar_sits_next_to_the_image() {
+        assert_eq!(
+            sidecar_path(Path::new("/data/debian-12.qcow2")),
+            Path::new("/data/debian-12.qcow2.json")
+        );
+    }
+
+    #[test]
+    fn round_trips_and_detects_changes() {
+        let dir = std::env::temp_dir().join(format!("cid-report-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let image_path = dir.join("image.img");
+        std::fs::write(&image_path, b"hello world").unwrap();
+
+        let image = sample_image();
+        let report = Report::new(
+            &image,
+            ChecksumKind::Sha256,
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9".to_string(),
+            true,
+        );
+        write(&image_path, &report).unwrap();
+        assert_eq!(verify(&image_path).unwrap(), report);
+
+        std::fs::write(&image_path, b"tampered").unwrap();
+        assert!(verify(&image_path).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+/// Limits `cache gc` and `library gc` enforce. Unset limits keep
+/// everything.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Retention {
+    /// Total bytes to shrink to, dropping the oldest entries first.
+    pub max_size: Option<u64>,
+    /// Entries older than this many days are dropped.
+    pub max_age_days: Option<u64>,
+    /// Entries kept per group (per release for the library), newest first.
+    pub keep_last: Option<usize>,
+}
+
+impl Retention {
+    /// Each limit of `self`, or of `fallback` where `self` sets none.
+    pub fn or(self, fallback: Retention) -> Retention {
+        Retention {
+            max_size: self.max_size.or(fallback.max_size),
+            max_age_days: self.max_age_days.or(fallback.max_age_days),
+            keep_last: self.keep_last.or(fallback.keep_last),
+        }
+    }
+}
+
+/// Parse a size such as `500M`, `50G` or `1.5T` (binary units); a bare
+/// number is bytes.
+pub fn parse_size(input: &str) -> Result<u64, String> {
+    let trimmed = input.trim();
+    let units = [
+        ('k', 1u64 << 10),
+        ('m', 1 << 20),
+        ('g', 1 << 30),
+        ('t', 1 << 40),
+    ];
+    let (number, multiplier) = trimmed
+        .chars()
+        .last()
+        .and_then(|last| {
+            let last = last.to_ascii_lowercase();
+            units.iter().find(|(unit, _)| *unit == last)
+        })
+        .map(|(_, multiplier)| (&trimmed[..trimmed.len() - 1], *multiplier))
+        .unwrap_or((trimmed, 1));
+    let value: f64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid size '{input}'"))?;
+    let bytes = (value * multiplier as f64).round();
+    if !bytes.is_finite() || bytes < 0.0 {
+        return Err(format!("invalid size '{input}'"));
+    }
+    Ok(bytes as u64)
+}
+
+/// Something a [`Retention`] may evict.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    /// Entries of a group count towards the same `keep_last`; ungrouped ones
+    /// are only subject to the other limits.
+    pub group: Option<String>,
+    /// When the entry was stored; age and eviction order derive from it.
+    pub stored: DateTime<Utc>,
+    /// Bytes held on disk.
+    pub size: u64,
+    /// File holding the bytes. Candidates sharing a file count its size
+    /// once and free it only when all of them go.
+    pub file: String,
+}
+
+/// What a garbage collection reclaimed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcSummary {
+    /// Entries dropped by the retention policy.
+    pub entries: usize,
+    /// Files deleted.
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// Bytes held by the candidates not `evicted`, shared files counted once.
+fn retained_size(candidates: &[Candidate], evicted: &[bool]) -> u64 {
+    let mut seen = BTreeSet::new();
+    candidates
+        .iter()
+        .zip(evicted)
+        .filter(|(c, evicted)| !**evicted && seen.insert(c.file.as_str()))
+        .map(|(c, _)| c.size)
+        .sum()
+}
+
+/// Indexes of the `candidates` `policy` evicts at `now`: those beyond the
+/// newest `keep_last` of their group, those older than `max_age_days`, then
+/// the oldest remaining ones until the rest fits in `max_size`.
+pub fn plan(candidates: &[Candidate], policy: &Retention, now: DateTime<Utc>) -> Vec<usize> {
+    let mut evicted = vec![false; candidates.len()];
+
+    if let Some(keep) = policy.keep_last {
+        let mut groups: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+        for (i, candidate) in candidates.iter().enumerate() {
+            if let Some(group) = &candidate.group {
+                groups.entry(group).or_default().push(i);
+            }
+        }
+        for members in groups.values_mut() {
+            members.sort_by_key(|&i| std::cmp::Reverse(candidates[i].stored));
+            for &i in members.iter().skip(keep) {
+                evicted[i] = true;
+            }
+        }
+    }
+
+    if let Some(days) = policy.max_age_days {
+        let max_age = i64::try_from(days)
+            .ok()
+            .and_then(TimeDelta::try_days)
+            .unwrap_or(TimeDelta::MAX);
+        for (i, candidate) in candidates.iter().enumerate() {
+            if now - candidate.stored > max_age {
+                evicted[i] = true;
+            }
+        }
+    }
+
+    if let Some(max_size) = policy.max_size {
+        let mut oldest_first: Vec<usize> = (0..candidates.len()).filter(|&i| !evicted[i]).collect();
+        oldest_first.sort_by_key(|&i| candidates[i].stored);
+        for i in oldest_first {
+            if retained_size(candidates, &evicted) <= max_size {
+                break;
+            }
+            evicted[i] = true;
+        }
+    }
+
+    (0..candidates.len()).filter(|&i| evicted[i]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Candidate, Retention, parse_size, plan};
+    use chrono::{TimeDelta, TimeZone, Utc};
+
+    #[test]
+    fn applies_every_limit() {
+        let now = Utc.with_ymd_and_hms(2025, 6, 1, 0, 0, 0).unwrap();
+        let candidate = |group: &str, days_old: i64, file: &str| Candidate {
+            group: Some(group.to_string()),
+            stored: now - TimeDelta::days(days_old),
+            size: 10,
+            file: file.to_string(),
+        };
+        let candidates = [
+            candidate("debian/12", 1, "a"),
+            candidate("debian/12", 5, "b"),
+            candidate("debian/12", 9, "c"),
+            candidate("ubuntu/24.04", 2, "d"),
+            candidate("ubuntu/24.04", 3, "d"),
+            candidate("ubuntu/22.04", 400, "e"),
+        ];
+
+        assert!(plan(&candidates, &Retention::default(), now).is_empty());
+        let keep_last = Retention {
+            keep_last: Some(2),
+            ..Retention::default()
+        };
+        assert_eq!(plan(&candidates, &keep_last, now), [2]);
+        let max_age = Retention {
+            max_age_days: Some(365),
+            ..keep_last
+        };
+        assert_eq!(plan(&candidates, &max_age, now), [2, 5]);
+        // Dropping one user of the shared file d frees nothing, so both go
+        // before the newer a.
+        let max_size = Retention {
+            max_size: Some(10),
+            ..max_age
+        };
+        assert_eq!(plan(&candidates, &max_size, now), [1, 2, 3, 4, 5]);
+
+        assert_eq!(parse_size("50G"), Ok(50 << 30));
+        assert_eq!(parse_size("1.5k"), Ok(1536));
+        assert!(parse_size("lots").is_err());
+    }
+}
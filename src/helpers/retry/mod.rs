@@ -0,0 +1,170 @@
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use reqwest::{RequestBuilder, Response, StatusCode};
+
+use crate::helpers::{fixtures, http};
+
+/// Process-wide retry policy (set at most once during start-up).
+static POLICY: OnceLock<RetryPolicy> = OnceLock::new();
+
+/// How often and how patiently transient HTTP failures are retried.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Total number of tries, including the first one.
+    pub attempts: u32,
+    /// Delay before the first retry; doubled for every further attempt.
+    pub base_delay: Duration,
+    /// Upper bound for a single backoff delay.
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 4,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(15),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with "full jitter" for the given retry number
+    /// (1 for the first retry).
+    pub fn delay_for(&self, retry: u32) -> Duration {
+        let exp = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)));
+        let capped = exp.min(self.max_delay);
+        let millis = capped.as_millis() as u64;
+        Duration::from_millis(fastrand::u64(millis / 2..=millis.max(1)))
+    }
+
+    /// Sleep for the backoff delay that precedes retry number `retry`.
+    pub async fn backoff(&self, retry: u32) {
+        tokio::time::sleep(self.delay_for(retry)).await;
+    }
+}
+
+/// Install the retry policy used by every HTTP call. Later calls are ignored.
+pub fn configure(policy: RetryPolicy) {
+    let _ = POLICY.set(policy);
+}
+
+/// The configured retry policy, or the defaults when none was installed.
+pub fn policy() -> RetryPolicy {
+    POLICY.get().copied().unwrap_or_default()
+}
+
+/// Status codes that are worth retrying because the server may recover.
+fn is_retryable_status(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+}
+
+/// Errors caused by the network rather than by the request itself.
+pub fn is_transient(err: &reqwest::Error) -> bool {
+    err.is_timeout() || err.is_connect() || err.is_request() || err.is_body()
+}
+
+/// Send the request produced by `make_request`, retrying transient network
+/// errors and retryable status codes with exponential backoff. The final
+/// response is returned as-is so callers keep their `error_for_status` checks.
+pub async fn send<F>(make_request: F) -> reqwest::Result<Response>
+where
+    F: Fn() -> RequestBuilder,
+{
+    let policy = policy();
+    let mut retry = 0;
+
+    loop {
+        let last_try = retry + 1 >= policy.attempts;
+        match http::send(make_request()).await {
+            Ok(res) if is_retryable_status(res.status()) && !last_try => {}
+            Err(err) if is_transient(&err) && !last_try => {}
+            outcome => return outcome,
+        }
+        retry += 1;
+        policy.backoff(retry).await;
+    }
+}
+
+/// Fetch the body of the request produced by `make_request`, retrying the
+/// whole exchange (including reading the body) on transient failures. Non
+/// success statuses are turned into errors. Bodies are recorded when
+/// [`fixtures`] are.
+pub async fn bytes<F>(make_request: F) -> reqwest::Result<Vec<u8>>
+where
+    F: Fn() -> RequestBuilder,
+{
+    let policy = policy();
+    let mut retry = 0;
+    let recorded = fixtures::recording()
+        .then(|| make_request().build().ok())
+        .flatten()
+        .map(|r| (r.method().clone(), r.url().to_string()));
+
+    loop {
+        let last_try = retry + 1 >= policy.attempts;
+        let outcome = match http::send(make_request()).await {
+            Ok(res) => match res.error_for_status() {
+                Ok(res) => {
+                    let (status, headers) = (res.status(), res.headers().clone());
+                    res.bytes().await.map(|body| {
+                        if let Some((method, url)) = &recorded {
+                            fixtures::record(method, url, status, &headers, &body);
+                        }
+                        body.to_vec()
+                    })
+                }
+                Err(err) => Err(err),
+            },
+            Err(err) => Err(err),
+        };
+        match outcome {
+            Err(err)
+                if !last_try
+                    && (is_transient(&err) || err.status().is_some_and(is_retryable_status)) => {}
+            outcome => return outcome,
+        }
+        retry += 1;
+        policy.backoff(retry).await;
+    }
+}
+
+/// Text flavour of [`bytes`].
+pub async fn text<F>(make_request: F) -> reqwest::Result<String>
+where
+    F: Fn() -> RequestBuilder,
+{
+    let body = bytes(make_request).await?;
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RetryPolicy, is_retryable_status};
+    use reqwest::StatusCode;
+    use std::time::Duration;
+
+    #[test]
+    fn backoff_grows_and_is_capped() {
+        let policy = RetryPolicy {
+            attempts: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(300),
+        };
+        assert!(policy.delay_for(1) <= Duration::from_millis(100));
+        assert!(policy.delay_for(2) >= Duration::from_millis(100));
+        assert!(policy.delay_for(10) <= Duration::from_millis(300));
+    }
+
+    #[test]
+    fn only_server_side_statuses_are_retried() {
+        assert!(is_retryable_status(StatusCode::BAD_GATEWAY));
+        assert!(is_retryable_status(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!is_retryable_status(StatusCode::NOT_FOUND));
+    }
+}
@@ -0,0 +1,255 @@
+use std::path::Path;
+
+use percent_encoding::utf8_percent_encode;
+use reqwest::Url;
+use tokio::io::AsyncReadExt;
+
+use super::{AWS_ENCODE, is_s3};
+use crate::cloud::Image;
+use crate::helpers::{http, retry};
+
+/// Size of every part but the last; 10 000 parts of it cover 640 GiB.
+const PART_SIZE: usize = 64 << 20;
+
+/// Parse an `s3://bucket/prefix/` upload target.
+pub fn parse_prefix(prefix: &str) -> Result<Url, String> {
+    Url::parse(prefix)
+        .ok()
+        .filter(|url| is_s3(url) && url.host_str().is_some_and(|bucket| !bucket.is_empty()))
+        .ok_or_else(|| format!("'{prefix}' is not an s3://bucket/prefix/ URL"))
+}
+
+/// Where the file `file_name` goes below `prefix`.
+pub fn object_url(prefix: &Url, file_name: &str) -> Result<Url, String> {
+    let mut url = prefix.clone();
+    url.set_query(None);
+    url.path_segments_mut()
+        .map_err(|_| format!("'{prefix}' is not an s3://bucket/prefix/ URL"))?
+        .pop_if_empty()
+        .push(file_name);
+    Ok(url)
+}
+
+/// `x-amz-tagging` value describing `image`: distro, release, arch, build
+/// and the published checksum, e.g. `checksum-sha256=<hex>`.
+pub fn tags(image: &Image) -> String {
+    let mut tags = vec![
+        ("distro".to_string(), image.os()),
+        ("release".to_string(), image.name()),
+        ("arch".to_string(), image.arch_name()),
+        ("build".to_string(), image.version()),
+    ];
+    if let (Some(kind), Some(value)) = (image.checksum_kind(), image.checksum_value()) {
+        tags.push((format!("checksum-{kind}"), value));
+    }
+    tags.iter()
+        .map(|(key, value)| {
+            format!(
+                "{}={}",
+                utf8_percent_encode(key, AWS_ENCODE),
+                utf8_percent_encode(value, AWS_ENCODE)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// `x-amz-meta-checksum` recorded with the object, `<kind>:<hex>`.
+fn checksum_metadata(image: &Image) -> Option<String> {
+    Some(format!(
+        "{}:{}",
+        image.checksum_kind()?,
+        image.checksum_value()?
+    ))
+}
+
+/// Value of the element `name` in the S3 XML response `body`.
+fn xml_text(body: &str, name: &str) -> Option<String> {
+    let doc = roxmltree::Document::parse(body).ok()?;
+    doc.descendants()
+        .find(|node| node.tag_name().name() == name)
+        .and_then(|node| node.text())
+        .map(str::to_string)
+}
+
+/// Send the request `make_request` builds and fail on an error status, or
+/// on the `<Error>` S3 may return with a 200.
+async fn call(
+    make_request: impl Fn() -> reqwest::RequestBuilder,
+) -> Result<reqwest::Response, String> {
+    let res = retry::send(make_request)
+        .await
+        .map_err(|e| format!("S3 request failed: {e}"))?;
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+    let url = res.url().clone();
+    let body = res.text().await.unwrap_or_default();
+    let message = xml_text(&body, "Message").unwrap_or(body);
+    Err(format!("S3 {url}: {status} {}", message.trim()))
+}
+
+/// Upload `file`, the verified download of `image`, below `prefix` as a
+/// multipart upload tagged with the image and its checksum. An object
+/// already recording the same checksum is left alone.
+pub async fn upload(file: &Path, prefix: &Url, image: &Image) -> Result<String, String> {
+    let file_name = file
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| format!("'{}' names no file", file.display()))?;
+    let url = object_url(prefix, &file_name)?;
+    let checksum = checksum_metadata(image);
+
+    if let Some(checksum) = &checksum
+        && let Ok(res) = retry::send(|| http::client().head(url.clone())).await
+        && res.status().is_success()
+        && res
+            .headers()
+            .get("x-amz-meta-checksum")
+            .is_some_and(|stored| stored == checksum.as_str())
+    {
+        return Ok(format!("{url} is already up to date"));
+    }
+
+    let tags = tags(image);
+    let mut create = url.clone();
+    create.set_query(Some("uploads"));
+    let res = call(|| {
+        let request = http::client()
+            .post(create.clone())
+            .header("x-amz-tagging", &tags)
+            .header("x-amz-meta-source-url", image.url());
+        match &checksum {
+            Some(checksum) => request.header("x-amz-meta-checksum", checksum),
+            None => request,
+        }
+    })
+    .await?;
+    let body = res
+        .text()
+        .await
+        .map_err(|e| format!("Failed to read the S3 response: {e}"))?;
+    let upload_id =
+        xml_text(&body, "UploadId").ok_or_else(|| format!("S3 returned no upload id: {body}"))?;
+
+    let result = upload_parts(file, &url, &upload_id).await;
+    if result.is_err() {
+        let mut abort = url.clone();
+        abort.query_pairs_mut().append_pair("uploadId", &upload_id);
+        let _ = retry::send(|| http::client().delete(abort.clone())).await;
+    }
+    let size = result?;
+    Ok(format!(
+        "Uploaded {} ({size} bytes) to {url}",
+        file.display()
+    ))
+}
+
+/// Send `file` in parts of [`PART_SIZE`] and complete the upload; returns
+/// the number of bytes sent.
+async fn upload_parts(file: &Path, url: &Url, upload_id: &str) -> Result<u64, String> {
+    let mut reader = tokio::fs::File::open(file)
+        .await
+        .map_err(|e| format!("Failed to open '{}': {e}", file.display()))?;
+    let mut etags = Vec::new();
+    let mut sent = 0;
+    loop {
+        let mut part = Vec::with_capacity(PART_SIZE);
+        (&mut reader)
+            .take(PART_SIZE as u64)
+            .read_to_end(&mut part)
+            .await
+            .map_err(|e| format!("Failed to read '{}': {e}", file.display()))?;
+        // An empty file still needs one (empty) part.
+        if part.is_empty() && !etags.is_empty() {
+            break;
+        }
+        let number = etags.len() + 1;
+        let mut part_url = url.clone();
+        part_url
+            .query_pairs_mut()
+            .append_pair("partNumber", &number.to_string())
+            .append_pair("uploadId", upload_id);
+        let len = part.len();
+        let body = bytes::Bytes::from(part);
+        let res = call(|| http::client().put(part_url.clone()).body(body.clone())).await?;
+        let etag = res
+            .headers()
+            .get("etag")
+            .and_then(|etag| etag.to_str().ok())
+            .ok_or_else(|| format!("S3 returned no ETag for part {number}"))?
+            .to_string();
+        etags.push(etag);
+        sent += len as u64;
+        if len < PART_SIZE {
+            break;
+        }
+    }
+
+    let mut complete = url.clone();
+    complete
+        .query_pairs_mut()
+        .append_pair("uploadId", upload_id);
+    let body = complete_body(&etags);
+    let res = call(|| http::client().post(complete.clone()).body(body.clone())).await?;
+    let text = res.text().await.unwrap_or_default();
+    if text.contains("<Error>") {
+        return Err(format!(
+            "S3 could not complete the upload: {}",
+            xml_text(&text, "Message").unwrap_or(text)
+        ));
+    }
+    Ok(sent)
+}
+
+/// Body of `CompleteMultipartUpload` listing the parts' `etags` in order.
+fn complete_body(etags: &[String]) -> String {
+    let parts: String = etags
+        .iter()
+        .enumerate()
+        .map(|(i, etag)| {
+            format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                i + 1,
+                etag.replace('&', "&amp;").replace('"', "&quot;")
+            )
+        })
+        .collect();
+    format!("<CompleteMultipartUpload>{parts}</CompleteMultipartUpload>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{complete_body, object_url, parse_prefix, tags};
+    use crate::cloud::{ChecksumKind, ImageChecksum, sample_image};
+
+    #[test]
+    fn tags_objects_below_the_prefix() {
+        let prefix = parse_prefix("s3://mirror/images/").unwrap();
+        assert_eq!(
+            object_url(&prefix, "noble server.img").unwrap().as_str(),
+            "s3://mirror/images/noble%20server.img"
+        );
+        assert_eq!(
+            object_url(&parse_prefix("s3://mirror").unwrap(), "noble.img")
+                .unwrap()
+                .as_str(),
+            "s3://mirror/noble.img"
+        );
+        assert!(parse_prefix("https://mirror/images/").is_err());
+        assert!(parse_prefix("s3:///images/").is_err());
+
+        let image =
+            sample_image().with_checksums([ImageChecksum::new(ChecksumKind::Sha256, "ab12")]);
+        assert_eq!(
+            tags(&image),
+            "distro=debian&release=bookworm&arch=amd64&build=20250210-2019&checksum-sha256=ab12"
+        );
+        assert_eq!(
+            complete_body(&["\"e1\"".to_string()]),
+            "<CompleteMultipartUpload><Part><PartNumber>1</PartNumber>\
+             <ETag>&quot;e1&quot;</ETag></Part></CompleteMultipartUpload>"
+        );
+    }
+}
@@ -0,0 +1,88 @@
+use reqwest::Url;
+
+/// Check that `name`, taken from a listing or URL, is a plain file name: not
+/// empty, not `.` or `..`, and free of path separators and control
+/// characters. A hostile mirror could otherwise make downloads land outside
+/// the destination directory.
+pub fn file_name(name: &str) -> Result<&str, String> {
+    let invalid = name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.contains(['/', '\\'])
+        || name.chars().any(char::is_control);
+    if invalid {
+        Err(format!("Refusing unsafe file name {name:?}"))
+    } else {
+        Ok(name)
+    }
+}
+
+/// Check that `url` points into the tree rooted at `base`: same scheme, host
+/// and port, and a path below the base path once `.` and `..` segments are
+/// resolved.
+pub fn url_under(base: &str, url: &str) -> Result<(), String> {
+    let parsed_base = Url::parse(base).map_err(|e| format!("Invalid base URL {base}: {e}"))?;
+    let parsed = Url::parse(url).map_err(|e| format!("Invalid URL {url:?}: {e}"))?;
+    let base_dir = match parsed_base.path().rfind('/') {
+        Some(idx) => &parsed_base.path()[..=idx],
+        None => "/",
+    };
+    if parsed.origin() == parsed_base.origin() && parsed.path().starts_with(base_dir) {
+        Ok(())
+    } else {
+        Err(format!(
+            "Refusing {url}: it leaves the repository at {base}"
+        ))
+    }
+}
+
+/// `base` joined with the listed file `name`, after checking both.
+pub fn artifact_url(base: &str, name: &str) -> Result<String, String> {
+    let url = format!("{base}{}", file_name(name)?);
+    url_under(base, &url)?;
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{artifact_url, file_name, url_under};
+
+    #[test]
+    fn rejects_traversal_and_control_characters() {
+        assert!(file_name("debian-12-genericcloud-amd64.qcow2").is_ok());
+        for name in [
+            "",
+            ".",
+            "..",
+            "../etc/passwd",
+            "a/b",
+            "a\\b",
+            "evil\n.img",
+            "x\0y",
+        ] {
+            assert!(file_name(name).is_err(), "{name:?} should be rejected");
+        }
+    }
+
+    #[test]
+    fn urls_must_stay_below_the_base() {
+        let base = "https://repo.almalinux.org/almalinux/9/cloud/x86_64/images/";
+        assert!(url_under(base, &format!("{base}AlmaLinux-9.qcow2")).is_ok());
+        assert!(url_under(base, &format!("{base}../../../../etc/passwd")).is_err());
+        assert!(
+            url_under(
+                base,
+                "https://evil.invalid/almalinux/9/cloud/x86_64/images/x"
+            )
+            .is_err()
+        );
+        assert!(
+            url_under(
+                base,
+                "http://repo.almalinux.org/almalinux/9/cloud/x86_64/images/x"
+            )
+            .is_err()
+        );
+        assert!(artifact_url(base, "../x").is_err());
+    }
+}
@@ -0,0 +1,542 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::Selection;
+use crate::helpers::{find_program, paths};
+
+/// How often a scheduled refresh runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+    /// A systemd `OnCalendar=` expression, e.g. `Sat *-*-* 03:00`.
+    Calendar(String),
+}
+
+impl Frequency {
+    /// `OnCalendar=` value of the timer.
+    pub fn on_calendar(&self) -> &str {
+        match self {
+            Frequency::Daily => "daily",
+            Frequency::Weekly => "weekly",
+            Frequency::Monthly => "monthly",
+            Frequency::Calendar(spec) => spec,
+        }
+    }
+
+    /// Schedule field of a crontab line; calendar expressions have none.
+    pub fn cron(&self) -> Option<&'static str> {
+        match self {
+            Frequency::Daily => Some("@daily"),
+            Frequency::Weekly => Some("@weekly"),
+            Frequency::Monthly => Some("@monthly"),
+            Frequency::Calendar(_) => None,
+        }
+    }
+}
+
+/// A scheduled run of `program` with `args`, installed under `name`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Job {
+    /// Unit name without suffix, e.g. `cloud-images-debian-bookworm-amd64`.
+    pub name: String,
+    pub description: String,
+    pub program: PathBuf,
+    pub args: Vec<String>,
+}
+
+/// Default job name for the image `parts` (distro, release, arch).
+pub fn job_name(parts: &[&str]) -> String {
+    std::iter::once("cloud-images")
+        .chain(parts.iter().copied())
+        .collect::<Vec<_>>()
+        .join("-")
+        .to_ascii_lowercase()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+/// Check that `name` can name units and tag a crontab line: letters,
+/// digits and `_.@-` only, so it cannot leave the unit directory.
+pub fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty()
+        || name.starts_with('.')
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '@' | '-'))
+    {
+        return Err(format!(
+            "Invalid job name '{name}': use letters, digits and '_.@-' only"
+        ));
+    }
+    Ok(())
+}
+
+/// Where a scheduled refresh puts the images and which `indexes.json` it
+/// reads on top of the user's.
+#[derive(Debug, Clone, Default)]
+pub struct Output {
+    pub dir: PathBuf,
+    pub flat: bool,
+    pub indexes: Option<PathBuf>,
+}
+
+/// A job refreshing every entry of the manifest at `manifest` into
+/// `output`, named `name` or after the manifest.
+pub fn manifest_job(manifest: &Path, output: &Output, name: Option<&str>) -> Result<Job, String> {
+    let manifest = absolute(manifest)?;
+    let stem = manifest
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let args = vec!["--manifest".to_string(), manifest.display().to_string()];
+    refresh_job(
+        name.map_or_else(|| job_name(&[&stem]), str::to_string),
+        format!("Refresh the cloud images of {}", manifest.display()),
+        args,
+        output,
+    )
+}
+
+/// A job downloading `selection` into `output` again at every run, at
+/// `build` (`latest` follows the newest one). Named `name` or after the
+/// distro, release and arch.
+pub fn selection_job(
+    selection: &Selection,
+    build: &str,
+    output: &Output,
+    name: Option<&str>,
+) -> Result<Job, String> {
+    let image = &selection.image;
+    let args = vec![
+        "--distro".to_string(),
+        selection.distro.to_lowercase(),
+        "--release".to_string(),
+        selection.release.clone(),
+        "--arch".to_string(),
+        image.arch_name().to_string(),
+        "--variant".to_string(),
+        image.variant().to_string(),
+        "--format".to_string(),
+        image.format().to_string(),
+        "--build".to_string(),
+        build.to_string(),
+    ];
+    refresh_job(
+        name.map_or_else(
+            || job_name(&[&selection.distro, &selection.release, image.arch_name()]),
+            str::to_string,
+        ),
+        format!(
+            "Refresh the {} {} {} cloud image",
+            selection.distro,
+            selection.release,
+            image.arch_name()
+        ),
+        args,
+        output,
+    )
+}
+
+/// The job `name` running this program with the selection `args`, the
+/// `output` flags and the flags of an unattended run.
+fn refresh_job(
+    name: String,
+    description: String,
+    mut args: Vec<String>,
+    output: &Output,
+) -> Result<Job, String> {
+    validate_name(&name)?;
+    args.extend([
+        "--output-dir".to_string(),
+        absolute(&output.dir)?.display().to_string(),
+    ]);
+    if output.flat {
+        args.push("--flat".to_string());
+    }
+    if let Some(indexes) = &output.indexes {
+        args.extend([
+            "--indexes".to_string(),
+            absolute(indexes)?.display().to_string(),
+        ]);
+    }
+    // Nobody is there to answer prompts or watch progress bars.
+    args.extend(
+        [
+            "--overwrite",
+            "--on-mismatch",
+            "redownload",
+            "--progress",
+            "log",
+        ]
+        .map(str::to_string),
+    );
+    Ok(Job {
+        name,
+        description,
+        program: std::env::current_exe()
+            .map_err(|e| format!("Failed to locate this program: {e}"))?,
+        args,
+    })
+}
+
+fn absolute(path: &Path) -> Result<PathBuf, String> {
+    std::path::absolute(path).map_err(|e| format!("Failed to resolve '{}': {e}", path.display()))
+}
+
+/// `text` on one line, for unit keys and crontab comments: line breaks
+/// and other control characters become spaces.
+fn one_line(text: &str) -> String {
+    text.chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect()
+}
+
+/// Quote `arg` for a systemd `ExecStart=` line, where `%` introduces
+/// specifiers and `$` variables.
+fn systemd_quote(arg: &str) -> String {
+    let escaped = arg.replace('%', "%%").replace('$', "$$");
+    if !escaped.is_empty()
+        && !escaped
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | ';'))
+    {
+        return escaped;
+    }
+    format!("\"{}\"", escaped.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Quote `arg` for `/bin/sh` in a crontab, where `%` ends the command.
+fn cron_quote(arg: &str) -> String {
+    let quoted = if !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:=@+,".contains(c))
+    {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    };
+    quoted.replace('%', "\\%")
+}
+
+/// The `.service` unit running `job` once.
+pub fn service_unit(job: &Job) -> String {
+    let command: Vec<String> = std::iter::once(job.program.to_string_lossy().into_owned())
+        .chain(job.args.iter().cloned())
+        .map(|arg| systemd_quote(&arg))
+        .collect();
+    format!(
+        "[Unit]\nDescription={}\nWants=network-online.target\nAfter=network-online.target\n\n\
+         [Service]\nType=oneshot\nExecStart={}\n",
+        one_line(&job.description).replace('%', "%%"),
+        command.join(" ")
+    )
+}
+
+/// The `.timer` unit starting `job` at `frequency`; runs missed while the
+/// machine was off are caught up on boot.
+pub fn timer_unit(job: &Job, frequency: &Frequency) -> String {
+    format!(
+        "[Unit]\nDescription={} (timer)\n\n[Timer]\nOnCalendar={}\nPersistent=true\n\
+         RandomizedDelaySec=1h\n\n[Install]\nWantedBy=timers.target\n",
+        one_line(&job.description).replace('%', "%%"),
+        frequency.on_calendar()
+    )
+}
+
+/// Comment tagging the crontab line of the job `name`.
+fn cron_marker(name: &str) -> String {
+    format!("# cloud-images-downloader: {name}")
+}
+
+/// The crontab line running `job` at `schedule`, tagged with its name.
+pub fn cron_line(job: &Job, schedule: &str) -> String {
+    let command: Vec<String> = std::iter::once(job.program.to_string_lossy().into_owned())
+        .chain(job.args.iter().cloned())
+        .map(|arg| cron_quote(&arg))
+        .collect();
+    format!(
+        "{schedule} {} {}",
+        command.join(" "),
+        cron_marker(&job.name)
+    )
+}
+
+/// Directory of the user's systemd units.
+fn unit_dir() -> Result<PathBuf, String> {
+    paths::systemd_user_dir().ok_or_else(|| "No home directory to install units into".to_string())
+}
+
+fn systemctl(args: &[&str]) -> Result<(), String> {
+    let systemctl = find_program("systemctl")
+        .ok_or_else(|| "systemctl was not found on PATH; use --cron instead".to_string())?;
+    let output = Command::new(&systemctl)
+        .arg("--user")
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run '{}': {e}", systemctl.display()))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "systemctl --user {} failed ({}): {}",
+            args.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+fn write(path: &Path, text: &str) -> Result<(), String> {
+    fs::write(path, text).map_err(|e| format!("Failed to write '{}': {e}", path.display()))
+}
+
+/// Write the service and timer of `job` to the user's unit directory and
+/// enable the timer; returns the timer's path.
+pub fn install_systemd(job: &Job, frequency: &Frequency) -> Result<PathBuf, String> {
+    validate_name(&job.name)?;
+    let dir = unit_dir()?;
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create '{}': {e}", dir.display()))?;
+    write(
+        &dir.join(format!("{}.service", job.name)),
+        &service_unit(job),
+    )?;
+    let timer = dir.join(format!("{}.timer", job.name));
+    write(&timer, &timer_unit(job, frequency))?;
+    systemctl(&["daemon-reload"])?;
+    systemctl(&["enable", "--now", &format!("{}.timer", job.name)])?;
+    Ok(timer)
+}
+
+/// Disable the timer of the job `name` and delete its units; returns
+/// whether there was anything to remove.
+pub fn remove_systemd(name: &str) -> Result<bool, String> {
+    validate_name(name)?;
+    let dir = unit_dir()?;
+    let units = [
+        dir.join(format!("{name}.timer")),
+        dir.join(format!("{name}.service")),
+    ];
+    if !units.iter().any(|unit| unit.exists()) {
+        return Ok(false);
+    }
+    let _ = systemctl(&["disable", "--now", &format!("{name}.timer")]);
+    for unit in &units {
+        let _ = fs::remove_file(unit);
+    }
+    let _ = systemctl(&["daemon-reload"]);
+    Ok(true)
+}
+
+fn crontab() -> Result<PathBuf, String> {
+    find_program("crontab").ok_or_else(|| "crontab was not found on PATH".to_string())
+}
+
+/// The user's crontab; empty when there is none yet.
+fn read_crontab() -> Result<String, String> {
+    let output = Command::new(crontab()?)
+        .arg("-l")
+        .output()
+        .map_err(|e| format!("Failed to run crontab: {e}"))?;
+    // `crontab -l` fails when the user has no crontab.
+    Ok(if output.status.success() {
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    } else {
+        String::new()
+    })
+}
+
+fn write_crontab(text: &str) -> Result<(), String> {
+    let mut child = Command::new(crontab()?)
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run crontab: {e}"))?;
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(text.as_bytes())
+        .map_err(|e| format!("Failed to write the crontab: {e}"))?;
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("Failed to run crontab: {e}"))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "crontab failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+/// `crontab` without the line of the job `name`.
+fn without_job(crontab: &str, name: &str) -> String {
+    let marker = cron_marker(name);
+    crontab
+        .lines()
+        .filter(|line| !line.ends_with(&marker))
+        .map(|line| format!("{line}\n"))
+        .collect()
+}
+
+/// Add `line` for the job `name` to the user's crontab, replacing the
+/// job's previous line.
+pub fn install_cron(name: &str, line: &str) -> Result<(), String> {
+    validate_name(name)?;
+    let mut crontab = without_job(&read_crontab()?, name);
+    crontab.push_str(line);
+    crontab.push('\n');
+    write_crontab(&crontab)
+}
+
+/// Drop the line of the job `name` from the user's crontab; returns whether
+/// there was one.
+pub fn remove_cron(name: &str) -> Result<bool, String> {
+    validate_name(name)?;
+    let current = read_crontab()?;
+    let updated = without_job(&current, name);
+    if updated.len() == current.len() {
+        return Ok(false);
+    }
+    write_crontab(&updated)?;
+    Ok(true)
+}
+
+/// Install `job` to run at `frequency`: as a line of the user's crontab
+/// with `cron`, as systemd user units otherwise. Returns what was installed.
+pub fn install(job: &Job, frequency: &Frequency, cron: bool) -> Result<String, String> {
+    if cron {
+        let schedule = frequency.cron().ok_or_else(|| {
+            "--on-calendar needs systemd; use --daily, --weekly or --monthly".to_string()
+        })?;
+        let line = cron_line(job, schedule);
+        install_cron(&job.name, &line)?;
+        Ok(format!("Added to the crontab: {line}"))
+    } else {
+        let timer = install_systemd(job, frequency)?;
+        Ok(format!(
+            "Installed {} ({}); `systemctl --user list-timers {}` shows the next run",
+            timer.display(),
+            frequency.on_calendar(),
+            job.name
+        ))
+    }
+}
+
+/// Remove the job `name` from the crontab with `cron`, else its systemd
+/// units; returns whether there was one.
+pub fn remove(name: &str, cron: bool) -> Result<bool, String> {
+    if cron {
+        remove_cron(name)
+    } else {
+        remove_systemd(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Frequency, Job, Output, cron_line, job_name, remove_systemd, selection_job, service_unit,
+        timer_unit, validate_name, without_job,
+    };
+    use crate::Selection;
+    use crate::cloud::sample_image;
+    use std::path::PathBuf;
+
+    #[test]
+    fn writes_units_and_cron_lines() {
+        let job = Job {
+            name: job_name(&["Debian", "bookworm", "amd64"]),
+            description: "Refresh debian bookworm amd64".to_string(),
+            program: PathBuf::from("/usr/bin/cloud-images-downloader"),
+            args: vec![
+                "--distro".to_string(),
+                "debian".to_string(),
+                "--output-dir".to_string(),
+                "/srv/My Images/100%".to_string(),
+            ],
+        };
+        assert_eq!(job.name, "cloud-images-debian-bookworm-amd64");
+        assert!(service_unit(&job).contains(
+            "ExecStart=/usr/bin/cloud-images-downloader --distro debian --output-dir \
+             \"/srv/My Images/100%%\"\n"
+        ));
+        let timer = timer_unit(&job, &Frequency::Weekly);
+        assert!(timer.contains("OnCalendar=weekly\nPersistent=true\n"));
+        assert!(timer.contains("WantedBy=timers.target"));
+
+        let line = cron_line(&job, Frequency::Weekly.cron().unwrap());
+        assert_eq!(
+            line,
+            "@weekly /usr/bin/cloud-images-downloader --distro debian --output-dir \
+             '/srv/My Images/100\\%' # cloud-images-downloader: cloud-images-debian-bookworm-amd64"
+        );
+        assert_eq!(
+            without_job(&format!("MAILTO=me\n{line}\n"), &job.name),
+            "MAILTO=me\n"
+        );
+        assert_eq!(Frequency::Calendar("Sat 03:00".to_string()).cron(), None);
+    }
+
+    #[test]
+    fn rejects_names_leaving_the_unit_directory() {
+        assert!(validate_name("cloud-images-debian_12.x@home").is_ok());
+        for name in ["", "../../x", "a/b", ".hidden", "a b", "job\nX=1"] {
+            assert!(validate_name(name).is_err(), "{name:?}");
+        }
+        assert!(remove_systemd("../../x").is_err());
+
+        let selection = Selection {
+            distro: "Debian".to_string(),
+            release: "bookworm".to_string(),
+            arch: "amd64".to_string(),
+            version: "bookworm (20250210-2019)".to_string(),
+            image: sample_image(),
+        };
+        let output = Output {
+            dir: PathBuf::from("/srv/images"),
+            ..Output::default()
+        };
+        assert!(selection_job(&selection, "latest", &output, Some("../../x")).is_err());
+        let job = selection_job(&selection, "latest", &output, None).unwrap();
+        assert_eq!(job.name, "cloud-images-debian-bookworm-amd64");
+        assert!(job.args.ends_with(&[
+            "--output-dir".to_string(),
+            "/srv/images".to_string(),
+            "--overwrite".to_string(),
+            "--on-mismatch".to_string(),
+            "redownload".to_string(),
+            "--progress".to_string(),
+            "log".to_string(),
+        ]));
+    }
+
+    #[test]
+    fn keeps_the_description_on_one_line() {
+        let job = Job {
+            name: "job".to_string(),
+            description: "Refresh\nExecStartPre=/bin/evil 100%".to_string(),
+            program: PathBuf::from("/usr/bin/cloud-images-downloader"),
+            args: Vec::new(),
+        };
+        let service = service_unit(&job);
+        assert!(service.contains("Description=Refresh ExecStartPre=/bin/evil 100%%\n"));
+        assert!(!service.contains("\nExecStartPre"));
+        assert!(timer_unit(&job, &Frequency::Daily).contains("Description=Refresh ExecStartPre"));
+    }
+}
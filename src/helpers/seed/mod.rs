@@ -0,0 +1,255 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::helpers::{find_program, paths};
+
+/// Volume label cloud-init looks for.
+const LABEL: &str = "cidata";
+
+/// Container of a NoCloud seed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SeedFormat {
+    /// ISO 9660 image, built with xorriso, genisoimage or mkisofs.
+    #[default]
+    Iso,
+    /// FAT image, built with mkfs.vfat and mcopy.
+    Vfat,
+}
+
+impl SeedFormat {
+    /// `.img` and `.vfat` outputs are FAT images, anything else an ISO.
+    pub fn for_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("img" | "vfat") => SeedFormat::Vfat,
+            _ => SeedFormat::Iso,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            SeedFormat::Iso => "iso",
+            SeedFormat::Vfat => "img",
+        }
+    }
+}
+
+impl fmt::Display for SeedFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SeedFormat::Iso => "iso",
+            SeedFormat::Vfat => "vfat",
+        })
+    }
+}
+
+/// The files of a NoCloud seed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Seed {
+    pub user_data: PathBuf,
+    /// Written from the instance id and hostname when not given.
+    pub meta_data: Option<PathBuf>,
+    pub network_config: Option<PathBuf>,
+    /// `local-hostname` of the generated `meta-data`; the distro by default.
+    pub hostname: Option<String>,
+    pub format: SeedFormat,
+}
+
+/// A minimal `meta-data` naming the instance.
+pub fn meta_data(instance_id: &str, hostname: &str) -> String {
+    format!("instance-id: {instance_id}\nlocal-hostname: {hostname}\n")
+}
+
+/// Where the seed for the image at `image` is written: `<stem>-seed.iso`
+/// (or `.img`) next to it.
+pub fn path_for(image: &Path, format: SeedFormat) -> PathBuf {
+    let stem = image
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "image".to_string());
+    image.with_file_name(format!("{stem}-seed.{}", format.extension()))
+}
+
+/// Build `seed` into `output`, generating `meta-data` for its hostname (or
+/// `default_hostname`) when the seed has none. Like the `qemu-img` steps, the result only appears
+/// under its final name once the tool succeeded.
+pub fn build(output: &Path, seed: &Seed, default_hostname: &str) -> Result<(), String> {
+    let hostname = seed.hostname.as_deref().unwrap_or(default_hostname);
+    let user_data = fs::read_to_string(&seed.user_data)
+        .map_err(|e| format!("Failed to read '{}': {e}", seed.user_data.display()))?;
+    if !user_data.starts_with('#') {
+        eprintln!(
+            "Warning: '{}' does not start with `#cloud-config` or `#!`; cloud-init may ignore it",
+            seed.user_data.display()
+        );
+    }
+
+    let staging = paths::temp_dir().join(format!("seed-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&staging);
+    fs::create_dir_all(&staging)
+        .map_err(|e| format!("Failed to create '{}': {e}", staging.display()))?;
+    let mut part = output.as_os_str().to_owned();
+    part.push(".part");
+    let part = PathBuf::from(part);
+
+    let result = stage(&staging, seed, hostname)
+        .and_then(|files| match seed.format {
+            SeedFormat::Iso => iso(&part, &files),
+            SeedFormat::Vfat => vfat(&part, &files),
+        })
+        .and_then(|_| {
+            fs::rename(&part, output)
+                .map_err(|e| format!("Failed to move '{}': {e}", part.display()))
+        });
+    let _ = fs::remove_dir_all(&staging);
+    if result.is_err() {
+        let _ = fs::remove_file(&part);
+    }
+    result
+}
+
+/// Copy the seed files into `dir` under the names cloud-init expects.
+fn stage(dir: &Path, seed: &Seed, hostname: &str) -> Result<Vec<PathBuf>, String> {
+    let copy = |from: &Path, name: &str| {
+        let to = dir.join(name);
+        fs::copy(from, &to)
+            .map(|_| to)
+            .map_err(|e| format!("Failed to copy '{}': {e}", from.display()))
+    };
+    let mut files = vec![copy(&seed.user_data, "user-data")?];
+    match &seed.meta_data {
+        Some(path) => files.push(copy(path, "meta-data")?),
+        None => {
+            let path = dir.join("meta-data");
+            fs::write(&path, meta_data(&format!("iid-{hostname}"), hostname))
+                .map_err(|e| format!("Failed to write '{}': {e}", path.display()))?;
+            files.push(path);
+        }
+    }
+    if let Some(path) = &seed.network_config {
+        files.push(copy(path, "network-config")?);
+    }
+    Ok(files)
+}
+
+/// Check that the tools building a `format` seed are on `PATH`.
+pub fn locate(format: SeedFormat) -> Result<(), String> {
+    match format {
+        SeedFormat::Iso => iso_tool().map(|_| ()),
+        SeedFormat::Vfat => vfat_tools().map(|_| ()),
+    }
+}
+
+/// The ISO builder and whether it is xorriso, which needs `-as mkisofs`.
+fn iso_tool() -> Result<(PathBuf, bool), String> {
+    if let Some(xorriso) = find_program("xorriso") {
+        return Ok((xorriso, true));
+    }
+    find_program("genisoimage")
+        .or_else(|| find_program("mkisofs"))
+        .map(|program| (program, false))
+        .ok_or_else(|| {
+            "no ISO tool was found on PATH; install xorriso, genisoimage or mkisofs \
+             (or build a `vfat` seed)"
+                .to_string()
+        })
+}
+
+fn vfat_tools() -> Result<(PathBuf, PathBuf), String> {
+    let missing = || {
+        "mkfs.vfat and mcopy were not found on PATH; install dosfstools and mtools \
+         (or build an `iso` seed)"
+            .to_string()
+    };
+    let mkfs = find_program("mkfs.vfat")
+        .or_else(|| find_program("mkfs.fat"))
+        .ok_or_else(missing)?;
+    Ok((mkfs, find_program("mcopy").ok_or_else(missing)?))
+}
+
+fn iso(output: &Path, files: &[PathBuf]) -> Result<(), String> {
+    let (program, xorriso) = iso_tool()?;
+    let mut args: Vec<&std::ffi::OsStr> = if xorriso {
+        vec!["-as".as_ref(), "mkisofs".as_ref()]
+    } else {
+        Vec::new()
+    };
+    args.extend([
+        "-quiet".as_ref(),
+        "-output".as_ref(),
+        output.as_os_str(),
+        "-volid".as_ref(),
+        LABEL.as_ref(),
+        "-joliet".as_ref(),
+        "-rock".as_ref(),
+    ]);
+    args.extend(files.iter().map(|f| f.as_os_str()));
+    run(&program, &args)
+}
+
+fn vfat(output: &Path, files: &[PathBuf]) -> Result<(), String> {
+    let (mkfs, mcopy) = vfat_tools()?;
+    let _ = fs::remove_file(output);
+    run(
+        &mkfs,
+        &[
+            "-n".as_ref(),
+            LABEL.to_uppercase().as_ref(),
+            "-C".as_ref(),
+            output.as_os_str(),
+            // KiB; plenty for user-data.
+            "2048".as_ref(),
+        ],
+    )?;
+    let mut args: Vec<&std::ffi::OsStr> = vec!["-o".as_ref(), "-i".as_ref(), output.as_os_str()];
+    args.extend(files.iter().map(|f| f.as_os_str()));
+    args.push("::".as_ref());
+    run(&mcopy, &args)
+}
+
+/// Run `program`, turning a non-zero exit into an error carrying its stderr.
+fn run(program: &Path, args: &[&std::ffi::OsStr]) -> Result<(), String> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run '{}': {e}", program.display()))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} failed ({}): {}",
+            program.display(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SeedFormat, meta_data, path_for};
+    use std::path::Path;
+
+    #[test]
+    fn names_the_seed_after_the_image() {
+        let image = Path::new("/data/debian-12-genericcloud-amd64.qcow2");
+        assert_eq!(
+            path_for(image, SeedFormat::Iso),
+            Path::new("/data/debian-12-genericcloud-amd64-seed.iso")
+        );
+        assert_eq!(
+            SeedFormat::for_path(&path_for(image, SeedFormat::Vfat)),
+            SeedFormat::Vfat
+        );
+        assert_eq!(SeedFormat::for_path(Path::new("seed.iso")), SeedFormat::Iso);
+        assert_eq!(
+            meta_data("iid-debian", "debian"),
+            "instance-id: iid-debian\nlocal-hostname: debian\n"
+        );
+    }
+}
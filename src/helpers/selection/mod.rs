@@ -0,0 +1,212 @@
+use anyhow::{Result, ensure};
+
+use crate::cloud::{Image, sort_builds_newest_first, sort_newest_first};
+use crate::helpers::{choose_build, choose_or_preset, trace};
+
+/// Reads the value of a facet from an image; `None` leaves the image out of
+/// the menu and out of the selection.
+type Extractor<'a> = Box<dyn for<'i> Fn(&'i Image) -> Option<&'i str> + 'a>;
+
+/// How the values of a facet are offered.
+enum Menu {
+    /// Alphabetical list.
+    Plain,
+    /// Newest first.
+    NewestFirst,
+    /// Builds newest first, trimmed to `max_builds` by [`choose_build`].
+    Builds(Option<usize>),
+}
+
+/// One property the user narrows the candidate images down by, such as the
+/// distro version, the build or the variant.
+pub struct Facet<'a> {
+    name: &'static str,
+    title: &'static str,
+    preset: Option<&'a str>,
+    menu: Menu,
+    extract: Extractor<'a>,
+}
+
+impl<'a> Facet<'a> {
+    /// Facet called `name` in error messages, offered under `title` with the
+    /// values `extract` reads from the images.
+    pub fn new(
+        name: &'static str,
+        title: &'static str,
+        extract: impl for<'i> Fn(&'i Image) -> Option<&'i str> + 'a,
+    ) -> Self {
+        Self {
+            name,
+            title,
+            preset: None,
+            menu: Menu::Plain,
+            extract: Box::new(extract),
+        }
+    }
+
+    /// Answer the facet with `preset` instead of prompting, when set.
+    pub fn preset(mut self, preset: Option<&'a str>) -> Self {
+        self.preset = preset;
+        self
+    }
+
+    /// Offer the values newest (largest) first.
+    pub fn newest_first(mut self) -> Self {
+        self.menu = Menu::NewestFirst;
+        self
+    }
+
+    /// Offer the values as builds, newest first by [`BuildId`](crate::cloud::BuildId)
+    /// with their age and trimmed to `max_builds`.
+    pub fn builds(mut self, max_builds: Option<usize>) -> Self {
+        self.menu = Menu::Builds(max_builds);
+        self
+    }
+
+    fn choose(&self, images: &[Image]) -> Result<Option<String>> {
+        let mut values: Vec<String> = images
+            .iter()
+            .filter_map(|i| (self.extract)(i))
+            .map(str::to_string)
+            .collect();
+        if values.is_empty() {
+            return Ok(None);
+        }
+        match self.menu {
+            Menu::Plain => {
+                values.sort();
+                values.dedup();
+            }
+            Menu::NewestFirst => sort_newest_first(&mut values),
+            Menu::Builds(_) => sort_builds_newest_first(&mut values),
+        }
+
+        let choice = match self.menu {
+            Menu::Builds(max_builds) => choose_build(self.title, self.preset, values, max_builds)?,
+            Menu::Plain | Menu::NewestFirst => choose_or_preset(self.title, self.preset, values)?,
+        };
+        Ok(Some(choice))
+    }
+}
+
+/// Narrows a list of images down one [`Facet`] at a time: the distinct values
+/// of each facet are offered (or taken from its preset) and the images not
+/// matching the choice are dropped before the next facet is asked.
+pub struct SelectionPipeline<'a> {
+    subject: &'a str,
+    facets: Vec<Facet<'a>>,
+}
+
+impl<'a> SelectionPipeline<'a> {
+    /// Empty pipeline; `subject` (e.g. `Debian`) names the images in errors.
+    pub fn new(subject: &'a str) -> Self {
+        Self {
+            subject,
+            facets: Vec::new(),
+        }
+    }
+
+    /// Append `facet` to the steps.
+    pub fn facet(mut self, facet: Facet<'a>) -> Self {
+        self.facets.push(facet);
+        self
+    }
+
+    /// Run every facet over `images` and return the images matching all
+    /// choices. Facets none of the remaining images has a value for are
+    /// skipped.
+    pub fn run(&self, mut images: Vec<Image>) -> Result<Vec<Image>> {
+        let mut chosen: Vec<String> = Vec::new();
+        for facet in &self.facets {
+            let Some(choice) = facet.choose(&images)? else {
+                continue;
+            };
+            trace::retain(facet.name, &mut images, |i| match (facet.extract)(i) {
+                Some(value) if value == choice => None,
+                Some(value) => Some(format!("{} is {value}, chose {choice}", facet.name)),
+                None => Some(format!("no {}", facet.name)),
+            });
+            chosen.push(format!("{}={choice}", facet.name));
+            ensure!(
+                !images.is_empty(),
+                "No {} images found for {}",
+                self.subject,
+                chosen.join(", ")
+            );
+        }
+        Ok(images)
+    }
+}
+
+/// Distinct versions, newest first by [`VersionKey`](crate::cloud::VersionKey).
+pub fn newest_first<'a>(values: impl Iterator<Item = &'a str>) -> Vec<String> {
+    let mut values: Vec<String> = values.map(str::to_string).collect();
+    sort_newest_first(&mut values);
+    values
+}
+
+/// The newest of `builds` by [`BuildId`](crate::cloud::BuildId).
+pub fn newest_build<'a>(builds: impl Iterator<Item = &'a str>) -> Option<String> {
+    let mut builds: Vec<String> = builds.map(str::to_string).collect();
+    sort_builds_newest_first(&mut builds);
+    builds.into_iter().next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Facet, SelectionPipeline, newest_first};
+    use crate::cloud::{Image, Variant, sample_image};
+
+    fn image(distro_version: &str, version: &str, variant: &str) -> Image {
+        sample_image()
+            .with_release("debian", "bookworm", distro_version)
+            .with_version(version)
+            .with_variant(Variant::from_name(variant))
+            .with_url(format!("https://example.invalid/{version}/{variant}.qcow2"))
+    }
+
+    #[test]
+    fn presets_narrow_the_images_facet_by_facet() {
+        let images = vec![
+            image("12", "latest", "genericcloud"),
+            image("12", "latest", "nocloud"),
+            image("12", "20250101-0000", "genericcloud"),
+        ];
+        let pipeline = SelectionPipeline::new("Debian")
+            .facet(
+                Facet::new("distro_version", "Select Distro Version", |i| {
+                    Some(i.distro_version())
+                })
+                .preset(Some("12")),
+            )
+            .facet(
+                Facet::new("version", "Select Image Version", |i| Some(i.version()))
+                    .preset(Some("latest"))
+                    .builds(Some(1)),
+            )
+            .facet(
+                Facet::new("variant", "Select Image Variant", |i| {
+                    Some(i.variant().as_str())
+                })
+                .preset(Some("NoCloud")),
+            )
+            // No image has a value: skipped instead of prompting.
+            .facet(Facet::new("format", "Select Image Format", |_| None));
+
+        let selected = pipeline.run(images.clone()).unwrap();
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].url(), images[1].url());
+
+        let pipeline = SelectionPipeline::new("Debian").facet(
+            Facet::new("version", "Select Image Version", |i| Some(i.version()))
+                .preset(Some("20990101-0000")),
+        );
+        assert!(pipeline.run(images).is_err());
+    }
+
+    #[test]
+    fn sorts_and_dedups_newest_first() {
+        let values = ["9.9", "latest", "9.10", "latest"];
+        assert_eq!(newest_first(values.into_iter()), ["latest", "9.10", "9.9"]);
+    }
+}
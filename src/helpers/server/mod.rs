@@ -0,0 +1,777 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+use http::header::CONTENT_TYPE;
+use http::{Method, Request, Response, StatusCode};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Body, Incoming};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+
+use crate::cloud::{Arch, BuildId, Image};
+use crate::error::CloudImagesError;
+use crate::helpers::cancel::CancellationToken;
+use crate::helpers::image_resolver::{
+    DownloadOptions, ExistingFile, OnMismatch, destination_dir, output_path,
+};
+use crate::helpers::library::Library;
+use crate::helpers::metrics::{self, Metrics};
+use crate::helpers::notify::Event;
+use crate::helpers::progress::{ProgressSink, SilentProgress, Transfer, TransferProgress};
+use crate::repositories::{ImageQuery, providers};
+
+/// Answer HTTP/1 connections on `listener` with `handle` until accepting one
+/// fails; the API and the file server share it.
+pub(crate) async fn serve_http<F, Fut, B>(listener: TcpListener, handle: F) -> Result<(), String>
+where
+    F: Fn(Request<Incoming>) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = Response<B>> + Send + 'static,
+    B: Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    loop {
+        let (stream, peer) = listener
+            .accept()
+            .await
+            .map_err(|e| format!("Failed to accept a connection: {e}"))?;
+        let handle = handle.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |request| {
+                let response = handle(request);
+                async move { Ok::<_, Infallible>(response.await) }
+            });
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                eprintln!("Connection from {peer} failed: {err}");
+            }
+        });
+    }
+}
+
+/// Largest request body accepted; queries are a few hundred bytes.
+const MAX_BODY: usize = 64 * 1024;
+
+/// What `serve` downloads with and where.
+#[derive(Debug, Clone)]
+pub struct ServeOptions {
+    /// Root of the download tree, laid out like the command line's.
+    pub root: PathBuf,
+    /// Put every image directly into `root`.
+    pub flat: bool,
+    /// Downloads running at once; later ones wait in `queued`.
+    pub jobs: usize,
+    /// Library listed by `GET /library`.
+    pub library: Option<Library>,
+    /// How often the images downloaded through the API are checked for
+    /// newer builds, for the freshness gauges of `GET /metrics` and the
+    /// `new_release` notifications of the download options' notifier.
+    pub recheck: Option<Duration>,
+    /// Options of every download. Prompts are answered the way scheduled
+    /// runs answer them (overwrite, download again on a mismatch) and
+    /// progress goes to the download's status.
+    pub download: DownloadOptions,
+}
+
+/// An image selection in a request: the query string of `GET /resolve` or
+/// the JSON body of `POST /downloads`. Without `build` the newest one is
+/// taken.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct QueryBody {
+    distro: String,
+    release: String,
+    arch: Arch,
+    build: Option<String>,
+    variant: Option<String>,
+    format: Option<String>,
+}
+
+impl From<QueryBody> for ImageQuery {
+    fn from(body: QueryBody) -> Self {
+        let mut query = ImageQuery::new()
+            .distro(body.distro)
+            .release(body.release)
+            .arch(body.arch);
+        query = match body.build {
+            Some(build) => query.build(build),
+            None => query.latest(),
+        };
+        if let Some(variant) = &body.variant {
+            query = query.variant(variant);
+        }
+        if let Some(format) = &body.format {
+            query = query.format(format);
+        }
+        query
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum State {
+    Queued,
+    Running,
+    Finished,
+    Failed,
+    Cancelled,
+}
+
+/// Progress and outcome of one download, as `GET /downloads/{id}` reports
+/// it.
+#[derive(Debug, Clone)]
+struct Record {
+    state: State,
+    /// URL of the current transfer.
+    url: Option<String>,
+    position: u64,
+    /// Whether `position` holds the offset the current transfer started at
+    /// yet; bytes up to it were transferred by an earlier run.
+    started: bool,
+    total: u64,
+    /// The last message: a mirror switch while running, the summary or the
+    /// error once done.
+    message: Option<String>,
+}
+
+/// Sink writing the progress of a download into its [`Record`] and the
+/// server's [`Metrics`].
+#[derive(Debug, Clone)]
+struct Recorder {
+    record: Arc<Mutex<Record>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Recorder {
+    fn update(&self, f: impl FnOnce(&mut Record)) {
+        if let Ok(mut record) = self.record.lock() {
+            f(&mut record);
+        }
+    }
+}
+
+impl TransferProgress for Recorder {
+    /// The first position of a transfer is where it resumes; only what
+    /// follows is counted as transferred.
+    fn set_position(&self, position: u64) {
+        self.update(|record| {
+            if record.started {
+                self.metrics
+                    .transferred(position.saturating_sub(record.position));
+            }
+            record.position = position;
+            record.started = true;
+        });
+    }
+
+    fn inc(&self, delta: u64) {
+        self.metrics.transferred(delta);
+        self.update(|record| {
+            record.position += delta;
+            record.started = true;
+        });
+    }
+
+    fn println(&self, message: &str) {
+        self.update(|record| record.message = Some(message.to_string()));
+    }
+
+    fn finish(&self, message: &str) {
+        self.println(message);
+    }
+
+    /// Transfers are only abandoned when they fail verification.
+    fn abandon(&self, message: &str) {
+        self.metrics.checksum_failed();
+        self.println(message);
+    }
+}
+
+impl ProgressSink for Recorder {
+    fn transfer(&self, url: &str, total: u64) -> Transfer {
+        self.update(|record| {
+            record.url = Some(url.to_string());
+            record.position = 0;
+            record.started = false;
+            record.total = total;
+        });
+        Arc::new(self.clone())
+    }
+
+    fn batch(&self, _images: u64) -> Transfer {
+        Arc::new(SilentProgress)
+    }
+}
+
+#[derive(Debug)]
+struct Download {
+    image: Image,
+    dest_dir: PathBuf,
+    record: Arc<Mutex<Record>>,
+    cancel: CancellationToken,
+}
+
+impl Download {
+    fn status(&self, id: u64) -> Value {
+        let record = self.record.lock().map(|r| r.clone()).ok();
+        let record = record.as_ref();
+        let (position, total) = record.map_or((0, 0), |r| (r.position, r.total));
+        json!({
+            "id": id,
+            "state": record.map(|r| r.state),
+            "image": self.image,
+            "dest_dir": self.dest_dir,
+            "url": record.and_then(|r| r.url.clone()),
+            "position": position,
+            "total": total,
+            "percent": (total > 0).then(|| position.min(total) * 100 / total),
+            "message": record.and_then(|r| r.message.clone()),
+        })
+    }
+}
+
+/// The HTTP API of `serve`:
+///
+/// | Request | Answer |
+/// | ------- | ------ |
+/// | `GET /providers` | Distros and their architectures |
+/// | `GET /resolve?distro=&release=&arch=[&build=&variant=&format=]` | Matching images |
+/// | `POST /downloads` with the same fields as JSON | `202` and the new download, which must match one image; `409` and the running one when the file is already being downloaded |
+/// | `GET /downloads` | Every download of this process |
+/// | `GET /downloads/{id}` | State, bytes done and total, last message |
+/// | `DELETE /downloads/{id}` | Cancel it |
+/// | `GET /library` | Entries of the image library |
+/// | `GET /metrics` | Download counters and image freshness for Prometheus |
+///
+/// Every answer is JSON; failures carry an `error` message.
+#[derive(Debug)]
+pub struct Server {
+    options: ServeOptions,
+    downloads: Mutex<BTreeMap<u64, Arc<Download>>>,
+    /// Ids of the queued and running downloads by the file they write, so
+    /// no two of them write the same `.part`.
+    active: Mutex<HashMap<PathBuf, u64>>,
+    next_id: AtomicU64,
+    slots: Arc<Semaphore>,
+    metrics: Arc<Metrics>,
+    /// Queries of the downloads started with the newest build seen for
+    /// them, rechecked for newer builds.
+    tracked: Mutex<Vec<(ImageQuery, BuildId)>>,
+}
+
+type Reply = (StatusCode, Value);
+
+fn error(status: StatusCode, message: impl Into<String>) -> Reply {
+    (status, json!({ "error": message.into() }))
+}
+
+/// Status answering a failed resolution or download.
+fn status_for(err: &CloudImagesError) -> StatusCode {
+    match err {
+        CloudImagesError::Resolution(_) => StatusCode::NOT_FOUND,
+        CloudImagesError::Network(_) => StatusCode::BAD_GATEWAY,
+        CloudImagesError::Cancelled(_) => StatusCode::CONFLICT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+impl Server {
+    pub fn new(mut options: ServeOptions) -> Arc<Self> {
+        // Nobody is there to answer prompts.
+        if options.download.existing == ExistingFile::Ask {
+            options.download.existing = ExistingFile::Overwrite;
+        }
+        if options.download.on_mismatch == OnMismatch::Ask {
+            options.download.on_mismatch = OnMismatch::Redownload;
+        }
+        let slots = Arc::new(Semaphore::new(options.jobs.max(1)));
+        Arc::new(Self {
+            options,
+            downloads: Mutex::new(BTreeMap::new()),
+            active: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+            slots,
+            metrics: Arc::new(Metrics::new()),
+            tracked: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Answer connections on `listener` until the process ends.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> Result<(), String> {
+        if let Some(interval) = self.options.recheck {
+            tokio::spawn(Arc::clone(&self).recheck(interval));
+        }
+        serve_http(listener, move |request| Arc::clone(&self).handle(request)).await
+    }
+
+    async fn handle(self: Arc<Self>, request: Request<Incoming>) -> Response<Full<Bytes>> {
+        let (parts, body) = request.into_parts();
+        if parts.method == Method::GET && parts.uri.path().trim_end_matches('/') == "/metrics" {
+            return Response::builder()
+                .header(CONTENT_TYPE, metrics::CONTENT_TYPE)
+                .body(Full::new(Bytes::from(self.metrics.render())))
+                .expect("responses are valid");
+        }
+        let (status, value) = match Limited::new(body, MAX_BODY).collect().await {
+            Ok(body) => {
+                self.route(
+                    &parts.method,
+                    parts.uri.path(),
+                    parts.uri.query(),
+                    &body.to_bytes(),
+                )
+                .await
+            }
+            Err(err) => error(StatusCode::BAD_REQUEST, format!("Unreadable body: {err}")),
+        };
+        let mut text = serde_json::to_string_pretty(&value).expect("answers serialise");
+        text.push('\n');
+        Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Full::new(Bytes::from(text)))
+            .expect("responses are valid")
+    }
+
+    async fn route(
+        self: &Arc<Self>,
+        method: &Method,
+        path: &str,
+        query: Option<&str>,
+        body: &[u8],
+    ) -> Reply {
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        match (method, segments.as_slice()) {
+            (&Method::GET, ["providers"]) => (StatusCode::OK, self.providers()),
+            (&Method::GET, ["resolve"]) => {
+                let pairs: serde_json::Map<String, Value> =
+                    url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+                        .map(|(key, value)| (key.into_owned(), Value::String(value.into_owned())))
+                        .collect();
+                match serde_json::from_value(Value::Object(pairs)) {
+                    Ok(body) => self.resolve(body).await,
+                    Err(err) => error(StatusCode::BAD_REQUEST, format!("Invalid query: {err}")),
+                }
+            }
+            (&Method::GET, ["downloads"]) => (StatusCode::OK, self.list_downloads()),
+            (&Method::POST, ["downloads"]) => match serde_json::from_slice(body) {
+                Ok(body) => self.start(body).await,
+                Err(err) => error(StatusCode::BAD_REQUEST, format!("Invalid body: {err}")),
+            },
+            (&Method::GET, ["downloads", id]) => match self.download(id) {
+                Some((id, download)) => (StatusCode::OK, download.status(id)),
+                None => error(StatusCode::NOT_FOUND, format!("No download {id}")),
+            },
+            (&Method::DELETE, ["downloads", id]) => match self.download(id) {
+                Some((id, download)) => {
+                    download.cancel.cancel();
+                    (StatusCode::ACCEPTED, download.status(id))
+                }
+                None => error(StatusCode::NOT_FOUND, format!("No download {id}")),
+            },
+            (&Method::GET, ["library"]) => self.library(),
+            (
+                _,
+                ["providers" | "resolve" | "downloads" | "library" | "metrics"] | ["downloads", _],
+            ) => error(
+                StatusCode::METHOD_NOT_ALLOWED,
+                format!("{method} is not allowed"),
+            ),
+            _ => error(StatusCode::NOT_FOUND, format!("No endpoint {path}")),
+        }
+    }
+
+    fn providers(&self) -> Value {
+        providers()
+            .iter()
+            .map(|p| json!({ "name": p.display_name(), "arches": p.supported_arches() }))
+            .collect()
+    }
+
+    async fn find(&self, query: &ImageQuery) -> Result<Vec<Image>, Reply> {
+        let images = crate::find(query)
+            .await
+            .map_err(|err| error(status_for(&err), err.to_string()))?;
+        self.metrics.available(&images);
+        if images.is_empty() {
+            return Err(error(StatusCode::NOT_FOUND, "No image matches the query"));
+        }
+        Ok(images)
+    }
+
+    async fn resolve(&self, body: QueryBody) -> Reply {
+        match self.find(&body.into()).await {
+            Ok(images) => (StatusCode::OK, json!({ "images": images })),
+            Err(reply) => reply,
+        }
+    }
+
+    fn list_downloads(&self) -> Value {
+        let downloads = self.downloads.lock().expect("downloads lock");
+        downloads
+            .iter()
+            .map(|(id, download)| download.status(*id))
+            .collect()
+    }
+
+    fn download(&self, id: &str) -> Option<(u64, Arc<Download>)> {
+        let id = id.parse().ok()?;
+        let downloads = self.downloads.lock().expect("downloads lock");
+        downloads
+            .get(&id)
+            .map(|download| (id, Arc::clone(download)))
+    }
+
+    /// Resolve `body` to a single image and download it in the background.
+    async fn start(self: &Arc<Self>, body: QueryBody) -> Reply {
+        let query = body.into();
+        let mut images = match self.find(&query).await {
+            Ok(images) => images,
+            Err(reply) => return reply,
+        };
+        if images.len() > 1 {
+            let (_, mut reply) = error(
+                StatusCode::CONFLICT,
+                format!("{} images match; name the variant and format", images.len()),
+            );
+            reply["images"] = json!(images);
+            return (StatusCode::CONFLICT, reply);
+        }
+        let image = images.remove(0);
+        {
+            let mut tracked = self.tracked.lock().expect("tracked lock");
+            if !tracked.iter().any(|(known, _)| *known == query) {
+                tracked.push((query, BuildId::new(image.version())));
+            }
+        }
+        self.begin(image)
+    }
+
+    /// Download `image` in the background unless a queued or running
+    /// download already writes the same file; that one is answered with
+    /// `409`.
+    fn begin(self: &Arc<Self>, image: Image) -> Reply {
+        let dest_dir = destination_dir(&self.options.root, &image, self.options.flat);
+        let target = match output_path(&image, &dest_dir) {
+            Ok(target) => target,
+            Err(err) => return error(status_for(&err), err.to_string()),
+        };
+        let mut active = self.active.lock().expect("active lock");
+        if let Some(&id) = active.get(&target) {
+            let running = self.downloads.lock().expect("downloads lock")[&id].status(id);
+            let (status, mut reply) = error(
+                StatusCode::CONFLICT,
+                format!("{} is already being downloaded", target.display()),
+            );
+            reply["download"] = running;
+            return (status, reply);
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let download = Arc::new(Download {
+            dest_dir,
+            image,
+            record: Arc::new(Mutex::new(Record {
+                state: State::Queued,
+                url: None,
+                position: 0,
+                started: false,
+                total: 0,
+                message: None,
+            })),
+            cancel: CancellationToken::new(),
+        });
+        active.insert(target.clone(), id);
+        self.downloads
+            .lock()
+            .expect("downloads lock")
+            .insert(id, Arc::clone(&download));
+        drop(active);
+        tokio::spawn(Arc::clone(self).run(target, Arc::clone(&download)));
+        (StatusCode::ACCEPTED, download.status(id))
+    }
+
+    async fn run(self: Arc<Self>, target: PathBuf, download: Arc<Download>) {
+        let recorder = Recorder {
+            record: Arc::clone(&download.record),
+            metrics: Arc::clone(&self.metrics),
+        };
+        let slots = Arc::clone(&self.slots);
+        let slot = download
+            .cancel
+            .run("the queued download", async move {
+                slots
+                    .acquire_owned()
+                    .await
+                    .expect("the semaphore is never closed")
+            })
+            .await;
+        let result = match slot {
+            Ok(_slot) => {
+                recorder.update(|record| record.state = State::Running);
+                self.metrics.download_started();
+                let options = DownloadOptions {
+                    progress: Arc::new(recorder.clone()),
+                    cancel: download.cancel.clone(),
+                    ..self.options.download.clone()
+                };
+                crate::download(&download.image, &download.dest_dir, &options).await
+            }
+            Err(err) => Err(err),
+        };
+        self.metrics.download_finished(&download.image, &result);
+        recorder.update(|record| {
+            let (state, message) = match result {
+                Ok(summary) => (State::Finished, summary),
+                Err(err @ CloudImagesError::Cancelled(_)) => (State::Cancelled, err.to_string()),
+                Err(err) => (State::Failed, err.to_string()),
+            };
+            record.state = state;
+            record.message = Some(message);
+        });
+        self.active.lock().expect("active lock").remove(&target);
+    }
+
+    /// Look for newer builds of the tracked queries every `interval`.
+    async fn recheck(self: Arc<Self>, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            let tracked = self.tracked.lock().expect("tracked lock").clone();
+            for (query, seen) in tracked {
+                let images = match crate::find(&query).await {
+                    Ok(images) => images,
+                    Err(err) => {
+                        eprintln!("Checking for newer builds failed: {err}");
+                        continue;
+                    }
+                };
+                self.metrics.available(&images);
+                let Some(newest) = images
+                    .iter()
+                    .max_by_key(|image| BuildId::new(image.version()))
+                    .filter(|image| BuildId::new(image.version()) > seen)
+                else {
+                    continue;
+                };
+                if let Some(entry) = self
+                    .tracked
+                    .lock()
+                    .expect("tracked lock")
+                    .iter_mut()
+                    .find(|(known, _)| *known == query)
+                {
+                    entry.1 = BuildId::new(newest.version());
+                }
+                if let Some(notifier) = &self.options.download.notifier {
+                    notifier
+                        .send(&Event::NewRelease {
+                            image: newest,
+                            previous: Some(seen.as_str()),
+                        })
+                        .await;
+                }
+            }
+        }
+    }
+
+    fn library(&self) -> Reply {
+        let Some(library) = &self.options.library else {
+            return error(StatusCode::NOT_FOUND, "No image library is configured");
+        };
+        match library.entries() {
+            Ok(entries) => (
+                StatusCode::OK,
+                json!({ "root": library.root(), "entries": entries }),
+            ),
+            Err(err) => error(StatusCode::INTERNAL_SERVER_ERROR, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use http::{Method, StatusCode};
+
+    use super::{Record, Recorder, ServeOptions, Server, State};
+    use crate::cloud::{ChecksumKind, ImageChecksum, sample_image};
+    use crate::helpers::checksum::StreamHasher;
+    use crate::helpers::file_server::FileServer;
+    use crate::helpers::image_resolver::{DownloadOptions, ExistingFile};
+    use crate::helpers::library::Library;
+    use crate::helpers::metrics::Metrics;
+    use crate::helpers::progress::ProgressSink;
+
+    #[tokio::test]
+    async fn routes_requests() {
+        let dir = std::env::temp_dir().join(format!("cid-server-{}", std::process::id()));
+        let server = Server::new(ServeOptions {
+            root: dir.join("images"),
+            flat: false,
+            jobs: 2,
+            library: Some(Library::new(dir.join("library"))),
+            recheck: None,
+            download: DownloadOptions::default(),
+        });
+        assert_eq!(server.options.download.existing, ExistingFile::Overwrite);
+
+        let (status, providers) = server.route(&Method::GET, "/providers", None, b"").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(providers[0]["name"], "Ubuntu");
+
+        let (status, library) = server.route(&Method::GET, "/library/", None, b"").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(library["entries"], serde_json::json!([]));
+
+        let (status, reply) = server
+            .route(
+                &Method::POST,
+                "/downloads",
+                None,
+                b"{\"distro\": \"debian\"}",
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(reply["error"].as_str().unwrap().contains("release"));
+        let (status, _) = server
+            .route(
+                &Method::GET,
+                "/resolve",
+                Some("distro=debian&arch=sparc"),
+                b"",
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = server.route(&Method::GET, "/downloads/7", None, b"").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = server.route(&Method::PUT, "/library", None, b"").await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        let (status, _) = server.route(&Method::GET, "/nope", None, b"").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn one_writer_per_destination() {
+        let root = std::env::temp_dir().join(format!("cid-server-dup-{}", std::process::id()));
+        let served = root.join("served");
+        std::fs::create_dir_all(&served).unwrap();
+        std::fs::write(served.join("image.qcow2"), b"cloud image").unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(FileServer::new(served).serve(listener));
+
+        let mut hasher = StreamHasher::new(ChecksumKind::Sha256);
+        hasher.update(b"cloud image");
+        let image = sample_image().with_source(
+            format!("http://{addr}/image.qcow2"),
+            Some(ImageChecksum::new(
+                ChecksumKind::Sha256,
+                hasher.finalize_hex(),
+            )),
+        );
+        let server = Server::new(ServeOptions {
+            root: root.join("images"),
+            flat: true,
+            jobs: 2,
+            library: None,
+            recheck: None,
+            download: DownloadOptions::default(),
+        });
+
+        let starts: Vec<_> = (0..2)
+            .map(|_| {
+                let (server, image) = (Arc::clone(&server), image.clone());
+                tokio::spawn(async move { server.begin(image) })
+            })
+            .collect();
+        let mut replies = Vec::new();
+        for start in starts {
+            replies.push(start.await.unwrap());
+        }
+        replies.sort_by_key(|(status, _)| *status);
+        let [(accepted, started), (conflict, duplicate)] = replies.try_into().unwrap();
+        assert_eq!(accepted, StatusCode::ACCEPTED);
+        assert_eq!(conflict, StatusCode::CONFLICT);
+        assert_eq!(duplicate["download"]["id"], started["id"]);
+
+        let id = started["id"].as_u64().unwrap();
+        let download = server.download(&id.to_string()).unwrap().1;
+        while matches!(
+            download.record.lock().unwrap().state,
+            State::Queued | State::Running
+        ) {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(download.record.lock().unwrap().state, State::Finished);
+        assert_eq!(
+            std::fs::read(root.join("images/image.qcow2")).unwrap(),
+            b"cloud image"
+        );
+
+        // Finished downloads no longer block the file.
+        let (status, _) = server.begin(image);
+        assert_eq!(status, StatusCode::ACCEPTED);
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn records_transfer_progress() {
+        let record = Arc::new(Mutex::new(Record {
+            state: State::Running,
+            url: None,
+            position: 0,
+            started: false,
+            total: 0,
+            message: None,
+        }));
+        let metrics = Arc::new(Metrics::new());
+        let sink = Recorder {
+            record: Arc::clone(&record),
+            metrics: Arc::clone(&metrics),
+        };
+        let transfer = sink.transfer("https://example.invalid/a.img", 100);
+        transfer.set_position(0);
+        transfer.set_position(30);
+        transfer.inc(10);
+        transfer.inc(2);
+        transfer.println("Switching to the next mirror");
+        assert!(
+            metrics
+                .render()
+                .contains("cloud_images_downloaded_bytes_total 42\n")
+        );
+        {
+            let record = record.lock().unwrap();
+            assert_eq!(record.url.as_deref(), Some("https://example.invalid/a.img"));
+            assert_eq!((record.position, record.total), (42, 100));
+            assert_eq!(
+                record.message.as_deref(),
+                Some("Switching to the next mirror")
+            );
+        }
+
+        // A resumed transfer only counts what it fetches past its offset.
+        let resumed = sink.transfer("https://example.invalid/b.img", 100);
+        resumed.set_position(60);
+        resumed.set_position(75);
+        assert!(
+            metrics
+                .render()
+                .contains("cloud_images_downloaded_bytes_total 57\n")
+        );
+        assert_eq!(record.lock().unwrap().position, 75);
+    }
+}
@@ -0,0 +1,269 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use serde_json::{Map, Value, json};
+
+use crate::cloud::{ArchNaming, ChecksumKind, Image};
+use crate::helpers::checksum::{self, StreamHasher};
+
+/// Where the index and the products files go, below the served root.
+pub const STREAMS_DIR: &str = "streams/v1";
+
+/// Content ids of the products files written for a `mirror` tree and for
+/// the image library.
+pub const MIRROR_CONTENT_ID: &str = "cloud-images-downloader:mirror:download";
+pub const LIBRARY_CONTENT_ID: &str = "cloud-images-downloader:library:download";
+
+/// An image held below a served root.
+#[derive(Debug, Clone)]
+pub struct LocalImage {
+    pub image: Image,
+    /// Relative to the root, `/` separated.
+    pub path: String,
+    /// SHA-256 of the file when already known (the library names its blobs
+    /// by it); otherwise taken from the image or computed.
+    pub sha256: Option<String>,
+}
+
+/// Product name of `image` in the products file `content_id`, e.g.
+/// `cloud-images-downloader:mirror:download` → `cloud-images-downloader:debian:12:amd64`.
+fn product_name(content_id: &str, image: &Image) -> String {
+    let prefix = content_id.split(':').next().unwrap_or(content_id);
+    format!(
+        "{prefix}:{}:{}:{}",
+        image.os(),
+        image.distro_version(),
+        image.arch().name(ArchNaming::Debian)
+    )
+}
+
+/// Item key of `image`: `<variant>.<format>`, which
+/// [`Image::from_metadata`] splits back into the two.
+fn ftype(image: &Image) -> String {
+    format!("{}.{}", image.variant().as_str(), image.format().as_str())
+}
+
+/// SHA-256 digests of the items of an earlier products file, by path and
+/// size, so regenerating does not re-hash unchanged files.
+fn known_digests(products: &Path) -> HashMap<(String, u64), String> {
+    let Ok(text) = fs::read_to_string(products) else {
+        return HashMap::new();
+    };
+    let Ok(value) = serde_json::from_str::<Value>(&text) else {
+        return HashMap::new();
+    };
+    let mut known = HashMap::new();
+    for product in value["products"].as_object().into_iter().flatten() {
+        for version in product.1["versions"].as_object().into_iter().flatten() {
+            for item in version.1["items"].as_object().into_iter().flatten() {
+                let item = item.1;
+                if let (Some(path), Some(size), Some(sha256)) = (
+                    item["path"].as_str(),
+                    item["size"].as_u64(),
+                    item["sha256"].as_str(),
+                ) {
+                    known.insert((path.to_string(), size), sha256.to_string());
+                }
+            }
+        }
+    }
+    known
+}
+
+fn write_json(path: &Path, value: &Value) -> Result<(), String> {
+    let mut text = serde_json::to_string_pretty(value).expect("JSON values serialise");
+    text.push('\n');
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, text).map_err(|e| format!("Failed to write '{}': {e}", tmp.display()))?;
+    fs::rename(&tmp, path).map_err(|e| format!("Failed to write '{}': {e}", path.display()))
+}
+
+/// Write Simplestreams metadata describing `images` below `root`:
+/// `streams/v1/index.json` and the `image-downloads` products file
+/// `streams/v1/<content_id>.json`, in the `products:1.0` format Canonical's
+/// cloud-images mirror publishes. Images whose file is missing are left
+/// out. Every item carries its size and SHA-256 (plus the other digests
+/// the image was published with); digests nobody published are computed
+/// once and then reused from the previous products file while the file
+/// keeps its size. Returns the number of images described.
+pub fn generate(root: &Path, images: &[LocalImage], content_id: &str) -> Result<usize, String> {
+    let dir = root.join(STREAMS_DIR);
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create '{}': {e}", dir.display()))?;
+    let products_file = format!("{content_id}.json");
+    let products_path = dir.join(&products_file);
+    let known = known_digests(&products_path);
+    let updated = Utc::now().to_rfc2822();
+
+    let mut products: BTreeMap<String, Map<String, Value>> = BTreeMap::new();
+    let mut described = 0;
+    for local in images {
+        let file = local
+            .path
+            .split('/')
+            .fold(root.to_path_buf(), |path, segment| path.join(segment));
+        let Ok(meta) = fs::metadata(&file) else {
+            continue;
+        };
+        let image = &local.image;
+        let published = |kind| {
+            image
+                .checksums()
+                .iter()
+                .find(|c| c.kind() == kind)
+                .map(|c| c.value().to_ascii_lowercase())
+        };
+        let sha256 = match local
+            .sha256
+            .clone()
+            .or_else(|| published(ChecksumKind::Sha256))
+            .or_else(|| known.get(&(local.path.clone(), meta.len())).cloned())
+        {
+            Some(sha256) => sha256,
+            None => {
+                let mut hasher = StreamHasher::new(ChecksumKind::Sha256);
+                checksum::hash_file(&file, &mut hasher)?;
+                hasher.finalize_hex()
+            }
+        };
+
+        let mut item = Map::new();
+        item.insert("ftype".to_string(), json!(ftype(image)));
+        item.insert("path".to_string(), json!(local.path));
+        item.insert("size".to_string(), json!(meta.len()));
+        item.insert("sha256".to_string(), json!(sha256));
+        for (kind, key) in [(ChecksumKind::Sha512, "sha512"), (ChecksumKind::Md5, "md5")] {
+            if let Some(value) = published(kind) {
+                item.insert(key.to_string(), json!(value));
+            }
+        }
+
+        let product = products
+            .entry(product_name(content_id, image))
+            .or_insert_with(|| {
+                let mut product = Map::new();
+                product.insert("arch".to_string(), json!(image.arch_name()));
+                product.insert("os".to_string(), json!(image.os()));
+                product.insert("release".to_string(), json!(image.name()));
+                product.insert("version".to_string(), json!(image.distro_version()));
+                product.insert(
+                    "release_title".to_string(),
+                    json!(format!("{} {}", image.os(), image.distro_version())),
+                );
+                product.insert("versions".to_string(), json!({}));
+                product
+            });
+        product["versions"]
+            .as_object_mut()
+            .expect("versions is an object")
+            .entry(image.version())
+            .or_insert_with(|| json!({ "items": {} }))["items"]
+            .as_object_mut()
+            .expect("items is an object")
+            .insert(ftype(image), Value::Object(item));
+        described += 1;
+    }
+
+    let names: Vec<&String> = products.keys().collect();
+    let index = json!({
+        "format": "index:1.0",
+        "updated": updated,
+        "index": {
+            content_id: {
+                "datatype": "image-downloads",
+                "format": "products:1.0",
+                "path": format!("{STREAMS_DIR}/{products_file}"),
+                "products": names,
+                "updated": updated,
+            }
+        }
+    });
+    write_json(
+        &products_path,
+        &json!({
+            "content_id": content_id,
+            "datatype": "image-downloads",
+            "format": "products:1.0",
+            "updated": updated,
+            "products": products,
+        }),
+    )?;
+    write_json(&dir.join("index.json"), &index)?;
+    Ok(described)
+}
+
+/// Path of the `index.json` [`generate`] writes below `root`.
+pub fn index_path(root: &Path) -> PathBuf {
+    root.join(STREAMS_DIR).join("index.json")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{LocalImage, MIRROR_CONTENT_ID, generate, index_path};
+    use crate::cloud::{
+        Arch, Catalog, ChecksumKind, Image, ImageChecksum, ImageFormat, StreamIndex, Variant,
+        sample_image,
+    };
+
+    #[test]
+    fn generated_streams_read_back() {
+        let root = std::env::temp_dir().join(format!("cid-simplestreams-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let path = "debian/bookworm/20250210-2019/debian-12-genericcloud-amd64.qcow2";
+        fs::create_dir_all(root.join("debian/bookworm/20250210-2019")).unwrap();
+        fs::write(root.join(path), b"disk").unwrap();
+        let image =
+            sample_image().with_checksums([ImageChecksum::new(ChecksumKind::Sha512, "ABCD")]);
+        let missing = LocalImage {
+            image: image.clone(),
+            path: "debian/bookworm/gone.qcow2".to_string(),
+            sha256: None,
+        };
+        let local = LocalImage {
+            image,
+            path: path.to_string(),
+            sha256: None,
+        };
+        assert_eq!(
+            generate(&root, &[local, missing], MIRROR_CONTENT_ID).unwrap(),
+            1
+        );
+
+        let index: StreamIndex =
+            serde_json::from_str(&fs::read_to_string(index_path(&root)).unwrap()).unwrap();
+        let (id, entry) = index.products(None).unwrap();
+        assert_eq!(id, MIRROR_CONTENT_ID);
+        let catalog: Catalog =
+            serde_json::from_str(&fs::read_to_string(root.join(entry.path())).unwrap()).unwrap();
+        let product = &catalog.products()["cloud-images-downloader:debian:12:amd64"];
+        assert_eq!(product.release().as_deref(), Some("bookworm"));
+        let (ftype, item) = product.versions()["20250210-2019"]
+            .items()
+            .iter()
+            .next()
+            .unwrap();
+        let read = Image::from_metadata(
+            "debian".to_string(),
+            "bookworm",
+            "12",
+            "20250210-2019",
+            Arch::Amd64,
+            "http://mirror.lan/",
+            item.path().as_deref().unwrap(),
+            item.checksums(),
+            ftype,
+        );
+        assert_eq!(read.variant(), &Variant::GenericCloud);
+        assert_eq!(read.format(), &ImageFormat::from_name("qcow2"));
+        assert_eq!(read.url(), format!("http://mirror.lan/{path}"));
+        assert_eq!(
+            read.sha256(),
+            Some("1044dec7206e8d7c9fbb4ae8f766668406d2567fc7fc1a160a9d4700fcf8f8e9")
+        );
+        assert_eq!(read.checksum_kind(), Some(ChecksumKind::Sha512));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
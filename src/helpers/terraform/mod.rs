@@ -0,0 +1,169 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use clap::ValueEnum;
+
+use crate::cloud::{ChecksumKind, Image, ImageFormat};
+use crate::helpers::libvirt;
+use crate::helpers::packer::{attributes, quote};
+
+/// Provider the module snippet declares the image for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Module {
+    /// A `libvirt_volume` of the dmacvicar/libvirt provider.
+    Libvirt,
+    /// A `proxmox_virtual_environment_download_file` of the bpg/proxmox
+    /// provider, downloading into the node's `import` storage.
+    Proxmox,
+}
+
+/// Checksum algorithms providers verify downloads with, strongest first.
+const ALGORITHMS: &[ChecksumKind] = &[
+    ChecksumKind::Sha512,
+    ChecksumKind::Sha256,
+    ChecksumKind::Sha1,
+    ChecksumKind::Md5,
+];
+
+/// The variables describing `image`, by name: `image_name`, `image_url`,
+/// `image_version`, ..., and `image_checksum` (`<algo>:<hex>`) with its
+/// parts when a usable checksum is known.
+pub fn variables(image: &Image) -> BTreeMap<&'static str, String> {
+    let mut vars = BTreeMap::from([
+        ("image_name", libvirt::domain_name(image)),
+        ("image_url", image.url().to_string()),
+        ("image_distro", image.os().to_string()),
+        ("image_release", image.name().to_string()),
+        ("image_distro_version", image.distro_version().to_string()),
+        ("image_version", image.version().to_string()),
+        ("image_arch", image.arch_name().to_string()),
+        ("image_format", image.format().as_str().to_string()),
+    ]);
+    if let Some(checksum) = image
+        .checksums()
+        .iter()
+        .filter(|checksum| ALGORITHMS.contains(&checksum.kind()))
+        .min_by_key(|checksum| ALGORITHMS.iter().position(|kind| *kind == checksum.kind()))
+    {
+        let kind = checksum.kind().as_str();
+        vars.insert("image_checksum", format!("{kind}:{}", checksum.value()));
+        vars.insert("image_checksum_algorithm", kind.to_string());
+        vars.insert("image_checksum_value", checksum.value().to_string());
+    }
+    vars
+}
+
+/// `image` as a `.tfvars.json` document.
+pub fn tfvars_json(image: &Image) -> String {
+    let mut json = serde_json::to_string_pretty(&variables(image)).expect("variables serialise");
+    json.push('\n');
+    json
+}
+
+/// File name the volume gets in the pool or storage; qcow2 images published
+/// as `.img` are named `.qcow2`, which Proxmox requires for imports.
+fn file_name(image: &Image) -> String {
+    let extension = match image.format() {
+        ImageFormat::Img => "qcow2",
+        format => format.as_str(),
+    };
+    format!("{}.{extension}", libvirt::domain_name(image))
+}
+
+/// A module snippet: one variable per entry of [`variables`], defaulting to
+/// the resolved value so a `.tfvars` file can still override it, and the
+/// resource `module` declares the image with.
+pub fn module(image: &Image, module: Module) -> String {
+    let vars = variables(image);
+    let mut out = String::new();
+    for (name, value) in &vars {
+        let _ = writeln!(out, "variable \"{name}\" {{");
+        attributes(
+            &mut out,
+            &[("type", "string".to_string()), ("default", quote(value))],
+        );
+        out.push_str("}\n\n");
+    }
+
+    let label = quote(&libvirt::domain_name(image).replace('.', "_"));
+    match module {
+        Module::Libvirt => {
+            let _ = writeln!(out, "resource \"libvirt_volume\" {label} {{");
+            attributes(
+                &mut out,
+                &[
+                    ("name", quote(&file_name(image))),
+                    ("source", "var.image_url".to_string()),
+                ],
+            );
+        }
+        Module::Proxmox => {
+            out.push_str("variable \"proxmox_node\" {\n  type = string\n}\n\n");
+            let _ = writeln!(
+                out,
+                "resource \"proxmox_virtual_environment_download_file\" {label} {{"
+            );
+            let mut pairs = vec![
+                ("content_type", quote("import")),
+                ("datastore_id", quote("local")),
+                ("node_name", "var.proxmox_node".to_string()),
+                ("url", "var.image_url".to_string()),
+                ("file_name", quote(&file_name(image))),
+            ];
+            if vars.contains_key("image_checksum") {
+                pairs.push(("checksum", "var.image_checksum_value".to_string()));
+                pairs.push((
+                    "checksum_algorithm",
+                    "var.image_checksum_algorithm".to_string(),
+                ));
+            }
+            attributes(&mut out, &pairs);
+        }
+    }
+    out.push_str("}\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Module, module, tfvars_json, variables};
+    use crate::cloud::{ChecksumKind, ImageChecksum, sample_image};
+
+    #[test]
+    fn describes_the_resolved_image() {
+        let image = sample_image().with_source(
+            "https://cloud.debian.org/debian-12-genericcloud-amd64.qcow2".to_string(),
+            Some(ImageChecksum::new(ChecksumKind::Sha512, "cd34")),
+        );
+        let vars: serde_json::Value = serde_json::from_str(&tfvars_json(&image)).unwrap();
+        assert_eq!(vars["image_checksum"], "sha512:cd34");
+        assert_eq!(vars["image_checksum_algorithm"], "sha512");
+        assert_eq!(vars["image_version"], "20250210-2019");
+        assert_eq!(vars["image_name"], "debian-bookworm-amd64-20250210-2019");
+
+        let libvirt = module(&image, Module::Libvirt);
+        assert!(libvirt.contains(
+            "variable \"image_url\" {\n  type    = string\n  default = \
+             \"https://cloud.debian.org/debian-12-genericcloud-amd64.qcow2\"\n}\n"
+        ));
+        assert!(libvirt.contains("  source = var.image_url\n"));
+
+        let proxmox = module(&image, Module::Proxmox);
+        assert!(
+            proxmox
+                .contains("  file_name          = \"debian-bookworm-amd64-20250210-2019.qcow2\"\n")
+        );
+        assert!(proxmox.contains("  checksum_algorithm = var.image_checksum_algorithm\n"));
+    }
+
+    #[test]
+    fn prefers_the_strongest_checksum() {
+        let image = sample_image().with_checksums([
+            ImageChecksum::new(ChecksumKind::Sha256, "ab12"),
+            ImageChecksum::new(ChecksumKind::Sha512, "cd34"),
+        ]);
+        let vars = variables(&image);
+        assert_eq!(vars["image_checksum"], "sha512:cd34");
+        assert_eq!(vars["image_checksum_value"], "cd34");
+    }
+}
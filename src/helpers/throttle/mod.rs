@@ -0,0 +1,122 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, bail};
+
+/// Parse a curl-style rate such as `500K`, `10M` or `1.5G` (binary units,
+/// bytes per second). A bare number is interpreted as bytes per second.
+pub fn parse_rate(input: &str) -> Result<u64> {
+    let trimmed = input.trim();
+    let (number, multiplier) = match trimmed.chars().last() {
+        Some('k' | 'K') => (&trimmed[..trimmed.len() - 1], 1024.0),
+        Some('m' | 'M') => (&trimmed[..trimmed.len() - 1], 1024.0 * 1024.0),
+        Some('g' | 'G') => (&trimmed[..trimmed.len() - 1], 1024.0 * 1024.0 * 1024.0),
+        _ => (trimmed, 1.0),
+    };
+
+    let value: f64 = number
+        .trim()
+        .parse()
+        .with_context(|| format!("invalid rate '{input}'"))?;
+    let rate = (value * multiplier).round();
+    if !rate.is_finite() || rate < 1.0 {
+        bail!("rate '{input}' must be at least one byte per second");
+    }
+    Ok(rate as u64)
+}
+
+/// Token bucket state; tokens may go negative, which is the debt paid by
+/// sleeping.
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(bytes_per_sec: u64, now: Instant) -> Self {
+        let rate = bytes_per_sec as f64;
+        Self {
+            rate,
+            capacity: rate,
+            tokens: rate,
+            last: now,
+        }
+    }
+
+    /// Take `bytes` tokens and return how long the caller has to wait before
+    /// the bucket is back out of debt.
+    fn reserve(&mut self, bytes: usize, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.tokens -= bytes as f64;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// Shared bandwidth limiter; clones throttle against the same budget so
+/// concurrent segments together stay under the configured rate.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bucket: Arc::new(Mutex::new(Bucket::new(bytes_per_sec, Instant::now()))),
+        }
+    }
+
+    /// Configured rate in bytes per second.
+    pub fn bytes_per_sec(&self) -> u64 {
+        let bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        bucket.rate as u64
+    }
+
+    /// Account for `bytes` just received, sleeping if the budget is exhausted.
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+            bucket.reserve(bytes, Instant::now())
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bucket, parse_rate};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn parses_suffixes() {
+        assert_eq!(parse_rate("2048").unwrap(), 2048);
+        assert_eq!(parse_rate("500K").unwrap(), 500 * 1024);
+        assert_eq!(parse_rate("10M").unwrap(), 10 * 1024 * 1024);
+        assert_eq!(parse_rate("1.5g").unwrap(), 1536 * 1024 * 1024);
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("0").is_err());
+    }
+
+    #[test]
+    fn bucket_makes_callers_wait_for_debt() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(1000, start);
+        assert_eq!(bucket.reserve(1000, start), Duration::ZERO);
+        assert_eq!(bucket.reserve(500, start), Duration::from_millis(500));
+        // After one second the debt is repaid and half a second of budget is back.
+        let later = start + Duration::from_secs(1);
+        assert_eq!(bucket.reserve(500, later), Duration::ZERO);
+    }
+}
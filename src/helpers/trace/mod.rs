@@ -0,0 +1,203 @@
+use std::fmt;
+use std::sync::Mutex;
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::cloud::Image;
+
+/// Trace being collected, when [`enable`]d.
+static TRACE: Mutex<Option<Trace>> = Mutex::new(None);
+
+/// How `--explain` prints the resolution trace.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ExplainFormat {
+    /// Indented tree on stderr.
+    #[default]
+    Tree,
+    /// One JSON document on stderr.
+    Json,
+}
+
+/// Why each candidate image was kept or dropped while a query was resolved,
+/// one [`Step`] per filter in the order they ran.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Trace {
+    pub steps: Vec<Step>,
+}
+
+/// One filter: how many candidates passed and why the others did not.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Step {
+    pub name: String,
+    pub kept: usize,
+    pub excluded: Vec<Exclusion>,
+}
+
+/// A candidate dropped by a [`Step`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Exclusion {
+    /// URL or file name of the candidate.
+    pub candidate: String,
+    pub reason: String,
+}
+
+/// Start collecting a trace, dropping any previous one.
+pub fn enable() {
+    *TRACE.lock().unwrap_or_else(|e| e.into_inner()) = Some(Trace::default());
+}
+
+/// Whether a trace is being collected, so callers can skip building reasons.
+pub fn enabled() -> bool {
+    TRACE.lock().unwrap_or_else(|e| e.into_inner()).is_some()
+}
+
+/// The trace collected since [`enable`], which keeps collecting into a fresh
+/// one.
+pub fn take() -> Option<Trace> {
+    TRACE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_mut()
+        .map(std::mem::take)
+}
+
+fn with_step(name: &str, update: impl FnOnce(&mut Step)) {
+    let mut trace = TRACE.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(trace) = trace.as_mut() else {
+        return;
+    };
+    // Consecutive reports of the same filter (one per listing, say) are
+    // merged into a single step.
+    if trace.steps.last().is_none_or(|s| s.name != name) {
+        trace.steps.push(Step {
+            name: name.to_string(),
+            kept: 0,
+            excluded: Vec::new(),
+        });
+    }
+    update(trace.steps.last_mut().expect("just pushed"));
+}
+
+/// Forget the steps recorded so far, e.g. those of a release lookup that
+/// listed images before the listing being explained.
+pub fn reset() {
+    if let Some(trace) = TRACE.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        trace.steps.clear();
+    }
+}
+
+/// Record that `step` let one candidate through.
+pub fn kept(step: &str) {
+    with_step(step, |s| s.kept += 1);
+}
+
+/// Record that `step` dropped `candidate` because of `reason`.
+pub fn excluded(step: &str, candidate: &str, reason: impl FnOnce() -> String) {
+    if !enabled() {
+        return;
+    }
+    with_step(step, |s| {
+        s.excluded.push(Exclusion {
+            candidate: candidate.to_string(),
+            reason: reason(),
+        })
+    });
+}
+
+/// Keep the images for which `reject` gives no reason, recording the
+/// outcome of each as `step`.
+pub fn retain(step: &str, images: &mut Vec<Image>, reject: impl Fn(&Image) -> Option<String>) {
+    let traced = enabled();
+    images.retain(|image| match reject(image) {
+        None => {
+            if traced {
+                kept(step);
+            }
+            true
+        }
+        Some(reason) => {
+            excluded(step, image.url(), || reason);
+            false
+        }
+    });
+}
+
+impl Trace {
+    /// Render the trace as `format`.
+    pub fn render(&self, format: ExplainFormat) -> String {
+        match format {
+            ExplainFormat::Tree => self.to_string(),
+            ExplainFormat::Json => {
+                serde_json::to_string_pretty(self).expect("traces serialize to JSON")
+            }
+        }
+    }
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Resolution trace:")?;
+        for (i, step) in self.steps.iter().enumerate() {
+            let last_step = i + 1 == self.steps.len();
+            let (branch, indent) = if last_step {
+                ("└─", "   ")
+            } else {
+                ("├─", "│  ")
+            };
+            writeln!(
+                f,
+                "{branch} {}: kept {}, excluded {}",
+                step.name,
+                step.kept,
+                step.excluded.len()
+            )?;
+            for (j, exclusion) in step.excluded.iter().enumerate() {
+                let leaf = if j + 1 == step.excluded.len() {
+                    "└─"
+                } else {
+                    "├─"
+                };
+                writeln!(
+                    f,
+                    "{indent}{leaf} {}: {}",
+                    exclusion.candidate, exclusion.reason
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Exclusion, Step, Trace};
+
+    #[test]
+    fn renders_a_tree() {
+        let trace = Trace {
+            steps: vec![
+                Step {
+                    name: "arch".to_string(),
+                    kept: 2,
+                    excluded: vec![Exclusion {
+                        candidate: "noble-arm64.img".to_string(),
+                        reason: "arch is arm64, wanted amd64".to_string(),
+                    }],
+                },
+                Step {
+                    name: "variant".to_string(),
+                    kept: 1,
+                    excluded: Vec::new(),
+                },
+            ],
+        };
+        assert_eq!(
+            trace.to_string(),
+            "Resolution trace:\n\
+             ├─ arch: kept 2, excluded 1\n\
+             │  └─ noble-arm64.img: arch is arm64, wanted amd64\n\
+             └─ variant: kept 1, excluded 0\n"
+        );
+    }
+}
@@ -0,0 +1,362 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::helpers::paths;
+
+const STORE_FILE: &str = "trust.json";
+
+/// The trust store in use; unset unless the trust mode was enabled.
+static STORE: OnceLock<Mutex<TrustStore>> = OnceLock::new();
+
+/// What was seen the first time a repository's metadata was resolved.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct RepoPins {
+    /// `scheme://host[:port]` the checksum files were fetched from.
+    hosts: BTreeSet<String>,
+    /// Fingerprints of the keys that signed them.
+    signers: BTreeSet<String>,
+    /// SHA-256 of the checksum files at versioned URLs, which never change
+    /// once published.
+    #[serde(default)]
+    digests: BTreeMap<String, String>,
+}
+
+/// Trust-on-first-use record of repository metadata, kept in `trust.json`.
+#[derive(Debug, Default)]
+struct TrustStore {
+    path: Option<PathBuf>,
+    repos: BTreeMap<String, RepoPins>,
+    /// Whether `repos` changed since it was loaded or saved.
+    dirty: bool,
+}
+
+impl TrustStore {
+    /// The store at `path`. A corrupt one is moved aside and an unreadable
+    /// one left alone (and not written to); both are reported loudly since
+    /// every repository is then pinned afresh.
+    fn load(path: Option<PathBuf>) -> Self {
+        let Some(file) = &path else {
+            return Self::default();
+        };
+        let data = match fs::read_to_string(file) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Self {
+                    path,
+                    ..Self::default()
+                };
+            }
+            Err(err) => {
+                eprintln!(
+                    "WARNING: cannot read the trust store {} ({err}); nothing is checked \
+                     against earlier runs and no pins are recorded",
+                    file.display()
+                );
+                return Self::default();
+            }
+        };
+        match serde_json::from_str(&data) {
+            Ok(repos) => Self {
+                path,
+                repos,
+                dirty: false,
+            },
+            Err(err) => {
+                let aside = file.with_extension("json.corrupt");
+                let _ = fs::rename(file, &aside);
+                eprintln!(
+                    "WARNING: the trust store {} is corrupt ({err}); moved it to {} and \
+                     started an empty one, so every repository is trusted on first use again",
+                    file.display(),
+                    aside.display()
+                );
+                Self {
+                    path,
+                    ..Self::default()
+                }
+            }
+        }
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("Failed to create '{}': {e}", parent.display()))?;
+        }
+        let json = serde_json::to_string_pretty(&self.repos)
+            .map_err(|e| format!("Failed to serialise the trust store: {e}"))?;
+        write_atomically(path, json.as_bytes())
+    }
+
+    /// Record the checksum file at `url` with content `text`, signed by
+    /// `signer`, for `repo` and return what deviates from the earlier runs.
+    /// New hosts are learned after being reported once; new or missing
+    /// signing keys keep being reported until the pins are reset. Content
+    /// is only pinned at versioned URLs: files like Debian's
+    /// `latest/SHA512SUMS` change with every rebuild, while a changed
+    /// `20250210-2019/SHA512SUMS` is reported on every run.
+    fn observe(&mut self, repo: &str, url: &str, text: &str, signer: Option<&str>) -> Vec<String> {
+        let pins = self.repos.entry(repo.to_lowercase()).or_default();
+        let first_use = pins.hosts.is_empty();
+        let mut alerts = Vec::new();
+
+        if let Some(host) = origin(url)
+            && !pins.hosts.contains(&host)
+        {
+            if !first_use {
+                alerts.push(format!(
+                    "{repo} metadata now comes from {host}, previously only from {}",
+                    join(&pins.hosts)
+                ));
+            }
+            pins.hosts.insert(host);
+            self.dirty = true;
+        }
+
+        if is_versioned(url) {
+            let digest = hex::encode(Sha256::digest(text.as_bytes()));
+            match pins.digests.get(url) {
+                Some(pinned) if *pinned != digest => alerts.push(format!(
+                    "{url} changed since it was first seen (SHA-256 {pinned}, now {digest})"
+                )),
+                Some(_) => {}
+                None => {
+                    pins.digests.insert(url.to_string(), digest);
+                    self.dirty = true;
+                }
+            }
+        }
+
+        match signer {
+            Some(signer) if first_use || pins.signers.is_empty() => {
+                self.dirty |= pins.signers.insert(signer.to_string());
+            }
+            Some(signer) if !pins.signers.contains(signer) => alerts.push(format!(
+                "{url} is signed by key {signer}, but {repo} was pinned to {}",
+                join(&pins.signers)
+            )),
+            Some(_) => {}
+            None if !pins.signers.is_empty() => alerts.push(format!(
+                "{url} was not verified, but {repo} metadata used to be signed by {}",
+                join(&pins.signers)
+            )),
+            None => {}
+        }
+        alerts
+    }
+}
+
+/// Replace `path` with `data` through a temporary file in the same
+/// directory, so a crash never leaves a truncated store behind.
+fn write_atomically(path: &Path, data: &[u8]) -> Result<(), String> {
+    let tmp = path.with_extension(format!("json.{}.tmp", std::process::id()));
+    fs::write(&tmp, data)
+        .and_then(|_| fs::rename(&tmp, path))
+        .map_err(|e| {
+            let _ = fs::remove_file(&tmp);
+            format!("Failed to write '{}': {e}", path.display())
+        })
+}
+
+/// Whether a directory of `url` names a dated build, like Debian's
+/// `20250210-2019` or Ubuntu's `release-20240423`, rather than an alias
+/// such as `latest` that moves to each new build.
+fn is_versioned(url: &str) -> bool {
+    let Ok(url) = Url::parse(url) else {
+        return false;
+    };
+    let mut dirs: Vec<&str> = url.path().split('/').filter(|s| !s.is_empty()).collect();
+    dirs.pop();
+    !dirs.iter().any(|dir| matches!(*dir, "latest" | "current"))
+        && dirs.iter().any(|dir| {
+            dir.as_bytes().windows(8).any(|date| {
+                date.iter().all(u8::is_ascii_digit)
+                    && matches!(&date[..2], b"19" | b"20")
+                    && (b"01".as_slice()..=b"12".as_slice()).contains(&&date[4..6])
+            })
+        })
+}
+
+/// `scheme://host[:port]` of `url`.
+fn origin(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    Some(url.origin().ascii_serialization())
+}
+
+fn join(items: &BTreeSet<String>) -> String {
+    items.iter().cloned().collect::<Vec<_>>().join(", ")
+}
+
+/// Where the trust store lives
+/// (`$XDG_DATA_HOME/cloud-images-downloader/trust.json` on Linux).
+pub fn store_path() -> Option<PathBuf> {
+    paths::data_dir().map(|dir| dir.join(STORE_FILE))
+}
+
+/// Turn the trust mode on for this process. Later calls are ignored.
+pub fn enable() {
+    let _ = STORE.set(Mutex::new(TrustStore::load(store_path())));
+}
+
+/// Check where a checksum file (or signed index) of `repo` came from, who
+/// signed it and, at versioned URLs, its content `text` against the pins and
+/// record it; the store is only written when that added pins. Deviations are
+/// printed as warnings; nothing happens unless the trust mode is enabled.
+/// Waiting for the store's lock and writing it happen on the blocking pool.
+pub async fn observe(repo: &str, url: &str, text: &str, signer: Option<&str>) {
+    let Some(store) = STORE.get() else {
+        return;
+    };
+    let (repo, url, text) = (repo.to_string(), url.to_string(), text.to_string());
+    let signer = signer.map(str::to_string);
+    let _ = tokio::task::spawn_blocking(move || {
+        observe_blocking(store, &repo, &url, &text, signer.as_deref())
+    })
+    .await;
+}
+
+fn observe_blocking(
+    store: &Mutex<TrustStore>,
+    repo: &str,
+    url: &str,
+    text: &str,
+    signer: Option<&str>,
+) {
+    let mut store = store.lock().unwrap_or_else(|e| e.into_inner());
+    let alerts = store.observe(repo, url, text, signer);
+    for alert in &alerts {
+        eprintln!("WARNING: possible mirror compromise: {alert}");
+    }
+    if !alerts.is_empty()
+        && let Some(path) = &store.path
+    {
+        eprintln!(
+            "WARNING: if the change is legitimate, remove the `{repo}` entry from {}",
+            path.display()
+        );
+    }
+    if store.dirty {
+        match store.save() {
+            Ok(()) => store.dirty = false,
+            Err(err) => eprintln!("Warning: {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TrustStore, is_versioned};
+
+    const SUMS: &str = "https://cloud.debian.org/images/cloud/bookworm/latest/SHA512SUMS";
+
+    #[test]
+    fn first_use_is_silent_and_pins_everything() {
+        let mut store = TrustStore::default();
+        assert!(store.observe("debian", SUMS, "", Some("KEY1")).is_empty());
+        assert!(store.observe("debian", SUMS, "", Some("KEY1")).is_empty());
+        let pins = &store.repos["debian"];
+        assert!(pins.signers.contains("KEY1"));
+        assert!(pins.hosts.contains("https://cloud.debian.org"));
+    }
+
+    #[test]
+    fn reports_new_keys_hosts_and_unsigned_files() {
+        let mut store = TrustStore::default();
+        store.observe("debian", SUMS, "", Some("KEY1"));
+
+        assert_eq!(store.observe("debian", SUMS, "", Some("KEY2")).len(), 1);
+        // A new key stays suspicious, a new host is only reported once.
+        assert_eq!(store.observe("debian", SUMS, "", Some("KEY2")).len(), 1);
+        let mirror = "https://mirror.invalid/debian/bookworm/latest/SHA512SUMS";
+        assert_eq!(store.observe("debian", mirror, "", Some("KEY1")).len(), 1);
+        assert!(store.observe("debian", mirror, "", Some("KEY1")).is_empty());
+
+        // An unsigned file where signed ones were seen before.
+        assert_eq!(store.observe("debian", SUMS, "", None).len(), 1);
+    }
+
+    #[test]
+    fn unsigned_rebuilds_are_not_reported() {
+        let mut store = TrustStore::default();
+        assert!(store.observe("almalinux", SUMS, "", None).is_empty());
+        assert!(store.observe("almalinux", SUMS, "", None).is_empty());
+    }
+
+    #[test]
+    fn pins_the_content_of_versioned_files() {
+        let build = "https://cloud.debian.org/images/cloud/bookworm/20250210-2019/SHA512SUMS";
+        assert!(is_versioned(build));
+        assert!(is_versioned(
+            "https://cloud-images.ubuntu.com/releases/noble/release-20240423/SHA256SUMS"
+        ));
+        assert!(!is_versioned(SUMS));
+        assert!(!is_versioned(
+            "https://repo.almalinux.org/almalinux/9/cloud/x86_64/images/CHECKSUM"
+        ));
+
+        let mut store = TrustStore::default();
+        assert!(store.observe("debian", build, "a", Some("KEY1")).is_empty());
+        assert!(store.observe("debian", build, "a", Some("KEY1")).is_empty());
+        // A changed file at a versioned URL is reported every time.
+        assert_eq!(store.observe("debian", build, "b", Some("KEY1")).len(), 1);
+        assert_eq!(store.observe("debian", build, "b", Some("KEY1")).len(), 1);
+        // Rebuilds behind `latest` are not.
+        store.observe("debian", SUMS, "a", Some("KEY1"));
+        assert!(store.observe("debian", SUMS, "b", Some("KEY1")).is_empty());
+        assert_eq!(store.repos["debian"].digests.len(), 1);
+    }
+
+    #[test]
+    fn only_new_pins_need_saving() {
+        let mut store = TrustStore::default();
+        store.observe("debian", SUMS, "", Some("KEY1"));
+        assert!(store.dirty);
+        store.dirty = false;
+        store.observe("debian", SUMS, "", Some("KEY1"));
+        store.observe("debian", SUMS, "", Some("KEY2"));
+        assert!(!store.dirty);
+    }
+
+    #[test]
+    fn moves_a_corrupt_store_aside() {
+        let dir = std::env::temp_dir().join(format!("cid-trust-corrupt-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("trust.json");
+        std::fs::write(&path, "{\"debian\": {\"hosts\": [").unwrap();
+        let store = TrustStore::load(Some(path.clone()));
+        assert!(store.repos.is_empty());
+        assert_eq!(store.path.as_deref(), Some(path.as_path()));
+        assert!(!path.exists());
+        assert!(dir.join("trust.json.corrupt").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn saves_through_a_temporary_file() {
+        let dir = std::env::temp_dir().join(format!("cid-trust-{}", std::process::id()));
+        let mut store = TrustStore {
+            path: Some(dir.join("trust.json")),
+            ..TrustStore::default()
+        };
+        store.observe(
+            "debian",
+            "https://cloud.debian.org/images/cloud/bookworm/20250210-2019/SHA512SUMS",
+            "a",
+            Some("KEY1"),
+        );
+        store.save().unwrap();
+        let reloaded = TrustStore::load(Some(dir.join("trust.json")));
+        assert_eq!(reloaded.repos, store.repos);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,258 @@
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::cloud::Arch;
+use crate::helpers::{find_program, paths};
+
+/// UEFI firmware for `-machine virt`, where distros and Homebrew install it.
+const AARCH64_FIRMWARE: &[&str] = &[
+    "/usr/share/qemu-efi-aarch64/QEMU_EFI.fd",
+    "/usr/share/AAVMF/AAVMF_CODE.fd",
+    "/usr/share/edk2/aarch64/QEMU_EFI.fd",
+    "/usr/share/edk2/aarch64/QEMU_EFI-pflash.raw",
+    "/opt/homebrew/share/qemu/edk2-aarch64-code.fd",
+    "/usr/local/share/qemu/edk2-aarch64-code.fd",
+];
+
+/// Public keys tried when no `--ssh-key` is given, below `~/.ssh`.
+const DEFAULT_KEYS: &[&str] = &["id_ed25519.pub", "id_ecdsa.pub", "id_rsa.pub"];
+
+/// How to boot an image with `qemu-system`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmOptions {
+    /// Guest memory as understood by `-m`, e.g. `2G`.
+    pub memory: String,
+    pub cpus: u32,
+    /// Host port forwarded to the guest's SSH port on `127.0.0.1`.
+    pub ssh_port: u16,
+}
+
+impl Default for VmOptions {
+    fn default() -> Self {
+        Self {
+            memory: "2G".to_string(),
+            cpus: 2,
+            ssh_port: 2222,
+        }
+    }
+}
+
+/// Locate the `qemu-system` binary emulating `arch`.
+pub fn locate(arch: Arch) -> Result<PathBuf, String> {
+    let name = format!("qemu-system-{}", system_arch(arch));
+    find_program(&name).ok_or_else(|| {
+        format!(
+            "{name} was not found on PATH; install it (e.g. `apt install qemu-system`, \
+             `dnf install qemu-kvm` or `brew install qemu`) to run images"
+        )
+    })
+}
+
+/// Suffix of the `qemu-system-*` binary emulating `arch`.
+pub fn system_arch(arch: Arch) -> &'static str {
+    match arch {
+        Arch::Amd64 => "x86_64",
+        Arch::Arm64 => "aarch64",
+        Arch::Armhf => "arm",
+        Arch::Ppc64le => "ppc64",
+        Arch::S390x => "s390x",
+        Arch::Riscv64 => "riscv64",
+    }
+}
+
+/// The public key at `path`, or the first of the usual keys in `~/.ssh`.
+pub fn ssh_key(path: Option<&Path>) -> Result<Option<String>, String> {
+    let candidates: Vec<PathBuf> = match path {
+        Some(path) => vec![path.to_path_buf()],
+        None => paths::home_dir()
+            .map(|home| {
+                DEFAULT_KEYS
+                    .iter()
+                    .map(|k| home.join(".ssh").join(k))
+                    .collect()
+            })
+            .unwrap_or_default(),
+    };
+    for candidate in &candidates {
+        match fs::read_to_string(candidate) {
+            Ok(key) => return Ok(Some(key.trim().to_string())),
+            Err(e) if path.is_some() => {
+                return Err(format!("Failed to read '{}': {e}", candidate.display()));
+            }
+            Err(_) => {}
+        }
+    }
+    Ok(None)
+}
+
+/// `user-data` authorising `ssh_key` for the image's default user.
+pub fn user_data(ssh_key: Option<&str>) -> String {
+    let mut data = "#cloud-config\n".to_string();
+    if let Some(key) = ssh_key {
+        data.push_str(&format!("ssh_authorized_keys:\n  - {key}\n"));
+    }
+    data
+}
+
+/// How the guest gets its first-boot configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Provisioning<'a> {
+    /// A NoCloud seed attached as a read-only drive.
+    Seed(&'a Path),
+    /// An Ignition config passed as the firmware config entry `name`.
+    Ignition { name: &'a str, config: &'a Path },
+}
+
+/// Arguments booting `disk` with its `provisioning`: virtio disk and
+/// network (SSH forwarded to `options.ssh_port`), the serial console on the
+/// terminal and hardware acceleration when the host supports it.
+pub fn args(
+    arch: Arch,
+    disk: &Path,
+    provisioning: Provisioning,
+    options: &VmOptions,
+) -> Vec<OsString> {
+    let mut args: Vec<OsString> = Vec::new();
+    let mut push = |parts: &[&str]| args.extend(parts.iter().map(OsString::from));
+    push(&["-m", &options.memory, "-smp", &options.cpus.to_string()]);
+    push(&["-nographic"]);
+    match arch {
+        Arch::Amd64 => push(&["-machine", "q35"]),
+        Arch::Arm64 | Arch::Armhf | Arch::Riscv64 => push(&["-machine", "virt"]),
+        Arch::Ppc64le | Arch::S390x => {}
+    }
+    if kvm_available(arch) {
+        push(&["-accel", "kvm", "-cpu", "host"]);
+    } else {
+        push(&["-accel", "tcg"]);
+        if arch != Arch::Amd64 {
+            push(&["-cpu", "max"]);
+        }
+    }
+    if arch == Arch::Arm64
+        && let Some(firmware) = AARCH64_FIRMWARE.iter().find(|f| Path::new(f).is_file())
+    {
+        push(&["-bios", firmware]);
+    }
+    push(&[
+        "-netdev",
+        &format!(
+            "user,id=net0,hostfwd=tcp:127.0.0.1:{}-:22",
+            options.ssh_port
+        ),
+        "-device",
+        "virtio-net-pci,netdev=net0",
+    ]);
+    let drive = |path: &Path, extra: &str| {
+        let mut drive = OsString::from("if=virtio,");
+        drive.push(extra);
+        drive.push(",file=");
+        // `,` separates options; qemu escapes it by doubling.
+        drive.push(path.to_string_lossy().replace(',', ",,"));
+        drive
+    };
+    args.extend(["-drive".into(), drive(disk, "format=qcow2")]);
+    match provisioning {
+        Provisioning::Seed(seed) => {
+            args.extend(["-drive".into(), drive(seed, "format=raw,readonly=on")]);
+        }
+        Provisioning::Ignition { name, config } => {
+            let mut fw_cfg = OsString::from(format!("name={name},file="));
+            fw_cfg.push(config.to_string_lossy().replace(',', ",,"));
+            args.extend(["-fw_cfg".into(), fw_cfg]);
+        }
+    }
+    args
+}
+
+/// Whether `arch` guests can use KVM on this host.
+fn kvm_available(arch: Arch) -> bool {
+    cfg!(target_os = "linux")
+        && Arch::from_name(std::env::consts::ARCH) == Some(arch)
+        && fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/kvm")
+            .is_ok()
+}
+
+/// Boot `disk` and wait until the guest powers off; the terminal is its
+/// serial console (`Ctrl-a x` quits).
+pub fn boot(
+    arch: Arch,
+    disk: &Path,
+    provisioning: Provisioning,
+    options: &VmOptions,
+) -> Result<(), String> {
+    let qemu = locate(arch)?;
+    let status = Command::new(&qemu)
+        .args(args(arch, disk, provisioning, options))
+        .status()
+        .map_err(|e| format!("Failed to run '{}': {e}", qemu.display()))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("{} exited with {status}", qemu.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Provisioning, VmOptions, args, user_data};
+    use crate::cloud::Arch;
+    use std::path::Path;
+
+    #[test]
+    fn boots_from_virtio_with_the_seed_and_ssh_forwarded() {
+        let args: Vec<String> = args(
+            Arch::Amd64,
+            Path::new("/vm/noble,1.qcow2"),
+            Provisioning::Seed(Path::new("/vm/seed.iso")),
+            &VmOptions::default(),
+        )
+        .into_iter()
+        .map(|a| a.into_string().unwrap())
+        .collect();
+        let after = |flag: &str| {
+            args.iter()
+                .enumerate()
+                .filter(|(_, a)| *a == flag)
+                .map(|(i, _)| args[i + 1].as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            after("-drive"),
+            [
+                "if=virtio,format=qcow2,file=/vm/noble,,1.qcow2",
+                "if=virtio,format=raw,readonly=on,file=/vm/seed.iso"
+            ]
+        );
+        assert_eq!(
+            after("-netdev"),
+            ["user,id=net0,hostfwd=tcp:127.0.0.1:2222-:22"]
+        );
+        assert!(args.iter().any(|a| a == "-nographic"));
+        assert_eq!(
+            user_data(Some("ssh-ed25519 AAAA me@host")),
+            "#cloud-config\nssh_authorized_keys:\n  - ssh-ed25519 AAAA me@host\n"
+        );
+
+        let ignition = super::args(
+            Arch::Amd64,
+            Path::new("/vm/fcos.qcow2"),
+            Provisioning::Ignition {
+                name: "opt/com.coreos/config",
+                config: Path::new("/vm/fcos.ign"),
+            },
+            &VmOptions::default(),
+        );
+        let fw_cfg = ignition.iter().position(|a| a == "-fw_cfg").unwrap();
+        assert_eq!(
+            ignition[fw_cfg + 1],
+            "name=opt/com.coreos/config,file=/vm/fcos.ign"
+        );
+        assert_eq!(ignition.iter().filter(|a| *a == "-drive").count(), 1);
+    }
+}
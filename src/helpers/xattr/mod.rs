@@ -0,0 +1,81 @@
+use std::path::Path;
+
+use crate::helpers::report::Report;
+
+/// Attribute prefix of the values this tool writes.
+const PREFIX: &str = "user.cloud-images-downloader";
+
+/// Extended attributes describing a download: the freedesktop origin URL
+/// plus the checksum, its verification and the download time.
+fn attributes(report: &Report) -> Vec<(String, String)> {
+    vec![
+        ("user.xdg.origin.url".to_string(), report.url.clone()),
+        (
+            format!("{PREFIX}.checksum"),
+            format!("{}:{}", report.algorithm, report.digest),
+        ),
+        (format!("{PREFIX}.verified"), report.verified.to_string()),
+        (
+            format!("{PREFIX}.downloaded_at"),
+            report.downloaded_at.to_string(),
+        ),
+    ]
+}
+
+/// Tag the image at `path` with the provenance recorded in `report`.
+pub fn tag(path: &Path, report: &Report) -> Result<(), String> {
+    for (name, value) in attributes(report) {
+        set(path, &name, value.as_bytes())
+            .map_err(|e| format!("Failed to set {name} on '{}': {e}", path.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set(path: &Path, name: &str, value: &[u8]) -> Result<(), String> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(|e| e.to_string())?;
+    let name = CString::new(name).map_err(|e| e.to_string())?;
+    // SAFETY: both strings are NUL terminated and `value` is valid for its
+    // length for the duration of the call.
+    let ret = unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error().to_string())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set(_path: &Path, _name: &str, _value: &[u8]) -> Result<(), String> {
+    Err("extended attributes are only supported on Linux".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::attributes;
+    use crate::cloud::{ChecksumKind, sample_image};
+    use crate::helpers::report::Report;
+
+    #[test]
+    fn describes_origin_and_checksum() {
+        let image = sample_image();
+        let report = Report::new(&image, ChecksumKind::Sha512, "abcd".to_string(), true);
+        let attrs = attributes(&report);
+        assert!(attrs.contains(&("user.xdg.origin.url".to_string(), image.url().to_string())));
+        assert!(attrs.contains(&(
+            "user.cloud-images-downloader.checksum".to_string(),
+            "sha512:abcd".to_string()
+        )));
+    }
+}
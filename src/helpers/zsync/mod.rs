@@ -0,0 +1,452 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use md4::{Digest, Md4};
+
+/// Bytes read from the seed file at a time.
+const READ_CHUNK: usize = 4 * 1024 * 1024;
+
+/// Missing blocks closer than this are fetched in one range request; the few
+/// bytes downloaded twice are cheaper than an extra round trip.
+const MERGE_GAP_BLOCKS: u64 = 16;
+
+/// Weak rolling checksum of one block, as defined by rsync/zsync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Rsum {
+    a: u16,
+    b: u16,
+}
+
+impl Rsum {
+    fn of(block: &[u8]) -> Self {
+        let mut a: u16 = 0;
+        let mut b: u16 = 0;
+        let mut weight = block.len();
+        for &c in block {
+            a = a.wrapping_add(c as u16);
+            b = b.wrapping_add((weight as u16).wrapping_mul(c as u16));
+            weight -= 1;
+        }
+        Self { a, b }
+    }
+
+    /// Slide the window one byte: drop `old`, append `new`.
+    fn roll(&mut self, old: u8, new: u8, blocksize: usize) {
+        self.a = self.a.wrapping_sub(old as u16).wrapping_add(new as u16);
+        self.b = self
+            .b
+            .wrapping_sub((blocksize as u16).wrapping_mul(old as u16))
+            .wrapping_add(self.a);
+    }
+
+    /// The sum as stored in a control file: `a` and `b` big-endian, cut to
+    /// the bytes `mask` keeps.
+    fn key(self, mask: u32) -> u32 {
+        ((self.a as u32) << 16 | self.b as u32) & mask
+    }
+}
+
+/// Checksums published for one block of the target file.
+#[derive(Debug, Clone)]
+struct BlockSum {
+    key: u32,
+    md4: Vec<u8>,
+}
+
+/// Parsed `.zsync` control file.
+#[derive(Debug)]
+pub struct ControlFile {
+    blocksize: usize,
+    length: u64,
+    seq_matches: usize,
+    /// Bits of the rolling sum the control file stores (its trailing
+    /// `rsum_bytes` bytes).
+    rsum_mask: u32,
+    /// Hex SHA-1 of the complete target file.
+    pub sha1: Option<String>,
+    blocks: Vec<BlockSum>,
+}
+
+/// Result of reusing a seed file: what was copied and what is still missing.
+#[derive(Debug)]
+pub struct Plan {
+    pub reused: u64,
+    /// Inclusive byte ranges that still have to be downloaded.
+    pub missing: Vec<(u64, u64)>,
+}
+
+impl ControlFile {
+    /// Parse the header and block table of a zsync 0.6 control file.
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        let header_end = data
+            .windows(2)
+            .position(|w| w == b"\n\n")
+            .ok_or("zsync control file has no header terminator")?;
+        let header = std::str::from_utf8(&data[..header_end])
+            .map_err(|e| format!("zsync header is not UTF-8: {e}"))?;
+
+        let mut fields = HashMap::new();
+        for line in header.lines() {
+            if let Some((key, value)) = line.split_once(':') {
+                fields.insert(key.trim(), value.trim());
+            }
+        }
+
+        if fields.contains_key("Z-Map2") {
+            return Err("compressed zsync targets are not supported".to_string());
+        }
+
+        let number = |key: &str| -> Result<u64, String> {
+            fields
+                .get(key)
+                .ok_or_else(|| format!("zsync header lacks '{key}'"))?
+                .parse()
+                .map_err(|e| format!("invalid zsync '{key}': {e}"))
+        };
+        let blocksize = number("Blocksize")? as usize;
+        let length = number("Length")?;
+        if blocksize == 0 {
+            return Err("zsync block size must not be zero".to_string());
+        }
+
+        let lengths: Vec<usize> = fields
+            .get("Hash-Lengths")
+            .ok_or("zsync header lacks 'Hash-Lengths'")?
+            .split(',')
+            .map(|n| n.trim().parse())
+            .collect::<Result<_, _>>()
+            .map_err(|e| format!("invalid zsync 'Hash-Lengths': {e}"))?;
+        let [seq_matches, rsum_bytes, checksum_bytes] = lengths[..] else {
+            return Err("zsync 'Hash-Lengths' must have three entries".to_string());
+        };
+        if !(1..=2).contains(&seq_matches)
+            || !(1..=4).contains(&rsum_bytes)
+            || !(3..=16).contains(&checksum_bytes)
+        {
+            return Err(format!("unsupported zsync hash lengths {lengths:?}"));
+        }
+        let rsum_mask = u32::MAX >> (8 * (4 - rsum_bytes));
+
+        let count = length.div_ceil(blocksize as u64) as usize;
+        let entry = rsum_bytes + checksum_bytes;
+        let table = &data[header_end + 2..];
+        if table.len() < count * entry {
+            return Err(format!(
+                "zsync block table is truncated: {} of {} bytes",
+                table.len(),
+                count * entry
+            ));
+        }
+
+        let blocks = table
+            .chunks_exact(entry)
+            .take(count)
+            .map(|raw| {
+                let mut rsum = [0u8; 4];
+                rsum[4 - rsum_bytes..].copy_from_slice(&raw[..rsum_bytes]);
+                let value = u32::from_be_bytes(rsum);
+                BlockSum {
+                    key: value & rsum_mask,
+                    md4: raw[rsum_bytes..].to_vec(),
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            blocksize,
+            length,
+            seq_matches,
+            rsum_mask,
+            sha1: fields.get("SHA-1").map(|s| s.to_ascii_lowercase()),
+            blocks,
+        })
+    }
+
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    /// Length of block `id`; only the last one may be short.
+    fn block_len(&self, id: usize) -> usize {
+        let start = id as u64 * self.blocksize as u64;
+        (self.length - start).min(self.blocksize as u64) as usize
+    }
+
+    /// Does `data` (zero padded to a full block) carry the checksums of
+    /// block `id`?
+    fn block_matches(&self, id: usize, data: &[u8]) -> bool {
+        let block = &self.blocks[id];
+        let mut padded;
+        let full = if data.len() < self.blocksize {
+            padded = data.to_vec();
+            padded.resize(self.blocksize, 0);
+            &padded[..]
+        } else {
+            &data[..self.blocksize]
+        };
+        Rsum::of(full).key(self.rsum_mask) == block.key
+            && md4(full)[..block.md4.len()] == block.md4[..]
+    }
+
+    /// Copy every block of `seed` that also occurs in the target into `out`
+    /// (pre-sized to the target length) and report the ranges left to fetch.
+    pub fn reuse_seed(&self, seed: &Path, out: &mut File) -> Result<Plan, String> {
+        let bs = self.blocksize;
+        let mut index: HashMap<u32, Vec<usize>> = HashMap::new();
+        let mut present = vec![false; 1 << 16];
+        for (id, block) in self.blocks.iter().enumerate() {
+            index.entry(block.key).or_default().push(id);
+            present[(block.key & 0xffff) as usize] = true;
+        }
+
+        let mut file =
+            File::open(seed).map_err(|e| format!("Failed to open '{}': {e}", seed.display()))?;
+        let mut found = vec![false; self.blocks.len()];
+        let mut reused: u64 = 0;
+        let mut data: Vec<u8> = Vec::new();
+        let mut pos = 0;
+        let mut eof = false;
+        let mut sum: Option<Rsum> = None;
+        let lookahead = bs * self.seq_matches + 1;
+
+        loop {
+            if !eof && data.len() - pos < lookahead {
+                data.drain(..pos);
+                pos = 0;
+                let start = data.len();
+                data.resize(start + READ_CHUNK, 0);
+                let n = file
+                    .read(&mut data[start..])
+                    .map_err(|e| format!("Failed to read '{}': {e}", seed.display()))?;
+                data.truncate(start + n);
+                eof = n == 0;
+                continue;
+            }
+            if data.len() - pos < bs {
+                break;
+            }
+
+            let rsum = *sum.get_or_insert_with(|| Rsum::of(&data[pos..pos + bs]));
+            let hits = if present[(rsum.key(self.rsum_mask) & 0xffff) as usize] {
+                self.matches_at(&index, &found, &data[pos..], rsum)
+            } else {
+                Vec::new()
+            };
+
+            if !hits.is_empty() {
+                let mut advance = bs;
+                for (id, offset) in hits {
+                    let len = self.block_len(id);
+                    out.seek(SeekFrom::Start(id as u64 * bs as u64))
+                        .and_then(|_| out.write_all(&data[pos + offset..pos + offset + len]))
+                        .map_err(|e| format!("Error while writing to file: {e}"))?;
+                    found[id] = true;
+                    reused += len as u64;
+                    advance = advance.max(offset + bs);
+                }
+                pos += advance.min(data.len() - pos);
+                sum = None;
+                continue;
+            }
+
+            if data.len() - pos <= bs {
+                break;
+            }
+            if let Some(sum) = sum.as_mut() {
+                sum.roll(data[pos], data[pos + bs], bs);
+            }
+            pos += 1;
+        }
+
+        Ok(Plan {
+            reused,
+            missing: self.missing_ranges(&found),
+        })
+    }
+
+    /// Not yet found blocks whose strong checksum matches the window starting
+    /// at `window`, as `(block id, offset in window)`. With `seq_matches == 2`
+    /// the following block has to match too, which keeps short checksums from
+    /// producing false positives.
+    fn matches_at(
+        &self,
+        index: &HashMap<u32, Vec<usize>>,
+        found: &[bool],
+        window: &[u8],
+        rsum: Rsum,
+    ) -> Vec<(usize, usize)> {
+        let bs = self.blocksize;
+        let Some(candidates) = index.get(&rsum.key(self.rsum_mask)) else {
+            return Vec::new();
+        };
+
+        let digest = md4(&window[..bs]);
+        let mut hits = Vec::new();
+        for &id in candidates {
+            let block = &self.blocks[id];
+            if found[id] || digest[..block.md4.len()] != block.md4[..] {
+                continue;
+            }
+            if self.seq_matches > 1 && id + 1 < self.blocks.len() {
+                let next_len = self.block_len(id + 1);
+                let Some(next) = window.get(bs..bs + next_len) else {
+                    continue;
+                };
+                if !self.block_matches(id + 1, next) {
+                    continue;
+                }
+                if !found[id + 1] {
+                    hits.push((id + 1, bs));
+                }
+            }
+            hits.push((id, 0));
+        }
+        hits
+    }
+
+    /// Merge the blocks that were not found into inclusive byte ranges.
+    fn missing_ranges(&self, found: &[bool]) -> Vec<(u64, u64)> {
+        let bs = self.blocksize as u64;
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        for (id, _) in found.iter().enumerate().filter(|(_, found)| !**found) {
+            let start = id as u64 * bs;
+            let end = (start + bs).min(self.length) - 1;
+            match ranges.last_mut() {
+                Some(last) if start <= last.1 + 1 + MERGE_GAP_BLOCKS * bs => last.1 = end,
+                _ => ranges.push((start, end)),
+            }
+        }
+        ranges
+    }
+}
+
+/// MD4 digest (RFC 1320), the strong block checksum used by zsync.
+fn md4(data: &[u8]) -> [u8; 16] {
+    Md4::digest(data).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ControlFile, Rsum, md4};
+    use std::fs::File;
+    use std::io::Read;
+
+    /// Build a control file for `target` the way `zsyncmake` would.
+    fn control_for(
+        target: &[u8],
+        blocksize: usize,
+        hash_lengths: (usize, usize, usize),
+    ) -> Vec<u8> {
+        let (seq, rsum_bytes, checksum_bytes) = hash_lengths;
+        let mut out = format!(
+            "zsync: 0.6.2\nFilename: t.img\nBlocksize: {blocksize}\nLength: {}\n\
+             Hash-Lengths: {seq},{rsum_bytes},{checksum_bytes}\nURL: t.img\n\n",
+            target.len()
+        )
+        .into_bytes();
+        for block in target.chunks(blocksize) {
+            let mut padded = block.to_vec();
+            padded.resize(blocksize, 0);
+            let rsum = Rsum::of(&padded);
+            let mut raw = rsum.a.to_be_bytes().to_vec();
+            raw.extend_from_slice(&rsum.b.to_be_bytes());
+            out.extend_from_slice(&raw[4 - rsum_bytes..]);
+            out.extend_from_slice(&md4(&padded)[..checksum_bytes]);
+        }
+        out
+    }
+
+    #[test]
+    fn md4_matches_rfc_vectors() {
+        // RFC 1320, appendix A.5.
+        let vectors: [(&[u8], &str); 7] = [
+            (b"", "31d6cfe0d16ae931b73c59d7e0c089c0"),
+            (b"a", "bde52cb31de33e46245e05fbdbd6fb24"),
+            (b"abc", "a448017aaf21d8525fc10ae87aa6729d"),
+            (b"message digest", "d9130a8164549fe818874806e1c7014b"),
+            (
+                b"abcdefghijklmnopqrstuvwxyz",
+                "d79e1c308aa5bbcdeea8ed63df412da9",
+            ),
+            (
+                b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789",
+                "043f8582f241db351ce627e153e7f0e4",
+            ),
+            (
+                b"12345678901234567890123456789012345678901234567890123456789012345678901234567890",
+                "e33b4ddc9c38f2199c3e7b164fcc0536",
+            ),
+        ];
+        for (input, digest) in vectors {
+            assert_eq!(hex::encode(md4(input)), digest);
+        }
+    }
+
+    #[test]
+    fn rolling_sum_equals_recomputed_sum() {
+        let data: Vec<u8> = (0..100u32).map(|i| (i * 37 % 251) as u8).collect();
+        let mut sum = Rsum::of(&data[0..16]);
+        for pos in 0..50 {
+            sum.roll(data[pos], data[pos + 16], 16);
+            assert_eq!(sum, Rsum::of(&data[pos + 1..pos + 17]));
+        }
+    }
+
+    /// Rebuild `new` from `old` with a control file using `hash_lengths`
+    /// and return how many bytes were reused.
+    fn rebuild(old: &[u8], new: &[u8], hash_lengths: (usize, usize, usize), name: &str) -> u64 {
+        let control = ControlFile::parse(&control_for(new, 64, hash_lengths)).unwrap();
+        let dir = std::env::temp_dir().join(format!("zsync-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let seed = dir.join("seed");
+        std::fs::write(&seed, old).unwrap();
+        let out_path = dir.join("out");
+        let mut out = File::create(&out_path).unwrap();
+        out.set_len(control.length()).unwrap();
+
+        let plan = control.reuse_seed(&seed, &mut out).unwrap();
+
+        // Fill the gaps from the target and compare.
+        let mut rebuilt = Vec::new();
+        File::open(&out_path)
+            .unwrap()
+            .read_to_end(&mut rebuilt)
+            .unwrap();
+        for (start, end) in &plan.missing {
+            rebuilt[*start as usize..=*end as usize]
+                .copy_from_slice(&new[*start as usize..=*end as usize]);
+        }
+        assert_eq!(rebuilt, new);
+        std::fs::remove_dir_all(&dir).unwrap();
+        plan.reused
+    }
+
+    #[test]
+    fn reuses_shifted_blocks_and_reports_the_rest() {
+        let old: Vec<u8> = (0..4096u32).map(|i| (i * 7 % 253) as u8).collect();
+        // New build: a few bytes inserted at the front and a changed tail.
+        let mut new = b"header!".to_vec();
+        new.extend_from_slice(&old[..3000]);
+        new.extend((0..500u32).map(|i| (i * 13 % 241) as u8));
+
+        let reused = rebuild(&old, &new, (2, 2, 5), "shifted");
+        assert!(reused >= 2800, "reused only {reused} bytes");
+    }
+
+    #[test]
+    fn one_byte_rolling_sums_still_match() {
+        let old: Vec<u8> = (0..4096u32).map(|i| (i * 7 % 253) as u8).collect();
+        let mut new = old[..3000].to_vec();
+        new.extend((0..500u32).map(|i| (i * 13 % 241) as u8));
+
+        for hash_lengths in [(2, 1, 8), (1, 1, 16), (2, 3, 5)] {
+            let reused = rebuild(&old, &new, hash_lengths, "short-rsum");
+            assert!(
+                reused >= 2900,
+                "{hash_lengths:?} reused only {reused} bytes"
+            );
+        }
+    }
+}
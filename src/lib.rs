@@ -0,0 +1,156 @@
+//! Resolve and download official cloud images of Ubuntu, Debian and
+//! AlmaLinux.
+//!
+//! The binary is a thin front-end over this crate; other tools can embed the
+//! same resolver and download engine:
+//!
+//! ```no_run
+//! use std::path::Path;
+//!
+//! use cloud_images_downloader::cloud::Arch;
+//! use cloud_images_downloader::{DownloadOptions, ImageQuery, download, repositories, resolve};
+//!
+//! # async fn run() -> anyhow::Result<()> {
+//! repositories::init_default()?;
+//! let query = ImageQuery::new()
+//!     .distro("debian")
+//!     .release("bookworm")
+//!     .arch(Arch::Amd64)
+//!     .variant("genericcloud")
+//!     .format("qcow2")
+//!     .latest();
+//! let image = resolve(&query).await?;
+//! download(&image, Path::new("/var/lib/images"), &DownloadOptions::default()).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Neither [`resolve`], [`find`] nor [`download`] with the default
+//! [`DownloadOptions`] prompts; [`select`] asks for the steps an
+//! [`ImageQuery`] leaves open, the way the binary does.
+//!
+//! Failures are reported as a [`CloudImagesError`] whose variant tells a
+//! missing image apart from network, checksum, I/O and configuration
+//! problems or a prompt the user backed out of.
+//!
+//! The integrations (`index`, `server`, `mirror`, `s3`, `oci`, `deploy` and
+//! `desktop-notifications`) are cargo features enabled by default; with
+//! `default-features = false` only the resolver and download engine are
+//! built.
+
+pub mod cloud;
+pub mod error;
+pub mod helpers;
+pub mod repositories;
+
+#[doc(hidden)]
+pub use inventory;
+
+use std::path::Path;
+
+pub use cloud::Image;
+pub use error::{CloudImagesError, Result};
+pub use helpers::cancel::CancellationToken;
+pub use helpers::image_resolver::DownloadOptions;
+pub use repositories::ImageQuery;
+
+use helpers::{choose_or_preset, image_resolver};
+use repositories::providers;
+
+/// Track the pickers resolve images from unless told otherwise.
+pub const DEFAULT_TRACK: &str = "releases";
+
+/// Matching images named when [`resolve`] finds the query ambiguous.
+const AMBIGUOUS_SHOWN: usize = 5;
+
+/// The outcome of [`select`]: the image plus how it is presented to users.
+#[derive(Debug, Clone)]
+pub struct Selection {
+    pub distro: String,
+    /// Release identifier understood by the provider, e.g. `bookworm`.
+    pub release: String,
+    pub arch: String,
+    /// Release as shown to users, e.g. `bookworm (20250210-2019)` for Debian.
+    pub version: String,
+    pub image: Image,
+}
+
+/// Narrow `query` down to a single image: distro, then the steps of its
+/// [`ImageProvider`](repositories::ImageProvider). Steps already answered by `query` are skipped, the others are
+/// prompted for. `max_builds` caps the builds offered per release and
+/// cancelling `cancel` aborts the listing in progress.
+///
+/// The repositories must have been loaded with one of the
+/// `repositories::init_*` functions.
+pub async fn select(
+    track: &str,
+    query: &ImageQuery,
+    max_builds: Option<usize>,
+    cancel: &CancellationToken,
+) -> Result<Selection> {
+    let names: Vec<&str> = providers().iter().map(|p| p.display_name()).collect();
+    let distro = choose_or_preset("Select Distro", query.distro_name(), names)?;
+    let provider = repositories::provider(&distro)
+        .ok_or_else(|| CloudImagesError::Resolution(format!("Unsupported distro '{distro}'")))?;
+
+    let (release, image) = repositories::pick(provider, track, query, max_builds, cancel).await?;
+    Ok(Selection {
+        distro,
+        release: release.id.clone(),
+        arch: image.arch_name().to_string(),
+        version: provider.version_label(&release, &image),
+        image,
+    })
+}
+
+/// Resolve `query` to an image from the [`DEFAULT_TRACK`] without
+/// prompting. A query matching no image or several of them is an error;
+/// [`select`] asks the user to pick instead.
+pub async fn resolve(query: &ImageQuery) -> Result<Image> {
+    let mut images = find(query).await?;
+    match images.len() {
+        1 => Ok(images.remove(0)),
+        0 => Err(CloudImagesError::Resolution(
+            "no image matches the query".to_string(),
+        )),
+        n => Err(CloudImagesError::Resolution(format!(
+            "{n} images match the query ({}); narrow it down by build, variant or format",
+            images
+                .iter()
+                .take(AMBIGUOUS_SHOWN)
+                .map(|i| i.url().rsplit('/').next().unwrap_or(i.url()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))),
+    }
+}
+
+/// Every image of the [`DEFAULT_TRACK`] matching `query`, without prompting.
+/// The query must name the distro, release and architecture.
+pub async fn find(query: &ImageQuery) -> Result<Vec<Image>> {
+    let distro = query
+        .distro_name()
+        .ok_or_else(|| CloudImagesError::Resolution("the query names no distro".to_string()))?;
+    let provider = repositories::provider(distro)
+        .ok_or_else(|| CloudImagesError::Resolution(format!("Unsupported distro '{distro}'")))?;
+    Ok(provider
+        .query(helpers::http::client(), DEFAULT_TRACK, query)
+        .await?)
+}
+
+/// Download `image` into `dest_dir` and verify it against its published
+/// checksum. Without mirrors in `options` the ones configured for the
+/// image's repository are used; [`DownloadOptions::cancel`] aborts it and
+/// removes its partial files. Returns a summary of what was done.
+pub async fn download(image: &Image, dest_dir: &Path, options: &DownloadOptions) -> Result<String> {
+    let mirrors = if options.mirrors.is_empty() {
+        repositories::mirror_urls(image.url())
+    } else {
+        options.mirrors.clone()
+    };
+    let options = DownloadOptions {
+        mirrors,
+        ..options.clone()
+    };
+    image_resolver::download_file(image, dest_dir, &options).await
+}
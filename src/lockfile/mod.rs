@@ -0,0 +1,166 @@
+use std::{fs, path::Path};
+
+use anyhow::{Context, Result, ensure};
+use serde::{Deserialize, Serialize};
+
+use cloud_images_downloader::{Image, ImageQuery, Selection};
+
+/// Lockfile written by `lock` when no other path is given.
+pub const DEFAULT_LOCKFILE: &str = "cloud-images.lock";
+
+/// Version of the lockfile layout, bumped on incompatible changes.
+const FORMAT_VERSION: u32 = 1;
+
+/// Exact artifacts pinned by `lock`, downloaded again by `install --locked`:
+///
+/// ```toml
+/// version = 1
+///
+/// [[image]]
+/// distro = "Debian"
+/// release = "bookworm"
+/// id = "3f6c..."
+/// os = "debian"
+/// version = "20250210-2019"
+/// url = "https://cloud.debian.org/images/cloud/bookworm/20250210-2019/debian-12-genericcloud-amd64-20250210-2019.qcow2"
+/// # ... the remaining fields of `--json`, including the checksum
+/// ```
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Lockfile {
+    version: u32,
+    #[serde(rename = "image", default)]
+    images: Vec<LockedImage>,
+}
+
+/// One pinned image plus what is needed to look it up upstream again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockedImage {
+    /// Provider the image was resolved with.
+    pub distro: String,
+    /// Release identifier understood by the provider.
+    pub release: String,
+    #[serde(flatten)]
+    pub image: Image,
+}
+
+impl LockedImage {
+    pub fn new(selection: &Selection) -> Self {
+        Self {
+            distro: selection.distro.clone(),
+            release: selection.release.clone(),
+            image: selection.image.clone(),
+        }
+    }
+
+    /// Query listing the pinned build upstream, for comparing checksums.
+    pub fn query(&self) -> ImageQuery {
+        ImageQuery::new()
+            .distro(&self.distro)
+            .release(&self.release)
+            .arch(self.image.arch())
+            .build(self.image.version())
+    }
+}
+
+impl Lockfile {
+    pub fn new(images: Vec<LockedImage>) -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            images,
+        }
+    }
+
+    pub fn images(&self) -> &[LockedImage] {
+        &self.images
+    }
+
+    /// Parse a lockfile.
+    pub fn parse(data: &str) -> Result<Self> {
+        let lockfile: Lockfile = toml::from_str(data)?;
+        ensure!(
+            lockfile.version == FORMAT_VERSION,
+            "unsupported lockfile version {} (expected {FORMAT_VERSION})",
+            lockfile.version
+        );
+        Ok(lockfile)
+    }
+
+    /// Read and parse the lockfile at `path`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = fs::read_to_string(path)
+            .with_context(|| format!("read lockfile {}", path.display()))?;
+        Self::parse(&data).with_context(|| format!("parse lockfile {}", path.display()))
+    }
+
+    /// Write the lockfile to `path`.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let data = toml::to_string_pretty(self).context("serialize lockfile")?;
+        let data = format!("# Written by `cloud-images-downloader lock`; do not edit.\n{data}");
+        fs::write(path, data).with_context(|| format!("write lockfile {}", path.display()))
+    }
+}
+
+/// Why the upstream `current` listing no longer matches `locked`, if it
+/// does not: the artifact is gone, or its published checksum changed.
+pub fn drift(locked: &LockedImage, current: &[Image]) -> Option<String> {
+    let pinned = &locked.image;
+    let Some(upstream) = current.iter().find(|i| i.url() == pinned.url()) else {
+        return Some(format!("{} is no longer published", pinned.url()));
+    };
+    let expected = pinned.checksum()?;
+    match upstream
+        .checksums()
+        .iter()
+        .find(|c| c.kind() == expected.kind())
+    {
+        Some(actual) if actual.value().eq_ignore_ascii_case(expected.value()) => None,
+        Some(actual) => Some(format!(
+            "the {} of {} changed upstream: locked {}, now {}",
+            expected.kind(),
+            pinned.url(),
+            expected.value(),
+            actual.value()
+        )),
+        None => Some(format!(
+            "{} no longer has a published {} checksum",
+            pinned.url(),
+            expected.kind()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LockedImage, Lockfile, drift};
+    use cloud_images_downloader::Image;
+    use cloud_images_downloader::cloud::{ChecksumKind, ImageChecksum, sample_image};
+
+    fn image(sha256: &str) -> Image {
+        sample_image()
+            .with_url("https://example.invalid/debian-12-genericcloud-amd64-20250210-2019.qcow2")
+            .with_checksums([ImageChecksum::new(ChecksumKind::Sha256, sha256)])
+    }
+
+    #[test]
+    fn round_trips_and_detects_drift() {
+        let locked = LockedImage {
+            distro: "Debian".to_string(),
+            release: "bookworm".to_string(),
+            image: image("ab12"),
+        };
+        let data = toml::to_string_pretty(&Lockfile::new(vec![locked.clone()])).unwrap();
+        let parsed = Lockfile::parse(&data).unwrap();
+        assert_eq!(parsed.images()[0].image, locked.image);
+        assert_eq!(parsed.images()[0].query().build_id(), Some("20250210-2019"));
+
+        assert!(drift(&locked, &[image("AB12")]).is_none());
+        assert!(
+            drift(&locked, &[image("cd34")])
+                .unwrap()
+                .contains("changed")
+        );
+        assert!(drift(&locked, &[]).unwrap().contains("no longer published"));
+        assert!(Lockfile::parse("version = 2\n").is_err());
+    }
+}
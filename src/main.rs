@@ -1,103 +1,206 @@
-mod cloud;
-mod helpers;
-mod repositories;
-
-use anyhow::{Result, bail};
-use std::{env, path::PathBuf};
-
-use helpers::{choose_one, image_resolver::download_file};
-use repositories::{self as repos, almalinux, debian, ubuntu};
-
-use cloud::Image;
-
-/// Resolve the absolute path to the bundled `indexes.json` file that contains
-/// the repository metadata used by the pickers.
-fn construct_properties_file_path() -> PathBuf {
-    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-        .join("resources")
-        .join("indexes.json")
-}
-
-/// A tiny wrapper to render the final selection cleanly
-fn print_selection(distro: &str, arch: &str, version: &str, image: &Image) {
-    // If your Image implements getters, use them here
-    println!("\n=== Selection ===");
-    println!("Distro:   {distro}");
-    println!("Arch:     {arch}");
-    println!("Version:  {version}");
-    println!("Image:");
-    println!("  name:        {}", image.name());
-    println!("  distro ver:  {}", image.distro_version());
-    println!("  version:     {}", image.version());
-    println!("  type:        {}", image.image_type());
-    println!("  arch:        {}", image.arch());
-    println!("  url:         {}", image.url());
-    if let Some(checksum) = image.checksum() {
-        println!("  checksum:    {} ({})", checksum.value(), checksum.kind());
-    } else {
-        println!("  checksum:    <none>");
+mod cli;
+mod commands;
+mod config;
+mod lockfile;
+mod manifest;
+mod queue;
+
+use anyhow::Result;
+use clap::Parser;
+use reqwest::header::{AUTHORIZATION, HeaderMap, HeaderValue};
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::Duration,
+};
+
+use cli::{Cli, Command, MirrorsCommand};
+use commands::{deploy, download, index, library, mirror, schedule, serve};
+use config::Config;
+
+use cloud_images_downloader::{
+    CloudImagesError, DEFAULT_TRACK,
+    helpers::{
+        ansible, fixtures,
+        gpg::{self, SignaturePolicy},
+        http::{self, HttpSettings},
+        http_cache, paths,
+        retry::{self, RetryPolicy},
+        trace, trust,
+    },
+    repositories as repos,
+};
+
+/// Assemble the shared HTTP client settings from the flags and the config
+/// file (flags win; headers from both are combined).
+fn http_settings(cli: &Cli, config: &Config) -> Result<HttpSettings> {
+    let defaults = HttpSettings::default();
+
+    let mut headers = HeaderMap::new();
+    for header in config.headers().iter().chain(&cli.headers) {
+        let (name, value) = http::parse_header(header).map_err(anyhow::Error::msg)?;
+        headers.insert(name, value);
     }
-}
 
-/// Full 3-step wizard: distro -> arch -> version -> image
-/// Ask the user to progressively narrow down their choice and return the final
-/// image selection.
-///
-/// The function keeps the prompts generic so they can be reused for the
-/// different distros supported by the tool while still returning a uniform
-/// structure that the caller can work with.
-async fn prompt_and_select(track: &str) -> Result<(String, String, String, Image)> {
-    // 0) Distro
-    let distro = choose_one("Select Distro", vec!["Ubuntu", "Debian", "AlmaLinux"])?;
+    Ok(HttpSettings {
+        user_agent: cli
+            .user_agent
+            .clone()
+            .or(config.user_agent().map(str::to_string))
+            .unwrap_or(defaults.user_agent.clone()),
+        proxy: cli.proxy.clone().or(config.proxy().map(str::to_string)),
+        connect_timeout: cli
+            .connect_timeout
+            .or(config.connect_timeout())
+            .map_or(defaults.connect_timeout, Duration::from_secs),
+        read_timeout: cli
+            .read_timeout
+            .or(config.read_timeout())
+            .map_or(defaults.read_timeout, Duration::from_secs),
+        timeout: cli.timeout.or(config.timeout()).map(Duration::from_secs),
+        max_redirects: cli
+            .max_redirects
+            .or(config.max_redirects())
+            .unwrap_or(defaults.max_redirects),
+        headers,
+        ..defaults
+    })
+}
 
-    match distro.as_str() {
-        "Ubuntu" => {
-            // pick_ubuntu also asks for arch + version internally
-            let img = ubuntu::pick_ubuntu(track).await?;
-            let arch = img.arch().to_string();
-            let version = img.version().to_string();
-            Ok((distro, arch, version, img))
-        }
-        "Debian" => {
-            let (codename, img) = debian::pick_debian_interactive().await?;
-            let arch = img.arch().to_string();
-            let version = format!("{codename} ({})", img.version());
-            Ok((distro, arch, version, img))
+/// Register the per-repository headers and credentials from `indexes.json`
+/// with the HTTP layer.
+fn configure_repository_headers() -> Result<()> {
+    let mut scoped = Vec::new();
+    for (root, repo) in repos::roots_with_headers()? {
+        let mut map = HeaderMap::new();
+        for (name, value) in repo.headers() {
+            let (name, value) =
+                http::parse_header(&format!("{name}: {value}")).map_err(anyhow::Error::msg)?;
+            map.insert(name, value);
         }
-        "AlmaLinux" => {
-            let img = almalinux::pick_almalinux(track).await?;
-            let arch = img.arch().to_string();
-            let version = img.version().to_string();
-            Ok((distro, arch, version, img))
+        if let Some(auth) = repo.auth() {
+            let mut value = HeaderValue::from_str(&auth.header_value()?)?;
+            value.set_sensitive(true);
+            map.insert(AUTHORIZATION, value);
         }
-        _ => bail!("Unsupported distro '{distro}'",),
+        scoped.push((root, map));
     }
+    http::configure_scoped_headers(scoped).map_err(anyhow::Error::msg)
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    let path = construct_properties_file_path();
-    repos::init_from_file(&path)?; // stays sync
-
-    // Get repos info from json by name
-    // let repo = repos::by_name("ubuntu").unwrap();
-
-    // You can toggle "daily" here if you want (already in your comments)
-    let track = "releases";
-
-    let (distro, arch, version, image) = prompt_and_select(track).await?;
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let ansible_output = cli.ansible_output();
+    match run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {err:?}");
+            // Typed library errors get a status per kind, anything else 1.
+            let code = err
+                .chain()
+                .find_map(|cause| cause.downcast_ref::<CloudImagesError>())
+                .map_or(1, CloudImagesError::exit_code);
+            if ansible_output {
+                println!("{}", ansible::failure(&format!("{err:#}"), code));
+            }
+            ExitCode::from(code)
+        }
+    }
+}
 
-    println!("{image:?}");
+async fn run(cli: Cli) -> Result<()> {
+    let config = Config::load()?;
+    if let Some(dir) = cli.cache_dir.clone().or(config.cache_dir()) {
+        paths::set_cache_dir(dir);
+    }
 
-    // Print the chosen structure (clean summary)
-    print_selection(&distro, &arch, &version, &image);
+    http::configure(&http_settings(&cli, &config)?)?;
+    if cli.explain.is_some() {
+        trace::enable();
+    }
+    if let Some(dir) = &cli.record {
+        fixtures::configure(fixtures::Mode::Record(dir.clone()));
+    } else if let Some(dir) = &cli.replay {
+        fixtures::configure(fixtures::Mode::Replay(dir.clone()));
+    }
+    if cli.offline {
+        http::set_offline();
+    }
 
-    let output = download_file(image.url()).await;
+    if let Some(attempts) = cli.retries.or(config.retries()) {
+        retry::configure(RetryPolicy {
+            attempts: attempts.max(1),
+            ..RetryPolicy::default()
+        });
+    }
 
-    match output {
-        Ok(msg) => println!("{msg}"),
-        Err(err) => eprintln!("{err}"),
+    gpg::configure(SignaturePolicy {
+        keyrings: cli
+            .keyrings
+            .iter()
+            .chain(config.keyrings())
+            .cloned()
+            .collect(),
+        require: cli.require_signature || config.require_signature(),
+    });
+    if cli.trust_store || config.trust_store() {
+        trust::enable();
     }
 
-    Ok(())
+    let user_indexes = Config::indexes_path().filter(|path| path.exists());
+    let overrides: Vec<&Path> = user_indexes
+        .iter()
+        .chain(&cli.indexes)
+        .map(PathBuf::as_path)
+        .collect();
+    repos::init_layered(&overrides, config.mirrors())?; // stays sync
+    configure_repository_headers()?;
+    http_cache::configure_refresh(repos::refresh_rules(config.refresh())?);
+
+    match &cli.command {
+        Some(Command::Mirrors {
+            action: MirrorsCommand::Bench { repo, save },
+        }) => mirror::bench(repo.as_deref(), *save).await,
+        Some(Command::Verify { images }) => download::verify(images).await,
+        Some(Command::Library { action }) => library::manage(action, &config),
+        Some(Command::Cache { action }) => library::manage_cache(action, &config),
+        Some(Command::Index { action }) => {
+            index::manage(action, cli.distro.as_deref(), DEFAULT_TRACK).await
+        }
+        Some(Command::Search { terms, remote }) => {
+            index::search(&cli, terms, *remote, DEFAULT_TRACK).await
+        }
+        Some(Command::List { remote }) => index::list(&cli, *remote, &config).await,
+        Some(Command::ServeFiles { dir, listen }) => serve::files(dir.as_deref(), listen).await,
+        Some(Command::Ignition { butane, output }) => deploy::ignition(butane, output),
+        Some(Command::Seed { user_data, output }) => deploy::seed(&cli, user_data, output),
+        Some(Command::Resume) => download::resume(&cli, &config).await,
+        Some(Command::Install {
+            locked: true,
+            lockfile,
+        }) => download::install_locked(&cli, &config, lockfile).await,
+        Some(Command::Lock { lockfile }) => download::lock(&cli, &config, lockfile, false).await,
+        Some(Command::Install { lockfile, .. }) => {
+            download::lock(&cli, &config, lockfile, true).await
+        }
+        Some(Command::Schedule { action }) => schedule::run(&cli, &config, action).await,
+        Some(Command::Serve { listen, recheck }) => {
+            serve::api(&cli, &config, listen, *recheck).await
+        }
+        Some(Command::Mirror(args)) => {
+            mirror::sync(&cli, args, &download::Downloads::new(&cli, &config)?).await
+        }
+        Some(
+            Command::Run { .. }
+            | Command::Libvirt { .. }
+            | Command::Proxmox { .. }
+            | Command::Incus { .. }
+            | Command::Openstack { .. }
+            | Command::Push { .. }
+            | Command::Export { .. }
+            | Command::Containerdisk { .. },
+        )
+        | None => download::fetch(&cli, &config).await,
+    }
 }
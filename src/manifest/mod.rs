@@ -0,0 +1,140 @@
+use std::{fs, path::Path};
+
+use anyhow::{Context, Result, ensure};
+use serde::Deserialize;
+
+use cloud_images_downloader::cloud::Arch;
+use cloud_images_downloader::helpers::customize::Customization;
+use cloud_images_downloader::repositories::ImageQuery;
+
+/// Batch file listing several images to download in one run:
+///
+/// ```toml
+/// [[image]]
+/// distro = "ubuntu"
+/// release = "24.04"
+/// arch = "amd64"
+///
+/// [[image]]
+/// distro = "debian"
+/// release = "bookworm"
+/// arch = "arm64"
+/// format = "qcow2"
+///
+/// [hooks]
+/// install = ["qemu-guest-agent"]
+/// ```
+///
+/// The optional `[hooks]` table customizes every image, see
+/// [`Customization`].
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    #[serde(rename = "image", default)]
+    images: Vec<Entry>,
+    #[serde(default)]
+    hooks: Customization,
+}
+
+/// The images of a manifest and the changes applied to each of them.
+#[derive(Debug)]
+pub struct Batch {
+    pub queries: Vec<ImageQuery>,
+    pub hooks: Customization,
+}
+
+/// One image; the keys mirror the selection flags of the command line and
+/// missing ones are prompted for.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+    distro: Option<String>,
+    release: Option<String>,
+    arch: Option<Arch>,
+    build: Option<String>,
+    variant: Option<String>,
+    format: Option<String>,
+}
+
+impl From<Entry> for ImageQuery {
+    fn from(entry: Entry) -> Self {
+        let mut query = ImageQuery::new();
+        if let Some(distro) = entry.distro {
+            query = query.distro(distro);
+        }
+        if let Some(release) = entry.release {
+            query = query.release(release);
+        }
+        if let Some(arch) = entry.arch {
+            query = query.arch(arch);
+        }
+        if let Some(build) = entry.build {
+            query = query.build(build);
+        }
+        if let Some(variant) = &entry.variant {
+            query = query.variant(variant);
+        }
+        if let Some(format) = &entry.format {
+            query = query.format(format);
+        }
+        query
+    }
+}
+
+/// Parse a manifest into one `ImageQuery` per listed image.
+pub fn parse(data: &str) -> Result<Batch> {
+    let manifest: Manifest = toml::from_str(data)?;
+    ensure!(
+        !manifest.images.is_empty(),
+        "manifest lists no [[image]] entries"
+    );
+    Ok(Batch {
+        queries: manifest.images.into_iter().map(ImageQuery::from).collect(),
+        hooks: manifest.hooks,
+    })
+}
+
+/// Read and parse the manifest at `path`.
+pub fn load(path: &Path) -> Result<Batch> {
+    let data =
+        fs::read_to_string(path).with_context(|| format!("read manifest {}", path.display()))?;
+    parse(&data).with_context(|| format!("parse manifest {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+    use cloud_images_downloader::cloud::{Arch, ImageFormat};
+
+    #[test]
+    fn maps_entries_to_requests() {
+        let batch = parse(
+            r#"
+            [[image]]
+            distro = "ubuntu"
+            release = "24.04"
+
+            [[image]]
+            distro = "debian"
+            arch = "arm64"
+            format = "qcow2"
+
+            [hooks]
+            install = ["htop"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(batch.hooks.install, ["htop"]);
+        let requests = batch.queries;
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].release_id(), Some("24.04"));
+        assert_eq!(requests[1].arch_value(), Some(Arch::Arm64));
+        assert_eq!(requests[1].format_value(), Some(&ImageFormat::Qcow2));
+    }
+
+    #[test]
+    fn rejects_empty_and_unknown_keys() {
+        assert!(parse("").is_err());
+        assert!(parse("[[image]]\ncolour = \"blue\"\n").is_err());
+    }
+}
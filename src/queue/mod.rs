@@ -0,0 +1,272 @@
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use cloud_images_downloader::cloud::{Arch, ChecksumKind, Image, ImageChecksum, Variant};
+use cloud_images_downloader::helpers::image_resolver::BatchItem;
+use cloud_images_downloader::helpers::paths;
+
+const QUEUE_FILE: &str = "queue.json";
+
+/// Downloads that were started but have not completed yet, kept in
+/// `$XDG_STATE_HOME/cloud-images-downloader/queue.json` (or the platform
+/// equivalent) so `resume` can finish them after the process was killed.
+#[derive(Debug, Default)]
+pub struct Queue {
+    path: Option<PathBuf>,
+    entries: Vec<Entry>,
+}
+
+/// One pending download: the resolved image, where it goes and how it was
+/// being fetched.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    os: String,
+    name: String,
+    distro_version: String,
+    version: String,
+    arch: Arch,
+    url: String,
+    checksum: Option<String>,
+    checksum_kind: Option<String>,
+    /// Called `image_type` in queues written by earlier versions.
+    #[serde(alias = "image_type")]
+    variant: String,
+    dest_dir: PathBuf,
+    mirrors: Vec<String>,
+    /// Connections of the interrupted run; partial segments only line up
+    /// again with the same count.
+    pub connections: usize,
+}
+
+impl Entry {
+    pub fn new(item: &BatchItem, connections: usize) -> Self {
+        let image = &item.image;
+        Self {
+            os: image.os().to_string(),
+            name: image.name().to_string(),
+            distro_version: image.distro_version().to_string(),
+            version: image.version().to_string(),
+            arch: image.arch(),
+            url: image.url().to_string(),
+            checksum: image.checksum_value().map(str::to_string),
+            checksum_kind: image.checksum_kind().map(|k| k.as_str().to_string()),
+            variant: image.variant().to_string(),
+            dest_dir: item.dest_dir.clone(),
+            mirrors: item.mirrors.clone(),
+            connections,
+        }
+    }
+
+    /// Rebuild the batch item this entry was created from.
+    pub fn item(&self) -> BatchItem {
+        let checksum = self
+            .checksum
+            .as_ref()
+            .zip(
+                self.checksum_kind
+                    .as_deref()
+                    .and_then(ChecksumKind::from_name),
+            )
+            .map(|(value, kind)| ImageChecksum::new(kind, value.clone()));
+        BatchItem {
+            image: Image::new(
+                self.os.clone(),
+                self.name.clone(),
+                self.distro_version.clone(),
+                self.version.clone(),
+                self.arch,
+                self.url.clone(),
+                checksum,
+                Variant::from_name(&self.variant),
+            ),
+            dest_dir: self.dest_dir.clone(),
+            mirrors: self.mirrors.clone(),
+        }
+    }
+
+    fn is_same_download(&self, other: &Entry) -> bool {
+        self.url == other.url && self.dest_dir == other.dest_dir
+    }
+}
+
+impl Queue {
+    /// Location of the queue file, if the platform exposes a state or local
+    /// data directory.
+    pub fn path() -> Option<PathBuf> {
+        paths::state_dir().map(|dir| dir.join(QUEUE_FILE))
+    }
+
+    /// Load the pending downloads. A missing file yields an empty queue; an
+    /// unreadable one is moved aside with a warning so a corrupt queue never
+    /// blocks the other commands.
+    pub fn load() -> Result<Self> {
+        let Some(path) = Self::path() else {
+            return Ok(Self::default());
+        };
+        let entries = read_entries(&path);
+        Ok(Self {
+            path: Some(path),
+            entries,
+        })
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Record `entry` as pending, replacing an older record of the same
+    /// download.
+    pub fn add(&mut self, entry: Entry) -> Result<()> {
+        self.update(|entries| {
+            entries.retain(|e| !e.is_same_download(&entry));
+            entries.push(entry);
+        })
+    }
+
+    /// Forget `entry` once its download has completed.
+    pub fn remove(&mut self, entry: &Entry) -> Result<()> {
+        self.update(|entries| entries.retain(|e| !e.is_same_download(entry)))
+    }
+
+    /// Apply `change` to the queue file under an exclusive lock, starting
+    /// from what is on disk so entries other invocations recorded meanwhile
+    /// are kept.
+    fn update(&mut self, change: impl FnOnce(&mut Vec<Entry>)) -> Result<()> {
+        let Some(path) = &self.path else {
+            change(&mut self.entries);
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+        }
+        let lock_path = path.with_extension("json.lock");
+        let lock =
+            File::create(&lock_path).with_context(|| format!("open {}", lock_path.display()))?;
+        lock.lock()
+            .with_context(|| format!("lock {}", lock_path.display()))?;
+
+        self.entries = read_entries(path);
+        change(&mut self.entries);
+        save(path, &self.entries)
+    }
+}
+
+/// The entries of the queue file at `path`. A missing file is an empty
+/// queue; a corrupt one is renamed to `queue.json.corrupt` and reported.
+fn read_entries(path: &Path) -> Vec<Entry> {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Vec::new(),
+        Err(err) => {
+            eprintln!(
+                "Warning: cannot read the download queue {}: {err}",
+                path.display()
+            );
+            return Vec::new();
+        }
+    };
+    match serde_json::from_str(&data) {
+        Ok(entries) => entries,
+        Err(err) => {
+            let aside = path.with_extension("json.corrupt");
+            let _ = fs::rename(path, &aside);
+            eprintln!(
+                "Warning: the download queue {} is corrupt ({err}); moved it to {} and started \
+                 an empty one",
+                path.display(),
+                aside.display()
+            );
+            Vec::new()
+        }
+    }
+}
+
+/// Write `entries` to `path` through a temporary file renamed over it, so
+/// readers never see a partial queue; an empty queue removes the file.
+fn save(path: &Path, entries: &[Entry]) -> Result<()> {
+    if entries.is_empty() {
+        if path.exists() {
+            fs::remove_file(path).with_context(|| format!("remove {}", path.display()))?;
+        }
+        return Ok(());
+    }
+
+    let tmp = path.with_extension(format!("json.{}.tmp", std::process::id()));
+    fs::write(&tmp, serde_json::to_string_pretty(entries)?)
+        .with_context(|| format!("write queue {}", tmp.display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("write queue {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Entry, Queue, read_entries, save};
+    use cloud_images_downloader::cloud::{ChecksumKind, ImageChecksum, sample_image};
+    use cloud_images_downloader::helpers::image_resolver::BatchItem;
+    use std::path::PathBuf;
+
+    fn item(url: &str) -> BatchItem {
+        BatchItem {
+            image: sample_image()
+                .with_url(url)
+                .with_checksums([ImageChecksum::new(ChecksumKind::Sha256, "abc")]),
+            dest_dir: PathBuf::from("/tmp/images"),
+            mirrors: vec![url.to_string()],
+        }
+    }
+
+    #[test]
+    fn entries_round_trip_through_json() {
+        let entry = Entry::new(&item("https://example.invalid/a.img"), 4);
+        let json = serde_json::to_string(&entry).unwrap();
+        let parsed: Entry = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, entry);
+
+        let rebuilt = parsed.item();
+        assert_eq!(rebuilt.image.url(), "https://example.invalid/a.img");
+        assert_eq!(rebuilt.image.checksum_kind(), Some(ChecksumKind::Sha256));
+        assert_eq!(rebuilt.image.checksum_value(), Some("abc"));
+        assert_eq!(rebuilt.dest_dir, PathBuf::from("/tmp/images"));
+    }
+
+    #[test]
+    fn corrupt_queue_is_moved_aside() {
+        let dir = std::env::temp_dir().join(format!("cid-queue-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("queue.json");
+        std::fs::write(&path, "[{\"os\": ").unwrap();
+
+        assert!(read_entries(&path).is_empty());
+        assert!(!path.exists());
+        assert!(dir.join("queue.json.corrupt").exists());
+
+        let entry = Entry::new(&item("https://example.invalid/a.img"), 1);
+        save(&path, std::slice::from_ref(&entry)).unwrap();
+        assert_eq!(read_entries(&path), vec![entry]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn add_replaces_and_remove_forgets_downloads() {
+        let mut queue = Queue::default();
+        queue
+            .add(Entry::new(&item("https://example.invalid/a.img"), 1))
+            .unwrap();
+        queue
+            .add(Entry::new(&item("https://example.invalid/b.img"), 1))
+            .unwrap();
+        queue
+            .add(Entry::new(&item("https://example.invalid/a.img"), 4))
+            .unwrap();
+        assert_eq!(queue.entries().len(), 2);
+        assert_eq!(queue.entries()[1].connections, 4);
+
+        let done = queue.entries()[0].clone();
+        queue.remove(&done).unwrap();
+        assert_eq!(queue.entries().len(), 1);
+    }
+}
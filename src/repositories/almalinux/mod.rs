@@ -1,26 +1,21 @@
-use std::sync::OnceLock;
+use std::sync::{Arc, OnceLock};
 
-use anyhow::{Context, Result};
-use anyhow::{bail, ensure};
+use anyhow::{Context, Result, bail, ensure};
+use async_trait::async_trait;
 use regex::Regex;
 use reqwest::Client;
 
-use crate::cloud::{ChecksumKind, Image, ImageChecksum};
-use crate::helpers::{arch_options_for, choose_one};
-use crate::repositories;
+use crate::cloud::{Arch, ArchNaming, BuildId, Image, Variant, VersionKey, sort_newest_first};
+use crate::helpers::{
+    checksum::{ChecksumEntry, ChecksumSource},
+    gpg::Signing,
+    listing, sanitize, trace,
+};
+use crate::repositories::{self, ImageProvider, Release};
 
 const DEFAULT_MAJORS: &[&str] = &["9", "8"];
 const CHECKSUM_FILENAME: &str = "CHECKSUM";
 
-/// Lazily build the regex that parses lines from the `CHECKSUM` file.
-fn checksum_line_regex() -> &'static Regex {
-    static LINE_RE: OnceLock<Regex> = OnceLock::new();
-    LINE_RE.get_or_init(|| {
-        Regex::new(r"^(?P<sha>[A-Fa-f0-9]{64})\s{2}(?P<file>AlmaLinux-[^\s]+)$")
-            .expect("invalid AlmaLinux checksum line regex")
-    })
-}
-
 /// Lazily build the regex that extracts metadata from artifact filenames.
 fn filename_regex() -> &'static Regex {
     static FILE_RE: OnceLock<Regex> = OnceLock::new();
@@ -96,7 +91,7 @@ fn parse_artifact_filename(filename: &str, expected_arch: &str) -> Option<AlmaAr
 
 /// Return the configured AlmaLinux repository definition or bubble up a
 /// descriptive error when it is missing.
-fn repository_config() -> Result<&'static repositories::Repository> {
+fn repository_config() -> Result<Arc<repositories::Repository>> {
     repositories::by_name("almalinux")
         .map_err(anyhow::Error::new)?
         .context("repository 'almalinux' is not configured")
@@ -104,9 +99,9 @@ fn repository_config() -> Result<&'static repositories::Repository> {
 
 /// Construct the base URL used to fetch artifacts for a specific major release
 /// and architecture.
-fn repository_base_url(major: &str, arch: &str) -> Result<String> {
+async fn repository_base_url(client: &Client, major: &str, arch: &str) -> Result<String> {
     let repo = repository_config()?;
-    let template = repo.url();
+    let template = repositories::resolve(client, &repo, repo.url()).await;
 
     let replaced_major = template.replacen("{}", major, 1);
     ensure!(
@@ -124,17 +119,16 @@ fn repository_base_url(major: &str, arch: &str) -> Result<String> {
 }
 
 /// Compute the root URL that lists all available major versions.
-fn majors_root_url() -> Result<String> {
+async fn majors_root_url(client: &Client) -> Result<String> {
     let repo = repository_config()?;
-    if let Some(params) = repo.other_parameters() {
-        if let Some(root) = params.get("majors_root") {
-            return Ok(root.clone());
-        }
+    if let Some(params) = repo.other_parameters()
+        && let Some(root) = params.get("majors_root")
+    {
+        return Ok(repositories::resolve(client, &repo, root).await);
     }
 
-    let template = repo.url();
-    if let Some((prefix, _)) = template.split_once("{}") {
-        return Ok(prefix.to_string());
+    if repo.url().contains("{}") {
+        return Ok(repositories::active_root(client, &repo).await);
     }
 
     bail!("unable to determine AlmaLinux majors root from repository config")
@@ -142,36 +136,27 @@ fn majors_root_url() -> Result<String> {
 
 /// Scrape the upstream directory listing to discover the major versions that
 /// currently expose cloud images.
-async fn fetch_major_versions() -> Result<Vec<String>> {
-    let root = majors_root_url()?;
-    let client = Client::new();
-
-    let html = client
-        .get(&root)
-        .send()
-        .await?
-        .error_for_status()?
-        .text()
+async fn fetch_major_versions(client: &Client) -> Result<Vec<String>> {
+    let root = majors_root_url(client).await?;
+
+    let entries = listing::fetch(client, &root)
         .await
-        .with_context(|| format!("fetch AlmaLinux directory listing from {root}"))?;
+        .context("fetch AlmaLinux directory listing")?;
 
-    let dir_re = Regex::new(r#"href=['"](?:[^"']*/)?(\d+)/['"]"#)?;
-    let mut majors: Vec<String> = dir_re
-        .captures_iter(&html)
-        .map(|cap| cap[1].to_string())
+    let mut majors: Vec<String> = listing::subdirs(&entries)
+        .into_iter()
+        .filter(|name| !name.is_empty() && name.bytes().all(|b| b.is_ascii_digit()))
         .collect();
 
-    majors.sort_by_cached_key(|e| e.parse::<i32>().unwrap());
-    majors.dedup();
-    majors.reverse();
+    sort_newest_first(&mut majors);
 
     Ok(majors)
 }
 
 /// Return the list of major versions, defaulting to a curated set when the
 /// remote lookup fails.
-pub async fn available_majors() -> Result<Vec<String>> {
-    match fetch_major_versions().await {
+pub async fn available_majors(client: &Client) -> Result<Vec<String>> {
+    match fetch_major_versions(client).await {
         Ok(list) if !list.is_empty() => Ok(list),
         _ => Ok(DEFAULT_MAJORS.iter().map(|s| s.to_string()).collect()),
     }
@@ -179,154 +164,119 @@ pub async fn available_majors() -> Result<Vec<String>> {
 
 /// Convert a parsed `AlmaArtifact` into the shared `Image` structure used by
 /// the higher level code.
-fn make_image(base_url: &str, artifact: AlmaArtifact, checksum: ImageChecksum) -> Image {
-    let url = format!("{base_url}{}", artifact.filename);
+fn make_image(url: String, artifact: AlmaArtifact, arch: Arch, entry: ChecksumEntry) -> Image {
     Image::from_parts(
         "almalinux".to_string(),
-        artifact.variant,
+        format!("AlmaLinux {}", artifact.major),
         artifact.distro_version,
         artifact.image_version,
-        artifact.arch,
+        arch,
         url,
-        Some(checksum),
-        artifact.format,
+        Some(entry.checksum),
+        Variant::from_name(&artifact.variant),
     )
+    .with_size(entry.size)
 }
 
 /// Enumerate all AlmaLinux cloud images available for the specified major
 /// version and architecture by parsing the upstream `CHECKSUM` manifest.
-pub async fn almalinux_list(major: &str, arch: &str) -> Result<Vec<Image>> {
-    let base = repository_base_url(major, arch)?;
+pub async fn almalinux_list(client: &Client, major: &str, arch: Arch) -> Result<Vec<Image>> {
+    let arch_name = arch.name(ArchNaming::Rpm);
+    let base = repository_base_url(client, major, arch_name).await?;
     let checksum_url = format!("{base}{CHECKSUM_FILENAME}");
-    let client = Client::new();
-
-    let checksum_body = client
-        .get(&checksum_url)
-        .send()
-        .await?
-        .error_for_status()?
-        .text()
+
+    let entries = checksum_source(checksum_url.clone())
+        .entries(client, &checksum_url)
         .await
+        .map_err(anyhow::Error::msg)?
         .with_context(|| format!("fetch AlmaLinux checksum list from {checksum_url}"))?;
 
     let mut images = Vec::new();
 
-    for line in checksum_body.lines() {
-        let trimmed = line.trim();
-        if trimmed.is_empty() {
+    for entry in entries {
+        let Some(artifact) = parse_artifact_filename(&entry.file, arch_name) else {
+            trace::excluded("arch", &entry.file, || {
+                format!("not a {arch_name} cloud image")
+            });
             continue;
-        }
-        let caps = match checksum_line_regex().captures(trimmed) {
-            Some(caps) => caps,
-            None => continue,
         };
-
-        let filename = caps.name("file").unwrap().as_str();
-        let sha = caps.name("sha").unwrap().as_str();
-
-        if let Some(artifact) = parse_artifact_filename(filename, arch) {
-            let checksum = ImageChecksum::new(ChecksumKind::Sha256, sha);
-            images.push(make_image(&base, artifact, checksum));
+        trace::kept("arch");
+        match sanitize::artifact_url(&base, &artifact.filename) {
+            Ok(url) => images.push(make_image(url, artifact, arch, entry)),
+            Err(err) => eprintln!("Warning: {err}"),
         }
     }
 
     images.sort_by(|a, b| {
-        b.distro_version()
-            .cmp(a.distro_version())
-            .then_with(|| b.version().cmp(a.version()))
-            .then_with(|| a.name().cmp(b.name()))
-            .then_with(|| a.image_type().cmp(b.image_type()))
+        VersionKey::new(b.distro_version())
+            .cmp(&VersionKey::new(a.distro_version()))
+            .then_with(|| BuildId::new(b.version()).cmp(&BuildId::new(a.version())))
+            .then_with(|| a.variant().as_str().cmp(b.variant().as_str()))
+            .then_with(|| a.format().as_str().cmp(b.format().as_str()))
     });
 
     Ok(images)
 }
 
-/// Multi-step AlmaLinux picker mirroring the flow implemented for Ubuntu and
-/// Debian.
-pub async fn pick_almalinux(_track: &str) -> Result<Image> {
-    let arch = choose_one("Select Architecture", arch_options_for("AlmaLinux"))?;
-
-    let majors = available_majors().await?;
-    ensure!(!majors.is_empty(), "No AlmaLinux major versions available");
-    let major = choose_one("Select AlmaLinux Major Version", majors)?;
-
-    let mut images = almalinux_list(&major, &arch).await?;
-    ensure!(
-        !images.is_empty(),
-        "No AlmaLinux images found for major={major} arch={arch}"
-    );
-
-    let mut distro_versions: Vec<String> = images
-        .iter()
-        .map(|i| i.distro_version().to_string())
-        .collect();
-    distro_versions.sort();
-    distro_versions.reverse();
-    distro_versions.dedup();
-
-    let distro_version = choose_one("Select Distro Version", distro_versions)?;
-    images.retain(|i| i.distro_version() == distro_version);
-    ensure!(
-        !images.is_empty(),
-        "No AlmaLinux images found for distro_version={distro_version}"
-    );
-
-    let mut image_versions: Vec<String> = images.iter().map(|i| i.version().to_string()).collect();
-    image_versions.sort();
-    image_versions.reverse();
-    image_versions.dedup();
-
-    let image_version = choose_one("Select Image Version", image_versions)?;
-    images.retain(|i| i.version() == image_version);
-    ensure!(
-        !images.is_empty(),
-        "No AlmaLinux images found for distro_version={distro_version} version={image_version}"
-    );
+fn checksum_source(url: String) -> ChecksumSource {
+    ChecksumSource::Combined {
+        url,
+        repo: "almalinux",
+        signing: Some(Signing::Clearsigned),
+    }
+}
 
-    let mut variants: Vec<String> = images.iter().map(|i| i.name().to_string()).collect();
-    variants.sort();
-    variants.dedup();
+/// AlmaLinux images listed in the `CHECKSUM` file of each major version.
+pub struct AlmaLinuxProvider;
 
-    let variant = choose_one("Select Image Variant", variants)?;
-    images.retain(|i| i.name() == variant);
-    ensure!(
-        !images.is_empty(),
-        "No AlmaLinux images found for distro_version={distro_version}, version={image_version}, variant={variant}"
-    );
+#[async_trait]
+impl ImageProvider for AlmaLinuxProvider {
+    fn display_name(&self) -> &'static str {
+        "AlmaLinux"
+    }
 
-    let mut formats: Vec<String> = images.iter().map(|i| i.image_type().to_string()).collect();
-    formats.sort();
-    formats.dedup();
+    fn supported_arches(&self) -> Vec<Arch> {
+        vec![Arch::Amd64, Arch::Arm64]
+    }
 
-    let format = choose_one("Select Image Format", formats)?;
-    images.retain(|i| i.image_type() == format);
-    ensure!(
-        !images.is_empty(),
-        "No AlmaLinux images found for distro_version={distro_version}, version={image_version}, variant={variant}, format={format}"
-    );
+    fn arch_naming(&self) -> ArchNaming {
+        ArchNaming::Rpm
+    }
 
-    let labelize = |i: &Image| {
-        format!(
-            "{} | {} | {} | {} | {}",
-            i.name(),
-            i.image_type(),
-            i.version(),
-            i.arch(),
-            i.url()
-        )
-    };
+    /// Major versions; the point release is picked from the listed images.
+    async fn releases(&self, client: &Client, _track: &str, _arch: Arch) -> Result<Vec<Release>> {
+        Ok(available_majors(client)
+            .await?
+            .into_iter()
+            .map(Release::new)
+            .collect())
+    }
 
-    let chosen_label = choose_one(
-        "Select Image Artifact",
-        images.iter().map(|i| labelize(i)).collect(),
-    )?;
+    async fn release(
+        &self,
+        _client: &Client,
+        _track: &str,
+        _arch: Arch,
+        id: &str,
+    ) -> Result<Release> {
+        Ok(Release::new(id))
+    }
 
-    let idx = images
-        .iter()
-        .position(|i| labelize(i) == chosen_label)
-        .expect("selected label must match one candidate");
+    async fn list(
+        &self,
+        client: &Client,
+        _track: &str,
+        release: &Release,
+        arch: Arch,
+    ) -> Result<Vec<Image>> {
+        almalinux_list(client, &release.id, arch).await
+    }
 
-    Ok(images[idx].clone())
+    /// The `CHECKSUM` file next to `image`.
+    fn checksum_source(&self, image: &Image) -> Option<ChecksumSource> {
+        let (dir, _) = image.url().rsplit_once('/')?;
+        Some(checksum_source(format!("{dir}/{CHECKSUM_FILENAME}")))
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,117 @@
+use std::cmp::Ordering;
+use std::time::{Duration, Instant};
+
+use reqwest::{Client, header::RANGE};
+
+use super::Repository;
+use crate::helpers::http;
+
+/// Upper bound for the probe downloaded from every mirror.
+pub const PROBE_BYTES: u64 = 256 * 1024;
+
+/// Measurement for a single repository root.
+#[derive(Debug, Clone)]
+pub struct MirrorScore {
+    pub root: String,
+    /// Time until the response headers arrived.
+    pub latency: Option<Duration>,
+    /// Bytes per second over the whole probe.
+    pub throughput: Option<f64>,
+    pub error: Option<String>,
+}
+
+impl MirrorScore {
+    /// Reachable mirrors first, fastest throughput first, then lowest latency.
+    fn rank(&self, other: &Self) -> Ordering {
+        match (self.throughput, other.throughput) {
+            (Some(a), Some(b)) => b
+                .partial_cmp(&a)
+                .unwrap_or(Ordering::Equal)
+                .then_with(|| self.latency.cmp(&other.latency)),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }
+    }
+}
+
+/// Fetch up to [`PROBE_BYTES`] from `root` and time it.
+async fn probe(client: &Client, root: &str) -> MirrorScore {
+    let mut score = MirrorScore {
+        root: root.to_string(),
+        latency: None,
+        throughput: None,
+        error: None,
+    };
+
+    let started = Instant::now();
+    let request = client
+        .get(root)
+        .header(RANGE, format!("bytes=0-{}", PROBE_BYTES - 1));
+    let mut res = match http::send(request)
+        .await
+        .and_then(|res| res.error_for_status())
+    {
+        Ok(res) => res,
+        Err(err) => {
+            score.error = Some(err.to_string());
+            return score;
+        }
+    };
+    score.latency = Some(started.elapsed());
+
+    // Servers that ignore `Range` send the whole body; stop at the probe size.
+    let mut received: u64 = 0;
+    while received < PROBE_BYTES {
+        match res.chunk().await {
+            Ok(Some(chunk)) => received += chunk.len() as u64,
+            Ok(None) => break,
+            Err(err) => {
+                score.error = Some(err.to_string());
+                return score;
+            }
+        }
+    }
+
+    let elapsed = started.elapsed().as_secs_f64().max(f64::EPSILON);
+    score.throughput = Some(received as f64 / elapsed);
+    score
+}
+
+/// Probe every root of `repo` one after the other (so they do not compete for
+/// bandwidth) and return them best first.
+pub async fn bench(client: &Client, repo: &Repository) -> Vec<MirrorScore> {
+    let mut scores = Vec::new();
+    for root in repo.roots() {
+        scores.push(probe(client, root).await);
+    }
+    scores.sort_by(MirrorScore::rank);
+    scores
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MirrorScore;
+    use std::time::Duration;
+
+    fn score(root: &str, throughput: Option<f64>, latency_ms: u64) -> MirrorScore {
+        MirrorScore {
+            root: root.to_string(),
+            latency: throughput.map(|_| Duration::from_millis(latency_ms)),
+            throughput,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn ranks_fastest_reachable_first() {
+        let mut scores = [
+            score("down", None, 0),
+            score("slow", Some(1_000.0), 10),
+            score("fast", Some(50_000.0), 80),
+        ];
+        scores.sort_by(MirrorScore::rank);
+        let order: Vec<&str> = scores.iter().map(|s| s.root.as_str()).collect();
+        assert_eq!(order, vec!["fast", "slow", "down"]);
+    }
+}
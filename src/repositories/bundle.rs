@@ -0,0 +1,284 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail, ensure};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use super::index::{MetadataIndex, SyncState};
+use crate::cloud::{ChecksumKind, Image};
+use crate::helpers::checksum::{self, StreamHasher};
+use crate::helpers::library::{Library, LibraryEntry};
+use crate::helpers::sanitize;
+
+/// Version of the bundle layout, bumped on incompatible changes.
+const FORMAT_VERSION: u32 = 1;
+
+/// First entry of every bundle, so the rest can be imported as it streams by.
+const MANIFEST: &str = "bundle.json";
+const INDEX_ENTRY: &str = "index.sqlite";
+const HTTP_CACHE_DIR: &str = "http-cache";
+const IMAGES_DIR: &str = "images";
+
+/// What a bundle holds besides the index and the cached responses.
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    /// RFC 3339.
+    created: String,
+    images: Vec<BundledImage>,
+}
+
+/// A library image carried in the bundle as `images/<digest>`.
+#[derive(Debug, Serialize, Deserialize)]
+struct BundledImage {
+    digest: String,
+    image: Image,
+}
+
+/// What went into or came out of a bundle.
+#[derive(Debug, Default)]
+pub struct BundleSummary {
+    /// Distros of the index and when they were synced.
+    pub syncs: Vec<SyncState>,
+    pub cached_responses: usize,
+    pub images: usize,
+}
+
+/// Write a zstd compressed tarball to `to` holding a snapshot of `index`,
+/// the responses cached in `http_cache` and the library `images`, for
+/// [`import`] on a host without network access.
+pub fn export(
+    to: &Path,
+    index: &MetadataIndex,
+    http_cache: Option<&Path>,
+    library: &Library,
+    images: &[LibraryEntry],
+) -> Result<BundleSummary> {
+    let tmp = to.with_extension("partial");
+    let snapshot = to.with_extension("index.partial");
+    let _ = fs::remove_file(&snapshot);
+    let summary = write(&tmp, &snapshot, index, http_cache, library, images);
+    let _ = fs::remove_file(&snapshot);
+    match summary {
+        Ok(summary) => {
+            fs::rename(&tmp, to).with_context(|| format!("write bundle {}", to.display()))?;
+            Ok(summary)
+        }
+        Err(err) => {
+            let _ = fs::remove_file(&tmp);
+            Err(err)
+        }
+    }
+}
+
+fn write(
+    path: &Path,
+    snapshot: &Path,
+    index: &MetadataIndex,
+    http_cache: Option<&Path>,
+    library: &Library,
+    images: &[LibraryEntry],
+) -> Result<BundleSummary> {
+    let file = File::create(path).with_context(|| format!("create {}", path.display()))?;
+    let mut tar = tar::Builder::new(zstd::Encoder::new(file, 0)?);
+
+    let manifest = Manifest {
+        version: FORMAT_VERSION,
+        created: Utc::now().to_rfc3339(),
+        images: images
+            .iter()
+            .map(|entry| BundledImage {
+                digest: entry.digest.clone(),
+                image: entry.image.clone(),
+            })
+            .collect(),
+    };
+    let data = serde_json::to_vec_pretty(&manifest)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp().max(0) as u64);
+    tar.append_data(&mut header, MANIFEST, data.as_slice())?;
+
+    index.snapshot(snapshot)?;
+    tar.append_path_with_name(snapshot, INDEX_ENTRY)?;
+
+    let mut summary = BundleSummary {
+        syncs: index.syncs()?,
+        ..BundleSummary::default()
+    };
+    if let Some(dir) = http_cache
+        && let Ok(files) = fs::read_dir(dir)
+    {
+        for file in files.flatten() {
+            let path = file.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                tar.append_path_with_name(&path, Path::new(HTTP_CACHE_DIR).join(file.file_name()))?;
+                summary.cached_responses += 1;
+            }
+        }
+    }
+    for entry in images {
+        let blob = library.blob(&entry.digest);
+        tar.append_path_with_name(&blob, Path::new(IMAGES_DIR).join(&entry.digest))
+            .with_context(|| format!("add {} to the bundle", blob.display()))?;
+        summary.images += 1;
+    }
+
+    tar.into_inner()?.finish()?;
+    Ok(summary)
+}
+
+/// Read a bundle written by [`export`]: its distros replace those of
+/// `index`, its cached responses go into `http_cache` and its images into
+/// `library`, each checked against the digest it was exported with.
+pub fn import(
+    from: &Path,
+    index: &mut MetadataIndex,
+    http_cache: Option<&Path>,
+    library: &Library,
+) -> Result<BundleSummary> {
+    let file = File::open(from).with_context(|| format!("open bundle {}", from.display()))?;
+    let mut archive = tar::Archive::new(zstd::Decoder::new(file)?);
+    // Next to the library, as images may not fit in the temp folder.
+    let staging = library
+        .root()
+        .join(format!(".bundle-{}", std::process::id()));
+    fs::create_dir_all(&staging).with_context(|| format!("create {}", staging.display()))?;
+    let summary = read(&mut archive, &staging, index, http_cache, library)
+        .with_context(|| format!("import bundle {}", from.display()));
+    let _ = fs::remove_dir_all(&staging);
+    summary
+}
+
+fn read<R: Read>(
+    archive: &mut tar::Archive<R>,
+    staging: &Path,
+    index: &mut MetadataIndex,
+    http_cache: Option<&Path>,
+    library: &Library,
+) -> Result<BundleSummary> {
+    let mut manifest: Option<Manifest> = None;
+    let mut summary = BundleSummary::default();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name: PathBuf = entry.path()?.into_owned();
+        let Some(manifest) = &manifest else {
+            ensure!(name == Path::new(MANIFEST), "not a bundle: no {MANIFEST}");
+            let parsed: Manifest = serde_json::from_reader(&mut entry)?;
+            ensure!(
+                parsed.version == FORMAT_VERSION,
+                "unsupported bundle version {} (expected {FORMAT_VERSION})",
+                parsed.version
+            );
+            manifest = Some(parsed);
+            continue;
+        };
+
+        if name == Path::new(INDEX_ENTRY) {
+            let path = staging.join(INDEX_ENTRY);
+            entry.unpack(&path)?;
+            summary.syncs = index.merge(&MetadataIndex::open(&path)?)?;
+        } else if let Ok(file) = name.strip_prefix(HTTP_CACHE_DIR) {
+            let Some(dir) = http_cache else {
+                continue;
+            };
+            let file = sanitize::file_name(file.to_str().unwrap_or_default())
+                .map_err(anyhow::Error::msg)?;
+            fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+            entry.unpack(dir.join(file))?;
+            summary.cached_responses += 1;
+        } else if let Ok(digest) = name.strip_prefix(IMAGES_DIR) {
+            let digest = digest.to_str().unwrap_or_default();
+            let Some(bundled) = manifest.images.iter().find(|i| i.digest == digest) else {
+                bail!("{} is not listed in {MANIFEST}", name.display());
+            };
+            let path = staging.join(sanitize::file_name(digest).map_err(anyhow::Error::msg)?);
+            entry.unpack(&path)?;
+            let mut hasher = StreamHasher::new(ChecksumKind::Sha256);
+            checksum::hash_file(&path, &mut hasher).map_err(anyhow::Error::msg)?;
+            let actual = hasher.finalize_hex();
+            ensure!(
+                actual.eq_ignore_ascii_case(digest),
+                "{} is corrupt: SHA-256 {actual}",
+                name.display()
+            );
+            library
+                .import(&path, &bundled.image, Some(digest))
+                .map_err(anyhow::Error::msg)?;
+            let _ = fs::remove_file(&path);
+            summary.images += 1;
+        }
+    }
+    if manifest.is_none() {
+        bail!("not a bundle: it is empty");
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{export, import};
+    use crate::cloud::{ChecksumKind, ImageChecksum, sample_image};
+    use crate::helpers::library::Library;
+    use crate::repositories::ImageQuery;
+    use crate::repositories::index::{IndexedImage, MetadataIndex};
+    use sha2::{Digest, Sha256};
+    use std::fs;
+
+    #[test]
+    fn carries_the_index_cache_and_images_across() {
+        let dir = std::env::temp_dir().join(format!("cid-bundle-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("http")).unwrap();
+        fs::write(dir.join("http/ab.json"), "{}").unwrap();
+        fs::write(dir.join("disk.qcow2"), b"disk").unwrap();
+
+        let sha256 = hex::encode(Sha256::digest(b"disk"));
+        let image = sample_image().with_source(
+            "https://example.invalid/disk.qcow2".to_string(),
+            Some(ImageChecksum::new(ChecksumKind::Sha256, &sha256)),
+        );
+        let mut index = MetadataIndex::in_memory().unwrap();
+        index
+            .replace(
+                "Debian",
+                &[IndexedImage {
+                    distro: "Debian".to_string(),
+                    release: "bookworm".to_string(),
+                    image: image.clone(),
+                }],
+            )
+            .unwrap();
+        let library = Library::new(dir.join("library"));
+        let entry = library
+            .import(&dir.join("disk.qcow2"), &image, None)
+            .unwrap();
+
+        let bundle = dir.join("bundle.tar.zst");
+        let exported =
+            export(&bundle, &index, Some(&dir.join("http")), &library, &[entry]).unwrap();
+        assert_eq!((exported.cached_responses, exported.images), (1, 1));
+
+        let mut offline = MetadataIndex::in_memory().unwrap();
+        let target = Library::new(dir.join("offline-library"));
+        let imported = import(
+            &bundle,
+            &mut offline,
+            Some(&dir.join("offline-http")),
+            &target,
+        )
+        .unwrap();
+        assert_eq!(imported.syncs, index.syncs().unwrap());
+        assert_eq!((imported.cached_responses, imported.images), (1, 1));
+        assert_eq!(offline.query(&ImageQuery::new()).unwrap().len(), 1);
+        assert!(dir.join("offline-http/ab.json").is_file());
+        assert_eq!(fs::read(target.lookup(&image).unwrap()).unwrap(), b"disk");
+
+        fs::write(&bundle, b"garbage").unwrap();
+        assert!(import(&bundle, &mut offline, None, &target).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
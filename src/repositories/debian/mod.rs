@@ -1,64 +1,54 @@
-pub mod models;
-pub use models::{DebianProvider, ImageAsset, ImageRequest, Provider};
-
-use anyhow::{Context, Result, anyhow, ensure};
+use anyhow::{Context, Result, anyhow};
+use async_trait::async_trait;
+use futures::{StreamExt, stream};
 use regex::Regex;
 use reqwest::Client;
 use std::cmp::Ordering;
-use std::collections::HashSet;
 
-use crate::cloud::{ChecksumKind, Image, ImageChecksum};
-use crate::helpers::{arch_options_for, choose_one};
-use crate::repositories;
+use crate::cloud::{
+    Arch, ArchNaming, Image, ImageChecksum, Variant, VersionKey, sort_builds_newest_first,
+};
+use crate::helpers::{
+    checksum::{ChecksumEntry, ChecksumSource},
+    gpg::Signing,
+    listing, sanitize, trace,
+};
+use crate::repositories::{self, ImageProvider, Release};
 
 const DEFAULT_CODENAMES: &[&str] = &["stable", "bookworm", "trixie"];
 
-const DEBIAN_SHA512_LINE_PATTERN: &str = r#"(?xi)
+/// Upper bound for simultaneous requests against the Debian mirror.
+const MAX_CONCURRENT_FETCHES: usize = 8;
+
+const DEBIAN_FILENAME_PATTERN: &str = r#"(?xi)
     ^
-    (?P<sha>[a-f0-9]{64}|[a-f0-9]{128})
-    \s+\*?
-    (?P<file>
-        debian-
-        (?P<dver>\d+)-
-        (?P<variant>[a-z0-9+]+(?:-[a-z0-9+]+)*)-
-        (?P<arch>amd64|arm64)
-        (?:-(?P<build>\d{8}-\d{4}))?
-        \.
-        (?P<ext>qcow2|raw)
-    )
+    debian-
+    (?P<dver>\d+)-
+    (?P<variant>[a-z0-9+]+(?:-[a-z0-9+]+)*)-
+    (?P<arch>amd64|arm64)
+    (?:-(?P<build>\d{8}-\d{4}))?
+    \.
+    (?P<ext>qcow2|raw)
     $
 "#;
 
-/// Fallback list of codenames used when the remote repository cannot be
-/// queried.
-pub fn codename_options() -> Vec<&'static str> {
-    DEFAULT_CODENAMES.to_vec()
-}
-
 /// Attempt to scrape the Debian cloud image directory to discover available
 /// codenames. The function falls back to a static list when the remote is
 /// unreachable or empty.
-pub async fn available_codenames() -> Result<Vec<String>> {
-    let client = Client::new();
-    let root = repository_root()?;
-
-    let html = client
-        .get(&root)
-        .send()
-        .await?
-        .error_for_status()?
-        .text()
+pub async fn available_codenames(client: &Client) -> Result<Vec<String>> {
+    let root = repository_root(client).await?;
+
+    let entries = listing::fetch(client, &root)
         .await
-        .with_context(|| format!("fetch Debian codename listing from {root}"))?;
+        .context("fetch Debian codename listing")?;
 
-    let dir_re = Regex::new(r#"href=\"([a-z0-9][a-z0-9-]+)/\""#)?;
-    let mut names: Vec<String> = dir_re
-        .captures_iter(&html)
-        .map(|cap| cap[1].to_string())
+    let codename_re = Regex::new(r"^[a-z0-9][a-z0-9-]+$")?;
+    let mut names: Vec<String> = listing::subdirs(&entries)
+        .into_iter()
+        .filter(|name| codename_re.is_match(name))
         .collect();
 
     names.sort();
-    names.dedup();
 
     if names.is_empty() {
         return Ok(DEFAULT_CODENAMES.iter().map(|s| s.to_string()).collect());
@@ -67,17 +57,10 @@ pub async fn available_codenames() -> Result<Vec<String>> {
     Ok(names)
 }
 
-#[derive(Debug, Clone)]
-struct CodenameOption {
-    codename: String,
-    label: String,
-    major_version: Option<String>,
-}
-
 /// Build the interactive codename list enriched with detected major versions
 /// so the picker can display more context to the user.
-async fn codename_options_with_versions() -> Result<Vec<CodenameOption>> {
-    let dynamic = available_codenames().await.unwrap_or_default();
+async fn codename_options_with_versions(client: &Client) -> Result<Vec<Release>> {
+    let dynamic = available_codenames(client).await.unwrap_or_default();
     let base = if dynamic.is_empty() {
         DEFAULT_CODENAMES
             .iter()
@@ -87,72 +70,121 @@ async fn codename_options_with_versions() -> Result<Vec<CodenameOption>> {
         dynamic
     };
 
-    let client = Client::new();
-    let mut options = Vec::new();
-
-    for codename in base {
-        let major_version = detect_major_version(&client, &codename).await;
-        let label = match &major_version {
-            Some(major) => format!("{major} ({codename})"),
-            None => codename.clone(),
-        };
-
-        options.push(CodenameOption {
-            codename,
-            label,
-            major_version,
-        });
-    }
+    let mut options: Vec<Release> = stream::iter(base)
+        .map(|codename| async move {
+            let distro_version = detect_major_version(client, &codename).await;
+            let label = match &distro_version {
+                Some(major) => format!("{major} ({codename})"),
+                None => codename.clone(),
+            };
+
+            Release {
+                id: codename,
+                label,
+                distro_version,
+            }
+        })
+        .buffer_unordered(MAX_CONCURRENT_FETCHES)
+        .collect()
+        .await;
 
-    options.sort_by(|a, b| match (&a.major_version, &b.major_version) {
-        (Some(ma), Some(mb)) => match (ma.parse::<u32>(), mb.parse::<u32>()) {
-            (Ok(va), Ok(vb)) => vb.cmp(&va),
-            _ => mb.cmp(ma),
-        },
+    options.sort_by(|a, b| match (&a.distro_version, &b.distro_version) {
+        (Some(ma), Some(mb)) => VersionKey::new(mb).cmp(&VersionKey::new(ma)),
         (Some(_), None) => Ordering::Less,
         (None, Some(_)) => Ordering::Greater,
-        (None, None) => a.codename.cmp(&b.codename),
+        (None, None) => a.id.cmp(&b.id),
     });
 
     Ok(options)
 }
 
+/// The signed `SHA512SUMS` listing of the build directory `dir_url`. Every
+/// Debian lookup reads the sums through here so they are verified the same
+/// way.
+async fn sums_listing(
+    client: &Client,
+    dir_url: &str,
+) -> Result<Option<Vec<ChecksumEntry>>, String> {
+    let sums_url = format!("{dir_url}SHA512SUMS");
+    sums_source(sums_url.clone())
+        .entries(client, &sums_url)
+        .await
+}
+
+fn sums_source(url: String) -> ChecksumSource {
+    ChecksumSource::Combined {
+        url,
+        repo: "debian",
+        signing: Some(Signing::Detached(".sign")),
+    }
+}
+
 /// Inspect the SHA512 sums file for a codename and try to extract the Debian
 /// major version. Returns `None` when the information is not present.
 async fn detect_major_version(client: &Client, codename: &str) -> Option<String> {
-    let repo_urls = repository_urls(codename).ok()?;
-    let sums_url = format!("{}SHA512SUMS", repo_urls.latest);
+    let repo_urls = repository_urls(client, codename).await.ok()?;
+    let entries = sums_listing(client, &repo_urls.latest).await.ok()??;
+
+    let file_re = Regex::new(DEBIAN_FILENAME_PATTERN).ok()?;
+    entries.iter().find_map(|entry| {
+        file_re
+            .captures(&entry.file)
+            .map(|caps| caps["dver"].to_string())
+    })
+}
 
-    let response = client.get(&sums_url).send().await.ok()?;
-    let text = response.error_for_status().ok()?.text().await.ok()?;
+/// Debian images from the dated build directories of each codename.
+pub struct DebianProvider;
 
-    let re = Regex::new(r"debian-(?P<major>\d+)-").ok()?;
-    re.captures_iter(&text)
-        .next()
-        .and_then(|caps| caps.name("major").map(|m| m.as_str().to_string()))
-}
+#[async_trait]
+impl ImageProvider for DebianProvider {
+    fn display_name(&self) -> &'static str {
+        "Debian"
+    }
 
-/// Present the list of Debian codenames to the user and return both the chosen
-/// codename and the detected major version (if available).
-pub async fn prompt_for_codename() -> Result<(String, Option<String>)> {
-    let options = codename_options_with_versions().await?;
-    ensure!(!options.is_empty(), "No Debian codenames available");
+    fn supported_arches(&self) -> Vec<Arch> {
+        vec![Arch::Amd64, Arch::Arm64]
+    }
 
-    let labels = options.iter().map(|opt| opt.label.clone()).collect();
-    let choice = choose_one("Select Debian Codename", labels)?;
+    /// Codenames labelled with their major version, newest first.
+    async fn releases(&self, client: &Client, _track: &str, _arch: Arch) -> Result<Vec<Release>> {
+        codename_options_with_versions(client).await
+    }
 
-    let selected = options
-        .into_iter()
-        .find(|opt| opt.label == choice)
-        .expect("chosen label must map to a codename");
+    /// Only the requested codename is probed for its major version.
+    async fn release(
+        &self,
+        client: &Client,
+        _track: &str,
+        _arch: Arch,
+        id: &str,
+    ) -> Result<Release> {
+        Ok(Release {
+            distro_version: detect_major_version(client, id).await,
+            ..Release::new(id)
+        })
+    }
 
-    Ok((selected.codename, selected.major_version))
-}
+    async fn list(
+        &self,
+        client: &Client,
+        _track: &str,
+        release: &Release,
+        arch: Arch,
+    ) -> Result<Vec<Image>> {
+        debian_list(client, &release.id, arch).await
+    }
+
+    /// The `SHA512SUMS` of the build directory holding `image`.
+    fn checksum_source(&self, image: &Image) -> Option<ChecksumSource> {
+        let (dir, _) = image.url().rsplit_once('/')?;
+        Some(sums_source(format!("{dir}/SHA512SUMS")))
+    }
 
-pub async fn pick_debian_interactive() -> Result<(String, Image)> {
-    let (codename, major_version) = prompt_for_codename().await?;
-    let image = pick_debian_with_hint(&codename, major_version.as_deref()).await?;
-    Ok((codename, image))
+    /// Debian builds are shown with their codename, e.g. `bookworm (latest)`.
+    fn version_label(&self, release: &Release, image: &Image) -> String {
+        format!("{} ({})", release.id, image.version())
+    }
 }
 
 struct DebianRepoUrls {
@@ -161,13 +193,15 @@ struct DebianRepoUrls {
 }
 
 /// Split the configured repository URL into the static prefix and suffix parts
-/// surrounding the `"{}"` placeholder used to inject the codename.
-fn repository_template() -> Result<(String, String)> {
+/// surrounding the `"{}"` placeholder used to inject the codename, rebased onto
+/// the first reachable mirror.
+async fn repository_template(client: &Client) -> Result<(String, String)> {
     let repo = repositories::by_name("debian")
         .map_err(anyhow::Error::new)?
         .context("repository 'debian' is not configured")?;
 
-    repo.url()
+    repositories::resolve(client, &repo, repo.url())
+        .await
         .split_once("{}")
         .map(|(prefix, suffix)| (prefix.to_string(), suffix.to_string()))
         .ok_or_else(|| anyhow!("repository URL for debian must contain '{{}}' placeholder"))
@@ -175,8 +209,8 @@ fn repository_template() -> Result<(String, String)> {
 
 /// Return the absolute base URL used to discover available directories for a
 /// given codename.
-fn repository_root() -> Result<String> {
-    let (prefix, _) = repository_template()?;
+async fn repository_root(client: &Client) -> Result<String> {
+    let (prefix, _) = repository_template(client).await?;
     Ok(if prefix.ends_with('/') {
         prefix
     } else {
@@ -186,8 +220,8 @@ fn repository_root() -> Result<String> {
 
 /// Construct the URLs required to browse and download the Debian artifacts for
 /// the provided codename.
-fn repository_urls(codename: &str) -> Result<DebianRepoUrls> {
-    let (prefix, suffix) = repository_template()?;
+async fn repository_urls(client: &Client, codename: &str) -> Result<DebianRepoUrls> {
+    let (prefix, suffix) = repository_template(client).await?;
 
     let mut latest = format!("{prefix}{codename}{suffix}");
     if !latest.ends_with('/') {
@@ -212,120 +246,13 @@ fn repository_urls(codename: &str) -> Result<DebianRepoUrls> {
     })
 }
 
-/// Interactive Debian picker that optionally reuses a detected major version
-/// hint to skip one of the prompts.
-pub async fn pick_debian_with_hint(
-    codename: &str,
-    distro_version_hint: Option<&str>,
-) -> Result<Image> {
-    // 1) Arch (use your existing helper; ensure it includes amd64/arm64 at least)
-    let arch = choose_one("Select Architecture", arch_options_for("Debian"))?;
-
-    // 2) Fetch images for the chosen arch (treat `codename` like "bookworm", "trixie", or "stable")
-    let mut images: Vec<Image> = debian_list(codename, &arch, /*include_testing=*/ false)
-        .await
-        .with_context(|| format!("fetch debian images for codename='{codename}' arch='{arch}'"))?;
-
-    ensure!(
-        !images.is_empty(),
-        "No Debian images found for codename={codename} arch={arch}"
-    );
-
-    // 3) Distro major version (e.g., "12", "13")
-    let distro_version = if let Some(hint) = distro_version_hint {
-        images.retain(|i| i.distro_version() == hint);
-        ensure!(
-            !images.is_empty(),
-            "No Debian images found for distro_version={hint}"
-        );
-        hint.to_string()
-    } else {
-        let mut distro_versions = images
-            .iter()
-            .map(|i| i.distro_version().to_string())
-            .collect::<Vec<_>>();
-        distro_versions.sort();
-        distro_versions.reverse();
-        distro_versions.dedup();
-
-        let chosen = choose_one("Select Distro Version", distro_versions)?;
-        images.retain(|i| i.distro_version() == chosen);
-        ensure!(
-            !images.is_empty(),
-            "No Debian images found for distro_version={chosen}"
-        );
-        chosen
-    };
-
-    // 4) Image version / build (e.g., point release or date-stamped build)
-    let mut image_versions: Vec<String> = images
-        .iter()
-        .map(|i| i.version().to_string())
-        .collect::<Vec<_>>();
-    image_versions.sort();
-    image_versions.reverse();
-    image_versions.dedup();
-
-    let image_version = choose_one("Select Image Version", image_versions)?;
-    images = images
-        .into_iter()
-        .filter(|i| i.version() == image_version)
-        .collect();
-    ensure!(
-        !images.is_empty(),
-        "No Debian images for distro_version={distro_version} and version={image_version}"
-    );
-
-    // 5) Image type / variant (Debian usually: "genericcloud" or "nocloud")
-    let mut image_types: Vec<String> = images.iter().map(|i| i.image_type().to_string()).collect();
-    image_types.sort();
-    image_types.dedup();
-
-    let image_type = choose_one("Select Disk Image Type", image_types)?;
-    images = images
-        .into_iter()
-        .filter(|i| i.image_type() == image_type)
-        .collect();
-    ensure!(
-        !images.is_empty(),
-        "No Debian images found for distro_version={distro_version}, version={image_version}, type={image_type}"
-    );
-
-    // 6) If multiple artifacts remain (qcow2/raw), let user pick the exact one
-    let labelize = |i: &Image| {
-        format!(
-            "{} | {} | {} | {} | {}",
-            i.name(),
-            i.image_type(),
-            i.version(),
-            i.arch(),
-            i.url()
-        )
-    };
-    let chosen_label = choose_one(
-        "Select Image Artifact",
-        images.iter().map(|i| labelize(i)).collect(),
-    )?;
-
-    let idx = images
-        .iter()
-        .position(|i| labelize(i) == chosen_label)
-        .expect("selected label must match one candidate");
-
-    Ok(images[idx].clone())
-}
-
-pub async fn pick_debian(codename: &str) -> Result<Image> {
-    pick_debian_with_hint(codename, None).await
-}
-
 /// Helper that keeps the mapping between parsed metadata and the generic
 /// `Image` structure in one place.
 fn make_image(
     codename: &str,
     url: String,
-    arch: String,
-    image_type: String,
+    arch: Arch,
+    variant: Variant,
     version: String,
     distro_version: String,
     checksum: Option<ImageChecksum>,
@@ -338,52 +265,31 @@ fn make_image(
         arch,
         url,
         checksum,
-        image_type,
+        variant,
     )
 }
 
 /// List Debian cloud images for a given codename & arch.
 ///
 /// - `codename`: "bookworm", "trixie", or "stable" (etc)
-/// - `arch`: "amd64" | "arm64" (accepts "x86_64" and normalizes to "amd64")
-/// - `include_testing`: currently unused (kept for API symmetry)
-pub async fn debian_list(codename: &str, arch: &str, _include_testing: bool) -> Result<Vec<Image>> {
-    let client = Client::new();
-
-    // Debian calls x86_64 -> amd64
-    let want_arch = match arch {
-        "x86_64" => "amd64",
-        other => other,
-    }
-    .to_string();
+/// - `arch`: architecture to keep images for
+pub async fn debian_list(client: &Client, codename: &str, arch: Arch) -> Result<Vec<Image>> {
+    let want_arch = arch.name(ArchNaming::Debian);
 
-    let repo_urls = repository_urls(codename)?;
+    let repo_urls = repository_urls(client, codename).await?;
     let base = repo_urls.listing_root;
 
     // 1) Fetch directory index and extract subdirs: latest/ and YYYYMMDD-HHMM/
-    let index_html = client
-        .get(&base)
-        .send()
-        .await?
-        .error_for_status()?
-        .text()
-        .await
-        .with_context(|| format!("fetch directory listing: {base}"))?;
+    let entries = listing::fetch(client, &base).await?;
 
-    let href_re = Regex::new(r#"href=\"([^\"/]+)/\""#)?;
     let valid_dir_re = Regex::new(r"^(?:latest|\d{8}(?:-\d{4})?)$")?;
-    let mut seen = HashSet::new();
     let mut dated_dirs: Vec<String> = Vec::new();
     let mut include_latest = false;
 
-    for cap in href_re.captures_iter(&index_html) {
-        let dir = cap[1].to_string();
+    for dir in listing::subdirs(&entries) {
         if !valid_dir_re.is_match(&dir) {
             continue;
         }
-        if !seen.insert(dir.clone()) {
-            continue;
-        }
         if dir == "latest" {
             include_latest = true;
         } else {
@@ -391,8 +297,7 @@ pub async fn debian_list(codename: &str, arch: &str, _include_testing: bool) ->
         }
     }
 
-    dated_dirs.sort();
-    dated_dirs.reverse();
+    sort_builds_newest_first(&mut dated_dirs);
 
     let mut dirs = Vec::new();
     if include_latest {
@@ -406,54 +311,69 @@ pub async fn debian_list(codename: &str, arch: &str, _include_testing: bool) ->
     //   debian-12-nocloud-amd64.qcow2
     // We’ll capture:
     //   distro_version = 12
-    //   image_type     = genericcloud|nocloud
+    //   variant        = genericcloud|nocloud
     //   arch           = amd64|arm64
     //   ext            = qcow2|raw (you can keep/filter later)
     //
     // SHA512SUMS lines are typically:
-    //   <sha256>  debian-12-genericcloud-amd64.qcow2
+    //   <sha512>  debian-12-genericcloud-amd64.qcow2
     //
-    let line_re = Regex::new(DEBIAN_SHA512_LINE_PATTERN)?;
+    let file_re = Regex::new(DEBIAN_FILENAME_PATTERN)?;
+
+    // Fetch every SHA512SUMS concurrently; keep the directory index so the
+    // output order stays stable (latest first, then newest builds).
+    let base_ref = &base;
+    let mut listings: Vec<(usize, String, Vec<ChecksumEntry>)> =
+        stream::iter(dirs.into_iter().enumerate())
+            .map(|(idx, d)| async move {
+                let sums = sums_listing(client, &format!("{base_ref}{d}/")).await;
+                (idx, d, sums)
+            })
+            .buffer_unordered(MAX_CONCURRENT_FETCHES)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            // no SHA512SUMS in this dir; skip
+            .filter_map(|(idx, d, sums)| sums.transpose().map(|sums| (idx, d, sums)))
+            .map(|(idx, d, sums)| sums.map(|sums| (idx, d, sums)))
+            .collect::<std::result::Result<_, String>>()
+            .map_err(anyhow::Error::msg)?;
+    listings.sort_by_key(|(idx, _, _)| *idx);
 
     let mut out = Vec::new();
 
-    for d in dirs {
-        let sums_url = format!("{base}{d}/SHA512SUMS");
-        let sums = match client.get(&sums_url).send().await {
-            Ok(resp) => match resp.error_for_status() {
-                Ok(ok) => ok.text().await.unwrap_or_default(),
-                Err(_) => continue, // no SHA512SUMS in this dir; skip
-            },
-            Err(_) => continue,
-        };
-
-        for line in sums.lines() {
-            if let Some(c) = line_re.captures(line.trim()) {
+    for (_, d, sums) in listings {
+        for entry in sums {
+            if let Some(c) = file_re.captures(&entry.file) {
                 let file_arch = c.name("arch").unwrap().as_str();
                 if file_arch != want_arch {
+                    trace::excluded("arch", &entry.file, || {
+                        format!("arch is {file_arch}, wanted {want_arch}")
+                    });
                     continue;
                 }
+                trace::kept("arch");
 
-                let filename = c.name("file").unwrap().as_str().to_string();
+                let filename = entry.file.clone();
                 let distro_version = c.name("dver").unwrap().as_str().to_string();
                 let variant = c.name("variant").unwrap().as_str().to_string();
-                let checksum = c
-                    .name("sha")
-                    .map(|cap| ImageChecksum::new(ChecksumKind::Sha512, cap.as_str()));
-
-                // You can choose to filter by ext here if you only want qcow2:
-                // let ext = c.name("ext").unwrap().as_str();
-                // if ext != "qcow2" { continue; }
+                let checksum = Some(entry.checksum);
 
-                let url = format!("{base}{d}/{filename}");
+                let url = match sanitize::artifact_url(&format!("{base}{d}/"), &filename) {
+                    Ok(url) => url,
+                    Err(err) => {
+                        eprintln!("Warning: {err}");
+                        continue;
+                    }
+                };
 
                 // "version" in your picker is the build dir (e.g., "latest" or "20241013-1744")
-                // "image_type" is the Debian variant (e.g., "genericcloud", "nocloud")
+                // "variant" is e.g. "genericcloud" or "nocloud"; the format comes from the file name
                 out.push(make_image(
                     codename,
                     url,
-                    want_arch.clone(),
-                    variant,
+                    arch,
+                    Variant::from_name(&variant),
                     d.clone(),
                     distro_version,
                     checksum,
@@ -467,40 +387,30 @@ pub async fn debian_list(codename: &str, arch: &str, _include_testing: bool) ->
 
 #[cfg(test)]
 mod tests {
-    use super::DEBIAN_SHA512_LINE_PATTERN;
+    use super::DEBIAN_FILENAME_PATTERN;
     use regex::Regex;
 
     fn regex() -> Regex {
-        Regex::new(DEBIAN_SHA512_LINE_PATTERN).expect("invalid debian sha512 regex")
+        Regex::new(DEBIAN_FILENAME_PATTERN).expect("invalid debian filename regex")
     }
 
     #[test]
     fn matches_latest_style_filename() {
-        let sha = "a".repeat(128);
-        let line = format!("{sha}  debian-12-genericcloud-amd64.qcow2");
-
         let caps = regex()
-            .captures(&line)
+            .captures("debian-12-genericcloud-amd64.qcow2")
             .expect("should match simple genericcloud artifact");
 
         assert_eq!(caps.name("dver").unwrap().as_str(), "12");
         assert_eq!(caps.name("variant").unwrap().as_str(), "genericcloud");
         assert_eq!(caps.name("arch").unwrap().as_str(), "amd64");
         assert_eq!(caps.name("ext").unwrap().as_str(), "qcow2");
-        assert_eq!(
-            caps.name("file").unwrap().as_str(),
-            "debian-12-genericcloud-amd64.qcow2"
-        );
         assert!(caps.name("build").is_none());
     }
 
     #[test]
     fn matches_timestamped_filename() {
-        let sha = "b".repeat(64);
-        let line = format!("{sha}  debian-12-genericcloud-arm64-20241013-1744.raw");
-
         let caps = regex()
-            .captures(&line)
+            .captures("debian-12-genericcloud-arm64-20241013-1744.raw")
             .expect("should match timestamped genericcloud artifact");
 
         assert_eq!(caps.name("dver").unwrap().as_str(), "12");
@@ -512,11 +422,8 @@ mod tests {
 
     #[test]
     fn matches_variant_with_plus_suffix() {
-        let sha = "c".repeat(64);
-        let line = format!("{sha}  debian-12-nocloud+nonfree-amd64-20240930-1200.qcow2");
-
         let caps = regex()
-            .captures(&line)
+            .captures("debian-12-nocloud+nonfree-amd64-20240930-1200.qcow2")
             .expect("should match nocloud+nonfree artifact");
 
         assert_eq!(caps.name("variant").unwrap().as_str(), "nocloud+nonfree");
@@ -0,0 +1,115 @@
+use std::collections::BTreeMap;
+
+use super::index::IndexedImage;
+use crate::cloud::ImageChecksum;
+
+/// How one image differs between two index snapshots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// Published since the previous snapshot.
+    Added(IndexedImage),
+    /// Gone since the previous snapshot.
+    Removed(IndexedImage),
+    /// Still published under the same URL, with another checksum.
+    ChecksumChanged {
+        old: Box<IndexedImage>,
+        new: IndexedImage,
+    },
+}
+
+impl Change {
+    /// The image as it is now, or as it was when removed.
+    pub fn entry(&self) -> &IndexedImage {
+        match self {
+            Change::Added(entry) | Change::Removed(entry) => entry,
+            Change::ChecksumChanged { new, .. } => new,
+        }
+    }
+}
+
+/// Whether `old` and `new` publish a different value for a checksum kind
+/// both carry.
+fn checksums_differ(old: &[ImageChecksum], new: &[ImageChecksum]) -> bool {
+    old.iter().any(|o| {
+        new.iter()
+            .any(|n| n.kind() == o.kind() && !n.value().eq_ignore_ascii_case(o.value()))
+    })
+}
+
+/// Changes from the `previous` snapshot to the `current` one, matching
+/// images by URL, ordered by distro, release and URL.
+pub fn diff(previous: Vec<IndexedImage>, current: Vec<IndexedImage>) -> Vec<Change> {
+    let key = |entry: &IndexedImage| {
+        (
+            entry.distro.clone(),
+            entry.release.clone(),
+            entry.image.url().to_string(),
+        )
+    };
+    let mut old: BTreeMap<_, _> = previous.into_iter().map(|e| (key(&e), e)).collect();
+    let mut changes: BTreeMap<_, Change> = BTreeMap::new();
+    for entry in current {
+        let at = key(&entry);
+        match old.remove(&at) {
+            None => {
+                changes.insert(at, Change::Added(entry));
+            }
+            Some(before) if checksums_differ(before.image.checksums(), entry.image.checksums()) => {
+                changes.insert(
+                    at,
+                    Change::ChecksumChanged {
+                        old: Box::new(before),
+                        new: entry,
+                    },
+                );
+            }
+            Some(_) => {}
+        }
+    }
+    for (at, entry) in old {
+        changes.insert(at, Change::Removed(entry));
+    }
+    changes.into_values().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Change, diff};
+    use crate::cloud::{ChecksumKind, ImageChecksum, sample_image};
+    use crate::repositories::index::IndexedImage;
+
+    fn entry(version: &str, sha256: &str) -> IndexedImage {
+        IndexedImage {
+            distro: "Debian".to_string(),
+            release: "bookworm".to_string(),
+            image: sample_image().with_version(version).with_source(
+                format!("https://example.invalid/{version}/disk.qcow2"),
+                Some(ImageChecksum::new(ChecksumKind::Sha256, sha256)),
+            ),
+        }
+    }
+
+    #[test]
+    fn reports_new_removed_and_republished_images() {
+        let previous = vec![entry("20250101", "aa"), entry("20250201", "bb")];
+        let current = vec![
+            entry("20250201", "BB"),
+            entry("20250301", "cc"),
+            entry("20250101", "dd"),
+        ];
+        assert_eq!(
+            diff(previous.clone(), current),
+            [
+                Change::ChecksumChanged {
+                    old: Box::new(entry("20250101", "aa")),
+                    new: entry("20250101", "dd"),
+                },
+                Change::Added(entry("20250301", "cc")),
+            ]
+        );
+        assert_eq!(
+            diff(previous, vec![entry("20250201", "bb")]),
+            [Change::Removed(entry("20250101", "aa"))]
+        );
+    }
+}
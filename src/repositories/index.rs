@@ -0,0 +1,347 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use futures::stream::{self, StreamExt};
+use reqwest::Client;
+use rusqlite::{Connection, OptionalExtension, params};
+
+use super::{ImageProvider, ImageQuery, Release};
+use crate::cloud::{ArchNaming, Image};
+use crate::helpers::paths;
+use crate::helpers::selection::newest_build;
+
+const INDEX_FILE: &str = "index.sqlite";
+const PREVIOUS_FILE: &str = "index.previous.sqlite";
+
+/// Releases listed at the same time while syncing one distro.
+const SYNC_CONCURRENCY: usize = 4;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS images (
+        id TEXT PRIMARY KEY,
+        distro TEXT NOT NULL,
+        release TEXT NOT NULL,
+        arch TEXT NOT NULL,
+        version TEXT NOT NULL,
+        url TEXT NOT NULL,
+        image TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS images_by_release
+        ON images (distro COLLATE NOCASE, release COLLATE NOCASE, arch);
+    CREATE TABLE IF NOT EXISTS syncs (
+        distro TEXT PRIMARY KEY,
+        synced_at TEXT NOT NULL,
+        images INTEGER NOT NULL
+    );
+";
+
+/// Local sqlite copy of every image the repositories published at the last
+/// `index sync`, so listings answer instantly and offline.
+pub struct MetadataIndex {
+    conn: Connection,
+}
+
+/// An image as stored in the index, with the release it was listed under.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexedImage {
+    /// Provider display name, e.g. `Debian`.
+    pub distro: String,
+    /// Release identifier understood by the provider.
+    pub release: String,
+    pub image: Image,
+}
+
+/// When a distro was last synced and how many images it had.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncState {
+    pub distro: String,
+    /// RFC 3339.
+    pub synced_at: String,
+    pub images: usize,
+}
+
+impl MetadataIndex {
+    /// `index.sqlite` below the cache directory
+    /// (`$XDG_CACHE_HOME/cloud-images-downloader` on Linux by default).
+    pub fn default_path() -> Option<PathBuf> {
+        paths::cache_dir().map(|dir| dir.join(INDEX_FILE))
+    }
+
+    /// The index as it was before the last `index sync`, next to
+    /// [`default_path`](Self::default_path).
+    pub fn previous_path() -> Option<PathBuf> {
+        paths::cache_dir().map(|dir| dir.join(PREVIOUS_FILE))
+    }
+
+    /// Open (creating if needed) the index at `path`.
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("create {}", parent.display()))?;
+        }
+        let conn =
+            Connection::open(path).with_context(|| format!("open index {}", path.display()))?;
+        Self::with_connection(conn)
+    }
+
+    /// An empty index kept in memory.
+    pub fn in_memory() -> Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(conn: Connection) -> Result<Self> {
+        conn.execute_batch(SCHEMA).context("create index schema")?;
+        Ok(Self { conn })
+    }
+
+    /// Replace everything known about `distro` with `images`.
+    pub fn replace(&mut self, distro: &str, images: &[IndexedImage]) -> Result<()> {
+        self.replace_synced(distro, images, &Utc::now().to_rfc3339())
+    }
+
+    fn replace_synced(
+        &mut self,
+        distro: &str,
+        images: &[IndexedImage],
+        synced_at: &str,
+    ) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "DELETE FROM images WHERE distro = ?1 COLLATE NOCASE",
+            params![distro],
+        )?;
+        {
+            let mut insert = tx.prepare(
+                "INSERT OR REPLACE INTO images (id, distro, release, arch, version, url, image)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?;
+            for entry in images {
+                let image = &entry.image;
+                insert.execute(params![
+                    image.id(),
+                    entry.distro,
+                    entry.release,
+                    image.arch().name(ArchNaming::Debian),
+                    image.version(),
+                    image.url(),
+                    serde_json::to_string(image)?,
+                ])?;
+            }
+        }
+        tx.execute(
+            "INSERT OR REPLACE INTO syncs (distro, synced_at, images) VALUES (?1, ?2, ?3)",
+            params![distro, synced_at, images.len()],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Take over every distro `other` synced, keeping the time it did;
+    /// returns those distros.
+    pub fn merge(&mut self, other: &MetadataIndex) -> Result<Vec<SyncState>> {
+        let syncs = other.syncs()?;
+        for sync in &syncs {
+            let images = other.query(&ImageQuery::new().distro(&sync.distro))?;
+            self.replace_synced(&sync.distro, &images, &sync.synced_at)?;
+        }
+        Ok(syncs)
+    }
+
+    /// Write a consistent copy of the index to the new file `path`.
+    pub fn snapshot(&self, path: &Path) -> Result<()> {
+        let target = path.to_str().context("index snapshot path is not UTF-8")?;
+        self.conn
+            .execute("VACUUM INTO ?1", params![target])
+            .with_context(|| format!("write index snapshot {}", path.display()))?;
+        Ok(())
+    }
+
+    /// The distros synced so far.
+    pub fn syncs(&self) -> Result<Vec<SyncState>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT distro, synced_at, images FROM syncs ORDER BY distro")?;
+        let rows = stmt.query_map([], |row| {
+            Ok(SyncState {
+                distro: row.get(0)?,
+                synced_at: row.get(1)?,
+                images: row.get(2)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Whether `distro` was ever synced.
+    pub fn has_synced(&self, distro: &str) -> Result<bool> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT 1 FROM syncs WHERE distro = ?1 COLLATE NOCASE",
+                params![distro],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some())
+    }
+
+    /// Indexed images matching `query`. Unlike a live query nothing is
+    /// required: a missing distro, release or arch matches all of them, and
+    /// `latest` keeps the newest build of every release and arch.
+    pub fn query(&self, query: &ImageQuery) -> Result<Vec<IndexedImage>> {
+        let arch = query.arch_value().map(|a| a.name(ArchNaming::Debian));
+        let mut stmt = self.conn.prepare(
+            "SELECT distro, release, image FROM images
+             WHERE (?1 IS NULL OR distro = ?1 COLLATE NOCASE)
+               AND (?2 IS NULL OR release = ?2 COLLATE NOCASE)
+               AND (?3 IS NULL OR arch = ?3)
+             ORDER BY distro, release, arch, url",
+        )?;
+        let rows = stmt.query_map(
+            params![query.distro_name(), query.release_id(), arch],
+            |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            },
+        )?;
+        let mut found = Vec::new();
+        for row in rows {
+            let (distro, release, image) = row?;
+            let image: Image = serde_json::from_str(&image).context("read indexed image")?;
+            if query.matches(&image) {
+                found.push(IndexedImage {
+                    distro,
+                    release,
+                    image,
+                });
+            }
+        }
+        if query.wants_latest() {
+            keep_newest_builds(&mut found);
+        }
+        Ok(found)
+    }
+}
+
+/// Keep the images of the newest build of every distro, release and arch.
+fn keep_newest_builds(images: &mut Vec<IndexedImage>) {
+    let mut groups: BTreeMap<(String, String, String), Vec<&str>> = BTreeMap::new();
+    for entry in images.iter() {
+        groups
+            .entry(group_key(entry))
+            .or_default()
+            .push(entry.image.version());
+    }
+    let newest: BTreeMap<_, _> = groups
+        .into_iter()
+        .filter_map(|(key, builds)| Some((key, newest_build(builds.into_iter())?)))
+        .collect();
+    images.retain(|entry| {
+        newest.get(&group_key(entry)).map(String::as_str) == Some(entry.image.version())
+    });
+}
+
+fn group_key(entry: &IndexedImage) -> (String, String, String) {
+    (
+        entry.distro.clone(),
+        entry.release.clone(),
+        entry.image.arch_name().to_string(),
+    )
+}
+
+/// List every image `provider` publishes on `track`, for all its arches and
+/// releases. Releases that fail to list are reported and skipped, so one
+/// broken directory does not lose the rest of the distro.
+pub async fn crawl(
+    provider: &dyn ImageProvider,
+    client: &Client,
+    track: &str,
+) -> Result<Vec<IndexedImage>> {
+    let name = provider.display_name();
+    let mut found = Vec::new();
+    for arch in provider.supported_arches() {
+        let releases = match provider.releases(client, track, arch).await {
+            Ok(releases) => releases,
+            Err(err) => {
+                let arch = arch.name(provider.arch_naming());
+                eprintln!("Warning: skipping {name} {arch}: {err:#}");
+                continue;
+            }
+        };
+        let listings = stream::iter(releases)
+            .map(|release: Release| async move {
+                let images = provider.list(client, track, &release, arch).await;
+                (release, images)
+            })
+            .buffer_unordered(SYNC_CONCURRENCY)
+            .collect::<Vec<_>>()
+            .await;
+        for (release, images) in listings {
+            match images {
+                Ok(images) => found.extend(images.into_iter().map(|image| IndexedImage {
+                    distro: name.to_string(),
+                    release: release.id.clone(),
+                    image,
+                })),
+                Err(err) => eprintln!("Warning: skipping {name} {}: {err:#}", release.id),
+            }
+        }
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IndexedImage, MetadataIndex};
+    use crate::cloud::{Arch, sample_image};
+    use crate::repositories::ImageQuery;
+
+    fn entry(release: &str, version: &str, arch: Arch) -> IndexedImage {
+        IndexedImage {
+            distro: "Debian".to_string(),
+            release: release.to_string(),
+            image: sample_image()
+                .with_version(version)
+                .with_arch(arch)
+                .with_url(format!(
+                    "https://example.invalid/{release}/{version}/{arch:?}.qcow2"
+                )),
+        }
+    }
+
+    #[test]
+    fn answers_partial_queries_from_the_synced_images() {
+        let mut index = MetadataIndex::in_memory().unwrap();
+        assert!(!index.has_synced("debian").unwrap());
+        index
+            .replace(
+                "Debian",
+                &[
+                    entry("bookworm", "20250101-0000", Arch::Amd64),
+                    entry("bookworm", "20250210-2019", Arch::Amd64),
+                    entry("bookworm", "20250210-2019", Arch::Arm64),
+                    entry("trixie", "20250901-0000", Arch::Amd64),
+                ],
+            )
+            .unwrap();
+        assert!(index.has_synced("debian").unwrap());
+        assert_eq!(index.syncs().unwrap()[0].images, 4);
+
+        assert_eq!(index.query(&ImageQuery::new()).unwrap().len(), 4);
+        let bookworm = ImageQuery::new().distro("debian").release("BOOKWORM");
+        assert_eq!(index.query(&bookworm).unwrap().len(), 3);
+        let newest = index
+            .query(&bookworm.clone().arch(Arch::Amd64).latest())
+            .unwrap();
+        assert_eq!(newest, [entry("bookworm", "20250210-2019", Arch::Amd64)]);
+
+        index
+            .replace("Debian", &[entry("trixie", "20250901-0000", Arch::Amd64)])
+            .unwrap();
+        assert!(index.query(&bookworm).unwrap().is_empty());
+    }
+}
@@ -1,14 +1,45 @@
 pub mod almalinux;
+pub mod bench;
+#[cfg(feature = "index")]
+pub mod bundle;
 pub mod debian;
+#[cfg(feature = "index")]
+pub mod diff;
+#[cfg(feature = "index")]
+pub mod index;
 mod models;
+mod provider;
+mod query;
+#[cfg(feature = "index")]
+pub mod search;
 pub mod ubuntu;
 
-use std::{fs, path::Path, sync::OnceLock};
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError, RwLock},
+    time::Duration,
+};
 
-pub use models::Repository; // Re-export the model type to callers.
+use reqwest::Client;
 
-/// Single, module-private cache (set exactly once).
-static CACHE: OnceLock<Vec<Repository>> = OnceLock::new();
+use crate::helpers::{http_cache, retry};
+
+pub use models::{Refresh, Repository, Secret}; // Re-export the model types to callers.
+pub use provider::{ImageProvider, ProviderRegistration, Release, pick, provider, providers};
+pub use query::ImageQuery;
+
+/// Known repositories: loaded once by one of the `init_*` functions, then
+/// extended with [`register`] or patched with [`override_repository`].
+static REGISTRY: RwLock<Option<Vec<Arc<Repository>>>> = RwLock::new(None);
+
+/// Serialises [`scoped`] registries so parallel tests do not see each
+/// other's repositories.
+static SCOPE: Mutex<()> = Mutex::new(());
+
+/// Root picked for each repository name by [`active_root`].
+static ACTIVE_ROOTS: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
 
 // ---- Public API (serde hidden from callers) ----
 
@@ -19,16 +50,117 @@ pub fn init_from_file(path: impl AsRef<Path>) -> Result<(), ReposError> {
     init_from_json_str(&data)
 }
 
+/// Initialize from the repositories embedded in the crate
+/// ([`Repository::default_set`]).
+pub fn init_default() -> Result<(), ReposError> {
+    install(Repository::default_set())
+}
+
+/// Initialize from the embedded repositories, replaced or extended by name
+/// with those of the `overrides` JSON files (later files win), and apply the
+/// user's preferred mirror order (repository name -> roots, best first).
+pub fn init_layered(
+    overrides: &[&Path],
+    ranking: &HashMap<String, Vec<String>>,
+) -> Result<(), ReposError> {
+    let mut repos = Repository::default_set();
+    for path in overrides {
+        let data = fs::read_to_string(path).map_err(ReposError::Io)?;
+        let parsed: Vec<Repository> = serde_json::from_str(&data).map_err(ReposError::Json)?;
+        for repo in parsed {
+            match repos.iter_mut().find(|r| r.name() == repo.name()) {
+                Some(slot) => *slot = repo,
+                None => repos.push(repo),
+            }
+        }
+    }
+    for repo in &mut repos {
+        if let Some(order) = ranking.get(repo.name()) {
+            repo.set_ranking(order);
+        }
+    }
+    install(repos)
+}
+
 /// Initialize from a JSON string.
 #[allow(unused)]
 pub fn init_from_json_str(json: &str) -> Result<(), ReposError> {
     let parsed: Vec<Repository> = serde_json::from_str(json).map_err(ReposError::Json)?;
-    CACHE
-        .set(parsed)
-        .map_err(|_| ReposError::AlreadyInitialized)?;
+    install(parsed)
+}
+
+fn install(parsed: Vec<Repository>) -> Result<(), ReposError> {
+    let mut registry = REGISTRY.write().unwrap_or_else(PoisonError::into_inner);
+    if registry.is_some() {
+        return Err(ReposError::AlreadyInitialized);
+    }
+    *registry = Some(parsed.into_iter().map(Arc::new).collect());
+    Ok(())
+}
+
+/// Add `repo` to the loaded repositories. Names must stay unique; use
+/// [`override_repository`] to replace a configured one.
+pub fn register(repo: Repository) -> Result<(), ReposError> {
+    let mut registry = REGISTRY.write().unwrap_or_else(PoisonError::into_inner);
+    let repos = registry.as_mut().ok_or(ReposError::NotInitialized)?;
+    if repos.iter().any(|r| r.name() == repo.name()) {
+        return Err(ReposError::AlreadyRegistered(repo.name().to_string()));
+    }
+    repos.push(Arc::new(repo));
     Ok(())
 }
 
+/// Put `repo` in place of the repository with the same name (or add it)
+/// and forget the root probed for the old definition. Returns the replaced
+/// repository.
+pub fn override_repository(repo: Repository) -> Result<Option<Arc<Repository>>, ReposError> {
+    let mut registry = REGISTRY.write().unwrap_or_else(PoisonError::into_inner);
+    let repos = registry.as_mut().ok_or(ReposError::NotInitialized)?;
+    forget_active_root(repo.name());
+    let repo = Arc::new(repo);
+    match repos.iter_mut().find(|r| r.name() == repo.name()) {
+        Some(slot) => Ok(Some(std::mem::replace(slot, repo))),
+        None => {
+            repos.push(repo);
+            Ok(None)
+        }
+    }
+}
+
+/// Registry installed by [`scoped`]; restores the previous one on drop.
+pub struct ScopedRegistry {
+    previous: Option<Vec<Arc<Repository>>>,
+    _serial: MutexGuard<'static, ()>,
+}
+
+/// Replace the repositories with `repos` until the returned guard is
+/// dropped, e.g. to point a test at a local server. Scopes are serialised,
+/// so a second one waits for the first to end.
+pub fn scoped(repos: Vec<Repository>) -> ScopedRegistry {
+    let serial = SCOPE.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut registry = REGISTRY.write().unwrap_or_else(PoisonError::into_inner);
+    for repo in &repos {
+        forget_active_root(repo.name());
+    }
+    let previous = registry.replace(repos.into_iter().map(Arc::new).collect());
+    ScopedRegistry {
+        previous,
+        _serial: serial,
+    }
+}
+
+impl Drop for ScopedRegistry {
+    fn drop(&mut self) {
+        let mut registry = REGISTRY.write().unwrap_or_else(PoisonError::into_inner);
+        if let Some(repos) = registry.as_ref() {
+            for repo in repos {
+                forget_active_root(repo.name());
+            }
+        }
+        *registry = self.previous.take();
+    }
+}
+
 /// Initialize from an env var containing JSON.
 #[allow(unused)]
 pub fn init_from_env(var: &str) -> Result<(), ReposError> {
@@ -44,23 +176,137 @@ pub fn init_from_env(var: &str) -> Result<(), ReposError> {
 /// ```
 #[allow(unused)]
 pub fn all_owned() -> Result<Vec<Repository>, ReposError> {
-    Ok(CACHE.get().ok_or(ReposError::NotInitialized)?.clone())
+    Ok(all()?.iter().map(|repo| Repository::clone(repo)).collect())
 }
 
-/// Borrowing alternative to avoid cloning.
+/// Shared handles to the repositories, without cloning their definitions.
 #[allow(unused)]
-pub fn all() -> Result<&'static [Repository], ReposError> {
-    CACHE
-        .get()
-        .map(|v| v.as_slice())
+pub fn all() -> Result<Vec<Arc<Repository>>, ReposError> {
+    REGISTRY
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
         .ok_or(ReposError::NotInitialized)
 }
 
 /// Optional: find by name without cloning.
 #[allow(unused)]
-pub fn by_name(name: &str) -> Result<Option<&'static Repository>, ReposError> {
-    let repos = CACHE.get().ok_or(ReposError::NotInitialized)?;
-    Ok(repos.iter().find(|r| r.name() == name))
+pub fn by_name(name: &str) -> Result<Option<Arc<Repository>>, ReposError> {
+    let registry = REGISTRY.read().unwrap_or_else(PoisonError::into_inner);
+    let repos = registry.as_ref().ok_or(ReposError::NotInitialized)?;
+    Ok(repos.iter().find(|r| r.name() == name).cloned())
+}
+
+/// Drop the root remembered for the repository called `name`.
+fn forget_active_root(name: &str) {
+    if let Some(roots) = ACTIVE_ROOTS.get() {
+        roots.lock().unwrap_or_else(|e| e.into_inner()).remove(name);
+    }
+}
+
+/// Root to use for `repo`: the first of its roots (in preference order) that
+/// answers a `HEAD` request, with transient failures retried so a single
+/// dropped connection does not demote a root. The choice is probed once and
+/// then remembered; when nothing answers the preferred root is used so the
+/// caller reports the real error.
+pub async fn active_root(client: &Client, repo: &Repository) -> String {
+    let roots = ACTIVE_ROOTS.get_or_init(Default::default);
+    if let Some(root) = roots
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(repo.name())
+    {
+        return root.clone();
+    }
+
+    let candidates = repo.roots();
+    let mut chosen = candidates[0].to_string();
+    if candidates.len() > 1 {
+        for root in candidates {
+            match retry::send(|| client.head(root)).await {
+                Ok(res) if !res.status().is_server_error() => {
+                    chosen = root.to_string();
+                    break;
+                }
+                _ => eprintln!("Mirror {root} is unreachable, trying the next one"),
+            }
+        }
+    }
+
+    roots
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(repo.name().to_string(), chosen.clone());
+    chosen
+}
+
+/// Rewrite `url` (usually the repository template or one of its parameters)
+/// onto the active root of `repo`.
+pub async fn resolve(client: &Client, repo: &Repository, url: &str) -> String {
+    let root = active_root(client, repo).await;
+    repo.rebase(url, &root)
+}
+
+/// Every known location of `url`, starting with `url` itself, for download
+/// failover. URLs outside the configured repositories have no alternatives.
+pub fn mirror_urls(url: &str) -> Vec<String> {
+    all()
+        .ok()
+        .and_then(|repos| {
+            repos
+                .iter()
+                .map(|repo| repo.equivalent_urls(url))
+                .find(|urls| !urls.is_empty())
+        })
+        .unwrap_or_else(|| vec![url.to_string()])
+}
+
+/// Every root of the repositories that configure extra headers or
+/// credentials, paired with its repository.
+pub fn roots_with_headers() -> Result<Vec<(String, Arc<Repository>)>, ReposError> {
+    Ok(all()?
+        .into_iter()
+        .filter(|repo| !repo.headers().is_empty() || repo.auth().is_some())
+        .flat_map(|repo| {
+            repo.roots()
+                .into_iter()
+                .map(|root| (root.to_string(), Arc::clone(&repo)))
+                .collect::<Vec<_>>()
+        })
+        .collect())
+}
+
+/// Every root's URL prefixes paired with how long metadata below them stays
+/// fresh, from the repository's `refresh` or, by repository name, from
+/// `overrides`.
+pub fn refresh_rules(
+    overrides: &HashMap<String, Refresh>,
+) -> Result<Vec<(String, Duration)>, ReposError> {
+    let mut rules = Vec::new();
+    for repo in all()? {
+        let Some(refresh) = overrides.get(repo.name()).or(repo.refresh()) else {
+            continue;
+        };
+        let parse = |interval: &str| {
+            http_cache::parse_interval(interval)
+                .map_err(|e| ReposError::Refresh(repo.name().to_string(), e))
+        };
+        for root in repo.roots() {
+            match refresh {
+                Refresh::Every(interval) => rules.push((root.to_string(), parse(interval)?)),
+                Refresh::PerTrack(tracks) => {
+                    for (track, interval) in tracks {
+                        let prefix = match track.as_str() {
+                            "*" => root.to_string(),
+                            track => format!("{root}{}/", track.trim_matches('/')),
+                        };
+                        rules.push((prefix, parse(interval)?));
+                    }
+                }
+            }
+        }
+    }
+    Ok(rules)
 }
 
 /// ---- Errors ----
@@ -70,10 +316,89 @@ pub enum ReposError {
     NotInitialized,
     #[error("repositories already initialized")]
     AlreadyInitialized,
+    #[error("repository '{0}' is already registered")]
+    AlreadyRegistered(String),
     #[error("missing env var: {0}")]
     MissingEnv(String),
     #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
     #[error("JSON error: {0}")]
     Json(#[from] serde_json::Error),
+    #[error("refresh of repository '{0}': {1}")]
+    Refresh(String, String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Refresh, ReposError, Repository, by_name, override_repository, refresh_rules, register,
+        scoped,
+    };
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    fn repo(name: &str, url: &str) -> Repository {
+        serde_json::from_value(serde_json::json!({ "name": name, "url": url })).unwrap()
+    }
+
+    #[test]
+    fn scoped_registry_accepts_runtime_changes() {
+        {
+            let _registry = scoped(vec![repo("debian", "https://a.invalid/{}/")]);
+            register(repo("fedora", "https://f.invalid/{}/")).unwrap();
+            assert!(matches!(
+                register(repo("fedora", "https://g.invalid/{}/")),
+                Err(ReposError::AlreadyRegistered(_))
+            ));
+
+            let replaced = override_repository(repo("debian", "https://b.invalid/{}/")).unwrap();
+            assert_eq!(replaced.unwrap().url(), "https://a.invalid/{}/");
+            assert_eq!(
+                by_name("debian").unwrap().unwrap().url(),
+                "https://b.invalid/{}/"
+            );
+            assert!(by_name("fedora").unwrap().is_some());
+        }
+
+        // The previous (here: uninitialised) registry is back.
+        let _registry = scoped(Vec::new());
+        assert!(by_name("fedora").unwrap().is_none());
+    }
+
+    #[test]
+    fn refresh_intervals_cover_every_root_and_track() {
+        let ubuntu: Repository = serde_json::from_value(serde_json::json!({
+            "name": "ubuntu",
+            "url": "https://u.invalid/{}/streams/v1/index.json",
+            "mirrors": ["https://m.invalid/"],
+            "refresh": { "daily": "6h", "*": "1d" }
+        }))
+        .unwrap();
+        let _registry = scoped(vec![ubuntu, repo("debian", "https://d.invalid/{}/")]);
+
+        let mut rules = refresh_rules(&HashMap::new()).unwrap();
+        rules.sort();
+        let hours = |h: u64| Duration::from_secs(h * 3600);
+        assert_eq!(
+            rules,
+            [
+                ("https://m.invalid/".to_string(), hours(24)),
+                ("https://m.invalid/daily/".to_string(), hours(6)),
+                ("https://u.invalid/".to_string(), hours(24)),
+                ("https://u.invalid/daily/".to_string(), hours(6)),
+            ]
+        );
+
+        let overrides = HashMap::from([("debian".to_string(), Refresh::Every("7d".to_string()))]);
+        assert!(
+            refresh_rules(&overrides)
+                .unwrap()
+                .contains(&("https://d.invalid/".to_string(), hours(7 * 24)))
+        );
+        let broken = HashMap::from([("debian".to_string(), Refresh::Every("soon".to_string()))]);
+        assert!(matches!(
+            refresh_rules(&broken),
+            Err(ReposError::Refresh(..))
+        ));
+    }
 }
@@ -1,16 +1,97 @@
+use base64::{Engine, engine::general_purpose::STANDARD};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
+use super::ReposError;
+
+/// The `indexes.json` shipped inside the binary.
+const DEFAULT_INDEXES: &str = include_str!("../../resources/indexes.json");
+
 /// Public model; serde is confined to this module tree.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Repository {
     pub(crate) name: String,
     pub(crate) url: String,
+    /// Alternative roots serving the same tree. Each one replaces the part of
+    /// `url` in front of the first `{}` placeholder.
+    #[serde(default)]
+    pub(crate) mirrors: Vec<String>,
+    /// User preferred order of the roots (e.g. from `mirrors bench --save`).
+    #[serde(skip)]
+    pub(crate) ranking: Vec<String>,
+    /// Extra HTTP headers sent to every root of the repository.
+    #[serde(default)]
+    pub(crate) headers: HashMap<String, String>,
+    /// Credentials sent to every root of the repository.
+    #[serde(default)]
+    pub(crate) auth: Option<RepoAuth>,
+    /// How long fetched metadata is served from the cache before it is
+    /// revalidated.
+    #[serde(default)]
+    pub(crate) refresh: Option<Refresh>,
     #[serde(rename = "parameters")]
     pub(crate) other_parameters: Option<HashMap<String, String>>,
 }
 
+/// Refresh interval of a repository's metadata such as `"7d"`, or one per
+/// first path segment below its root, usually the track:
+/// `{"daily": "6h", "*": "1d"}`, where `*` covers the other segments.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Refresh {
+    Every(String),
+    PerTrack(HashMap<String, String>),
+}
+
+/// A secret given either inline or as the name of an environment variable, so
+/// `indexes.json` can be committed without the secret itself.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Secret {
+    Env { env: String },
+    Literal(String),
+}
+
+impl Secret {
+    /// The secret's value, read from the environment for `{ "env": ... }`.
+    pub fn resolve(&self) -> Result<String, ReposError> {
+        match self {
+            Secret::Literal(value) => Ok(value.clone()),
+            Secret::Env { env } => {
+                std::env::var(env).map_err(|_| ReposError::MissingEnv(env.clone()))
+            }
+        }
+    }
+}
+
+/// Authentication scheme of a password-protected mirror.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum RepoAuth {
+    Basic { username: String, password: Secret },
+    Bearer { token: Secret },
+}
+
+impl RepoAuth {
+    /// Value of the `Authorization` header.
+    pub fn header_value(&self) -> Result<String, ReposError> {
+        Ok(match self {
+            RepoAuth::Basic { username, password } => {
+                let credentials = format!("{username}:{}", password.resolve()?);
+                format!("Basic {}", STANDARD.encode(credentials))
+            }
+            RepoAuth::Bearer { token } => format!("Bearer {}", token.resolve()?),
+        })
+    }
+}
+
 impl Repository {
+    /// The repositories of the `indexes.json` compiled into the crate, so an
+    /// installed binary works without its source tree.
+    pub fn default_set() -> Vec<Repository> {
+        serde_json::from_str(DEFAULT_INDEXES).expect("the embedded indexes.json is valid")
+    }
+
     #[allow(unused)]
     // Borrowing getters (no clones).
     pub fn name(&self) -> &str {
@@ -26,4 +107,158 @@ impl Repository {
     pub fn other_parameters(&self) -> Option<&HashMap<String, String>> {
         self.other_parameters.as_ref()
     }
+
+    pub fn headers(&self) -> &HashMap<String, String> {
+        &self.headers
+    }
+
+    pub fn auth(&self) -> Option<&RepoAuth> {
+        self.auth.as_ref()
+    }
+
+    pub fn refresh(&self) -> Option<&Refresh> {
+        self.refresh.as_ref()
+    }
+
+    /// Static part of `url` in front of the first placeholder; every URL the
+    /// repository hands out starts with it.
+    pub fn root(&self) -> &str {
+        self.url
+            .split_once("{}")
+            .map_or(self.url.as_str(), |(prefix, _)| prefix)
+    }
+
+    /// All roots in preference order: the user ranking first, then the
+    /// primary root followed by the mirrors as listed.
+    pub fn roots(&self) -> Vec<&str> {
+        let mut roots: Vec<&str> = std::iter::once(self.root())
+            .chain(self.mirrors.iter().map(String::as_str))
+            .collect();
+        roots.sort_by_key(|root| {
+            self.ranking
+                .iter()
+                .position(|ranked| ranked == root)
+                .unwrap_or(usize::MAX)
+        });
+        roots
+    }
+
+    /// Prefer the roots in `ranking` (best first). Unknown entries are ignored.
+    pub(crate) fn set_ranking(&mut self, ranking: &[String]) {
+        self.ranking = ranking.to_vec();
+    }
+
+    /// Move `url` from the primary root onto `root`. URLs outside the
+    /// repository are returned unchanged.
+    pub fn rebase(&self, url: &str, root: &str) -> String {
+        match url.strip_prefix(self.root()) {
+            Some(rest) => format!("{root}{rest}"),
+            None => url.to_string(),
+        }
+    }
+
+    /// The same file on every root, starting with `url` itself. Empty when
+    /// `url` does not belong to this repository.
+    pub fn equivalent_urls(&self, url: &str) -> Vec<String> {
+        let roots = self.roots();
+        let Some((matched, rest)) = roots
+            .iter()
+            .find_map(|root| url.strip_prefix(root).map(|rest| (root, rest)))
+        else {
+            return Vec::new();
+        };
+
+        std::iter::once(url.to_string())
+            .chain(
+                roots
+                    .iter()
+                    .filter(|root| **root != *matched)
+                    .map(|root| format!("{root}{rest}")),
+            )
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RepoAuth, Repository};
+
+    fn repo() -> Repository {
+        Repository {
+            name: "almalinux".into(),
+            url: "https://repo.almalinux.org/almalinux/{}/cloud/{}/images/".into(),
+            mirrors: vec!["https://mirror.example.org/almalinux/".into()],
+            ranking: Vec::new(),
+            headers: Default::default(),
+            auth: None,
+            refresh: None,
+            other_parameters: None,
+        }
+    }
+
+    #[test]
+    fn rebases_onto_mirror_root() {
+        let repo = repo();
+        assert_eq!(repo.root(), "https://repo.almalinux.org/almalinux/");
+        assert_eq!(
+            repo.rebase(repo.url(), "https://mirror.example.org/almalinux/"),
+            "https://mirror.example.org/almalinux/{}/cloud/{}/images/"
+        );
+        assert_eq!(
+            repo.rebase("https://other/x", "https://m/"),
+            "https://other/x"
+        );
+    }
+
+    #[test]
+    fn lists_equivalent_urls_starting_with_the_original() {
+        let repo = repo();
+        let url = "https://mirror.example.org/almalinux/9/cloud/x86_64/images/a.qcow2";
+        assert_eq!(
+            repo.equivalent_urls(url),
+            vec![
+                url.to_string(),
+                "https://repo.almalinux.org/almalinux/9/cloud/x86_64/images/a.qcow2".to_string(),
+            ]
+        );
+        assert!(repo.equivalent_urls("https://elsewhere/a.qcow2").is_empty());
+    }
+
+    #[test]
+    fn embeds_the_default_repositories() {
+        let names: Vec<String> = Repository::default_set()
+            .iter()
+            .map(|r| r.name().to_string())
+            .collect();
+        for name in ["ubuntu", "debian", "almalinux"] {
+            assert!(names.iter().any(|n| n == name), "{name} missing");
+        }
+    }
+
+    #[test]
+    fn ranking_reorders_roots() {
+        let mut repo = repo();
+        repo.set_ranking(&["https://mirror.example.org/almalinux/".to_string()]);
+        assert_eq!(
+            repo.roots(),
+            vec![
+                "https://mirror.example.org/almalinux/",
+                "https://repo.almalinux.org/almalinux/",
+            ]
+        );
+    }
+
+    #[test]
+    fn builds_authorization_headers() {
+        let basic: RepoAuth =
+            serde_json::from_str(r#"{ "type": "basic", "username": "ci", "password": "secret" }"#)
+                .unwrap();
+        assert_eq!(basic.header_value().unwrap(), "Basic Y2k6c2VjcmV0");
+
+        let bearer: RepoAuth = serde_json::from_str(
+            r#"{ "type": "bearer", "token": { "env": "CID_TEST_UNSET_TOKEN_VAR" } }"#,
+        )
+        .unwrap();
+        assert!(bearer.header_value().is_err());
+    }
 }
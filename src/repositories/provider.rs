@@ -0,0 +1,408 @@
+use anyhow::{Context, Result, anyhow, ensure};
+use async_trait::async_trait;
+use reqwest::Client;
+
+use crate::cloud::{Arch, ArchNaming, Image};
+use crate::helpers::{
+    cancel::CancellationToken,
+    checksum::ChecksumSource,
+    choose_one, http, human_size,
+    image_resolver::content_lengths,
+    selection::{Facet, SelectionPipeline, newest_build},
+    trace,
+};
+use crate::repositories::{
+    ImageQuery, almalinux::AlmaLinuxProvider, debian::DebianProvider, ubuntu::UbuntuProvider,
+};
+
+/// The distros built into the crate, in menu order.
+static PROVIDERS: &[&dyn ImageProvider] = &[&UbuntuProvider, &DebianProvider, &AlmaLinuxProvider];
+
+/// A provider added by a crate embedding this one, see
+/// [`register_provider!`](crate::register_provider).
+pub struct ProviderRegistration(&'static dyn ImageProvider);
+
+impl ProviderRegistration {
+    pub const fn new(provider: &'static dyn ImageProvider) -> Self {
+        Self(provider)
+    }
+}
+
+inventory::collect!(ProviderRegistration);
+
+/// Add a provider to the wizard, the CLI and the library entry points from
+/// any crate linked into the program, without touching this one:
+///
+/// ```ignore
+/// struct FedoraProvider;
+///
+/// #[async_trait::async_trait]
+/// impl ImageProvider for FedoraProvider { /* ... */ }
+///
+/// cloud_images_downloader::register_provider!(FedoraProvider);
+/// ```
+///
+/// Registered providers follow the built-in ones in the menu, ordered by
+/// name; one whose name is already taken is ignored.
+#[macro_export]
+macro_rules! register_provider {
+    ($provider:expr) => {
+        $crate::inventory::submit! {
+            $crate::repositories::ProviderRegistration::new(&$provider)
+        }
+    };
+}
+
+/// A release as offered by a provider: an Ubuntu version, a Debian codename
+/// or an AlmaLinux major.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Release {
+    /// Identifier understood by the provider, e.g. `bookworm`.
+    pub id: String,
+    /// Menu entry, e.g. `12 (bookworm)`.
+    pub label: String,
+    /// Distro version carried by every image of the release, when known up
+    /// front. Otherwise the wizard asks for it.
+    pub distro_version: Option<String>,
+}
+
+impl Release {
+    /// A release labelled by its identifier.
+    pub fn new(id: impl Into<String>) -> Self {
+        let id = id.into();
+        Self {
+            label: id.clone(),
+            id,
+            distro_version: None,
+        }
+    }
+}
+
+/// A distro publishing cloud images. The wizard, the CLI and the library
+/// entry points only talk to distros through this trait.
+#[async_trait]
+pub trait ImageProvider: Send + Sync {
+    /// Name shown in menus and accepted by `--distro` (case-insensitively).
+    fn display_name(&self) -> &'static str;
+
+    /// Architectures the distro publishes images for.
+    fn supported_arches(&self) -> Vec<Arch>;
+
+    /// How the distro spells architecture names in menus and URLs.
+    fn arch_naming(&self) -> ArchNaming {
+        ArchNaming::Debian
+    }
+
+    /// Releases published for `arch`, newest first.
+    async fn releases(&self, client: &Client, track: &str, arch: Arch) -> Result<Vec<Release>>;
+
+    /// The release called `id`, looked up in [`releases`](Self::releases)
+    /// unless the provider can describe it more cheaply.
+    async fn release(&self, client: &Client, track: &str, arch: Arch, id: &str) -> Result<Release> {
+        let releases = self.releases(client, track, arch).await?;
+        let available = releases
+            .iter()
+            .map(|r| r.id.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        releases
+            .iter()
+            .find(|r| r.id.eq_ignore_ascii_case(id))
+            .cloned()
+            .ok_or_else(|| {
+                anyhow!(
+                    "'{id}' is not a {} release (available: {available})",
+                    self.display_name()
+                )
+            })
+    }
+
+    /// Every image of `release` built for `arch`.
+    async fn list(
+        &self,
+        client: &Client,
+        track: &str,
+        release: &Release,
+        arch: Arch,
+    ) -> Result<Vec<Image>>;
+
+    /// Images of the newest build of `release` for `arch`.
+    async fn latest(
+        &self,
+        client: &Client,
+        track: &str,
+        release: &Release,
+        arch: Arch,
+    ) -> Result<Vec<Image>> {
+        let mut images = self.list(client, track, release, arch).await?;
+        if let Some(newest) = newest_build(images.iter().map(|i| i.version())) {
+            trace::retain("latest", &mut images, |i| {
+                (i.version() != newest)
+                    .then(|| format!("build {} is older than {newest}", i.version()))
+            });
+        }
+        Ok(images)
+    }
+
+    /// Images matching `query` without prompting; its release and arch are
+    /// required and `latest` keeps the newest build only.
+    async fn query(&self, client: &Client, track: &str, query: &ImageQuery) -> Result<Vec<Image>> {
+        let name = self.display_name();
+        let arch = query
+            .arch_value()
+            .with_context(|| format!("a {name} query needs an architecture"))?;
+        let id = query
+            .release_id()
+            .with_context(|| format!("a {name} query needs a release"))?;
+        let release = self.release(client, track, arch, id).await?;
+        trace::reset();
+        let mut images = if query.wants_latest() {
+            self.latest(client, track, &release, arch).await?
+        } else {
+            self.list(client, track, &release, arch).await?
+        };
+        trace::retain("query", &mut images, |i| query.mismatch(i));
+        Ok(images)
+    }
+
+    /// Version of `image` as shown in the final selection.
+    fn version_label(&self, _release: &Release, image: &Image) -> String {
+        image.version().to_string()
+    }
+
+    /// Listing `image`'s checksum is published in, read again when a
+    /// download of it fails verification; `None` leaves the sidecar files
+    /// next to the image.
+    fn checksum_source(&self, _image: &Image) -> Option<ChecksumSource> {
+        None
+    }
+}
+
+/// Every provider, in menu order: the built-in ones, then those added with
+/// [`register_provider!`](crate::register_provider) by name.
+pub fn providers() -> Vec<&'static dyn ImageProvider> {
+    let mut registered: Vec<&'static dyn ImageProvider> = inventory::iter::<ProviderRegistration>
+        .into_iter()
+        .map(|r| r.0)
+        .collect();
+    registered.sort_by_key(|p| p.display_name());
+
+    let mut all: Vec<&'static dyn ImageProvider> = PROVIDERS.to_vec();
+    for provider in registered {
+        let name = provider.display_name();
+        if !all
+            .iter()
+            .any(|p| p.display_name().eq_ignore_ascii_case(name))
+        {
+            all.push(provider);
+        }
+    }
+    all
+}
+
+/// The provider called `name` (case-insensitively).
+pub fn provider(name: &str) -> Option<&'static dyn ImageProvider> {
+    providers()
+        .into_iter()
+        .find(|p| p.display_name().eq_ignore_ascii_case(name))
+}
+
+/// Walk the user from architecture to a single artifact of `provider`:
+/// arch -> release -> distro version -> build -> variant -> format ->
+/// artifact. Steps already answered by `query` are skipped and
+/// `max_builds` trims the build menu to the most recent builds. Cancelling
+/// `cancel` aborts the listing requests in flight.
+pub async fn pick(
+    provider: &dyn ImageProvider,
+    track: &str,
+    query: &ImageQuery,
+    max_builds: Option<usize>,
+    cancel: &CancellationToken,
+) -> Result<(Release, Image)> {
+    let name = provider.display_name();
+    let client = http::client();
+
+    // 1) Arch; a preset may use any distro's spelling (x86_64 or amd64)
+    let naming = provider.arch_naming();
+    let arches = provider.supported_arches();
+    let arch_names: Vec<&str> = arches.iter().map(|a| a.name(naming)).collect();
+    let arch = match query.arch_value() {
+        Some(preset) => {
+            ensure!(
+                arches.contains(&preset),
+                "'{}' is not a {name} architecture (available: {})",
+                preset.name(naming),
+                arch_names.join(", ")
+            );
+            preset
+        }
+        None => {
+            let choice = choose_one("Select Architecture", arch_names)?;
+            Arch::from_name(&choice).expect("menu entries are known architectures")
+        }
+    };
+
+    // 2) Release
+    let release = match query.release_id() {
+        Some(id) => {
+            let release = provider.release(client, track, arch, id);
+            cancel.run("release lookup", release).await??
+        }
+        None => {
+            let releases = provider.releases(client, track, arch);
+            let releases = cancel.run("release listing", releases).await??;
+            ensure!(!releases.is_empty(), "No {name} releases available");
+            let labels = releases.iter().map(|r| r.label.clone()).collect();
+            let choice = choose_one(&format!("Select {name} Release"), labels)?;
+            releases
+                .into_iter()
+                .find(|r| r.label == choice)
+                .expect("chosen label must map to a release")
+        }
+    };
+
+    // 3) Fetch images for the chosen release and arch; only the newest
+    // build for `latest`
+    trace::reset();
+    let listing = if query.wants_latest() {
+        provider.latest(client, track, &release, arch)
+    } else {
+        provider.list(client, track, &release, arch)
+    };
+    let images = cancel
+        .run("image listing", listing)
+        .await?
+        .with_context(|| {
+            format!(
+                "fetch {name} images for release='{}' arch='{arch}'",
+                release.id
+            )
+        })?;
+    ensure!(
+        !images.is_empty(),
+        "No {name} images found for release={} arch={arch}",
+        release.id
+    );
+
+    // 4) Distro version (e.g. "12" for bookworm, "9.4" for AlmaLinux 9),
+    // build, variant and format
+    let newest = newest_build(images.iter().map(|i| i.version()));
+    let build = match query.build_id() {
+        Some(_) if query.wants_latest() => newest.as_deref(),
+        build => build,
+    };
+    let mut images = SelectionPipeline::new(name)
+        .facet(
+            Facet::new("distro_version", "Select Distro Version", |i| {
+                Some(i.distro_version())
+            })
+            .preset(release.distro_version.as_deref())
+            .newest_first(),
+        )
+        .facet(
+            Facet::new("version", "Select Image Version", |i| Some(i.version()))
+                .preset(build)
+                .builds(max_builds),
+        )
+        .facet(
+            Facet::new("variant", "Select Image Variant", |i| {
+                Some(i.variant().as_str())
+            })
+            .preset(query.variant_value().map(|v| v.as_str())),
+        )
+        .facet(
+            Facet::new("format", "Select Image Format", |i| {
+                Some(i.format().as_str()).filter(|f| !f.is_empty())
+            })
+            .preset(query.format_value().map(|f| f.as_str())),
+        )
+        .run(images)?;
+
+    if images.len() == 1 {
+        return Ok((release, images.remove(0)));
+    }
+
+    // 5) Several artifacts left: let the user pick one. Sizes the metadata
+    // leaves out are asked from the server.
+    let unknown: Vec<&str> = images
+        .iter()
+        .filter(|i| i.size().is_none())
+        .map(|i| i.url())
+        .collect();
+    let mut looked_up = cancel
+        .run("size lookup", content_lengths(&unknown))
+        .await?
+        .into_iter();
+    let sizes: Vec<Option<u64>> = images
+        .iter()
+        .map(|i| i.size().or_else(|| looked_up.next().flatten()))
+        .collect();
+    let labels: Vec<String> = images
+        .iter()
+        .zip(sizes)
+        .map(|(i, size)| {
+            format!(
+                "{} | {} | {} | {} | {} | {} | {}",
+                i.name(),
+                i.variant(),
+                i.format(),
+                i.version(),
+                i.arch_name(),
+                human_size(size),
+                i.url()
+            )
+        })
+        .collect();
+    let chosen_label = choose_one("Select Image Artifact", labels.clone())?;
+
+    let idx = labels
+        .iter()
+        .position(|l| *l == chosen_label)
+        .expect("selected label must match one candidate");
+
+    Ok((release, images.swap_remove(idx)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ImageProvider, Release, provider, providers};
+    use crate::cloud::{Arch, Image};
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use reqwest::Client;
+
+    /// Registered the way an embedding crate would.
+    struct PrivateProvider(&'static str);
+
+    #[async_trait]
+    impl ImageProvider for PrivateProvider {
+        fn display_name(&self) -> &'static str {
+            self.0
+        }
+
+        fn supported_arches(&self) -> Vec<Arch> {
+            vec![Arch::Amd64]
+        }
+
+        async fn releases(&self, _: &Client, _: &str, _: Arch) -> Result<Vec<Release>> {
+            Ok(Vec::new())
+        }
+
+        async fn list(&self, _: &Client, _: &str, _: &Release, _: Arch) -> Result<Vec<Image>> {
+            Ok(Vec::new())
+        }
+    }
+
+    crate::register_provider!(PrivateProvider("Internal"));
+    // Built-in names cannot be taken over.
+    crate::register_provider!(PrivateProvider("ubuntu"));
+
+    #[test]
+    fn registry_finds_providers_case_insensitively() {
+        let names: Vec<&str> = providers().iter().map(|p| p.display_name()).collect();
+        assert_eq!(names, ["Ubuntu", "Debian", "AlmaLinux", "Internal"]);
+        assert_eq!(provider("almalinux").unwrap().display_name(), "AlmaLinux");
+        assert_eq!(provider("internal").unwrap().display_name(), "Internal");
+        assert!(provider("fedora").is_none());
+    }
+}
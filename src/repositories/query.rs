@@ -0,0 +1,166 @@
+use crate::cloud::{Arch, Image, ImageFormat, Variant};
+
+/// Build the newest (or any listed) build resolves to, see
+/// [`ImageQuery::latest`].
+const LATEST: &str = "latest";
+
+/// Partially or fully specified image selection, built step by step:
+///
+/// ```
+/// use cloud_images_downloader::cloud::Arch;
+/// use cloud_images_downloader::repositories::ImageQuery;
+///
+/// let query = ImageQuery::new()
+///     .distro("debian")
+///     .release("bookworm")
+///     .arch(Arch::Amd64)
+///     .variant("genericcloud")
+///     .format("qcow2")
+///     .latest();
+/// assert_eq!(query.release_id(), Some("bookworm"));
+/// ```
+///
+/// The wizard skips every step the query answers and prompts for the
+/// others; [`ImageProvider::query`](super::ImageProvider::query) filters
+/// without prompting.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImageQuery {
+    distro: Option<String>,
+    release: Option<String>,
+    arch: Option<Arch>,
+    build: Option<String>,
+    variant: Option<Variant>,
+    format: Option<ImageFormat>,
+}
+
+impl ImageQuery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Provider by display name, case-insensitively (`ubuntu`, `Debian`).
+    pub fn distro(mut self, distro: impl Into<String>) -> Self {
+        self.distro = Some(distro.into());
+        self
+    }
+
+    /// Ubuntu version (`24.04`), Debian codename (`bookworm`) or AlmaLinux
+    /// major (`9`).
+    pub fn release(mut self, release: impl Into<String>) -> Self {
+        self.release = Some(release.into());
+        self
+    }
+
+    pub fn arch(mut self, arch: Arch) -> Self {
+        self.arch = Some(arch);
+        self
+    }
+
+    /// Image build, e.g. `20241013-1744`.
+    pub fn build(mut self, build: impl Into<String>) -> Self {
+        self.build = Some(build.into());
+        self
+    }
+
+    /// The newest build of the release.
+    pub fn latest(self) -> Self {
+        self.build(LATEST)
+    }
+
+    /// Image variant, e.g. `genericcloud`.
+    pub fn variant(mut self, variant: &str) -> Self {
+        self.variant = Some(Variant::from_name(variant));
+        self
+    }
+
+    /// Disk format, e.g. `qcow2`.
+    pub fn format(mut self, format: &str) -> Self {
+        self.format = Some(ImageFormat::from_name(format));
+        self
+    }
+
+    pub fn distro_name(&self) -> Option<&str> {
+        self.distro.as_deref()
+    }
+
+    pub fn release_id(&self) -> Option<&str> {
+        self.release.as_deref()
+    }
+
+    pub fn arch_value(&self) -> Option<Arch> {
+        self.arch
+    }
+
+    pub fn build_id(&self) -> Option<&str> {
+        self.build.as_deref()
+    }
+
+    pub fn variant_value(&self) -> Option<&Variant> {
+        self.variant.as_ref()
+    }
+
+    pub fn format_value(&self) -> Option<&ImageFormat> {
+        self.format.as_ref()
+    }
+
+    /// Whether the query asks for the newest build.
+    pub fn wants_latest(&self) -> bool {
+        self.build.as_deref() == Some(LATEST)
+    }
+
+    /// Whether `image` satisfies the build, variant and format of the query.
+    /// The newest build is only known for a whole listing, so `latest`
+    /// matches every build here.
+    pub fn matches(&self, image: &Image) -> bool {
+        self.mismatch(image).is_none()
+    }
+
+    /// Why `image` does not satisfy the query, or `None` when it does.
+    pub fn mismatch(&self, image: &Image) -> Option<String> {
+        if let Some(build) = self.build.as_deref()
+            && build != LATEST
+            && image.version() != build
+        {
+            return Some(format!("build is {}, wanted {build}", image.version()));
+        }
+        if let Some(variant) = &self.variant
+            && !image
+                .variant()
+                .as_str()
+                .eq_ignore_ascii_case(variant.as_str())
+        {
+            return Some(format!("variant is {}, wanted {variant}", image.variant()));
+        }
+        if let Some(format) = &self.format
+            && image.format() != format
+        {
+            return Some(format!("format is {}, wanted {format}", image.format()));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ImageQuery;
+    use crate::cloud::{Image, sample_image};
+
+    fn image(version: &str, url: &str) -> Image {
+        sample_image().with_version(version).with_url(url)
+    }
+
+    #[test]
+    fn matches_build_variant_and_format() {
+        let qcow2 = image("20250101-0000", "https://x.invalid/a.qcow2");
+        let raw = image("20250101-0000", "https://x.invalid/a.raw");
+
+        let query = ImageQuery::new().variant("GenericCloud").format("QCOW2");
+        assert!(query.matches(&qcow2));
+        assert!(!query.matches(&raw));
+
+        assert!(query.clone().latest().matches(&qcow2));
+        assert!(query.clone().latest().wants_latest());
+        assert!(!query.build("20240101-0000").matches(&qcow2));
+        assert!(ImageQuery::new().matches(&raw));
+    }
+}
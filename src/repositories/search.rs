@@ -0,0 +1,138 @@
+use std::cmp::Reverse;
+
+use super::index::IndexedImage;
+use crate::cloud::BuildId;
+
+/// How well one search term matched a field; better matches rank first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum TermMatch {
+    /// The term's letters appear in order (`jmy` in `jammy`).
+    Fuzzy = 1,
+    /// The term is part of the field (`min` in `minimal`).
+    Substring = 2,
+    /// The term is the whole field.
+    Exact = 3,
+}
+
+fn match_term(term: &str, field: &str) -> Option<TermMatch> {
+    let field = field.to_ascii_lowercase();
+    if field == term {
+        return Some(TermMatch::Exact);
+    }
+    if field.contains(term) {
+        return Some(TermMatch::Substring);
+    }
+    let mut letters = field.chars();
+    term.chars()
+        .all(|c| letters.any(|f| f == c))
+        .then_some(TermMatch::Fuzzy)
+}
+
+/// Everything a search term is compared with: distro, os, release and its
+/// codename, versions, arch, variant, format and file name.
+fn fields(entry: &IndexedImage) -> Vec<String> {
+    let image = &entry.image;
+    let file = image.url().rsplit('/').next().unwrap_or_default();
+    vec![
+        entry.distro.clone(),
+        image.os().to_string(),
+        entry.release.clone(),
+        image.name().to_string(),
+        image.distro_version().to_string(),
+        image.version().to_string(),
+        image.arch_name().to_string(),
+        image.variant().to_string(),
+        image.format().to_string(),
+        file.to_string(),
+    ]
+}
+
+/// Score of `entry` for the lower-case `terms`: the sum of each term's best
+/// match, or `None` when a term matches no field.
+fn score(entry: &IndexedImage, terms: &[String]) -> Option<u32> {
+    let fields = fields(entry);
+    terms.iter().try_fold(0, |total, term| {
+        let best = fields.iter().filter_map(|f| match_term(term, f)).max()?;
+        Some(total + best as u32)
+    })
+}
+
+/// The `candidates` matching every whitespace separated term of `pattern`
+/// (ignoring case), best match first and newest build first among equals.
+pub fn search(candidates: Vec<IndexedImage>, pattern: &str) -> Vec<IndexedImage> {
+    let terms: Vec<String> = pattern
+        .split_whitespace()
+        .map(str::to_ascii_lowercase)
+        .collect();
+    let mut found: Vec<(u32, IndexedImage)> = candidates
+        .into_iter()
+        .filter_map(|entry| Some((score(&entry, &terms)?, entry)))
+        .collect();
+    found.sort_by_cached_key(|(score, entry)| {
+        (
+            Reverse(*score),
+            Reverse(BuildId::new(entry.image.version())),
+            entry.image.url().to_string(),
+        )
+    });
+    found.into_iter().map(|(_, entry)| entry).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::search;
+    use crate::cloud::{Arch, Variant, sample_image};
+    use crate::repositories::index::IndexedImage;
+
+    fn entry(
+        distro: &str,
+        release: &str,
+        codename: &str,
+        variant: Variant,
+        arch: Arch,
+    ) -> IndexedImage {
+        IndexedImage {
+            distro: distro.to_string(),
+            release: release.to_string(),
+            image: sample_image()
+                .with_release(&distro.to_ascii_lowercase(), codename, release)
+                .with_version("20250210")
+                .with_arch(arch)
+                .with_url(format!(
+                    "https://example.invalid/{codename}-{variant}-{arch:?}.img"
+                ))
+                .with_variant(variant),
+        }
+    }
+
+    #[test]
+    fn every_term_has_to_match_some_field() {
+        let jammy_minimal_arm = entry("Ubuntu", "22.04", "jammy", Variant::Minimal, Arch::Arm64);
+        let candidates = vec![
+            entry(
+                "Ubuntu",
+                "22.04",
+                "jammy",
+                Variant::GenericCloud,
+                Arch::Arm64,
+            ),
+            jammy_minimal_arm.clone(),
+            entry("Ubuntu", "22.04", "jammy", Variant::Minimal, Arch::Amd64),
+            entry(
+                "Debian",
+                "12",
+                "bookworm",
+                Variant::GenericCloud,
+                Arch::Arm64,
+            ),
+        ];
+
+        assert_eq!(
+            search(candidates.clone(), "jammy minimal arm64"),
+            [jammy_minimal_arm]
+        );
+        assert_eq!(search(candidates.clone(), "JMY MIN aarch64").len(), 0);
+        assert_eq!(search(candidates.clone(), "jmy min arm").len(), 1);
+        assert_eq!(search(candidates, "bookworm").len(), 1);
+    }
+}
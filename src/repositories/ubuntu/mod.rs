@@ -1,168 +1,108 @@
-use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+use crate::cloud::{Arch, IMAGE_DOWNLOADS, StreamIndex, sort_newest_first};
 pub use crate::cloud::{Catalog, Image};
-use crate::helpers::{arch_options_for, choose_one};
-use crate::repositories;
-
-use anyhow::{Context, Result, bail, ensure};
+use crate::helpers::{
+    checksum::ChecksumSource,
+    gpg::{self, Signing},
+    http_cache, sanitize, trace, trust,
+};
+use crate::repositories::{self, ImageProvider, Release};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
 use reqwest::Client;
-use std::fs;
-use std::io::Write;
-
-/// Build a human readable label for the picker so users can distinguish very
-/// similar images at a glance.
-fn format_image_label(image: &Image) -> String {
-    format!("{} | {} | {}", image.name(), image.arch(), image.url())
-}
-
-/// Picking ubuntu
-pub async fn pick_ubuntu(track: &str) -> Result<Image> {
-    // 1) Arch
-    let arch = choose_one("Select Architecture", arch_options_for("Ubuntu"))?;
-
-    // 2) Fetch images for the chosen arch
-    let mut images: Vec<Image> = ubuntu_list(track, &arch, false)
-        .await
-        .with_context(|| format!("fetch ubuntu images for track='{track}' arch='{arch}'"))?;
-
-    ensure!(!images.is_empty(), "No Ubuntu images found for arch={arch}");
-
-    // 3) Distro version (filter the working set after selection)
-    let mut distro_versions = images
-        .iter()
-        .map(|i| i.distro_version().to_string())
-        .collect::<Vec<_>>();
-    distro_versions.sort();
-    distro_versions.reverse();
-    distro_versions.dedup();
-
-    let distro_version = choose_one("Select Distro Version", distro_versions)?;
-    images.retain(|i| i.distro_version() == distro_version);
-    ensure!(
-        !images.is_empty(),
-        "No Ubuntu images found for distro_version={distro_version}"
-    );
-
-    // 4) Image version (filter again after selection)
-    let mut image_versions: Vec<String> = images
-        .iter()
-        .map(|i| i.version().to_string())
-        .collect::<Vec<_>>();
-    image_versions.sort();
-    image_versions.reverse();
-    image_versions.dedup();
-
-    let image_version = choose_one("Select Image Version", image_versions)?;
-    images.retain(|i| i.version() == image_version);
-    ensure!(
-        !images.is_empty(),
-        "No Ubuntu images found for distro_version={distro_version} and version={image_version}"
-    );
-
-    // 5) Pick image type (now uses the model's image_type; filter again after selection)
-    let mut image_types: Vec<String> = images.iter().map(|i| i.image_type().to_string()).collect();
-    image_types.sort();
-    image_types.dedup();
 
-    let image_type = choose_one("Select image type", image_types)?;
-    images.retain(|i| i.image_type() == image_type);
-    ensure!(
-        !images.is_empty(),
-        "No Ubuntu images found for distro_version={distro_version}, version={image_version}, type={image_type}"
-    );
+/// Ubuntu images from Canonical's Simplestreams index.
+pub struct UbuntuProvider;
 
-    // 6) If a version maps to multiple artifacts, let the user pick one (now the working set is already scoped)
-    let chosen_label = choose_one(
-        "Select Image Artifact",
-        images.iter().map(format_image_label).collect(),
-    )?;
-
-    // Find back the chosen image
-    let idx = images
-        .iter()
-        .position(|i| format_image_label(i) == chosen_label)
-        .expect("selected label must match one candidate");
-
-    Ok(images[idx].clone())
-}
-
-/// Download the JSON at `url` into `dest_path` inside the temp folder.
-/// Returns the full path of the saved file.
-/// Download the remote Simplestreams document into a deterministic location so
-/// future runs can reuse the cached copy.
-async fn fetch_repo_json_file_to_tmp(url: &str, dest_path: &Path) -> Result<PathBuf> {
-    let client = Client::builder().build()?;
-
-    let res = client
-        .get(url)
-        .header("User-Agent", "cloud-index-reader-rust/1.0")
-        .send()
-        .await
-        .with_context(|| format!("GET {}", url))?;
-
-    let status = res.status();
-    if !status.is_success() {
-        bail!("HTTP {} for {}", status, url);
+#[async_trait]
+impl ImageProvider for UbuntuProvider {
+    fn display_name(&self) -> &'static str {
+        "Ubuntu"
     }
 
-    let bytes = res
-        .bytes()
-        .await
-        .with_context(|| format!("read body from {}", url))?;
-
-    if let Some(parent) = dest_path.parent() {
-        fs::create_dir_all(parent).with_context(|| format!("create dir {}", parent.display()))?;
+    fn supported_arches(&self) -> Vec<Arch> {
+        vec![Arch::Amd64, Arch::Arm64, Arch::Ppc64le, Arch::S390x]
     }
 
-    // Write atomically: write to a tmp file then rename.
-    let tmp = dest_path.with_extension("download");
-    let mut file =
-        fs::File::create(&tmp).with_context(|| format!("create file {}", tmp.display()))?;
-    file.write_all(&bytes)
-        .with_context(|| format!("write file {}", tmp.display()))?;
-    drop(file);
+    /// Ubuntu versions (`24.04`, ...) with images for `arch` on `track`.
+    async fn releases(&self, client: &Client, track: &str, arch: Arch) -> Result<Vec<Release>> {
+        let images = ubuntu_list(client, track, arch, false)
+            .await
+            .with_context(|| format!("fetch ubuntu images for track='{track}' arch='{arch}'"))?;
+        let mut versions: Vec<&str> = images.iter().map(|i| i.distro_version()).collect();
+        sort_newest_first(&mut versions);
+        Ok(versions
+            .into_iter()
+            .map(|version| Release {
+                distro_version: Some(version.to_string()),
+                ..Release::new(version)
+            })
+            .collect())
+    }
 
-    fs::rename(&tmp, dest_path)
-        .with_context(|| format!("move {} -> {}", tmp.display(), dest_path.display()))?;
+    async fn list(
+        &self,
+        client: &Client,
+        track: &str,
+        release: &Release,
+        arch: Arch,
+    ) -> Result<Vec<Image>> {
+        let mut images = ubuntu_list(client, track, arch, false).await?;
+        trace::retain("release", &mut images, |i| {
+            (i.distro_version() != release.id)
+                .then(|| format!("release is {}, wanted {}", i.distro_version(), release.id))
+        });
+        Ok(images)
+    }
 
-    Ok(dest_path.to_path_buf())
+    /// The signed `SHA256SUMS` Canonical publishes in every build
+    /// directory; the Simplestreams index may lag behind it.
+    fn checksum_source(&self, image: &Image) -> Option<ChecksumSource> {
+        let (dir, _) = image.url().rsplit_once('/')?;
+        Some(ChecksumSource::Combined {
+            url: format!("{dir}/SHA256SUMS"),
+            repo: "ubuntu",
+            signing: Some(Signing::Detached(".gpg")),
+        })
+    }
 }
 
-/// Build a catalogue by reading JSON either from a cached temp file (if it exists)
-/// or by downloading it once and caching it. Deserializes into `T`.
-async fn construct_repo_catalogue<T: for<'de> serde::Deserialize<'de>>(url: &str) -> Result<T> {
-    // Decide the filename from the URL (fallback to "repo.json")
-    let file_name = url
-        .rsplit('/')
-        .next()
-        .filter(|s| !s.is_empty())
-        .unwrap_or("repo.json");
-
-    // Get json file from temp folder
-    let mut tmp_path: PathBuf = std::env::temp_dir();
-    tmp_path.push(file_name);
-
-    // If file does not exist, download it to tmp first
-    if !tmp_path.exists() {
-        match fetch_repo_json_file_to_tmp(url, &tmp_path).await {
-            Ok(file) => {
-                println!("Repo file successfully downloaded to {}", file.display());
-            }
-            Err(err) => {
-                // Fail fast as in your intent
-                panic!("Repo file did not download into the temp folder: {err}");
-            }
-        }
+/// Fetch the Simplestreams document at `url` through the HTTP cache and
+/// deserialize it into `T`.
+async fn construct_repo_catalogue<T: for<'de> serde::Deserialize<'de>>(
+    client: &Client,
+    url: &str,
+) -> Result<T> {
+    // With signature checks on, read the clearsigned variant of the index
+    // (`.sjson`) instead, verified on every run.
+    if gpg::enabled_for("ubuntu")
+        && let Some(stem) = url.strip_suffix(".json")
+    {
+        let signed_url = format!("{stem}.sjson");
+        let signed = http_cache::text(client, &signed_url)
+            .await
+            .with_context(|| format!("GET {signed_url}"))?;
+        let verified =
+            gpg::verify_checksums(client, &signed_url, signed, "ubuntu", Signing::Clearsigned)
+                .await
+                .map_err(anyhow::Error::msg)?;
+        trust::observe(
+            "ubuntu",
+            &signed_url,
+            &verified.text,
+            verified.signer.as_deref(),
+        )
+        .await;
+        return serde_json::from_str(&verified.text)
+            .with_context(|| format!("parse JSON from {signed_url}"));
     }
 
-    // Read from the cached file and deserialize
-    let bytes =
-        fs::read(&tmp_path).with_context(|| format!("read cached file {}", tmp_path.display()))?;
-
-    let data: T = serde_json::from_slice(&bytes)
-        .with_context(|| format!("parse JSON from {}", tmp_path.display()))?;
-
-    Ok(data)
+    let body = http_cache::text(client, url)
+        .await
+        .with_context(|| format!("GET {url}"))?;
+    serde_json::from_str(&body).with_context(|| format!("parse JSON from {url}"))
 }
 
 /// Construct the repository url which contains the '{}' delimiter
@@ -170,58 +110,100 @@ async fn construct_repo_catalogue<T: for<'de> serde::Deserialize<'de>>(url: &str
 /// The upstream configuration stores a template with placeholders for the
 /// requested track (e.g. `releases` or `daily`). This helper replaces the first
 /// placeholder while leaving the rest untouched for downstream consumers.
-fn construct_repo_url(track: &str) -> String {
-    let catalog_url: String = repositories::by_name("ubuntu")
-        .unwrap_or_else(|err| panic!("{err}"))
-        .unwrap()
-        .url()
-        .to_string();
-    catalog_url.replacen("{}", track, 1)
+fn construct_repo_url(repo: &repositories::Repository, track: &str) -> String {
+    repo.url().replacen("{}", track, 1)
+}
+
+/// Return the configured Ubuntu repository definition or bubble up a
+/// descriptive error when it is missing.
+fn repository_config() -> Result<Arc<repositories::Repository>> {
+    repositories::by_name("ubuntu")
+        .map_err(anyhow::Error::new)?
+        .context("repository 'ubuntu' is not configured")
+}
+
+/// Location of the products file to read. A Simplestreams `index.json` is
+/// searched for the products called `content_id` (by default the
+/// `image-downloads` ones) and their path is resolved against `base`, the
+/// mirror root; any other URL is taken to be a products file already.
+async fn products_url(
+    client: &Client,
+    stream_url: &str,
+    base: &str,
+    content_id: Option<&str>,
+) -> Result<String> {
+    if !stream_url.ends_with("/index.json") {
+        return Ok(stream_url.to_string());
+    }
+
+    let index: StreamIndex = construct_repo_catalogue(client, stream_url).await?;
+    let (id, entry) = index
+        .products(content_id)
+        .with_context(|| match content_id {
+            Some(id) => format!("{stream_url} does not list the products '{id}'"),
+            None => format!("{stream_url} lists no {IMAGE_DOWNLOADS} products"),
+        })?;
+    let url = format!("{}/{}", base.trim_end_matches('/'), entry.path());
+    sanitize::url_under(base, &url)
+        .map_err(anyhow::Error::msg)
+        .with_context(|| format!("products '{id}' point outside the mirror"))?;
+    Ok(url)
 }
 
 /// Fetch a normalized list of Ubuntu images from Canonical Simplestreams.
 /// - `track`: "releases" (stable) or "daily"
-/// - `arch`: "amd64", "arm64", "ppc64el", "s390x"
+/// - `target_arch`: architecture to keep images for
 /// - `only_disk_images`: if true, keep only `.img` and `.qcow2`
 pub async fn ubuntu_list(
+    client: &Client,
     release_track: &str,
-    target_arch: &str,
+    target_arch: Arch,
     only_disk_images: bool,
 ) -> Result<Vec<Image>> {
-    let repo_base_url_for_paths: String = repositories::by_name("ubuntu")
-        .unwrap_or_else(|err| panic!("{err}"))
-        .unwrap()
+    let repo = repository_config()?;
+    let repo_base_url_for_paths = repo
         .other_parameters()
-        .unwrap()
-        .get("base_for_paths")
-        .unwrap_or_else(|| panic!("Key in extra parameters not found!"))
-        .clone();
+        .and_then(|params| params.get("base_for_paths"))
+        .context("repository 'ubuntu' lacks the 'base_for_paths' parameter")?;
 
-    let base_url_for_paths = repo_base_url_for_paths.replacen("{}", release_track, 1);
-    let catalog_url = construct_repo_url(release_track);
+    // Both the catalogue and the image paths are served from the first
+    // reachable mirror.
+    let base_url_for_paths = repositories::resolve(client, &repo, repo_base_url_for_paths)
+        .await
+        .replacen("{}", release_track, 1);
+    let stream_url =
+        repositories::resolve(client, &repo, &construct_repo_url(&repo, release_track)).await;
+    let content_id = repo
+        .other_parameters()
+        .and_then(|params| params.get("content_id"))
+        .map(String::as_str);
+    let catalog_url = products_url(client, &stream_url, &base_url_for_paths, content_id).await?;
 
-    let catalog: Catalog = construct_repo_catalogue(&catalog_url).await?;
+    let catalog: Catalog = construct_repo_catalogue(client, &catalog_url).await?;
 
     let mut images: Vec<Image> = Vec::new();
 
     for (product_name, product_metadata) in catalog.products() {
-        let mut resolved_architecture = product_metadata.arch().clone();
-
-        if resolved_architecture.is_none()
-            && let Some(product_tail) = product_name.rsplit(':').next()
-            && matches!(product_tail, "amd64" | "arm64" | "ppc64el" | "s390x")
-        {
-            resolved_architecture = Some(product_tail.to_string());
-        }
-
-        if let Some(ref detected_architecture) = resolved_architecture {
-            if detected_architecture != target_arch {
-                continue;
-            }
-        } else {
-            continue; // no arch info
+        // Products without an arch field carry it as the last part of
+        // their name (`com.ubuntu.cloud:server:24.04:amd64`).
+        let resolved_architecture = product_metadata
+            .arch()
+            .as_deref()
+            .or_else(|| product_name.rsplit(':').next())
+            .and_then(Arch::from_name);
+        if resolved_architecture != Some(target_arch) {
+            // other or no arch info
+            trace::excluded("arch", product_name, || match resolved_architecture {
+                Some(arch) => format!("arch is {arch}, wanted {target_arch}"),
+                None => "no arch information".to_string(),
+            });
+            continue;
         }
+        trace::kept("arch");
 
+        let os = product_metadata
+            .os()
+            .unwrap_or_else(|| "ubuntu".to_string());
         let release_name = product_metadata
             .release()
             .clone()
@@ -245,17 +227,23 @@ pub async fn ubuntu_list(
                     continue;
                 }
 
-                images.push(Image::from_metadata(
-                    product_metadata.os().unwrap(), // keep as-is per your code
+                let image = Image::from_metadata(
+                    os.clone(),
                     &release_name,
                     &distro_version,
                     version_id, // <-- use version id from loop (not product_metadata.version())
-                    resolved_architecture.as_ref().unwrap(),
+                    target_arch,
                     &base_url_for_paths,
                     &relative_path,
-                    image_item.sha256().clone(),
-                    alias.to_string(),
-                ));
+                    image_item.checksums(),
+                    alias,
+                )
+                .with_size(image_item.size());
+                if let Err(err) = sanitize::url_under(&base_url_for_paths, image.url()) {
+                    eprintln!("Warning: {err}");
+                    continue;
+                }
+                images.push(image);
             }
         }
     }